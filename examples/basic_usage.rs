@@ -3,6 +3,7 @@ use fsh::{
     server::FshServer,
     client::FshClient,
     protocol::ShellType,
+    security::TokenConfig,
 };
 use std::collections::HashMap;
 use tempfile::TempDir;
@@ -32,6 +33,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::default();
     config.server.port = 12345; // Use a different port for the example
 
+    // Provision a client token; the config only keeps its hash
+    let (token_config, token) = TokenConfig::generate("Example client");
+    config.security.tokens.push(token_config);
+
     // Add the temporary folder as an available folder
     let folder_config = FolderConfig::new("example".to_string(), &temp_path)
         .with_description("Example temporary folder".to_string())
@@ -60,9 +65,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     client.connect().await?;
     info!("✓ Connected to server");
 
-    // Authenticate (using default token)
+    // Authenticate with the provisioned token
     let mut credentials = HashMap::new();
-    credentials.insert("token".to_string(), "default".to_string());
+    credentials.insert("token".to_string(), token);
     client.authenticate("token", credentials).await?;
     info!("✓ Authenticated");
 
@@ -0,0 +1,406 @@
+use clap::{Parser, ValueEnum};
+use fsh::client::{CommandOutputType, FshClient};
+use fsh::protocol::{FshResult, MAX_FILE_CHUNK_SIZE};
+use fsh::security::TlsClientConfig;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Size of each write and read while measuring file transfers.
+const TRANSFER_CHUNK: usize = 1024 * 1024;
+
+#[derive(Parser)]
+#[command(name = "fsh-bench")]
+#[command(about = "Load-test an FSH server with concurrent simulated clients")]
+#[command(version)]
+struct Cli {
+    /// Server address
+    #[arg(short, long, default_value = "127.0.0.1:2222")]
+    server: String,
+
+    /// Folder every client binds to
+    #[arg(short, long)]
+    folder: String,
+
+    /// Authentication token
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// Connect over TLS, trusting server certificates signed by this PEM CA bundle
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Simulated clients running at once
+    #[arg(short, long, default_value_t = 10)]
+    clients: usize,
+
+    /// Commands each client runs
+    #[arg(short = 'n', long, default_value_t = 20)]
+    commands: usize,
+
+    /// Bytes each client uploads and downloads again; 0 skips file transfers
+    #[arg(long, default_value_t = 4 * 1024 * 1024)]
+    transfer_bytes: usize,
+
+    /// How to print the results
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Verbose logging
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Command each client runs, with its arguments (default: echo fsh-bench)
+    #[arg(last = true)]
+    command: Vec<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Csv,
+    Json,
+}
+
+/// What one simulated client does, shared by all of them.
+struct Workload {
+    server: String,
+    folder: String,
+    token: Option<String>,
+    tls: Option<TlsClientConfig>,
+    commands: usize,
+    command: String,
+    args: Vec<String>,
+    transfer_bytes: usize,
+}
+
+/// When a client started and finished one phase of the run.
+#[derive(Debug, Clone, Copy)]
+struct Phase {
+    start: Instant,
+    end: Instant,
+}
+
+impl Phase {
+    fn since(start: Instant) -> Self {
+        Self { start, end: Instant::now() }
+    }
+}
+
+/// What one simulated client measured. Phases it didn't reach are `None`.
+#[derive(Debug, Default)]
+struct ClientRun {
+    handshake: Option<Duration>,
+    command_latencies: Vec<Duration>,
+    commands: Option<Phase>,
+    output_bytes: u64,
+    upload: Option<Phase>,
+    download: Option<Phase>,
+    uploaded_bytes: u64,
+    downloaded_bytes: u64,
+    error: Option<String>,
+}
+
+async fn run_client(workload: Arc<Workload>, index: usize) -> ClientRun {
+    let mut run = ClientRun::default();
+    if let Err(e) = simulate(&workload, index, &mut run).await {
+        run.error = Some(e.to_string());
+    }
+    run
+}
+
+async fn simulate(workload: &Workload, index: usize, run: &mut ClientRun) -> FshResult<()> {
+    // Handshake: connect, log in, bind and wait for the shell
+    let start = Instant::now();
+    let mut client = FshClient::new(workload.server.clone());
+    if let Some(tls) = &workload.tls {
+        client = client.with_tls(tls.clone());
+    }
+    client.connect().await?;
+    if let Some(token) = &workload.token {
+        let mut credentials = HashMap::new();
+        credentials.insert("token".to_string(), token.clone());
+        client.authenticate("token", credentials).await?;
+    }
+    client.bind_folder(&workload.folder, None).await?;
+    client.wait_for_session_ready().await?;
+    run.handshake = Some(start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..workload.commands {
+        let sent = Instant::now();
+        let mut output_rx = client.execute_command(&workload.command, workload.args.clone()).await?;
+        while let Some(output) = output_rx.recv().await {
+            match output.output_type {
+                CommandOutputType::Stdout | CommandOutputType::Stderr => {
+                    run.output_bytes += output.raw.as_ref().map_or(output.data.len(), |raw| raw.len()) as u64;
+                }
+                CommandOutputType::Error => return Err(fsh::protocol::FshError::ShellError(output.data)),
+                CommandOutputType::Complete => {}
+            }
+        }
+        run.command_latencies.push(sent.elapsed());
+    }
+    run.commands = Some(Phase::since(start));
+
+    if workload.transfer_bytes > 0 {
+        let path = format!(".fsh-bench-{}-{}", std::process::id(), index);
+        let data = vec![b'x'; TRANSFER_CHUNK.min(MAX_FILE_CHUNK_SIZE)];
+
+        let start = Instant::now();
+        let mut offset = 0;
+        while offset < workload.transfer_bytes {
+            let length = data.len().min(workload.transfer_bytes - offset);
+            let write_offset = (offset > 0).then_some(offset as u64);
+            client.write_file_range(&path, &data[..length], write_offset).await?;
+            offset += length;
+        }
+        run.upload = Some(Phase::since(start));
+        run.uploaded_bytes = offset as u64;
+
+        let start = Instant::now();
+        let mut offset = 0;
+        while offset < workload.transfer_bytes {
+            let (chunk, _) = client.read_file_range(&path, offset as u64, data.len() as u64).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            offset += chunk.len();
+        }
+        run.download = Some(Phase::since(start));
+        run.downloaded_bytes = offset as u64;
+
+        client.delete_file(&path, false).await?;
+    }
+
+    client.disconnect().await
+}
+
+/// Latency distribution in milliseconds.
+#[derive(Debug, Default, Serialize)]
+struct Latency {
+    min_ms: f64,
+    mean_ms: f64,
+    p50_ms: f64,
+    p95_ms: f64,
+    max_ms: f64,
+}
+
+impl Latency {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort();
+        let ms = |duration: &Duration| duration.as_secs_f64() * 1000.0;
+        // Nearest rank
+        let percentile = |p: usize| ms(&samples[(samples.len() * p).div_ceil(100) - 1]);
+        Self {
+            min_ms: ms(&samples[0]),
+            mean_ms: samples.iter().map(ms).sum::<f64>() / samples.len() as f64,
+            p50_ms: percentile(50),
+            p95_ms: percentile(95),
+            max_ms: ms(&samples[samples.len() - 1]),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    clients: usize,
+    failed_clients: usize,
+    elapsed_secs: f64,
+    handshake: Latency,
+    commands: usize,
+    commands_per_sec: f64,
+    command: Latency,
+    output_bytes: u64,
+    output_mib_per_sec: f64,
+    uploaded_bytes: u64,
+    upload_mib_per_sec: f64,
+    downloaded_bytes: u64,
+    download_mib_per_sec: f64,
+    /// The first few distinct failures.
+    errors: Vec<String>,
+}
+
+impl Report {
+    /// Rates are over the window from the first client starting a phase
+    /// to the last one finishing it, so they are the server's aggregate.
+    fn new(runs: &[ClientRun], elapsed: Duration) -> Self {
+        let window = |phase: fn(&ClientRun) -> Option<Phase>| {
+            let phases: Vec<Phase> = runs.iter().filter_map(phase).collect();
+            let start = phases.iter().map(|phase| phase.start).min();
+            let end = phases.iter().map(|phase| phase.end).max();
+            match (start, end) {
+                (Some(start), Some(end)) => end.duration_since(start).as_secs_f64(),
+                _ => 0.0,
+            }
+        };
+        let rate = |amount: f64, seconds: f64| if seconds > 0.0 { amount / seconds } else { 0.0 };
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+        let commands: usize = runs.iter().map(|run| run.command_latencies.len()).sum();
+        let output_bytes = runs.iter().map(|run| run.output_bytes).sum();
+        let uploaded_bytes = runs.iter().map(|run| run.uploaded_bytes).sum();
+        let downloaded_bytes = runs.iter().map(|run| run.downloaded_bytes).sum();
+        let command_window = window(|run| run.commands);
+
+        let mut errors: Vec<String> = Vec::new();
+        for error in runs.iter().filter_map(|run| run.error.clone()) {
+            if errors.len() < 5 && !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+
+        Self {
+            clients: runs.len(),
+            failed_clients: runs.iter().filter(|run| run.error.is_some()).count(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            handshake: Latency::from_samples(runs.iter().filter_map(|run| run.handshake).collect()),
+            commands,
+            commands_per_sec: rate(commands as f64, command_window),
+            command: Latency::from_samples(runs.iter().flat_map(|run| run.command_latencies.clone()).collect()),
+            output_bytes,
+            output_mib_per_sec: rate(mib(output_bytes), command_window),
+            uploaded_bytes,
+            upload_mib_per_sec: rate(mib(uploaded_bytes), window(|run| run.upload)),
+            downloaded_bytes,
+            download_mib_per_sec: rate(mib(downloaded_bytes), window(|run| run.download)),
+            errors,
+        }
+    }
+
+    fn csv_header() -> String {
+        let latency = |name: &str| {
+            ["min_ms", "mean_ms", "p50_ms", "p95_ms", "max_ms"].map(|field| format!("{}_{}", name, field)).join(",")
+        };
+        format!(
+            "clients,failed_clients,elapsed_secs,{},commands,commands_per_sec,{},output_bytes,output_mib_per_sec,\
+             uploaded_bytes,upload_mib_per_sec,downloaded_bytes,download_mib_per_sec",
+            latency("handshake"), latency("command")
+        )
+    }
+
+    fn csv_row(&self) -> String {
+        let latency = |l: &Latency| format!("{:.3},{:.3},{:.3},{:.3},{:.3}", l.min_ms, l.mean_ms, l.p50_ms, l.p95_ms, l.max_ms);
+        format!(
+            "{},{},{:.3},{},{},{:.1},{},{},{:.2},{},{:.2},{},{:.2}",
+            self.clients, self.failed_clients, self.elapsed_secs, latency(&self.handshake),
+            self.commands, self.commands_per_sec, latency(&self.command), self.output_bytes,
+            self.output_mib_per_sec, self.uploaded_bytes, self.upload_mib_per_sec,
+            self.downloaded_bytes, self.download_mib_per_sec
+        )
+    }
+
+    fn print_text(&self) {
+        let latency = |l: &Latency| format!(
+            "min {:.2} / mean {:.2} / p50 {:.2} / p95 {:.2} / max {:.2} ms",
+            l.min_ms, l.mean_ms, l.p50_ms, l.p95_ms, l.max_ms
+        );
+        println!("Clients:     {} ({} failed) in {:.2}s", self.clients, self.failed_clients, self.elapsed_secs);
+        println!("Handshake:   {}", latency(&self.handshake));
+        println!("Commands:    {} at {:.1}/s", self.commands, self.commands_per_sec);
+        println!("  latency    {}", latency(&self.command));
+        println!("Output:      {} bytes at {:.2} MiB/s", self.output_bytes, self.output_mib_per_sec);
+        if self.uploaded_bytes > 0 {
+            println!("Upload:      {} bytes at {:.2} MiB/s", self.uploaded_bytes, self.upload_mib_per_sec);
+            println!("Download:    {} bytes at {:.2} MiB/s", self.downloaded_bytes, self.download_mib_per_sec);
+        }
+        for error in &self.errors {
+            println!("Error:       {}", error);
+        }
+    }
+}
+
+fn init_logging(verbose: bool) {
+    let level = if verbose { "debug" } else { "warn" };
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("fsh={},fsh_bench={}", level, level).into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    init_logging(cli.verbose);
+
+    let (command, args) = match cli.command.split_first() {
+        Some((command, args)) => (command.clone(), args.to_vec()),
+        None => ("echo".to_string(), vec!["fsh-bench".to_string()]),
+    };
+    let workload = Arc::new(Workload {
+        server: cli.server,
+        folder: cli.folder,
+        token: cli.token,
+        tls: cli.tls_ca.map(TlsClientConfig::new),
+        commands: cli.commands,
+        command,
+        args,
+        transfer_bytes: cli.transfer_bytes,
+    });
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..cli.clients)
+        .map(|index| tokio::spawn(run_client(Arc::clone(&workload), index)))
+        .collect();
+    let mut runs = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        runs.push(task.await.unwrap_or_else(|e| ClientRun { error: Some(e.to_string()), ..Default::default() }));
+    }
+    let report = Report::new(&runs, start.elapsed());
+
+    match cli.format {
+        Format::Text => report.print_text(),
+        Format::Csv => {
+            println!("{}", Report::csv_header());
+            println!("{}", report.csv_row());
+        }
+        Format::Json => match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Failed to write the report: {}", e),
+        },
+    }
+
+    if report.failed_clients > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let start = Instant::now();
+        let run = |ms: u64| ClientRun {
+            handshake: Some(Duration::from_millis(ms)),
+            command_latencies: vec![Duration::from_millis(ms); 10],
+            commands: Some(Phase { start, end: start + Duration::from_secs(2) }),
+            output_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+        let failed = ClientRun { error: Some("Connection refused".to_string()), ..Default::default() };
+        let report = Report::new(&[run(10), run(30), failed], Duration::from_secs(3));
+
+        assert_eq!(report.failed_clients, 1);
+        assert_eq!(report.commands, 20);
+        assert_eq!(report.commands_per_sec, 10.0);
+        assert_eq!(report.output_mib_per_sec, 1.0);
+        assert_eq!(report.handshake.min_ms, 10.0);
+        assert_eq!(report.handshake.mean_ms, 20.0);
+        assert_eq!(report.handshake.max_ms, 30.0);
+        assert_eq!(report.upload_mib_per_sec, 0.0);
+        assert_eq!(report.errors, vec!["Connection refused".to_string()]);
+        assert_eq!(Report::csv_header().split(',').count(), report.csv_row().split(',').count());
+    }
+}
@@ -1,6 +1,11 @@
 use clap::{Parser, Subcommand};
-use fsh::client::{FshClient, Terminal};
+use fsh::client::{AnsiSequences, Copier, CopyEndpoint, CopyLocation, FshClient, LineEndings, RenderOptions, Terminal};
+use fsh::protocol::message::GitChange;
+use fsh::protocol::{ChecksumAlgorithm, Framing, RelayTransport};
+use fsh::security::{KnownServers, NoiseClientConfig, NoiseKey, TlsClientConfig};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tracing::{info, error};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -19,6 +24,171 @@ struct Cli {
     /// Verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    /// Connect over TLS, trusting server certificates signed by this PEM CA bundle
+    #[arg(long, global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// Connect over TLS without a CA: trust the server's key on first use and
+    /// refuse the server if its key changes later
+    #[arg(long, global = true)]
+    tls: bool,
+
+    /// Where server keys are recorded and checked (defaults to
+    /// ~/.config/fsh/known_servers when there is no --tls-ca)
+    #[arg(long, global = true)]
+    known_servers: Option<PathBuf>,
+
+    /// PEM client certificate; logs in by certificate when no token is given
+    #[arg(long, global = true, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name expected in the server certificate (defaults to the server host)
+    #[arg(long, global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Encrypt the connection with Noise, expecting the server to have this
+    /// base64 public key
+    #[arg(long, global = true)]
+    noise_server_key: Option<String>,
+
+    /// This client's Noise private key (defaults to ~/.config/fsh/noise_key,
+    /// generated on first use)
+    #[arg(long, global = true)]
+    noise_key: Option<PathBuf>,
+
+    /// Send newline-delimited JSON instead of binary frames, to read the
+    /// traffic with tcpdump
+    #[cfg(feature = "json-codec")]
+    #[arg(long, global = true)]
+    json_frames: bool,
+
+    /// Treat --server as a relay and reach the agent with this name, or the
+    /// agent serving a folder of this name
+    #[arg(long, global = true)]
+    relay_target: Option<String>,
+
+    /// Line breaks in command output: keep, lf or crlf (overrides
+    /// ~/.config/fsh/client.toml)
+    #[arg(long, global = true, value_parser = parse_line_endings)]
+    line_endings: Option<LineEndings>,
+
+    /// Expand tabs in command output to this many columns
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    tab_width: Option<u16>,
+
+    /// ANSI escape sequences in command output: keep or strip
+    #[arg(long, global = true, value_parser = parse_ansi)]
+    ansi: Option<AnsiSequences>,
+}
+
+/// The preferences file's render options, with the command line's on top.
+fn render_options(cli: &Cli) -> Result<RenderOptions, Box<dyn std::error::Error>> {
+    let mut render = RenderOptions::load(&RenderOptions::default_path()?)?;
+    if let Some(line_endings) = cli.line_endings {
+        render = render.with_line_endings(line_endings);
+    }
+    if let Some(tab_width) = cli.tab_width {
+        render = render.with_tab_width(tab_width.into());
+    }
+    if let Some(ansi) = cli.ansi {
+        render = render.with_ansi(ansi);
+    }
+    Ok(render)
+}
+
+/// Where and how to reach the server.
+struct Server {
+    addr: String,
+    tls: Option<TlsClientConfig>,
+    noise: Option<NoiseClientConfig>,
+    framing: Framing,
+    relay_target: Option<String>,
+}
+
+impl Server {
+    fn from_cli(cli: &Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = match &cli.tls_ca {
+            Some(ca) => Some(match &cli.known_servers {
+                Some(known_servers) => TlsClientConfig::new(ca).with_known_servers(known_servers),
+                None => TlsClientConfig::new(ca),
+            }),
+            // Without a CA, the key a server presented before is what proves it
+            None if cli.tls || cli.tls_cert.is_some() || cli.known_servers.is_some() => {
+                let known_servers = match &cli.known_servers {
+                    Some(path) => path.clone(),
+                    None => KnownServers::default_path()?,
+                };
+                Some(TlsClientConfig::trust_on_first_use(known_servers))
+            }
+            None => None,
+        };
+        let tls = tls.map(|mut tls| {
+            if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+                tls = tls.with_client_cert(cert, key);
+            }
+            if let Some(name) = &cli.tls_server_name {
+                tls = tls.with_server_name(name.clone());
+            }
+            tls
+        });
+
+        let noise = match &cli.noise_server_key {
+            Some(server_key) => {
+                let key_file = match &cli.noise_key {
+                    Some(path) => path.clone(),
+                    None => NoiseClientConfig::default_key_path()?,
+                };
+                Some(NoiseClientConfig::new(key_file, server_key.clone()))
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "json-codec")]
+        let framing = if cli.json_frames { Framing::Json } else { Framing::Binary };
+        #[cfg(not(feature = "json-codec"))]
+        let framing = Framing::Binary;
+
+        Ok(Self { addr: cli.server.clone(), tls, noise, framing, relay_target: cli.relay_target.clone() })
+    }
+
+    fn client(&self) -> FshClient {
+        self.client_for(&self.addr)
+    }
+
+    /// A client for another server, reached the same way.
+    fn client_for(&self, addr: &str) -> FshClient {
+        let client = FshClient::new(addr.to_string()).with_framing(self.framing);
+        let client = match &self.relay_target {
+            Some(target) => client.with_transport(Arc::new(RelayTransport::new(target.clone()))),
+            None => client,
+        };
+        let client = match &self.noise {
+            Some(noise) => client.with_noise(noise.clone()),
+            None => client,
+        };
+        match &self.tls {
+            Some(tls) => client.with_tls(tls.clone()),
+            None => client,
+        }
+    }
+}
+
+/// Authenticate with the token if one was given, otherwise with the client
+/// certificate if there is one.
+async fn login(client: &mut FshClient, token: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(token) = token {
+        let mut credentials = HashMap::new();
+        credentials.insert("token".to_string(), token);
+        client.authenticate("token", credentials).await?;
+    } else if client.has_client_cert() {
+        client.authenticate_with_certificate().await?;
+    }
+    Ok(())
 }
 
 #[derive(Subcommand)]
@@ -33,7 +203,7 @@ enum Commands {
         #[arg(short, long)]
         token: Option<String>,
 
-        /// Preferred shell type (powershell, cmd, bash, git-bash)
+        /// Preferred shell type (powershell, pwsh, cmd, bash, git-bash, zsh, fish, nu, sh)
         #[arg(long)]
         shell: Option<String>,
     },
@@ -48,6 +218,15 @@ enum Commands {
         #[arg(short, long)]
         token: Option<String>,
 
+        /// Preferred shell type (powershell, pwsh, cmd, bash, git-bash, zsh, fish, nu, sh)
+        #[arg(long)]
+        shell: Option<String>,
+
+        /// Write binary output to this file; by default it goes to stdout
+        /// unless that is a terminal
+        #[arg(long, value_name = "FILE")]
+        binary_output: Option<PathBuf>,
+
         /// Command to execute
         command: String,
 
@@ -72,10 +251,229 @@ enum Commands {
         /// Show hidden files
         #[arg(long)]
         hidden: bool,
+
+        /// Skip this many entries
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+
+        /// Show at most this many entries
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+
+    /// Download a file, verifying it with checksums
+    Get {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// File to download (relative to folder root)
+        remote: String,
+
+        /// Where to save it (defaults to the remote file name)
+        local: Option<std::path::PathBuf>,
+    },
+
+    /// Upload a file, verifying it with checksums
+    Put {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// File to upload
+        local: std::path::PathBuf,
+
+        /// Destination (relative to folder root, defaults to the local file name)
+        remote: Option<String>,
+    },
+
+    /// Print the end of a file, optionally following it as it grows
+    Tail {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// File to read (relative to folder root)
+        path: String,
+
+        /// Number of lines to print
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: u32,
+
+        /// Keep printing lines as they are appended (stop with Ctrl+C)
+        #[arg(long)]
+        follow: bool,
+    },
+
+    /// Copy files between this machine and servers, or between two servers.
+    /// Remote paths are written fsh://server[:port]/folder/path
+    Cp {
+        /// Authentication token, used for every server
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// Copy directories and their contents
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Sources, which may use * and ? in their last component, then the destination
+        #[arg(required = true, num_args = 2..)]
+        paths: Vec<String>,
+    },
+
+    /// Delete a file or directory (moved to the folder's trash when enabled)
+    Rm {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// Path to delete (relative to folder root)
+        path: String,
+
+        /// Delete directories and their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+
+    /// Restore a deleted file from the folder's trash
+    Restore {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// Trash id or original path (the most recently deleted copy is restored)
+        target: String,
+    },
+
+    /// Edit a remote file with $VISUAL or $EDITOR and write it back
+    Edit {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// File to edit (relative to folder root)
+        path: String,
+    },
+
+    /// Show git status, diffs or history of a repository in the folder
+    Git {
+        /// Folder to bind to
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        #[command(subcommand)]
+        action: GitAction,
+    },
+
+    /// Forward local connections to a port on the server, like `ssh -L`
+    Forward {
+        /// Folder to bind to; it must list the remote port as forwardable
+        #[arg(short, long)]
+        folder: String,
+
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// `[bind_address:]local_port:host:remote_port`, e.g. `3000:localhost:3000`
+        #[arg(short = 'L', long = "local", value_parser = parse_forward_spec)]
+        spec: ForwardSpec,
+    },
+
+    /// Watch another client's session read-only, once its owner has run `share`
+    Observe {
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// The session to watch, as shown by `share` in its terminal
+        session_id: String,
+    },
+
+    /// Run commands in another client's session, once its owner has run `share rw`
+    Join {
+        /// Authentication token
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// The session to join, as shown by `share rw` in its terminal
+        session_id: String,
     },
 
     /// Test connection to server
     Test,
+
+    /// List FSH servers advertised over mDNS on the local network
+    Discover {
+        /// Seconds to listen for advertisements
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+
+    /// Print this client's Noise public key for a server's authorized_keys,
+    /// generating the key if needed
+    NoiseKey,
+}
+
+#[derive(Subcommand)]
+enum GitAction {
+    /// Changed and untracked files
+    Status {
+        /// Path inside the repository (relative to folder root)
+        path: Option<String>,
+    },
+
+    /// Unstaged changes as a unified diff
+    Diff {
+        /// Path inside the repository (relative to folder root)
+        path: Option<String>,
+
+        /// Show staged changes instead
+        #[arg(long)]
+        staged: bool,
+
+        /// Lines of context around each change
+        #[arg(short = 'U', long, default_value_t = 3)]
+        context: u32,
+    },
+
+    /// Recent commits, newest first
+    Log {
+        /// Only commits touching this path (relative to folder root)
+        path: Option<String>,
+
+        /// Number of commits to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        max_count: u32,
+    },
 }
 
 #[tokio::main]
@@ -85,18 +483,71 @@ async fn main() {
     // Initialize logging
     init_logging(cli.verbose);
 
+    let server = match Server::from_cli(&cli) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Invalid TLS or Noise settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let render = match render_options(&cli) {
+        Ok(render) => render,
+        Err(e) => {
+            error!("Invalid render options: {}", e);
+            std::process::exit(1);
+        }
+    };
     let result = match cli.command {
         Commands::Connect { folder, token, shell } => {
-            connect_interactive(cli.server, folder, token, shell).await
+            connect_interactive(&server, render, folder, token, shell).await
+        }
+        Commands::Exec { folder, token, shell, binary_output, command, args } => {
+            execute_command(&server, render, folder, token, shell, binary_output, command, args).await
+        }
+        Commands::List { folder, token, path, hidden, offset, limit } => {
+            list_files(&server, folder, token, path, hidden, offset, limit).await
+        }
+        Commands::Get { folder, token, remote, local } => {
+            transfer_file(&server, folder, token, Transfer::Get { remote, local }).await
+        }
+        Commands::Put { folder, token, local, remote } => {
+            transfer_file(&server, folder, token, Transfer::Put { local, remote }).await
         }
-        Commands::Exec { folder, token, command, args } => {
-            execute_command(cli.server, folder, token, command, args).await
+        Commands::Tail { folder, token, path, lines, follow } => {
+            tail_file(&server, folder, token, path, lines, follow).await
         }
-        Commands::List { folder, token, path, hidden } => {
-            list_files(cli.server, folder, token, path, hidden).await
+        Commands::Cp { token, recursive, paths } => {
+            copy_files(&server, token, recursive, paths).await
+        }
+        Commands::Rm { folder, token, path, recursive } => {
+            manage_trash(&server, folder, token, TrashAction::Delete { path, recursive }).await
+        }
+        Commands::Restore { folder, token, target } => {
+            manage_trash(&server, folder, token, TrashAction::Restore { target }).await
+        }
+        Commands::Edit { folder, token, path } => {
+            edit_file(&server, folder, token, path).await
+        }
+        Commands::Git { folder, token, action } => {
+            show_git(&server, folder, token, action).await
+        }
+        Commands::Forward { folder, token, spec } => {
+            forward_port(&server, folder, token, spec).await
+        }
+        Commands::Observe { token, session_id } => {
+            observe_session(&server, render, token, session_id).await
+        }
+        Commands::Join { token, session_id } => {
+            join_session(&server, render, token, session_id).await
         }
         Commands::Test => {
-            test_connection(cli.server).await
+            test_connection(&server).await
+        }
+        Commands::Discover { timeout } => {
+            discover(timeout).await
+        }
+        Commands::NoiseKey => {
+            show_noise_key(cli.noise_key)
         }
     };
 
@@ -119,14 +570,17 @@ fn init_logging(verbose: bool) {
 }
 
 async fn connect_interactive(
-    server_addr: String,
+    server: &Server,
+    render: RenderOptions,
     _folder: Option<String>,
     _token: Option<String>,
     _shell: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Starting interactive FSH client");
 
-    let mut terminal = Terminal::new(server_addr);
+    let mut terminal = Terminal::new(server.addr.clone())
+        .with_client(server.client())
+        .with_render_options(render);
 
     // Run the interactive terminal
     terminal.run().await?;
@@ -134,29 +588,59 @@ async fn connect_interactive(
     Ok(())
 }
 
+async fn join_session(
+    server: &Server,
+    render: RenderOptions,
+    token: Option<String>,
+    session_id: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Joining session {}", session_id);
+
+    let mut terminal = Terminal::new(server.addr.clone())
+        .with_client(server.client())
+        .with_token(token)
+        .with_session_to_join(session_id)
+        .with_render_options(render);
+    terminal.run().await?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn execute_command(
-    server_addr: String,
+    server: &Server,
+    render: RenderOptions,
     folder: String,
     token: Option<String>,
+    shell: Option<String>,
+    binary_output: Option<PathBuf>,
     command: String,
     args: Vec<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{IsTerminal, Write};
+
     info!("Executing single command: {} {:?}", command, args);
 
-    let mut client = FshClient::new(server_addr);
+    // Binary output would garble a terminal
+    let mut binary_sink: Option<Box<dyn Write>> = match binary_output {
+        Some(path) => Some(Box::new(std::fs::File::create(path)?)),
+        None if !std::io::stdout().is_terminal() => Some(Box::new(std::io::stdout())),
+        None => None,
+    };
+
+    let mut client = server.client();
 
     // Connect
     client.connect().await?;
 
-    // Authenticate if token provided
-    if let Some(token) = token {
-        let mut credentials = HashMap::new();
-        credentials.insert("token".to_string(), token);
-        client.authenticate("token", credentials).await?;
-    }
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
 
     // Bind to folder
-    let shell_type = None; // Use default shell type
+    let shell_type = match shell {
+        Some(shell) => Some(parse_shell_type(&shell).ok_or_else(|| format!("Invalid shell type: {}", shell))?),
+        None => None, // Use default shell type
+    };
 
     client.bind_folder(&folder, shell_type).await?;
 
@@ -168,12 +652,19 @@ async fn execute_command(
 
     // Print output
     while let Some(output) = output_rx.recv().await {
+        if let Some(raw) = &output.raw {
+            match (&output.output_type, binary_sink.as_mut()) {
+                (fsh::client::CommandOutputType::Stdout, Some(sink)) => sink.write_all(raw)?,
+                _ => eprintln!("[{} bytes of binary output not shown; save it with --binary-output FILE]", raw.len()),
+            }
+            continue;
+        }
         match output.output_type {
             fsh::client::CommandOutputType::Stdout => {
-                print!("{}", output.data);
+                print!("{}", render.render(&output.data));
             }
             fsh::client::CommandOutputType::Stderr => {
-                eprint!("{}", output.data);
+                eprint!("{}", render.render(&output.data));
             }
             fsh::client::CommandOutputType::Complete => {
                 break;
@@ -185,6 +676,10 @@ async fn execute_command(
         }
     }
 
+    if let Some(sink) = binary_sink.as_mut() {
+        sink.flush()?;
+    }
+
     // Disconnect
     client.disconnect().await?;
 
@@ -192,46 +687,379 @@ async fn execute_command(
 }
 
 async fn list_files(
-    server_addr: String,
+    server: &Server,
     folder: String,
     token: Option<String>,
     path: String,
     show_hidden: bool,
+    offset: u64,
+    limit: Option<u32>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Listing files in folder: {}, path: {}", folder, path);
 
-    let mut client = FshClient::new(server_addr);
+    let mut client = server.client();
 
     // Connect
     client.connect().await?;
 
-    // Authenticate if token provided
-    if let Some(token) = token {
-        let mut credentials = HashMap::new();
-        credentials.insert("token".to_string(), token);
-        client.authenticate("token", credentials).await?;
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
+
+    // Bind to folder
+    client.bind_folder(&folder, None).await?;
+
+    // Wait for session ready
+    client.wait_for_session_ready().await?;
+
+    // List files, printing each batch as it arrives
+    println!("Files in {}:", path);
+    let next_offset = client.list_files_paged(&path, show_hidden, offset, limit, |files| {
+        for file in files {
+            let file_type = if file.is_directory { "DIR" } else { "FILE" };
+            let size = if file.is_directory { "-".to_string() } else { file.size.to_string() };
+
+            println!("{:>6} {:>10} {:<8} {:>10} {:>20} {}",
+                    file_type,
+                    file.permissions.as_deref().unwrap_or("-"),
+                    file.owner.as_deref().unwrap_or("-"),
+                    size,
+                    file.modified.format("%Y-%m-%d %H:%M"),
+                    file.name);
+        }
+    }).await?;
+
+    if let Some(next_offset) = next_offset {
+        println!("More entries available, continue with --offset {}", next_offset);
     }
 
+    // Disconnect
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+enum Transfer {
+    Get { remote: String, local: Option<std::path::PathBuf> },
+    Put { local: std::path::PathBuf, remote: Option<String> },
+}
+
+async fn transfer_file(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    transfer: Transfer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = server.client();
+
+    // Connect
+    client.connect().await?;
+
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
+
     // Bind to folder
     client.bind_folder(&folder, None).await?;
 
     // Wait for session ready
     client.wait_for_session_ready().await?;
 
-    // List files
-    let files = client.list_files(&path, show_hidden).await?;
+    match transfer {
+        Transfer::Get { remote, local } => {
+            let local = match local {
+                Some(local) => local,
+                None => std::path::Path::new(&remote).file_name()
+                    .map(std::path::PathBuf::from)
+                    .ok_or_else(|| format!("Cannot derive a local file name from '{}'", remote))?,
+            };
+            let bytes = client.download_file(&remote, &local).await?;
+            println!("Downloaded {} ({} bytes, checksum verified)", local.display(), bytes);
+        }
+        Transfer::Put { local, remote } => {
+            let remote = match remote {
+                Some(remote) => remote,
+                None => local.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .ok_or_else(|| format!("Cannot derive a remote file name from '{}'", local.display()))?,
+            };
+            let bytes = client.upload_file(&local, &remote).await?;
+            println!("Uploaded {} ({} bytes, checksum verified)", remote, bytes);
+        }
+    }
 
-    // Print file list
-    println!("Files in {}:", path);
-    for file in files {
-        let file_type = if file.is_directory { "DIR" } else { "FILE" };
-        let size = if file.is_directory { "-".to_string() } else { file.size.to_string() };
+    // Disconnect
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+/// Copy between local paths and `fsh://` locations. All sources must be in
+/// the same place; each remote side gets its own session.
+async fn copy_files(
+    server: &Server,
+    token: Option<String>,
+    recursive: bool,
+    mut paths: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    async fn open(server: &Server, location: &CopyLocation, token: Option<String>) -> Result<CopyEndpoint, Box<dyn std::error::Error>> {
+        let CopyLocation::Remote(remote) = location else {
+            return Ok(CopyEndpoint::Local);
+        };
+        let mut client = server.client_for(&remote.server);
+        client.connect().await?;
+        login(&mut client, token).await?;
+        client.bind_folder(&remote.folder, None).await?;
+        client.wait_for_session_ready().await?;
+        Ok(CopyEndpoint::Remote(Box::new(client)))
+    }
+
+    /// Where `location` is, and the path there.
+    fn split(location: CopyLocation) -> (Option<(String, String)>, String) {
+        match location {
+            CopyLocation::Local(path) => (None, path.to_string_lossy().to_string()),
+            CopyLocation::Remote(remote) => (Some((remote.server, remote.folder)), remote.path),
+        }
+    }
+
+    let destination: CopyLocation = paths.pop().ok_or("Missing destination")?.parse()?;
+    let sources = paths.iter().map(|path| path.parse()).collect::<Result<Vec<CopyLocation>, _>>()?;
+    let source_location = sources[0].clone();
+    let (source_place, _) = split(source_location.clone());
+    let mut source_paths = Vec::new();
+    for source in sources {
+        let (place, path) = split(source);
+        if place != source_place {
+            return Err("All sources must be on the same side of the copy".into());
+        }
+        source_paths.push(path);
+    }
+
+    let mut from = open(server, &source_location, token.clone()).await?;
+    let mut to = open(server, &destination, token).await?;
+    let (_, destination_path) = split(destination);
+    let summary = Copier::new().with_recursive(recursive)
+        .copy(&mut from, &source_paths, &mut to, &destination_path).await?;
+    println!("Copied {} file(s) ({} bytes), creating {} directory(s)", summary.files, summary.bytes, summary.directories);
+
+    for endpoint in [from, to] {
+        if let CopyEndpoint::Remote(mut client) = endpoint {
+            client.disconnect().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Download a file, edit it locally and write it back in one step, unless it
+/// changed on the server while it was being edited. The connection is closed
+/// while the editor runs so an idle timeout can't end it.
+async fn edit_file(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    path: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    async fn open(server: &Server, folder: &str, token: Option<String>) -> Result<FshClient, Box<dyn std::error::Error>> {
+        let mut client = server.client();
+        client.connect().await?;
+        login(&mut client, token).await?;
+        client.bind_folder(folder, None).await?;
+        client.wait_for_session_ready().await?;
+        Ok(client)
+    }
+
+    let file_name = std::path::Path::new(&path).file_name()
+        .ok_or_else(|| format!("'{}' is not a file", path))?;
+    let edit_dir = std::env::temp_dir().join(format!("fsh-edit-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&edit_dir)?;
+    let local = edit_dir.join(file_name);
+
+    let mut client = open(server, &folder, token.clone()).await?;
+    client.download_file(&path, &local).await?;
+    client.disconnect().await?;
+    let algorithm = ChecksumAlgorithm::default();
+    let (original, _) = algorithm.digest_reader(std::fs::File::open(&local)?)?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args.next().ok_or("The editor command is empty")?;
+    let status = std::process::Command::new(program).args(editor_args).arg(&local).status()
+        .map_err(|e| format!("Failed to start editor '{}': {}", editor, e))?;
+    if !status.success() {
+        return Err(format!("Editor exited with {}; your copy is in {}", status, local.display()).into());
+    }
+
+    let data = std::fs::read(&local)?;
+    if algorithm.digest(&data) == original {
+        println!("No changes to {}", path);
+        let _ = std::fs::remove_dir_all(&edit_dir);
+        return Ok(());
+    }
+
+    let mut client = open(server, &folder, token).await?;
+    if let Err(e) = client.replace_file_if_unchanged(&path, &data, &original).await {
+        return Err(format!("{}; your copy is in {}", e, local.display()).into());
+    }
+    client.disconnect().await?;
+    let _ = std::fs::remove_dir_all(&edit_dir);
+
+    println!("Saved {} ({} bytes, checksum verified)", path, data.len());
+    Ok(())
+}
+
+enum TrashAction {
+    Delete { path: String, recursive: bool },
+    Restore { target: String },
+}
+
+async fn manage_trash(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    action: TrashAction,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = server.client();
+
+    // Connect
+    client.connect().await?;
+
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
+
+    // Bind to folder
+    client.bind_folder(&folder, None).await?;
+
+    // Wait for session ready
+    client.wait_for_session_ready().await?;
+
+    match action {
+        TrashAction::Delete { path, recursive } => match client.delete_file(&path, recursive).await? {
+            Some(trash_id) => println!("Moved {} to trash (restore with: restore {})", path, trash_id),
+            None => println!("Deleted {}", path),
+        },
+        TrashAction::Restore { target } => {
+            let restored = client.restore_file(&target).await?;
+            println!("Restored {}", restored);
+        }
+    }
+
+    // Disconnect
+    client.disconnect().await?;
+
+    Ok(())
+}
 
-        println!("{:>6} {:>10} {:>20} {}",
-                file_type,
-                size,
-                file.modified.format("%Y-%m-%d %H:%M"),
-                file.name);
+async fn show_git(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    action: GitAction,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = server.client();
+
+    // Connect
+    client.connect().await?;
+
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
+
+    // Bind to folder
+    client.bind_folder(&folder, None).await?;
+
+    // Wait for session ready
+    client.wait_for_session_ready().await?;
+
+    match action {
+        GitAction::Status { path } => {
+            let status = client.git_status(path.as_deref()).await?;
+            let branch = status.branch.as_deref().unwrap_or("(detached)");
+            println!("Repository {} on {}", status.repository, branch);
+            for entry in &status.entries {
+                let code = |change: Option<GitChange>| match change {
+                    Some(GitChange::Added) => 'A',
+                    Some(GitChange::Modified) => 'M',
+                    Some(GitChange::Deleted) => 'D',
+                    Some(GitChange::Renamed) => 'R',
+                    Some(GitChange::TypeChanged) => 'T',
+                    Some(GitChange::Untracked) => '?',
+                    Some(GitChange::Conflicted) => 'U',
+                    None => ' ',
+                };
+                println!("{}{} {}", code(entry.index), code(entry.worktree), entry.path);
+            }
+        }
+        GitAction::Diff { path, staged, context } => {
+            let (diff, truncated) = client.git_diff(path.as_deref(), staged, context).await?;
+            print!("{}", diff);
+            if truncated {
+                eprintln!("(diff truncated by the server)");
+            }
+        }
+        GitAction::Log { path, max_count } => {
+            for commit in client.git_log(path.as_deref(), max_count).await? {
+                let time = chrono::DateTime::from_timestamp(commit.time, 0)
+                    .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                println!("{} {} {} {}", &commit.id[..commit.id.len().min(10)], time, commit.author, commit.summary);
+            }
+        }
+    }
+
+    // Disconnect
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+async fn tail_file(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    path: String,
+    lines: u32,
+    follow: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    info!("Tailing {} in folder: {}", path, folder);
+
+    let mut client = server.client();
+
+    // Connect
+    client.connect().await?;
+
+    // Authenticate with the token or client certificate, if provided
+    login(&mut client, token).await?;
+
+    // Bind to folder
+    client.bind_folder(&folder, None).await?;
+
+    // Wait for session ready
+    client.wait_for_session_ready().await?;
+
+    client.tail_file(&path, lines, follow).await?;
+
+    let mut stopping = false;
+    loop {
+        let chunk = tokio::select! {
+            chunk = client.next_tail_chunk() => chunk?,
+            _ = tokio::signal::ctrl_c(), if !stopping => {
+                // Keep reading until the server confirms the tail has ended
+                client.stop_tail().await?;
+                stopping = true;
+                continue;
+            }
+        };
+
+        let Some(chunk) = chunk else {
+            break;
+        };
+        if chunk.rotated {
+            eprintln!("==> {} was rotated <==", path);
+        }
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&chunk.data)?;
+        stdout.flush()?;
     }
 
     // Disconnect
@@ -240,10 +1068,126 @@ async fn list_files(
     Ok(())
 }
 
-async fn test_connection(server_addr: String) -> Result<(), Box<dyn std::error::Error>> {
+/// A local forward: connections to `bind` are carried to `host:port` on the server.
+#[derive(Debug, Clone)]
+struct ForwardSpec {
+    bind: String,
+    host: String,
+    port: u16,
+}
+
+fn parse_forward_spec(spec: &str) -> Result<ForwardSpec, String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (bind_address, local_port, host, remote_port) = match parts[..] {
+        [local_port, host, remote_port] => ("127.0.0.1", local_port, host, remote_port),
+        [bind_address, local_port, host, remote_port] => (bind_address, local_port, host, remote_port),
+        _ => return Err("expected [bind_address:]local_port:host:remote_port".to_string()),
+    };
+    let local_port: u16 = local_port.parse().map_err(|_| format!("invalid local port '{}'", local_port))?;
+    let port: u16 = remote_port.parse().map_err(|_| format!("invalid remote port '{}'", remote_port))?;
+    Ok(ForwardSpec {
+        bind: format!("{}:{}", bind_address, local_port),
+        host: host.to_string(),
+        port,
+    })
+}
+
+async fn forward_port(
+    server: &Server,
+    folder: String,
+    token: Option<String>,
+    spec: ForwardSpec,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind(&spec.bind).await?;
+
+    let mut client = server.client();
+    client.connect().await?;
+    login(&mut client, token).await?;
+    client.bind_folder(&folder, None).await?;
+    client.wait_for_session_ready().await?;
+
+    println!("Forwarding {} to {}:{} in folder '{}' (stop with Ctrl+C)", spec.bind, spec.host, spec.port, folder);
+    tokio::select! {
+        result = client.forward_local(listener, &spec.host, spec.port) => result?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+async fn observe_session(
+    server: &Server,
+    render: RenderOptions,
+    token: Option<String>,
+    session_id: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::protocol::message::ObservedEvent;
+    use fsh::protocol::OutputType;
+    use std::io::Write;
+
+    let mut client = server.client();
+    client.connect().await?;
+    login(&mut client, token).await?;
+
+    let folder_info = client.observe_session(&session_id).await?;
+    eprintln!("Observing session {} in folder '{}' (read-only, stop with Ctrl+C)", session_id, folder_info.name);
+
+    loop {
+        let event = tokio::select! {
+            event = client.next_observed() => event?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let Some(event) = event else {
+            break;
+        };
+        match event {
+            ObservedEvent::Command(command) => {
+                let job = command.job_id.map(|job_id| format!(" [job {}]", job_id)).unwrap_or_default();
+                eprintln!("$ {} {}{}", command.command, command.args.join(" "), job);
+            }
+            ObservedEvent::Output(output) => {
+                let data = if output.binary {
+                    output.data
+                } else {
+                    render.render(&String::from_utf8_lossy(&output.data)).into_bytes().into()
+                };
+                match output.output_type {
+                    OutputType::Stderr => {
+                        std::io::stderr().write_all(&data)?;
+                    }
+                    OutputType::Stdout => {
+                        let mut stdout = std::io::stdout();
+                        stdout.write_all(&data)?;
+                        stdout.flush()?;
+                    }
+                }
+            }
+            ObservedEvent::Complete(complete) if complete.exit_code != 0 => {
+                eprintln!("[exit {}]", complete.exit_code);
+            }
+            ObservedEvent::Complete(_) => {}
+            ObservedEvent::WorkingDirChanged(changed) => eprintln!("[cd {}]", changed.working_directory),
+            ObservedEvent::Error(error) => eprintln!("[error] {}", error.message),
+            ObservedEvent::ParticipantCommand { participant, command } => {
+                eprintln!("{}$ {} {}", participant, command.command, command.args.join(" "));
+            }
+            ObservedEvent::ParticipantJoined { participant } => eprintln!("[{} joined]", participant),
+            ObservedEvent::ParticipantLeft { participant } => eprintln!("[{} left]", participant),
+        }
+    }
+
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+async fn test_connection(server: &Server) -> Result<(), Box<dyn std::error::Error>> {
+    let server_addr = &server.addr;
     info!("Testing connection to {}", server_addr);
 
-    let mut client = FshClient::new(server_addr.clone());
+    let mut client = server.client();
 
     match client.connect().await {
         Ok(_) => {
@@ -265,13 +1209,64 @@ async fn test_connection(server_addr: String) -> Result<(), Box<dyn std::error::
     }
 }
 
+async fn discover(timeout: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let servers = fsh::client::discover_servers(std::time::Duration::from_secs(timeout)).await?;
+    if servers.is_empty() {
+        println!("No FSH servers found");
+        return Ok(());
+    }
+
+    for server in servers {
+        let address = server.address()
+            .map(|address| address.to_string())
+            .unwrap_or_else(|| format!("{}:{}", server.host, server.port));
+        let tls = if server.tls { " (TLS)" } else { "" };
+        println!("{}  {}{}", server.name, address, tls);
+        if !server.folders.is_empty() {
+            println!("    folders: {}", server.folders.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn show_noise_key(key_file: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let key_file = match key_file {
+        Some(path) => path,
+        None => NoiseClientConfig::default_key_path()?,
+    };
+    println!("{}", NoiseKey::load_or_generate(&key_file)?.public_key());
+    Ok(())
+}
+
 // Helper function to get shell type from string
+fn parse_line_endings(value: &str) -> Result<LineEndings, String> {
+    match value.to_lowercase().as_str() {
+        "keep" => Ok(LineEndings::Keep),
+        "lf" => Ok(LineEndings::Lf),
+        "crlf" => Ok(LineEndings::Crlf),
+        _ => Err("expected keep, lf or crlf".to_string()),
+    }
+}
+
+fn parse_ansi(value: &str) -> Result<AnsiSequences, String> {
+    match value.to_lowercase().as_str() {
+        "keep" => Ok(AnsiSequences::Keep),
+        "strip" => Ok(AnsiSequences::Strip),
+        _ => Err("expected keep or strip".to_string()),
+    }
+}
+
 fn parse_shell_type(shell: &str) -> Option<fsh::protocol::ShellType> {
     match shell.to_lowercase().as_str() {
         "powershell" => Some(fsh::protocol::ShellType::PowerShell),
+        "pwsh" => Some(fsh::protocol::ShellType::PowerShellCore),
         "cmd" => Some(fsh::protocol::ShellType::Cmd),
         "bash" => Some(fsh::protocol::ShellType::Bash),
         "git-bash" => Some(fsh::protocol::ShellType::GitBash),
+        "zsh" => Some(fsh::protocol::ShellType::Zsh),
+        "fish" => Some(fsh::protocol::ShellType::Fish),
+        "nu" | "nushell" => Some(fsh::protocol::ShellType::Nushell),
+        "sh" => Some(fsh::protocol::ShellType::Sh),
         _ => None,
     }
 }
@@ -283,9 +1278,11 @@ mod tests {
     #[test]
     fn test_shell_type_parsing() {
         assert!(matches!(parse_shell_type("powershell"), Some(fsh::protocol::ShellType::PowerShell)));
+        assert!(matches!(parse_shell_type("pwsh"), Some(fsh::protocol::ShellType::PowerShellCore)));
         assert!(matches!(parse_shell_type("cmd"), Some(fsh::protocol::ShellType::Cmd)));
         assert!(matches!(parse_shell_type("bash"), Some(fsh::protocol::ShellType::Bash)));
         assert!(matches!(parse_shell_type("git-bash"), Some(fsh::protocol::ShellType::GitBash)));
+        assert!(matches!(parse_shell_type("nushell"), Some(fsh::protocol::ShellType::Nushell)));
         assert!(parse_shell_type("invalid").is_none());
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use clap::{Parser, Subcommand};
-use fsh::{config::Config, server::FshServer};
-use std::path::PathBuf;
+use fsh::{config::{Config, LogFormat, LoggingConfig}, server::FshServer, telemetry::Telemetry};
+use std::path::{Path, PathBuf};
 use tracing::{info, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -19,13 +19,17 @@ struct Cli {
     /// Verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    /// Log format; defaults to `[logging] format` in the config file
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start the FSH server
     Start {
-        /// Override server host
+        /// Override server host (and the `listen` list)
         #[arg(long)]
         host: Option<String>,
 
@@ -51,6 +55,13 @@ enum Commands {
     #[command(subcommand)]
     Folder(FolderCommands),
 
+    /// Interactively create a configuration file and a first client token
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+
     /// Generate default configuration file
     Config {
         /// Output path for config file
@@ -64,6 +75,149 @@ enum Commands {
 
     /// Validate configuration file
     Validate,
+
+    /// Review commands waiting for approval (uses the admin API)
+    #[command(subcommand)]
+    Approvals(ApprovalCommands),
+
+    /// Review and lift authentication lockouts (uses the admin API)
+    #[command(subcommand)]
+    Lockouts(LockoutCommands),
+
+    /// List and revoke client tokens (uses the admin API)
+    #[command(subcommand)]
+    Tokens(TokenCommands),
+
+    /// Create share links that let someone browse one folder (uses the admin API)
+    #[command(subcommand)]
+    Share(ShareCommands),
+
+    /// List live sessions with the CPU and memory their commands use (uses the admin API)
+    #[command(subcommand)]
+    Sessions(SessionCommands),
+
+    /// Manage the master key and encrypt config secrets
+    #[command(subcommand)]
+    Secrets(SecretCommands),
+
+    /// Show connection counts and per-folder and per-session usage (uses the admin API)
+    Stats {
+        /// Print the raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List folders' scheduled tasks and how each last ran (uses the admin API)
+    Schedule {
+        /// Print the raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show a notice to every connected client, e.g. "maintenance in 10 min" (uses the admin API)
+    Broadcast {
+        message: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ApprovalCommands {
+    /// List pending approval requests
+    List,
+
+    /// Let a command run
+    Approve {
+        /// Approval request id
+        id: String,
+    },
+
+    /// Refuse a command
+    Deny {
+        /// Approval request id
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum LockoutCommands {
+    /// List addresses, users and tokens with failed logins
+    List,
+
+    /// Lift a lock
+    Unlock {
+        /// Lockout key, e.g. ip:10.0.0.5, user:alice or token:<fingerprint>
+        key: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommands {
+    /// List tokens clients can authenticate with
+    List,
+
+    /// Revoke a token and close every session it opened
+    Revoke {
+        /// Token id, as shown by `tokens list`
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommands {
+    /// List live sessions, busiest first
+    List {
+        /// Print the raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ShareCommands {
+    /// Create a token for one folder; list and revoke it with `tokens`
+    Create {
+        /// Folder to share
+        #[arg(long)]
+        folder: String,
+
+        /// How long the link works, e.g. 30m, 24h, 7d or seconds
+        #[arg(long, default_value = "24h", value_parser = parse_ttl)]
+        ttl: u64,
+
+        /// Only allow listing and reading files
+        #[arg(long)]
+        read_only: bool,
+
+        /// The link works for a single session
+        #[arg(long)]
+        single_use: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecretCommands {
+    /// Generate a master key
+    Keygen {
+        /// Save the key in the OS keychain instead of printing it
+        #[arg(long)]
+        store: bool,
+    },
+
+    /// Encrypt a value for the config file with the master key
+    Encrypt {
+        /// Value to encrypt; read from stdin when omitted
+        value: Option<String>,
+
+        /// Encrypt a file, such as a TLS key, in place
+        #[arg(long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+    },
+
+    /// Print the hash to put in a [[security.tokens]] entry
+    HashToken {
+        /// Token clients will authenticate with
+        token: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -79,7 +233,7 @@ enum FolderCommands {
         /// Folder path
         path: PathBuf,
 
-        /// Shell type (powershell, cmd, bash, git-bash)
+        /// Shell type (powershell, pwsh, cmd, bash, git-bash, zsh, fish, nu, sh)
         #[arg(long, default_value = "powershell")]
         shell: String,
 
@@ -109,14 +263,21 @@ enum FolderCommands {
 async fn main() {
     let cli = Cli::parse();
 
-    // Initialize logging
-    init_logging(cli.verbose);
-
     // Load configuration
     let config_path = cli.config.unwrap_or_else(|| {
         Config::get_default_config_path().unwrap_or_else(|_| PathBuf::from("fsh_config.toml"))
     });
 
+    // Telemetry export is only for a running server
+    let telemetry = match cli.command {
+        Commands::Start { .. } => start_telemetry(&config_path),
+        _ => None,
+    };
+
+    // Initialize logging
+    let log_format = cli.log_format.unwrap_or_else(|| LoggingConfig::peek(&config_path).format);
+    init_logging(cli.verbose, log_format, telemetry.as_ref());
+
     let result = match cli.command {
         Commands::Start { host, port, foreground } => {
             start_server(config_path, host, port, foreground).await
@@ -133,30 +294,90 @@ async fn main() {
         Commands::Folder(folder_cmd) => {
             handle_folder_command(config_path, folder_cmd).await
         }
+        Commands::Init { force } => {
+            init_config(config_path, force).await
+        }
         Commands::Config { output, force } => {
             generate_config(output.unwrap_or(config_path), force).await
         }
         Commands::Validate => {
             validate_config(config_path).await
         }
+        Commands::Approvals(approval_cmd) => {
+            handle_approval_command(config_path, approval_cmd).await
+        }
+        Commands::Lockouts(lockout_cmd) => {
+            handle_lockout_command(config_path, lockout_cmd).await
+        }
+        Commands::Tokens(token_cmd) => {
+            handle_token_command(config_path, token_cmd).await
+        }
+        Commands::Share(share_cmd) => {
+            handle_share_command(config_path, share_cmd).await
+        }
+        Commands::Sessions(session_cmd) => {
+            handle_session_command(config_path, session_cmd).await
+        }
+        Commands::Secrets(secret_cmd) => {
+            handle_secret_command(secret_cmd)
+        }
+        Commands::Stats { json } => {
+            show_stats(config_path, json).await
+        }
+        Commands::Schedule { json } => {
+            show_schedule(config_path, json).await
+        }
+        Commands::Broadcast { message } => {
+            broadcast(config_path, message).await
+        }
     };
 
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+
     if let Err(e) = result {
         error!("Command failed: {}", e);
         std::process::exit(1);
     }
 }
 
-fn init_logging(verbose: bool) {
+/// Start OTLP export if `[observability]` enables it. Runs before logging is
+/// set up, so problems go to stderr.
+fn start_telemetry(config_path: &Path) -> Option<Telemetry> {
+    let config = if config_path.exists() {
+        match Config::load_from_file(config_path) {
+            Ok(config) => config,
+            Err(_) => return None, // Reported when the server loads the config
+        }
+    } else {
+        return None;
+    };
+    if !config.observability.enabled {
+        return None;
+    }
+
+    match Telemetry::init(&config.observability) {
+        Ok(telemetry) => Some(telemetry),
+        Err(e) => {
+            eprintln!("Telemetry export disabled: {}", e);
+            None
+        }
+    }
+}
+
+fn init_logging(verbose: bool, format: LogFormat, telemetry: Option<&Telemetry>) {
     let level = if verbose { "debug" } else { "info" };
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("fsh={},fsh_server={}", level, level).into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| format!("fsh={},fsh_server={}", level, level).into());
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(telemetry.and_then(Telemetry::tracing_layer));
+    match format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry.with(tracing_subscriber::fmt::layer().json().flatten_event(true)).init(),
+    }
 }
 
 async fn start_server(
@@ -171,13 +392,18 @@ async fn start_server(
     let mut config = if config_path.exists() {
         Config::load_from_file(&config_path)?
     } else {
-        warn!("Configuration file not found at {:?}, using defaults", config_path);
-        Config::default()
+        warn!("Configuration file not found at {:?}, creating a starter configuration", config_path);
+        let mut config = Config::default();
+        let token = add_first_token(&mut config);
+        save_new_config(&config, &config_path)?;
+        print_first_token(&config_path, &token);
+        config
     };
 
     // Apply command line overrides
     if let Some(host) = host_override {
         config.server.host = host;
+        config.server.listen.clear();
     }
     if let Some(port) = port_override {
         config.server.port = port;
@@ -190,22 +416,22 @@ async fn start_server(
     let mut server = FshServer::new(config)?;
 
     info!("FSH server configuration loaded from {:?}", config_path);
-    info!("Starting FSH server on {}:{}", server.config().server.host, server.config().server.port);
+    info!("Starting FSH server on {}", server.config().server.bind_addresses().join(", "));
 
     // Handle Ctrl+C gracefully
-    tokio::select! {
-        result = server.start() => {
-            match result {
-                Ok(_) => info!("FSH server stopped normally"),
-                Err(e) => error!("FSH server error: {}", e),
-            }
-        }
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received Ctrl+C, shutting down...");
-            if let Err(e) = server.stop().await {
-                error!("Error during shutdown: {}", e);
-            }
-        }
+    let shutdown = server.shutdown_token();
+    tokio::spawn(async move {
+        fsh::server::shutdown_signal().await;
+        info!("Received shutdown signal, shutting down...");
+        shutdown.cancel();
+    });
+
+    match server.start().await {
+        Ok(_) => info!("FSH server stopped accepting connections"),
+        Err(e) => error!("FSH server error: {}", e),
+    }
+    if let Err(e) = server.stop().await {
+        error!("Error during shutdown: {}", e);
     }
 
     Ok(())
@@ -252,18 +478,7 @@ async fn handle_folder_command(
         }
 
         FolderCommands::Add { name, path, shell, description, readonly } => {
-            use fsh::protocol::ShellType;
-
-            let shell_type = match shell.to_lowercase().as_str() {
-                "powershell" => ShellType::PowerShell,
-                "cmd" => ShellType::Cmd,
-                "bash" => ShellType::Bash,
-                "git-bash" => ShellType::GitBash,
-                _ => {
-                    error!("Invalid shell type: {}. Valid options: powershell, cmd, bash, git-bash", shell);
-                    return Err("Invalid shell type".into());
-                }
-            };
+            let shell_type = parse_shell_type(&shell)?;
 
             let folder = fsh::config::FolderConfig::new(name.clone(), &path)
                 .with_shell_type(shell_type)
@@ -294,6 +509,12 @@ async fn handle_folder_command(
                 println!("  Shell: {:?}", folder.shell_type);
                 println!("  Permissions: {:?}", folder.permissions);
                 println!("  Read-only: {}", folder.readonly);
+                if let Some(max_sessions) = folder.max_sessions {
+                    println!("  Max sessions: {}", max_sessions);
+                }
+                if let Some(timeout) = folder.session_timeout_minutes {
+                    println!("  Session timeout: {} minutes", timeout);
+                }
                 if let Some(desc) = &folder.description {
                     println!("  Description: {}", desc);
                 }
@@ -317,6 +538,476 @@ async fn handle_folder_command(
     Ok(())
 }
 
+/// Client for the running server's admin API.
+struct AdminApi {
+    base_url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl AdminApi {
+    fn from_config(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config::load_from_file(config_path)?;
+        if !config.admin.enabled {
+            return Err("The admin API is disabled; set admin.enabled = true".into());
+        }
+
+        Ok(Self {
+            base_url: format!("http://{}", config.admin.listen),
+            token: config.admin.token,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.with_token(self.http.get(format!("{}{}", self.base_url, path)))
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.with_token(self.http.post(format!("{}{}", self.base_url, path)))
+    }
+
+    fn with_token(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn parse_shell_type(shell: &str) -> Result<fsh::protocol::ShellType, Box<dyn std::error::Error>> {
+    use fsh::protocol::ShellType;
+
+    Ok(match shell.to_lowercase().as_str() {
+        "powershell" => ShellType::PowerShell,
+        "pwsh" => ShellType::PowerShellCore,
+        "cmd" => ShellType::Cmd,
+        "bash" => ShellType::Bash,
+        "git-bash" => ShellType::GitBash,
+        "zsh" => ShellType::Zsh,
+        "fish" => ShellType::Fish,
+        "nu" | "nushell" => ShellType::Nushell,
+        "sh" => ShellType::Sh,
+        _ => {
+            error!("Invalid shell type: {}. Valid options: powershell, pwsh, cmd, bash, git-bash, zsh, fish, nu, sh", shell);
+            return Err("Invalid shell type".into());
+        }
+    })
+}
+
+async fn handle_approval_command(
+    config_path: PathBuf,
+    approval_cmd: ApprovalCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::ApprovalRequest;
+
+    let admin = AdminApi::from_config(&config_path)?;
+
+    match approval_cmd {
+        ApprovalCommands::List => {
+            let requests: Vec<ApprovalRequest> = admin.get("/approvals")
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+
+            if requests.is_empty() {
+                println!("No pending approvals");
+            }
+            for request in requests {
+                println!("{}  {}  [{}] {} ({})",
+                         request.id,
+                         request.requested_at.format("%Y-%m-%d %H:%M:%S"),
+                         request.folder,
+                         request.command,
+                         request.client_addr);
+            }
+        }
+
+        ApprovalCommands::Approve { ref id } | ApprovalCommands::Deny { ref id } => {
+            let (action, done) = match approval_cmd {
+                ApprovalCommands::Approve { .. } => ("approve", "Approved"),
+                _ => ("deny", "Denied"),
+            };
+            let response = admin.post(&format!("/approvals/{}/{}", id, action))
+                .send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Err(format!("No pending approval '{}'", id).into());
+            }
+            let request: ApprovalRequest = response.error_for_status()?.json().await?;
+            println!("{} '{}'", done, request.command);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_lockout_command(
+    config_path: PathBuf,
+    lockout_cmd: LockoutCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::security::{LockoutKey, LockoutStatus};
+
+    let admin = AdminApi::from_config(&config_path)?;
+
+    match lockout_cmd {
+        LockoutCommands::List => {
+            let statuses: Vec<LockoutStatus> = admin.get("/lockouts")
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+
+            if statuses.is_empty() {
+                println!("No failed logins");
+            }
+            for status in statuses {
+                let state = match status.locked_until {
+                    Some(until) if until > chrono::Utc::now() => format!("locked until {}", until.format("%Y-%m-%d %H:%M:%S")),
+                    _ => "not locked".to_string(),
+                };
+                println!("{}  {} failures, last {}  {}",
+                         status.key,
+                         status.failures,
+                         status.last_failure.format("%Y-%m-%d %H:%M:%S"),
+                         state);
+            }
+        }
+
+        LockoutCommands::Unlock { key } => {
+            // Catch typos before they reach the server
+            let key: LockoutKey = key.parse()?;
+            let response = admin.post(&format!("/lockouts/{}/unlock", key)).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Err(format!("No lockout for '{}'", key).into());
+            }
+            response.error_for_status()?;
+            println!("Unlocked {}", key);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_token_command(
+    config_path: PathBuf,
+    token_cmd: TokenCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::security::TokenSummary;
+    use fsh::server::RevokedToken;
+
+    let admin = AdminApi::from_config(&config_path)?;
+
+    match token_cmd {
+        TokenCommands::List => {
+            let tokens: Vec<TokenSummary> = admin.get("/tokens")
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+
+            if tokens.is_empty() {
+                println!("No tokens");
+            }
+            for token in tokens {
+                let expires = match token.expires_at {
+                    Some(at) => format!("expires {}", at.format("%Y-%m-%d %H:%M:%S")),
+                    None => "never expires".to_string(),
+                };
+                let user = token.user.map(|user| format!("  user {}", user)).unwrap_or_default();
+                println!("{}  {}  {:?}  {}{}", token.id, token.description, token.permissions, expires, user);
+            }
+        }
+
+        TokenCommands::Revoke { id } => {
+            let response = admin.post(&format!("/tokens/{}/revoke", id)).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Err(format!("No token with id '{}'", id).into());
+            }
+            let revoked: RevokedToken = response.error_for_status()?.json().await?;
+            println!("Revoked {} ({}), closed {} session(s)",
+                     revoked.token.id, revoked.token.description, revoked.closed_sessions);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_share_command(
+    config_path: PathBuf,
+    share_cmd: ShareCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::{ShareCreated, ShareRequest};
+
+    let admin = AdminApi::from_config(&config_path)?;
+
+    match share_cmd {
+        ShareCommands::Create { folder, ttl, read_only, single_use } => {
+            let request = ShareRequest { folder: folder.clone(), ttl_seconds: ttl, read_only, single_use };
+            let response = admin.post("/shares").json(&request).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Err(format!("No folder named '{}'", folder).into());
+            }
+            let created: ShareCreated = response.error_for_status()?.json().await?;
+
+            println!("Share link for '{}' ({})", folder, created.share.id);
+            println!("Token: {}", created.token);
+            if let Some(expires_at) = created.share.expires_at {
+                println!("Expires {}", expires_at.format("%Y-%m-%d %H:%M:%S"));
+            }
+            println!("Permissions: {:?}{}", created.share.permissions,
+                     if single_use { ", single use" } else { "" });
+            println!("The token is not shown again; revoke it with `fsh-server tokens revoke {}`", created.share.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a duration such as `90`, `30m`, `24h` or `7d` into seconds.
+fn parse_ttl(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => (&value[..i], unit),
+        _ => (value, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit '{}', use s, m, h or d", unit)),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number * multiplier),
+        _ => Err(format!("Invalid duration '{}'", value)),
+    }
+}
+
+async fn broadcast(config_path: PathBuf, message: String) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::{NoticeRequest, NoticeSent};
+
+    let admin = AdminApi::from_config(&config_path)?;
+    let sent: NoticeSent = admin.post("/notices")
+        .json(&NoticeRequest { message })
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+
+    println!("Notice sent to {} session(s)", sent.delivered);
+    if sent.skipped > 0 {
+        println!("{} session(s) use a client that can't display notices", sent.skipped);
+    }
+    Ok(())
+}
+
+async fn show_stats(config_path: PathBuf, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::ServerStats;
+
+    let admin = AdminApi::from_config(&config_path)?;
+    let stats: ServerStats = admin.get("/stats")
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    if let Some(started_at) = stats.started_at {
+        println!("Up since {} ({})", started_at.format("%Y-%m-%d %H:%M:%S"), format_uptime(stats.uptime_seconds));
+    }
+    if !stats.listen_addresses.is_empty() {
+        println!("Listening on {}", stats.listen_addresses.join(", "));
+    }
+    println!("Connections: {} active of {} (peak {}, {} handshaking), {} accepted, {} rejected, {} failed authentication",
+             stats.active_connections, stats.max_connections, stats.peak_connections, stats.pending_handshakes,
+             stats.accepted_connections, stats.rejected_connections, stats.auth_failed_connections);
+
+    let last_activity = |at: Option<chrono::DateTime<chrono::Utc>>| match at {
+        Some(at) => at.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "never".to_string(),
+    };
+
+    println!();
+    if stats.folders.is_empty() {
+        println!("No folder activity");
+    }
+    for folder in &stats.folders {
+        let usage = &folder.usage;
+        println!("{}  {} sessions, {} commands ({} failed, {:.1}s), {} bytes read, {} written, last active {}",
+                 folder.folder, folder.sessions,
+                 usage.commands_run, usage.command_failures, usage.command_time_ms as f64 / 1000.0,
+                 usage.bytes_read, usage.bytes_written, last_activity(usage.last_activity));
+    }
+
+    println!();
+    if stats.sessions.is_empty() {
+        println!("No active sessions");
+    }
+    for session in &stats.sessions {
+        let usage = &session.usage;
+        println!("{}  [{}] {} since {}, {} commands ({} failed), {} bytes read, {} written, last active {}, CPU {}%, {} memory",
+                 session.session_id, session.folder, session.client_addr,
+                 session.started_at.format("%Y-%m-%d %H:%M:%S"),
+                 usage.commands_run, usage.command_failures,
+                 usage.bytes_read, usage.bytes_written, last_activity(usage.last_activity),
+                 session.resources.cpu_percent, format_bytes(session.resources.memory_bytes));
+    }
+
+    Ok(())
+}
+
+async fn show_schedule(config_path: PathBuf, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::TaskStatus;
+
+    let admin = AdminApi::from_config(&config_path)?;
+    let tasks: Vec<TaskStatus> = admin.get("/schedule")
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&tasks)?);
+        return Ok(());
+    }
+
+    if tasks.is_empty() {
+        println!("No scheduled tasks");
+    }
+    for task in &tasks {
+        let next_run = match task.next_run {
+            Some(at) => at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => "never".to_string(),
+        };
+        let last_run = match &task.last_run {
+            Some(run) => {
+                let outcome = match (run.exit_code, &run.error) {
+                    (Some(code), _) => format!("exit {}", code),
+                    (None, Some(error)) => error.clone(),
+                    (None, None) => "failed".to_string(),
+                };
+                format!("{} ({})", run.started_at.format("%Y-%m-%d %H:%M:%S"), outcome)
+            }
+            None => "not yet run".to_string(),
+        };
+        println!("{}/{}  [{}] {}  next {}, last {}",
+                 task.folder, task.name, task.cron, task.command, next_run, last_run);
+    }
+
+    Ok(())
+}
+
+async fn handle_session_command(
+    config_path: PathBuf,
+    session_cmd: SessionCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::server::SessionStats;
+
+    let admin = AdminApi::from_config(&config_path)?;
+
+    match session_cmd {
+        SessionCommands::List { json } => {
+            let mut sessions: Vec<SessionStats> = admin.get("/sessions")
+                .send().await?
+                .error_for_status()?
+                .json().await?;
+            sessions.sort_by_key(|session| std::cmp::Reverse((session.resources.cpu_percent, session.resources.memory_bytes)));
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&sessions)?);
+                return Ok(());
+            }
+            if sessions.is_empty() {
+                println!("No active sessions");
+            }
+            for session in sessions {
+                let resources = &session.resources;
+                println!("{}  [{}] {}  CPU {}%, {} memory, {} processes, {} commands, since {}",
+                         session.session_id, session.folder, session.client_addr,
+                         resources.cpu_percent, format_bytes(resources.memory_bytes), resources.processes,
+                         session.usage.commands_run, session.started_at.format("%Y-%m-%d %H:%M:%S"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m {}s", minutes, seconds % 60)
+    }
+}
+
+fn handle_secret_command(secret_cmd: SecretCommands) -> Result<(), Box<dyn std::error::Error>> {
+    use fsh::config::{is_encrypted, MasterKey, MASTER_KEY_ENV};
+    use fsh::security::AuthManager;
+    use std::io::Read;
+
+    match secret_cmd {
+        SecretCommands::Keygen { store } => {
+            let key = MasterKey::generate();
+            if store {
+                key.store_in_keychain()?;
+                println!("Master key stored in the OS keychain");
+            } else {
+                println!("{}", key.to_base64());
+                eprintln!("Keep this key safe and pass it to the server in {}", MASTER_KEY_ENV);
+            }
+        }
+
+        SecretCommands::Encrypt { value, file } => {
+            let key = MasterKey::load()?;
+            match file {
+                Some(path) => {
+                    let content = std::fs::read_to_string(&path)?;
+                    if is_encrypted(content.trim()) {
+                        return Err(format!("{} is already encrypted", path.display()).into());
+                    }
+                    std::fs::write(&path, key.encrypt(&content)?)?;
+                    println!("Encrypted {}", path.display());
+                }
+                None => {
+                    let value = match value {
+                        Some(value) => value,
+                        None => {
+                            let mut value = String::new();
+                            std::io::stdin().read_to_string(&mut value)?;
+                            value.trim_end_matches(['\r', '\n']).to_string()
+                        }
+                    };
+                    println!("{}", key.encrypt(&value)?);
+                }
+            }
+        }
+
+        SecretCommands::HashToken { token } => {
+            println!("{}", AuthManager::hash_token(&token));
+        }
+    }
+
+    Ok(())
+}
+
 async fn generate_config(
     output_path: PathBuf,
     force: bool,
@@ -335,6 +1026,84 @@ async fn generate_config(
     Ok(())
 }
 
+async fn init_config(config_path: PathBuf, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if config_path.exists() && !force {
+        error!("Configuration file already exists at {:?}. Use --force to overwrite.", config_path);
+        return Err("File exists".into());
+    }
+
+    println!("Setting up {:?}; press Enter to accept the [default].", config_path);
+    let mut config = Config::default();
+    config.server.host = prompt("Listen address", &config.server.host)?;
+    config.server.port = prompt("Port", &config.server.port.to_string())?
+        .parse()
+        .map_err(|_| "Invalid port")?;
+
+    let folder_path = prompt("Folder to share (empty to skip)", "")?;
+    if !folder_path.is_empty() {
+        let folder_path = PathBuf::from(folder_path);
+        let default_name = folder_path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "default".to_string());
+        let name = prompt("Folder name", &default_name)?;
+        let shell = prompt("Shell", if cfg!(windows) { "powershell" } else { "bash" })?;
+        let readonly = prompt("Read-only (y/n)", "n")?.eq_ignore_ascii_case("y");
+        config.add_folder(
+            fsh::config::FolderConfig::new(name, &folder_path)
+                .with_shell_type(parse_shell_type(&shell)?)
+                .with_readonly(readonly),
+        )?;
+    }
+
+    config.validate()?;
+    let token = add_first_token(&mut config);
+    save_new_config(&config, &config_path)?;
+    print_first_token(&config_path, &token);
+    Ok(())
+}
+
+/// Ask for a value on stdin, falling back to `default` on an empty answer.
+fn prompt(label: &str, default: &str) -> std::io::Result<String> {
+    use std::io::Write;
+
+    if default.is_empty() {
+        print!("{}: ", label);
+    } else {
+        print!("{} [{}]: ", label, default);
+    }
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+}
+
+/// Provision a full-permission client token, returning the token itself.
+fn add_first_token(config: &mut Config) -> String {
+    let (token_config, token) = fsh::security::TokenConfig::generate("Initial token");
+    config.security.tokens.push(token_config);
+    token
+}
+
+fn save_new_config(config: &Config, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    config.save_to_file(path)?;
+    Ok(())
+}
+
+fn print_first_token(config_path: &Path, token: &str) {
+    println!();
+    println!("Created {:?}. Clients authenticate with this token (--token):", config_path);
+    println!();
+    println!("    {}", token);
+    println!();
+    println!("Only its hash is stored, so it won't be shown again. Add more with");
+    println!("`fsh-server secrets hash-token` and [[security.tokens]].");
+    println!();
+}
+
 async fn validate_config(config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     println!("Validating configuration file: {:?}", config_path);
 
@@ -343,13 +1112,22 @@ async fn validate_config(config_path: PathBuf) -> Result<(), Box<dyn std::error:
 
     println!("✓ Configuration is valid");
     println!("Server settings:");
-    println!("  Host: {}", config.server.host);
-    println!("  Port: {}", config.server.port);
+    println!("  Listen: {}", config.server.bind_addresses().join(", "));
     println!("  Max connections: {}", config.server.max_connections);
+    if let Some(per_ip) = config.server.max_connections_per_ip {
+        println!("  Max connections per IP: {}", per_ip);
+    }
+    if let Some(max_running) = config.server.max_running_commands {
+        println!("  Max running commands: {}", max_running);
+    }
 
     println!("Security settings:");
     println!("  Authentication required: {}", config.security.require_authentication);
     println!("  Auth methods: {:?}", config.security.auth_methods);
+    println!("  Provisioned tokens: {}", config.security.tokens.len());
+    if config.security.development_mode {
+        println!("  ⚠ Development mode: the token \"default\" grants full access");
+    }
 
     println!("Configured folders: {}", config.folders.len());
     for folder in &config.folders {
@@ -0,0 +1,281 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::protocol::{FshError, FshResult};
+use crate::security::name_matches;
+use super::FshClient;
+
+/// Port assumed when an `fsh://` address doesn't give one.
+pub const DEFAULT_PORT: u16 = 2222;
+
+/// A path in a folder on a server, written `fsh://host[:port]/folder/path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePath {
+    /// `host:port` to connect to.
+    pub server: String,
+    pub folder: String,
+    /// Path relative to the folder root, `.` for the root itself.
+    pub path: String,
+}
+
+/// One side of a copy, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyLocation {
+    Local(PathBuf),
+    Remote(RemotePath),
+}
+
+impl FromStr for CopyLocation {
+    type Err = FshError;
+
+    fn from_str(location: &str) -> FshResult<Self> {
+        let Some(address) = location.strip_prefix("fsh://") else {
+            return Ok(Self::Local(PathBuf::from(location)));
+        };
+
+        let (server, folder_path) = address.split_once('/').unwrap_or((address, ""));
+        let (folder, path) = folder_path.split_once('/').unwrap_or((folder_path, ""));
+        if server.is_empty() || folder.is_empty() {
+            return Err(FshError::InvalidPath(format!("'{}' is not of the form fsh://server/folder/path", location)));
+        }
+
+        let has_port = server.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        Ok(Self::Remote(RemotePath {
+            server: if has_port { server.to_string() } else { format!("{}:{}", server, DEFAULT_PORT) },
+            folder: folder.to_string(),
+            path: if path.is_empty() { ".".to_string() } else { path.to_string() },
+        }))
+    }
+}
+
+/// Where a copy reads or writes: the local file system, or a folder a
+/// client has a session on. Paths use `/` on both.
+#[derive(Debug)]
+pub enum CopyEndpoint {
+    Local,
+    Remote(Box<FshClient>),
+}
+
+impl CopyEndpoint {
+    /// The paths `pattern` names. `*` and `?` may be used in its last
+    /// component; hidden entries only match patterns starting with `.`.
+    pub async fn expand(&mut self, pattern: &str) -> FshResult<Vec<String>> {
+        let (dir, name) = split_last(pattern);
+        if !name.contains(['*', '?']) {
+            return Ok(vec![pattern.to_string()]);
+        }
+
+        let mut matches: Vec<String> = self.entries(dir).await?.into_iter()
+            .filter(|entry| name_matches(name, entry) && (name.starts_with('.') || !entry.starts_with('.')))
+            .map(|entry| join(dir, &entry))
+            .collect();
+        if matches.is_empty() {
+            return Err(FshError::InvalidPath(format!("No match for '{}'", pattern)));
+        }
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Whether `path` is a directory, or `None` if it can't be found.
+    async fn is_dir(&mut self, path: &str) -> Option<bool> {
+        match self {
+            Self::Local => std::fs::metadata(path).ok().map(|metadata| metadata.is_dir()),
+            Self::Remote(client) => client.stat_file(path).await.ok().map(|stat| stat.entry.is_directory),
+        }
+    }
+
+    /// Names of everything in the directory `path`, hidden entries included.
+    async fn entries(&mut self, path: &str) -> FshResult<Vec<String>> {
+        match self {
+            Self::Local => std::fs::read_dir(path)
+                .map_err(|e| FshError::io(format!("Cannot read directory '{}'", path), e))?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+                .collect(),
+            Self::Remote(client) => Ok(client.list_files(path, true).await?.into_iter().map(|file| file.name).collect()),
+        }
+    }
+
+    async fn create_dir(&mut self, path: &str) -> FshResult<()> {
+        match self {
+            Self::Local => std::fs::create_dir_all(path)
+                .map_err(|e| FshError::io(format!("Cannot create directory '{}'", path), e)),
+            Self::Remote(client) => client.create_dir(path).await,
+        }
+    }
+
+    /// Copy the file `source` here to `target` on `to`, verifying any remote
+    /// side. Returns the number of bytes copied.
+    async fn copy_file(&mut self, source: &str, to: &mut CopyEndpoint, target: &str) -> FshResult<u64> {
+        match (self, to) {
+            (Self::Local, Self::Local) => std::fs::copy(source, target)
+                .map_err(|e| FshError::io(format!("Cannot copy '{}'", source), e)),
+            (Self::Local, Self::Remote(client)) => client.upload_file(Path::new(source), target).await,
+            (Self::Remote(client), Self::Local) => client.download_file(source, Path::new(target)).await,
+            (Self::Remote(client), Self::Remote(destination)) => client.copy_file_to(source, destination, target).await,
+        }
+    }
+}
+
+/// What a copy did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopySummary {
+    pub files: u64,
+    pub directories: u64,
+    pub bytes: u64,
+}
+
+/// Copies files, and directory trees when recursive, between endpoints the
+/// way `cp` does.
+#[derive(Debug, Clone, Default)]
+pub struct Copier {
+    recursive: bool,
+}
+
+impl Copier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Copy what `sources` name on `from` to `destination` on `to`. Several
+    /// sources, or a destination that is an existing directory, are copied
+    /// into it under their own names.
+    pub async fn copy(
+        &self,
+        from: &mut CopyEndpoint,
+        sources: &[String],
+        to: &mut CopyEndpoint,
+        destination: &str,
+    ) -> FshResult<CopySummary> {
+        let mut paths = Vec::new();
+        for source in sources {
+            paths.extend(from.expand(source).await?);
+        }
+
+        let into_dir = to.is_dir(destination).await == Some(true);
+        if paths.len() > 1 && !into_dir {
+            return Err(FshError::InvalidPath(format!("'{}' is not a directory", destination)));
+        }
+
+        let mut summary = CopySummary::default();
+        for path in paths {
+            let target = if into_dir { join(destination, split_last(&path).1) } else { destination.to_string() };
+            self.copy_tree(from, path, to, target, &mut summary).await?;
+        }
+        Ok(summary)
+    }
+
+    async fn copy_tree(
+        &self,
+        from: &mut CopyEndpoint,
+        source: String,
+        to: &mut CopyEndpoint,
+        target: String,
+        summary: &mut CopySummary,
+    ) -> FshResult<()> {
+        let mut pending = vec![(source, target)];
+        while let Some((source, target)) = pending.pop() {
+            match from.is_dir(&source).await {
+                None => return Err(FshError::InvalidPath(format!("'{}' does not exist", source))),
+                Some(true) if !self.recursive => {
+                    return Err(FshError::InvalidPath(format!("'{}' is a directory (copy it recursively)", source)));
+                }
+                Some(true) => {
+                    to.create_dir(&target).await?;
+                    summary.directories += 1;
+                    for name in from.entries(&source).await? {
+                        pending.push((join(&source, &name), join(&target, &name)));
+                    }
+                }
+                Some(false) => {
+                    summary.bytes += from.copy_file(&source, to, &target).await?;
+                    summary.files += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `path` split into its directory (`.` if it has none) and last component.
+fn split_last(path: &str) -> (&str, &str) {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", trimmed),
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "." | "" => name.to_string(),
+        dir => format!("{}/{}", dir.trim_end_matches('/'), name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_locations() {
+        assert_eq!("notes.txt".parse::<CopyLocation>().unwrap(), CopyLocation::Local(PathBuf::from("notes.txt")));
+        assert_eq!(
+            "fsh://lab/docs/guide/intro.md".parse::<CopyLocation>().unwrap(),
+            CopyLocation::Remote(RemotePath {
+                server: "lab:2222".to_string(),
+                folder: "docs".to_string(),
+                path: "guide/intro.md".to_string(),
+            })
+        );
+        let CopyLocation::Remote(root) = "fsh://[::1]:2300/docs".parse::<CopyLocation>().unwrap() else {
+            panic!("expected a remote location");
+        };
+        assert_eq!((root.server.as_str(), root.path.as_str()), ("[::1]:2300", "."));
+        assert!("fsh://lab".parse::<CopyLocation>().is_err());
+        assert!("fsh:///docs/x".parse::<CopyLocation>().is_err());
+    }
+
+    #[test]
+    fn test_path_helpers() {
+        assert_eq!(split_last("a/b/c.txt"), ("a/b", "c.txt"));
+        assert_eq!(split_last("/c.txt"), ("/", "c.txt"));
+        assert_eq!(split_last("dir/"), (".", "dir"));
+        assert_eq!(join(".", "x"), "x");
+        assert_eq!(join("/", "x"), "/x");
+        assert_eq!(join("a/", "x"), "a/x");
+    }
+
+    #[tokio::test]
+    async fn test_local_copies() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        std::fs::create_dir_all(source.path().join("src/nested")).unwrap();
+        std::fs::write(source.path().join("src/a.rs"), "a").unwrap();
+        std::fs::write(source.path().join("src/b.txt"), "b").unwrap();
+        std::fs::write(source.path().join("src/nested/c.rs"), "cc").unwrap();
+        let root = |dir: &TempDir, path: &str| format!("{}/{}", dir.path().display(), path);
+        let (mut from, mut to) = (CopyEndpoint::Local, CopyEndpoint::Local);
+
+        // A glob copies each match into the destination directory
+        let summary = Copier::new().copy(&mut from, &[root(&source, "src/*.rs")], &mut to, &root(&target, "")).await.unwrap();
+        assert_eq!((summary.files, summary.bytes), (1, 1));
+        assert!(target.path().join("a.rs").exists() && !target.path().join("b.txt").exists());
+
+        let directory = [root(&source, "src")];
+        assert!(Copier::new().copy(&mut from, &directory, &mut to, &root(&target, "copy")).await.is_err());
+        let summary = Copier::new().with_recursive(true)
+            .copy(&mut from, &directory, &mut to, &root(&target, "copy")).await.unwrap();
+        assert_eq!(summary, CopySummary { files: 3, directories: 2, bytes: 4 });
+        assert_eq!(std::fs::read(target.path().join("copy/nested/c.rs")).unwrap(), b"cc");
+
+        let sources = [root(&source, "src/a.rs"), root(&source, "src/b.txt")];
+        assert!(Copier::new().copy(&mut from, &sources, &mut to, &root(&target, "missing")).await.is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::protocol::{FshResult, MDNS_SERVICE_TYPE};
+use crate::server::mdns_error;
+
+/// A server found on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    /// The advertised instance name.
+    pub name: String,
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub version: Option<String>,
+    pub tls: bool,
+    /// Empty when the server doesn't advertise its folders.
+    pub folders: Vec<String>,
+}
+
+impl DiscoveredServer {
+    fn from_service(service: &ServiceInfo) -> Self {
+        let mut addresses: Vec<IpAddr> = service.get_addresses().iter().copied().collect();
+        addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+        let suffix = format!(".{}", MDNS_SERVICE_TYPE);
+        Self {
+            name: service.get_fullname().strip_suffix(&suffix).unwrap_or(service.get_fullname()).to_string(),
+            host: service.get_hostname().trim_end_matches('.').to_string(),
+            addresses,
+            port: service.get_port(),
+            version: service.get_property_val_str("version").map(str::to_string),
+            tls: service.get_property_val_str("tls") == Some("1"),
+            folders: service.get_property_val_str("folders")
+                .map(|folders| folders.split(',').filter(|name| !name.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Where to connect, preferring IPv4.
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.addresses.first().map(|address| SocketAddr::new(*address, self.port))
+    }
+}
+
+/// Browse for servers advertised over mDNS for `timeout`.
+pub async fn discover_servers(timeout: Duration) -> FshResult<Vec<DiscoveredServer>> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    let events = daemon.browse(MDNS_SERVICE_TYPE).map_err(mdns_error)?;
+
+    let mut servers = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                servers.insert(service.get_fullname().to_string(), DiscoveredServer::from_service(&service));
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(servers.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_server_from_service() {
+        let properties = HashMap::from([
+            ("version".to_string(), "1.0".to_string()),
+            ("tls".to_string(), "1".to_string()),
+            ("folders".to_string(), "docs,src".to_string()),
+        ]);
+        let service = ServiceInfo::new(
+            MDNS_SERVICE_TYPE, "lab-pi", "lab-pi.local.", "fe80::1,192.168.1.20", 2222, properties,
+        ).unwrap();
+
+        let server = DiscoveredServer::from_service(&service);
+        assert_eq!(server.name, "lab-pi");
+        assert_eq!(server.host, "lab-pi.local");
+        assert_eq!(server.address(), Some("192.168.1.20:2222".parse().unwrap()));
+        assert_eq!(server.version.as_deref(), Some("1.0"));
+        assert!(server.tls);
+        assert_eq!(server.folders, ["docs", "src"]);
+    }
+}
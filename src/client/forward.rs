@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::protocol::{
+    message::*, FshError, FshMessage, FshResult, FEATURE_PORT_FORWARD, FORWARD_CHUNK_SIZE, FORWARD_QUEUE_DEPTH,
+};
+use super::FshClient;
+
+/// What a local connection's task has for the server: data, or `None` at EOF.
+type LocalEvent = (u32, Option<Vec<u8>>);
+
+impl FshClient {
+    /// Forward every connection accepted on `listener` to `port` on the
+    /// server's `host`, like `ssh -L`. Runs until the server closes the
+    /// connection; the folder must list `port` as forwardable.
+    pub async fn forward_local(&mut self, listener: TcpListener, host: &str, port: u16) -> FshResult<()> {
+        let session_id = self.session_id.clone()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_PORT_FORWARD) {
+            return Err(FshError::ProtocolError("The server does not support port forwarding".to_string()));
+        }
+
+        let (events_tx, mut events) = mpsc::channel::<LocalEvent>(FORWARD_QUEUE_DEPTH);
+        // Accepted connections waiting for the server to open their channel
+        let mut pending: HashMap<u32, TcpStream> = HashMap::new();
+        // Where the server's data for each open channel goes
+        let mut channels: HashMap<u32, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut next_channel_id: u32 = 0;
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = accepted
+                        .map_err(|e| FshError::io("Failed to accept a local connection", e))?;
+                    next_channel_id = next_channel_id.wrapping_add(1);
+                    debug!("Forwarding {} as channel {} to {}:{}", peer, next_channel_id, host, port);
+                    self.send_message(FshMessage::PortForwardOpen(PortForwardOpenMessage {
+                        session_id: session_id.clone(),
+                        channel_id: next_channel_id,
+                        host: host.to_string(),
+                        port,
+                    })).await?;
+                    pending.insert(next_channel_id, stream);
+                }
+
+                Some((channel_id, data)) = events.recv() => {
+                    let message = match data {
+                        Some(data) => FshMessage::PortForwardData(PortForwardDataMessage {
+                            session_id: session_id.clone(),
+                            channel_id,
+                            data,
+                        }),
+                        None => FshMessage::PortForwardEof(PortForwardEofMessage {
+                            session_id: session_id.clone(),
+                            channel_id,
+                        }),
+                    };
+                    self.send_message(message).await?;
+                }
+
+                message = self.receive_message() => match message? {
+                    FshMessage::PortForwardOpenResponse(response) => {
+                        let Some(stream) = pending.remove(&response.channel_id) else {
+                            continue;
+                        };
+                        if !response.success {
+                            warn!(
+                                "Server refused to forward to {}:{}: {}",
+                                host, port, response.error_message.unwrap_or_default()
+                            );
+                            continue;
+                        }
+                        let (input_tx, input) = mpsc::channel(FORWARD_QUEUE_DEPTH);
+                        channels.insert(response.channel_id, input_tx);
+                        tokio::spawn(pump_local(response.channel_id, stream, input, events_tx.clone()));
+                    }
+                    FshMessage::PortForwardData(data_msg) => {
+                        if let Some(input) = channels.get(&data_msg.channel_id) {
+                            if input.send(data_msg.data).await.is_err() {
+                                channels.remove(&data_msg.channel_id);
+                            }
+                        }
+                    }
+                    FshMessage::PortForwardEof(eof_msg) => {
+                        // The local connection's write side is shut down once queued data is written
+                        channels.remove(&eof_msg.channel_id);
+                    }
+                    FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                    FshMessage::Disconnect(disconnect_msg) => {
+                        info!("Server closed the connection: {}", disconnect_msg.reason);
+                        return Ok(());
+                    }
+                    other => debug!("Ignoring {:?} while forwarding ports", other.message_type()),
+                },
+            }
+        }
+    }
+}
+
+/// Carry one local connection: data from the server is written to it, and
+/// what it sends is passed on as events until EOF.
+async fn pump_local(
+    channel_id: u32,
+    stream: TcpStream,
+    mut input: mpsc::Receiver<Vec<u8>>,
+    events: mpsc::Sender<LocalEvent>,
+) {
+    let (mut read_half, mut write_half) = stream.into_split();
+    let upstream = async {
+        let mut buffer = vec![0u8; FORWARD_CHUNK_SIZE];
+        loop {
+            match read_half.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if events.send((channel_id, Some(buffer[..read].to_vec()))).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = events.send((channel_id, None)).await;
+    };
+    let downstream = async {
+        while let Some(data) = input.recv().await {
+            if write_half.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        input.close();
+        let _ = write_half.shutdown().await;
+    };
+    tokio::join!(upstream, downstream);
+}
@@ -0,0 +1,248 @@
+use reedline::{
+    default_emacs_keybindings, ColumnarMenu, Completer, Emacs, ExternalPrinter, FileBackedHistory, History,
+    HistoryItem, KeyCode, KeyModifiers, MenuBuilder, Prompt, PromptEditMode, PromptHistorySearch,
+    PromptHistorySearchStatus, Reedline, ReedlineEvent, ReedlineMenu, Signal, Span, Suggestion,
+};
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Commands the line editor remembers, including those recalled from the
+/// server.
+const HISTORY_CAPACITY: usize = 1000;
+
+/// Lines waiting to be printed above the prompt.
+const PRINTER_CAPACITY: usize = 1024;
+
+/// How long Tab waits for the terminal to list remote files before giving
+/// up on completing.
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The menu asks for the same completions several times per keypress;
+/// answers this recent are reused rather than listing files again.
+const COMPLETION_REUSE: Duration = Duration::from_secs(1);
+
+const COMPLETION_MENU: &str = "completion_menu";
+
+/// What the user did at the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadLine {
+    Line(String),
+    /// Ctrl+C, or Ctrl+D on an empty line.
+    Exit,
+}
+
+/// A line editor with word-wise movement, a kill buffer, history search and
+/// completion, run by reedline on a thread of its own since it blocks while
+/// reading. The terminal stays in raw mode around it, so command output
+/// passes through untouched between prompts.
+pub struct LineEditor {
+    reads: std::sync::mpsc::Sender<ReadRequest>,
+    printer: ExternalPrinter<String>,
+    completions: mpsc::UnboundedReceiver<CompletionRequest>,
+}
+
+struct ReadRequest {
+    prompt: String,
+    reply: oneshot::Sender<std::io::Result<Signal>>,
+}
+
+/// Tab was pressed: the editor waits for what could replace the word
+/// before the cursor.
+#[derive(Debug)]
+pub struct CompletionRequest {
+    /// The line up to the cursor.
+    line: String,
+    reply: std::sync::mpsc::Sender<Vec<String>>,
+}
+
+impl CompletionRequest {
+    /// The word being completed.
+    pub fn word(&self) -> &str {
+        &self.line[word_start(&self.line)..]
+    }
+
+    /// Whether the word is the command name rather than an argument.
+    pub fn is_command(&self) -> bool {
+        self.line[..word_start(&self.line)].trim().is_empty()
+    }
+
+    /// Answer with replacements for the word; ones ending in `/` are
+    /// directories and aren't followed by a space.
+    pub fn respond(self, candidates: Vec<String>) {
+        let _ = self.reply.send(candidates);
+    }
+}
+
+impl LineEditor {
+    /// Start an editor whose ↑ and Ctrl+R recall `history`, oldest first.
+    pub fn new(history: &[String]) -> FshResult<Self> {
+        let (reads, requests) = std::sync::mpsc::channel::<ReadRequest>();
+        let (completion_tx, completions) = mpsc::unbounded_channel();
+        let printer = ExternalPrinter::new(PRINTER_CAPACITY);
+
+        let mut recalled = FileBackedHistory::new(HISTORY_CAPACITY)
+            .map_err(|e| FshError::ShellError(format!("Cannot set up command history: {}", e)))?;
+        for command in history {
+            if let Err(e) = recalled.save(HistoryItem::from_command_line(command.as_str())) {
+                debug!("Failed to recall {}: {}", command, e);
+            }
+        }
+
+        let mut keybindings = default_emacs_keybindings();
+        keybindings.add_binding(
+            KeyModifiers::NONE,
+            KeyCode::Tab,
+            ReedlineEvent::UntilFound(vec![ReedlineEvent::Menu(COMPLETION_MENU.to_string()), ReedlineEvent::MenuNext]),
+        );
+        let editor_printer = printer.clone();
+
+        std::thread::Builder::new()
+            .name("fsh-line-editor".to_string())
+            .spawn(move || {
+                let mut editor = Reedline::create()
+                    .with_history(Box::new(recalled))
+                    .with_completer(Box::new(RemoteCompleter { requests: completion_tx, last: None }))
+                    .with_menu(ReedlineMenu::EngineCompleter(Box::new(ColumnarMenu::default().with_name(COMPLETION_MENU))))
+                    .with_edit_mode(Box::new(Emacs::new(keybindings)))
+                    .with_external_printer(editor_printer);
+                while let Ok(request) = requests.recv() {
+                    let signal = editor.read_line(&RemotePrompt(request.prompt));
+                    let _ = request.reply.send(signal);
+                }
+            })
+            .map_err(|e| FshError::io("Cannot start the line editor", e))?;
+
+        Ok(Self { reads, printer, completions })
+    }
+
+    /// Read a line after showing `prompt`. Completion requests arrive on
+    /// [`next_completion`](Self::next_completion) meanwhile.
+    pub fn read_line(&self, prompt: &str) -> PendingLine {
+        let (reply, line) = oneshot::channel();
+        if self.reads.send(ReadRequest { prompt: prompt.to_string(), reply }).is_err() {
+            debug!("The line editor has stopped");
+        }
+        PendingLine(line)
+    }
+
+    /// The next time Tab is pressed.
+    pub async fn next_completion(&mut self) -> Option<CompletionRequest> {
+        self.completions.recv().await
+    }
+
+    /// Print `text` above the prompt while a line is being read, or before
+    /// the next prompt otherwise.
+    pub fn print(&self, text: String) {
+        if let Err(e) = self.printer.sender().try_send(text) {
+            debug!("Dropped output for the prompt: {}", e);
+        }
+    }
+}
+
+/// A line being read.
+pub struct PendingLine(oneshot::Receiver<std::io::Result<Signal>>);
+
+impl std::future::Future for PendingLine {
+    type Output = FshResult<ReadLine>;
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        std::pin::Pin::new(&mut self.0).poll(cx).map(|signal| match signal {
+            Ok(Ok(Signal::Success(line))) => Ok(ReadLine::Line(line)),
+            Ok(Ok(_)) => Ok(ReadLine::Exit),
+            Ok(Err(e)) => Err(FshError::io("Input error", e)),
+            Err(_) => Err(FshError::ShellError("The line editor has stopped".to_string())),
+        })
+    }
+}
+
+struct RemotePrompt(String);
+
+impl Prompt for RemotePrompt {
+    fn render_prompt_left(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn render_prompt_right(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_indicator(&self, _prompt_mode: PromptEditMode) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
+        Cow::Borrowed("... ")
+    }
+
+    fn render_prompt_history_search_indicator(&self, search: PromptHistorySearch) -> Cow<'_, str> {
+        let failing = match search.status {
+            PromptHistorySearchStatus::Passing => "",
+            PromptHistorySearchStatus::Failing => "failing ",
+        };
+        Cow::Owned(format!("({}reverse-search: {}) ", failing, search.term))
+    }
+}
+
+/// Asks the terminal, which owns the connection, for completions.
+struct RemoteCompleter {
+    requests: mpsc::UnboundedSender<CompletionRequest>,
+    last: Option<(Instant, String, Vec<Suggestion>)>,
+}
+
+impl Completer for RemoteCompleter {
+    fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
+        let line = &line[..pos];
+        if let Some((answered, last_line, suggestions)) = &self.last {
+            if last_line == line && answered.elapsed() < COMPLETION_REUSE {
+                return suggestions.clone();
+            }
+        }
+
+        let (reply, candidates) = std::sync::mpsc::channel();
+        if self.requests.send(CompletionRequest { line: line.to_string(), reply }).is_err() {
+            return Vec::new();
+        }
+        let span = Span::new(word_start(line), pos);
+        let suggestions: Vec<Suggestion> = candidates.recv_timeout(COMPLETION_TIMEOUT).unwrap_or_default()
+            .into_iter()
+            .map(|value| Suggestion { append_whitespace: !value.ends_with('/'), value, span, ..Default::default() })
+            .collect();
+        self.last = Some((Instant::now(), line.to_string(), suggestions.clone()));
+        suggestions
+    }
+}
+
+/// Where the word ending at the end of `line` starts.
+fn word_start(line: &str) -> usize {
+    line.char_indices()
+        .rev()
+        .find(|(_, c)| c.is_whitespace())
+        .map_or(0, |(index, c)| index + c.len_utf8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str) -> CompletionRequest {
+        CompletionRequest { line: line.to_string(), reply: std::sync::mpsc::channel().0 }
+    }
+
+    #[test]
+    fn test_completion_word() {
+        assert_eq!(request("gi").word(), "gi");
+        assert!(request("  gi").is_command());
+
+        let argument = request("cat src/ma");
+        assert_eq!(argument.word(), "src/ma");
+        assert!(!argument.is_command());
+
+        // Multi-byte characters before the word
+        assert_eq!(request("cat größe\u{3000}no").word(), "no");
+        assert_eq!(request("ls ").word(), "");
+    }
+}
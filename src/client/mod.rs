@@ -1,23 +1,75 @@
+pub mod copy;
+pub mod discovery;
+mod forward;
+pub mod line_editor;
+pub mod remote_fs;
+pub mod render;
 pub mod terminal;
 
+pub use copy::*;
+pub use discovery::*;
+pub use line_editor::*;
+pub use remote_fs::*;
+pub use render::*;
 pub use terminal::*;
 
 use crate::protocol::{
-    FshMessage, FshCodec, FshError, FshResult, FSH_VERSION, ClientInfo,
-    message::*,
+    FshMessage, FshCodec, FshFramed, FshError, FshErrorCode, FshResult, FSH_VERSION, ClientInfo,
+    Capabilities, CompressionAlgorithm, ProtocolVersion, CORE_FEATURES, FEATURE_FRAGMENTATION, FEATURE_GIT,
+    FEATURE_COLLABORATE, FEATURE_ENV, FEATURE_FOLDER_ROOTS, FEATURE_FOLDER_SWITCH, FEATURE_HISTORY, FEATURE_IDLE_LOCK, FEATURE_MKDIR, FEATURE_NOISE, FEATURE_OBSERVE, FEATURE_OPERATION_IDS, FEATURE_PORT_FORWARD, FEATURE_SERVER_NOTICE, FEATURE_SESSION_INFO, FEATURE_SESSION_RESUME, FEATURE_USER_FOLDERS, FEATURE_WATCH_RUNS, message::*,
+    Checksum, ChecksumAlgorithm, Framing, FshStream, wire_revision_features, FshTransport, TcpTransport, CHECKSUM_MISMATCH, MAX_FILE_CHUNK_SIZE,
 };
-use std::collections::HashMap;
-use tokio::net::TcpStream;
+use crate::security::{NoiseClientConfig, TlsClientConfig};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::path::Path;
+use bytes::Bytes;
+use futures::FutureExt;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{info, error, debug, warn};
 
+/// Bytes requested per chunk when transferring files.
+const TRANSFER_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Times a chunk that fails checksum verification is resent before giving up.
+const MAX_CHUNK_RETRIES: usize = 3;
+
 #[derive(Debug)]
 pub struct FshClient {
-    stream: Option<TcpStream>,
+    stream: Option<FshFramed<FshStream>>,
     server_addr: String,
+    transport: Arc<dyn FshTransport>,
+    tls: Option<TlsClientConfig>,
+    noise: Option<NoiseClientConfig>,
     client_info: ClientInfo,
     session_id: Option<String>,
+    /// The session this client had before it last disconnected.
+    previous_session_id: Option<String>,
     connected: bool,
+    compression: Vec<CompressionAlgorithm>,
+    framing: Framing,
+    capabilities: Capabilities,
+    /// Folders the server offers, updated once the client has logged in.
+    available_folders: Vec<String>,
+    session_environment: HashMap<String, String>,
+    working_directory: Option<String>,
+    shell_prompt: Option<String>,
+    /// Background jobs started on this connection that have not completed.
+    running_jobs: BTreeSet<u32>,
+    /// Job output received while waiting for other replies.
+    job_output: VecDeque<JobOutput>,
+    /// Operator notices and idle warnings not yet shown.
+    notices: VecDeque<ServerNoticeMessage>,
+    /// The server locked the session for inactivity; see [`unlock`](Self::unlock).
+    locked: bool,
+    /// Runs of the folder's watch rules not yet shown.
+    watch_runs: VecDeque<WatchRunMessage>,
+    /// What happened in an observed or shared session, not yet shown.
+    observed: VecDeque<ObservedEvent>,
+    /// A change sent without getting a reply, kept across reconnects so it
+    /// can be resent under the same id.
+    pending_operation: Option<OperationMessage>,
 }
 
 impl FshClient {
@@ -31,30 +83,133 @@ impl FshClient {
         Self {
             stream: None,
             server_addr,
+            transport: Arc::new(TcpTransport),
+            tls: None,
+            noise: None,
             client_info,
             session_id: None,
+            previous_session_id: None,
             connected: false,
+            compression: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Deflate],
+            framing: Framing::Binary,
+            capabilities: Capabilities::default(),
+            available_folders: Vec::new(),
+            session_environment: HashMap::new(),
+            working_directory: None,
+            shell_prompt: None,
+            running_jobs: BTreeSet::new(),
+            job_output: VecDeque::new(),
+            notices: VecDeque::new(),
+            locked: false,
+            watch_runs: VecDeque::new(),
+            observed: VecDeque::new(),
+            pending_operation: None,
         }
     }
 
+    /// Set the compression algorithms to offer the server, most preferred first.
+    /// An empty list disables compression for this connection.
+    pub fn with_compression(mut self, algorithms: Vec<CompressionAlgorithm>) -> Self {
+        self.compression = algorithms;
+        self
+    }
+
+    /// Frame messages this way, starting with Connect. JSON frames are never
+    /// compressed.
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Open connections with `transport` instead of TCP.
+    pub fn with_transport(mut self, transport: Arc<dyn FshTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Connect over TLS, presenting a client certificate if one is configured.
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Encrypt connections with Noise, refusing servers that don't offer it
+    /// or present another key.
+    pub fn with_noise(mut self, noise: NoiseClientConfig) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// Whether this client presents a certificate it can authenticate with.
+    pub fn has_client_cert(&self) -> bool {
+        self.tls.as_ref().is_some_and(TlsClientConfig::has_client_cert)
+    }
+
+    /// The compression algorithm negotiated for the current connection, if any.
+    pub fn compression(&self) -> Option<CompressionAlgorithm> {
+        self.stream.as_ref().and_then(|stream| stream.codec().compression())
+    }
+
+    /// The wire revision frames are written in on the current connection.
+    pub fn wire_revision(&self) -> Option<u8> {
+        self.stream.as_ref().map(|stream| stream.codec().revision())
+    }
+
+    /// Features enabled for the current connection, as confirmed by the server.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     pub async fn connect(&mut self) -> FshResult<()> {
         info!("Connecting to FSH server at {}", self.server_addr);
 
-        // Establish TCP connection
-        let stream = TcpStream::connect(&self.server_addr).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to connect to {}: {}", self.server_addr, e)))?;
+        let stream = self.transport.connect(&self.server_addr).await?;
 
-        self.stream = Some(stream);
+        let stream: FshStream = match &self.tls {
+            Some(tls) => {
+                let (connector, server_name) = tls.connector(&self.server_addr)?;
+                let stream = connector.connect(server_name, stream).await
+                    .map_err(|e| FshError::io(format!("TLS handshake with {} failed", self.server_addr), e))?;
+                tokio_rustls::TlsStream::from(stream).into()
+            }
+            None => stream,
+        };
+
+        let mut framed = FshCodec::framed(stream);
+        framed.codec_mut().set_framing(self.framing);
+        self.stream = Some(framed);
 
         // Send connect message
+        let mut supported_features: Vec<String> = CORE_FEATURES.iter().map(|f| f.to_string()).collect();
+        supported_features.push(FEATURE_GIT.to_string());
+        supported_features.push(FEATURE_PORT_FORWARD.to_string());
+        supported_features.push(FEATURE_FRAGMENTATION.to_string());
+        supported_features.push(FEATURE_SERVER_NOTICE.to_string());
+        supported_features.push(FEATURE_ENV.to_string());
+        supported_features.push(FEATURE_HISTORY.to_string());
+        supported_features.push(FEATURE_OBSERVE.to_string());
+        supported_features.push(FEATURE_COLLABORATE.to_string());
+        supported_features.push(FEATURE_FOLDER_SWITCH.to_string());
+        supported_features.push(FEATURE_FOLDER_ROOTS.to_string());
+        supported_features.push(FEATURE_MKDIR.to_string());
+        supported_features.push(FEATURE_OPERATION_IDS.to_string());
+        supported_features.push(FEATURE_IDLE_LOCK.to_string());
+        supported_features.push(FEATURE_SESSION_RESUME.to_string());
+        supported_features.push(FEATURE_WATCH_RUNS.to_string());
+        supported_features.push(FEATURE_USER_FOLDERS.to_string());
+        supported_features.push(FEATURE_SESSION_INFO.to_string());
+        if self.noise.is_some() {
+            supported_features.push(FEATURE_NOISE.to_string());
+        }
+        supported_features.extend(wire_revision_features());
+        if self.framing == Framing::Binary {
+            supported_features.extend(self.compression.iter().map(|algorithm| algorithm.feature()));
+        }
+
         let connect_msg = FshMessage::Connect(ConnectMessage {
             version: FSH_VERSION.to_string(),
             client_info: self.client_info.clone(),
-            supported_features: vec![
-                "folder_binding".to_string(),
-                "file_operations".to_string(),
-                "command_execution".to_string(),
-            ],
+            supported_features,
         });
 
         self.send_message(connect_msg).await?;
@@ -65,9 +220,42 @@ impl FshClient {
         match response {
             FshMessage::ConnectResponse(resp) => {
                 if resp.success {
+                    let server_version = ProtocolVersion::parse(&resp.server_version)?;
+                    if !server_version.is_compatible_with(&ProtocolVersion::current()) {
+                        error!("Server speaks incompatible protocol version {}", server_version);
+                        return Err(FshError::ProtocolError(format!(
+                            "Incompatible server protocol version: {}", server_version
+                        )));
+                    }
+
                     info!("Connected to FSH server (version {})", resp.server_version);
                     debug!("Server features: {:?}", resp.supported_features);
                     debug!("Available folders: {:?}", resp.available_folders);
+                    self.available_folders = resp.available_folders;
+
+                    // The server echoes back only features we both support
+                    self.capabilities = Capabilities::from_features(resp.supported_features);
+                    let compression = self.capabilities.compression()
+                        .filter(|algorithm| self.compression.contains(algorithm));
+                    let fragmentation = self.capabilities.supports(FEATURE_FRAGMENTATION);
+                    if let Some(stream) = self.stream.as_mut() {
+                        stream.codec_mut().set_revision(self.capabilities.wire_revision());
+                        stream.codec_mut().set_compression(compression);
+                        stream.codec_mut().set_fragmentation(fragmentation);
+                    }
+
+                    if let Some(noise) = &self.noise {
+                        if !self.capabilities.supports(FEATURE_NOISE) {
+                            return Err(FshError::PermissionDenied(
+                                "Server does not offer Noise encryption".to_string()
+                            ));
+                        }
+                        let stream = self.stream.take()
+                            .ok_or_else(|| FshError::NetworkError("Not connected to server".to_string()))?;
+                        self.stream = Some(noise.connect(stream).await?);
+                        debug!("Noise channel established");
+                    }
+
                     self.connected = true;
                     Ok(())
                 } else {
@@ -104,6 +292,15 @@ impl FshClient {
             FshMessage::AuthResponse(resp) => {
                 if resp.success {
                     info!("Authentication successful");
+                    if self.capabilities.supports(FEATURE_USER_FOLDERS) {
+                        match self.receive_message().await? {
+                            FshMessage::FolderList(list) => {
+                                debug!("Available folders: {:?}", list.folders);
+                                self.available_folders = list.folders;
+                            }
+                            _ => return Err(FshError::ProtocolError("Expected the folder list".to_string())),
+                        }
+                    }
                     Ok(())
                 } else {
                     let error_msg = resp.message.unwrap_or_else(|| "Authentication failed".to_string());
@@ -118,6 +315,11 @@ impl FshClient {
         }
     }
 
+    /// Log in with the client certificate presented during the TLS handshake.
+    pub async fn authenticate_with_certificate(&mut self) -> FshResult<()> {
+        self.authenticate("certificate", HashMap::new()).await
+    }
+
     pub async fn bind_folder(&mut self, folder_name: &str, preferred_shell: Option<crate::protocol::ShellType>) -> FshResult<crate::protocol::FolderInfo> {
         if !self.connected {
             return Err(FshError::NetworkError("Not connected to server".to_string()));
@@ -161,6 +363,42 @@ impl FshClient {
         }
     }
 
+    /// Instead of binding a folder, resume the session the server saved as
+    /// `session_id` before it restarted. Like [`bind_folder`](Self::bind_folder)
+    /// it is followed by [`wait_for_session_ready`](Self::wait_for_session_ready);
+    /// if the session can't be resumed, a folder may still be bound.
+    pub async fn resume_session(&mut self, session_id: &str, preferred_shell: Option<crate::protocol::ShellType>) -> FshResult<crate::protocol::FolderInfo> {
+        if !self.connected {
+            return Err(FshError::NetworkError("Not connected to server".to_string()));
+        }
+        if !self.capabilities.supports(FEATURE_SESSION_RESUME) {
+            return Err(FshError::ProtocolError("The server does not resume sessions".to_string()));
+        }
+
+        info!("Resuming session {}", session_id);
+        self.send_message(FshMessage::SessionResume(SessionResumeMessage {
+            session_id: session_id.to_string(),
+            preferred_shell,
+        })).await?;
+
+        match self.receive_message().await? {
+            FshMessage::FolderBound(resp) if resp.success => {
+                resp.folder_info.ok_or_else(|| FshError::ProtocolError("Missing folder info".to_string()))
+            }
+            FshMessage::FolderBound(resp) => {
+                let error_msg = resp.error_message.unwrap_or_else(|| format!("Session {} can't be resumed", session_id));
+                Err(FshError::SessionNotFound(error_msg))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to resume request".to_string())),
+        }
+    }
+
+    /// The session this client had before it last disconnected, which
+    /// [`resume_session`](Self::resume_session) may pick up again.
+    pub fn previous_session_id(&self) -> Option<&str> {
+        self.previous_session_id.as_deref()
+    }
+
     pub async fn wait_for_session_ready(&mut self) -> FshResult<(String, String)> {
         // Wait for session start message
         let response = self.receive_message().await?;
@@ -169,6 +407,7 @@ impl FshClient {
             FshMessage::SessionStart(session_start) => {
                 self.session_id = Some(session_start.session_id.clone());
                 debug!("Session started: {}", session_start.session_id);
+                self.session_environment = session_start.environment_vars;
 
                 // Wait for session ready message
                 let response = self.receive_message().await?;
@@ -176,6 +415,8 @@ impl FshClient {
                 match response {
                     FshMessage::SessionReady(session_ready) => {
                         info!("Session ready: {}", session_ready.session_id);
+                        self.shell_prompt = Some(session_ready.shell_prompt.clone());
+                        self.working_directory = Some(session_ready.working_directory.clone());
                         Ok((session_ready.shell_prompt, session_ready.working_directory))
                     }
                     _ => {
@@ -191,6 +432,68 @@ impl FshClient {
         }
     }
 
+    /// Move the session to another folder without reconnecting. Its shell
+    /// starts over in the new folder; background jobs, forwarded ports and
+    /// followed files from the old one are stopped.
+    pub async fn switch_folder(
+        &mut self,
+        folder_name: &str,
+        preferred_shell: Option<crate::protocol::ShellType>,
+    ) -> FshResult<crate::protocol::FolderInfo> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_FOLDER_SWITCH) {
+            return Err(FshError::ProtocolError("The server does not support switching folders".to_string()));
+        }
+
+        info!("Switching to folder: {}", folder_name);
+        let switch_msg = FshMessage::FolderSwitch(FolderSwitchMessage {
+            session_id: session_id.clone(),
+            target_folder: folder_name.to_string(),
+            preferred_shell,
+        });
+        self.send_message(switch_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::FolderSwitched(resp) => {
+                    self.shell_prompt = Some(resp.shell_prompt);
+                    self.working_directory = Some(resp.working_directory);
+                    self.session_environment = resp.environment_vars;
+                    return Ok(resp.folder_info);
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to folder switch".to_string())),
+            }
+        }
+    }
+
+    /// Bind another folder into the session next to its own. Its files are
+    /// reachable under the returned root, e.g. `/docs`, with that folder's
+    /// permissions.
+    pub async fn bind_root(&mut self, folder_name: &str) -> FshResult<RootBoundMessage> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_FOLDER_ROOTS) {
+            return Err(FshError::ProtocolError("The server does not support binding more folders".to_string()));
+        }
+
+        info!("Binding folder: {}", folder_name);
+        let bind_msg = FshMessage::RootBind(RootBindMessage {
+            session_id: session_id.clone(),
+            folder: folder_name.to_string(),
+        });
+        self.send_message(bind_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::RootBound(resp) => return Ok(resp),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to folder bind".to_string())),
+            }
+        }
+    }
+
     pub async fn execute_command(&mut self, command: &str, args: Vec<String>) -> FshResult<mpsc::Receiver<CommandOutput>> {
         let session_id = self.session_id.as_ref()
             .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
@@ -202,130 +505,1292 @@ impl FshClient {
             command: command.to_string(),
             args,
             environment: None,
+            job_id: None,
         });
 
         self.send_message(cmd_msg).await?;
 
-        let (tx, rx) = mpsc::channel(100);
+        // The connection is not multiplexed, so read the command's replies up to
+        // CommandComplete here and hand them back through the channel
+        let mut outputs = Vec::new();
+        loop {
+            match self.receive_message().await {
+                Ok(FshMessage::CommandOutput(output)) => {
+                    outputs.push(CommandOutput::received(output.output_type, output.data, output.binary));
+                }
+                Ok(FshMessage::WorkingDirChanged(changed)) => {
+                    debug!("Working directory changed to {}", changed.working_directory);
+                    self.shell_prompt = Some(changed.shell_prompt);
+                    self.working_directory = Some(changed.working_directory);
+                }
+                Ok(FshMessage::CommandComplete(complete)) => {
+                    self.working_directory = Some(complete.working_directory);
+                    outputs.push(CommandOutput {
+                        output_type: CommandOutputType::Complete,
+                        data: format!("Exit code {} ({} ms)", complete.exit_code, complete.execution_time_ms),
+                        raw: None,
+                    });
+                    break;
+                }
+                Ok(FshMessage::Ping) => self.send_message(FshMessage::Pong).await?,
+                Ok(other) => {
+                    debug!("Ignoring {:?} while waiting for command output", other.message_type());
+                }
+                Err(e @ (FshError::NetworkError(_) | FshError::Io { .. })) => return Err(e),
+                Err(e) => {
+                    outputs.push(CommandOutput {
+                        output_type: CommandOutputType::Error,
+                        data: e.to_string(),
+                        raw: None,
+                    });
+                    break;
+                }
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(outputs.len());
+        for output in outputs {
+            // Capacity matches the number of outputs, so this never waits
+            let _ = tx.try_send(output);
+        }
+
+        Ok(rx)
+    }
 
-        // For simplicity, we'll handle responses synchronously in the main loop
-        // This is a simplified version - in production you'd want async message handling
-        tokio::spawn(async move {
-            // Simulate command completion for now
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    /// Start a background job and return its id. Its output is collected with
+    /// [`next_job_output`](Self::next_job_output).
+    pub async fn start_job(&mut self, command: &str, args: Vec<String>) -> FshResult<u32> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
 
-            let cmd_output = CommandOutput {
-                output_type: CommandOutputType::Complete,
-                data: "Command executed (simplified implementation)".to_string(),
-            };
+        // Like a shell, reuse the lowest free job number
+        let job_id = (1..).find(|id| !self.running_jobs.contains(id)).unwrap_or(1);
+        debug!("Starting job %{}: {} {:?}", job_id, command, args);
 
-            let _ = tx.send(cmd_output).await;
+        let cmd_msg = FshMessage::Command(CommandMessage {
+            session_id: session_id.clone(),
+            command: command.to_string(),
+            args,
+            environment: None,
+            job_id: Some(job_id),
         });
 
-        Ok(rx)
+        self.send_message(cmd_msg).await?;
+        self.running_jobs.insert(job_id);
+
+        Ok(job_id)
     }
 
-    pub async fn list_files(&mut self, path: &str, show_hidden: bool) -> FshResult<Vec<FileEntry>> {
+    /// Ids of background jobs that have not completed yet.
+    pub fn running_jobs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.running_jobs.iter().copied()
+    }
+
+    /// Job output that has already arrived, without waiting for more.
+    pub fn take_job_output(&mut self) -> Option<JobOutput> {
+        self.job_output.pop_front()
+    }
+
+    /// Folders the server offers this client; with users configured, only
+    /// those of the user it logged in as.
+    pub fn available_folders(&self) -> &[String] {
+        &self.available_folders
+    }
+
+    /// An operator notice that has already arrived, without waiting for more.
+    pub fn take_notice(&mut self) -> Option<ServerNoticeMessage> {
+        self.notices.pop_front()
+    }
+
+    /// The outcome of a watch rule's run that has already arrived, without
+    /// waiting for more.
+    pub fn take_watch_run(&mut self) -> Option<WatchRunMessage> {
+        self.watch_runs.pop_front()
+    }
+
+    /// Whether the server locked the session for inactivity. Until it is
+    /// unlocked, everything but [`unlock`](Self::unlock) is refused.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Unlock a session the server locked for inactivity by entering the
+    /// token it authenticated with again.
+    pub async fn unlock(&mut self, token: &str) -> FshResult<()> {
         let session_id = self.session_id.as_ref()
             .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
 
-        let list_msg = FshMessage::FileList(FileListMessage {
+        let unlock_msg = FshMessage::SessionUnlock(SessionUnlockMessage {
             session_id: session_id.clone(),
-            path: path.to_string(),
-            show_hidden,
+            token: token.to_string(),
         });
+        self.send_message(unlock_msg).await?;
 
-        self.send_message(list_msg).await?;
+        loop {
+            match self.receive_message().await? {
+                FshMessage::SessionUnlockResponse(resp) if resp.success => {
+                    self.locked = false;
+                    return Ok(());
+                }
+                FshMessage::SessionUnlockResponse(resp) => {
+                    return Err(FshError::PermissionDenied(
+                        resp.error_message.unwrap_or_else(|| "Wrong token".to_string()),
+                    ));
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to unlock".to_string())),
+            }
+        }
+    }
 
-        // Wait for response
-        let response = self.receive_message().await?;
+    /// Something that happened in a shared session that has already
+    /// arrived, without waiting for more.
+    pub fn take_observed(&mut self) -> Option<ObservedEvent> {
+        self.observed.pop_front()
+    }
 
-        match response {
-            FshMessage::FileListResponse(resp) => {
-                if resp.success {
-                    Ok(resp.files)
-                } else {
-                    let error_msg = resp.error_message.unwrap_or_else(|| "File list failed".to_string());
-                    Err(FshError::ShellError(error_msg))
+    /// Handle whatever the server has sent while the client was idle,
+    /// without waiting: notices, job output and shared session events are
+    /// queued and pings answered.
+    pub async fn poll_messages(&mut self) -> FshResult<()> {
+        while let Some(message) = self.receive_any().now_or_never() {
+            match message? {
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                message => {
+                    if let Some(other) = self.queue_job_output(message).and_then(|message| self.queue_observed(message)) {
+                        debug!("Ignoring {:?} while idle", other.message_type());
+                    }
                 }
             }
-            _ => {
-                Err(FshError::ProtocolError("Unexpected response to file list".to_string()))
+        }
+        Ok(())
+    }
+
+    /// Next piece of background job output, waiting for it if necessary.
+    /// Returns `None` once no jobs are running and everything has been read.
+    pub async fn next_job_output(&mut self) -> FshResult<Option<JobOutput>> {
+        loop {
+            if let Some(output) = self.job_output.pop_front() {
+                return Ok(Some(output));
+            }
+            if self.running_jobs.is_empty() {
+                return Ok(None);
+            }
+
+            match self.receive_any().await? {
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                message => {
+                    if let Some(other) = self.queue_job_output(message) {
+                        debug!("Ignoring {:?} while waiting for job output", other.message_type());
+                    }
+                }
             }
         }
     }
 
-    pub async fn disconnect(&mut self) -> FshResult<()> {
-        if !self.connected {
-            return Ok(());
+    pub async fn list_jobs(&mut self) -> FshResult<Vec<JobInfo>> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let list_msg = FshMessage::JobList(JobListMessage {
+            session_id: session_id.clone(),
+        });
+
+        self.send_message(list_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::JobListResponse(resp) => return Ok(resp.jobs),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to job list".to_string())),
+            }
         }
+    }
 
-        info!("Disconnecting from FSH server");
+    /// Kill a background job. Its completion arrives through [`next_job_output`](Self::next_job_output).
+    pub async fn kill_job(&mut self, job_id: u32) -> FshResult<()> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
 
-        let disconnect_msg = FshMessage::Disconnect(DisconnectMessage {
-            reason: "Client requested disconnect".to_string(),
+        let kill_msg = FshMessage::JobKill(JobKillMessage {
+            session_id: session_id.clone(),
+            job_id,
         });
 
-        if let Err(e) = self.send_message(disconnect_msg).await {
-            warn!("Failed to send disconnect message: {}", e);
+        self.send_message(kill_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::JobKillResponse(resp) if resp.success => return Ok(()),
+                FshMessage::JobKillResponse(resp) => {
+                    let error_msg = resp.error_message.unwrap_or_else(|| "Job kill failed".to_string());
+                    return Err(FshError::ShellError(error_msg));
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to job kill".to_string())),
+            }
         }
+    }
 
-        self.stream = None;
-        self.connected = false;
-        self.session_id = None;
+    /// Set `name` for the session's later commands, or unset it with `None`.
+    pub async fn set_env(&mut self, name: &str, value: Option<&str>) -> FshResult<()> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_ENV) {
+            return Err(FshError::ProtocolError("The server does not support setting variables".to_string()));
+        }
 
-        info!("Disconnected from FSH server");
-        Ok(())
+        let set_msg = FshMessage::EnvSet(EnvSetMessage {
+            session_id: session_id.clone(),
+            name: name.to_string(),
+            value: value.map(str::to_string),
+        });
+        self.send_message(set_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::EnvSetResponse(resp) => {
+                    match resp.value {
+                        Some(value) => { self.session_environment.insert(resp.name, value); }
+                        None => { self.session_environment.remove(&resp.name); }
+                    }
+                    return Ok(());
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to env set".to_string())),
+            }
+        }
     }
 
-    async fn send_message(&mut self, message: FshMessage) -> FshResult<()> {
-        if let Some(ref mut stream) = self.stream {
-            FshCodec::write_message(stream, &message).await
-        } else {
-            Err(FshError::NetworkError("Not connected".to_string()))
+    /// The session's variable `name`, or every variable it sets when `name`
+    /// is `None`.
+    pub async fn get_env(&mut self, name: Option<&str>) -> FshResult<HashMap<String, String>> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_ENV) {
+            return Err(FshError::ProtocolError("The server does not support reading variables".to_string()));
+        }
+
+        let get_msg = FshMessage::EnvGet(EnvGetMessage {
+            session_id: session_id.clone(),
+            name: name.map(str::to_string),
+        });
+        self.send_message(get_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::EnvGetResponse(resp) => return Ok(resp.variables),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to env get".to_string())),
+            }
         }
     }
 
-    async fn receive_message(&mut self) -> FshResult<FshMessage> {
-        if let Some(ref mut stream) = self.stream {
-            FshCodec::read_message(stream).await
-        } else {
-            Err(FshError::NetworkError("Not connected".to_string()))
+    /// The newest `limit` commands the server remembers for this session, or
+    /// for its folder when the folder shares history, oldest first.
+    pub async fn history(&mut self, limit: Option<u32>) -> FshResult<Vec<HistoryEntry>> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_HISTORY) {
+            return Err(FshError::ProtocolError("The server does not support command history".to_string()));
+        }
+
+        let history_msg = FshMessage::HistoryRequest(HistoryRequestMessage {
+            session_id: session_id.clone(),
+            limit,
+        });
+        self.send_message(history_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::HistoryResponse(resp) => return Ok(resp.entries),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to history request".to_string())),
+            }
         }
     }
 
-    pub fn is_connected(&self) -> bool {
-        self.connected
+    /// The session's folder, working directory, and the CPU and memory its
+    /// commands use, as the server last sampled them.
+    pub async fn session_info(&mut self) -> FshResult<SessionInfoMessage> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_SESSION_INFO) {
+            return Err(FshError::ProtocolError("The server does not support session info".to_string()));
+        }
+
+        let info_msg = FshMessage::SessionInfoRequest(SessionInfoRequestMessage {
+            session_id: session_id.clone(),
+        });
+        self.send_message(info_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::SessionInfo(info) => return Ok(info),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to session info request".to_string())),
+            }
+        }
     }
 
-    pub fn session_id(&self) -> Option<&str> {
-        self.session_id.as_deref()
+    pub async fn list_files(&mut self, path: &str, show_hidden: bool) -> FshResult<Vec<FileEntry>> {
+        let mut files = Vec::new();
+        self.list_files_paged(path, show_hidden, 0, None, |batch| files.extend(batch)).await?;
+        Ok(files)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct CommandOutput {
-    pub output_type: CommandOutputType,
-    pub data: String,
-}
+    /// List up to `limit` entries starting at `offset`, handing each batch to
+    /// `on_batch` as it arrives. Returns the offset of the next page, if any.
+    pub async fn list_files_paged<F>(
+        &mut self,
+        path: &str,
+        show_hidden: bool,
+        offset: u64,
+        limit: Option<u32>,
+        mut on_batch: F,
+    ) -> FshResult<Option<u64>>
+    where
+        F: FnMut(Vec<FileEntry>),
+    {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
 
-#[derive(Debug, Clone)]
-pub enum CommandOutputType {
-    Stdout,
-    Stderr,
-    Complete,
-    Error,
-}
+        let list_msg = FshMessage::FileList(FileListMessage {
+            session_id: session_id.clone(),
+            path: path.to_string(),
+            show_hidden,
+            offset,
+            limit,
+        });
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        self.send_message(list_msg).await?;
 
-    #[test]
-    fn test_client_creation() {
-        let client = FshClient::new("127.0.0.1:2222".to_string());
-        assert_eq!(client.server_addr, "127.0.0.1:2222");
-        assert!(!client.is_connected());
-        assert!(client.session_id().is_none());
+        loop {
+            match self.receive_message().await? {
+                FshMessage::FileListResponse(resp) => {
+                    if !resp.success {
+                        let error_msg = resp.error_message.unwrap_or_else(|| "File list failed".to_string());
+                        return Err(FshError::ShellError(error_msg));
+                    }
+
+                    on_batch(resp.files);
+                    if resp.complete {
+                        return Ok(resp.next_offset);
+                    }
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => {
+                    return Err(FshError::ProtocolError("Unexpected response to file list".to_string()));
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    pub async fn stat_file(&mut self, path: &str) -> FshResult<FileStat> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let stat_msg = FshMessage::FileStat(FileStatMessage {
+            session_id: session_id.clone(),
+            path: path.to_string(),
+        });
+
+        self.send_message(stat_msg).await?;
+
+        match self.receive_message().await? {
+            FshMessage::FileStatResponse(resp) => match resp.stat {
+                Some(stat) if resp.success => Ok(stat),
+                _ => {
+                    let error_msg = resp.error_message.unwrap_or_else(|| "File stat failed".to_string());
+                    Err(FshError::ShellError(error_msg))
+                }
+            },
+            _ => Err(FshError::ProtocolError("Unexpected response to file stat".to_string())),
+        }
+    }
+
+    /// Delete a remote file, or a directory with `recursive`. Returns the trash
+    /// id to restore it with, or `None` if the folder deletes permanently.
+    pub async fn delete_file(&mut self, path: &str, recursive: bool) -> FshResult<Option<String>> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let delete_msg = OperationRequest::FileDelete(FileDeleteMessage {
+            session_id: session_id.clone(),
+            file_path: path.to_string(),
+            recursive,
+        });
+
+        match self.request_change(delete_msg).await? {
+            FshMessage::FileDeleteResponse(resp) if resp.success => Ok(resp.trash_id),
+            FshMessage::FileDeleteResponse(resp) => {
+                let error_msg = resp.error_message.unwrap_or_else(|| "File delete failed".to_string());
+                Err(FshError::ShellError(error_msg))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to file delete".to_string())),
+        }
+    }
+
+    /// Create a remote directory and any missing parents.
+    pub async fn create_dir(&mut self, path: &str) -> FshResult<()> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_MKDIR) {
+            return Err(FshError::ProtocolError("The server does not support creating directories".to_string()));
+        }
+
+        let mkdir_msg = OperationRequest::FileMkdir(FileMkdirMessage {
+            session_id: session_id.clone(),
+            path: path.to_string(),
+        });
+
+        match self.request_change(mkdir_msg).await? {
+            FshMessage::FileMkdirResponse(resp) if resp.success => Ok(()),
+            FshMessage::FileMkdirResponse(resp) => {
+                let error_msg = resp.error_message.unwrap_or_else(|| "Directory creation failed".to_string());
+                Err(FshError::ShellError(error_msg))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to directory creation".to_string())),
+        }
+    }
+
+    /// Restore a deleted item by trash id or original path. Returns the path it
+    /// was restored to.
+    pub async fn restore_file(&mut self, target: &str) -> FshResult<String> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let restore_msg = OperationRequest::FileRestore(FileRestoreMessage {
+            session_id: session_id.clone(),
+            target: target.to_string(),
+        });
+
+        match self.request_change(restore_msg).await? {
+            FshMessage::FileRestoreResponse(resp) => match resp.restored_path {
+                Some(path) if resp.success => Ok(path),
+                _ => {
+                    let error_msg = resp.error_message.unwrap_or_else(|| "File restore failed".to_string());
+                    Err(FshError::ShellError(error_msg))
+                }
+            },
+            _ => Err(FshError::ProtocolError("Unexpected response to file restore".to_string())),
+        }
+    }
+
+    /// Status of the git repository containing `path`, or the working directory.
+    pub async fn git_status(&mut self, path: Option<&str>) -> FshResult<GitStatus> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let status_msg = FshMessage::GitStatus(GitStatusMessage {
+            session_id: session_id.clone(),
+            path: path.map(str::to_string),
+        });
+
+        self.send_message(status_msg).await?;
+
+        match self.receive_message().await? {
+            FshMessage::GitStatusResponse(resp) => match resp.status {
+                Some(status) if resp.success => Ok(status),
+                _ => Err(FshError::ShellError(resp.error_message.unwrap_or_else(|| "Git status failed".to_string()))),
+            },
+            _ => Err(FshError::ProtocolError("Unexpected response to git status".to_string())),
+        }
+    }
+
+    /// Unified diff of unstaged changes, or of staged ones with `staged`.
+    /// Returns the patch and whether the server cut it off.
+    pub async fn git_diff(&mut self, path: Option<&str>, staged: bool, context_lines: u32) -> FshResult<(String, bool)> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let diff_msg = FshMessage::GitDiff(GitDiffMessage {
+            session_id: session_id.clone(),
+            path: path.map(str::to_string),
+            staged,
+            context_lines,
+        });
+
+        self.send_message(diff_msg).await?;
+
+        match self.receive_message().await? {
+            FshMessage::GitDiffResponse(resp) => match resp.diff {
+                Some(diff) if resp.success => Ok((diff, resp.truncated)),
+                _ => Err(FshError::ShellError(resp.error_message.unwrap_or_else(|| "Git diff failed".to_string()))),
+            },
+            _ => Err(FshError::ProtocolError("Unexpected response to git diff".to_string())),
+        }
+    }
+
+    /// Recent commits, newest first, touching `path` when given.
+    pub async fn git_log(&mut self, path: Option<&str>, max_count: u32) -> FshResult<Vec<GitCommit>> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let log_msg = FshMessage::GitLog(GitLogMessage {
+            session_id: session_id.clone(),
+            path: path.map(str::to_string),
+            max_count,
+        });
+
+        self.send_message(log_msg).await?;
+
+        match self.receive_message().await? {
+            FshMessage::GitLogResponse(resp) if resp.success => Ok(resp.commits),
+            FshMessage::GitLogResponse(resp) => {
+                Err(FshError::ShellError(resp.error_message.unwrap_or_else(|| "Git log failed".to_string())))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to git log".to_string())),
+        }
+    }
+
+    /// Checksum of a remote file, or of the byte range `offset..offset + length`.
+    pub async fn file_checksum(
+        &mut self,
+        path: &str,
+        algorithm: ChecksumAlgorithm,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> FshResult<Checksum> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let checksum_msg = FshMessage::FileChecksum(FileChecksumMessage {
+            session_id: session_id.clone(),
+            file_path: path.to_string(),
+            algorithm,
+            offset,
+            length,
+        });
+
+        self.send_message(checksum_msg).await?;
+
+        match self.receive_message().await? {
+            FshMessage::FileChecksumResponse(resp) => match resp.checksum {
+                Some(checksum) if resp.success => Ok(checksum),
+                _ => {
+                    let error_msg = resp.error_message.unwrap_or_else(|| "File checksum failed".to_string());
+                    Err(FshError::ShellError(error_msg))
+                }
+            },
+            _ => Err(FshError::ProtocolError("Unexpected response to file checksum".to_string())),
+        }
+    }
+
+    /// Download a remote file, verifying every chunk and then the whole file.
+    /// Returns the number of bytes transferred.
+    pub async fn download_file(&mut self, remote_path: &str, local_path: &Path) -> FshResult<u64> {
+        let mut file = std::fs::File::create(local_path)?;
+        let mut offset = 0;
+
+        loop {
+            let chunk = self.read_verified_chunk(remote_path, offset, TRANSFER_CHUNK_SIZE).await?;
+            file.write_all(&chunk.data)?;
+            offset += chunk.data.len() as u64;
+
+            if chunk.data.is_empty() || offset >= chunk.total_size {
+                break;
+            }
+        }
+        file.flush()?;
+
+        self.verify_transfer(remote_path, local_path).await?;
+        Ok(offset)
+    }
+
+    /// Upload a local file, replacing the remote one. Chunks the server rejects
+    /// as corrupted are resent, and the whole file is verified at the end.
+    pub async fn upload_file(&mut self, local_path: &Path, remote_path: &str) -> FshResult<u64> {
+        let mut file = std::fs::File::open(local_path)?;
+        let mut buffer = vec![0u8; TRANSFER_CHUNK_SIZE as usize];
+        let mut offset = 0;
+
+        loop {
+            let read = read_full(&mut file, &mut buffer)?;
+            // The first chunk replaces the file, so an empty file is still created
+            let write_offset = (offset > 0).then_some(offset);
+            if read == 0 && write_offset.is_some() {
+                break;
+            }
+
+            self.write_verified_chunk(remote_path, &buffer[..read], write_offset).await?;
+            offset += read as u64;
+
+            if read < buffer.len() {
+                break;
+            }
+        }
+
+        self.verify_transfer(remote_path, local_path).await?;
+        Ok(offset)
+    }
+
+    /// Copy a remote file into the folder `destination` has a session on,
+    /// chunk by chunk, then verify the copy. Returns the number of bytes copied.
+    pub async fn copy_file_to(&mut self, path: &str, destination: &mut FshClient, destination_path: &str) -> FshResult<u64> {
+        let mut offset = 0;
+
+        loop {
+            let chunk = self.read_verified_chunk(path, offset, TRANSFER_CHUNK_SIZE).await?;
+            // The first chunk replaces the file, so an empty file is still created
+            let write_offset = (offset > 0).then_some(offset);
+            if chunk.data.is_empty() && write_offset.is_some() {
+                break;
+            }
+
+            destination.write_verified_chunk(destination_path, &chunk.data, write_offset).await?;
+            offset += chunk.data.len() as u64;
+
+            if chunk.data.is_empty() || offset >= chunk.total_size {
+                break;
+            }
+        }
+
+        let algorithm = ChecksumAlgorithm::default();
+        let source = self.file_checksum(path, algorithm, None, None).await?;
+        let copied = destination.file_checksum(destination_path, algorithm, None, None).await?;
+        if source != copied {
+            return Err(FshError::NetworkError(format!(
+                "Copied file '{}' does not match: source {}, copy {}", destination_path, source, copied
+            )));
+        }
+        Ok(offset)
+    }
+
+    /// Replace a remote file with `data` in a single write, unless it no
+    /// longer has the `expected` checksum it had when it was downloaded.
+    pub async fn replace_file_if_unchanged(&mut self, remote_path: &str, data: &[u8], expected: &Checksum) -> FshResult<()> {
+        if data.len() > MAX_FILE_CHUNK_SIZE {
+            return Err(FshError::ShellError(format!(
+                "'{}' is too large to write back in one piece ({} bytes)", remote_path, data.len()
+            )));
+        }
+
+        let current = self.file_checksum(remote_path, expected.algorithm, None, None).await?;
+        if current != *expected {
+            return Err(FshError::ShellError(format!("'{}' was changed on the server in the meantime", remote_path)));
+        }
+
+        self.write_verified_chunk(remote_path, data, None).await?;
+        let written = self.file_checksum(remote_path, expected.algorithm, None, None).await?;
+        if !written.verify(data) {
+            return Err(FshError::NetworkError(format!(
+                "Written file '{}' does not match: remote {}", remote_path, written
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read up to `length` bytes of a remote file from `offset`, verified.
+    /// Returns the data and the file's total size.
+    pub async fn read_file_range(&mut self, path: &str, offset: u64, length: u64) -> FshResult<(Vec<u8>, u64)> {
+        let chunk = self.read_verified_chunk(path, offset, length).await?;
+        Ok((chunk.data.into(), chunk.total_size))
+    }
+
+    /// Write `data` into a remote file at `offset`, or replace the file with
+    /// it when `offset` is `None`.
+    pub async fn write_file_range(&mut self, path: &str, data: &[u8], offset: Option<u64>) -> FshResult<()> {
+        if data.len() > MAX_FILE_CHUNK_SIZE {
+            return Err(FshError::ShellError(format!("Writes are limited to {} bytes", MAX_FILE_CHUNK_SIZE)));
+        }
+        self.write_verified_chunk(path, data, offset).await
+    }
+
+    async fn read_verified_chunk(&mut self, path: &str, offset: u64, length: u64) -> FshResult<FileReadResponseMessage> {
+        let session_id = self.session_id.clone()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        for attempt in 1..=MAX_CHUNK_RETRIES + 1 {
+            let read_msg = FshMessage::FileRead(FileReadMessage {
+                session_id: session_id.clone(),
+                file_path: path.to_string(),
+                offset: Some(offset),
+                length: Some(length),
+            });
+            self.send_message(read_msg).await?;
+
+            let resp = match self.receive_message().await? {
+                FshMessage::FileReadResponse(resp) => resp,
+                _ => return Err(FshError::ProtocolError("Unexpected response to file read".to_string())),
+            };
+
+            if !resp.success {
+                let error_msg = resp.error_message.unwrap_or_else(|| "File read failed".to_string());
+                return Err(FshError::ShellError(error_msg));
+            }
+
+            match &resp.checksum {
+                Some(checksum) if !checksum.verify(&resp.data) => {
+                    warn!("Chunk at offset {} of '{}' is corrupted (attempt {})", offset, path, attempt);
+                }
+                _ => return Ok(resp),
+            }
+        }
+
+        Err(FshError::NetworkError(format!(
+            "Chunk at offset {} of '{}' failed verification {} times", offset, path, MAX_CHUNK_RETRIES + 1
+        )))
+    }
+
+    async fn write_verified_chunk(&mut self, path: &str, data: &[u8], offset: Option<u64>) -> FshResult<()> {
+        let session_id = self.session_id.clone()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        let checksum = ChecksumAlgorithm::default().digest(data);
+
+        for attempt in 1..=MAX_CHUNK_RETRIES + 1 {
+            let write_msg = OperationRequest::FileWrite(FileWriteMessage {
+                session_id: session_id.clone(),
+                file_path: path.to_string(),
+                data: data.to_vec(),
+                append: false,
+                offset,
+                checksum: Some(checksum.clone()),
+            });
+
+            let resp = match self.request_change(write_msg).await? {
+                FshMessage::FileWriteResponse(resp) => resp,
+                FshMessage::Error(error) => return Err(FshError::from_code(error.code, error.message)),
+                _ => return Err(FshError::ProtocolError("Unexpected response to file write".to_string())),
+            };
+
+            match resp.error_message {
+                _ if resp.success => return Ok(()),
+                Some(error) if error == CHECKSUM_MISMATCH => {
+                    warn!("Chunk at offset {} of '{}' was corrupted in transit (attempt {})",
+                          offset.unwrap_or(0), path, attempt);
+                }
+                error => {
+                    return Err(FshError::ShellError(error.unwrap_or_else(|| "File write failed".to_string())));
+                }
+            }
+        }
+
+        Err(FshError::NetworkError(format!(
+            "Chunk at offset {} of '{}' failed verification {} times", offset.unwrap_or(0), path, MAX_CHUNK_RETRIES + 1
+        )))
+    }
+
+    /// Send a change to files and wait for the reply. When the server
+    /// deduplicates operations the change is sent under a new operation id,
+    /// kept until answered so `retry_operation` can resend it.
+    async fn request_change(&mut self, request: OperationRequest) -> FshResult<FshMessage> {
+        if !self.capabilities.supports(FEATURE_OPERATION_IDS) {
+            self.send_message(request.into()).await?;
+            return self.receive_message().await;
+        }
+
+        let operation = OperationMessage { operation_id: uuid::Uuid::new_v4().to_string(), request };
+        self.pending_operation = Some(operation.clone());
+        Ok(self.send_operation(operation).await?.into())
+    }
+
+    async fn send_operation(&mut self, operation: OperationMessage) -> FshResult<OperationReply> {
+        let operation_id = operation.operation_id.clone();
+        self.send_message(FshMessage::Operation(operation)).await?;
+
+        match self.receive_message().await? {
+            FshMessage::OperationResponse(resp) if resp.operation_id == operation_id => {
+                // Still worth resending if the server couldn't do it yet
+                if !matches!(&resp.reply, OperationReply::Error(error) if error.code.is_retryable()) {
+                    self.pending_operation = None;
+                }
+                Ok(resp.reply)
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to operation".to_string())),
+        }
+    }
+
+    /// Id of the last change sent that got no reply, e.g. because the
+    /// connection dropped before it came.
+    pub fn pending_operation(&self) -> Option<&str> {
+        self.pending_operation.as_ref().map(|operation| operation.operation_id.as_str())
+    }
+
+    /// Resend the change that got no reply on the current session, under its
+    /// original id so the server makes it at most once. Returns its reply, or
+    /// `None` if there was nothing to resend.
+    pub async fn retry_operation(&mut self) -> FshResult<Option<OperationReply>> {
+        let Some(mut operation) = self.pending_operation.clone() else {
+            return Ok(None);
+        };
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_OPERATION_IDS) {
+            return Err(FshError::ProtocolError("The server does not support operation ids".to_string()));
+        }
+
+        operation.request.set_session_id(session_id);
+        self.send_operation(operation).await.map(Some)
+    }
+
+    /// Compare the checksums of the remote and local copies of a transferred file.
+    async fn verify_transfer(&mut self, remote_path: &str, local_path: &Path) -> FshResult<()> {
+        let algorithm = ChecksumAlgorithm::default();
+        let remote = self.file_checksum(remote_path, algorithm, None, None).await?;
+        let (local, _) = algorithm.digest_reader(std::fs::File::open(local_path)?)?;
+
+        if remote != local {
+            return Err(FshError::NetworkError(format!(
+                "Transferred file '{}' does not match: remote {}, local {}", remote_path, remote, local
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ask the server for the last `lines` lines of a file and, with `follow`,
+    /// for lines appended later. Read the data with [`next_tail_chunk`](Self::next_tail_chunk).
+    pub async fn tail_file(&mut self, path: &str, lines: u32, follow: bool) -> FshResult<()> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let tail_msg = FshMessage::FileTail(FileTailMessage {
+            session_id: session_id.clone(),
+            file_path: path.to_string(),
+            lines,
+            follow,
+        });
+
+        self.send_message(tail_msg).await
+    }
+
+    /// Next batch of lines from the file being tailed, or `None` once the tail has ended.
+    pub async fn next_tail_chunk(&mut self) -> FshResult<Option<FileTailDataMessage>> {
+        loop {
+            match self.receive_message().await? {
+                FshMessage::FileTailData(data) => return Ok(Some(data)),
+                FshMessage::FileTailEnd(end) => {
+                    return match end.error_message {
+                        Some(error) => Err(FshError::ShellError(error)),
+                        None => Ok(None),
+                    };
+                }
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                other => debug!("Ignoring {:?} while tailing a file", other.message_type()),
+            }
+        }
+    }
+
+    /// Stop following the current file. The server confirms with the end of the tail.
+    pub async fn stop_tail(&mut self) -> FshResult<()> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+
+        let stop_msg = FshMessage::FileTailStop(FileTailStopMessage {
+            session_id: session_id.clone(),
+        });
+
+        self.send_message(stop_msg).await
+    }
+
+    /// Let other clients observe this session read-only, or detach them all,
+    /// returning how many are attached.
+    pub async fn allow_observers(&mut self, allow: bool) -> FshResult<u32> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_OBSERVE) {
+            return Err(FshError::ProtocolError("The server does not support session observers".to_string()));
+        }
+
+        let consent_msg = FshMessage::ObserverConsent(ObserverConsentMessage {
+            session_id: session_id.clone(),
+            allow,
+        });
+        self.send_message(consent_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::ObserverConsentResponse(resp) => return Ok(resp.observers),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to observer consent".to_string())),
+            }
+        }
+    }
+
+    /// Watch another client's session instead of binding a folder. Its
+    /// owner must have allowed observers; follow it with `next_observed`.
+    pub async fn observe_session(&mut self, session_id: &str) -> FshResult<crate::protocol::FolderInfo> {
+        if !self.connected {
+            return Err(FshError::NetworkError("Not connected to server".to_string()));
+        }
+        if !self.capabilities.supports(FEATURE_OBSERVE) {
+            return Err(FshError::ProtocolError("The server does not support session observers".to_string()));
+        }
+
+        info!("Observing session {}", session_id);
+        self.send_message(FshMessage::ObserveSession(ObserveSessionMessage {
+            session_id: session_id.to_string(),
+        })).await?;
+
+        match self.receive_message().await? {
+            FshMessage::ObserveStarted(resp) if resp.success => {
+                self.working_directory = resp.working_directory;
+                resp.folder_info.ok_or_else(|| FshError::ProtocolError("Missing folder info".to_string()))
+            }
+            FshMessage::ObserveStarted(resp) => {
+                let error_msg = resp.error_message.unwrap_or_else(|| "Observing the session failed".to_string());
+                Err(FshError::PermissionDenied(error_msg))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to observe request".to_string())),
+        }
+    }
+
+    /// The next thing that happened in the observed or shared session, or
+    /// `None` once the observation is over.
+    pub async fn next_observed(&mut self) -> FshResult<Option<ObservedEvent>> {
+        loop {
+            if let Some(event) = self.observed.pop_front() {
+                return Ok(Some(event));
+            }
+            let message = self.receive_any().await?;
+            match self.queue_job_output(message).and_then(|message| self.queue_observed(message)) {
+                None => {}
+                Some(FshMessage::Disconnect(disconnect)) => {
+                    info!("Observation ended: {}", disconnect.reason);
+                    return Ok(None);
+                }
+                Some(FshMessage::Ping) => self.send_message(FshMessage::Pong).await?,
+                Some(other) => debug!("Ignoring {:?} while observing a session", other.message_type()),
+            }
+        }
+    }
+
+    /// Let other authenticated clients run commands in this session, or
+    /// detach them all, returning how many are attached. What they do is
+    /// collected with `take_observed` or `next_observed`.
+    pub async fn allow_collaborators(&mut self, allow: bool) -> FshResult<u32> {
+        let session_id = self.session_id.as_ref()
+            .ok_or_else(|| FshError::SessionNotFound("No active session".to_string()))?;
+        if !self.capabilities.supports(FEATURE_COLLABORATE) {
+            return Err(FshError::ProtocolError("The server does not support shared sessions".to_string()));
+        }
+
+        let consent_msg = FshMessage::CollaboratorConsent(CollaboratorConsentMessage {
+            session_id: session_id.clone(),
+            allow,
+        });
+        self.send_message(consent_msg).await?;
+
+        loop {
+            match self.receive_message().await? {
+                FshMessage::CollaboratorConsentResponse(resp) => return Ok(resp.participants),
+                FshMessage::Ping => self.send_message(FshMessage::Pong).await?,
+                _ => return Err(FshError::ProtocolError("Unexpected response to collaborator consent".to_string())),
+            }
+        }
+    }
+
+    /// Join another client's session instead of binding a folder, to run
+    /// commands in its shell with `execute_command`. Its owner must have
+    /// allowed collaborators.
+    pub async fn join_session(&mut self, session_id: &str) -> FshResult<crate::protocol::FolderInfo> {
+        if !self.connected {
+            return Err(FshError::NetworkError("Not connected to server".to_string()));
+        }
+        if !self.capabilities.supports(FEATURE_COLLABORATE) {
+            return Err(FshError::ProtocolError("The server does not support shared sessions".to_string()));
+        }
+
+        info!("Joining session {}", session_id);
+        self.send_message(FshMessage::JoinSession(JoinSessionMessage {
+            session_id: session_id.to_string(),
+        })).await?;
+
+        match self.receive_message().await? {
+            FshMessage::SessionJoined(resp) if resp.success => {
+                info!("Joined session {} as {}", session_id, resp.participant.as_deref().unwrap_or("a participant"));
+                self.session_id = Some(session_id.to_string());
+                self.working_directory = resp.working_directory;
+                resp.folder_info.ok_or_else(|| FshError::ProtocolError("Missing folder info".to_string()))
+            }
+            FshMessage::SessionJoined(resp) => {
+                let error_msg = resp.error_message.unwrap_or_else(|| "Joining the session failed".to_string());
+                Err(FshError::PermissionDenied(error_msg))
+            }
+            _ => Err(FshError::ProtocolError("Unexpected response to join request".to_string())),
+        }
+    }
+
+    pub async fn disconnect(&mut self) -> FshResult<()> {
+        if !self.connected {
+            return Ok(());
+        }
+
+        info!("Disconnecting from FSH server");
+
+        let disconnect_msg = FshMessage::Disconnect(DisconnectMessage {
+            reason: "Client requested disconnect".to_string(),
+        });
+
+        if let Err(e) = self.send_message(disconnect_msg).await {
+            warn!("Failed to send disconnect message: {}", e);
+        }
+
+        self.stream = None;
+        self.connected = false;
+        self.previous_session_id = self.session_id.take().or(self.previous_session_id.take());
+        self.capabilities = Capabilities::default();
+        self.session_environment.clear();
+        self.observed.clear();
+        self.locked = false;
+
+        info!("Disconnected from FSH server");
+        Ok(())
+    }
+
+    async fn send_message(&mut self, message: FshMessage) -> FshResult<()> {
+        if let Some(ref mut stream) = self.stream {
+            FshCodec::write_message(stream, message).await
+        } else {
+            Err(FshError::NetworkError("Not connected".to_string()))
+        }
+    }
+
+    /// Receive the next reply, turning server `Error` messages into typed errors.
+    /// Idle warnings, notices, background job output and shared session
+    /// events are queued.
+    async fn receive_message(&mut self) -> FshResult<FshMessage> {
+        loop {
+            let message = self.receive_any().await?;
+            if let Some(message) = self.queue_job_output(message).and_then(|message| self.queue_observed(message)) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Queue `message` if it reports on an observed or shared session,
+    /// otherwise hand it back.
+    fn queue_observed(&mut self, message: FshMessage) -> Option<FshMessage> {
+        let FshMessage::Observed(observed) = message else {
+            return Some(message);
+        };
+        // Everyone in a shared session works in the same directory
+        if let ObservedEvent::WorkingDirChanged(changed) = &observed.event {
+            self.working_directory = Some(changed.working_directory.clone());
+            self.shell_prompt = Some(changed.shell_prompt.clone());
+        }
+        self.observed.push_back(observed.event);
+        None
+    }
+
+    /// Queue `message` if it belongs to a background job, otherwise hand it back.
+    fn queue_job_output(&mut self, message: FshMessage) -> Option<FshMessage> {
+        let output = match message {
+            FshMessage::CommandOutput(CommandOutputMessage { job_id: Some(job_id), output_type, data, binary, .. }) => {
+                JobOutput {
+                    job_id,
+                    output: CommandOutput::received(output_type, data, binary),
+                    exit_code: None,
+                }
+            }
+            FshMessage::CommandComplete(CommandCompleteMessage {
+                job_id: Some(job_id), exit_code, execution_time_ms, ..
+            }) => {
+                self.running_jobs.remove(&job_id);
+                JobOutput {
+                    job_id,
+                    output: CommandOutput {
+                        output_type: CommandOutputType::Complete,
+                        data: format!("Exit code {} ({} ms)", exit_code, execution_time_ms),
+                        raw: None,
+                    },
+                    exit_code: Some(exit_code),
+                }
+            }
+            message => return Some(message),
+        };
+
+        self.job_output.push_back(output);
+        None
+    }
+
+    async fn receive_any(&mut self) -> FshResult<FshMessage> {
+        let stream = self.stream.as_mut()
+            .ok_or_else(|| FshError::NetworkError("Not connected".to_string()))?;
+
+        loop {
+            match FshCodec::read_message(stream).await? {
+                FshMessage::Error(err) if err.code == FshErrorCode::IdleWarning => {
+                    warn!("{}", err.message);
+                    self.notices.push_back(ServerNoticeMessage { message: err.message, sent_at: chrono::Utc::now() });
+                }
+                FshMessage::SessionLocked(locked) => {
+                    info!("{}", locked.reason);
+                    self.locked = true;
+                    self.notices.push_back(ServerNoticeMessage {
+                        message: format!("{}; enter your token to continue", locked.reason),
+                        sent_at: chrono::Utc::now(),
+                    });
+                }
+                FshMessage::ServerNotice(notice) => {
+                    info!("Server notice: {}", notice.message);
+                    self.notices.push_back(notice);
+                }
+                FshMessage::WatchRun(run) => {
+                    debug!("Watch rule {} ran '{}': {:?}", run.rule, run.command, run.exit_code);
+                    self.watch_runs.push_back(run);
+                }
+                FshMessage::Error(err) => {
+                    debug!("Server error ({}): {}", err.code, err.message);
+                    return Err(err.into());
+                }
+                message => return Ok(message),
+            }
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Environment variables the server set up for the current session.
+    pub fn session_environment(&self) -> &HashMap<String, String> {
+        &self.session_environment
+    }
+
+    /// Server-side working directory of the current session, as of the last command.
+    pub fn working_directory(&self) -> Option<&str> {
+        self.working_directory.as_deref()
+    }
+
+    /// Shell prompt for the session's current working directory.
+    pub fn shell_prompt(&self) -> Option<&str> {
+        self.shell_prompt.as_deref()
+    }
+}
+
+/// Fill `buffer` from `reader`, stopping early only at end of file.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// How the client should respond to a failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Transient failure; the same request may succeed if sent again.
+    Retry,
+    /// The connection or session is no longer usable; reconnect and authenticate again.
+    Reauthenticate,
+    /// The request itself was rejected; report it and move on.
+    Abort,
+}
+
+impl ErrorAction {
+    pub fn for_code(code: FshErrorCode) -> Self {
+        if code.is_retryable() {
+            return ErrorAction::Retry;
+        }
+        match code {
+            FshErrorCode::AuthenticationFailed | FshErrorCode::SessionNotFound => ErrorAction::Reauthenticate,
+            FshErrorCode::NetworkError
+            | FshErrorCode::RateLimited
+            | FshErrorCode::MalwareDetected
+            | FshErrorCode::ProtocolError
+            | FshErrorCode::FolderNotFound
+            | FshErrorCode::PermissionDenied
+            | FshErrorCode::InvalidPath
+            | FshErrorCode::ShellError
+            | FshErrorCode::ConfigError
+            | FshErrorCode::IdleWarning => ErrorAction::Abort,
+        }
+    }
+
+    pub fn for_error(error: &FshError) -> Self {
+        if error.is_retryable() {
+            return ErrorAction::Retry;
+        }
+        match Self::for_code(error.code()) {
+            // Local I/O failures that aren't transient won't go away on retry
+            ErrorAction::Retry => ErrorAction::Abort,
+            action => action,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub output_type: CommandOutputType,
+    pub data: String,
+    /// Output that isn't text, exactly as the command wrote it; `data` is
+    /// empty then.
+    pub raw: Option<Bytes>,
+}
+
+impl CommandOutput {
+    fn received(output_type: OutputType, data: Bytes, binary: bool) -> Self {
+        let output_type = match output_type {
+            OutputType::Stdout => CommandOutputType::Stdout,
+            OutputType::Stderr => CommandOutputType::Stderr,
+        };
+        if binary {
+            return Self { output_type, data: String::new(), raw: Some(data) };
+        }
+        Self { output_type, data: String::from_utf8_lossy(&data).to_string(), raw: None }
+    }
+}
+
+/// Output of a background job; `exit_code` is set on its final `Complete` entry.
+#[derive(Debug, Clone)]
+pub struct JobOutput {
+    pub job_id: u32,
+    pub output: CommandOutput,
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CommandOutputType {
+    Stdout,
+    Stderr,
+    Complete,
+    Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_creation() {
+        let client = FshClient::new("127.0.0.1:2222".to_string());
+        assert_eq!(client.server_addr, "127.0.0.1:2222");
+        assert!(!client.is_connected());
+        assert!(client.session_id().is_none());
+    }
+
+    #[test]
+    fn test_error_actions() {
+        assert_eq!(ErrorAction::for_error(&FshError::NetworkError("reset".to_string())), ErrorAction::Retry);
+        assert_eq!(ErrorAction::for_error(&FshError::AuthenticationFailed), ErrorAction::Reauthenticate);
+        assert_eq!(ErrorAction::for_code(FshErrorCode::SessionNotFound), ErrorAction::Reauthenticate);
+        assert_eq!(ErrorAction::for_code(FshErrorCode::PermissionDenied), ErrorAction::Abort);
+
+        // I/O failures are judged by their kind, not just their wire code
+        let reset = FshError::io("Connection lost", std::io::ErrorKind::ConnectionReset.into());
+        assert_eq!(ErrorAction::for_error(&reset), ErrorAction::Retry);
+        let broken = FshError::io("Write failed", std::io::Error::other("disk on fire"));
+        assert_eq!(broken.code(), FshErrorCode::NetworkError);
+        assert_eq!(ErrorAction::for_error(&broken), ErrorAction::Abort);
+    }
+
+    #[test]
+    fn test_error_message_roundtrip() {
+        let error = FshError::PermissionDenied("Execute permission denied".to_string());
+        let message = ErrorMessage::from(&error);
+        assert_eq!(message.code, FshErrorCode::PermissionDenied);
+        match FshError::from(message) {
+            FshError::PermissionDenied(msg) => assert_eq!(msg, "Execute permission denied"),
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::message::{FileEntry, FileStat};
+use crate::protocol::FshResult;
+use super::FshClient;
+
+/// How long attributes and listings are trusted by default.
+const DEFAULT_ATTRIBUTE_TTL: Duration = Duration::from_secs(1);
+
+/// Bytes fetched per read by default, so sequential reads need few round trips.
+const DEFAULT_READ_AHEAD: u64 = 256 * 1024;
+
+/// A bound folder seen as a file system: the operations a FUSE or WinFsp
+/// driver needs, on top of the file messages. Attributes and listings are
+/// cached for a short time, and reads fetch a whole read-ahead block.
+/// Paths are relative to the folder root; a leading `/` is ignored.
+#[derive(Debug)]
+pub struct RemoteFs {
+    client: FshClient,
+    ttl: Duration,
+    read_ahead: u64,
+    attributes: HashMap<String, (Instant, FileStat)>,
+    listings: HashMap<String, (Instant, Vec<FileEntry>)>,
+    /// The last block read: path, offset and data.
+    block: Option<(String, u64, Vec<u8>)>,
+}
+
+impl RemoteFs {
+    /// `client` must have a session on the folder.
+    pub fn new(client: FshClient) -> Self {
+        Self {
+            client,
+            ttl: DEFAULT_ATTRIBUTE_TTL,
+            read_ahead: DEFAULT_READ_AHEAD,
+            attributes: HashMap::new(),
+            listings: HashMap::new(),
+            block: None,
+        }
+    }
+
+    pub fn with_attribute_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_read_ahead(mut self, bytes: u64) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    pub fn into_client(self) -> FshClient {
+        self.client
+    }
+
+    pub async fn getattr(&mut self, path: &str) -> FshResult<FileStat> {
+        let path = normalize(path);
+        if let Some((fetched, stat)) = self.attributes.get(&path) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(stat.clone());
+            }
+        }
+        let stat = self.client.stat_file(&path).await?;
+        self.attributes.insert(path, (Instant::now(), stat.clone()));
+        Ok(stat)
+    }
+
+    pub async fn readdir(&mut self, path: &str) -> FshResult<Vec<FileEntry>> {
+        let path = normalize(path);
+        if let Some((fetched, entries)) = self.listings.get(&path) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(entries.clone());
+            }
+        }
+        let entries = self.client.list_files(&path, true).await?;
+        self.listings.insert(path, (Instant::now(), entries.clone()));
+        Ok(entries)
+    }
+
+    /// Up to `size` bytes from `offset`; fewer at the end of the file.
+    pub async fn read(&mut self, path: &str, offset: u64, size: u32) -> FshResult<Vec<u8>> {
+        let path = normalize(path);
+        let end = offset + size as u64;
+
+        let cached = match &self.block {
+            Some((block_path, start, data)) if *block_path == path => {
+                let block_end = start + data.len() as u64;
+                let at_eof = (data.len() as u64) < self.read_ahead.max(size as u64);
+                // Served from the block if it covers the range, or reaches the end of the file
+                (offset >= *start && (end <= block_end || (at_eof && offset <= block_end)))
+                    .then(|| data[(offset - start) as usize..(end.min(block_end) - start) as usize].to_vec())
+            }
+            _ => None,
+        };
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+
+        let length = self.read_ahead.max(size as u64);
+        let (data, _) = self.client.read_file_range(&path, offset, length).await?;
+        let result = data[..data.len().min(size as usize)].to_vec();
+        self.block = Some((path, offset, data));
+        Ok(result)
+    }
+
+    /// Write `data` at `offset`, or replace the file with it when `offset`
+    /// is `None`.
+    pub async fn write(&mut self, path: &str, data: &[u8], offset: Option<u64>) -> FshResult<()> {
+        let path = normalize(path);
+        let result = self.client.write_file_range(&path, data, offset).await;
+        self.invalidate(&path);
+        result
+    }
+
+    /// Delete a file, or a directory and its contents.
+    pub async fn remove(&mut self, path: &str, recursive: bool) -> FshResult<()> {
+        let path = normalize(path);
+        let result = self.client.delete_file(&path, recursive).await.map(|_| ());
+        self.invalidate(&path);
+        result
+    }
+
+    /// Forget what is cached about `path` and its directory.
+    pub fn invalidate(&mut self, path: &str) {
+        let path = normalize(path);
+        let parent = match path.rsplit_once('/') {
+            Some((parent, _)) => parent.to_string(),
+            None => ".".to_string(),
+        };
+        self.attributes.remove(&path);
+        self.listings.remove(&path);
+        self.listings.remove(&parent);
+        if self.block.as_ref().is_some_and(|(block_path, _, _)| *block_path == path) {
+            self.block = None;
+        }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    match path.trim_matches('/') {
+        "" => ".".to_string(),
+        path => path.to_string(),
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::protocol::{FshError, FshResult};
+
+/// How line breaks in command output are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEndings {
+    /// As the command wrote them, so a lone `\r` still redraws the line,
+    /// e.g. for progress bars.
+    #[default]
+    Keep,
+    /// `\n`, with `\r\n` and lone `\r` turned into one.
+    Lf,
+    /// `\r\n`, with `\n` and lone `\r` turned into one.
+    Crlf,
+}
+
+/// What to do with ANSI escape sequences in command output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnsiSequences {
+    #[default]
+    Keep,
+    /// Drop colors, cursor movement and window titles.
+    Strip,
+}
+
+/// How the client shows command output, the same for every folder whatever
+/// its shell writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    pub line_endings: LineEndings,
+    /// Expand tabs to spaces up to the next multiple of this many columns.
+    pub tab_width: Option<usize>,
+    pub ansi: AnsiSequences,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ClientPreferences {
+    #[serde(default)]
+    render: RenderOptions,
+}
+
+impl RenderOptions {
+    /// `client.toml` next to the default config file, e.g.
+    /// `~/.config/fsh/client.toml`.
+    pub fn default_path() -> FshResult<PathBuf> {
+        Ok(Config::get_default_config_path()?.with_file_name("client.toml"))
+    }
+
+    /// The `[render]` table of the preferences file at `path`; the defaults
+    /// if there is none.
+    pub fn load(path: &Path) -> FshResult<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(FshError::io(format!("Cannot read {}", path.display()), e)),
+        };
+        let preferences: ClientPreferences = toml::from_str(&content)
+            .map_err(|e| FshError::ConfigError(format!("Failed to parse {}: {}", path.display(), e)))?;
+        if preferences.render.tab_width == Some(0) {
+            return Err(FshError::ConfigError("tab_width must be greater than 0".to_string()));
+        }
+        Ok(preferences.render)
+    }
+
+    pub fn with_line_endings(mut self, line_endings: LineEndings) -> Self {
+        self.line_endings = line_endings;
+        self
+    }
+
+    pub fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = Some(tab_width).filter(|width| *width > 0);
+        self
+    }
+
+    pub fn with_ansi(mut self, ansi: AnsiSequences) -> Self {
+        self.ansi = ansi;
+        self
+    }
+
+    /// `text` as these options show it. Output arrives a line at a time, so
+    /// columns for tab stops count from the start of `text`.
+    pub fn render(&self, text: &str) -> String {
+        if *self == Self::default() {
+            return text.to_string();
+        }
+
+        let mut rendered = String::with_capacity(text.len());
+        let mut column = 0;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\x1b' => {
+                    let sequence = take_escape_sequence(&mut chars);
+                    if self.ansi == AnsiSequences::Keep {
+                        rendered.push(c);
+                        rendered.push_str(&sequence);
+                    }
+                }
+                '\r' | '\n' => {
+                    let crlf = c == '\r' && chars.peek() == Some(&'\n');
+                    if crlf {
+                        chars.next();
+                    }
+                    match self.line_endings {
+                        LineEndings::Keep if crlf => rendered.push_str("\r\n"),
+                        LineEndings::Keep => rendered.push(c),
+                        LineEndings::Lf => rendered.push('\n'),
+                        LineEndings::Crlf => rendered.push_str("\r\n"),
+                    }
+                    column = 0;
+                }
+                '\t' => match self.tab_width {
+                    Some(width) => {
+                        let spaces = width - column % width;
+                        rendered.extend(std::iter::repeat_n(' ', spaces));
+                        column += spaces;
+                    }
+                    None => {
+                        rendered.push(c);
+                        column += 1;
+                    }
+                },
+                _ => {
+                    rendered.push(c);
+                    column += 1;
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// The rest of an escape sequence after its `ESC`: a CSI sequence up to its
+/// final byte, an OSC string up to `BEL` or `ESC \`, or a single character.
+fn take_escape_sequence(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut sequence = String::new();
+    match chars.next() {
+        Some('[') => {
+            sequence.push('[');
+            for c in chars.by_ref() {
+                sequence.push(c);
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+        Some(']') => {
+            sequence.push(']');
+            while let Some(c) = chars.next() {
+                sequence.push(c);
+                if c == '\x07' {
+                    break;
+                }
+                if c == '\x1b' && chars.peek() == Some(&'\\') {
+                    sequence.extend(chars.next());
+                    break;
+                }
+            }
+        }
+        Some(c) => sequence.push(c),
+        None => {}
+    }
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_endings() {
+        let output = "one\r\ntwo\nprogress 50%\rprogress 100%\n";
+        assert_eq!(RenderOptions::default().render(output), output);
+        assert_eq!(
+            RenderOptions::default().with_line_endings(LineEndings::Lf).render(output),
+            "one\ntwo\nprogress 50%\nprogress 100%\n"
+        );
+        assert_eq!(
+            RenderOptions::default().with_line_endings(LineEndings::Crlf).render(output),
+            "one\r\ntwo\r\nprogress 50%\r\nprogress 100%\r\n"
+        );
+    }
+
+    #[test]
+    fn test_tabs_and_ansi() {
+        let options = RenderOptions::default().with_tab_width(4).with_ansi(AnsiSequences::Strip);
+        assert_eq!(options.render("a\tbc\td\n\te\n"), "a   bc  d\n    e\n");
+
+        // Colors don't take up columns, and titles and cursor moves go too
+        assert_eq!(options.render("\x1b[1;31mred\x1b[0m\tx\n"), "red x\n");
+        assert_eq!(options.render("\x1b]0;title\x07\x1b[2Kdone\x1b]0;t\x1b\\\n"), "done\n");
+        assert_eq!(RenderOptions::default().with_tab_width(4).render("\x1b[31mab\tc"), "\x1b[31mab  c");
+    }
+
+    #[test]
+    fn test_load_preferences() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("client.toml");
+        assert_eq!(RenderOptions::load(&path).unwrap(), RenderOptions::default());
+
+        std::fs::write(&path, "[render]\nline_endings = \"lf\"\ntab_width = 8\n").unwrap();
+        let options = RenderOptions::load(&path).unwrap();
+        assert_eq!(options.line_endings, LineEndings::Lf);
+        assert_eq!(options.tab_width, Some(8));
+        assert_eq!(options.ansi, AnsiSequences::Keep);
+
+        std::fs::write(&path, "[render]\nansi = \"sometimes\"\n").unwrap();
+        assert!(RenderOptions::load(&path).is_err());
+    }
+}
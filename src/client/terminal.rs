@@ -1,24 +1,42 @@
-use crate::client::{FshClient, CommandOutputType};
-use crate::protocol::{FshError, FshResult};
+use crate::client::{FshClient, CommandOutputType, CompletionRequest, ErrorAction, JobOutput, LineEditor, ReadLine, RenderOptions};
+use crate::protocol::message::{HistoryEntry, ObservedEvent, WatchRunMessage};
+use crate::protocol::{FshError, FshResult, OutputType, FEATURE_COLLABORATE, FEATURE_HISTORY, FEATURE_SESSION_RESUME};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
-    style::{Color, Print, ResetColor, SetForegroundColor},
+    style::{Color, Print, ResetColor, SetForegroundColor, Stylize},
     terminal::{self, ClearType},
 };
 use std::collections::HashMap;
 use std::io::{Write, stdout};
+use std::time::Duration;
 use tracing::debug;
 
+/// How often the terminal checks for server notices while waiting for input.
+const NOTICE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many commands to recall from the server when a session starts.
+const HISTORY_RECALL_LIMIT: u32 = 500;
+
+/// Commands the terminal runs itself, offered when completing a command name.
+const BUILTIN_COMMANDS: &[&str] = &[
+    "bind", "clear", "dir", "env", "exit", "export", "folder", "help", "history", "jobs", "kill", "ls",
+    "quit", "share", "unset", "wait",
+];
+
 pub struct Terminal {
     client: FshClient,
     current_prompt: String,
     current_directory: String,
     command_history: Vec<String>,
-    history_index: usize,
-    input_buffer: String,
-    cursor_position: usize,
+    /// Started at the first prompt, with the history recalled by then.
+    editor: Option<LineEditor>,
+    /// Token to log in with instead of the default one.
+    token: Option<String>,
+    /// Another client's shared session to join instead of binding a folder.
+    join: Option<String>,
+    render: RenderOptions,
 }
 
 impl Terminal {
@@ -28,19 +46,45 @@ impl Terminal {
             current_prompt: "FSH> ".to_string(),
             current_directory: "/".to_string(),
             command_history: Vec::new(),
-            history_index: 0,
-            input_buffer: String::new(),
-            cursor_position: 0,
+            editor: None,
+            token: None,
+            join: None,
+            render: RenderOptions::default(),
         }
     }
 
+    /// Use a preconfigured client, e.g. one set up for TLS.
+    pub fn with_client(mut self, client: FshClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Log in with `token` unless the client has a certificate.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Join another client's session, once its owner has run `share rw`,
+    /// instead of starting one.
+    pub fn with_session_to_join(mut self, session_id: impl Into<String>) -> Self {
+        self.join = Some(session_id.into());
+        self
+    }
+
+    /// Show command output with these line endings, tabs and ANSI handling.
+    pub fn with_render_options(mut self, render: RenderOptions) -> Self {
+        self.render = render;
+        self
+    }
+
     pub async fn run(&mut self) -> FshResult<()> {
         // Setup terminal
         terminal::enable_raw_mode()
-            .map_err(|e| FshError::NetworkError(format!("Failed to enable raw mode: {}", e)))?;
+            .map_err(|e| FshError::io("Failed to enable raw mode", e))?;
 
         execute!(stdout(), terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))
-            .map_err(|e| FshError::NetworkError(format!("Terminal setup failed: {}", e)))?;
+            .map_err(|e| FshError::io("Terminal setup failed", e))?;
 
         // Show welcome message
         self.print_welcome().await?;
@@ -81,15 +125,29 @@ impl Terminal {
         // Connect
         self.client.connect().await?;
 
-        // Authenticate (simple token for now)
-        let mut credentials = HashMap::new();
-        credentials.insert("token".to_string(), "default".to_string());
+        // Authenticate with the client certificate if there is one, else a simple token for now
+        let result = if self.client.has_client_cert() {
+            self.client.authenticate_with_certificate().await
+        } else {
+            let mut credentials = HashMap::new();
+            credentials.insert("token".to_string(), self.token.clone().unwrap_or_else(|| "default".to_string()));
+            self.client.authenticate("token", credentials).await
+        };
 
-        if let Err(e) = self.client.authenticate("token", credentials).await {
+        if let Err(e) = result {
             // Authentication might not be required
             debug!("Authentication not required or failed: {}", e);
         }
 
+        if let Some(session_id) = self.join.clone() {
+            let folder_info = self.client.join_session(&session_id).await?;
+            self.current_directory = self.client.working_directory().unwrap_or("/").to_string();
+            self.print_success(&format!(
+                "Joined session {} in folder {}; commands you run are seen by its owner", session_id, folder_info.name
+            )).await?;
+            return Ok(());
+        }
+
         // Get available folders and let user choose
         self.print_status("Getting available folders...").await?;
 
@@ -97,8 +155,23 @@ impl Terminal {
         // In a real implementation, you'd show a list and let the user choose
         let folder_name = self.prompt_for_folder().await?;
 
-        // Bind to folder
-        let folder_info = self.client.bind_folder(&folder_name, None).await?;
+        // Pick up the session from before a reconnect if the server saved it, else bind the folder
+        let resumed = match self.client.previous_session_id().map(str::to_string) {
+            Some(previous) if self.client.capabilities().supports(FEATURE_SESSION_RESUME) => {
+                match self.client.resume_session(&previous, None).await {
+                    Ok(folder_info) => Some(folder_info),
+                    Err(e) => {
+                        debug!("Session {} not resumed: {}", previous, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let folder_info = match resumed {
+            Some(folder_info) => folder_info,
+            None => self.client.bind_folder(&folder_name, None).await?,
+        };
 
         self.print_status(&format!("Bound to folder: {}", folder_info.name)).await?;
 
@@ -110,6 +183,18 @@ impl Terminal {
 
         self.print_success(&format!("Session ready! Working directory: {}", self.current_directory)).await?;
 
+        // Recall what was run on the server, e.g. from another machine
+        if self.client.capabilities().supports(FEATURE_HISTORY) {
+            match self.client.history(Some(HISTORY_RECALL_LIMIT)).await {
+                Ok(entries) => {
+                    self.command_history = merge_history(entries, &self.command_history);
+                    // Start the editor over so ↑ reaches what was recalled
+                    self.editor = None;
+                }
+                Err(e) => debug!("Failed to fetch command history: {}", e),
+            }
+        }
+
         Ok(())
     }
 
@@ -121,8 +206,19 @@ impl Terminal {
 
     async fn terminal_loop(&mut self) -> FshResult<()> {
         loop {
-            // Display prompt and current input
-            self.display_prompt().await?;
+            // Report background job output and notices that arrived since the last prompt
+            while let Some(output) = self.client.take_job_output() {
+                self.print_job_output(output).await?;
+            }
+            self.print_notices().await?;
+
+            // Nothing but the token is accepted while the server has the session locked
+            if self.client.is_locked() {
+                if !self.unlock_session().await? {
+                    break;
+                }
+                continue;
+            }
 
             // Handle input
             match self.read_input().await? {
@@ -133,7 +229,6 @@ impl Terminal {
 
                     // Add to history
                     self.command_history.push(command.clone());
-                    self.history_index = self.command_history.len();
 
                     // Handle built-in commands
                     if self.handle_builtin_command(&command).await? {
@@ -142,7 +237,7 @@ impl Terminal {
 
                     // Execute command on server
                     if let Err(e) = self.execute_remote_command(&command).await {
-                        self.print_error(&format!("Command failed: {}", e)).await?;
+                        self.handle_command_error(&command, e).await?;
                     }
                 }
                 InputResult::Exit => {
@@ -162,133 +257,122 @@ impl Terminal {
         Ok(())
     }
 
-    async fn display_prompt(&mut self) -> FshResult<()> {
-        execute!(
-            stdout(),
-            Print("\r"),
-            terminal::Clear(ClearType::CurrentLine),
-            SetForegroundColor(Color::Green),
-            Print(&self.current_prompt),
-            ResetColor,
-            Print(&self.input_buffer),
-        ).map_err(|e| FshError::NetworkError(format!("Display error: {}", e)))?;
-
-        // Position cursor
-        let prompt_len = self.current_prompt.len();
-        execute!(
-            stdout(),
-            cursor::MoveTo((prompt_len + self.cursor_position) as u16, cursor::position().unwrap().1)
-        ).map_err(|e| FshError::NetworkError(format!("Cursor error: {}", e)))?;
+    async fn handle_command_error(&mut self, command: &str, error: FshError) -> FshResult<()> {
+        match ErrorAction::for_error(&error) {
+            ErrorAction::Retry => {
+                self.print_status(&format!("{}; retrying...", error)).await?;
+                match self.execute_remote_command(command).await {
+                    Ok(()) => {}
+                    // Failing again the same way, the connection is likely gone, e.g. the server restarted
+                    Err(e) if ErrorAction::for_error(&e) == ErrorAction::Retry => self.reconnect(&e).await?,
+                    Err(e) => self.print_error(&format!("Command failed: {}", e)).await?,
+                }
+            }
+            ErrorAction::Reauthenticate => self.reconnect(&error).await?,
+            ErrorAction::Abort => {
+                self.print_error(&format!("Command failed: {}", error)).await?;
+            }
+        }
 
-        stdout().flush()
-            .map_err(|e| FshError::NetworkError(format!("Flush error: {}", e)))?;
+        Ok(())
+    }
 
+    /// Connect again after `error`, resuming the session if the server kept
+    /// it, and resend a change that may not have been made.
+    async fn reconnect(&mut self, error: &FshError) -> FshResult<()> {
+        self.print_status(&format!("{}; reconnecting...", error)).await?;
+        if let Err(e) = self.client.disconnect().await {
+            debug!("Disconnect before reconnect failed: {}", e);
+        }
+        if let Err(e) = self.connect_and_setup().await {
+            self.print_error(&format!("Reconnect failed: {}", e)).await?;
+        } else if let Some(operation_id) = self.client.pending_operation().map(str::to_string) {
+            // The server skips the change if it was made before the connection dropped
+            match self.client.retry_operation().await {
+                Ok(_) => self.print_status(&format!("Resent unfinished change {}", operation_id)).await?,
+                Err(e) => self.print_error(&format!("Resending change {} failed: {}", operation_id, e)).await?,
+            }
+        }
         Ok(())
     }
 
     async fn read_input(&mut self) -> FshResult<InputResult> {
-        loop {
-            if let Ok(event) = event::read() {
-                match event {
-                    Event::Key(KeyEvent { code, modifiers, .. }) => {
-                        match (code, modifiers) {
-                            // Ctrl+C
-                            (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
-                                return Ok(InputResult::Exit);
-                            }
-
-                            // Ctrl+D
-                            (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
-                                if self.input_buffer.is_empty() {
-                                    return Ok(InputResult::Exit);
-                                }
-                            }
-
-                            // Enter
-                            (KeyCode::Enter, _) => {
-                                println!(); // New line
-                                let command = self.input_buffer.clone();
-                                self.input_buffer.clear();
-                                self.cursor_position = 0;
-                                return Ok(InputResult::Command(command));
-                            }
-
-                            // Backspace
-                            (KeyCode::Backspace, _) => {
-                                if self.cursor_position > 0 {
-                                    self.input_buffer.remove(self.cursor_position - 1);
-                                    self.cursor_position -= 1;
-                                }
-                            }
-
-                            // Delete
-                            (KeyCode::Delete, _) => {
-                                if self.cursor_position < self.input_buffer.len() {
-                                    self.input_buffer.remove(self.cursor_position);
-                                }
-                            }
-
-                            // Arrow keys
-                            (KeyCode::Left, _) => {
-                                if self.cursor_position > 0 {
-                                    self.cursor_position -= 1;
-                                }
-                            }
-
-                            (KeyCode::Right, _) => {
-                                if self.cursor_position < self.input_buffer.len() {
-                                    self.cursor_position += 1;
-                                }
-                            }
-
-                            (KeyCode::Up, _) => {
-                                if self.history_index > 0 {
-                                    self.history_index -= 1;
-                                    if let Some(cmd) = self.command_history.get(self.history_index) {
-                                        self.input_buffer = cmd.clone();
-                                        self.cursor_position = self.input_buffer.len();
-                                    }
-                                }
-                            }
-
-                            (KeyCode::Down, _) => {
-                                if self.history_index < self.command_history.len() {
-                                    self.history_index += 1;
-                                    if self.history_index == self.command_history.len() {
-                                        self.input_buffer.clear();
-                                        self.cursor_position = 0;
-                                    } else if let Some(cmd) = self.command_history.get(self.history_index) {
-                                        self.input_buffer = cmd.clone();
-                                        self.cursor_position = self.input_buffer.len();
-                                    }
-                                }
-                            }
-
-                            // Tab completion (placeholder)
-                            (KeyCode::Tab, _) => {
-                                // TODO: Implement tab completion
-                            }
-
-                            // Regular character input
-                            (KeyCode::Char(c), _) => {
-                                self.input_buffer.insert(self.cursor_position, c);
-                                self.cursor_position += 1;
-                            }
-
-                            _ => {}
-                        }
+        let mut editor = match self.editor.take() {
+            Some(editor) => editor,
+            None => LineEditor::new(&self.command_history)?,
+        };
+        let result = self.read_line(&mut editor).await;
+        self.editor = Some(editor);
+        result
+    }
 
-                        self.display_prompt().await?;
-                        return Ok(InputResult::Continue);
+    async fn read_line(&mut self, editor: &mut LineEditor) -> FshResult<InputResult> {
+        let mut line = editor.read_line(&self.current_prompt);
+        let mut lock_shown = false;
+        loop {
+            tokio::select! {
+                read = &mut line => {
+                    return Ok(match read? {
+                        // The line was typed before the session was locked
+                        ReadLine::Line(_) if self.client.is_locked() => InputResult::Continue,
+                        ReadLine::Line(command) => InputResult::Command(command),
+                        ReadLine::Exit => InputResult::Exit,
+                    });
+                }
+                Some(request) = editor.next_completion() => {
+                    let candidates = self.complete(&request).await;
+                    request.respond(candidates);
+                }
+                // Check for notices while the user types, showing them above the prompt
+                _ = tokio::time::sleep(NOTICE_POLL_INTERVAL) => {
+                    if let Err(e) = self.client.poll_messages().await {
+                        debug!("Failed to check for server messages: {}", e);
+                    }
+                    let notices = self.take_notices();
+                    if !notices.is_empty() {
+                        editor.print(notices);
+                    }
+                    if self.client.is_locked() && !lock_shown {
+                        editor.print(colored("[INFO] Session locked; press Enter to unlock it\n", Color::Yellow));
+                        lock_shown = true;
                     }
-                    _ => {}
                 }
             }
         }
     }
 
+    /// Commands or remote paths that could replace the word being completed.
+    async fn complete(&mut self, request: &CompletionRequest) -> Vec<String> {
+        let word = request.word();
+        if request.is_command() && !word.contains('/') {
+            let history = self.command_history.iter().filter_map(|command| command.split_whitespace().next());
+            let mut commands: Vec<String> = BUILTIN_COMMANDS.iter().copied().chain(history)
+                .filter(|command| command.starts_with(word))
+                .map(str::to_string)
+                .collect();
+            commands.sort();
+            commands.dedup();
+            return commands;
+        }
+
+        let (directory, prefix) = match word.rfind('/') {
+            Some(index) => word.split_at(index + 1),
+            None => ("", word),
+        };
+        match self.client.list_files(directory, prefix.starts_with('.')).await {
+            Ok(files) => files.into_iter()
+                .filter(|file| file.name.starts_with(prefix))
+                .map(|file| format!("{}{}{}", directory, file.name, if file.is_directory { "/" } else { "" }))
+                .collect(),
+            Err(e) => {
+                debug!("Failed to complete {}: {}", word, e);
+                Vec::new()
+            }
+        }
+    }
+
     async fn handle_builtin_command(&mut self, command: &str) -> FshResult<bool> {
-        let parts: Vec<&str> = command.trim().split_whitespace().collect();
+        let parts: Vec<&str> = command.split_whitespace().collect();
         if parts.is_empty() {
             return Ok(false);
         }
@@ -296,23 +380,172 @@ impl Terminal {
         match parts[0] {
             "exit" | "quit" => {
                 self.print_status("Goodbye!").await?;
-                return Ok(true); // This will cause exit
+                Ok(true) // This will cause exit
             }
 
             "help" => {
                 self.show_help().await?;
-                return Ok(true);
+                Ok(true)
             }
 
             "clear" => {
                 execute!(stdout(), terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))
-                    .map_err(|e| FshError::NetworkError(format!("Clear failed: {}", e)))?;
-                return Ok(true);
+                    .map_err(|e| FshError::io("Clear failed", e))?;
+                Ok(true)
             }
 
             "history" => {
                 self.show_history().await?;
-                return Ok(true);
+                Ok(true)
+            }
+
+            "jobs" => {
+                match self.client.list_jobs().await {
+                    Ok(jobs) if jobs.is_empty() => self.print_status("No background jobs").await?,
+                    Ok(jobs) => {
+                        for job in jobs {
+                            println!("[{}]  Running    {}", job.job_id, job.command);
+                        }
+                    }
+                    Err(e) => self.print_error(&format!("Failed to list jobs: {}", e)).await?,
+                }
+                Ok(true)
+            }
+
+            "kill" if parts.get(1).is_some_and(|arg| arg.starts_with('%')) => {
+                match parts[1][1..].parse::<u32>() {
+                    Ok(job_id) => {
+                        if let Err(e) = self.client.kill_job(job_id).await {
+                            self.print_error(&format!("kill: {}", e)).await?;
+                        }
+                    }
+                    Err(_) => self.print_error(&format!("kill: invalid job spec: {}", parts[1])).await?,
+                }
+                Ok(true)
+            }
+
+            "export" if parts.len() > 1 => {
+                let assignment = command.trim_start()["export".len()..].trim();
+                match assignment.split_once('=') {
+                    Some((name, value)) => {
+                        if let Err(e) = self.client.set_env(name, Some(strip_quotes(value))).await {
+                            self.print_error(&format!("export: {}", e)).await?;
+                        }
+                    }
+                    None => self.print_error("usage: export NAME=value").await?,
+                }
+                Ok(true)
+            }
+
+            "unset" if parts.len() > 1 => {
+                for name in &parts[1..] {
+                    if let Err(e) = self.client.set_env(name, None).await {
+                        self.print_error(&format!("unset: {}", e)).await?;
+                    }
+                }
+                Ok(true)
+            }
+
+            "env" if parts.len() <= 2 => {
+                match self.client.get_env(parts.get(1).copied()).await {
+                    Ok(variables) => {
+                        let mut variables: Vec<_> = variables.into_iter().collect();
+                        variables.sort();
+                        for (name, value) in variables {
+                            println!("{}={}", name, value);
+                        }
+                    }
+                    Err(e) => self.print_error(&format!("env: {}", e)).await?,
+                }
+                Ok(true)
+            }
+
+            "folder" if parts.len() == 2 => {
+                match self.client.switch_folder(parts[1], None).await {
+                    Ok(folder_info) => {
+                        self.current_prompt = self.client.shell_prompt().unwrap_or(&self.current_prompt).to_string();
+                        self.current_directory = self.client.working_directory().unwrap_or("/").to_string();
+                        self.print_success(&format!("Switched to folder: {}", folder_info.name)).await?;
+                    }
+                    Err(e) => self.print_error(&format!("folder: {}", e)).await?,
+                }
+                Ok(true)
+            }
+
+            "bind" if parts.len() == 2 => {
+                match self.client.bind_root(parts[1]).await {
+                    Ok(bound) => self.print_success(&format!("Bound folder {} as {}", bound.folder_info.name, bound.root)).await?,
+                    Err(e) => self.print_error(&format!("bind: {}", e)).await?,
+                }
+                Ok(true)
+            }
+
+            "share" if matches!(parts.get(1).copied(), None | Some("on") | Some("rw") | Some("off")) => {
+                let session_id = self.client.session_id().unwrap_or_default().to_string();
+                match parts.get(1).copied() {
+                    Some("rw") => match self.client.allow_collaborators(true).await {
+                        Ok(_) => {
+                            self.print_status(&format!(
+                                "Others may now run commands in this session with: fsh-client join {}", session_id
+                            )).await?;
+                        }
+                        Err(e) => self.print_error(&format!("share: {}", e)).await?,
+                    },
+                    Some("off") => {
+                        let participants = if self.client.capabilities().supports(FEATURE_COLLABORATE) {
+                            self.client.allow_collaborators(false).await
+                        } else {
+                            Ok(0)
+                        };
+                        match (self.client.allow_observers(false).await, participants) {
+                            (Ok(observers), Ok(participants)) => {
+                                self.print_status(&format!(
+                                    "Stopped sharing; {} observer(s) and {} participant(s) detached", observers, participants
+                                )).await?;
+                            }
+                            (Err(e), _) | (_, Err(e)) => self.print_error(&format!("share: {}", e)).await?,
+                        }
+                    }
+                    _ => match self.client.allow_observers(true).await {
+                        Ok(_) => {
+                            self.print_status(&format!(
+                                "Others may now watch this session read-only with: fsh-client observe {}", session_id
+                            )).await?;
+                        }
+                        Err(e) => self.print_error(&format!("share: {}", e)).await?,
+                    },
+                }
+                Ok(true)
+            }
+
+            "wait" => {
+                let job_id = match parts.get(1).map(|arg| arg.trim_start_matches('%').parse::<u32>()) {
+                    Some(Ok(job_id)) => Some(job_id),
+                    Some(Err(_)) => {
+                        self.print_error(&format!("wait: invalid job spec: {}", parts[1])).await?;
+                        return Ok(true);
+                    }
+                    None => None,
+                };
+                if let Err(e) = self.wait_for_jobs(job_id).await {
+                    self.print_error(&format!("wait: {}", e)).await?;
+                }
+                Ok(true)
+            }
+
+            _ if command.trim_end().ends_with('&') && !command.trim_end().ends_with("&&") => {
+                let job_command = command.trim_end().trim_end_matches('&');
+                let parts: Vec<&str> = job_command.split_whitespace().collect();
+                if parts.is_empty() {
+                    return Ok(false);
+                }
+
+                let args = parts[1..].iter().map(|s| s.to_string()).collect();
+                match self.client.start_job(parts[0], args).await {
+                    Ok(job_id) => println!("[{}] {}", job_id, job_command.trim()),
+                    Err(e) => self.print_error(&format!("Failed to start job: {}", e)).await?,
+                }
+                Ok(true)
             }
 
             "ls" | "dir" => {
@@ -320,17 +553,17 @@ impl Terminal {
                 if let Err(e) = self.list_files(parts.get(1).unwrap_or(&".")).await {
                     self.print_error(&format!("Failed to list files: {}", e)).await?;
                 }
-                return Ok(true);
+                Ok(true)
             }
 
             _ => {
-                return Ok(false); // Not a built-in command
+                Ok(false) // Not a built-in command
             }
         }
     }
 
     async fn execute_remote_command(&mut self, command: &str) -> FshResult<()> {
-        let parts: Vec<&str> = command.trim().split_whitespace().collect();
+        let parts: Vec<&str> = command.split_whitespace().collect();
         if parts.is_empty() {
             return Ok(());
         }
@@ -344,12 +577,15 @@ impl Terminal {
         // Display output as it comes
         while let Some(output) = output_rx.recv().await {
             match output.output_type {
+                CommandOutputType::Stdout | CommandOutputType::Stderr if output.raw.is_some() => {
+                    self.print_binary_notice(output.raw.as_ref().map_or(0, |raw| raw.len())).await?;
+                }
                 CommandOutputType::Stdout => {
-                    print!("{}", output.data);
+                    print!("{}", self.rendered(&output.data));
                     stdout().flush().unwrap();
                 }
                 CommandOutputType::Stderr => {
-                    self.print_colored(&output.data, Color::Red).await?;
+                    self.print_colored(&self.rendered(&output.data), Color::Red).await?;
                 }
                 CommandOutputType::Complete => {
                     debug!("{}", output.data);
@@ -362,6 +598,58 @@ impl Terminal {
             }
         }
 
+        // The command may have moved the session to another directory
+        if let Some(prompt) = self.client.shell_prompt() {
+            self.current_prompt = prompt.to_string();
+        }
+        if let Some(working_dir) = self.client.working_directory() {
+            self.current_directory = working_dir.to_string();
+        }
+
+        Ok(())
+    }
+
+    /// Print job output until `job_id` (or every job) has finished.
+    async fn wait_for_jobs(&mut self, job_id: Option<u32>) -> FshResult<()> {
+        if let Some(job_id) = job_id {
+            if !self.client.running_jobs().any(|id| id == job_id) {
+                return Err(FshError::ShellError(format!("no such job: %{}", job_id)));
+            }
+        }
+
+        while let Some(output) = self.client.next_job_output().await? {
+            let finished = output.exit_code.is_some() && Some(output.job_id) == job_id;
+            self.print_job_output(output).await?;
+            if finished {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn print_job_output(&mut self, output: JobOutput) -> FshResult<()> {
+        match output.output.output_type {
+            CommandOutputType::Stdout | CommandOutputType::Stderr if output.output.raw.is_some() => {
+                self.print_binary_notice(output.output.raw.as_ref().map_or(0, |raw| raw.len())).await?;
+            }
+            CommandOutputType::Stdout => {
+                print!("{}", self.rendered(&output.output.data));
+                stdout().flush().unwrap();
+            }
+            CommandOutputType::Stderr => {
+                self.print_colored(&self.rendered(&output.output.data), Color::Red).await?;
+            }
+            CommandOutputType::Complete => match output.exit_code {
+                Some(0) => println!("[{}]+  Done", output.job_id),
+                Some(code) => println!("[{}]+  Exit {}", output.job_id, code),
+                None => debug!("{}", output.output.data),
+            },
+            CommandOutputType::Error => {
+                self.print_error(&output.output.data).await?;
+            }
+        }
+
         Ok(())
     }
 
@@ -377,7 +665,7 @@ impl Terminal {
                 SetForegroundColor(color),
                 Print(format!("{} {:>10} {}\n", prefix, file.size, file.name)),
                 ResetColor
-            ).map_err(|e| FshError::NetworkError(format!("Print error: {}", e)))?;
+            ).map_err(|e| FshError::io("Print error", e))?;
         }
 
         Ok(())
@@ -393,15 +681,30 @@ Built-in commands:
   clear         - Clear the screen
   history       - Show command history
   ls, dir       - List files and directories
+  <command> &   - Run a command as a background job
+  jobs          - List running background jobs
+  kill %N       - Kill background job N
+  export N=V    - Set a variable for later commands
+  unset NAME    - Remove a variable set for later commands
+  env [NAME]    - Show variables set for this session
+  folder NAME   - Move this session to another folder
+  bind NAME     - Make another folder reachable as /NAME in this session
+  share [off]   - Let others watch this session read-only, or stop
+  share rw      - Let others run commands in this session too
+  wait [%N]     - Show job output until job N (or every job) finishes
 
 Remote commands:
   All other commands are executed on the remote folder.
   The available commands depend on the folder configuration.
 
-Navigation:
+Editing:
   ↑/↓           - Navigate command history
-  ←/→           - Move cursor in input line
-  Tab           - Auto-complete (coming soon)
+  Ctrl+R        - Search command history
+  Alt+B/Alt+F   - Move a word back or forward (also Ctrl+←/→)
+  Ctrl+W/Alt+D  - Cut the word before or after the cursor
+  Ctrl+K/Ctrl+U - Cut to the end or start of the line
+  Ctrl+Y        - Paste what was cut last
+  Tab           - Complete commands and remote paths
   Ctrl+C        - Exit
   Ctrl+D        - Exit (if input is empty)
 
@@ -424,13 +727,149 @@ Navigation:
         Ok(())
     }
 
+    /// Ask for the token until the session is unlocked, returning `false` if
+    /// the user gave up instead.
+    async fn unlock_session(&mut self) -> FshResult<bool> {
+        while self.client.is_locked() {
+            let Some(token) = self.read_secret("Token: ").await? else {
+                return Ok(false);
+            };
+            match self.client.unlock(&token).await {
+                Ok(()) => self.print_status("Session unlocked").await?,
+                Err(e @ FshError::PermissionDenied(_)) => self.print_error(&e.to_string()).await?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read a line without echoing it, or `None` on Ctrl+C or Ctrl+D.
+    async fn read_secret(&mut self, prompt: &str) -> FshResult<Option<String>> {
+        execute!(stdout(), Print("\r"), terminal::Clear(ClearType::CurrentLine), Print(prompt))
+            .map_err(|e| FshError::io("Display error", e))?;
+        stdout().flush().map_err(|e| FshError::io("Flush error", e))?;
+
+        let mut secret = String::new();
+        loop {
+            let event = event::read().map_err(|e| FshError::io("Input error", e))?;
+            if let Event::Key(KeyEvent { code, modifiers, .. }) = event {
+                match (code, modifiers) {
+                    (KeyCode::Char('c' | 'd'), KeyModifiers::CONTROL) => {
+                        print!("\r\n");
+                        return Ok(None);
+                    }
+                    (KeyCode::Enter, _) => {
+                        print!("\r\n");
+                        return Ok(Some(secret));
+                    }
+                    (KeyCode::Backspace, _) => {
+                        secret.pop();
+                    }
+                    (KeyCode::Char(c), _) => secret.push(c),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Print notices, and what others did in a shared session, that have
+    /// arrived on their own lines.
+    async fn print_notices(&mut self) -> FshResult<()> {
+        let notices = self.take_notices();
+        if !notices.is_empty() {
+            print!("{}", notices.replace('\n', "\r\n"));
+            stdout().flush().map_err(|e| FshError::io("Flush error", e))?;
+        }
+        Ok(())
+    }
+
+    /// Notices, shared session activity and watch rule runs that have
+    /// arrived, as colored lines.
+    fn take_notices(&mut self) -> String {
+        let mut notices = String::new();
+        while let Some(event) = self.client.take_observed() {
+            notices.push_str(&self.shared_activity(event));
+        }
+        while let Some(notice) = self.client.take_notice() {
+            notices.push_str(&colored(
+                &format!("[NOTICE {}] {}\n", notice.sent_at.with_timezone(&chrono::Local).format("%H:%M"), notice.message),
+                Color::Magenta,
+            ));
+        }
+        while let Some(run) = self.client.take_watch_run() {
+            notices.push_str(&self.watch_run(run));
+        }
+        notices
+    }
+
+    fn watch_run(&self, run: WatchRunMessage) -> String {
+        let (outcome, color) = match (run.exit_code, &run.error) {
+            (Some(0), _) => ("ok".to_string(), Color::Green),
+            (Some(code), _) => (format!("exit {}", code), Color::Red),
+            (None, Some(error)) => (error.clone(), Color::Red),
+            (None, None) => ("failed".to_string(), Color::Red),
+        };
+        let mut text = colored(
+            &format!("[WATCH {}] {}: {} ({})\n", run.rule, run.command, outcome, run.changed.join(", ")),
+            color,
+        );
+        if run.exit_code != Some(0) && !run.output.is_empty() {
+            text.push_str(&self.render.render(run.output.trim_end()));
+            text.push('\n');
+        }
+        text
+    }
+
+    fn shared_activity(&mut self, event: ObservedEvent) -> String {
+        match event {
+            ObservedEvent::Command(command) => {
+                colored(&format!("(owner)$ {} {}\n", command.command, command.args.join(" ")), Color::Magenta)
+            }
+            ObservedEvent::ParticipantCommand { participant, command } => {
+                colored(&format!("({})$ {} {}\n", participant, command.command, command.args.join(" ")), Color::Magenta)
+            }
+            ObservedEvent::ParticipantJoined { participant } => {
+                colored(&format!("[{} joined the session]\n", participant), Color::Magenta)
+            }
+            ObservedEvent::ParticipantLeft { participant } => {
+                colored(&format!("[{} left the session]\n", participant), Color::Magenta)
+            }
+            ObservedEvent::Output(output) if output.binary => {
+                colored(&format!("[{} bytes of binary output]\n", output.data.len()), Color::Magenta)
+            }
+            ObservedEvent::Output(output) => {
+                let data = self.render.render(&String::from_utf8_lossy(&output.data));
+                match output.output_type {
+                    OutputType::Stdout => data,
+                    OutputType::Stderr => colored(&data, Color::Red),
+                }
+            }
+            ObservedEvent::Complete(complete) if complete.exit_code != 0 => {
+                colored(&format!("[exit {}]\n", complete.exit_code), Color::Magenta)
+            }
+            ObservedEvent::Complete(_) => String::new(),
+            ObservedEvent::WorkingDirChanged(changed) => {
+                self.current_prompt = changed.shell_prompt;
+                self.current_directory = changed.working_directory;
+                String::new()
+            }
+            ObservedEvent::Error(error) => colored(&format!("[ERROR] {}\n", error.message), Color::Red),
+        }
+    }
+
+    /// Command output as the render options show it, with every line break
+    /// a `\r\n` as the terminal's raw mode needs.
+    fn rendered(&self, output: &str) -> String {
+        self.render.render(output).replace("\r\n", "\n").replace('\n', "\r\n")
+    }
+
     async fn print_status(&self, message: &str) -> FshResult<()> {
         execute!(
             stdout(),
             SetForegroundColor(Color::Yellow),
             Print(format!("[INFO] {}\n", message)),
             ResetColor
-        ).map_err(|e| FshError::NetworkError(format!("Print error: {}", e)))?;
+        ).map_err(|e| FshError::io("Print error", e))?;
 
         Ok(())
     }
@@ -441,7 +880,7 @@ Navigation:
             SetForegroundColor(Color::Green),
             Print(format!("[SUCCESS] {}\n", message)),
             ResetColor
-        ).map_err(|e| FshError::NetworkError(format!("Print error: {}", e)))?;
+        ).map_err(|e| FshError::io("Print error", e))?;
 
         Ok(())
     }
@@ -452,33 +891,68 @@ Navigation:
             SetForegroundColor(Color::Red),
             Print(format!("[ERROR] {}\n", message)),
             ResetColor
-        ).map_err(|e| FshError::NetworkError(format!("Print error: {}", e)))?;
+        ).map_err(|e| FshError::io("Print error", e))?;
 
         Ok(())
     }
 
+    /// Binary output would garble the terminal, so only its size is shown.
+    async fn print_binary_notice(&self, bytes: usize) -> FshResult<()> {
+        self.print_status(&format!(
+            "{} bytes of binary output not shown; use `fsh-client exec --binary-output FILE` to save it", bytes
+        )).await
+    }
+
     async fn print_colored(&self, message: &str, color: Color) -> FshResult<()> {
         execute!(
             stdout(),
             SetForegroundColor(color),
             Print(message),
             ResetColor
-        ).map_err(|e| FshError::NetworkError(format!("Print error: {}", e)))?;
+        ).map_err(|e| FshError::io("Print error", e))?;
 
         Ok(())
     }
 
     fn cleanup_terminal(&self) -> FshResult<()> {
         terminal::disable_raw_mode()
-            .map_err(|e| FshError::NetworkError(format!("Failed to disable raw mode: {}", e)))?;
+            .map_err(|e| FshError::io("Failed to disable raw mode", e))?;
 
         execute!(stdout(), ResetColor, cursor::Show)
-            .map_err(|e| FshError::NetworkError(format!("Terminal cleanup failed: {}", e)))?;
+            .map_err(|e| FshError::io("Terminal cleanup failed", e))?;
 
         Ok(())
     }
 }
 
+/// Server history followed by local history, without the same command twice
+/// in a row.
+fn merge_history(remote: Vec<HistoryEntry>, local: &[String]) -> Vec<String> {
+    let mut merged: Vec<String> = Vec::with_capacity(remote.len() + local.len());
+    for command in remote.into_iter().map(|entry| entry.command).chain(local.iter().cloned()) {
+        if merged.last() != Some(&command) {
+            merged.push(command);
+        }
+    }
+    merged
+}
+
+/// `value` without one pair of surrounding quotes, as a shell would read
+/// `export NAME="value"`.
+fn strip_quotes(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|rest| rest.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    value
+}
+
+/// `text` in `color`, line by line so each line stands alone above the prompt.
+fn colored(text: &str, color: Color) -> String {
+    text.lines().map(|line| format!("{}\n", line.with(color))).collect()
+}
+
 #[derive(Debug)]
 enum InputResult {
     Command(String),
@@ -497,4 +971,27 @@ mod tests {
         assert_eq!(terminal.current_directory, "/");
         assert!(terminal.command_history.is_empty());
     }
+
+    #[test]
+    fn test_merge_history() {
+        let remote = ["ls", "ls", "make"].iter().map(|command| HistoryEntry {
+            command: command.to_string(),
+            working_directory: "/".to_string(),
+            exit_code: 0,
+            session_id: "earlier".to_string(),
+            ran_at: chrono::Utc::now(),
+        }).collect();
+        let local = vec!["make".to_string(), "git status".to_string()];
+        assert_eq!(merge_history(remote, &local), vec!["ls", "make", "git status"]);
+        assert!(merge_history(Vec::new(), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_strip_quotes() {
+        assert_eq!(strip_quotes("\"en_US.UTF-8\""), "en_US.UTF-8");
+        assert_eq!(strip_quotes("'a b'"), "a b");
+        assert_eq!(strip_quotes("plain"), "plain");
+        assert_eq!(strip_quotes("\"unbalanced"), "\"unbalanced");
+        assert_eq!(strip_quotes("\""), "\"");
+    }
 }
\ No newline at end of file
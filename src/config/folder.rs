@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use crate::protocol::{FshError, FshResult, ShellType, Permission};
+use crate::sandbox::{CommandPolicy, ContainerConfig, HoneypotConfig, HookConfig, OutputEncoding, SymlinkPolicy, TrashConfig, WasiConfig};
+use crate::security::{Policy, PolicyConfig};
+use crate::server::{HistoryConfig, ScheduledTask, WatchRule};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FolderConfig {
@@ -15,6 +18,85 @@ pub struct FolderConfig {
     pub description: Option<String>,
     pub readonly: bool,
     pub environment_vars: HashMap<String, String>,
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// External commands running at once in this folder across sessions;
+    /// more queue until one finishes.
+    #[serde(default)]
+    pub max_running_commands: Option<usize>,
+    /// Output in KB streamed for each command; the rest is written to a
+    /// file in the folder's `.fsh-output` directory.
+    #[serde(default)]
+    pub max_command_output_kb: Option<u64>,
+    /// Encoding of command output, e.g. `utf-16le` or `cp936`, converted to
+    /// UTF-8; `None` detects UTF-16 and the Windows code page.
+    #[serde(default)]
+    pub output_encoding: Option<String>,
+    #[serde(default)]
+    pub session_timeout_minutes: Option<u64>,
+    /// File transfer rate limit in KB/s shared by all sessions on this folder.
+    #[serde(default)]
+    pub transfer_rate_limit_kbps: Option<u64>,
+    /// File transfer rate limit in KB/s for each session on this folder.
+    #[serde(default)]
+    pub session_transfer_rate_limit_kbps: Option<u64>,
+    #[serde(default)]
+    pub symlink_policy: SymlinkPolicy,
+    /// Server environment variables passed to commands; `None` uses the built-in allowlist.
+    #[serde(default)]
+    pub env_allowlist: Option<Vec<String>>,
+    /// Pass the server's entire environment to commands instead of the allowlist.
+    #[serde(default)]
+    pub inherit_environment: bool,
+    /// Variables clients may set with `export`, e.g. `["LANG", "LC_*"]`;
+    /// `None` uses the built-in list. Names such as `PATH` and `LD_*` are
+    /// never settable.
+    #[serde(default)]
+    pub settable_env: Option<Vec<String>>,
+    /// Deleted files go to the folder's trash so they can be restored.
+    #[serde(default)]
+    pub trash: TrashConfig,
+    /// Named command sequences clients invoke with `run <name> [args...]`.
+    #[serde(default)]
+    pub macros: BTreeMap<String, String>,
+    /// Commands run before and after each client command.
+    #[serde(default)]
+    pub hooks: HookConfig,
+    /// Commands remembered for clients to recall.
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Rules checked before the allowed/blocked command lists.
+    #[serde(default)]
+    pub policy: Option<PolicyConfig>,
+    /// Allowed subcommands and refused flags, by program name.
+    #[serde(default)]
+    pub command_policies: BTreeMap<String, CommandPolicy>,
+    /// Command line patterns (`*` matches anything) that wait for an
+    /// operator's approval before running.
+    #[serde(default)]
+    pub requires_approval: Vec<String>,
+    #[serde(default)]
+    pub honeypot: HoneypotConfig,
+    /// Run commands in a Docker or Podman container with the folder mounted.
+    #[serde(default)]
+    pub container: ContainerConfig,
+    /// Run some commands as WebAssembly modules that can only see the folder.
+    #[serde(default)]
+    pub wasi: WasiConfig,
+    /// Client certificate names (CN or SAN) allowed to bind this folder.
+    /// Empty allows every authenticated client.
+    #[serde(default)]
+    pub allowed_identities: Vec<String>,
+    /// Ports on the server's loopback interface that clients may forward
+    /// connections to through a session, e.g. a dev server on 3000.
+    #[serde(default)]
+    pub forward_ports: Vec<u16>,
+    /// Commands the server runs in the folder on a cron schedule.
+    #[serde(default)]
+    pub schedule: Vec<ScheduledTask>,
+    /// Commands the server runs in the folder when matching files change.
+    #[serde(default)]
+    pub watch: Vec<WatchRule>,
 }
 
 impl FolderConfig {
@@ -30,6 +112,31 @@ impl FolderConfig {
             description: None,
             readonly: false,
             environment_vars: HashMap::new(),
+            max_sessions: None,
+            max_running_commands: None,
+            max_command_output_kb: None,
+            output_encoding: None,
+            session_timeout_minutes: None,
+            transfer_rate_limit_kbps: None,
+            session_transfer_rate_limit_kbps: None,
+            symlink_policy: SymlinkPolicy::default(),
+            env_allowlist: None,
+            inherit_environment: false,
+            settable_env: None,
+            trash: TrashConfig::default(),
+            macros: BTreeMap::new(),
+            hooks: HookConfig::default(),
+            history: HistoryConfig::default(),
+            policy: None,
+            command_policies: BTreeMap::new(),
+            requires_approval: Vec::new(),
+            honeypot: HoneypotConfig::default(),
+            container: ContainerConfig::default(),
+            wasi: WasiConfig::default(),
+            allowed_identities: Vec::new(),
+            forward_ports: Vec::new(),
+            schedule: Vec::new(),
+            watch: Vec::new(),
         }
     }
 
@@ -67,6 +174,126 @@ impl FolderConfig {
         self
     }
 
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    pub fn with_env_allowlist(mut self, allowlist: Vec<String>) -> Self {
+        self.env_allowlist = Some(allowlist);
+        self
+    }
+
+    pub fn with_inherit_environment(mut self, inherit: bool) -> Self {
+        self.inherit_environment = inherit;
+        self
+    }
+
+    pub fn with_settable_env(mut self, settable: Vec<String>) -> Self {
+        self.settable_env = Some(settable);
+        self
+    }
+
+    pub fn with_trash(mut self, trash: TrashConfig) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    pub fn with_macro(mut self, name: String, body: String) -> Self {
+        self.macros.insert(name, body);
+        self
+    }
+
+    pub fn with_watch_rule(mut self, rule: WatchRule) -> Self {
+        self.watch.push(rule);
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: HookConfig) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn with_history(mut self, history: HistoryConfig) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: PolicyConfig) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn with_command_policy(mut self, program: String, policy: CommandPolicy) -> Self {
+        self.command_policies.insert(program, policy);
+        self
+    }
+
+    pub fn with_requires_approval(mut self, pattern: String) -> Self {
+        self.requires_approval.push(pattern);
+        self
+    }
+
+    pub fn with_honeypot(mut self, honeypot: HoneypotConfig) -> Self {
+        self.honeypot = honeypot;
+        self
+    }
+
+    pub fn with_container(mut self, container: ContainerConfig) -> Self {
+        self.container = container;
+        self
+    }
+
+    pub fn with_wasi(mut self, wasi: WasiConfig) -> Self {
+        self.wasi = wasi;
+        self
+    }
+
+    pub fn with_allowed_identity(mut self, identity: String) -> Self {
+        self.allowed_identities.push(identity);
+        self
+    }
+
+    pub fn with_forward_port(mut self, port: u16) -> Self {
+        self.forward_ports.push(port);
+        self
+    }
+
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    pub fn with_max_running_commands(mut self, max_running_commands: usize) -> Self {
+        self.max_running_commands = Some(max_running_commands);
+        self
+    }
+
+    pub fn with_max_command_output_kb(mut self, kb: u64) -> Self {
+        self.max_command_output_kb = Some(kb);
+        self
+    }
+
+    pub fn with_output_encoding(mut self, encoding: &str) -> Self {
+        self.output_encoding = Some(encoding.to_string());
+        self
+    }
+
+    pub fn with_session_timeout_minutes(mut self, minutes: u64) -> Self {
+        self.session_timeout_minutes = Some(minutes);
+        self
+    }
+
+    pub fn with_transfer_rate_limit_kbps(mut self, kbps: u64) -> Self {
+        self.transfer_rate_limit_kbps = Some(kbps);
+        self
+    }
+
+    pub fn with_session_transfer_rate_limit_kbps(mut self, kbps: u64) -> Self {
+        self.session_transfer_rate_limit_kbps = Some(kbps);
+        self
+    }
+
     pub fn add_environment_var(mut self, key: String, value: String) -> Self {
         self.environment_vars.insert(key, value);
         self
@@ -92,6 +319,16 @@ impl FolderConfig {
         self.has_permission(&Permission::Execute)
     }
 
+    /// Whether a client whose certificate carries `names` may bind this folder.
+    pub fn allows_identity(&self, names: &[String]) -> bool {
+        self.allowed_identities.is_empty()
+            || self.allowed_identities.iter().any(|allowed| names.contains(allowed))
+    }
+
+    pub fn can_forward_port(&self, port: u16) -> bool {
+        self.forward_ports.contains(&port)
+    }
+
     pub fn is_command_allowed(&self, command: &str) -> bool {
         // First check if it's explicitly blocked
         if self.blocked_commands.iter().any(|blocked| command.contains(blocked)) {
@@ -141,23 +378,26 @@ impl FolderConfig {
             return Err(FshError::ConfigError("Folder name contains invalid characters".to_string()));
         }
 
-        // Check if path exists and is a directory
+        // Check if path exists and is a directory; a honeypot's path is only
+        // a template for its snapshots and may be missing
         let path = PathBuf::from(&self.path);
-        if !path.exists() {
-            return Err(FshError::FolderNotFound(self.path.clone()));
-        }
+        if !self.honeypot.enabled {
+            if !path.exists() {
+                return Err(FshError::FolderNotFound(self.path.clone()));
+            }
 
-        if !path.is_dir() {
-            return Err(FshError::ConfigError(
-                format!("Path '{}' is not a directory", self.path)
-            ));
-        }
+            if !path.is_dir() {
+                return Err(FshError::ConfigError(
+                    format!("Path '{}' is not a directory", self.path)
+                ));
+            }
 
-        // Check if path is accessible
-        if let Err(e) = std::fs::read_dir(&path) {
-            return Err(FshError::PermissionDenied(
-                format!("Cannot access directory '{}': {}", self.path, e)
-            ));
+            // Check if path is accessible
+            if let Err(e) = std::fs::read_dir(&path) {
+                return Err(FshError::PermissionDenied(
+                    format!("Cannot access directory '{}': {}", self.path, e)
+                ));
+            }
         }
 
         // Validate permissions
@@ -170,6 +410,63 @@ impl FolderConfig {
             return Err(FshError::ConfigError("Cannot have write permission on readonly folder".to_string()));
         }
 
+        if self.max_sessions == Some(0) {
+            return Err(FshError::ConfigError("max_sessions must be greater than 0".to_string()));
+        }
+
+        if self.max_running_commands == Some(0) {
+            return Err(FshError::ConfigError("max_running_commands must be greater than 0".to_string()));
+        }
+
+        if self.max_command_output_kb == Some(0) {
+            return Err(FshError::ConfigError("max_command_output_kb must be greater than 0".to_string()));
+        }
+
+        if let Some(encoding) = &self.output_encoding {
+            OutputEncoding::parse(encoding)?;
+        }
+
+        if let Some(policy) = &self.policy {
+            Policy::new(policy)?;
+        }
+
+        self.container.validate()?;
+        self.wasi.validate()?;
+        // Honeypot sessions each get their own copy of the folder to mount
+        if self.container.enabled && self.honeypot.enabled {
+            return Err(FshError::ConfigError("A honeypot folder cannot run commands in a container".to_string()));
+        }
+
+        if self.forward_ports.contains(&0) {
+            return Err(FshError::ConfigError("Forwardable ports must be greater than 0".to_string()));
+        }
+
+        for (index, task) in self.schedule.iter().enumerate() {
+            task.validate()?;
+            if self.schedule[..index].iter().any(|other| other.name == task.name) {
+                return Err(FshError::ConfigError(format!("Folder '{}' has more than one task named '{}'", self.name, task.name)));
+            }
+        }
+        for (index, rule) in self.watch.iter().enumerate() {
+            rule.validate()?;
+            if self.watch[..index].iter().any(|other| other.name == rule.name) {
+                return Err(FshError::ConfigError(format!("Folder '{}' has more than one watch rule named '{}'", self.name, rule.name)));
+            }
+        }
+        // Its snapshots only exist while a client is connected
+        if self.honeypot.enabled && !(self.schedule.is_empty() && self.watch.is_empty()) {
+            return Err(FshError::ConfigError("A honeypot folder cannot have scheduled tasks or watch rules".to_string()));
+        }
+
+        for (name, body) in &self.macros {
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(FshError::ConfigError(format!("Invalid macro name '{}'", name)));
+            }
+            if body.split("&&").any(|step| step.trim().is_empty()) {
+                return Err(FshError::ConfigError(format!("Macro '{}' contains an empty step", name)));
+            }
+        }
+
         Ok(())
     }
 
@@ -437,5 +734,10 @@ mod tests {
         // Test invalid name
         let invalid_name_config = FolderConfig::new("test*".to_string(), temp_dir.path());
         assert!(invalid_name_config.validate().is_err());
+
+        // Test zero session limit
+        let zero_sessions_config = FolderConfig::new("test".to_string(), temp_dir.path())
+            .with_max_sessions(0);
+        assert!(zero_sessions_config.validate().is_err());
     }
 }
\ No newline at end of file
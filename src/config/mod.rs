@@ -1,25 +1,414 @@
 pub mod folder;
+pub mod secrets;
+pub mod users;
 
 pub use folder::*;
+pub use secrets::*;
+pub use users::*;
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use crate::protocol::{FshError, FshResult};
+use crate::protocol::{
+    CompressionAlgorithm, FshError, FshResult, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_MAX_FRAME_LENGTH,
+    DEFAULT_MAX_MESSAGE_LENGTH, FRAGMENT_LENGTH, PRE_AUTH_MAX_FRAME_LENGTH,
+};
+use crate::security::{DlpConfig, LockoutConfig, MalwareScanConfig, RateLimitConfig, TokenConfig, TokenStoreConfig};
+use crate::server::{AgentConfig, DiscoveryConfig, NotificationsConfig, RelayConfig, WebDavConfig};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub security: SecurityConfig,
     pub folders: Vec<FolderConfig>,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub observability: ObservabilityConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub session_state: SessionStateConfig,
+    #[serde(default)]
+    pub malware_scan: MalwareScanConfig,
+    #[serde(default)]
+    pub dlp: DlpConfig,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+    #[serde(default)]
+    pub webdav: WebDavConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub relay: RelayConfig,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub users: UsersConfig,
+    /// Values that were encrypted in the file this config was loaded from.
+    #[serde(skip)]
+    encrypted: EncryptedValues,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
+    /// 0 picks a free port.
     pub port: u16,
+    /// Addresses to listen on instead of `host`, each a host (on `port`) or
+    /// `host:port`, e.g. `["0.0.0.0", "[::]"]` for IPv4 and IPv6.
+    #[serde(default)]
+    pub listen: Vec<String>,
     pub max_connections: usize,
+    /// Connections one client address may have open at once; `None` leaves
+    /// only `max_connections`.
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+    /// External commands running at once across all sessions; more queue
+    /// until one finishes. `None` doesn't limit them.
+    #[serde(default)]
+    pub max_running_commands: Option<usize>,
+    /// External commands, including background jobs, one session may run
+    /// at once; more are refused.
+    #[serde(default)]
+    pub max_running_commands_per_session: Option<usize>,
     pub connection_timeout_seconds: u64,
     pub session_timeout_minutes: u64,
+    /// Minutes of inactivity after which a session locks until the client
+    /// re-enters its token; 0 never locks.
+    #[serde(default)]
+    pub idle_lock_minutes: u64,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub keepalive: KeepaliveConfig,
+    #[serde(default)]
+    pub handshake: HandshakeConfig,
+    #[serde(default)]
+    pub frames: FrameConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub noise: NoiseConfig,
+}
+
+impl ServerConfig {
+    /// The `host:port` addresses to listen on: each of `listen`, or else `host`.
+    pub fn bind_addresses(&self) -> Vec<String> {
+        if self.listen.is_empty() {
+            vec![with_port(&self.host, self.port)]
+        } else {
+            self.listen.iter().map(|address| with_port(address, self.port)).collect()
+        }
+    }
+}
+
+/// `address` with `port` added unless it already has one.
+fn with_port(address: &str, port: u16) -> String {
+    if address.parse::<std::net::SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+    if let Ok(ip) = address.trim_start_matches('[').trim_end_matches(']').parse::<std::net::IpAddr>() {
+        return std::net::SocketAddr::new(ip, port).to_string();
+    }
+    match address.rsplit_once(':') {
+        Some((_, address_port)) if address_port.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{}:{}", address, port),
+    }
+}
+
+/// Dead-peer detection for established sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveConfig {
+    /// Ping the client after this many seconds without receiving anything (0 = never ping).
+    pub interval_seconds: u64,
+    /// How long the client has to answer a ping.
+    pub timeout_seconds: u64,
+    /// Consecutive unanswered pings before the session is terminated.
+    pub max_missed: u32,
+    /// Enable OS-level TCP keepalive probes on accepted sockets.
+    pub tcp_keepalive: bool,
+    /// Seconds of socket inactivity before the first TCP keepalive probe.
+    pub tcp_keepalive_idle_seconds: u64,
+    /// Seconds between TCP keepalive probes.
+    pub tcp_keepalive_interval_seconds: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 30,
+            timeout_seconds: 10,
+            max_missed: 3,
+            tcp_keepalive: true,
+            tcp_keepalive_idle_seconds: 60,
+            tcp_keepalive_interval_seconds: 15,
+        }
+    }
+}
+
+/// Limits on connections that haven't finished the handshake, so clients
+/// that connect and go quiet can't hold connection slots.
+/// `connection_timeout_seconds` still caps the handshake as a whole.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandshakeConfig {
+    /// Seconds for the TLS handshake, and again for the Connect message.
+    pub connect_timeout_seconds: u64,
+    /// Seconds to authenticate, across every attempt.
+    pub auth_timeout_seconds: u64,
+    /// Seconds to bind a folder once authenticated.
+    pub bind_timeout_seconds: u64,
+    /// Handshakes one client address may have in progress at once.
+    pub max_pending_per_ip: usize,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_seconds: 10,
+            auth_timeout_seconds: 30,
+            bind_timeout_seconds: 15,
+            max_pending_per_ip: 8,
+        }
+    }
+}
+
+/// Message size limits, by how far the client has got.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameConfig {
+    /// Largest frame accepted before the client has authenticated.
+    pub pre_auth_max_bytes: usize,
+    /// Largest frame accepted afterwards.
+    pub max_frame_bytes: usize,
+    /// Largest message clients that support fragmentation may send or
+    /// receive in several frames.
+    pub max_message_bytes: usize,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        Self {
+            pre_auth_max_bytes: PRE_AUTH_MAX_FRAME_LENGTH,
+            max_frame_bytes: DEFAULT_MAX_FRAME_LENGTH,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_LENGTH,
+        }
+    }
+}
+
+/// TLS for client connections, optionally requiring client certificates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain presented to clients.
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key for `cert_file`.
+    pub key_file: Option<PathBuf>,
+    /// PEM bundle of CAs whose client certificates are accepted. Clients that
+    /// present one can log in with the `certificate` auth method.
+    pub client_ca_file: Option<PathBuf>,
+    /// Refuse the TLS handshake unless the client presents a certificate
+    /// signed by `client_ca_file`.
+    pub require_client_cert: bool,
+}
+
+/// Noise_XX encryption offered to clients in the connect handshake, for
+/// deployments without X.509 certificates. Both sides prove a static key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoiseConfig {
+    pub enabled: bool,
+    /// The server's private key, generated on first start if missing.
+    pub key_file: Option<PathBuf>,
+    /// Base64 public keys of the clients allowed to connect.
+    pub authorized_keys: Vec<String>,
+    /// Refuse clients that don't ask for Noise.
+    pub required: bool,
+}
+
+/// Payload compression offered to clients during the connect handshake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Algorithms the server is willing to use; the client's preference order decides among them.
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Payloads smaller than this many bytes are sent uncompressed.
+    pub threshold_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            algorithms: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Deflate],
+            threshold_bytes: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Algorithms that may be negotiated, empty when compression is disabled.
+    pub fn allowed_algorithms(&self) -> &[CompressionAlgorithm] {
+        if self.enabled {
+            &self.algorithms
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Local HTTP API for operators.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub listen: String,
+    /// Bearer token required on every request; `None` leaves the API open to
+    /// anyone who can reach `listen`.
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:7878".to_string(),
+            token: None,
+        }
+    }
+}
+
+/// Server log output.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+}
+
+impl LoggingConfig {
+    /// The `[logging]` section of a config file, read before logging is set
+    /// up and without decrypting anything. Unreadable files give the default.
+    pub fn peek<P: AsRef<Path>>(path: P) -> Self {
+        #[derive(Deserialize)]
+        struct Partial {
+            #[serde(default)]
+            logging: LoggingConfig,
+        }
+
+        std::fs::read_to_string(path).ok()
+            .and_then(|content| toml::from_str::<Partial>(&content).ok())
+            .map(|partial| partial.logging)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span.
+    Json,
+}
+
+/// Export of traces and metrics to an OpenTelemetry collector over OTLP/HTTP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObservabilityConfig {
+    pub enabled: bool,
+    /// Collector base URL; `/v1/traces` and `/v1/metrics` are appended.
+    pub otlp_endpoint: String,
+    pub service_name: String,
+    pub traces: bool,
+    pub metrics: bool,
+    pub metrics_interval_seconds: u64,
+    /// Extra HTTP headers sent to the collector, e.g. for authentication.
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for ObservabilityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            otlp_endpoint: "http://localhost:4318".to_string(),
+            service_name: "fsh-server".to_string(),
+            traces: true,
+            metrics: true,
+            metrics_interval_seconds: 60,
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Per-folder usage statistics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// JSON file the totals are saved to and restored from; without one
+    /// they start from zero on every restart.
+    pub file: Option<PathBuf>,
+    pub persist_interval_seconds: u64,
+    /// How often the CPU and memory of each session's commands is sampled;
+    /// 0 turns sampling off.
+    pub resource_sample_seconds: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            persist_interval_seconds: 60,
+            resource_sample_seconds: 5,
+        }
+    }
+}
+
+/// Saving sessions' working directory, variables and history so clients
+/// can resume them after the server restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionStateConfig {
+    /// Directory sessions are saved in; without one they aren't saved.
+    pub directory: Option<PathBuf>,
+    /// Hours a saved session can still be resumed.
+    pub retention_hours: u64,
+}
+
+impl Default for SessionStateConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            retention_hours: 24,
+        }
+    }
+}
+
+/// How commands that need an operator's approval are handled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApprovalConfig {
+    /// Requests not decided within this many seconds are denied.
+    pub timeout_seconds: u64,
+    /// URL that receives a JSON POST for each new approval request.
+    pub webhook_url: Option<String>,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: 300,
+            webhook_url: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +418,20 @@ pub struct SecurityConfig {
     pub max_failed_attempts: u32,
     pub enable_logging: bool,
     pub log_file: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    /// Tokens accepted besides those created at runtime.
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// External store to validate tokens against instead.
+    #[serde(default)]
+    pub token_store: Option<TokenStoreConfig>,
+    /// Accept the well-known token `default` with full permissions. For
+    /// local development only.
+    #[serde(default)]
+    pub development_mode: bool,
 }
 
 impl Default for Config {
@@ -37,9 +440,20 @@ impl Default for Config {
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 2222,
+                listen: Vec::new(),
                 max_connections: 10,
+                max_connections_per_ip: None,
+                max_running_commands: None,
+                max_running_commands_per_session: None,
                 connection_timeout_seconds: 30,
                 session_timeout_minutes: 60,
+                idle_lock_minutes: 0,
+                compression: CompressionConfig::default(),
+                keepalive: KeepaliveConfig::default(),
+                handshake: HandshakeConfig::default(),
+                frames: FrameConfig::default(),
+                tls: TlsConfig::default(),
+                noise: NoiseConfig::default(),
             },
             security: SecurityConfig {
                 require_authentication: true,
@@ -47,8 +461,28 @@ impl Default for Config {
                 max_failed_attempts: 3,
                 enable_logging: true,
                 log_file: None,
+                rate_limits: RateLimitConfig::default(),
+                lockout: LockoutConfig::default(),
+                tokens: vec![],
+                token_store: None,
+                development_mode: false,
             },
             folders: vec![],
+            admin: AdminConfig::default(),
+            approval: ApprovalConfig::default(),
+            logging: LoggingConfig::default(),
+            observability: ObservabilityConfig::default(),
+            stats: StatsConfig::default(),
+            session_state: SessionStateConfig::default(),
+            malware_scan: MalwareScanConfig::default(),
+            dlp: DlpConfig::default(),
+            notifications: NotificationsConfig::default(),
+            webdav: WebDavConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            relay: RelayConfig::default(),
+            agent: AgentConfig::default(),
+            users: UsersConfig::default(),
+            encrypted: EncryptedValues::default(),
         }
     }
 }
@@ -58,13 +492,25 @@ impl Config {
         let content = std::fs::read_to_string(path)
             .map_err(|e| FshError::ConfigError(format!("Failed to read config file: {}", e)))?;
 
-        toml::from_str(&content)
-            .map_err(|e| FshError::ConfigError(format!("Failed to parse config file: {}", e)))
+        Self::parse(&content, &mut MasterKey::load)
+    }
+
+    /// Parse a config file, decrypting `enc:v1:` values with the key from `key`.
+    fn parse(content: &str, key: &mut impl FnMut() -> FshResult<MasterKey>) -> FshResult<Self> {
+        let parse_error = |e: toml::de::Error| FshError::ConfigError(format!("Failed to parse config file: {}", e));
+        if !content.contains(ENCRYPTED_PREFIX) {
+            return toml::from_str(content).map_err(parse_error);
+        }
+
+        let mut value: toml::Value = toml::from_str(content).map_err(parse_error)?;
+        let encrypted = EncryptedValues::decrypt_tree(&mut value, key)?;
+        let mut config: Config = value.try_into().map_err(parse_error)?;
+        config.encrypted = encrypted;
+        Ok(config)
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> FshResult<()> {
-        let content = toml::to_string_pretty(self)
-            .map_err(|e| FshError::ConfigError(format!("Failed to serialize config: {}", e)))?;
+        let content = self.to_toml(&mut MasterKey::load)?;
 
         std::fs::write(path, content)
             .map_err(|e| FshError::ConfigError(format!("Failed to write config file: {}", e)))?;
@@ -72,6 +518,19 @@ impl Config {
         Ok(())
     }
 
+    /// Serialize the config, encrypting again the values that were encrypted
+    /// when it was loaded.
+    fn to_toml(&self, key: &mut impl FnMut() -> FshResult<MasterKey>) -> FshResult<String> {
+        let serialize_error = |e: toml::ser::Error| FshError::ConfigError(format!("Failed to serialize config: {}", e));
+        if self.encrypted.is_empty() {
+            return toml::to_string_pretty(self).map_err(serialize_error);
+        }
+
+        let mut value = toml::Value::try_from(self).map_err(serialize_error)?;
+        self.encrypted.encrypt_tree(&mut value, &key()?)?;
+        toml::to_string_pretty(&value).map_err(serialize_error)
+    }
+
     pub fn load_or_create_default<P: AsRef<Path>>(path: P) -> FshResult<Self> {
         let path = path.as_ref();
 
@@ -140,6 +599,26 @@ impl Config {
         Ok(())
     }
 
+    /// Idle timeout for sessions bound to `folder`, honoring the folder override.
+    /// Returns `None` when the effective timeout is 0 (sessions never expire).
+    pub fn session_idle_timeout(&self, folder: &FolderConfig) -> Option<std::time::Duration> {
+        let minutes = folder.session_timeout_minutes.unwrap_or(self.server.session_timeout_minutes);
+        if minutes == 0 {
+            None
+        } else {
+            Some(std::time::Duration::from_secs(minutes * 60))
+        }
+    }
+
+    /// How long a session may sit idle before it locks, or `None` when
+    /// sessions never lock.
+    pub fn session_idle_lock(&self) -> Option<std::time::Duration> {
+        match self.server.idle_lock_minutes {
+            0 => None,
+            minutes => Some(std::time::Duration::from_secs(minutes * 60)),
+        }
+    }
+
     pub fn get_default_config_path() -> FshResult<PathBuf> {
         let config_dir = if cfg!(windows) {
             // Windows: %APPDATA%\FSH
@@ -159,23 +638,129 @@ impl Config {
 
     pub fn validate(&self) -> FshResult<()> {
         // Validate server config
-        if self.server.port == 0 || self.server.port > 65535 {
-            return Err(FshError::ConfigError("Invalid port number".to_string()));
-        }
-
         if self.server.max_connections == 0 {
             return Err(FshError::ConfigError("max_connections must be greater than 0".to_string()));
         }
 
+        if self.server.listen.iter().any(|address| address.trim().is_empty()) {
+            return Err(FshError::ConfigError("Listen addresses cannot be empty".to_string()));
+        }
+
+        if self.server.max_connections_per_ip == Some(0) {
+            return Err(FshError::ConfigError("max_connections_per_ip must be greater than 0".to_string()));
+        }
+
+        if self.server.max_running_commands == Some(0) || self.server.max_running_commands_per_session == Some(0) {
+            return Err(FshError::ConfigError("max_running_commands limits must be greater than 0".to_string()));
+        }
+
+        if self.server.keepalive.interval_seconds > 0 && self.server.keepalive.timeout_seconds == 0 {
+            return Err(FshError::ConfigError("keepalive timeout_seconds must be greater than 0".to_string()));
+        }
+
+        let handshake = &self.server.handshake;
+        if handshake.connect_timeout_seconds == 0 || handshake.auth_timeout_seconds == 0 || handshake.bind_timeout_seconds == 0 {
+            return Err(FshError::ConfigError("handshake timeouts must be greater than 0".to_string()));
+        }
+
+        if handshake.max_pending_per_ip == 0 {
+            return Err(FshError::ConfigError("handshake max_pending_per_ip must be greater than 0".to_string()));
+        }
+
+        let frames = &self.server.frames;
+        if frames.pre_auth_max_bytes < 1024 {
+            return Err(FshError::ConfigError("frames pre_auth_max_bytes must be at least 1024".to_string()));
+        }
+
+        // Peers split fragmented messages into frames of this size
+        if frames.max_frame_bytes < FRAGMENT_LENGTH {
+            return Err(FshError::ConfigError(format!("frames max_frame_bytes must be at least {}", FRAGMENT_LENGTH)));
+        }
+
+        if frames.max_message_bytes < frames.max_frame_bytes {
+            return Err(FshError::ConfigError("frames max_message_bytes cannot be less than max_frame_bytes".to_string()));
+        }
+
+        if self.server.keepalive.max_missed == 0 {
+            return Err(FshError::ConfigError("keepalive max_missed must be greater than 0".to_string()));
+        }
+
+        let tls = &self.server.tls;
+        if tls.enabled && (tls.cert_file.is_none() || tls.key_file.is_none()) {
+            return Err(FshError::ConfigError("tls cert_file and key_file are required when TLS is enabled".to_string()));
+        }
+
+        if tls.require_client_cert && tls.client_ca_file.is_none() {
+            return Err(FshError::ConfigError("tls require_client_cert needs a client_ca_file".to_string()));
+        }
+
+        let noise = &self.server.noise;
+        if noise.enabled && noise.key_file.is_none() {
+            return Err(FshError::ConfigError("noise key_file is required when Noise is enabled".to_string()));
+        }
+
+        if noise.required && !noise.enabled {
+            return Err(FshError::ConfigError("noise required needs Noise to be enabled".to_string()));
+        }
+
         // Validate security config
         if self.security.require_authentication && self.security.auth_methods.is_empty() {
             return Err(FshError::ConfigError("At least one auth method must be specified when authentication is required".to_string()));
         }
 
+        if self.security.auth_methods.iter().any(|method| method == "certificate")
+            && !(tls.enabled && tls.client_ca_file.is_some()) {
+            return Err(FshError::ConfigError(
+                "The certificate auth method needs TLS with a client_ca_file".to_string()
+            ));
+        }
+
+        if self.security.token_store.is_some() && !self.security.auth_methods.iter().any(|method| method == "token") {
+            return Err(FshError::ConfigError("A token_store needs the token auth method".to_string()));
+        }
+
+        if self.observability.enabled {
+            if !self.observability.otlp_endpoint.starts_with("http://") && !self.observability.otlp_endpoint.starts_with("https://") {
+                return Err(FshError::ConfigError("observability.otlp_endpoint must be an http:// or https:// URL".to_string()));
+            }
+            if self.observability.metrics && self.observability.metrics_interval_seconds == 0 {
+                return Err(FshError::ConfigError("observability.metrics_interval_seconds must be greater than 0".to_string()));
+            }
+        }
+
+        if self.stats.file.is_some() && self.stats.persist_interval_seconds == 0 {
+            return Err(FshError::ConfigError("stats.persist_interval_seconds must be greater than 0".to_string()));
+        }
+
+        if self.session_state.directory.is_some() && self.session_state.retention_hours == 0 {
+            return Err(FshError::ConfigError("session_state.retention_hours must be greater than 0".to_string()));
+        }
+
+        self.malware_scan.validate()?;
+        self.dlp.validate()?;
+        self.notifications.validate()?;
+        self.webdav.validate()?;
+        self.relay.validate()?;
+        self.agent.validate()?;
+        if self.webdav.enabled && self.security.require_authentication
+            && !self.security.auth_methods.iter().any(|method| method == "token")
+        {
+            return Err(FshError::ConfigError("webdav needs the 'token' auth method".to_string()));
+        }
+
+        if self.security.rate_limits.window_seconds == 0 {
+            return Err(FshError::ConfigError("rate_limits.window_seconds must be greater than 0".to_string()));
+        }
+
+        if self.admin.enabled && self.admin.listen.parse::<std::net::SocketAddr>().is_err() {
+            return Err(FshError::ConfigError(format!("Invalid admin listen address '{}'", self.admin.listen)));
+        }
+
         // Validate all folders
         for folder in &self.folders {
             folder.validate()?;
         }
+        self.users.validate(&self.folders)?;
 
         Ok(())
     }
@@ -184,6 +769,8 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{Permission, ShellType};
+    use std::collections::HashMap;
     use tempfile::TempDir;
 
     #[test]
@@ -228,9 +815,35 @@ mod tests {
             shell_type: ShellType::Bash,
             allowed_commands: vec!["ls".to_string()],
             blocked_commands: vec!["rm".to_string()],
+            system_aware_commands: None,
             description: Some("Test folder".to_string()),
             readonly: false,
             environment_vars: HashMap::new(),
+            max_sessions: None,
+            max_running_commands: None,
+            max_command_output_kb: None,
+            output_encoding: None,
+            session_timeout_minutes: None,
+            transfer_rate_limit_kbps: None,
+            session_transfer_rate_limit_kbps: None,
+            symlink_policy: crate::sandbox::SymlinkPolicy::default(),
+            env_allowlist: None,
+            inherit_environment: false,
+            settable_env: None,
+            trash: crate::sandbox::TrashConfig::default(),
+            macros: std::collections::BTreeMap::new(),
+            hooks: crate::sandbox::HookConfig::default(),
+            history: crate::server::HistoryConfig::default(),
+            policy: None,
+            command_policies: std::collections::BTreeMap::new(),
+            requires_approval: Vec::new(),
+            honeypot: crate::sandbox::HoneypotConfig::default(),
+            container: crate::sandbox::ContainerConfig::default(),
+            wasi: crate::sandbox::WasiConfig::default(),
+            allowed_identities: vec![],
+            forward_ports: vec![],
+            schedule: vec![],
+            watch: vec![],
         };
 
         config.add_folder(folder.clone()).unwrap();
@@ -243,4 +856,99 @@ mod tests {
         config.remove_folder("test").unwrap();
         assert_eq!(config.folders.len(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_session_idle_timeout() {
+        let config = Config::default();
+        let temp_dir = TempDir::new().unwrap();
+
+        let folder = FolderConfig::new("test".to_string(), temp_dir.path());
+        assert_eq!(config.session_idle_timeout(&folder), Some(std::time::Duration::from_secs(60 * 60)));
+
+        let folder = folder.with_session_timeout_minutes(5);
+        assert_eq!(config.session_idle_timeout(&folder), Some(std::time::Duration::from_secs(5 * 60)));
+
+        let folder = folder.with_session_timeout_minutes(0);
+        assert_eq!(config.session_idle_timeout(&folder), None);
+    }
+
+    #[test]
+    fn test_session_idle_lock() {
+        let mut config = Config::default();
+        assert_eq!(config.session_idle_lock(), None);
+
+        config.server.idle_lock_minutes = 10;
+        assert_eq!(config.session_idle_lock(), Some(std::time::Duration::from_secs(10 * 60)));
+    }
+
+    #[test]
+    fn test_bind_addresses() {
+        let mut config = Config::default();
+        assert_eq!(config.server.bind_addresses(), ["127.0.0.1:2222"]);
+
+        config.server.listen = ["0.0.0.0", "::", "[::1]:2300", "localhost", "lab.example.com:2400"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(config.server.bind_addresses(), [
+            "0.0.0.0:2222", "[::]:2222", "[::1]:2300", "localhost:2222", "lab.example.com:2400",
+        ]);
+    }
+
+    #[test]
+    fn test_tls_validation() {
+        let mut config = Config::default();
+        config.server.tls.enabled = true;
+        assert!(config.validate().is_err());
+
+        config.server.tls.cert_file = Some(PathBuf::from("server.pem"));
+        config.server.tls.key_file = Some(PathBuf::from("server.key"));
+        assert!(config.validate().is_ok());
+
+        // Certificate logins need a CA to verify client certificates against
+        config.security.auth_methods.push("certificate".to_string());
+        assert!(config.validate().is_err());
+        config.server.tls.client_ca_file = Some(PathBuf::from("clients-ca.pem"));
+        config.server.tls.require_client_cert = true;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_encrypted_values() {
+        let key = MasterKey::generate();
+        let encoded = key.to_base64();
+        let mut load_key = || MasterKey::from_base64(&encoded);
+
+        let hash = crate::security::AuthManager::hash_token("ci-secret");
+        let mut config = Config::default();
+        config.security.tokens.push(crate::security::TokenConfig {
+            description: "CI".to_string(),
+            hash: key.encrypt(&hash).unwrap(),
+            permissions: vec![],
+            expires_at: None,
+            user: None,
+        });
+        let content = toml::to_string_pretty(&config).unwrap();
+        let config = Config::parse(&content, &mut load_key).unwrap();
+        assert_eq!(config.security.tokens[0].hash, hash);
+
+        // Saving writes the secret encrypted again
+        let saved = config.to_toml(&mut load_key).unwrap();
+        assert!(!saved.contains(&hash));
+        let reloaded = Config::parse(&saved, &mut load_key).unwrap();
+        assert_eq!(reloaded.security.tokens[0].hash, hash);
+
+        // Without the key an encrypted config cannot be loaded
+        assert!(Config::parse(&content, &mut || Err(FshError::ConfigError("no key".to_string()))).is_err());
+    }
+
+    #[test]
+    fn test_logging_peek() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("fsh_config.toml");
+        assert_eq!(LoggingConfig::peek(&path).format, LogFormat::Text);
+
+        // Only the [logging] section is read, so encrypted values elsewhere don't matter
+        std::fs::write(&path, "[logging]\nformat = \"json\"\n\n[admin]\ntoken = \"enc:v1:AAAA\"\n").unwrap();
+        assert_eq!(LoggingConfig::peek(&path).format, LogFormat::Json);
+    }
+}
@@ -0,0 +1,269 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::protocol::{FshError, FshResult};
+
+/// Encrypted config values look like `enc:v1:<base64 of nonce + ciphertext>`.
+pub const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+/// Environment variable holding the base64 master key.
+pub const MASTER_KEY_ENV: &str = "FSH_MASTER_KEY";
+
+/// Service and account the master key is stored under in the OS keychain.
+const KEYCHAIN_SERVICE: &str = "fsh";
+const KEYCHAIN_ACCOUNT: &str = "master-key";
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// The key that encrypts secrets in config files.
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_base64(encoded: &str) -> FshResult<Self> {
+        let bytes = BASE64.decode(encoded.trim())
+            .map_err(|e| FshError::ConfigError(format!("Invalid master key: {}", e)))?;
+        let key = bytes.try_into()
+            .map_err(|_| FshError::ConfigError("Master key must be 32 bytes".to_string()))?;
+        Ok(Self(key))
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    /// The key from `FSH_MASTER_KEY`, or failing that the OS keychain.
+    pub fn load() -> FshResult<Self> {
+        if let Ok(encoded) = std::env::var(MASTER_KEY_ENV) {
+            return Self::from_base64(&encoded);
+        }
+        match keychain_lookup(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT)? {
+            Some(encoded) => Self::from_base64(&encoded),
+            None => Err(FshError::ConfigError(format!(
+                "The config contains encrypted values but no master key was found; set {} or store one with `fsh-server secrets keygen --store`",
+                MASTER_KEY_ENV
+            ))),
+        }
+    }
+
+    /// Save the key in the OS keychain, where `load` finds it.
+    pub fn store_in_keychain(&self) -> FshResult<()> {
+        let (program, args): (&str, Vec<&str>) = if cfg!(target_os = "macos") {
+            ("security", vec!["add-generic-password", "-U", "-s", KEYCHAIN_SERVICE, "-a", KEYCHAIN_ACCOUNT, "-w"])
+        } else if cfg!(unix) {
+            ("secret-tool", vec!["store", "--label", "FSH master key", "service", KEYCHAIN_SERVICE, "account", KEYCHAIN_ACCOUNT])
+        } else {
+            return Err(FshError::ConfigError(format!(
+                "No supported keychain on this platform; set {} instead", MASTER_KEY_ENV
+            )));
+        };
+
+        // Pass the key on stdin so it never shows up in the process list
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| FshError::ConfigError(format!("Failed to run {}: {}", program, e)))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "{}", self.to_base64())
+                .map_err(|e| FshError::ConfigError(format!("Failed to write to {}: {}", program, e)))?;
+        }
+        let status = child.wait()
+            .map_err(|e| FshError::ConfigError(format!("Failed to run {}: {}", program, e)))?;
+        if !status.success() {
+            return Err(FshError::ConfigError(format!("{} could not store the master key", program)));
+        }
+        Ok(())
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> FshResult<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut sealed = plaintext.as_bytes().to_vec();
+        self.aead_key()?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+            .map_err(|_| FshError::ConfigError("Failed to encrypt value".to_string()))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(sealed);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(payload)))
+    }
+
+    pub fn decrypt(&self, value: &str) -> FshResult<String> {
+        let encoded = value.strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or_else(|| FshError::ConfigError("Value is not encrypted".to_string()))?;
+        let payload = BASE64.decode(encoded.trim())
+            .map_err(|e| FshError::ConfigError(format!("Invalid encrypted value: {}", e)))?;
+        if payload.len() < NONCE_LEN {
+            return Err(FshError::ConfigError("Invalid encrypted value: too short".to_string()));
+        }
+
+        let (nonce, sealed) = payload.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| FshError::ConfigError("Invalid encrypted value".to_string()))?;
+        let mut sealed = sealed.to_vec();
+        let plaintext = self.aead_key()?
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| FshError::ConfigError("Failed to decrypt value; wrong master key?".to_string()))?;
+
+        String::from_utf8(plaintext.to_vec())
+            .map_err(|_| FshError::ConfigError("Decrypted value is not UTF-8".to_string()))
+    }
+
+    fn aead_key(&self) -> FshResult<LessSafeKey> {
+        UnboundKey::new(&CHACHA20_POLY1305, &self.0)
+            .map(LessSafeKey::new)
+            .map_err(|_| FshError::ConfigError("Invalid master key".to_string()))
+    }
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+/// Decrypt a secret read from a file, such as a TLS key, if it is encrypted.
+pub fn decrypt_if_encrypted(content: String) -> FshResult<String> {
+    if is_encrypted(content.trim()) {
+        MasterKey::load()?.decrypt(content.trim())
+    } else {
+        Ok(content)
+    }
+}
+
+/// Plaintexts of the values that were encrypted in a loaded config, so they
+/// are encrypted again when it is saved.
+#[derive(Clone, Default)]
+pub struct EncryptedValues(BTreeSet<String>);
+
+impl EncryptedValues {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decrypt every encrypted string in `value`, fetching the key only if
+    /// there is one.
+    pub fn decrypt_tree(
+        value: &mut toml::Value,
+        key: &mut impl FnMut() -> FshResult<MasterKey>,
+    ) -> FshResult<Self> {
+        let mut key_cache = None;
+        let mut decrypted = BTreeSet::new();
+        visit_strings(value, &mut |string| {
+            if !is_encrypted(string) {
+                return Ok(());
+            }
+            let master_key = match key_cache.take() {
+                Some(master_key) => master_key,
+                None => key()?,
+            };
+            let plaintext = master_key.decrypt(string)?;
+            key_cache = Some(master_key);
+            decrypted.insert(plaintext.clone());
+            *string = plaintext;
+            Ok(())
+        })?;
+        Ok(Self(decrypted))
+    }
+
+    /// Encrypt again every string in `value` that was encrypted on load.
+    pub fn encrypt_tree(&self, value: &mut toml::Value, key: &MasterKey) -> FshResult<()> {
+        visit_strings(value, &mut |string| {
+            if self.0.contains(string.as_str()) {
+                *string = key.encrypt(string)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl std::fmt::Debug for EncryptedValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptedValues({} values)", self.0.len())
+    }
+}
+
+fn visit_strings(value: &mut toml::Value, visit: &mut impl FnMut(&mut String) -> FshResult<()>) -> FshResult<()> {
+    match value {
+        toml::Value::String(string) => visit(string),
+        toml::Value::Array(items) => items.iter_mut().try_for_each(|item| visit_strings(item, visit)),
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, item)| visit_strings(item, visit)),
+        _ => Ok(()),
+    }
+}
+
+/// The password stored in the OS keychain for `service` and `account`.
+pub(crate) fn keychain_lookup(service: &str, account: &str) -> FshResult<Option<String>> {
+    let (program, args): (&str, Vec<&str>) = if cfg!(target_os = "macos") {
+        ("security", vec!["find-generic-password", "-s", service, "-a", account, "-w"])
+    } else if cfg!(unix) {
+        ("secret-tool", vec!["lookup", "service", service, "account", account])
+    } else {
+        return Ok(None);
+    };
+
+    // A missing keychain tool just means there is nothing stored
+    let Ok(output) = Command::new(program).args(args).stderr(Stdio::null()).output() else {
+        return Ok(None);
+    };
+    let secret = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((output.status.success() && !secret.is_empty()).then_some(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = MasterKey::generate();
+        let encrypted = key.encrypt("hunter2").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(encrypted, key.encrypt("hunter2").unwrap());
+        assert_eq!(key.decrypt(&encrypted).unwrap(), "hunter2");
+
+        let restored = MasterKey::from_base64(&key.to_base64()).unwrap();
+        assert_eq!(restored.decrypt(&encrypted).unwrap(), "hunter2");
+
+        // Wrong keys and tampered values are refused
+        assert!(MasterKey::generate().decrypt(&encrypted).is_err());
+        let mut tampered = encrypted.clone();
+        tampered.replace_range(tampered.len() - 4.., "AAAA");
+        assert!(key.decrypt(&tampered).is_err());
+        assert!(MasterKey::from_base64("c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn test_tree_round_trip() {
+        let key = MasterKey::generate();
+        let mut value: toml::Value = toml::from_str(&format!(
+            "plain = \"visible\"\n[admin]\ntoken = \"{}\"", key.encrypt("s3cret").unwrap()
+        )).unwrap();
+
+        let encrypted = EncryptedValues::decrypt_tree(&mut value, &mut || MasterKey::from_base64(&key.to_base64())).unwrap();
+        assert_eq!(value["admin"]["token"].as_str(), Some("s3cret"));
+
+        encrypted.encrypt_tree(&mut value, &key).unwrap();
+        assert!(is_encrypted(value["admin"]["token"].as_str().unwrap()));
+        assert_eq!(value["plain"].as_str(), Some("visible"));
+
+        // Configs without encrypted values never need the key
+        let mut value: toml::Value = toml::from_str("plain = \"visible\"").unwrap();
+        let encrypted = EncryptedValues::decrypt_tree(&mut value, &mut || Err(FshError::ConfigError("no key".to_string()))).unwrap();
+        assert!(encrypted.is_empty());
+    }
+}
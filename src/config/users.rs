@@ -0,0 +1,265 @@
+use crate::config::FolderConfig;
+use crate::protocol::{FshError, FshResult, Permission};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Users that each see only the folders assigned to them. A client logs in
+/// as a user with a token naming it, or with a client certificate whose
+/// user has an account here (or any user, with `provision_on_login`). Other
+/// clients see every folder, as before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsersConfig {
+    /// Directory holding a home folder for each account, `<home_base>/<name>`,
+    /// created on start and bound as `home-<name>`.
+    pub home_base: Option<PathBuf>,
+    pub accounts: Vec<UserAccount>,
+    /// Also give a home folder to any user that logs in without an account,
+    /// created on its first login. Such users see only their home.
+    pub provision_on_login: bool,
+    /// Directory whose contents are copied into each new home folder.
+    pub home_template: Option<PathBuf>,
+    pub home_permissions: Vec<Permission>,
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self {
+            home_base: None,
+            accounts: Vec::new(),
+            provision_on_login: false,
+            home_template: None,
+            home_permissions: vec![Permission::Read, Permission::Write, Permission::Execute],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserAccount {
+    pub name: String,
+    /// Names of the folders the user sees besides its home.
+    #[serde(default)]
+    pub folders: Vec<String>,
+}
+
+impl UserAccount {
+    pub fn new(name: &str, folders: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            folders: folders.iter().map(|folder| folder.to_string()).collect(),
+        }
+    }
+}
+
+impl UsersConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.accounts.is_empty() || self.provision_on_login
+    }
+
+    /// Whether `name` may log in as a user: it has an account, or gets a
+    /// home on login.
+    pub fn is_user(&self, name: &str) -> bool {
+        self.account(name).is_some() || (self.provision_on_login && valid_user_name(name))
+    }
+
+    pub fn account(&self, name: &str) -> Option<&UserAccount> {
+        self.accounts.iter().find(|account| account.name == name)
+    }
+
+    pub fn home_folder_name(user: &str) -> String {
+        format!("home-{}", user)
+    }
+
+    /// Whether a client logged in as `user` sees `folder`. Clients that
+    /// aren't a user see every folder.
+    pub fn allows(&self, user: Option<&str>, folder: &str) -> bool {
+        let Some(user) = user else {
+            return true;
+        };
+        self.account(user).is_some_and(|account| account.folders.iter().any(|assigned| assigned == folder))
+            || (self.home_base.is_some() && self.is_user(user) && folder == Self::home_folder_name(user))
+    }
+
+    /// The home folder of `user`, creating its directory from the template
+    /// if it doesn't exist yet. `None` without a `home_base`.
+    pub fn home_folder(&self, user: &str) -> FshResult<Option<FolderConfig>> {
+        let Some(home_base) = &self.home_base else {
+            return Ok(None);
+        };
+        if !valid_user_name(user) {
+            return Err(FshError::ConfigError(format!("Invalid user name '{}'", user)));
+        }
+
+        let path = home_base.join(user);
+        if !path.exists() {
+            std::fs::create_dir_all(&path)
+                .map_err(|e| FshError::io(format!("Failed to create home folder {}", path.display()), e))?;
+            if let Some(template) = &self.home_template {
+                copy_template(template, &path)?;
+            }
+        }
+        Ok(Some(FolderConfig::new(Self::home_folder_name(user), &path)
+            .with_permissions(self.home_permissions.clone())
+            .with_description(format!("Home folder of {}", user))))
+    }
+
+    /// Create a home folder for every account that hasn't one yet and add
+    /// it to `folders`, along with the homes earlier logins created. A folder
+    /// already named `home-<name>` is left alone.
+    pub fn provision_homes(&self, folders: &mut Vec<FolderConfig>) -> FshResult<()> {
+        let Some(home_base) = &self.home_base else {
+            return Ok(());
+        };
+        let mut users: Vec<String> = self.accounts.iter().map(|account| account.name.clone()).collect();
+        if self.provision_on_login {
+            let mut existing: Vec<String> = std::fs::read_dir(home_base).into_iter().flatten().flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| valid_user_name(name) && !users.contains(name))
+                .collect();
+            existing.sort();
+            users.extend(existing);
+        }
+
+        for user in users {
+            let name = Self::home_folder_name(&user);
+            if folders.iter().any(|folder| folder.name == name) {
+                continue;
+            }
+            if let Some(home) = self.home_folder(&user)? {
+                folders.push(home);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, folders: &[FolderConfig]) -> FshResult<()> {
+        if self.provision_on_login && self.home_base.is_none() {
+            return Err(FshError::ConfigError("users.provision_on_login needs a home_base".to_string()));
+        }
+        if let Some(template) = self.home_template.as_ref().filter(|template| !template.is_dir()) {
+            return Err(FshError::ConfigError(format!("users.home_template {} is not a directory", template.display())));
+        }
+        if self.home_permissions.is_empty() {
+            return Err(FshError::ConfigError("users.home_permissions cannot be empty".to_string()));
+        }
+
+        let mut names = HashSet::new();
+        for account in &self.accounts {
+            if !valid_user_name(&account.name) {
+                return Err(FshError::ConfigError(format!(
+                    "Invalid user name '{}'; use letters, digits, '-', '_' and '.'", account.name
+                )));
+            }
+            if !names.insert(account.name.as_str()) {
+                return Err(FshError::ConfigError(format!("User '{}' is configured twice", account.name)));
+            }
+            if let Some(missing) = account.folders.iter().find(|name| !folders.iter().any(|folder| &folder.name == *name)) {
+                return Err(FshError::ConfigError(format!(
+                    "User '{}' is assigned folder '{}', which is not configured", account.name, missing
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// User names become directory names, so they are kept to a safe set.
+fn valid_user_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Copy the files and directories under `template` into `target`. Symlinks
+/// are skipped so a template can't reach outside itself.
+fn copy_template(template: &Path, target: &Path) -> FshResult<()> {
+    for entry in walkdir::WalkDir::new(template).min_depth(1) {
+        let entry = entry.map_err(|e| FshError::ConfigError(format!("Failed to read home template: {}", e)))?;
+        let Ok(relative) = entry.path().strip_prefix(template) else {
+            continue;
+        };
+        let destination = target.join(relative);
+        let result = if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&destination)
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &destination).map(|_| ())
+        } else {
+            Ok(())
+        };
+        result.map_err(|e| FshError::io(format!("Failed to copy {} into a home folder", entry.path().display()), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_users_see_their_folders() {
+        let temp_dir = TempDir::new().unwrap();
+        let users = UsersConfig {
+            home_base: Some(temp_dir.path().join("home")),
+            accounts: vec![UserAccount::new("alice", &["docs"]), UserAccount::new("bob", &[])],
+            ..UsersConfig::default()
+        };
+        let mut folders = vec![FolderConfig::new("docs".to_string(), temp_dir.path())];
+        users.provision_homes(&mut folders).unwrap();
+        users.validate(&folders).unwrap();
+
+        let names: Vec<&str> = folders.iter().map(|folder| folder.name.as_str()).collect();
+        assert_eq!(names, vec!["docs", "home-alice", "home-bob"]);
+        assert!(temp_dir.path().join("home/alice").is_dir());
+
+        assert!(users.allows(Some("alice"), "docs"));
+        assert!(users.allows(Some("alice"), "home-alice"));
+        assert!(!users.allows(Some("alice"), "home-bob"));
+        assert!(!users.allows(Some("bob"), "docs"));
+        assert!(!users.allows(Some("carol"), "docs"));
+        assert!(users.allows(None, "home-bob"));
+    }
+
+    #[test]
+    fn test_invalid_users() {
+        let folders = vec![FolderConfig::new("docs".to_string(), std::env::temp_dir())];
+        let users = |accounts| UsersConfig { accounts, ..UsersConfig::default() };
+        assert!(users(vec![UserAccount::new("../root", &[])]).validate(&folders).is_err());
+        assert!(users(vec![UserAccount::new("alice", &[]), UserAccount::new("alice", &[])]).validate(&folders).is_err());
+        assert!(users(vec![UserAccount::new("alice", &["missing"])]).validate(&folders).is_err());
+    }
+
+    #[test]
+    fn test_home_from_template() {
+        let temp_dir = TempDir::new().unwrap();
+        let template = temp_dir.path().join("skel");
+        std::fs::create_dir_all(template.join("src")).unwrap();
+        std::fs::write(template.join("README.md"), "Welcome").unwrap();
+        std::fs::write(template.join("src/main.py"), "print()").unwrap();
+        let users = UsersConfig {
+            home_base: Some(temp_dir.path().join("home")),
+            provision_on_login: true,
+            home_template: Some(template),
+            home_permissions: vec![Permission::Read, Permission::Write],
+            ..UsersConfig::default()
+        };
+        users.validate(&[]).unwrap();
+
+        let home = users.home_folder("carol").unwrap().unwrap();
+        assert_eq!(home.name, "home-carol");
+        assert_eq!(home.permissions, vec![Permission::Read, Permission::Write]);
+        assert_eq!(std::fs::read_to_string(temp_dir.path().join("home/carol/src/main.py")).unwrap(), "print()");
+        assert!(users.allows(Some("carol"), "home-carol"));
+        assert!(users.home_folder("../carol").is_err());
+
+        // An existing home is left as it is, and registered again on start
+        std::fs::remove_file(temp_dir.path().join("home/carol/README.md")).unwrap();
+        let mut folders = Vec::new();
+        users.provision_homes(&mut folders).unwrap();
+        assert_eq!(folders.len(), 1);
+        assert_eq!(folders[0].name, "home-carol");
+        assert!(!temp_dir.path().join("home/carol/README.md").exists());
+    }
+}
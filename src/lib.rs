@@ -4,6 +4,9 @@ pub mod client;
 pub mod sandbox;
 pub mod config;
 pub mod security;
+pub mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub use protocol::*;
 pub use config::Config;
\ No newline at end of file
@@ -31,8 +31,7 @@ async fn main() {
 
     // 显示服务器信息
     info!("FSH Server Configuration:");
-    info!("  Host: {}", config.server.host);
-    info!("  Port: {}", config.server.port);
+    info!("  Listen: {}", config.server.bind_addresses().join(", "));
     info!("  Max connections: {}", config.server.max_connections);
     info!("  Authentication required: {}", config.security.require_authentication);
     info!("  Available folders: {}", config.folders.len());
@@ -50,7 +49,7 @@ async fn main() {
         }
     };
 
-    info!("FSH server starting on {}:{}", server.config().server.host, server.config().server.port);
+    info!("FSH server starting on {}", server.config().server.bind_addresses().join(", "));
     info!("Press Ctrl+C to stop the server");
 
     // 优雅关闭处理
@@ -61,8 +60,8 @@ async fn main() {
                 Err(e) => error!("FSH server error: {}", e),
             }
         }
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received Ctrl+C, shutting down gracefully...");
+        _ = fsh::server::shutdown_signal() => {
+            info!("Received shutdown signal, shutting down gracefully...");
             if let Err(e) = server.stop().await {
                 error!("Error during shutdown: {}", e);
             } else {
@@ -0,0 +1,221 @@
+use super::{CompressionAlgorithm, FshError, FshResult, COMPRESSION_FEATURE_PREFIX, MIN_WIRE_REVISION, WIRE_REVISION};
+
+pub const FEATURE_FOLDER_BINDING: &str = "folder_binding";
+pub const FEATURE_FILE_OPERATIONS: &str = "file_operations";
+pub const FEATURE_COMMAND_EXECUTION: &str = "command_execution";
+pub const FEATURE_SHELL_SESSION: &str = "shell_session";
+pub const FEATURE_PTY: &str = "pty";
+pub const FEATURE_FILE_WATCH: &str = "file_watch";
+pub const FEATURE_MULTIPLEXING: &str = "multiplexing";
+/// `GitStatus`, `GitDiff` and `GitLog` requests.
+pub const FEATURE_GIT: &str = "git";
+/// Forwarding TCP connections to the folder's forwardable ports.
+pub const FEATURE_PORT_FORWARD: &str = "port_forward";
+/// Messages larger than a frame, split with `FRAGMENT_FLAG`.
+pub const FEATURE_FRAGMENTATION: &str = "fragmentation";
+/// `ServerNotice` announcements from the server's operators.
+pub const FEATURE_SERVER_NOTICE: &str = "server_notice";
+/// `EnvSet` and `EnvGet` requests.
+pub const FEATURE_ENV: &str = "env";
+/// `HistoryRequest` for the commands run in the session and its folder.
+pub const FEATURE_HISTORY: &str = "history";
+/// Consenting to, and attaching, read-only session observers.
+pub const FEATURE_OBSERVE: &str = "observe";
+/// Sharing a session with participants who may also run commands in it.
+pub const FEATURE_COLLABORATE: &str = "collaborate";
+/// Moving a session to another folder without reconnecting.
+pub const FEATURE_FOLDER_SWITCH: &str = "folder_switch";
+/// Binding more folders into a session as `/<name>` roots.
+pub const FEATURE_FOLDER_ROOTS: &str = "folder_roots";
+/// Creating directories with FileMkdir.
+pub const FEATURE_MKDIR: &str = "mkdir";
+/// Sending changes as operations with ids, answered once however often they
+/// are resent.
+pub const FEATURE_OPERATION_IDS: &str = "operation_ids";
+/// Encrypting the connection with a Noise_XX handshake after `ConnectResponse`.
+pub const FEATURE_NOISE: &str = "noise";
+/// `SessionLocked` after a configured idle period, lifted by `SessionUnlock`.
+pub const FEATURE_IDLE_LOCK: &str = "idle_lock";
+/// `SessionResume` for sessions saved before the server restarted.
+pub const FEATURE_SESSION_RESUME: &str = "session_resume";
+/// `WatchRun` whenever a folder's watch rule has run its command.
+pub const FEATURE_WATCH_RUNS: &str = "watch_runs";
+/// `FolderList` after a successful login, naming the folders the user sees.
+pub const FEATURE_USER_FOLDERS: &str = "user_folders";
+/// `SessionInfoRequest` for the session's working directory and the CPU and
+/// memory its commands use.
+pub const FEATURE_SESSION_INFO: &str = "session_info";
+
+/// Prefix of the features naming each wire revision a peer can switch to
+/// after the handshake, e.g. `wire_revision:2`.
+pub const WIRE_REVISION_FEATURE_PREFIX: &str = "wire_revision:";
+
+/// Features for every wire revision above the minimum this build speaks.
+pub fn wire_revision_features() -> Vec<String> {
+    (MIN_WIRE_REVISION + 1..=WIRE_REVISION)
+        .map(|revision| format!("{}{}", WIRE_REVISION_FEATURE_PREFIX, revision))
+        .collect()
+}
+
+/// Features every 1.x peer implements. They are always reported as enabled,
+/// whether or not the other side lists them.
+pub const CORE_FEATURES: &[&str] = &[
+    FEATURE_FOLDER_BINDING,
+    FEATURE_FILE_OPERATIONS,
+    FEATURE_COMMAND_EXECUTION,
+    FEATURE_SHELL_SESSION,
+];
+
+/// The set of features enabled for a connection.
+///
+/// Optional features (compression, pty, file watching, multiplexing) are only
+/// enabled when both peers advertise them. At most one compression algorithm
+/// is enabled: the first one in the client's preference order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Intersect the client's advertised features with the server's, keeping
+    /// the client's ordering.
+    pub fn negotiate(client_features: &[String], server_features: &[String]) -> Self {
+        let mut features: Vec<String> = CORE_FEATURES.iter().map(|f| f.to_string()).collect();
+        let mut compression_chosen = false;
+
+        for feature in client_features {
+            if features.contains(feature) || !server_features.contains(feature) {
+                continue;
+            }
+            if feature.starts_with(COMPRESSION_FEATURE_PREFIX) {
+                if compression_chosen || CompressionAlgorithm::from_feature(feature).is_none() {
+                    continue;
+                }
+                compression_chosen = true;
+            }
+            features.push(feature.clone());
+        }
+
+        Self { features }
+    }
+
+    /// Capabilities as reported by the server in its `ConnectResponse`.
+    pub fn from_features(features: Vec<String>) -> Self {
+        Self { features }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    pub fn compression(&self) -> Option<CompressionAlgorithm> {
+        self.features.iter().find_map(|f| CompressionAlgorithm::from_feature(f))
+    }
+
+    /// The newest wire revision both peers speak, or the minimum if they
+    /// share no other, e.g. during a rolling upgrade.
+    pub fn wire_revision(&self) -> u8 {
+        self.features.iter()
+            .filter_map(|f| f.strip_prefix(WIRE_REVISION_FEATURE_PREFIX)?.parse::<u8>().ok())
+            .filter(|revision| *revision <= WIRE_REVISION)
+            .max()
+            .unwrap_or(MIN_WIRE_REVISION)
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn into_features(self) -> Vec<String> {
+        self.features
+    }
+}
+
+/// A `major.minor` protocol version. Peers with the same major version can
+/// talk to each other; the minor version only adds optional features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    pub fn parse(version: &str) -> FshResult<Self> {
+        let invalid = || FshError::ProtocolError(format!("Invalid protocol version: {}", version));
+
+        let (major, minor) = version.split_once('.').unwrap_or((version, "0"));
+        Ok(Self {
+            major: major.trim().parse().map_err(|_| invalid())?,
+            minor: minor.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+
+    pub fn current() -> Self {
+        Self::parse(super::FSH_VERSION).expect("FSH_VERSION is a valid version")
+    }
+
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(list: &[&str]) -> Vec<String> {
+        list.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn test_negotiate_intersection() {
+        let client = features(&[FEATURE_PTY, FEATURE_FILE_WATCH, "compression:deflate", "compression:zstd"]);
+        let server = features(&[FEATURE_FILE_WATCH, FEATURE_MULTIPLEXING, "compression:zstd", "compression:deflate"]);
+
+        let caps = Capabilities::negotiate(&client, &server);
+        assert!(caps.supports(FEATURE_FILE_WATCH));
+        assert!(caps.supports(FEATURE_COMMAND_EXECUTION));
+        assert!(!caps.supports(FEATURE_PTY));
+        assert!(!caps.supports(FEATURE_MULTIPLEXING));
+        assert_eq!(caps.compression(), Some(CompressionAlgorithm::Deflate));
+        assert!(!caps.supports("compression:zstd"));
+    }
+
+    #[test]
+    fn test_negotiate_old_client() {
+        let caps = Capabilities::negotiate(&[], &features(&[FEATURE_PTY, "compression:zstd"]));
+        assert_eq!(caps.features().len(), CORE_FEATURES.len());
+        assert_eq!(caps.compression(), None);
+    }
+
+    #[test]
+    fn test_negotiate_wire_revision() {
+        let ours = wire_revision_features();
+        assert_eq!(Capabilities::negotiate(&ours, &ours).wire_revision(), WIRE_REVISION);
+
+        // A peer from before revisions were negotiated keeps the first one
+        assert_eq!(Capabilities::negotiate(&[], &ours).wire_revision(), MIN_WIRE_REVISION);
+        assert_eq!(Capabilities::negotiate(&ours, &[]).wire_revision(), MIN_WIRE_REVISION);
+
+        // A newer peer settles on the newest revision both list
+        let newer = features(&["wire_revision:2", "wire_revision:3"]);
+        assert_eq!(Capabilities::negotiate(&newer, &ours).wire_revision(), 2);
+        assert_eq!(Capabilities::from_features(newer).wire_revision(), 2);
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let current = ProtocolVersion::parse("1.0").unwrap();
+        assert!(current.is_compatible_with(&ProtocolVersion::parse("1.4").unwrap()));
+        assert!(current.is_compatible_with(&ProtocolVersion::parse("1").unwrap()));
+        assert!(!current.is_compatible_with(&ProtocolVersion::parse("2.0").unwrap()));
+        assert!(ProtocolVersion::parse("one.zero").is_err());
+        assert_eq!(ProtocolVersion::current().to_string(), crate::protocol::FSH_VERSION);
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Read;
+
+/// Error detail reported when a received chunk does not match its checksum.
+pub const CHECKSUM_MISMATCH: &str = "Checksum mismatch";
+
+/// Hash algorithms used to verify file transfers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    #[default]
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Checksum {
+        let mut hasher = Hasher::new(*self);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Hash everything `reader` yields, returning the checksum and the byte count.
+    pub fn digest_reader<R: Read>(&self, mut reader: R) -> std::io::Result<(Checksum, u64)> {
+        let mut hasher = Hasher::new(*self);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut total = 0;
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            total += read as u64;
+        }
+
+        Ok((hasher.finish(), total))
+    }
+}
+
+/// A hex-encoded digest together with the algorithm that produced it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+impl Checksum {
+    /// Whether `data` hashes to this checksum.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == *self
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.value)
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finish(self) -> Checksum {
+        match self {
+            Hasher::Sha256(hasher) => Checksum {
+                algorithm: ChecksumAlgorithm::Sha256,
+                value: hex::encode(hasher.finalize()),
+            },
+            Hasher::Blake3(hasher) => Checksum {
+                algorithm: ChecksumAlgorithm::Blake3,
+                value: hasher.finalize().to_hex().to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.digest(b"abc").value,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            ChecksumAlgorithm::Blake3.digest(b"").value,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    #[test]
+    fn test_digest_reader_matches_digest() {
+        let data = vec![7u8; 200 * 1024];
+        for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] {
+            let (checksum, size) = algorithm.digest_reader(&data[..]).unwrap();
+            assert_eq!(size, data.len() as u64);
+            assert!(checksum.verify(&data));
+            assert!(!checksum.verify(&data[1..]));
+        }
+    }
+}
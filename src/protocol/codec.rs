@@ -1,206 +1,848 @@
-use super::{FshMessage, FshError, FshResult, FSH_MAGIC};
-// Removed unused imports
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::{
+    CompressionAlgorithm, FshMessage, FshError, FshResult, FRAME_MAGIC, FSH_MAGIC, MIN_WIRE_REVISION, WIRE_REVISION,
+    DEFAULT_COMPRESSION_THRESHOLD,
+};
+use bincode::Options;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Size of a revision 1 frame header: magic and revision followed by a
+/// big-endian u32 holding the flags and payload length.
+pub const FRAME_HEADER_LEN: usize = FSH_MAGIC.len() + 4;
+
+/// Size of a revision 2 frame header, which moves the flags into a byte of
+/// their own after the revision, leaving room for more.
+pub const FRAME_HEADER_LEN_V2: usize = FRAME_HEADER_LEN + 1;
+
+/// Size of the CRC32 trailer appended to every frame payload.
+pub const FRAME_CHECKSUM_LEN: usize = 4;
+
+/// Default upper bound for a single frame payload.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 10 * 1024 * 1024;
+
+/// Frame limit until the client has authenticated: Connect, Authenticate
+/// and the like are small, and anonymous peers shouldn't make the server
+/// buffer megabytes.
+pub const PRE_AUTH_MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// Largest file chunk carried by a single read or write message, leaving room
+/// for the rest of the message within the default frame limit.
+pub const MAX_FILE_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default upper bound for a message reassembled from fragments.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Size of each frame a fragmented message is split into. Peers accept
+/// frames at least this large once authenticated.
+pub const FRAGMENT_LENGTH: usize = 1024 * 1024;
+
+/// High bit of the length field, set when the payload is compressed with the
+/// algorithm negotiated for the connection.
+pub const COMPRESSED_FLAG: u32 = 0x8000_0000;
+
+/// Second bit of the length field, set on every frame of a fragmented
+/// message but the last.
+pub const FRAGMENT_FLAG: u32 = 0x4000_0000;
+
+const LENGTH_MASK: u32 = !(COMPRESSED_FLAG | FRAGMENT_FLAG);
+
+/// Working buffers grown past this are released after use, so one huge
+/// message doesn't pin its memory for the life of the connection.
+const MAX_RETAINED_BUFFER: usize = 2 * MAX_FILE_CHUNK_SIZE;
+
+/// How messages are laid out on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Checksummed bincode frames.
+    Binary,
+    /// One JSON message per line, for debugging and for clients that can't
+    /// produce bincode. Never compressed or fragmented.
+    #[cfg(feature = "json-codec")]
+    Json,
+}
+
+/// A connection framed with the FSH wire codec.
+pub type FshFramed<T> = Framed<T, FshCodec>;
+
+/// Length-delimited FSH frame codec for use with `tokio_util::codec::Framed`.
+///
+/// Each frame is `FSH_MAGIC | u32 length | bincode payload | u32 CRC32`, where
+/// the checksum covers the payload bytes as sent on the wire. That is wire
+/// revision 1; revision 2 frames are `FRAME_MAGIC | 2 | u8 flags | u32 length
+/// | payload | u32 CRC32`, the flags byte holding the top byte of the
+/// revision 1 length field. Frames of every revision from
+/// [`MIN_WIRE_REVISION`] up are read; they are written in the revision set
+/// with [`FshCodec::set_revision`], 1 until the handshake agrees on another.
+///
+/// Corruption is reported as a protocol error instead of surfacing as a bincode
+/// misparse. The decoder resynchronizes before returning the error: a frame
+/// with a bad checksum is consumed whole, and on bad magic bytes input is
+/// skipped up to the next magic sequence. `Framed` ends the stream after any
+/// decode error, so resync only matters to callers driving the codec directly.
+///
+/// Once compression has been negotiated, payloads at or above the compression
+/// threshold are compressed and flagged with [`COMPRESSED_FLAG`] in the length
+/// field. The frame length limit always applies to the uncompressed payload.
+///
+/// Once fragmentation has been negotiated, payloads over [`FRAGMENT_LENGTH`]
+/// (or the frame limit, if lower) are split into frames of that size flagged
+/// with [`FRAGMENT_FLAG`], and reassembled up to the message length limit. A
+/// compressed message is compressed whole and then split.
+///
+/// With the `json-codec` feature, a codec set to [`Framing::Json`] reads and
+/// writes newline-delimited JSON instead, and a server-side codec can pick the
+/// framing from the first byte the client sends.
+#[derive(Debug, Clone)]
+pub struct FshCodec {
+    framing: Framing,
+    /// Wire revision frames are written in.
+    revision: u8,
+    /// Choose `framing` from the first byte received: `{` starts JSON.
+    detect_framing: bool,
+    max_frame_length: usize,
+    max_message_length: usize,
+    compression: Option<CompressionAlgorithm>,
+    compression_threshold: usize,
+    fragmentation: bool,
+    /// Payload of a fragmented message received so far.
+    fragments: BytesMut,
+    /// Reused for payloads that are compressed or fragmented, and for
+    /// decompressed payloads.
+    scratch: Vec<u8>,
+    /// Reused for compressed payloads.
+    compressed: Vec<u8>,
+}
 
-pub struct FshCodec;
+impl Default for FshCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl FshCodec {
-    pub fn encode(message: &FshMessage) -> FshResult<Vec<u8>> {
-        let mut buffer = Vec::new();
+    pub fn new() -> Self {
+        Self {
+            framing: Framing::Binary,
+            revision: MIN_WIRE_REVISION,
+            detect_framing: false,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            fragmentation: false,
+            fragments: BytesMut::new(),
+            scratch: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
 
-        // Write magic bytes
-        buffer.extend_from_slice(FSH_MAGIC);
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
 
-        // Serialize message
-        let data = bincode::serialize(message)
-            .map_err(|e| FshError::ProtocolError(format!("Serialization failed: {}", e)))?;
+    /// Switch framing; clients choose it before sending Connect.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.detect_framing = false;
+    }
 
-        // Write message length (4 bytes, big-endian)
-        let length = data.len() as u32;
-        buffer.extend_from_slice(&length.to_be_bytes());
+    /// Let the peer's first message decide the framing, as servers do.
+    pub fn with_framing_detection(mut self) -> Self {
+        self.detect_framing = true;
+        self
+    }
 
-        // Write message data
-        buffer.extend_from_slice(&data);
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
 
-        Ok(buffer)
+    /// Write frames in `revision`; called once the handshake has agreed on it.
+    pub fn set_revision(&mut self, revision: u8) {
+        self.revision = revision.clamp(MIN_WIRE_REVISION, WIRE_REVISION);
     }
 
-    pub fn decode(data: &[u8]) -> FshResult<FshMessage> {
-        if data.len() < FSH_MAGIC.len() + 4 {
-            return Err(FshError::ProtocolError("Insufficient data".to_string()));
-        }
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.set_max_frame_length(max_frame_length);
+        self
+    }
 
-        // Check magic bytes
-        if &data[..FSH_MAGIC.len()] != FSH_MAGIC {
-            return Err(FshError::ProtocolError("Invalid magic bytes".to_string()));
-        }
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        // The top bits of the length field are reserved for the flags
+        self.max_frame_length = max_frame_length.min(LENGTH_MASK as usize);
+    }
 
-        // Read message length
-        let length_bytes = &data[FSH_MAGIC.len()..FSH_MAGIC.len() + 4];
-        let length = u32::from_be_bytes([
-            length_bytes[0], length_bytes[1],
-            length_bytes[2], length_bytes[3]
-        ]) as usize;
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
 
-        // Check if we have enough data
-        let expected_total = FSH_MAGIC.len() + 4 + length;
-        if data.len() < expected_total {
-            return Err(FshError::ProtocolError("Incomplete message".to_string()));
-        }
+    pub fn with_max_message_length(mut self, max_message_length: usize) -> Self {
+        self.max_message_length = max_message_length;
+        self
+    }
 
-        // Deserialize message
-        let message_data = &data[FSH_MAGIC.len() + 4..FSH_MAGIC.len() + 4 + length];
-        bincode::deserialize(message_data)
-            .map_err(|e| FshError::ProtocolError(format!("Deserialization failed: {}", e)))
+    pub fn set_max_message_length(&mut self, max_message_length: usize) {
+        self.max_message_length = max_message_length;
     }
 
-    pub async fn read_message<R>(reader: &mut R) -> FshResult<FshMessage>
-    where
-        R: AsyncRead + Unpin,
-    {
-        // Read magic bytes
-        let mut magic = vec![0u8; FSH_MAGIC.len()];
-        reader.read_exact(&mut magic).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to read magic: {}", e)))?;
+    /// Switch fragmentation on; called once the handshake has negotiated it.
+    pub fn set_fragmentation(&mut self, fragmentation: bool) {
+        self.fragmentation = fragmentation;
+    }
+
+    pub fn fragmentation(&self) -> bool {
+        self.fragmentation
+    }
 
-        if magic != FSH_MAGIC {
-            return Err(FshError::ProtocolError("Invalid magic bytes".to_string()));
+    /// Largest message payload that can be sent or received.
+    fn message_limit(&self) -> usize {
+        if self.fragmentation {
+            self.max_message_length.max(self.max_frame_length)
+        } else {
+            self.max_frame_length
         }
+    }
 
-        // Read message length
-        let mut length_bytes = [0u8; 4];
-        reader.read_exact(&mut length_bytes).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to read length: {}", e)))?;
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
 
-        let length = u32::from_be_bytes(length_bytes) as usize;
+    pub fn compression(&self) -> Option<CompressionAlgorithm> {
+        self.compression
+    }
 
-        // Validate length (prevent DoS attacks)
-        if length > 10 * 1024 * 1024 { // 10MB max
-            return Err(FshError::ProtocolError("Message too large".to_string()));
-        }
+    /// Switch compression on or off; called once the handshake has negotiated an algorithm.
+    pub fn set_compression(&mut self, compression: Option<CompressionAlgorithm>) {
+        self.compression = compression;
+    }
 
-        // Read message data
-        let mut data = vec![0u8; length];
-        reader.read_exact(&mut data).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to read data: {}", e)))?;
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
 
-        // Deserialize message
-        bincode::deserialize(&data)
-            .map_err(|e| FshError::ProtocolError(format!("Deserialization failed: {}", e)))
+    /// Wrap an I/O object with the FSH codec.
+    pub fn framed<T>(io: T) -> FshFramed<T>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        Framed::new(io, Self::new())
     }
 
-    pub async fn write_message<W>(writer: &mut W, message: &FshMessage) -> FshResult<()>
+    /// Receive the next message, treating end-of-stream as a network error.
+    pub async fn read_message<S>(stream: &mut S) -> FshResult<FshMessage>
     where
-        W: AsyncWrite + Unpin,
+        S: Stream<Item = FshResult<FshMessage>> + Unpin,
     {
-        let encoded = Self::encode(message)?;
-        writer.write_all(&encoded).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to write message: {}", e)))?;
-        writer.flush().await
-            .map_err(|e| FshError::NetworkError(format!("Failed to flush: {}", e)))?;
-        Ok(())
+        stream.next().await
+            .unwrap_or_else(|| Err(FshError::NetworkError("Connection closed".to_string())))
     }
-}
 
-#[derive(Debug)]
-pub struct MessageBuffer {
-    buffer: Vec<u8>,
-    messages: Vec<FshMessage>,
+    /// Send a message and flush it to the peer.
+    pub async fn write_message<S>(sink: &mut S, message: FshMessage) -> FshResult<()>
+    where
+        S: Sink<FshMessage, Error = FshError> + Unpin,
+    {
+        sink.send(message).await
+    }
 }
 
-impl MessageBuffer {
-    pub fn new() -> Self {
-        Self {
-            buffer: Vec::new(),
-            messages: Vec::new(),
-        }
-    }
+impl Decoder for FshCodec {
+    type Item = FshMessage;
+    type Error = FshError;
 
-    pub fn add_data(&mut self, data: &[u8]) {
-        self.buffer.extend_from_slice(data);
-        self.try_parse_messages();
-    }
+    fn decode(&mut self, src: &mut BytesMut) -> FshResult<Option<FshMessage>> {
+        if self.detect_framing && !src.is_empty() {
+            #[cfg(feature = "json-codec")]
+            if src[0] == b'{' {
+                self.framing = Framing::Json;
+            }
+            self.detect_framing = false;
+        }
+        #[cfg(feature = "json-codec")]
+        if self.framing == Framing::Json {
+            return self.decode_json(src);
+        }
 
-    pub fn take_messages(&mut self) -> Vec<FshMessage> {
-        std::mem::take(&mut self.messages)
-    }
+        let (frame, compressed) = loop {
+            if src.len() < FRAME_HEADER_LEN {
+                return Ok(None);
+            }
 
-    fn try_parse_messages(&mut self) {
-        while self.buffer.len() >= FSH_MAGIC.len() + 4 {
             // Check magic bytes
-            if &self.buffer[..FSH_MAGIC.len()] != FSH_MAGIC {
-                // Skip one byte and try again
-                self.buffer.drain(0..1);
-                continue;
+            if &src[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+                self.fragments.clear();
+                let skipped = resync_to_magic(src);
+                return Err(FshError::ProtocolError(format!(
+                    "Invalid magic bytes, skipped {} bytes", skipped
+                )));
+            }
+
+            // Read the flags and message length, laid out by revision
+            let revision = src[FRAME_MAGIC.len()];
+            let (header_length, raw_length) = match revision {
+                1 => (FRAME_HEADER_LEN, u32::from_be_bytes(src[FSH_MAGIC.len()..FRAME_HEADER_LEN].try_into().unwrap_or_default())),
+                2 => {
+                    if src.len() < FRAME_HEADER_LEN_V2 {
+                        return Ok(None);
+                    }
+                    let flags = (src[FSH_MAGIC.len()] as u32) << 24;
+                    let length = u32::from_be_bytes(src[FSH_MAGIC.len() + 1..FRAME_HEADER_LEN_V2].try_into().unwrap_or_default());
+                    if flags & LENGTH_MASK != 0 || length & !LENGTH_MASK != 0 {
+                        self.fragments.clear();
+                        let skipped = resync_to_magic(src);
+                        return Err(FshError::ProtocolError(format!(
+                            "Unknown frame flags {:02x}, skipped {} bytes", flags >> 24, skipped
+                        )));
+                    }
+                    (FRAME_HEADER_LEN_V2, flags | length)
+                }
+                _ => {
+                    self.fragments.clear();
+                    let skipped = resync_to_magic(src);
+                    return Err(FshError::ProtocolError(format!(
+                        "Unsupported wire revision {} (supported {} to {}), skipped {} bytes",
+                        revision, MIN_WIRE_REVISION, WIRE_REVISION, skipped
+                    )));
+                }
+            };
+            let compressed = raw_length & COMPRESSED_FLAG != 0;
+            let fragment = raw_length & FRAGMENT_FLAG != 0;
+            let length = (raw_length & LENGTH_MASK) as usize;
+
+            // Validate length (prevent DoS attacks)
+            if length > self.max_frame_length {
+                self.fragments.clear();
+                return Err(FshError::ProtocolError(format!(
+                    "Message too large: {} bytes (max {})", length, self.max_frame_length
+                )));
             }
 
-            // Read message length
-            let length_bytes = &self.buffer[FSH_MAGIC.len()..FSH_MAGIC.len() + 4];
-            let length = u32::from_be_bytes([
-                length_bytes[0], length_bytes[1],
-                length_bytes[2], length_bytes[3]
-            ]) as usize;
+            // Wait for the rest of the frame, reserving room for it up front
+            let total_length = header_length + length + FRAME_CHECKSUM_LEN;
+            if src.len() < total_length {
+                src.reserve(total_length - src.len());
+                return Ok(None);
+            }
+
+            src.advance(header_length);
+            let payload = src.split_to(length);
+            let expected_checksum = src.get_u32();
 
-            // Check if we have the complete message
-            let total_length = FSH_MAGIC.len() + 4 + length;
-            if self.buffer.len() < total_length {
-                break; // Wait for more data
+            let checksum = crc32fast::hash(&payload);
+            if checksum != expected_checksum {
+                self.fragments.clear();
+                return Err(FshError::ProtocolError(format!(
+                    "Frame checksum mismatch: expected {:08x}, got {:08x}", expected_checksum, checksum
+                )));
             }
 
-            // Try to parse the message
-            match FshCodec::decode(&self.buffer[..total_length]) {
-                Ok(message) => {
-                    self.messages.push(message);
-                    self.buffer.drain(0..total_length);
+            if !fragment && self.fragments.is_empty() {
+                break (Some(payload), compressed);
+            }
+            if !self.fragmentation {
+                return Err(FshError::ProtocolError(
+                    "Received fragmented frame without negotiated fragmentation".to_string()
+                ));
+            }
+            if self.fragments.len() + length > self.message_limit() {
+                let received = self.fragments.len() + length;
+                self.fragments.clear();
+                return Err(FshError::ProtocolError(format!(
+                    "Message too large: over {} bytes (max {})", received, self.message_limit()
+                )));
+            }
+            self.fragments.extend_from_slice(&payload);
+            if !fragment {
+                // Decoded in place so the buffer is kept for the next message
+                break (None, compressed);
+            }
+        };
+
+        let message_limit = self.message_limit();
+        let payload = frame.as_deref().unwrap_or(&self.fragments);
+        let result = if compressed {
+            match self.compression {
+                Some(algorithm) => {
+                    self.scratch.clear();
+                    algorithm.decompress_into(payload, message_limit, &mut self.scratch)
+                        .and_then(|_| deserialize(&self.scratch))
                 }
-                Err(_) => {
-                    // Skip this message and try again
-                    self.buffer.drain(0..FSH_MAGIC.len());
+                None => Err(FshError::ProtocolError(
+                    "Received compressed frame without negotiated compression".to_string()
+                )),
+            }
+        } else {
+            deserialize(payload)
+        };
+
+        if frame.is_none() {
+            self.fragments.clear();
+            if self.fragments.capacity() > MAX_RETAINED_BUFFER {
+                self.fragments = BytesMut::new();
+            }
+        }
+        release_if_large(&mut self.scratch);
+        result.map(Some)
+    }
+}
+
+impl Encoder<FshMessage> for FshCodec {
+    type Error = FshError;
+
+    fn encode(&mut self, message: FshMessage, dst: &mut BytesMut) -> FshResult<()> {
+        #[cfg(feature = "json-codec")]
+        if self.framing == Framing::Json {
+            return self.encode_json(&message, dst);
+        }
+
+        let length = bincode::serialized_size(&message)
+            .map_err(|e| FshError::codec("Serialization failed", e))? as usize;
+
+        if length > self.message_limit() {
+            return Err(FshError::ProtocolError(format!(
+                "Message too large: {} bytes (max {})", length, self.message_limit()
+            )));
+        }
+
+        // Fragments are small enough for any peer's frame limit
+        let frame_limit = if self.fragmentation {
+            self.max_frame_length.min(FRAGMENT_LENGTH)
+        } else {
+            self.max_frame_length
+        };
+        let compression = self.compression.filter(|_| length >= self.compression_threshold);
+
+        if compression.is_none() && length <= frame_limit {
+            // Serialize straight into the output buffer
+            let start = dst.len();
+            dst.reserve(FRAME_HEADER_LEN_V2 + length + FRAME_CHECKSUM_LEN);
+            put_header(dst, self.revision, length as u32);
+            let payload_start = dst.len();
+            if let Err(e) = bincode::serialize_into((&mut *dst).writer(), &message) {
+                dst.truncate(start);
+                return Err(FshError::codec("Serialization failed", e));
+            }
+            let checksum = crc32fast::hash(&dst[payload_start..]);
+            dst.put_u32(checksum);
+            return Ok(());
+        }
+
+        self.scratch.clear();
+        self.scratch.reserve(length);
+        let result = bincode::serialize_into(&mut self.scratch, &message)
+            .map_err(|e| FshError::codec("Serialization failed", e))
+            .and_then(|_| match compression {
+                Some(algorithm) => {
+                    self.compressed.clear();
+                    algorithm.compress_into(&self.scratch, &mut self.compressed)
+                }
+                None => Ok(()),
+            });
+
+        if result.is_ok() {
+            // Large payloads are only sent compressed when that actually saves space
+            if compression.is_some() && self.compressed.len() < length {
+                put_frames(dst, &self.compressed, frame_limit, self.revision, COMPRESSED_FLAG);
+            } else {
+                put_frames(dst, &self.scratch, frame_limit, self.revision, 0);
+            }
+        }
+        release_if_large(&mut self.scratch);
+        release_if_large(&mut self.compressed);
+        result
+    }
+}
+
+#[cfg(feature = "json-codec")]
+impl FshCodec {
+    fn decode_json(&mut self, src: &mut BytesMut) -> FshResult<Option<FshMessage>> {
+        loop {
+            let Some(end) = src.iter().position(|&b| b == b'\n') else {
+                if src.len() > self.message_limit() {
+                    let received = src.len();
+                    src.clear();
+                    return Err(FshError::ProtocolError(format!(
+                        "Message too large: over {} bytes (max {})", received, self.message_limit()
+                    )));
                 }
+                return Ok(None);
+            };
+            let line = src.split_to(end + 1);
+            let line = line[..end].trim_ascii();
+            if line.is_empty() {
+                continue;
             }
+            if line.len() > self.message_limit() {
+                return Err(FshError::ProtocolError(format!(
+                    "Message too large: {} bytes (max {})", line.len(), self.message_limit()
+                )));
+            }
+            return serde_json::from_slice(line)
+                .map(Some)
+                .map_err(|e| FshError::ProtocolError(format!("Invalid JSON message: {}", e)));
+        }
+    }
+
+    fn encode_json(&mut self, message: &FshMessage, dst: &mut BytesMut) -> FshResult<()> {
+        let start = dst.len();
+        serde_json::to_writer((&mut *dst).writer(), message)
+            .map_err(|e| FshError::ProtocolError(format!("Serialization failed: {}", e)))?;
+        if dst.len() - start > self.message_limit() {
+            let length = dst.len() - start;
+            dst.truncate(start);
+            return Err(FshError::ProtocolError(format!(
+                "Message too large: {} bytes (max {})", length, self.message_limit()
+            )));
         }
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// Write `payload` as one frame, or as fragments of at most `frame_limit` bytes.
+fn put_frames(dst: &mut BytesMut, payload: &[u8], frame_limit: usize, revision: u8, flags: u32) {
+    let frames = payload.len().div_ceil(frame_limit);
+    dst.reserve(payload.len() + frames * (FRAME_HEADER_LEN_V2 + FRAME_CHECKSUM_LEN));
+    let mut chunks = payload.chunks(frame_limit).peekable();
+    while let Some(chunk) = chunks.next() {
+        let mut header = chunk.len() as u32 | flags;
+        if chunks.peek().is_some() {
+            header |= FRAGMENT_FLAG;
+        }
+        put_header(dst, revision, header);
+        dst.put_slice(chunk);
+        dst.put_u32(crc32fast::hash(chunk));
     }
 }
 
+/// Write a frame header in `revision` for a length field holding `header`'s
+/// flags and payload length.
+fn put_header(dst: &mut BytesMut, revision: u8, header: u32) {
+    dst.put_slice(FRAME_MAGIC);
+    dst.put_u8(revision);
+    if revision >= 2 {
+        dst.put_u8((header >> 24) as u8);
+        dst.put_u32(header & LENGTH_MASK);
+    } else {
+        dst.put_u32(header);
+    }
+}
+
+/// Parse a message payload. Lengths inside it are untrusted, so bincode may
+/// read no more than the payload holds.
+pub(crate) fn deserialize(payload: &[u8]) -> FshResult<FshMessage> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(payload.len() as u64)
+        .deserialize(payload)
+        .map_err(|e| FshError::codec("Deserialization failed", e))
+}
+
+fn release_if_large(buffer: &mut Vec<u8>) {
+    if buffer.capacity() > MAX_RETAINED_BUFFER {
+        *buffer = Vec::new();
+    }
+}
+
+/// Drop bytes up to the next occurrence of the magic sequence, keeping a
+/// trailing partial match in case the rest of it has not arrived yet.
+/// Returns the number of bytes discarded.
+fn resync_to_magic(src: &mut BytesMut) -> usize {
+    let skip = (1..src.len())
+        .find(|&i| {
+            let candidate = &src[i..];
+            let n = candidate.len().min(FRAME_MAGIC.len());
+            candidate[..n] == FRAME_MAGIC[..n]
+        })
+        .unwrap_or(src.len());
+
+    src.advance(skip);
+    skip
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::protocol::message::*;
 
+    fn encode(message: FshMessage) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        FshCodec::new().encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
     #[test]
     fn test_codec_roundtrip() {
-        let original = FshMessage::Ping;
-        let encoded = FshCodec::encode(&original).unwrap();
-        let decoded = FshCodec::decode(&encoded).unwrap();
+        let mut buffer = encode(FshMessage::Ping);
+        let decoded = FshCodec::new().decode(&mut buffer).unwrap();
 
-        match (original, decoded) {
-            (FshMessage::Ping, FshMessage::Ping) => {},
+        match decoded {
+            Some(FshMessage::Ping) => {},
             _ => panic!("Messages don't match"),
         }
+        assert!(buffer.is_empty());
     }
 
     #[test]
-    fn test_message_buffer() {
-        let mut buffer = MessageBuffer::new();
+    fn test_partial_frames() {
+        let mut codec = FshCodec::new();
+        let encoded1 = encode(FshMessage::Ping);
+        let encoded2 = encode(FshMessage::Pong);
 
-        let msg1 = FshMessage::Ping;
-        let msg2 = FshMessage::Pong;
+        // Add partial data
+        let mut buffer = BytesMut::from(&encoded1[..5]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+        // Add rest of first message plus the second message
+        buffer.extend_from_slice(&encoded1[5..]);
+        buffer.extend_from_slice(&encoded2);
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(FshMessage::Pong)));
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+    }
 
-        let encoded1 = FshCodec::encode(&msg1).unwrap();
-        let encoded2 = FshCodec::encode(&msg2).unwrap();
+    #[test]
+    fn test_invalid_magic_resyncs() {
+        let mut codec = FshCodec::new();
+        let mut buffer = BytesMut::from(&b"XXXX\x00\x00\x00\x00FS"[..]);
+        assert!(codec.decode(&mut buffer).is_err());
+        assert_eq!(&buffer[..], b"FS");
+
+        // Garbage followed by a valid frame: one error, then the frame decodes
+        let mut buffer = BytesMut::from(&b"garbage"[..]);
+        buffer.extend_from_slice(&encode(FshMessage::Ping));
+        assert!(codec.decode(&mut buffer).is_err());
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+    }
 
-        // Add partial data
-        buffer.add_data(&encoded1[..5]);
-        assert_eq!(buffer.take_messages().len(), 0);
+    #[test]
+    fn test_checksum_mismatch_is_error() {
+        let mut codec = FshCodec::new();
+        let mut buffer = encode(FshMessage::Disconnect(DisconnectMessage {
+            reason: "bye".to_string(),
+        }));
+        buffer[FRAME_HEADER_LEN] ^= 0xff;
+        buffer.extend_from_slice(&encode(FshMessage::Pong));
+
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+
+        // The corrupted frame is consumed, so the next one still decodes
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(FshMessage::Pong)));
+    }
+
+    #[test]
+    fn test_frame_length_limit() {
+        let mut codec = FshCodec::new().with_max_frame_length(16);
+        let message = FshMessage::FileWriteResponse(FileWriteResponseMessage {
+            success: false,
+            bytes_written: 0,
+            error_message: Some("a message longer than sixteen bytes".to_string()),
+        });
+
+        let mut buffer = BytesMut::new();
+        assert!(codec.encode(message.clone(), &mut buffer).is_err());
+
+        let mut buffer = encode(message);
+        assert!(codec.decode(&mut buffer).is_err());
+    }
+
+    fn file_read_response(size: usize) -> FshMessage {
+        FshMessage::FileReadResponse(FileReadResponseMessage {
+            success: true,
+            data: b"0123456789".repeat(size / 10).into(),
+            total_size: size as u64,
+            error_message: None,
+            offset: 0,
+            checksum: None,
+        })
+    }
 
-        // Add rest of first message
-        buffer.add_data(&encoded1[5..]);
-        let messages = buffer.take_messages();
-        assert_eq!(messages.len(), 1);
+    #[test]
+    fn test_compressed_roundtrip() {
+        for algorithm in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Deflate] {
+            let mut codec = FshCodec::new();
+            codec.set_compression(Some(algorithm));
+
+            let mut buffer = BytesMut::new();
+            codec.encode(file_read_response(64 * 1000), &mut buffer).unwrap();
+            assert!(buffer.len() < 64 * 1000);
+
+            let header = u32::from_be_bytes(buffer[FSH_MAGIC.len()..FRAME_HEADER_LEN].try_into().unwrap());
+            assert_ne!(header & COMPRESSED_FLAG, 0);
+
+            match codec.decode(&mut buffer).unwrap() {
+                Some(FshMessage::FileReadResponse(resp)) => assert_eq!(resp.data.len(), 64 * 1000),
+                _ => panic!("Messages don't match"),
+            }
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_small_frames_not_compressed() {
+        let mut codec = FshCodec::new();
+        codec.set_compression(Some(CompressionAlgorithm::Zstd));
+
+        let mut buffer = BytesMut::new();
+        codec.encode(FshMessage::Ping, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &encode(FshMessage::Ping)[..]);
+    }
+
+    #[test]
+    fn test_compressed_frame_requires_negotiation() {
+        let mut sender = FshCodec::new();
+        sender.set_compression(Some(CompressionAlgorithm::Zstd));
+
+        let mut buffer = BytesMut::new();
+        sender.encode(file_read_response(8 * 1024), &mut buffer).unwrap();
+        assert!(FshCodec::new().decode(&mut buffer).is_err());
+    }
 
-        // Add second message
-        buffer.add_data(&encoded2);
-        let messages = buffer.take_messages();
-        assert_eq!(messages.len(), 1);
+    #[test]
+    fn test_fragmented_roundtrip() {
+        let fragmenting = || {
+            let mut codec = FshCodec::new().with_max_frame_length(1024);
+            codec.set_fragmentation(true);
+            codec
+        };
+        let (mut sender, mut receiver) = (fragmenting(), fragmenting());
+
+        let mut buffer = BytesMut::new();
+        sender.encode(file_read_response(10 * 1000), &mut buffer).unwrap();
+        sender.encode(FshMessage::Ping, &mut buffer).unwrap();
+        let header = u32::from_be_bytes(buffer[FSH_MAGIC.len()..FRAME_HEADER_LEN].try_into().unwrap());
+        assert_ne!(header & FRAGMENT_FLAG, 0);
+
+        // Fragments already buffered are reassembled in one call
+        match receiver.decode(&mut buffer).unwrap() {
+            Some(FshMessage::FileReadResponse(resp)) => assert_eq!(resp.data.len(), 10 * 1000),
+            _ => panic!("Messages don't match"),
+        }
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+
+        // Peers that haven't negotiated fragmentation refuse the fragments
+        let mut buffer = BytesMut::new();
+        sender.encode(file_read_response(10 * 1000), &mut buffer).unwrap();
+        assert!(FshCodec::new().with_max_frame_length(1024).decode(&mut buffer).is_err());
+
+        // The reassembled message is still capped
+        let mut buffer = BytesMut::new();
+        sender.encode(file_read_response(10 * 1000), &mut buffer).unwrap();
+        receiver.set_max_message_length(4 * 1024);
+        assert!(receiver.decode(&mut buffer).is_err());
+        sender.set_max_message_length(4 * 1024);
+        assert!(sender.encode(file_read_response(10 * 1000), &mut BytesMut::new()).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_buffers_reused_across_messages() {
+        let reusing = || {
+            let mut codec = FshCodec::new().with_max_frame_length(1024);
+            codec.set_fragmentation(true);
+            codec.set_compression(Some(CompressionAlgorithm::Zstd));
+            codec
+        };
+        let (mut sender, mut receiver) = (reusing(), reusing());
+
+        for size in [10 * 1000, 100, 4 * 1000, 20 * 1000] {
+            let mut buffer = BytesMut::new();
+            sender.encode(file_read_response(size), &mut buffer).unwrap();
+            match receiver.decode(&mut buffer).unwrap() {
+                Some(FshMessage::FileReadResponse(resp)) => {
+                    assert_eq!(resp.data, b"0123456789".repeat(size / 10));
+                }
+                _ => panic!("Messages don't match"),
+            }
+            assert!(buffer.is_empty());
+        }
+
+        // Carrying data as Bytes leaves the wire format unchanged
+        let data = b"fsh".to_vec();
+        assert_eq!(
+            bincode::serialize(&bytes::Bytes::from(data.clone())).unwrap(),
+            bincode::serialize(&data).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_framed_stream() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = FshCodec::framed(client);
+        let mut server = FshCodec::framed(server);
+
+        FshCodec::write_message(&mut client, FshMessage::Ping).await.unwrap();
+        assert!(matches!(FshCodec::read_message(&mut server).await.unwrap(), FshMessage::Ping));
+
+        drop(client);
+        assert!(FshCodec::read_message(&mut server).await.is_err());
+    }
+
+    #[test]
+    fn test_wire_revisions() {
+        let mut sender = FshCodec::new();
+        sender.set_revision(WIRE_REVISION);
+        sender.set_fragmentation(true);
+        let mut buffer = BytesMut::new();
+        sender.encode(FshMessage::Ping, &mut buffer).unwrap();
+        assert_eq!(&buffer[..FRAME_HEADER_LEN_V2], b"FSH\x02\x00\x00\x00\x00\x04");
+
+        // Revision 1 frames are still read after switching, and fragments keep their flags
+        let mut receiver = FshCodec::new();
+        receiver.set_revision(WIRE_REVISION);
+        receiver.set_fragmentation(true);
+        buffer.extend_from_slice(&encode(FshMessage::Pong));
+        let data = vec![7u8; FRAGMENT_LENGTH + 10];
+        let start = buffer.len();
+        sender.encode(FshMessage::FileReadResponse(FileReadResponseMessage {
+            success: true,
+            data: data.clone().into(),
+            total_size: data.len() as u64,
+            error_message: None,
+            offset: 0,
+            checksum: None,
+        }), &mut buffer).unwrap();
+        assert_eq!(buffer[start + FRAME_MAGIC.len() + 1], (FRAGMENT_FLAG >> 24) as u8);
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Pong)));
+        match receiver.decode(&mut buffer).unwrap() {
+            Some(FshMessage::FileReadResponse(resp)) => assert_eq!(resp.data, data),
+            _ => panic!("Messages don't match"),
+        }
+
+        // Revisions from the future are refused, and skipped past
+        let mut buffer = BytesMut::from(&b"FSH\x09\x00\x00\x00\x00"[..]);
+        buffer.extend_from_slice(&encode(FshMessage::Ping));
+        let err = receiver.decode(&mut buffer).unwrap_err();
+        assert!(err.to_string().contains("wire revision 9"), "{}", err);
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+    }
+
+    #[cfg(feature = "json-codec")]
+    #[test]
+    fn test_json_framing() {
+        let mut sender = FshCodec::new();
+        sender.set_framing(Framing::Json);
+        let mut buffer = BytesMut::new();
+        sender.encode(FshMessage::Disconnect(DisconnectMessage { reason: "bye".to_string() }), &mut buffer).unwrap();
+        assert_eq!(&buffer[..], b"{\"Disconnect\":{\"reason\":\"bye\"}}\n");
+
+        // Servers follow the client's choice, and scripts may send blank lines
+        let mut receiver = FshCodec::new().with_framing_detection();
+        buffer.extend_from_slice(b"\n\"Ping\"\n");
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Disconnect(_))));
+        assert_eq!(receiver.framing(), Framing::Json);
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+        assert!(receiver.decode(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(b"{\"Nope\":1}\n");
+        assert!(receiver.decode(&mut buffer).is_err());
+
+        let mut receiver = FshCodec::new().with_framing_detection();
+        let mut buffer = encode(FshMessage::Ping);
+        assert!(matches!(receiver.decode(&mut buffer).unwrap(), Some(FshMessage::Ping)));
+        assert_eq!(receiver.framing(), Framing::Binary);
+    }
+}
@@ -0,0 +1,121 @@
+use super::{FshError, FshResult};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Prefix used to advertise compression algorithms in the handshake feature lists.
+pub const COMPRESSION_FEATURE_PREFIX: &str = "compression:";
+
+/// Payloads smaller than this are sent uncompressed by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Payload compression algorithms that can be negotiated during the connect handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    Zstd,
+    Deflate,
+}
+
+impl CompressionAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Zstd => "zstd",
+            CompressionAlgorithm::Deflate => "deflate",
+        }
+    }
+
+    /// The handshake feature string advertising this algorithm, e.g. `compression:zstd`.
+    pub fn feature(&self) -> String {
+        format!("{}{}", COMPRESSION_FEATURE_PREFIX, self.name())
+    }
+
+    pub fn from_feature(feature: &str) -> Option<Self> {
+        match feature.strip_prefix(COMPRESSION_FEATURE_PREFIX)? {
+            "zstd" => Some(CompressionAlgorithm::Zstd),
+            "deflate" => Some(CompressionAlgorithm::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> FshResult<Vec<u8>> {
+        let mut output = Vec::new();
+        self.compress_into(data, &mut output)?;
+        Ok(output)
+    }
+
+    /// Compress a payload, appending it to `output` so callers can reuse the buffer.
+    pub fn compress_into(&self, data: &[u8], output: &mut Vec<u8>) -> FshResult<()> {
+        match self {
+            CompressionAlgorithm::Zstd => zstd::stream::copy_encode(data, output, ZSTD_LEVEL)
+                .map_err(|e| FshError::ProtocolError(format!("zstd compression failed: {}", e))),
+            CompressionAlgorithm::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(output, flate2::Compression::default());
+                encoder.write_all(data)
+                    .and_then(|_| encoder.try_finish())
+                    .map_err(|e| FshError::ProtocolError(format!("deflate compression failed: {}", e)))
+            }
+        }
+    }
+
+    /// Decompress a payload, refusing to inflate it beyond `max_length` bytes.
+    pub fn decompress(&self, data: &[u8], max_length: usize) -> FshResult<Vec<u8>> {
+        let mut output = Vec::new();
+        self.decompress_into(data, max_length, &mut output)?;
+        Ok(output)
+    }
+
+    /// Decompress a payload, appending it to `output` so callers can reuse the
+    /// buffer. Refuses to inflate it beyond `max_length` bytes.
+    pub fn decompress_into(&self, data: &[u8], max_length: usize, output: &mut Vec<u8>) -> FshResult<()> {
+        let start = output.len();
+        let limit = max_length as u64 + 1;
+        // Streamed rather than sized up front: the limit is far larger than most payloads
+        let result = match self {
+            CompressionAlgorithm::Zstd => zstd::stream::read::Decoder::with_buffer(data)
+                .and_then(|decoder| decoder.take(limit).read_to_end(output))
+                .map_err(|e| FshError::ProtocolError(format!("zstd decompression failed: {}", e))),
+            CompressionAlgorithm::Deflate => flate2::read::DeflateDecoder::new(data)
+                .take(limit)
+                .read_to_end(output)
+                .map_err(|e| FshError::ProtocolError(format!("deflate decompression failed: {}", e))),
+        };
+
+        match result {
+            Ok(length) if length > max_length => {
+                output.truncate(start);
+                Err(FshError::ProtocolError(format!("Decompressed payload exceeds {} bytes", max_length)))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                output.truncate(start);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let data = b"fsh ".repeat(1000);
+
+        for algorithm in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Deflate] {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(algorithm.decompress(&compressed, data.len()).unwrap(), data);
+            assert!(algorithm.decompress(&compressed, data.len() - 1).is_err());
+
+            let mut buffer = b"kept".to_vec();
+            algorithm.decompress_into(&compressed, data.len(), &mut buffer).unwrap();
+            assert_eq!(&buffer[4..], &data[..]);
+            buffer.truncate(4);
+            assert!(algorithm.decompress_into(&compressed, 10, &mut buffer).is_err());
+            assert_eq!(buffer, b"kept");
+        }
+    }
+}
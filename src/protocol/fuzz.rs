@@ -0,0 +1,157 @@
+use bytes::{Bytes, BytesMut};
+use proptest::prelude::*;
+use std::collections::HashMap;
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{CompressionAlgorithm, FshCodec, FshMessage, FshResult, FRAME_HEADER_LEN, FSH_MAGIC};
+
+/// Decode `data` as a peer's byte stream once the handshake is done, with
+/// fragmentation and zstd compression negotiated, returning every message or
+/// error the codec produces. Malformed input of any kind must come back as
+/// errors rather than a panic or a hang.
+pub fn decode_frames(data: &[u8]) -> Vec<FshResult<FshMessage>> {
+    let mut codec = FshCodec::new().with_max_frame_length(64 * 1024).with_max_message_length(256 * 1024);
+    codec.set_fragmentation(true);
+    codec.set_compression(Some(CompressionAlgorithm::Zstd));
+
+    let mut buffer = BytesMut::from(data);
+    let mut results = Vec::new();
+    loop {
+        let remaining = buffer.len();
+        match codec.decode(&mut buffer) {
+            Ok(Some(message)) => results.push(Ok(message)),
+            Ok(None) => break,
+            Err(e) => {
+                results.push(Err(e));
+                // Errors the codec can't skip past end the stream, as they do in `Framed`
+                if buffer.len() == remaining {
+                    break;
+                }
+            }
+        }
+    }
+    results
+}
+
+/// Parse `payload` as a frame payload, skipping the framing and checksum so
+/// a fuzzer reaches the message parser directly.
+pub fn decode_payload(payload: &[u8]) -> FshResult<FshMessage> {
+    super::codec::deserialize(payload)
+}
+
+/// Wrap `payload` in a single frame with a valid checksum and the given
+/// length-field flags, so generated payloads get past the frame checks.
+pub fn frame(payload: &[u8], flags: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len() + 4);
+    frame.extend_from_slice(FSH_MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32 | flags).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    frame
+}
+
+/// Encode `message` with `codec` and decode it again.
+pub fn roundtrip(codec: &mut FshCodec, message: FshMessage) -> FshResult<Option<FshMessage>> {
+    let mut buffer = BytesMut::new();
+    codec.encode(message, &mut buffer)?;
+    codec.decode(&mut buffer)
+}
+
+/// Maps with at most one entry, so a message's encoding doesn't depend on
+/// hash order and round trips compare byte for byte.
+pub fn string_map() -> impl Strategy<Value = HashMap<String, String>> {
+    proptest::collection::hash_map(any::<String>(), any::<String>(), 0..=1)
+}
+
+/// Times between 1970 and 2100.
+pub fn timestamp() -> impl Strategy<Value = chrono::DateTime<chrono::Utc>> {
+    (0i64..4_102_444_800, 0u32..1_000_000_000)
+        .prop_map(|(seconds, nanos)| chrono::DateTime::from_timestamp(seconds, nanos).unwrap_or_default())
+}
+
+pub fn bytes() -> impl Strategy<Value = Bytes> {
+    proptest::collection::vec(any::<u8>(), 0..4096).prop_map(Bytes::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{COMPRESSED_FLAG, FRAGMENT_FLAG};
+
+    fn encoded(message: &FshMessage) -> Vec<u8> {
+        bincode::serialize(message).unwrap()
+    }
+
+    /// The derived message strategy nests deeply enough to overflow the
+    /// default test thread's stack in debug builds.
+    fn with_large_stack(test: impl FnOnce() + Send + 'static) {
+        let thread = std::thread::Builder::new().stack_size(64 * 1024 * 1024).spawn(test).unwrap();
+        if let Err(panic) = thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    #[test]
+    fn prop_every_message_roundtrips() {
+        with_large_stack(|| proptest!(|(message in any::<FshMessage>())| {
+            let expected = encoded(&message);
+            let mut codec = FshCodec::new();
+            let decoded = roundtrip(&mut codec, message).unwrap().unwrap();
+            prop_assert_eq!(encoded(&decoded), expected);
+        }));
+    }
+
+    #[test]
+    fn prop_roundtrips_compressed_and_fragmented() {
+        with_large_stack(|| proptest!(|(message in any::<FshMessage>())| {
+            let expected = encoded(&message);
+            let mut codec = FshCodec::new().with_max_frame_length(64).with_compression_threshold(0);
+            codec.set_fragmentation(true);
+            codec.set_compression(Some(CompressionAlgorithm::Deflate));
+            let decoded = roundtrip(&mut codec, message).unwrap().unwrap();
+            prop_assert_eq!(encoded(&decoded), expected);
+        }));
+    }
+
+    #[test]
+    fn prop_mutated_messages_never_panic() {
+        with_large_stack(|| proptest!(|(
+            message in any::<FshMessage>(),
+            position in any::<prop::sample::Index>(),
+            value in any::<u8>(),
+        )| {
+            // Valid payloads with one byte changed reach deep into the parser
+            let mut payload = encoded(&message);
+            let index = position.index(payload.len());
+            payload[index] = value;
+            let _ = decode_payload(&payload);
+            decode_frames(&frame(&payload, 0));
+        }));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_arbitrary_bytes_never_panic(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            decode_frames(&data);
+            let _ = decode_payload(&data);
+        }
+
+        #[test]
+        fn prop_framed_garbage_never_panics(
+            payload in proptest::collection::vec(any::<u8>(), 0..2048),
+            flags in prop_oneof![Just(0), Just(COMPRESSED_FLAG), Just(FRAGMENT_FLAG), Just(COMPRESSED_FLAG | FRAGMENT_FLAG)],
+        ) {
+            let results = decode_frames(&frame(&payload, flags));
+            prop_assert!(results.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_oversized_lengths_are_refused() {
+        // A Connect whose version string claims to be 2^60 bytes long
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(decode_payload(&payload).is_err());
+        assert!(decode_frames(&frame(&payload, 0))[0].is_err());
+    }
+}
@@ -1,8 +1,10 @@
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use super::{ClientInfo, FolderInfo, ShellType};
+use super::{Checksum, ChecksumAlgorithm, ClientInfo, FolderInfo, FshError, FshErrorCode, ShellType};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub enum FshMessage {
     // 握手阶段
     Connect(ConnectMessage),
@@ -24,6 +26,11 @@ pub enum FshMessage {
     Command(CommandMessage),
     CommandOutput(CommandOutputMessage),
     CommandComplete(CommandCompleteMessage),
+    WorkingDirChanged(WorkingDirChangedMessage),
+    JobList(JobListMessage),
+    JobListResponse(JobListResponseMessage),
+    JobKill(JobKillMessage),
+    JobKillResponse(JobKillResponseMessage),
 
     // 文件操作
     FileList(FileListMessage),
@@ -32,15 +39,100 @@ pub enum FshMessage {
     FileReadResponse(FileReadResponseMessage),
     FileWrite(FileWriteMessage),
     FileWriteResponse(FileWriteResponseMessage),
+    FileChecksum(FileChecksumMessage),
+    FileChecksumResponse(FileChecksumResponseMessage),
+    FileStat(FileStatMessage),
+    FileStatResponse(FileStatResponseMessage),
+    FileTail(FileTailMessage),
+    FileTailData(FileTailDataMessage),
+    FileTailStop(FileTailStopMessage),
+    FileTailEnd(FileTailEndMessage),
+    FileDelete(FileDeleteMessage),
+    FileDeleteResponse(FileDeleteResponseMessage),
+    FileRestore(FileRestoreMessage),
+    FileRestoreResponse(FileRestoreResponseMessage),
+
+    // Git
+    GitStatus(GitStatusMessage),
+    GitStatusResponse(GitStatusResponseMessage),
+    GitDiff(GitDiffMessage),
+    GitDiffResponse(GitDiffResponseMessage),
+    GitLog(GitLogMessage),
+    GitLogResponse(GitLogResponseMessage),
+
+    // Port forwarding
+    PortForwardOpen(PortForwardOpenMessage),
+    PortForwardOpenResponse(PortForwardOpenResponseMessage),
+    PortForwardData(PortForwardDataMessage),
+    PortForwardEof(PortForwardEofMessage),
 
     // 控制消息
     Ping,
     Pong,
     Disconnect(DisconnectMessage),
     Error(ErrorMessage),
+    ServerNotice(ServerNoticeMessage),
+
+    // Session environment
+    EnvSet(EnvSetMessage),
+    EnvSetResponse(EnvSetResponseMessage),
+    EnvGet(EnvGetMessage),
+    EnvGetResponse(EnvGetResponseMessage),
+
+    // Command history
+    HistoryRequest(HistoryRequestMessage),
+    HistoryResponse(HistoryResponseMessage),
+
+    // Session observers
+    ObserverConsent(ObserverConsentMessage),
+    ObserverConsentResponse(ObserverConsentResponseMessage),
+    ObserveSession(ObserveSessionMessage),
+    ObserveStarted(ObserveStartedMessage),
+    Observed(ObservedMessage),
+
+    // Collaborative sessions
+    CollaboratorConsent(CollaboratorConsentMessage),
+    CollaboratorConsentResponse(CollaboratorConsentResponseMessage),
+    JoinSession(JoinSessionMessage),
+    SessionJoined(SessionJoinedMessage),
+
+    // Folder switching
+    FolderSwitch(FolderSwitchMessage),
+    FolderSwitched(FolderSwitchedMessage),
+
+    // Binding more folders into a session
+    RootBind(RootBindMessage),
+    RootBound(RootBoundMessage),
+
+    // Directory creation
+    FileMkdir(FileMkdirMessage),
+    FileMkdirResponse(FileMkdirResponseMessage),
+
+    // Operations a client may retry after reconnecting
+    Operation(OperationMessage),
+    OperationResponse(OperationResponseMessage),
+
+    // Locking idle sessions
+    SessionLocked(SessionLockedMessage),
+    SessionUnlock(SessionUnlockMessage),
+    SessionUnlockResponse(SessionUnlockResponseMessage),
+
+    // Resuming sessions saved before a server restart
+    SessionResume(SessionResumeMessage),
+
+    // Commands the server runs when a folder's files change
+    WatchRun(WatchRunMessage),
+
+    // Folders of the user a client logged in as
+    FolderList(FolderListMessage),
+
+    // What a session is using
+    SessionInfoRequest(SessionInfoRequestMessage),
+    SessionInfo(SessionInfoMessage),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct ConnectMessage {
     pub version: String,
     pub client_info: ClientInfo,
@@ -48,6 +140,7 @@ pub struct ConnectMessage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct ConnectResponseMessage {
     pub success: bool,
     pub server_version: String,
@@ -57,24 +150,29 @@ pub struct ConnectResponseMessage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct AuthenticateMessage {
     pub auth_type: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::string_map()"))]
     pub credentials: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct AuthResponseMessage {
     pub success: bool,
     pub message: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FolderBindMessage {
     pub target_folder: String,
     pub preferred_shell: Option<ShellType>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FolderBoundMessage {
     pub success: bool,
     pub folder_info: Option<FolderInfo>,
@@ -82,12 +180,15 @@ pub struct FolderBoundMessage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct SessionStartMessage {
     pub session_id: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::string_map()"))]
     pub environment_vars: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct SessionReadyMessage {
     pub session_id: String,
     pub shell_prompt: String,
@@ -95,58 +196,178 @@ pub struct SessionReadyMessage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct CommandMessage {
     pub session_id: String,
     pub command: String,
     pub args: Vec<String>,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "proptest::option::of(crate::protocol::fuzz::string_map())"))]
     pub environment: Option<HashMap<String, String>>,
+    /// Run as a background job with this client-chosen id instead of in the foreground.
+    pub job_id: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct CommandOutputMessage {
     pub session_id: String,
     pub output_type: OutputType,
-    pub data: Vec<u8>,
+    /// Shared rather than copied as the output is passed along.
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::bytes()"))]
+    pub data: Bytes,
+    /// The background job the output belongs to; `None` for the foreground command.
+    pub job_id: Option<u32>,
+    /// Raw bytes of output that isn't text, e.g. from `tar -cO`; not meant
+    /// for a terminal.
+    pub binary: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub enum OutputType {
     Stdout,
     Stderr,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct CommandCompleteMessage {
     pub session_id: String,
     pub exit_code: i32,
     pub execution_time_ms: u64,
+    /// Working directory once the command finished.
+    pub working_directory: String,
+    pub job_id: Option<u32>,
+}
+
+/// Sent before `CommandComplete` when a command moved the session to another directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct WorkingDirChangedMessage {
+    pub session_id: String,
+    pub working_directory: String,
+    pub shell_prompt: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JobListMessage {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JobListResponseMessage {
+    pub jobs: Vec<JobInfo>,
+}
+
+/// A running background job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JobInfo {
+    pub job_id: u32,
+    pub command: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::timestamp()"))]
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Kill a background job. Its `CommandComplete` follows once it has exited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JobKillMessage {
+    pub session_id: String,
+    pub job_id: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JobKillResponseMessage {
+    pub success: bool,
+    pub error_message: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileListMessage {
     pub session_id: String,
     pub path: String,
     pub show_hidden: bool,
+    /// Index of the first entry to return.
+    pub offset: u64,
+    /// Maximum number of entries to return; `None` for the rest of the directory.
+    pub limit: Option<u32>,
 }
 
+/// One batch of a directory listing. A listing is sent as one or more batches,
+/// the last of which has `complete` set.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileListResponseMessage {
     pub success: bool,
     pub files: Vec<FileEntry>,
     pub error_message: Option<String>,
+    /// Index of this batch's first entry within the directory.
+    pub offset: u64,
+    /// Number of entries in the directory.
+    pub total: u64,
+    pub complete: bool,
+    /// Offset to request the entries after `limit`, if any remain.
+    pub next_offset: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileEntry {
     pub name: String,
     pub path: String,
     pub is_directory: bool,
     pub size: u64,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::timestamp()"))]
     pub modified: chrono::DateTime<chrono::Utc>,
+    /// `ls -l` style mode string, e.g. `-rw-r--r--`.
     pub permissions: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// Windows file attributes (`readonly`, `hidden`, ...); empty on other platforms.
+    pub attributes: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileStatMessage {
+    pub session_id: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileStatResponseMessage {
+    pub success: bool,
+    pub stat: Option<FileStat>,
+    pub error_message: Option<String>,
+}
+
+/// Extended metadata for a single path. `entry` describes the symlink target
+/// when the path is a symlink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileStat {
+    pub entry: FileEntry,
+    pub is_symlink: bool,
+    /// Symlink target, relative to the folder root.
+    pub symlink_target: Option<String>,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "proptest::option::of(crate::protocol::fuzz::timestamp())"))]
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "proptest::option::of(crate::protocol::fuzz::timestamp())"))]
+    pub accessed: Option<chrono::DateTime<chrono::Utc>>,
+    /// Inode number on Unix.
+    pub file_id: Option<u64>,
+    pub device: Option<u64>,
+    pub hard_links: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileReadMessage {
     pub session_id: String,
     pub file_path: String,
@@ -155,40 +376,714 @@ pub struct FileReadMessage {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileReadResponseMessage {
     pub success: bool,
-    pub data: Vec<u8>,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::bytes()"))]
+    pub data: Bytes,
     pub total_size: u64,
     pub error_message: Option<String>,
+    /// Offset of `data` within the file.
+    pub offset: u64,
+    /// Checksum of `data`, so the receiver can detect a corrupted chunk.
+    pub checksum: Option<Checksum>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileWriteMessage {
     pub session_id: String,
     pub file_path: String,
     pub data: Vec<u8>,
     pub append: bool,
+    /// Write at this offset without truncating; `None` replaces the file (or appends).
+    pub offset: Option<u64>,
+    /// Checksum of `data`; the write is refused if it does not match.
+    pub checksum: Option<Checksum>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FileWriteResponseMessage {
     pub success: bool,
     pub bytes_written: u64,
     pub error_message: Option<String>,
 }
 
+/// Hash a file, or the byte range `offset..offset + length` of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileChecksumMessage {
+    pub session_id: String,
+    pub file_path: String,
+    pub algorithm: ChecksumAlgorithm,
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileChecksumResponseMessage {
+    pub success: bool,
+    pub checksum: Option<Checksum>,
+    /// Number of bytes hashed.
+    pub size: u64,
+    pub error_message: Option<String>,
+}
+
+/// Request the last `lines` lines of a file and, with `follow`, everything appended afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileTailMessage {
+    pub session_id: String,
+    pub file_path: String,
+    pub lines: u32,
+    pub follow: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileTailDataMessage {
+    pub session_id: String,
+    pub data: Vec<u8>,
+    /// The file was rotated or truncated and `data` starts at the beginning of the new file.
+    pub rotated: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileTailStopMessage {
+    pub session_id: String,
+}
+
+/// Last message of a tail: sent when it finishes, is stopped, or fails.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileTailEndMessage {
+    pub session_id: String,
+    pub error_message: Option<String>,
+}
+
+/// Delete a file, or a directory with `recursive`. The folder's trash keeps
+/// it restorable unless the trash is disabled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileDeleteMessage {
+    pub session_id: String,
+    pub file_path: String,
+    pub recursive: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileDeleteResponseMessage {
+    pub success: bool,
+    /// Id to restore the item with, `None` if it was deleted permanently.
+    pub trash_id: Option<String>,
+    pub error_message: Option<String>,
+}
+
+/// Create a directory and any missing parents. Succeeds if it already exists.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileMkdirMessage {
+    pub session_id: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileMkdirResponseMessage {
+    pub success: bool,
+    pub error_message: Option<String>,
+}
+
+/// Restore a trashed item by trash id or original path; for a path the most
+/// recently deleted copy is restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileRestoreMessage {
+    pub session_id: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FileRestoreResponseMessage {
+    pub success: bool,
+    /// Where the item was put back, relative to the folder root.
+    pub restored_path: Option<String>,
+    pub error_message: Option<String>,
+}
+
+/// Status of the git repository containing `path` (the working directory
+/// when `None`), limited to `path` when it is below the repository root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitStatusMessage {
+    pub session_id: String,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitStatusResponseMessage {
+    pub success: bool,
+    pub status: Option<GitStatus>,
+    pub error_message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitStatus {
+    /// The repository's working tree, relative to the folder root.
+    pub repository: String,
+    /// `None` when HEAD is detached or the branch has no commits yet.
+    pub branch: Option<String>,
+    /// Commit id of HEAD.
+    pub head: Option<String>,
+    pub entries: Vec<GitStatusEntry>,
+}
+
+/// A changed file; `path` is relative to the repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitStatusEntry {
+    pub path: String,
+    /// Change staged in the index.
+    pub index: Option<GitChange>,
+    /// Change in the working tree that is not staged.
+    pub worktree: Option<GitChange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub enum GitChange {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    TypeChanged,
+    Untracked,
+    Conflicted,
+}
+
+/// Unified diff of the working tree against the index, or of the index
+/// against HEAD with `staged`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitDiffMessage {
+    pub session_id: String,
+    pub path: Option<String>,
+    pub staged: bool,
+    pub context_lines: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitDiffResponseMessage {
+    pub success: bool,
+    /// Paths in the patch are relative to the repository.
+    pub diff: Option<String>,
+    /// The diff was cut off at the size limit.
+    pub truncated: bool,
+    pub error_message: Option<String>,
+}
+
+/// Commits reachable from HEAD, newest first, that touch `path` when it is
+/// below the repository root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitLogMessage {
+    pub session_id: String,
+    pub path: Option<String>,
+    pub max_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitLogResponseMessage {
+    pub success: bool,
+    pub commits: Vec<GitCommit>,
+    pub error_message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct GitCommit {
+    pub id: String,
+    pub author: String,
+    pub email: String,
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    pub summary: String,
+}
+
+/// Open a TCP connection from the server to `host:port` and carry it as
+/// channel `channel_id`. Only loopback hosts and the folder's forwardable
+/// ports are accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct PortForwardOpenMessage {
+    pub session_id: String,
+    /// Chosen by the client; unique among the session's open channels.
+    pub channel_id: u32,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct PortForwardOpenResponseMessage {
+    pub channel_id: u32,
+    pub success: bool,
+    pub error_message: Option<String>,
+}
+
+/// Bytes for a forwarded connection, sent by either side.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct PortForwardDataMessage {
+    pub session_id: String,
+    pub channel_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// The sender will send no more data on the channel. The channel is closed
+/// once both sides have sent this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct PortForwardEofMessage {
+    pub session_id: String,
+    pub channel_id: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct DisconnectMessage {
     pub reason: String,
 }
 
+/// Set a variable for the session's later commands, or unset it when
+/// `value` is `None`. Refused unless the folder lets clients set it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct EnvSetMessage {
+    pub session_id: String,
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct EnvSetResponseMessage {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// Ask for one variable, or every variable the session sets when `name` is
+/// `None`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct EnvGetMessage {
+    pub session_id: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct EnvGetResponseMessage {
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::string_map()"))]
+    pub variables: HashMap<String, String>,
+}
+
+/// Ask for the most recent commands run in the session, or in its folder
+/// when the folder shares history, newest last.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct HistoryRequestMessage {
+    pub session_id: String,
+    /// At most this many entries; `None` for everything the server kept.
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct HistoryResponseMessage {
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// A command a client ran, as the server remembers it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct HistoryEntry {
+    /// The command line as the client sent it.
+    pub command: String,
+    pub working_directory: String,
+    pub exit_code: i32,
+    pub session_id: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::timestamp()"))]
+    pub ran_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The session owner allows or stops read-only observers. Withdrawing
+/// consent detaches everyone observing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ObserverConsentMessage {
+    pub session_id: String,
+    pub allow: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ObserverConsentResponseMessage {
+    pub allow: bool,
+    /// Observers attached when the response was sent.
+    pub observers: u32,
+}
+
+/// Sent instead of `FolderBind` to watch another client's session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ObserveSessionMessage {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ObserveStartedMessage {
+    pub success: bool,
+    pub folder_info: Option<FolderInfo>,
+    pub working_directory: Option<String>,
+    pub error_message: Option<String>,
+}
+
+/// Something that happened in an observed session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ObservedMessage {
+    pub session_id: String,
+    pub event: ObservedEvent,
+}
+
+/// The owner's side of the session, as mirrored to observers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub enum ObservedEvent {
+    /// The owner ran a command.
+    Command(CommandMessage),
+    Output(CommandOutputMessage),
+    Complete(CommandCompleteMessage),
+    WorkingDirChanged(WorkingDirChangedMessage),
+    /// The server refused or failed a request.
+    Error(ErrorMessage),
+    /// A participant other than the one receiving the event ran a command.
+    ParticipantCommand { participant: String, command: CommandMessage },
+    ParticipantJoined { participant: String },
+    ParticipantLeft { participant: String },
+}
+
+/// The session owner lets other clients join and run commands, or detaches
+/// every participant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct CollaboratorConsentMessage {
+    pub session_id: String,
+    pub allow: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct CollaboratorConsentResponseMessage {
+    pub allow: bool,
+    /// Participants joined when the response was sent.
+    pub participants: u32,
+}
+
+/// Sent instead of `FolderBind` to take part in another client's session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct JoinSessionMessage {
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionJoinedMessage {
+    pub success: bool,
+    pub folder_info: Option<FolderInfo>,
+    pub working_directory: Option<String>,
+    /// How the session's audit log and other participants name this client.
+    pub participant: Option<String>,
+    pub error_message: Option<String>,
+}
+
+/// Move the session to another folder, replacing its shell but keeping the
+/// session and connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FolderSwitchMessage {
+    pub session_id: String,
+    pub target_folder: String,
+    pub preferred_shell: Option<ShellType>,
+}
+
+/// The session's new folder and shell. A refused switch is answered with an
+/// Error instead, and the session stays where it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FolderSwitchedMessage {
+    pub folder_info: FolderInfo,
+    pub shell_prompt: String,
+    pub working_directory: String,
+    /// The new shell's environment, as sent in SessionStart.
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::string_map()"))]
+    pub environment_vars: HashMap<String, String>,
+}
+
+/// Bind another folder into the session, reachable as `/<folder name>`
+/// next to the session's own folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct RootBindMessage {
+    pub session_id: String,
+    pub folder: String,
+}
+
+/// The folder now bound into the session. A refused bind is answered with an
+/// Error instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct RootBoundMessage {
+    /// Where the folder appears in the session, e.g. `/docs`.
+    pub root: String,
+    /// The folder, with the permissions that apply under `root`.
+    pub folder_info: FolderInfo,
+}
+
+/// A change to files sent with an id the client picked, so that resending it
+/// after a reconnect doesn't make the change twice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct OperationMessage {
+    /// A UUID, the same on every attempt.
+    pub operation_id: String,
+    pub request: OperationRequest,
+}
+
+/// The requests that can be sent as an operation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub enum OperationRequest {
+    FileWrite(FileWriteMessage),
+    FileDelete(FileDeleteMessage),
+    FileMkdir(FileMkdirMessage),
+    FileRestore(FileRestoreMessage),
+}
+
+impl OperationRequest {
+    /// Send the request on the session `session_id`, e.g. after reconnecting.
+    pub fn set_session_id(&mut self, session_id: &str) {
+        let id = match self {
+            OperationRequest::FileWrite(msg) => &mut msg.session_id,
+            OperationRequest::FileDelete(msg) => &mut msg.session_id,
+            OperationRequest::FileMkdir(msg) => &mut msg.session_id,
+            OperationRequest::FileRestore(msg) => &mut msg.session_id,
+        };
+        *id = session_id.to_string();
+    }
+}
+
+impl From<OperationRequest> for FshMessage {
+    fn from(request: OperationRequest) -> Self {
+        match request {
+            OperationRequest::FileWrite(msg) => FshMessage::FileWrite(msg),
+            OperationRequest::FileDelete(msg) => FshMessage::FileDelete(msg),
+            OperationRequest::FileMkdir(msg) => FshMessage::FileMkdir(msg),
+            OperationRequest::FileRestore(msg) => FshMessage::FileRestore(msg),
+        }
+    }
+}
+
+/// The answer to an operation, echoing its id. `replayed` is set when the
+/// operation had already been done and this is the answer it got then.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct OperationResponseMessage {
+    pub operation_id: String,
+    pub replayed: bool,
+    pub reply: OperationReply,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub enum OperationReply {
+    FileWrite(FileWriteResponseMessage),
+    FileDelete(FileDeleteResponseMessage),
+    FileMkdir(FileMkdirResponseMessage),
+    FileRestore(FileRestoreResponseMessage),
+    Error(ErrorMessage),
+}
+
+impl OperationReply {
+    /// `message` as the reply to a request of type `request_type` (see
+    /// `FshMessage::message_type`), if it is one.
+    pub fn answering(request_type: &str, message: &FshMessage) -> Option<Self> {
+        match (request_type, message) {
+            (_, FshMessage::Error(error)) => Some(OperationReply::Error(error.clone())),
+            ("file_write", FshMessage::FileWriteResponse(resp)) => Some(OperationReply::FileWrite(resp.clone())),
+            ("file_delete", FshMessage::FileDeleteResponse(resp)) => Some(OperationReply::FileDelete(resp.clone())),
+            ("file_mkdir", FshMessage::FileMkdirResponse(resp)) => Some(OperationReply::FileMkdir(resp.clone())),
+            ("file_restore", FshMessage::FileRestoreResponse(resp)) => Some(OperationReply::FileRestore(resp.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl From<OperationReply> for FshMessage {
+    fn from(reply: OperationReply) -> Self {
+        match reply {
+            OperationReply::FileWrite(resp) => FshMessage::FileWriteResponse(resp),
+            OperationReply::FileDelete(resp) => FshMessage::FileDeleteResponse(resp),
+            OperationReply::FileMkdir(resp) => FshMessage::FileMkdirResponse(resp),
+            OperationReply::FileRestore(resp) => FshMessage::FileRestoreResponse(resp),
+            OperationReply::Error(error) => FshMessage::Error(error),
+        }
+    }
+}
+
+/// Sent instead of `FolderBind` to pick up a session the server saved, in
+/// its folder and working directory and with its variables and history. It
+/// is answered with `FolderBound`; after a refusal the client may still bind
+/// a folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionResumeMessage {
+    /// The session to resume, as it was called before the restart.
+    pub session_id: String,
+    pub preferred_shell: Option<ShellType>,
+}
+
+/// The session sat idle and now refuses everything but keepalives,
+/// `Disconnect` and `SessionUnlock` until its token is entered again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionLockedMessage {
+    pub session_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionUnlockMessage {
+    pub session_id: String,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionUnlockResponseMessage {
+    pub success: bool,
+    pub error_message: Option<String>,
+}
+
+/// Sent to the sessions on a folder after one of its watch rules ran its
+/// command because files changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct WatchRunMessage {
+    pub folder: String,
+    pub rule: String,
+    pub command: String,
+    /// Some of the files whose change triggered the run, relative to the folder.
+    pub changed: Vec<String>,
+    /// `None` if the command couldn't be started or timed out.
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    /// The end of its stdout and stderr.
+    pub output: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::timestamp()"))]
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Sent right after a successful `AuthResponse` when users are configured,
+/// since `ConnectResponse` went out before the server knew who the client is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct FolderListMessage {
+    pub folders: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionInfoRequestMessage {
+    pub session_id: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct SessionInfoMessage {
+    pub session_id: String,
+    pub folder: String,
+    pub working_directory: String,
+    pub commands_run: u64,
+    pub resources: ResourceUsage,
+}
+
+/// What a session's running commands and everything they started use, as
+/// last sampled by the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ResourceUsage {
+    /// Percent of one core over the last sampling interval.
+    pub cpu_percent: u32,
+    /// Resident memory.
+    pub memory_bytes: u64,
+    pub processes: u32,
+}
+
+/// An operator's announcement, e.g. planned maintenance, sent to every
+/// session whose client negotiated `FEATURE_SERVER_NOTICE`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub struct ServerNoticeMessage {
+    pub message: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "crate::protocol::fuzz::timestamp()"))]
+    pub sent_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct ErrorMessage {
-    pub error_type: String,
+    pub code: FshErrorCode,
     pub message: String,
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(strategy = "proptest::option::of(crate::protocol::fuzz::string_map())"))]
     pub details: Option<HashMap<String, String>>,
 }
 
+impl ErrorMessage {
+    pub fn new(code: FshErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+}
+
+impl From<&FshError> for ErrorMessage {
+    fn from(error: &FshError) -> Self {
+        Self::new(error.code(), error.detail())
+    }
+}
+
+impl From<ErrorMessage> for FshError {
+    fn from(error: ErrorMessage) -> Self {
+        FshError::from_code(error.code, error.message)
+    }
+}
+
 impl FshMessage {
     pub fn message_type(&self) -> &'static str {
         match self {
@@ -203,16 +1098,75 @@ impl FshMessage {
             FshMessage::Command(_) => "command",
             FshMessage::CommandOutput(_) => "command_output",
             FshMessage::CommandComplete(_) => "command_complete",
+            FshMessage::WorkingDirChanged(_) => "working_dir_changed",
+            FshMessage::JobList(_) => "job_list",
+            FshMessage::JobListResponse(_) => "job_list_response",
+            FshMessage::JobKill(_) => "job_kill",
+            FshMessage::JobKillResponse(_) => "job_kill_response",
             FshMessage::FileList(_) => "file_list",
             FshMessage::FileListResponse(_) => "file_list_response",
             FshMessage::FileRead(_) => "file_read",
             FshMessage::FileReadResponse(_) => "file_read_response",
             FshMessage::FileWrite(_) => "file_write",
             FshMessage::FileWriteResponse(_) => "file_write_response",
+            FshMessage::FileChecksum(_) => "file_checksum",
+            FshMessage::FileChecksumResponse(_) => "file_checksum_response",
+            FshMessage::FileStat(_) => "file_stat",
+            FshMessage::FileStatResponse(_) => "file_stat_response",
+            FshMessage::FileTail(_) => "file_tail",
+            FshMessage::FileTailData(_) => "file_tail_data",
+            FshMessage::FileTailStop(_) => "file_tail_stop",
+            FshMessage::FileTailEnd(_) => "file_tail_end",
+            FshMessage::FileDelete(_) => "file_delete",
+            FshMessage::FileDeleteResponse(_) => "file_delete_response",
+            FshMessage::FileRestore(_) => "file_restore",
+            FshMessage::FileRestoreResponse(_) => "file_restore_response",
+            FshMessage::GitStatus(_) => "git_status",
+            FshMessage::GitStatusResponse(_) => "git_status_response",
+            FshMessage::GitDiff(_) => "git_diff",
+            FshMessage::GitDiffResponse(_) => "git_diff_response",
+            FshMessage::GitLog(_) => "git_log",
+            FshMessage::GitLogResponse(_) => "git_log_response",
+            FshMessage::PortForwardOpen(_) => "port_forward_open",
+            FshMessage::PortForwardOpenResponse(_) => "port_forward_open_response",
+            FshMessage::PortForwardData(_) => "port_forward_data",
+            FshMessage::PortForwardEof(_) => "port_forward_eof",
             FshMessage::Ping => "ping",
             FshMessage::Pong => "pong",
             FshMessage::Disconnect(_) => "disconnect",
             FshMessage::Error(_) => "error",
+            FshMessage::ServerNotice(_) => "server_notice",
+            FshMessage::EnvSet(_) => "env_set",
+            FshMessage::EnvSetResponse(_) => "env_set_response",
+            FshMessage::EnvGet(_) => "env_get",
+            FshMessage::EnvGetResponse(_) => "env_get_response",
+            FshMessage::HistoryRequest(_) => "history_request",
+            FshMessage::HistoryResponse(_) => "history_response",
+            FshMessage::ObserverConsent(_) => "observer_consent",
+            FshMessage::ObserverConsentResponse(_) => "observer_consent_response",
+            FshMessage::ObserveSession(_) => "observe_session",
+            FshMessage::ObserveStarted(_) => "observe_started",
+            FshMessage::Observed(_) => "observed",
+            FshMessage::CollaboratorConsent(_) => "collaborator_consent",
+            FshMessage::CollaboratorConsentResponse(_) => "collaborator_consent_response",
+            FshMessage::JoinSession(_) => "join_session",
+            FshMessage::SessionJoined(_) => "session_joined",
+            FshMessage::FolderSwitch(_) => "folder_switch",
+            FshMessage::FolderSwitched(_) => "folder_switched",
+            FshMessage::RootBind(_) => "root_bind",
+            FshMessage::RootBound(_) => "root_bound",
+            FshMessage::FileMkdir(_) => "file_mkdir",
+            FshMessage::FileMkdirResponse(_) => "file_mkdir_response",
+            FshMessage::Operation(_) => "operation",
+            FshMessage::OperationResponse(_) => "operation_response",
+            FshMessage::SessionLocked(_) => "session_locked",
+            FshMessage::SessionUnlock(_) => "session_unlock",
+            FshMessage::SessionUnlockResponse(_) => "session_unlock_response",
+            FshMessage::SessionResume(_) => "session_resume",
+            FshMessage::WatchRun(_) => "watch_run",
+            FshMessage::FolderList(_) => "folder_list",
+            FshMessage::SessionInfoRequest(_) => "session_info_request",
+            FshMessage::SessionInfo(_) => "session_info",
         }
     }
 }
\ No newline at end of file
@@ -1,19 +1,40 @@
 pub mod message;
+pub mod capabilities;
+pub mod checksum;
 pub mod codec;
+pub mod compression;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+pub mod relay;
 pub mod ssh_compat;
+pub mod transport;
 
 pub use message::*;
+pub use capabilities::*;
+pub use checksum::*;
 pub use codec::*;
+pub use compression::*;
+pub use relay::*;
 pub use ssh_compat::*;
+pub use transport::*;
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub enum ShellType {
     Cmd,
+    /// Windows PowerShell (`powershell`), falling back to `pwsh` where it is missing.
     PowerShell,
+    /// PowerShell 7+ (`pwsh`).
+    PowerShellCore,
     Bash,
     GitBash,
+    Zsh,
+    Fish,
+    Nushell,
+    /// Plain POSIX `sh`.
+    Sh,
 }
 
 impl Default for ShellType {
@@ -27,6 +48,7 @@ impl Default for ShellType {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub enum Permission {
     Read,
     Write,
@@ -34,6 +56,7 @@ pub enum Permission {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct ClientInfo {
     pub platform: String,
     pub app_version: String,
@@ -41,6 +64,7 @@ pub struct ClientInfo {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
 pub struct FolderInfo {
     pub name: String,
     pub path: String,
@@ -59,37 +83,258 @@ pub struct SessionInfo {
 }
 
 pub const FSH_VERSION: &str = "1.0";
+
+/// Starts every frame, followed by the frame's wire revision.
+pub const FRAME_MAGIC: &[u8] = b"FSH";
+
+/// Newest frame layout this build writes, once the peer has agreed to it.
+/// Independent of `FSH_VERSION`, which only governs features.
+pub const WIRE_REVISION: u8 = 2;
+
+/// Oldest frame layout this build reads. Connect and ConnectResponse are
+/// always written in it, so peers of any revision can start talking.
+pub const MIN_WIRE_REVISION: u8 = 1;
+
+/// Magic and revision of a revision 1 frame.
 pub const FSH_MAGIC: &[u8] = b"FSH\x01";
 
-#[derive(Debug)]
+/// The mDNS service type servers advertise themselves under.
+pub const MDNS_SERVICE_TYPE: &str = "_fsh._tcp.local.";
+
+/// Bytes read from a forwarded connection per `PortForwardData` message.
+pub const FORWARD_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Data messages queued for a forwarded connection before the reader of the
+/// FSH connection waits for it to catch up.
+pub const FORWARD_QUEUE_DEPTH: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
 pub enum FshError {
+    #[error("Protocol error: {0}")]
     ProtocolError(String),
+    #[error("Authentication failed")]
     AuthenticationFailed,
+    #[error("Folder not found: {0}")]
     FolderNotFound(String),
+    #[error("Permission denied: {0}")]
     PermissionDenied(String),
+    #[error("Session not found: {0}")]
     SessionNotFound(String),
+    #[error("Invalid path: {0}")]
     InvalidPath(String),
+    #[error("Shell error: {0}")]
     ShellError(String),
+    #[error("Network error: {0}")]
     NetworkError(String),
+    #[error("Configuration error: {0}")]
     ConfigError(String),
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    /// A written file was found infected and quarantined.
+    #[error("Malware detected: {0}")]
+    MalwareDetected(String),
+    /// An I/O failure, keeping the original error so its kind decides the
+    /// wire code and whether a retry can help.
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A message that could not be encoded or decoded.
+    #[error("Protocol error: {context}: {source}")]
+    Codec {
+        context: String,
+        #[source]
+        source: bincode::Error,
+    },
 }
 
-impl std::fmt::Display for FshError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl FshError {
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        FshError::Io { context: context.into(), source }
+    }
+
+    pub fn codec(context: impl Into<String>, source: bincode::Error) -> Self {
+        FshError::Codec { context: context.into(), source }
+    }
+
+    /// The wire-level code for this error, as sent in `ErrorMessage`.
+    pub fn code(&self) -> FshErrorCode {
         match self {
-            FshError::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
-            FshError::AuthenticationFailed => write!(f, "Authentication failed"),
-            FshError::FolderNotFound(path) => write!(f, "Folder not found: {}", path),
-            FshError::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
-            FshError::SessionNotFound(id) => write!(f, "Session not found: {}", id),
-            FshError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
-            FshError::ShellError(msg) => write!(f, "Shell error: {}", msg),
-            FshError::NetworkError(msg) => write!(f, "Network error: {}", msg),
-            FshError::ConfigError(msg) => write!(f, "Configuration error: {}", msg),
+            FshError::ProtocolError(_) => FshErrorCode::ProtocolError,
+            FshError::AuthenticationFailed => FshErrorCode::AuthenticationFailed,
+            FshError::FolderNotFound(_) => FshErrorCode::FolderNotFound,
+            FshError::PermissionDenied(_) => FshErrorCode::PermissionDenied,
+            FshError::SessionNotFound(_) => FshErrorCode::SessionNotFound,
+            FshError::InvalidPath(_) => FshErrorCode::InvalidPath,
+            FshError::ShellError(_) => FshErrorCode::ShellError,
+            FshError::NetworkError(_) => FshErrorCode::NetworkError,
+            FshError::ConfigError(_) => FshErrorCode::ConfigError,
+            FshError::RateLimited(_) => FshErrorCode::RateLimited,
+            FshError::MalwareDetected(_) => FshErrorCode::MalwareDetected,
+            FshError::Io { source, .. } => match source.kind() {
+                std::io::ErrorKind::PermissionDenied => FshErrorCode::PermissionDenied,
+                std::io::ErrorKind::NotFound
+                | std::io::ErrorKind::AlreadyExists
+                | std::io::ErrorKind::IsADirectory
+                | std::io::ErrorKind::NotADirectory
+                | std::io::ErrorKind::DirectoryNotEmpty => FshErrorCode::InvalidPath,
+                std::io::ErrorKind::InvalidData => FshErrorCode::ProtocolError,
+                _ => FshErrorCode::NetworkError,
+            },
+            FshError::Codec { .. } => FshErrorCode::ProtocolError,
         }
     }
+
+    /// The error text without the category prefix added by `Display`.
+    pub fn detail(&self) -> String {
+        match self {
+            FshError::AuthenticationFailed => "Authentication failed".to_string(),
+            FshError::ProtocolError(msg)
+            | FshError::FolderNotFound(msg)
+            | FshError::PermissionDenied(msg)
+            | FshError::SessionNotFound(msg)
+            | FshError::InvalidPath(msg)
+            | FshError::ShellError(msg)
+            | FshError::NetworkError(msg)
+            | FshError::ConfigError(msg)
+            | FshError::RateLimited(msg)
+            | FshError::MalwareDetected(msg) => msg.clone(),
+            FshError::Io { context, source } => format!("{}: {}", context, source),
+            FshError::Codec { context, source } => format!("{}: {}", context, source),
+        }
+    }
+
+    /// The kind of the underlying I/O error, if there is one.
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            FshError::Io { source, .. } => Some(source.kind()),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may succeed if sent again. I/O failures are
+    /// judged by their kind; other errors by their wire code.
+    pub fn is_retryable(&self) -> bool {
+        match self.io_kind() {
+            Some(kind) => matches!(
+                kind,
+                std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            None => self.code().is_retryable(),
+        }
+    }
+
+    /// Rebuild an error from a code received from the peer.
+    pub fn from_code(code: FshErrorCode, message: String) -> Self {
+        match code {
+            FshErrorCode::AuthenticationFailed => FshError::AuthenticationFailed,
+            FshErrorCode::FolderNotFound => FshError::FolderNotFound(message),
+            FshErrorCode::PermissionDenied => FshError::PermissionDenied(message),
+            FshErrorCode::SessionNotFound => FshError::SessionNotFound(message),
+            FshErrorCode::InvalidPath => FshError::InvalidPath(message),
+            FshErrorCode::ShellError => FshError::ShellError(message),
+            FshErrorCode::NetworkError => FshError::NetworkError(message),
+            FshErrorCode::ConfigError => FshError::ConfigError(message),
+            FshErrorCode::RateLimited => FshError::RateLimited(message),
+            FshErrorCode::MalwareDetected => FshError::MalwareDetected(message),
+            FshErrorCode::ProtocolError | FshErrorCode::IdleWarning => FshError::ProtocolError(message),
+        }
+    }
+}
+
+/// Machine-readable classification of an `ErrorMessage`, so peers can react
+/// to failures without parsing message text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(proptest_derive::Arbitrary))]
+pub enum FshErrorCode {
+    ProtocolError,
+    AuthenticationFailed,
+    FolderNotFound,
+    PermissionDenied,
+    SessionNotFound,
+    InvalidPath,
+    ShellError,
+    NetworkError,
+    ConfigError,
+    /// Not a failure: the session is about to be closed for inactivity.
+    IdleWarning,
+    /// Too many requests; wait before sending more.
+    RateLimited,
+    /// The file just written was infected and has been quarantined.
+    MalwareDetected,
 }
 
-impl std::error::Error for FshError {}
+impl FshErrorCode {
+    /// Whether a request refused with this code may succeed if sent again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FshErrorCode::NetworkError | FshErrorCode::RateLimited)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FshErrorCode::ProtocolError => "protocol_error",
+            FshErrorCode::AuthenticationFailed => "authentication_failed",
+            FshErrorCode::FolderNotFound => "folder_not_found",
+            FshErrorCode::PermissionDenied => "permission_denied",
+            FshErrorCode::SessionNotFound => "session_not_found",
+            FshErrorCode::InvalidPath => "invalid_path",
+            FshErrorCode::ShellError => "shell_error",
+            FshErrorCode::NetworkError => "network_error",
+            FshErrorCode::ConfigError => "config_error",
+            FshErrorCode::IdleWarning => "idle_warning",
+            FshErrorCode::RateLimited => "rate_limited",
+            FshErrorCode::MalwareDetected => "malware_detected",
+        }
+    }
+}
 
-pub type FshResult<T> = Result<T, FshError>;
\ No newline at end of file
+impl std::fmt::Display for FshErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<std::io::Error> for FshError {
+    fn from(e: std::io::Error) -> Self {
+        FshError::io("I/O error", e)
+    }
+}
+
+pub type FshResult<T> = Result<T, FshError>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_io_error_source() {
+        let error = FshError::io("Failed to open 'notes.txt'", std::io::ErrorKind::NotFound.into());
+        assert_eq!(error.io_kind(), Some(std::io::ErrorKind::NotFound));
+        assert_eq!(error.code(), FshErrorCode::InvalidPath);
+        assert!(!error.is_retryable());
+        let source = error.source().unwrap().downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+
+        // Errors crossing the wire keep their code and detail, without a doubled prefix
+        let message = ErrorMessage::from(&error);
+        let received = FshError::from(message);
+        assert_eq!(received.code(), FshErrorCode::InvalidPath);
+        assert_eq!(received.to_string(), format!("Invalid path: {}", error));
+
+        let timeout = FshError::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(timeout.code(), FshErrorCode::NetworkError);
+        assert!(timeout.is_retryable());
+        assert!(FshError::RateLimited("slow down".to_string()).is_retryable());
+        assert!(!FshError::PermissionDenied("no".to_string()).is_retryable());
+    }
+}
@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::protocol::{FshError, FshResult, FshStream, FshTransport};
+
+/// Longest handshake line accepted from a relay peer.
+const MAX_RELAY_LINE: usize = 16 * 1024;
+
+const RELAY_PREFIX: &str = "FSH-RELAY";
+
+/// The first line of every connection to a relay. After the relay answers
+/// `OK`, client and data connections carry the FSH protocol end to end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayHello {
+    /// A client asking for the agent with this name, or else the one
+    /// serving a folder of this name.
+    Client { target: String },
+    /// An agent's control connection, registering its folders.
+    Agent { name: String, secret: String, folders: Vec<String> },
+    /// An agent's connection for the tunnel the relay announced as `id`.
+    Data { name: String, secret: String, id: String },
+}
+
+impl RelayHello {
+    pub fn to_line(&self) -> String {
+        match self {
+            RelayHello::Client { target } => format!("{} CLIENT {}", RELAY_PREFIX, target),
+            RelayHello::Agent { name, secret, folders } => format!(
+                "{} AGENT {} {} {}",
+                RELAY_PREFIX, name, secret, serde_json::to_string(folders).unwrap_or_else(|_| "[]".to_string()),
+            ),
+            RelayHello::Data { name, secret, id } => format!("{} DATA {} {} {}", RELAY_PREFIX, name, secret, id),
+        }
+    }
+
+    pub fn parse(line: &str) -> FshResult<Self> {
+        let invalid = || FshError::ProtocolError("Invalid relay handshake".to_string());
+        let rest = line.strip_prefix(RELAY_PREFIX).and_then(|rest| rest.strip_prefix(' ')).ok_or_else(invalid)?;
+        let (kind, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+        match kind {
+            "CLIENT" if !rest.is_empty() => Ok(RelayHello::Client { target: rest.to_string() }),
+            "AGENT" => {
+                let mut parts = rest.splitn(3, ' ');
+                let (Some(name), Some(secret), Some(folders)) = (parts.next(), parts.next(), parts.next()) else {
+                    return Err(invalid());
+                };
+                let folders = serde_json::from_str(folders).map_err(|_| invalid())?;
+                Ok(RelayHello::Agent { name: name.to_string(), secret: secret.to_string(), folders })
+            }
+            "DATA" => match rest.split(' ').collect::<Vec<_>>()[..] {
+                [name, secret, id] => Ok(RelayHello::Data {
+                    name: name.to_string(),
+                    secret: secret.to_string(),
+                    id: id.to_string(),
+                }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Whether `name` can be used as a relay agent name or secret: printable
+/// ASCII without spaces.
+pub fn is_relay_word(word: &str) -> bool {
+    !word.is_empty() && word.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Read one `\n`-terminated line. Reads a byte at a time so nothing after
+/// the line is consumed.
+pub async fn read_relay_line<S: AsyncRead + Unpin>(stream: &mut S) -> FshResult<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await.map_err(|e| FshError::io("Relay connection closed", e))?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_RELAY_LINE {
+            return Err(FshError::ProtocolError("Relay handshake line too long".to_string()));
+        }
+        line.push(byte);
+    }
+    String::from_utf8(line)
+        .map(|line| line.trim_end_matches('\r').to_string())
+        .map_err(|_| FshError::ProtocolError("Relay handshake is not UTF-8".to_string()))
+}
+
+pub async fn write_relay_line<S: AsyncWrite + Unpin>(stream: &mut S, line: &str) -> FshResult<()> {
+    stream.write_all(format!("{}\n", line).as_bytes()).await
+        .map_err(|e| FshError::io("Failed to write to relay connection", e))?;
+    stream.flush().await.map_err(|e| FshError::io("Failed to write to relay connection", e))
+}
+
+/// Wait for the relay's `OK`; an `ERR <reason>` becomes an error.
+pub async fn expect_relay_ok<S: AsyncRead + Unpin>(stream: &mut S) -> FshResult<()> {
+    match read_relay_line(stream).await?.as_str() {
+        "OK" => Ok(()),
+        answer => Err(FshError::NetworkError(format!(
+            "Relay refused the connection: {}", answer.strip_prefix("ERR ").unwrap_or(answer)
+        ))),
+    }
+}
+
+/// Reaches a server through a relay: `addr` is the relay, and `target`
+/// names the agent, or a folder one of its agents serves. Authentication
+/// and TLS still happen with the agent.
+#[derive(Debug, Clone)]
+pub struct RelayTransport {
+    target: String,
+}
+
+impl RelayTransport {
+    pub fn new(target: impl Into<String>) -> Self {
+        Self { target: target.into() }
+    }
+}
+
+#[async_trait]
+impl FshTransport for RelayTransport {
+    async fn connect(&self, addr: &str) -> FshResult<FshStream> {
+        let mut stream = TcpStream::connect(addr).await
+            .map_err(|e| FshError::io(format!("Failed to connect to relay {}", addr), e))?;
+        write_relay_line(&mut stream, &RelayHello::Client { target: self.target.clone() }.to_line()).await?;
+        expect_relay_ok(&mut stream).await?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_lines() {
+        let hellos = [
+            RelayHello::Client { target: "my project".to_string() },
+            RelayHello::Agent {
+                name: "lab-pi".to_string(),
+                secret: "s3cret".to_string(),
+                folders: vec!["docs".to_string(), "my project".to_string()],
+            },
+            RelayHello::Data { name: "lab-pi".to_string(), secret: "s3cret".to_string(), id: "42".to_string() },
+        ];
+        for hello in hellos {
+            assert_eq!(RelayHello::parse(&hello.to_line()).unwrap(), hello);
+        }
+
+        assert!(RelayHello::parse("FSH-RELAY CLIENT ").is_err());
+        assert!(RelayHello::parse("FSH-RELAY DATA a b").is_err());
+        assert!(RelayHello::parse("GET / HTTP/1.1").is_err());
+        assert!(!is_relay_word("two words"));
+    }
+}
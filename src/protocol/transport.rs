@@ -0,0 +1,359 @@
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio_rustls::TlsStream;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Any byte stream the protocol can run over, e.g. a WebSocket adapter or
+/// an in-process pipe.
+pub trait RawStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static> RawStream for T {}
+
+/// A connection between client and server: plain TCP, TLS over another
+/// stream, or a stream from a custom transport.
+pub enum FshStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<FshStream>>),
+    Custom(Box<dyn RawStream>),
+}
+
+impl FshStream {
+    pub fn custom(stream: impl RawStream) -> Self {
+        FshStream::Custom(Box::new(stream))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, FshStream::Tls(_))
+    }
+}
+
+impl std::fmt::Debug for FshStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FshStream::Tcp(stream) => f.debug_tuple("Tcp").field(stream).finish(),
+            FshStream::Tls(stream) => f.debug_tuple("Tls").field(stream.get_ref().0).finish(),
+            FshStream::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl From<TcpStream> for FshStream {
+    fn from(stream: TcpStream) -> Self {
+        FshStream::Tcp(stream)
+    }
+}
+
+impl From<TlsStream<FshStream>> for FshStream {
+    fn from(stream: TlsStream<FshStream>) -> Self {
+        FshStream::Tls(Box::new(stream))
+    }
+}
+
+impl From<DuplexStream> for FshStream {
+    fn from(stream: DuplexStream) -> Self {
+        FshStream::custom(stream)
+    }
+}
+
+impl AsyncRead for FshStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FshStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            FshStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            FshStream::Custom(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for FshStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            FshStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            FshStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            FshStream::Custom(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FshStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            FshStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            FshStream::Custom(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FshStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            FshStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            FshStream::Custom(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Opens the client's connection to a server. TLS, when configured, is
+/// layered on top of the stream it returns.
+#[async_trait]
+pub trait FshTransport: Send + Sync + std::fmt::Debug {
+    async fn connect(&self, addr: &str) -> FshResult<FshStream>;
+}
+
+/// Accepts connections for the server. TLS, when configured, is layered on
+/// top of the streams it returns.
+#[async_trait]
+pub trait FshListener: Send + Sync + std::fmt::Debug {
+    /// The next connection and its peer's address, or `None` once no more
+    /// connections can arrive.
+    async fn accept(&self) -> FshResult<Option<(FshStream, String)>>;
+
+    /// The socket address clients connect to, if there is one.
+    fn local_addr(&self) -> Option<SocketAddr>;
+
+    /// Every socket address clients can connect to.
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.local_addr().into_iter().collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+#[async_trait]
+impl FshTransport for TcpTransport {
+    async fn connect(&self, addr: &str) -> FshResult<FshStream> {
+        let stream = TcpStream::connect(addr).await
+            .map_err(|e| FshError::io(format!("Failed to connect to {}", addr), e))?;
+        Ok(stream.into())
+    }
+}
+
+#[async_trait]
+impl FshListener for TcpListener {
+    async fn accept(&self) -> FshResult<Option<(FshStream, String)>> {
+        let (stream, addr) = TcpListener::accept(self).await
+            .map_err(|e| FshError::io("Failed to accept connection", e))?;
+        Ok(Some((stream.into(), addr.to_string())))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpListener::local_addr(self).ok()
+    }
+}
+
+/// TCP listeners on several addresses accepting as one, e.g. the IPv4 and
+/// IPv6 wildcard addresses, or a few interfaces.
+#[derive(Debug)]
+pub struct TcpListeners {
+    listeners: Vec<TcpListener>,
+}
+
+impl TcpListeners {
+    /// Listen on every address a `host:port` in `addresses` resolves to, or
+    /// on none: if any bind fails, the error names each failure and what did
+    /// bind. Addresses with port 0 share the port the first of them got.
+    pub async fn bind(addresses: &[String]) -> FshResult<Self> {
+        let mut resolved: Vec<SocketAddr> = Vec::new();
+        for address in addresses {
+            let addrs = tokio::net::lookup_host(address.as_str()).await
+                .map_err(|e| FshError::io(format!("Cannot resolve listen address {}", address), e))?;
+            for addr in addrs {
+                if !resolved.contains(&addr) {
+                    resolved.push(addr);
+                }
+            }
+        }
+        if resolved.is_empty() {
+            return Err(FshError::ConfigError("No addresses to listen on".to_string()));
+        }
+
+        // With IPv6 and IPv4 wildcards both listed, the IPv6 socket must not
+        // claim IPv4 as well
+        let only_v6 = resolved.len() > 1;
+        let mut chosen_port = None;
+        let mut listeners = Vec::new();
+        let mut failures = Vec::new();
+        for mut addr in resolved {
+            if addr.port() == 0 {
+                if let Some(port) = chosen_port {
+                    addr.set_port(port);
+                }
+            }
+            match bind_tcp(addr, only_v6) {
+                Ok(listener) => {
+                    if addr.port() == 0 {
+                        chosen_port = listener.local_addr().ok().map(|local| local.port());
+                    }
+                    listeners.push(listener);
+                }
+                Err(e) => failures.push((addr, e)),
+            }
+        }
+
+        if failures.is_empty() {
+            return Ok(Self { listeners });
+        }
+        if listeners.is_empty() && failures.len() == 1 {
+            let (addr, e) = failures.remove(0);
+            return Err(FshError::io(format!("Failed to bind to {}", addr), e));
+        }
+        let failed = failures.iter()
+            .map(|(addr, e)| format!("{} ({})", addr, e))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let bound = listeners.iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        Err(FshError::NetworkError(if bound.is_empty() {
+            format!("Failed to bind to {}", failed)
+        } else {
+            format!("Failed to bind to {}; not listening on {} alone", failed, bound.join(", "))
+        }))
+    }
+
+    /// Accept on sockets that are already listening, e.g. ones passed in by
+    /// a service manager.
+    pub fn from_listeners(listeners: Vec<TcpListener>) -> Self {
+        Self { listeners }
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() && only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    // As `TcpListener::bind` does, so a restarted server can rebind at once
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[async_trait]
+impl FshListener for TcpListeners {
+    async fn accept(&self) -> FshResult<Option<(FshStream, String)>> {
+        let accepts = self.listeners.iter().map(|listener| Box::pin(listener.accept()));
+        let (accepted, _, _) = futures::future::select_all(accepts).await;
+        let (stream, addr) = accepted.map_err(|e| FshError::io("Failed to accept connection", e))?;
+        Ok(Some((stream.into(), addr.to_string())))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.listeners.first().and_then(|listener| listener.local_addr().ok())
+    }
+
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        TcpListeners::local_addrs(self)
+    }
+}
+
+/// Buffer size of each direction of an in-process connection.
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A transport and listener joined by in-process pipes, for running client
+/// and server in one process without a socket.
+pub fn memory_transport() -> (MemoryTransport, MemoryListener) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let transport = MemoryTransport { incoming: tx, next_id: Arc::new(AtomicU64::new(1)) };
+    (transport, MemoryListener { incoming: Mutex::new(rx) })
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryTransport {
+    incoming: mpsc::UnboundedSender<(DuplexStream, String)>,
+    next_id: Arc<AtomicU64>,
+}
+
+#[async_trait]
+impl FshTransport for MemoryTransport {
+    /// Connects to the paired listener whatever `addr` is.
+    async fn connect(&self, _addr: &str) -> FshResult<FshStream> {
+        let (client, server) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let peer = format!("memory:{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.incoming.send((server, peer))
+            .map_err(|_| FshError::NetworkError("In-process listener is closed".to_string()))?;
+        Ok(client.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryListener {
+    incoming: Mutex<mpsc::UnboundedReceiver<(DuplexStream, String)>>,
+}
+
+#[async_trait]
+impl FshListener for MemoryListener {
+    async fn accept(&self) -> FshResult<Option<(FshStream, String)>> {
+        Ok(self.incoming.lock().await.recv().await.map(|(stream, peer)| (stream.into(), peer)))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let (transport, listener) = memory_transport();
+
+        let mut client = transport.connect("anywhere").await.unwrap();
+        let (mut server, peer) = listener.accept().await.unwrap().unwrap();
+        assert_eq!(peer, "memory:1");
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Dropping every transport closes the listener
+        drop(transport);
+        assert!(listener.accept().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_listeners() {
+        let mut addresses = vec!["127.0.0.1:0".to_string()];
+        if std::net::TcpListener::bind("[::1]:0").is_ok() {
+            addresses.push("[::1]:0".to_string());
+        }
+        let listeners = TcpListeners::bind(&addresses).await.unwrap();
+        let local_addrs = listeners.local_addrs();
+        assert_eq!(local_addrs.len(), addresses.len());
+        // Every address gets the port the first one was given
+        assert!(local_addrs.iter().all(|addr| addr.port() == local_addrs[0].port()));
+
+        for addr in &local_addrs {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = listeners.accept().await.unwrap().unwrap();
+            assert_eq!(peer.parse::<SocketAddr>().unwrap().is_ipv6(), addr.is_ipv6());
+        }
+
+        // Nothing is left listening when one of the addresses can't be bound
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = taken.local_addr().unwrap().to_string();
+        let error = TcpListeners::bind(&["127.0.0.1:0".to_string(), taken_addr.clone()]).await.unwrap_err();
+        assert!(error.to_string().contains(&taken_addr), "{}", error);
+        assert!(error.to_string().contains("not listening on"), "{}", error);
+    }
+}
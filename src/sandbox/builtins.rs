@@ -0,0 +1,396 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::PathValidator;
+
+/// Read-only file commands run in-process, so they behave the same on every
+/// platform and never leave the sandbox.
+pub const FILE_BUILTINS: &[&str] = &["cat", "head", "tail", "stat", "tree", "du"];
+
+const DEFAULT_LINE_COUNT: usize = 10;
+
+/// Run a file builtin. Returns `None` if `command` is not one, otherwise the
+/// command's stdout, which `cat` of a binary file leaves as it is, or the
+/// error message to report on stderr.
+pub(crate) fn run(
+    command: &str,
+    args: &[String],
+    validator: &PathValidator,
+    working_directory: &Path,
+) -> Option<Result<Vec<u8>, String>> {
+    let ctx = Context { validator, working_directory };
+
+    let result = match command {
+        "cat" => return Some(ctx.cat(args)),
+        "head" => ctx.head_or_tail(args, false),
+        "tail" => ctx.head_or_tail(args, true),
+        "stat" => ctx.stat(args),
+        "tree" => ctx.tree(args),
+        "du" => ctx.du(args),
+        _ => return None,
+    };
+
+    Some(result.map(String::into_bytes))
+}
+
+struct Context<'a> {
+    validator: &'a PathValidator,
+    working_directory: &'a Path,
+}
+
+impl Context<'_> {
+    fn resolve(&self, arg: &str) -> Result<PathBuf, String> {
+        let path = self.working_directory.join(arg);
+        self.validator
+            .validate_path(&path.to_string_lossy())
+            .map_err(|e| format!("{}: {}", arg, e))
+    }
+
+    fn display(&self, path: &Path) -> String {
+        match self.validator.get_relative_path(path) {
+            Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+            Ok(relative) => relative.display().to_string(),
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    fn read_bytes(&self, arg: &str) -> Result<Vec<u8>, String> {
+        let path = self.resolve(arg)?;
+        if path.is_dir() {
+            return Err(format!("{}: Is a directory", arg));
+        }
+
+        fs::read(&path).map_err(|e| format!("{}: {}", arg, e))
+    }
+
+    fn read_file(&self, arg: &str) -> Result<String, String> {
+        self.read_bytes(arg).map(|data| String::from_utf8_lossy(&data).into_owned())
+    }
+
+    fn cat(&self, args: &[String]) -> Result<Vec<u8>, String> {
+        if args.is_empty() {
+            return Err("cat: missing file operand".to_string());
+        }
+
+        let mut output = Vec::new();
+        for arg in args {
+            output.extend(self.read_bytes(arg).map_err(|e| format!("cat: {}", e))?);
+        }
+        Ok(output)
+    }
+
+    fn head_or_tail(&self, args: &[String], tail: bool) -> Result<String, String> {
+        let name = if tail { "tail" } else { "head" };
+        let (count, files) = parse_line_count(args).map_err(|e| format!("{}: {}", name, e))?;
+        if files.is_empty() {
+            return Err(format!("{}: missing file operand", name));
+        }
+
+        let mut output = String::new();
+        for (index, file) in files.iter().enumerate() {
+            let content = self.read_file(file).map_err(|e| format!("{}: {}", name, e))?;
+            if files.len() > 1 {
+                if index > 0 {
+                    output.push('\n');
+                }
+                output.push_str(&format!("==> {} <==\n", file));
+            }
+
+            let lines: Vec<&str> = content.split_inclusive('\n').collect();
+            let selected = if tail {
+                &lines[lines.len().saturating_sub(count)..]
+            } else {
+                &lines[..count.min(lines.len())]
+            };
+            output.extend(selected.iter().copied());
+        }
+        Ok(output)
+    }
+
+    fn stat(&self, args: &[String]) -> Result<String, String> {
+        if args.is_empty() {
+            return Err("stat: missing operand".to_string());
+        }
+
+        let mut output = String::new();
+        for arg in args {
+            let path = self.resolve(arg).map_err(|e| format!("stat: {}", e))?;
+            let metadata = path.symlink_metadata().map_err(|e| format!("stat: {}: {}", arg, e))?;
+
+            let file_type = if metadata.file_type().is_symlink() {
+                "symbolic link"
+            } else if metadata.is_dir() {
+                "directory"
+            } else {
+                "regular file"
+            };
+
+            output.push_str(&format!("  File: {}\n", self.display(&path)));
+            output.push_str(&format!("  Type: {}\n", file_type));
+            output.push_str(&format!("  Size: {}\n", metadata.len()));
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                output.push_str(&format!("  Mode: {:04o}\n", metadata.permissions().mode() & 0o7777));
+            }
+            if let Ok(modified) = metadata.modified() {
+                let modified: chrono::DateTime<chrono::Utc> = modified.into();
+                output.push_str(&format!("Modify: {}\n", modified.to_rfc3339()));
+            }
+        }
+        Ok(output)
+    }
+
+    fn tree(&self, args: &[String]) -> Result<String, String> {
+        let mut show_hidden = false;
+        let mut max_depth = usize::MAX;
+        let mut target = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-a" => show_hidden = true,
+                "-L" => {
+                    max_depth = iter.next()
+                        .and_then(|depth| depth.parse().ok())
+                        .filter(|depth| *depth > 0)
+                        .ok_or_else(|| "tree: -L requires a positive depth".to_string())?;
+                }
+                _ => target = Some(arg.as_str()),
+            }
+        }
+
+        let root = self.resolve(target.unwrap_or(".")).map_err(|e| format!("tree: {}", e))?;
+        if !root.is_dir() {
+            return Err(format!("tree: {}: Not a directory", self.display(&root)));
+        }
+
+        let mut output = format!("{}\n", self.display(&root));
+        let mut counts = (0, 0);
+        self.tree_level(&root, "", 1, max_depth, show_hidden, &mut output, &mut counts);
+        output.push_str(&format!("\n{} directories, {} files\n", counts.0, counts.1));
+        Ok(output)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn tree_level(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        depth: usize,
+        max_depth: usize,
+        show_hidden: bool,
+        output: &mut String,
+        counts: &mut (usize, usize),
+    ) {
+        let entries = self.sorted_entries(dir, show_hidden);
+
+        for (index, (name, path, is_dir)) in entries.iter().enumerate() {
+            let last = index + 1 == entries.len();
+            output.push_str(&format!("{}{}{}\n", prefix, if last { "└── " } else { "├── " }, name));
+
+            if *is_dir {
+                counts.0 += 1;
+                if depth < max_depth {
+                    let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                    self.tree_level(path, &child_prefix, depth + 1, max_depth, show_hidden, output, counts);
+                }
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    /// Directory entries sorted by name, skipping symlinks that escape the sandbox.
+    /// Symlinked directories are reported but never descended into.
+    fn sorted_entries(&self, dir: &Path, show_hidden: bool) -> Vec<(String, PathBuf, bool)> {
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+
+        let mut entries: Vec<(String, PathBuf, bool)> = read_dir
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !show_hidden && name.starts_with('.') {
+                    return None;
+                }
+
+                let path = entry.path();
+                let metadata = self.validator.entry_metadata(&path)?;
+                let is_symlink = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+                Some((name, path, metadata.is_dir() && !is_symlink))
+            })
+            .collect();
+
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    fn du(&self, args: &[String]) -> Result<String, String> {
+        let mut summarize = false;
+        let mut human = false;
+        let mut targets = Vec::new();
+
+        for arg in args {
+            match arg.as_str() {
+                "-s" => summarize = true,
+                "-h" => human = true,
+                "-sh" | "-hs" => {
+                    summarize = true;
+                    human = true;
+                }
+                _ => targets.push(arg.as_str()),
+            }
+        }
+        if targets.is_empty() {
+            targets.push(".");
+        }
+
+        let mut output = String::new();
+        for target in targets {
+            let path = self.resolve(target).map_err(|e| format!("du: {}", e))?;
+            let mut lines = Vec::new();
+            let total = self.disk_usage(&path, !summarize, &mut lines);
+
+            for (size, dir) in lines {
+                output.push_str(&format!("{}\t{}\n", format_size(size, human), self.display(&dir)));
+            }
+            output.push_str(&format!("{}\t{}\n", format_size(total, human), self.display(&path)));
+        }
+        Ok(output)
+    }
+
+    /// Total size of `path`, recording every subdirectory's size in `lines` when `list` is set.
+    fn disk_usage(&self, path: &Path, list: bool, lines: &mut Vec<(u64, PathBuf)>) -> u64 {
+        let Ok(metadata) = path.symlink_metadata() else {
+            return 0;
+        };
+        if !metadata.is_dir() {
+            return metadata.len();
+        }
+
+        let mut total = 0;
+        for (_, child, is_dir) in self.sorted_entries(path, true) {
+            if is_dir {
+                let size = self.disk_usage(&child, list, lines);
+                if list {
+                    lines.push((size, child));
+                }
+                total += size;
+            } else {
+                total += child.symlink_metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+        total
+    }
+}
+
+/// Split `-n N`, `-nN` or `-N` off the arguments of `head`/`tail`.
+fn parse_line_count(args: &[String]) -> Result<(usize, Vec<&str>), String> {
+    let mut count = DEFAULT_LINE_COUNT;
+    let mut files = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = if arg == "-n" {
+            Some(iter.next().ok_or_else(|| "option requires an argument -- 'n'".to_string())?.as_str())
+        } else if let Some(value) = arg.strip_prefix("-n") {
+            Some(value)
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            Some(&arg[1..])
+        } else {
+            files.push(arg.as_str());
+            None
+        };
+
+        if let Some(value) = value {
+            count = value.parse().map_err(|_| format!("invalid number of lines: '{}'", value))?;
+        }
+    }
+
+    Ok((count, files))
+}
+
+fn format_size(bytes: u64, human: bool) -> String {
+    if !human {
+        return bytes.to_string();
+    }
+
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, PathValidator) {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir(temp_dir.path().join("src")).unwrap();
+        std::fs::write(temp_dir.path().join("src/lib.rs"), "1\n2\n3\n4\n5\n").unwrap();
+        std::fs::write(temp_dir.path().join("README"), "hello\n").unwrap();
+
+        let validator = PathValidator::new(temp_dir.path().to_path_buf()).unwrap();
+        (temp_dir, validator)
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_cat_head_tail() {
+        let (_temp_dir, validator) = setup();
+        let root = validator.root_path().to_path_buf();
+
+        let cat = run("cat", &args(&["README"]), &validator, &root).unwrap().unwrap();
+        assert_eq!(cat, b"hello\n");
+
+        let head = run("head", &args(&["-n", "2", "src/lib.rs"]), &validator, &root).unwrap().unwrap();
+        assert_eq!(head, b"1\n2\n");
+
+        let tail = run("tail", &args(&["-2", "lib.rs"]), &validator, &root.join("src")).unwrap().unwrap();
+        assert_eq!(tail, b"4\n5\n");
+
+        assert!(run("cat", &args(&["../etc/passwd"]), &validator, &root).unwrap().is_err());
+        assert!(run("echo", &[], &validator, &root).is_none());
+    }
+
+    #[test]
+    fn test_tree_and_du() {
+        let (_temp_dir, validator) = setup();
+        let root = validator.root_path().to_path_buf();
+
+        let tree = String::from_utf8(run("tree", &[], &validator, &root).unwrap().unwrap()).unwrap();
+        assert!(tree.contains("├── README"));
+        assert!(tree.contains("└── src"));
+        assert!(tree.contains("    └── lib.rs"));
+        assert!(tree.ends_with("1 directories, 2 files\n"));
+
+        let du = run("du", &args(&["-s"]), &validator, &root).unwrap().unwrap();
+        assert_eq!(du, b"16\t.\n");
+    }
+
+    #[test]
+    fn test_stat() {
+        let (_temp_dir, validator) = setup();
+        let root = validator.root_path().to_path_buf();
+
+        let stat = String::from_utf8(run("stat", &args(&["src"]), &validator, &root).unwrap().unwrap()).unwrap();
+        assert!(stat.contains("File: src"));
+        assert!(stat.contains("Type: directory"));
+    }
+}
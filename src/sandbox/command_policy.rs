@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Characters the shell would reinterpret. Arguments to a program with a
+/// policy may not contain them, so the argv that is checked is the argv that runs.
+const SHELL_SPECIAL_CHARS: &[char] = &[
+    ';', '&', '|', '<', '>', '$', '`', '\'', '"', '\\', '(', ')', '{', '}',
+    '*', '?', '[', ']', '%', '^', '\n', '\r',
+];
+
+/// Which subcommands and flags a program may be run with, e.g. for `git`:
+/// `allow = ["status", "diff", "log", "pull"]`, `deny = ["push --force"]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandPolicy {
+    /// Subcommands that may be run; empty allows any.
+    pub allow: Vec<String>,
+    /// Subcommand and flag combinations that are refused, e.g. `push --force`.
+    /// An entry starting with a flag applies to every subcommand.
+    pub deny: Vec<String>,
+}
+
+/// An argument list split into its subcommand and flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ParsedArgs {
+    /// The first argument that isn't a flag.
+    subcommand: Option<String>,
+    flags: Vec<String>,
+}
+
+impl ParsedArgs {
+    fn parse(args: &[&str]) -> Self {
+        let mut subcommand = None;
+        let mut flags = Vec::new();
+
+        for &arg in args {
+            // Everything after `--` is an operand
+            if arg == "--" {
+                break;
+            }
+            if arg.starts_with('-') && arg.len() > 1 {
+                flags.push(arg.to_string());
+            } else if subcommand.is_none() {
+                subcommand = Some(arg.to_string());
+            }
+        }
+
+        Self { subcommand, flags }
+    }
+
+    /// Whether `flag` was given: exactly, as `--flag=value`, or for a
+    /// single-letter flag, inside a group such as `-uf`.
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|given| {
+            if given == flag || given.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')) {
+                return true;
+            }
+            match (flag.strip_prefix('-'), given.strip_prefix('-')) {
+                (Some(letter), Some(group)) if letter.len() == 1 && !group.starts_with('-') => group.contains(letter),
+                _ => false,
+            }
+        })
+    }
+}
+
+impl CommandPolicy {
+    /// Check the arguments of a program this policy applies to.
+    pub fn check(&self, program: &str, args: &[&str]) -> FshResult<()> {
+        let parsed = ParsedArgs::parse(args);
+
+        if !self.allow.is_empty() {
+            let allowed = parsed.subcommand.as_ref().is_some_and(|sub| self.allow.contains(sub));
+            if !allowed {
+                return Err(FshError::PermissionDenied(format!(
+                    "'{} {}' is not allowed; permitted subcommands: {}",
+                    program,
+                    parsed.subcommand.as_deref().unwrap_or(""),
+                    self.allow.join(", ")
+                )));
+            }
+        }
+
+        for rule in &self.deny {
+            let mut words = rule.split_whitespace().peekable();
+            let subcommand = words.next_if(|word| !word.starts_with('-'));
+            if subcommand.is_some() && subcommand != parsed.subcommand.as_deref() {
+                continue;
+            }
+
+            let mut flags = words.peekable();
+            if flags.peek().is_none() || flags.all(|flag| parsed.has_flag(flag)) {
+                return Err(FshError::PermissionDenied(format!("'{} {}' is not allowed", program, rule)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check a command line against the per-program policies. `command` may
+/// itself contain arguments; the program is matched by file name.
+pub fn check_command_policies(
+    policies: &BTreeMap<String, CommandPolicy>,
+    command: &str,
+    args: &[String],
+) -> FshResult<()> {
+    if policies.is_empty() {
+        return Ok(());
+    }
+
+    // The shell splits on whitespace, so an argument with spaces is several
+    let mut words = command.split_whitespace().chain(args.iter().flat_map(|arg| arg.split_whitespace()));
+    let Some(program) = words.next() else {
+        return Ok(());
+    };
+    let Some((name, policy)) = find_policy(policies, program) else {
+        return Ok(());
+    };
+
+    let words: Vec<&str> = words.collect();
+    if let Some(word) = words.iter().find(|word| word.contains(SHELL_SPECIAL_CHARS) || word.starts_with('~')) {
+        return Err(FshError::PermissionDenied(format!(
+            "Argument '{}' to '{}' contains shell syntax", word, name
+        )));
+    }
+
+    policy.check(name, &words)
+}
+
+fn find_policy<'a>(policies: &'a BTreeMap<String, CommandPolicy>, program: &str) -> Option<(&'a str, &'a CommandPolicy)> {
+    let file_name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let lower = file_name.to_ascii_lowercase();
+    let stem = lower.strip_suffix(".exe").unwrap_or(&lower);
+
+    policies.iter()
+        .find(|(name, _)| if cfg!(windows) { name.eq_ignore_ascii_case(stem) } else { name.as_str() == file_name })
+        .map(|(name, policy)| (name.as_str(), policy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git_policies() -> BTreeMap<String, CommandPolicy> {
+        BTreeMap::from([(
+            "git".to_string(),
+            CommandPolicy {
+                allow: vec!["status".to_string(), "diff".to_string(), "log".to_string(), "push".to_string()],
+                deny: vec!["push --force".to_string(), "--no-verify".to_string()],
+            },
+        )])
+    }
+
+    fn check(command: &str, args: &[&str]) -> FshResult<()> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        check_command_policies(&git_policies(), command, &args)
+    }
+
+    #[test]
+    fn test_subcommands_and_flags() {
+        assert!(check("git", &["status"]).is_ok());
+        assert!(check("git", &["push", "origin", "main"]).is_ok());
+        assert!(check("git", &["commit", "-m", "msg"]).is_err());
+        assert!(check("git", &[]).is_err());
+
+        assert!(check("git", &["push", "--force"]).is_err());
+        assert!(check("git", &["push", "--force=origin"]).is_err());
+        assert!(check("git", &["log", "--no-verify"]).is_err());
+        // Only `push --force` is refused, and `--` ends the flags
+        assert!(check("git", &["diff", "--force"]).is_ok());
+        assert!(check("git", &["log", "--", "--force"]).is_ok());
+
+        // Other programs are not affected
+        assert!(check("ls", &["-la"]).is_ok());
+    }
+
+    #[test]
+    fn test_args_are_split_like_the_shell() {
+        assert!(check("git push", &["--force"]).is_err());
+        assert!(check("git", &["push --force"]).is_err());
+        assert!(check("/usr/bin/git", &["push", "--force"]).is_err());
+        assert!(check("git", &["status;", "rm", "-rf", "."]).is_err());
+        assert!(check("git", &["push", "\"--for\"ce"]).is_err());
+    }
+
+    #[test]
+    fn test_short_flag_groups() {
+        let policies = BTreeMap::from([(
+            "rm".to_string(),
+            CommandPolicy { allow: Vec::new(), deny: vec!["-r".to_string()] },
+        )]);
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        assert!(check_command_policies(&policies, "rm", &args(&["-fr", "dir"])).is_err());
+        assert!(check_command_policies(&policies, "rm", &args(&["-f", "file"])).is_ok());
+    }
+}
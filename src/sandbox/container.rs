@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Where the per-folder state directory (working directory reports) is
+/// mounted inside the container.
+const STATE_MOUNT: &str = "/run/fsh";
+
+/// Held while a container is being started, so two sessions binding the same
+/// folder don't both try to create it.
+static STARTING: Mutex<()> = Mutex::const_new(());
+
+/// Run a folder's commands inside a long-lived Docker or Podman container
+/// with the folder mounted, instead of directly on the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContainerConfig {
+    pub enabled: bool,
+    /// `docker` or `podman`, or the path to either.
+    pub runtime: String,
+    /// Needs the folder's shell and `sleep`.
+    pub image: String,
+    /// `none` for no network at all, `bridge`, or a network the runtime knows.
+    pub network: String,
+    /// Memory limit in the runtime's syntax, e.g. `512m`.
+    pub memory: Option<String>,
+    /// CPU limit, e.g. `1.5`.
+    pub cpus: Option<String>,
+    pub pids_limit: Option<u32>,
+    /// Where the folder is mounted inside the container.
+    pub mount_path: String,
+}
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            runtime: "docker".to_string(),
+            image: String::new(),
+            network: "none".to_string(),
+            memory: None,
+            cpus: None,
+            pids_limit: None,
+            mount_path: "/workspace".to_string(),
+        }
+    }
+}
+
+impl ContainerConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.image.is_empty() {
+            return Err(FshError::ConfigError("container.image is required".to_string()));
+        }
+        if !self.mount_path.starts_with('/') || self.mount_path == "/" {
+            return Err(FshError::ConfigError(format!(
+                "container.mount_path '{}' must be an absolute path below /", self.mount_path
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A folder's container, named `fsh-<folder>`, shared by every session on
+/// the folder and left running when they end.
+#[derive(Debug, Clone)]
+pub struct FolderContainer {
+    config: ContainerConfig,
+    name: String,
+    host_root: PathBuf,
+    state_dir: PathBuf,
+    read_only: bool,
+}
+
+impl FolderContainer {
+    pub fn new(config: &ContainerConfig, folder: &str, host_root: &Path, read_only: bool) -> Self {
+        let name: String = folder.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+            .collect();
+        Self {
+            config: config.clone(),
+            state_dir: std::env::temp_dir().join(format!("fsh-container-{}", name)),
+            name: format!("fsh-{}", name),
+            host_root: host_root.canonicalize().unwrap_or_else(|_| host_root.to_path_buf()),
+            read_only,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mount_path(&self) -> &str {
+        &self.config.mount_path
+    }
+
+    /// Where `host_path`, inside the folder, is in the container.
+    pub fn container_path(&self, host_path: &Path) -> Option<String> {
+        let host_path = host_path.canonicalize().unwrap_or_else(|_| host_path.to_path_buf());
+        let relative = host_path.strip_prefix(&self.host_root).ok()?;
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        Some(match relative.as_str() {
+            "" => self.config.mount_path.clone(),
+            relative => format!("{}/{}", self.config.mount_path.trim_end_matches('/'), relative),
+        })
+    }
+
+    /// Where `container_path`, inside the mount, is on the server.
+    pub fn host_path(&self, container_path: &str) -> Option<PathBuf> {
+        let relative = container_path.strip_prefix(self.config.mount_path.trim_end_matches('/'))?;
+        if !relative.is_empty() && !relative.starts_with('/') {
+            return None;
+        }
+        Some(self.host_root.join(relative.trim_start_matches('/')))
+    }
+
+    /// A file shared with the container for `file_name`: its path on the
+    /// server and inside the container.
+    pub fn state_file(&self, file_name: &str) -> (PathBuf, String) {
+        (self.state_dir.join(file_name), format!("{}/{}", STATE_MOUNT, file_name))
+    }
+
+    /// Start the container unless it is already running.
+    pub async fn ensure_running(&self) -> FshResult<()> {
+        let _starting = STARTING.lock().await;
+        if self.is_running().await? {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.state_dir)?;
+        // A stopped container from an earlier run would keep the name taken
+        let _ = self.runtime().args(["rm", "-f", &self.name]).output().await;
+
+        info!("Starting container {} from {}", self.name, self.config.image);
+        let output = self.runtime().args(self.run_args()).output().await
+            .map_err(|e| FshError::io(format!("Failed to run {}", self.config.runtime), e))?;
+        if !output.status.success() {
+            return Err(FshError::ShellError(format!(
+                "Failed to start container {}: {}", self.name, String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn is_running(&self) -> FshResult<bool> {
+        let output = self.runtime()
+            .args(["inspect", "--format", "{{.State.Running}}", &self.name])
+            .output().await
+            .map_err(|e| FshError::io(format!("Failed to run {}", self.config.runtime), e))?;
+        Ok(output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "true")
+    }
+
+    fn run_args(&self) -> Vec<String> {
+        let mount = format!(
+            "{}:{}{}",
+            self.host_root.display(),
+            self.config.mount_path,
+            if self.read_only { ":ro" } else { "" },
+        );
+        let mut args = vec![
+            "run".to_string(), "-d".to_string(),
+            "--name".to_string(), self.name.clone(),
+            "--network".to_string(), self.config.network.clone(),
+            "-v".to_string(), mount,
+            "-v".to_string(), format!("{}:{}", self.state_dir.display(), STATE_MOUNT),
+            "-w".to_string(), self.config.mount_path.clone(),
+        ];
+        if let Some(memory) = &self.config.memory {
+            args.extend(["--memory".to_string(), memory.clone()]);
+        }
+        if let Some(cpus) = &self.config.cpus {
+            args.extend(["--cpus".to_string(), cpus.clone()]);
+        }
+        if let Some(pids_limit) = self.config.pids_limit {
+            args.extend(["--pids-limit".to_string(), pids_limit.to_string()]);
+        }
+        args.extend([
+            "--entrypoint".to_string(), "sleep".to_string(),
+            self.config.image.clone(),
+            "infinity".to_string(),
+        ]);
+        args
+    }
+
+    /// The process that runs `program args` in the container, in
+    /// `working_dir` (a container path) with exactly `env`.
+    pub fn exec(&self, program: &str, args: &[String], working_dir: &str, env: &HashMap<String, String>) -> Command {
+        let mut cmd = self.runtime();
+        cmd.args(["exec", "-i", "-w", working_dir]);
+        for (key, value) in env {
+            cmd.arg("-e").arg(format!("{}={}", key, value));
+        }
+        cmd.arg(&self.name).arg(program).args(args);
+        cmd
+    }
+
+    fn runtime(&self) -> Command {
+        let mut cmd = Command::new(&self.config.runtime);
+        cmd.stdin(Stdio::null());
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_paths_and_limits() {
+        let config = ContainerConfig {
+            enabled: true,
+            image: "alpine:3".to_string(),
+            memory: Some("512m".to_string()),
+            pids_limit: Some(64),
+            ..ContainerConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        let container = FolderContainer::new(&config, "my project", Path::new("/srv/project"), true);
+        assert_eq!(container.name(), "fsh-my-project");
+
+        assert_eq!(container.container_path(Path::new("/srv/project")).unwrap(), "/workspace");
+        assert_eq!(container.container_path(Path::new("/srv/project/src/lib")).unwrap(), "/workspace/src/lib");
+        assert!(container.container_path(Path::new("/etc")).is_none());
+        assert_eq!(container.host_path("/workspace/src").unwrap(), Path::new("/srv/project/src"));
+        assert_eq!(container.host_path("/workspace").unwrap(), Path::new("/srv/project"));
+        assert!(container.host_path("/workspace2").is_none());
+
+        let args = container.run_args();
+        assert!(args.windows(2).any(|pair| pair == ["--network", "none"]));
+        assert!(args.windows(2).any(|pair| pair == ["-v", "/srv/project:/workspace:ro"]));
+        assert!(args.windows(2).any(|pair| pair == ["--memory", "512m"]));
+        assert!(args.windows(2).any(|pair| pair == ["--pids-limit", "64"]));
+        assert!(args.ends_with(&["alpine:3".to_string(), "infinity".to_string()]));
+
+        let missing_image = ContainerConfig { enabled: true, ..ContainerConfig::default() };
+        assert!(matches!(missing_image.validate(), Err(FshError::ConfigError(_))));
+    }
+}
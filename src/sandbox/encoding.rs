@@ -0,0 +1,216 @@
+use encoding_rs::{CoderResult, Decoder, Encoding};
+
+use crate::protocol::{FshError, FshResult, ShellType};
+
+/// How a folder's command output is converted to UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// UTF-8, unless the output starts like UTF-16 or, from cmd or
+    /// PowerShell, isn't UTF-8 and is taken to be in the Windows code page.
+    #[default]
+    Auto,
+    Fixed(&'static Encoding),
+}
+
+impl OutputEncoding {
+    /// `auto`, a code page such as `cp936`, or an encoding name such as
+    /// `utf-16le`, `gbk` or `windows-1252`.
+    pub fn parse(label: &str) -> FshResult<Self> {
+        let label = label.trim();
+        if label.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        let code_page = label.get(..2)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("cp"))
+            .and_then(|_| label[2..].parse::<u16>().ok());
+        let encoding = match code_page {
+            Some(code_page) => encoding_for_code_page(code_page),
+            None => Encoding::for_label(label.as_bytes()),
+        };
+        encoding.map(Self::Fixed)
+            .ok_or_else(|| FshError::ConfigError(format!("Unknown output encoding '{}'", label)))
+    }
+}
+
+/// The encodings Windows code pages map to, for those that have one.
+fn encoding_for_code_page(code_page: u16) -> Option<&'static Encoding> {
+    Some(match code_page {
+        866 => encoding_rs::IBM866,
+        874 => encoding_rs::WINDOWS_874,
+        932 => encoding_rs::SHIFT_JIS,
+        936 => encoding_rs::GBK,
+        949 => encoding_rs::EUC_KR,
+        950 => encoding_rs::BIG5,
+        1200 => encoding_rs::UTF_16LE,
+        1201 => encoding_rs::UTF_16BE,
+        1250 => encoding_rs::WINDOWS_1250,
+        1251 => encoding_rs::WINDOWS_1251,
+        1252 => encoding_rs::WINDOWS_1252,
+        1253 => encoding_rs::WINDOWS_1253,
+        1254 => encoding_rs::WINDOWS_1254,
+        1255 => encoding_rs::WINDOWS_1255,
+        1256 => encoding_rs::WINDOWS_1256,
+        1257 => encoding_rs::WINDOWS_1257,
+        1258 => encoding_rs::WINDOWS_1258,
+        20866 => encoding_rs::KOI8_R,
+        54936 => encoding_rs::GB18030,
+        65001 => encoding_rs::UTF_8,
+        _ => return None,
+    })
+}
+
+/// The host's ANSI code page, which Windows programs fall back to when
+/// writing to a pipe.
+#[cfg(windows)]
+fn windows_code_page() -> Option<&'static Encoding> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetACP() -> u32;
+    }
+    let code_page = unsafe { GetACP() };
+    u16::try_from(code_page).ok().and_then(encoding_for_code_page)
+}
+
+#[cfg(not(windows))]
+fn windows_code_page() -> Option<&'static Encoding> {
+    None
+}
+
+/// Converts one of a command's output streams to UTF-8 as it is read.
+#[derive(Debug)]
+pub struct OutputDecoder {
+    state: DecoderState,
+    /// The command runs in cmd or PowerShell.
+    windows_shell: bool,
+}
+
+#[derive(Debug)]
+enum DecoderState {
+    /// Nothing but ASCII so far, which reads the same in every encoding.
+    Undecided { first: bool },
+    PassThrough,
+    Decoding(Decoder),
+}
+
+impl OutputDecoder {
+    pub fn new(encoding: OutputEncoding, shell_type: &ShellType) -> Self {
+        let state = match encoding {
+            OutputEncoding::Fixed(encoding) if encoding == encoding_rs::UTF_8 => DecoderState::PassThrough,
+            OutputEncoding::Fixed(encoding) => DecoderState::Decoding(encoding.new_decoder_with_bom_removal()),
+            OutputEncoding::Auto => DecoderState::Undecided { first: true },
+        };
+        let windows_shell = matches!(shell_type, ShellType::Cmd | ShellType::PowerShell | ShellType::PowerShellCore);
+        Self { state, windows_shell }
+    }
+
+    /// `data` as UTF-8. A character split between reads comes out with the
+    /// rest of it.
+    pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        self.decode_part(data, false)
+    }
+
+    /// What is left of a character cut off when the stream ended.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.decode_part(&[], true)
+    }
+
+    fn decode_part(&mut self, data: &[u8], last: bool) -> Vec<u8> {
+        if let DecoderState::Undecided { first } = self.state {
+            if let Some(state) = self.detect(data, first) {
+                self.state = state;
+            } else if !data.is_empty() {
+                self.state = DecoderState::Undecided { first: false };
+            }
+        }
+
+        let DecoderState::Decoding(decoder) = &mut self.state else {
+            return data.to_vec();
+        };
+        let mut decoded = String::with_capacity(decoder.max_utf8_buffer_length(data.len()).unwrap_or(data.len() * 3));
+        let (result, _, _) = decoder.decode_to_string(data, &mut decoded, last);
+        debug_assert!(matches!(result, CoderResult::InputEmpty));
+        decoded.into_bytes()
+    }
+
+    /// How to read a stream that has been ASCII so far, given its next
+    /// chunk; `None` while that is still undecided.
+    fn detect(&self, data: &[u8], first: bool) -> Option<DecoderState> {
+        if first && (data.starts_with(&[0xFF, 0xFE]) || looks_like_utf16le(data)) {
+            return Some(DecoderState::Decoding(encoding_rs::UTF_16LE.new_decoder_with_bom_removal()));
+        }
+        if first && data.starts_with(&[0xFE, 0xFF]) {
+            return Some(DecoderState::Decoding(encoding_rs::UTF_16BE.new_decoder_with_bom_removal()));
+        }
+        if data.is_ascii() {
+            return None;
+        }
+
+        let utf8 = match std::str::from_utf8(data) {
+            Ok(_) => true,
+            // Cut off at the end only
+            Err(e) => e.error_len().is_none(),
+        };
+        match windows_code_page() {
+            Some(encoding) if self.windows_shell && !utf8 => Some(DecoderState::Decoding(encoding.new_decoder())),
+            _ => Some(DecoderState::PassThrough),
+        }
+    }
+}
+
+/// ASCII text in UTF-16LE: nearly every other byte is zero.
+fn looks_like_utf16le(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(256) & !1];
+    if sample.len() < 4 {
+        return false;
+    }
+    let pairs = sample.len() / 2;
+    let high_zero = sample.iter().skip(1).step_by(2).filter(|byte| **byte == 0).count();
+    let low_zero = sample.iter().step_by(2).filter(|byte| **byte == 0).count();
+    high_zero * 4 >= pairs * 3 && low_zero * 4 <= pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn test_parse_output_encoding() {
+        assert_eq!(OutputEncoding::parse("auto").unwrap(), OutputEncoding::Auto);
+        assert_eq!(OutputEncoding::parse("cp936").unwrap(), OutputEncoding::Fixed(encoding_rs::GBK));
+        assert_eq!(OutputEncoding::parse("CP1252").unwrap(), OutputEncoding::Fixed(encoding_rs::WINDOWS_1252));
+        assert_eq!(OutputEncoding::parse("utf-16le").unwrap(), OutputEncoding::Fixed(encoding_rs::UTF_16LE));
+        assert!(OutputEncoding::parse("cp437").is_err());
+        assert!(OutputEncoding::parse("klingon").is_err());
+    }
+
+    #[test]
+    fn test_utf16_is_detected() {
+        let mut decoder = OutputDecoder::new(OutputEncoding::Auto, &ShellType::PowerShell);
+        let data = utf16le("Verzeichnis: C:\\Größe\r\n");
+
+        // A code unit split between reads is kept for the next one
+        let mut decoded = decoder.decode(&data[..9]);
+        decoded.extend(decoder.decode(&data[9..]));
+        decoded.extend(decoder.finish());
+        assert_eq!(String::from_utf8(decoded).unwrap(), "Verzeichnis: C:\\Größe\r\n");
+
+        // UTF-8 is left alone
+        let mut decoder = OutputDecoder::new(OutputEncoding::Auto, &ShellType::Bash);
+        assert_eq!(decoder.decode("ascii\n".as_bytes()), b"ascii\n");
+        assert_eq!(decoder.decode("größe\n".as_bytes()), "größe\n".as_bytes());
+    }
+
+    #[test]
+    fn test_fixed_code_page() {
+        let mut decoder = OutputDecoder::new(OutputEncoding::parse("cp936").unwrap(), &ShellType::Cmd);
+        // "中文" in GBK
+        assert_eq!(String::from_utf8(decoder.decode(&[0xD6, 0xD0, 0xCE, 0xC4, b'\n'])).unwrap(), "中文\n");
+
+        let mut decoder = OutputDecoder::new(OutputEncoding::parse("windows-1252").unwrap(), &ShellType::Cmd);
+        assert_eq!(String::from_utf8(decoder.decode(b"caf\xE9\r\n")).unwrap(), "café\r\n");
+    }
+}
@@ -0,0 +1,272 @@
+use git2::{DiffFormat, DiffOptions, Repository, RepositoryOpenFlags, Sort, Status, StatusOptions};
+use std::path::{Path, PathBuf};
+
+use crate::protocol::message::{GitChange, GitCommit, GitStatus, GitStatusEntry};
+use crate::protocol::{FshError, FshResult};
+
+/// Diffs are cut off after this many bytes.
+pub const MAX_GIT_DIFF_SIZE: usize = 4 * 1024 * 1024;
+
+/// Most commits one log request returns.
+pub const MAX_GIT_LOG_COUNT: usize = 1000;
+
+/// The git repository containing a path inside a folder. Repositories above
+/// the folder root, or whose git directory is outside it, are not opened.
+pub struct FolderRepository {
+    repo: Repository,
+    /// The working tree, relative to the folder root.
+    workdir: PathBuf,
+    /// The path asked about, relative to the working tree; `None` for all of it.
+    pathspec: Option<String>,
+}
+
+impl FolderRepository {
+    /// Open the repository containing `path`; both paths must be canonical.
+    pub fn open(root: &Path, path: &Path) -> FshResult<Self> {
+        let not_found = || FshError::ShellError(format!("Not a git repository: {}", display(root, path)));
+        let repo = Repository::open_ext(path, RepositoryOpenFlags::empty(), root.parent())
+            .map_err(|_| not_found())?;
+
+        let workdir = repo.workdir()
+            .ok_or_else(|| FshError::ShellError("Bare repositories are not supported".to_string()))?
+            .canonicalize()?;
+        // A .git file can point anywhere, e.g. at a worktree's main repository
+        for git_dir in [repo.path(), repo.commondir()] {
+            if !git_dir.canonicalize()?.starts_with(root) {
+                return Err(FshError::PermissionDenied(
+                    "The repository's git directory is outside the folder".to_string(),
+                ));
+            }
+        }
+        let relative_workdir = workdir.strip_prefix(root).map_err(|_| not_found())?.to_path_buf();
+
+        let pathspec = path.strip_prefix(&workdir).map_err(|_| not_found())?;
+        let pathspec = (!pathspec.as_os_str().is_empty())
+            .then(|| pathspec.to_string_lossy().replace('\\', "/"));
+
+        Ok(Self { repo, workdir: relative_workdir, pathspec })
+    }
+
+    /// Where a path relative to the working tree is in the folder.
+    pub fn folder_path(&self, path: &Path) -> PathBuf {
+        self.workdir.join(path)
+    }
+
+    pub fn status(&self) -> FshResult<GitStatus> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .renames_head_to_index(true);
+        if let Some(pathspec) = &self.pathspec {
+            options.pathspec(pathspec);
+        }
+
+        let statuses = self.repo.statuses(Some(&mut options)).map_err(git_error)?;
+        let entries = statuses.iter()
+            .filter_map(|entry| {
+                let status = entry.status();
+                Some(GitStatusEntry {
+                    path: entry.path()?.to_string(),
+                    index: index_change(status),
+                    worktree: worktree_change(status),
+                })
+            })
+            .collect();
+
+        let head = self.repo.head().ok();
+        Ok(GitStatus {
+            repository: display(Path::new(""), &self.workdir),
+            branch: head.as_ref().filter(|head| head.is_branch()).and_then(|head| head.shorthand()).map(str::to_string),
+            head: head.and_then(|head| head.target()).map(|id| id.to_string()),
+            entries,
+        })
+    }
+
+    /// The unified diff, without files `readable` refuses, and whether it
+    /// was cut off at `MAX_GIT_DIFF_SIZE`.
+    pub fn diff(&self, staged: bool, context_lines: u32, readable: impl Fn(&Path) -> bool) -> FshResult<(String, bool)> {
+        let mut options = DiffOptions::new();
+        options.context_lines(context_lines);
+        if let Some(pathspec) = &self.pathspec {
+            options.pathspec(pathspec);
+        }
+
+        let diff = if staged {
+            let head = self.repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+            self.repo.diff_tree_to_index(head.as_ref(), None, Some(&mut options))
+        } else {
+            self.repo.diff_index_to_workdir(None, Some(&mut options))
+        }.map_err(git_error)?;
+
+        let mut patch = Vec::new();
+        let mut truncated = false;
+        let printed = diff.print(DiffFormat::Patch, |delta, _, line| {
+            let shown = [delta.old_file().path(), delta.new_file().path()].into_iter()
+                .flatten()
+                .all(|path| readable(&self.folder_path(path)));
+            if !shown {
+                return true;
+            }
+            if patch.len() + line.content().len() > MAX_GIT_DIFF_SIZE {
+                truncated = true;
+                return false;
+            }
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                patch.push(line.origin() as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        });
+        if !truncated {
+            printed.map_err(git_error)?;
+        }
+
+        Ok((String::from_utf8_lossy(&patch).into_owned(), truncated))
+    }
+
+    /// Up to `max_count` commits reachable from HEAD, newest first.
+    pub fn log(&self, max_count: usize) -> FshResult<Vec<GitCommit>> {
+        let mut revwalk = self.repo.revwalk().map_err(git_error)?;
+        if revwalk.push_head().is_err() {
+            // No commits yet
+            return Ok(Vec::new());
+        }
+        revwalk.set_sorting(Sort::TIME).map_err(git_error)?;
+
+        let mut commits = Vec::new();
+        for id in revwalk {
+            if commits.len() >= max_count {
+                break;
+            }
+            let commit = self.repo.find_commit(id.map_err(git_error)?).map_err(git_error)?;
+            if self.pathspec.is_some() && !self.touches_pathspec(&commit)? {
+                continue;
+            }
+            let author = commit.author();
+            commits.push(GitCommit {
+                id: commit.id().to_string(),
+                author: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+                email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+                time: commit.time().seconds(),
+                summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).into_owned(),
+            });
+        }
+        Ok(commits)
+    }
+
+    /// Whether `commit` changed anything under the pathspec, compared to its
+    /// first parent.
+    fn touches_pathspec(&self, commit: &git2::Commit<'_>) -> FshResult<bool> {
+        let mut options = DiffOptions::new();
+        if let Some(pathspec) = &self.pathspec {
+            options.pathspec(pathspec);
+        }
+        let parent = commit.parent(0).ok().map(|parent| parent.tree()).transpose().map_err(git_error)?;
+        let tree = commit.tree().map_err(git_error)?;
+        let diff = self.repo.diff_tree_to_tree(parent.as_ref(), Some(&tree), Some(&mut options))
+            .map_err(git_error)?;
+        Ok(diff.deltas().len() > 0)
+    }
+}
+
+fn index_change(status: Status) -> Option<GitChange> {
+    if status.contains(Status::INDEX_NEW) {
+        Some(GitChange::Added)
+    } else if status.contains(Status::INDEX_MODIFIED) {
+        Some(GitChange::Modified)
+    } else if status.contains(Status::INDEX_DELETED) {
+        Some(GitChange::Deleted)
+    } else if status.contains(Status::INDEX_RENAMED) {
+        Some(GitChange::Renamed)
+    } else if status.contains(Status::INDEX_TYPECHANGE) {
+        Some(GitChange::TypeChanged)
+    } else {
+        None
+    }
+}
+
+fn worktree_change(status: Status) -> Option<GitChange> {
+    if status.contains(Status::CONFLICTED) {
+        Some(GitChange::Conflicted)
+    } else if status.contains(Status::WT_NEW) {
+        Some(GitChange::Untracked)
+    } else if status.contains(Status::WT_MODIFIED) {
+        Some(GitChange::Modified)
+    } else if status.contains(Status::WT_DELETED) {
+        Some(GitChange::Deleted)
+    } else if status.contains(Status::WT_RENAMED) {
+        Some(GitChange::Renamed)
+    } else if status.contains(Status::WT_TYPECHANGE) {
+        Some(GitChange::TypeChanged)
+    } else {
+        None
+    }
+}
+
+/// `path` relative to `root` as the client sees it, `.` for the root itself.
+fn display(root: &Path, path: &Path) -> String {
+    match path.strip_prefix(root).map(|relative| relative.to_string_lossy().replace('\\', "/")) {
+        Ok(relative) if !relative.is_empty() => relative,
+        _ => ".".to_string(),
+    }
+}
+
+fn git_error(e: git2::Error) -> FshError {
+    FshError::ShellError(format!("git: {}", e.message()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn commit_all(repo: &Repository, message: &str) {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], git2::IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Dev", "dev@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, parent.as_slice().iter().collect::<Vec<_>>().as_slice())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_status_diff_and_log() {
+        let folder = TempDir::new().unwrap();
+        let root = folder.path().canonicalize().unwrap();
+        let project = root.join("project");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        let repo = Repository::init(&project).unwrap();
+        std::fs::write(project.join("src/lib.rs"), "fn one() {}\n").unwrap();
+        std::fs::write(project.join("README"), "readme\n").unwrap();
+        commit_all(&repo, "Initial commit");
+        std::fs::write(project.join("README"), "readme\nmore\n").unwrap();
+        commit_all(&repo, "Expand readme");
+
+        std::fs::write(project.join("src/lib.rs"), "fn two() {}\n").unwrap();
+        std::fs::write(project.join("notes.txt"), "new\n").unwrap();
+
+        let repository = FolderRepository::open(&root, &project).unwrap();
+        let status = repository.status().unwrap();
+        assert_eq!(status.repository, "project");
+        assert!(status.head.is_some());
+        assert_eq!(status.entries, vec![
+            GitStatusEntry { path: "notes.txt".to_string(), index: None, worktree: Some(GitChange::Untracked) },
+            GitStatusEntry { path: "src/lib.rs".to_string(), index: None, worktree: Some(GitChange::Modified) },
+        ]);
+
+        let (diff, truncated) = repository.diff(false, 3, |_| true).unwrap();
+        assert!(!truncated);
+        assert!(diff.contains("-fn one() {}\n+fn two() {}\n"));
+        let (hidden, _) = repository.diff(false, 3, |path| !path.starts_with("project/src")).unwrap();
+        assert!(hidden.is_empty());
+
+        assert_eq!(repository.log(10).unwrap().len(), 2);
+        let src_log = FolderRepository::open(&root, &project.join("src")).unwrap().log(10).unwrap();
+        assert_eq!(src_log.iter().map(|commit| commit.summary.as_str()).collect::<Vec<_>>(), ["Initial commit"]);
+
+        // Nothing above the folder root is searched
+        assert!(FolderRepository::open(&root, &root).is_err());
+    }
+}
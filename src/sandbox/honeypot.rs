@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Deception mode for a folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HoneypotConfig {
+    /// Accept every bind, run each session against a throwaway copy of the
+    /// folder, and alert on everything the client does.
+    pub enabled: bool,
+    /// URL that receives a JSON POST for every action in the honeypot.
+    pub alert_webhook_url: Option<String>,
+}
+
+/// A session's private copy of a honeypot folder, removed when dropped.
+#[derive(Debug)]
+pub struct HoneypotSnapshot {
+    path: PathBuf,
+}
+
+impl HoneypotSnapshot {
+    /// Copy `template` into a new directory. A missing template gives an
+    /// empty snapshot, so the bind still succeeds. Symlinks are not copied.
+    pub fn create(template: &Path, session_id: &str) -> FshResult<Self> {
+        let path = std::env::temp_dir().join(format!("fsh-honeypot-{}", session_id));
+        std::fs::create_dir_all(&path)?;
+        let snapshot = Self { path };
+
+        if template.is_dir() {
+            for entry in WalkDir::new(template).min_depth(1).follow_links(false) {
+                let entry = entry.map_err(|e| FshError::ShellError(format!("Cannot copy honeypot template: {}", e)))?;
+                let relative = entry.path().strip_prefix(template)
+                    .map_err(|_| FshError::InvalidPath(entry.path().display().to_string()))?;
+                let target = snapshot.path.join(relative);
+
+                if entry.file_type().is_dir() {
+                    std::fs::create_dir_all(&target)?;
+                } else if entry.file_type().is_file() {
+                    std::fs::copy(entry.path(), &target)?;
+                }
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for HoneypotSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove honeypot snapshot {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_snapshot_is_a_throwaway_copy() {
+        let template = TempDir::new().unwrap();
+        std::fs::create_dir(template.path().join("config")).unwrap();
+        std::fs::write(template.path().join("config/credentials"), "aws_key=bait").unwrap();
+
+        let snapshot = HoneypotSnapshot::create(template.path(), "test-session").unwrap();
+        let copy = snapshot.path().join("config/credentials");
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "aws_key=bait");
+
+        std::fs::write(&copy, "overwritten").unwrap();
+        assert_eq!(std::fs::read_to_string(template.path().join("config/credentials")).unwrap(), "aws_key=bait");
+
+        let path = snapshot.path().to_path_buf();
+        drop(snapshot);
+        assert!(!path.exists());
+
+        let empty = HoneypotSnapshot::create(&template.path().join("missing"), "test-session-2").unwrap();
+        assert!(empty.path().is_dir());
+    }
+}
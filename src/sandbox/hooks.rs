@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::protocol::{FshError, FshResult};
+use super::{CommandResult, SandboxedShell};
+
+/// Commands run around each client command in a folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    /// Runs before each command; a non-zero exit refuses the command.
+    pub pre_command: Option<String>,
+    /// Runs after each command, with its exit code in `FSH_EXIT_CODE`.
+    pub post_command: Option<String>,
+    /// Hooks still running after this many seconds are killed.
+    pub timeout_seconds: u64,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            pre_command: None,
+            post_command: None,
+            timeout_seconds: 30,
+        }
+    }
+}
+
+/// Which side of a command a hook runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreCommand,
+    PostCommand { exit_code: i32 },
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::PreCommand => "pre_command",
+            HookEvent::PostCommand { .. } => "post_command",
+        }
+    }
+}
+
+/// A folder's hooks, bound to one session.
+#[derive(Debug, Clone)]
+pub struct CommandHooks {
+    config: HookConfig,
+    environment: Vec<(String, String)>,
+}
+
+impl CommandHooks {
+    pub fn new(config: HookConfig, session_id: &str, folder: &str, user: &str) -> Self {
+        Self {
+            config,
+            environment: vec![
+                ("FSH_SESSION_ID".to_string(), session_id.to_string()),
+                ("FSH_FOLDER".to_string(), folder.to_string()),
+                ("FSH_USER".to_string(), user.to_string()),
+            ],
+        }
+    }
+
+    /// The same session's hooks after it moves to `folder`.
+    pub fn for_folder(&self, config: HookConfig, folder: &str) -> Self {
+        let mut environment = self.environment.clone();
+        for (name, value) in &mut environment {
+            if name == "FSH_FOLDER" {
+                *value = folder.to_string();
+            }
+        }
+        Self { config, environment }
+    }
+
+    fn hook(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::PreCommand => self.config.pre_command.as_deref(),
+            HookEvent::PostCommand { .. } => self.config.post_command.as_deref(),
+        }
+    }
+
+    /// Environment a hook sees on top of the folder's command environment.
+    fn hook_environment(&self, event: HookEvent, command_line: &str) -> Vec<(String, String)> {
+        let mut env = self.environment.clone();
+        env.push(("FSH_HOOK".to_string(), event.name().to_string()));
+        env.push(("FSH_COMMAND".to_string(), command_line.to_string()));
+        if let HookEvent::PostCommand { exit_code } = event {
+            env.push(("FSH_EXIT_CODE".to_string(), exit_code.to_string()));
+        }
+        env
+    }
+
+    /// Run the pre-command hook. The command may only run if this succeeds;
+    /// a hook that fails or cannot be run refuses it.
+    pub async fn pre_command(&self, shell: &Mutex<SandboxedShell>, command_line: &str) -> FshResult<()> {
+        let Some(result) = self.run(shell, HookEvent::PreCommand, command_line).await else {
+            return Ok(());
+        };
+
+        let result = result.map_err(|e| FshError::PermissionDenied(format!("Pre-command hook failed: {}", e)))?;
+        if result.exit_code == 0 {
+            return Ok(());
+        }
+
+        let output = format!("{}{}", String::from_utf8_lossy(&result.stdout), result.stderr);
+        let output = shell.lock().await.sanitize_output(output.trim_end());
+        let mut message = format!("Command refused by pre-command hook (exit code {})", result.exit_code);
+        if !output.is_empty() {
+            message.push_str(":\n");
+            message.push_str(&output);
+        }
+        Err(FshError::PermissionDenied(message))
+    }
+
+    /// Run the post-command hook. Failures are logged; the command's own
+    /// result stands.
+    pub async fn post_command(&self, shell: &Mutex<SandboxedShell>, command_line: &str, exit_code: i32) {
+        match self.run(shell, HookEvent::PostCommand { exit_code }, command_line).await {
+            Some(Ok(result)) if result.exit_code != 0 => {
+                warn!("Post-command hook exited with {} after '{}': {}",
+                      result.exit_code, command_line, result.stderr.trim_end());
+            }
+            Some(Err(e)) => warn!("Post-command hook failed after '{}': {}", command_line, e),
+            _ => {}
+        }
+    }
+
+    async fn run(
+        &self,
+        shell: &Mutex<SandboxedShell>,
+        event: HookEvent,
+        command_line: &str,
+    ) -> Option<FshResult<CommandResult>> {
+        let hook = self.hook(event)?;
+        debug!("Running {} hook for '{}'", event.name(), command_line);
+
+        let env = self.hook_environment(event, command_line);
+        let command = shell.lock().await.hook_command(hook, env);
+        let timeout = Duration::from_secs(self.config.timeout_seconds);
+        Some(match command {
+            Ok(command) => run_hook_command(command, timeout).await,
+            Err(e) => Err(e),
+        })
+    }
+}
+
+async fn run_hook_command(mut command: tokio::process::Command, timeout: Duration) -> FshResult<CommandResult> {
+    let start_time = Instant::now();
+    let child = command.spawn()
+        .map_err(|e| FshError::ShellError(format!("Failed to spawn hook: {}", e)))?;
+
+    // Dropping the child on timeout kills it
+    let output = tokio::time::timeout(timeout, child.wait_with_output()).await
+        .map_err(|_| FshError::ShellError(format!("Hook timed out after {}s", timeout.as_secs())))?
+        .map_err(|e| FshError::ShellError(format!("Hook execution failed: {}", e)))?;
+
+    Ok(CommandResult {
+        exit_code: output.status.code().unwrap_or(-1),
+        stdout: output.stdout,
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        execution_time_ms: start_time.elapsed().as_millis() as u64,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::protocol::ShellType;
+    use crate::sandbox::SandboxConfig;
+    use tempfile::TempDir;
+
+    fn hooks(config: HookConfig) -> CommandHooks {
+        CommandHooks::new(config, "session-1", "project", "127.0.0.1")
+    }
+
+    fn shell(dir: &TempDir) -> Mutex<SandboxedShell> {
+        let config = SandboxConfig::new(dir.path().to_path_buf(), ShellType::Sh);
+        Mutex::new(SandboxedShell::new(config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_pre_command_hook_can_refuse() {
+        let temp_dir = TempDir::new().unwrap();
+        let shell = shell(&temp_dir);
+
+        assert!(hooks(HookConfig::default()).pre_command(&shell, "git push").await.is_ok());
+
+        let checking = hooks(HookConfig {
+            pre_command: Some("test \"$FSH_COMMAND\" != 'git push' || { echo \"no push for $FSH_USER\"; exit 3; }".to_string()),
+            ..HookConfig::default()
+        });
+        assert!(checking.pre_command(&shell, "git status").await.is_ok());
+
+        let error = checking.pre_command(&shell, "git push").await.unwrap_err();
+        assert!(error.to_string().contains("exit code 3"));
+        assert!(error.to_string().contains("no push for 127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_post_command_hook_sees_exit_code() {
+        let temp_dir = TempDir::new().unwrap();
+        let shell = shell(&temp_dir);
+
+        let recording = hooks(HookConfig {
+            post_command: Some("echo \"$FSH_HOOK $FSH_EXIT_CODE $FSH_FOLDER\" > hook.log".to_string()),
+            ..HookConfig::default()
+        });
+        recording.post_command(&shell, "make", 2).await;
+
+        let log = std::fs::read_to_string(temp_dir.path().join("hook.log")).unwrap();
+        assert_eq!(log, "post_command 2 project\n");
+    }
+
+    #[tokio::test]
+    async fn test_slow_hook_times_out() {
+        let temp_dir = TempDir::new().unwrap();
+        let shell = shell(&temp_dir);
+
+        let slow = hooks(HookConfig {
+            pre_command: Some("sleep 5".to_string()),
+            timeout_seconds: 0,
+            ..HookConfig::default()
+        });
+        let error = slow.pre_command(&shell, "ls").await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::protocol::{FshError, FshResult};
+
+/// Command that invokes a folder macro: `run <name> [args...]`.
+pub const RUN_MACRO_COMMAND: &str = "run";
+
+/// Separator between the steps of a macro; each step must succeed before the next runs.
+const STEP_SEPARATOR: &str = "&&";
+
+/// Characters that would let an argument smuggle extra shell syntax into a step.
+const FORBIDDEN_ARGUMENT_CHARS: &[char] = &[';', '&', '|', '<', '>', '$', '`', '\n', '\r'];
+
+/// One expanded step of a macro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroStep {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl MacroStep {
+    /// The step as a single command line, as recorded in the audit log.
+    pub fn command_line(&self) -> String {
+        std::iter::once(self.command.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Expand a macro body into its steps, substituting `$1`..`$9` with the
+/// positional arguments and `$@` with all of them. `$$` is a literal `$`.
+pub fn expand_macro(body: &str, args: &[String]) -> FshResult<Vec<MacroStep>> {
+    if let Some(arg) = args.iter().find(|arg| arg.contains(FORBIDDEN_ARGUMENT_CHARS)) {
+        return Err(FshError::PermissionDenied(format!(
+            "Macro argument '{}' contains shell operators", arg
+        )));
+    }
+
+    body.split(STEP_SEPARATOR)
+        .map(|step| {
+            let expanded = substitute(step, args)?;
+            let mut words = expanded.split_whitespace().map(str::to_string);
+            let command = words.next()
+                .ok_or_else(|| FshError::ConfigError("Macro contains an empty step".to_string()))?;
+            Ok(MacroStep { command, args: words.collect() })
+        })
+        .collect()
+}
+
+fn substitute(step: &str, args: &[String]) -> FshResult<String> {
+    let mut result = String::with_capacity(step.len());
+    let mut chars = step.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.peek().copied() {
+            Some('$') => {
+                chars.next();
+                result.push('$');
+            }
+            Some('@') => {
+                chars.next();
+                result.push_str(&args.join(" "));
+            }
+            Some(digit @ '1'..='9') => {
+                chars.next();
+                let index = digit as usize - '1' as usize;
+                let arg = args.get(index)
+                    .ok_or_else(|| FshError::ShellError(format!("Macro argument ${} is missing", digit)))?;
+                result.push_str(arg);
+            }
+            // Anything else (e.g. `$HOME`) is left for the shell
+            _ => result.push('$'),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand_steps_and_arguments() {
+        let steps = expand_macro("cargo build --release && scp target/app $1:/srv/$2", &args(&["prod", "app"])).unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0], MacroStep { command: "cargo".to_string(), args: args(&["build", "--release"]) });
+        assert_eq!(steps[1].command_line(), "scp target/app prod:/srv/app");
+
+        let steps = expand_macro("echo $@ $$HOME $HOME", &args(&["a", "b"])).unwrap();
+        assert_eq!(steps[0].command_line(), "echo a b $HOME $HOME");
+    }
+
+    #[test]
+    fn test_expand_rejects_bad_input() {
+        assert!(expand_macro("deploy $2", &args(&["only-one"])).is_err());
+        assert!(expand_macro("echo $1", &args(&["x; rm -rf ."])).is_err());
+        assert!(expand_macro("echo ok && ", &[]).is_err());
+    }
+}
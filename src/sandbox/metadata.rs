@@ -0,0 +1,171 @@
+use std::fs::Metadata;
+
+/// `ls -l` style mode string, e.g. `drwxr-xr-x`.
+#[cfg(unix)]
+pub fn permission_string(metadata: &Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_dir() {
+        'd'
+    } else {
+        '-'
+    };
+
+    // (read, write, execute, special bit, special char when executable, when not)
+    let triads = [
+        (0o400, 0o200, 0o100, 0o4000, 's', 'S'),
+        (0o040, 0o020, 0o010, 0o2000, 's', 'S'),
+        (0o004, 0o002, 0o001, 0o1000, 't', 'T'),
+    ];
+
+    let mut result = String::with_capacity(10);
+    result.push(kind);
+    for (read, write, execute, special, special_exec, special_no_exec) in triads {
+        result.push(if mode & read != 0 { 'r' } else { '-' });
+        result.push(if mode & write != 0 { 'w' } else { '-' });
+        result.push(match (mode & execute != 0, mode & special != 0) {
+            (true, true) => special_exec,
+            (false, true) => special_no_exec,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    result
+}
+
+/// Mode string approximating the Unix form from the read-only attribute.
+#[cfg(not(unix))]
+pub fn permission_string(metadata: &Metadata) -> String {
+    let kind = if metadata.is_dir() { 'd' } else { '-' };
+    if metadata.permissions().readonly() {
+        format!("{}r--r--r--", kind)
+    } else {
+        format!("{}rw-rw-rw-", kind)
+    }
+}
+
+/// Owner and group names of a file, falling back to the numeric ids.
+#[cfg(unix)]
+pub fn owner_and_group(metadata: &Metadata) -> (Option<String>, Option<String>) {
+    use std::os::unix::fs::MetadataExt;
+
+    let owner = user_name(metadata.uid()).unwrap_or_else(|| metadata.uid().to_string());
+    let group = group_name(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string());
+    (Some(owner), Some(group))
+}
+
+#[cfg(not(unix))]
+pub fn owner_and_group(_metadata: &Metadata) -> (Option<String>, Option<String>) {
+    (None, None)
+}
+
+/// Windows file attributes such as `readonly` and `hidden`; empty elsewhere.
+#[cfg(windows)]
+pub fn file_attributes(metadata: &Metadata) -> Vec<String> {
+    use std::os::windows::fs::MetadataExt;
+
+    const ATTRIBUTES: &[(u32, &str)] = &[
+        (0x1, "readonly"),
+        (0x2, "hidden"),
+        (0x4, "system"),
+        (0x20, "archive"),
+        (0x400, "reparse_point"),
+    ];
+
+    let attributes = metadata.file_attributes();
+    ATTRIBUTES.iter()
+        .filter(|(flag, _)| attributes & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+#[cfg(not(windows))]
+pub fn file_attributes(_metadata: &Metadata) -> Vec<String> {
+    Vec::new()
+}
+
+/// Inode, device and hard-link count of a file (Unix only).
+#[cfg(unix)]
+pub fn file_identity(metadata: &Metadata) -> (Option<u64>, Option<u64>, Option<u64>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.ino()), Some(metadata.dev()), Some(metadata.nlink()))
+}
+
+#[cfg(not(unix))]
+pub fn file_identity(_metadata: &Metadata) -> (Option<u64>, Option<u64>, Option<u64>) {
+    (None, None, None)
+}
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: all pointers reference live, correctly sized buffers for the call's duration
+    let status = unsafe {
+        libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success pw_name points to a NUL-terminated string inside `buffer`
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: all pointers reference live, correctly sized buffers for the call's duration
+    let status = unsafe {
+        libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success gr_name points to a NUL-terminated string inside `buffer`
+    let name = unsafe { std::ffi::CStr::from_ptr(group.gr_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permission_string() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("script.sh");
+        std::fs::write(&file, "").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o754)).unwrap();
+
+        let metadata = std::fs::metadata(&file).unwrap();
+        assert_eq!(permission_string(&metadata), "-rwxr-xr--");
+
+        let metadata = std::fs::metadata(temp_dir.path()).unwrap();
+        assert!(permission_string(&metadata).starts_with('d'));
+    }
+
+    #[test]
+    fn test_owner_and_group() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata = std::fs::metadata(temp_dir.path()).unwrap();
+        let (owner, group) = owner_and_group(&metadata);
+        assert_eq!(owner.is_some(), cfg!(unix));
+        assert_eq!(group.is_some(), cfg!(unix));
+    }
+}
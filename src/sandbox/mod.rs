@@ -1,11 +1,63 @@
+pub mod builtins;
+pub mod command_policy;
+pub mod container;
+pub mod encoding;
+pub mod git;
+pub mod honeypot;
+pub mod hooks;
+pub mod macros;
+pub mod metadata;
+pub mod output;
+pub mod processes;
 pub mod shell;
+pub mod tail;
+pub mod trash;
 pub mod validator;
+pub mod wasi;
 
+pub use command_policy::*;
+pub use container::*;
+pub use encoding::*;
+pub use git::*;
+pub use honeypot::*;
+pub use hooks::*;
+pub use macros::*;
+pub use output::*;
+pub use processes::*;
 pub use shell::*;
+pub use tail::*;
+pub use trash::*;
 pub use validator::*;
+pub use wasi::*;
 
-use std::path::PathBuf;
-use crate::protocol::{ShellType, Permission};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use crate::protocol::{FshError, FshResult, ShellType, Permission};
+use crate::security::{PatternSet, Policy, PolicyAction, PolicyDecision};
+
+/// Parent environment variables passed through to commands by default.
+/// A trailing `*` matches any suffix.
+pub const DEFAULT_ENV_ALLOWLIST: &[&str] = &[
+    "PATH", "HOME", "USER", "USERNAME", "LOGNAME", "SHELL", "TERM",
+    "LANG", "LANGUAGE", "LC_*", "TZ",
+    "TMPDIR", "TEMP", "TMP",
+    "HTTP_PROXY", "HTTPS_PROXY", "NO_PROXY", "ALL_PROXY",
+    "http_proxy", "https_proxy", "no_proxy", "all_proxy",
+    // Needed for processes to start correctly on Windows
+    "SYSTEMROOT", "COMSPEC", "PATHEXT", "WINDIR", "USERPROFILE", "APPDATA", "LOCALAPPDATA",
+];
+
+/// Variables clients may set for their session unless the folder lists its own.
+pub const DEFAULT_SETTABLE_ENV: &[&str] = &[
+    "LANG", "LANGUAGE", "LC_*", "TZ", "TERM", "COLUMNS", "LINES", "EDITOR", "VISUAL", "PAGER",
+];
+
+/// Variables clients can never set, whatever the folder allows: the sandbox's
+/// own, and those that change which program or library a command runs.
+pub const PROTECTED_ENV: &[&str] = &[
+    "FSH_*", "PATH", "LD_*", "DYLD_*", "BASH_ENV", "ENV", "IFS", "PS4", "PROMPT_COMMAND", "SHELLOPTS",
+    "BASHOPTS", "GCONV_PATH", "PATHEXT", "COMSPEC",
+];
 
 #[derive(Debug, Clone)]
 pub struct SandboxConfig {
@@ -13,13 +65,31 @@ pub struct SandboxConfig {
     pub shell_type: ShellType,
     pub permissions: Vec<Permission>,
     pub allowed_commands: Vec<String>,
-    pub blocked_commands: Vec<String>,
-    pub environment_vars: std::collections::HashMap<String, String>,
+    /// Commands containing any of these are refused.
+    pub blocked_commands: PatternSet,
+    pub environment_vars: HashMap<String, String>,
+    pub symlink_policy: SymlinkPolicy,
+    /// Parent environment variables passed through to commands.
+    pub env_allowlist: Vec<String>,
+    /// Pass the server's entire environment through instead of the allowlist.
+    pub inherit_environment: bool,
+    /// Variables clients may set for their session's later commands.
+    pub settable_env: Vec<String>,
+    pub trash: TrashConfig,
+    pub policy: Option<Policy>,
+    /// Allowed subcommands and refused flags, by program name.
+    pub command_policies: BTreeMap<String, CommandPolicy>,
+    /// Run external commands and hooks in this container instead of on the server.
+    pub container: Option<FolderContainer>,
+    /// Commands run as WASI modules instead of through the shell.
+    pub wasi: WasiConfig,
+    /// How command output is converted to UTF-8.
+    pub output_encoding: OutputEncoding,
 }
 
 impl SandboxConfig {
     pub fn new(root_path: PathBuf, shell_type: ShellType) -> Self {
-        let mut environment_vars = std::collections::HashMap::new();
+        let mut environment_vars = HashMap::new();
         environment_vars.insert("FSH_ROOT".to_string(), root_path.to_string_lossy().to_string());
         environment_vars.insert("FSH_MODE".to_string(), "restricted".to_string());
 
@@ -37,16 +107,74 @@ impl SandboxConfig {
                 "python".to_string(), "pip".to_string(), "cargo".to_string(),
                 "rustc".to_string(), "code".to_string(),
             ],
-            blocked_commands: vec![
-                "format".to_string(), "fdisk".to_string(), "dd".to_string(),
-                "mkfs".to_string(), "shutdown".to_string(), "reboot".to_string(),
-                "halt".to_string(), "poweroff".to_string(), "passwd".to_string(),
-                "su".to_string(), "sudo".to_string(), "runas".to_string(),
-            ],
+            blocked_commands: PatternSet::new([
+                "format", "fdisk", "dd", "mkfs", "shutdown", "reboot",
+                "halt", "poweroff", "passwd", "su", "sudo", "runas",
+            ]).expect("the default blocked commands compile"),
             environment_vars,
+            symlink_policy: SymlinkPolicy::default(),
+            env_allowlist: DEFAULT_ENV_ALLOWLIST.iter().map(|name| name.to_string()).collect(),
+            inherit_environment: false,
+            settable_env: DEFAULT_SETTABLE_ENV.iter().map(|name| name.to_string()).collect(),
+            trash: TrashConfig::default(),
+            policy: None,
+            command_policies: BTreeMap::new(),
+            container: None,
+            wasi: WasiConfig::default(),
+            output_encoding: OutputEncoding::default(),
         }
     }
 
+    pub fn with_container(mut self, container: FolderContainer) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    pub fn with_wasi(mut self, wasi: WasiConfig) -> Self {
+        self.wasi = wasi;
+        self
+    }
+
+    pub fn with_output_encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.output_encoding = encoding;
+        self
+    }
+
+    pub fn with_trash(mut self, trash: TrashConfig) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn with_command_policies(mut self, policies: BTreeMap<String, CommandPolicy>) -> Self {
+        self.command_policies = policies;
+        self
+    }
+
+    pub fn with_env_allowlist(mut self, allowlist: Vec<String>) -> Self {
+        self.env_allowlist = allowlist;
+        self
+    }
+
+    pub fn with_inherit_environment(mut self, inherit: bool) -> Self {
+        self.inherit_environment = inherit;
+        self
+    }
+
+    pub fn with_settable_env(mut self, settable: Vec<String>) -> Self {
+        self.settable_env = settable;
+        self
+    }
+
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
     pub fn with_permissions(mut self, permissions: Vec<Permission>) -> Self {
         self.permissions = permissions;
         self
@@ -57,7 +185,7 @@ impl SandboxConfig {
         self
     }
 
-    pub fn with_blocked_commands(mut self, commands: Vec<String>) -> Self {
+    pub fn with_blocked_commands(mut self, commands: PatternSet) -> Self {
         self.blocked_commands = commands;
         self
     }
@@ -67,12 +195,36 @@ impl SandboxConfig {
         self
     }
 
+    /// Build the environment for a child process from the server's environment:
+    /// allowlisted (or, if opted in, all) parent variables, overlaid with the
+    /// sandbox's own variables.
+    pub fn child_environment<I>(&self, parent_env: I) -> HashMap<String, String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut env: HashMap<String, String> = parent_env.into_iter()
+            .filter(|(name, _)| self.inherit_environment || self.is_env_allowed(name))
+            .collect();
+
+        env.extend(self.environment_vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        env
+    }
+
+    fn is_env_allowed(&self, name: &str) -> bool {
+        env_matches(&self.env_allowlist, name)
+    }
+
+    /// Whether clients may set `name` for their session.
+    pub fn is_env_settable(&self, name: &str) -> bool {
+        !env_matches(PROTECTED_ENV, name) && env_matches(&self.settable_env, name)
+    }
+
     pub fn has_permission(&self, permission: &Permission) -> bool {
         self.permissions.contains(permission)
     }
 
     pub fn is_command_allowed(&self, command: &str) -> bool {
-        if self.blocked_commands.iter().any(|blocked| command.contains(blocked)) {
+        if self.blocked_commands.is_match(command) {
             return false;
         }
 
@@ -85,6 +237,39 @@ impl SandboxConfig {
         })
     }
 
+    /// Check a command line against the folder's policy, falling back to the
+    /// allowed/blocked lists when no rule decides, then against the program's
+    /// argument policy.
+    pub fn check_command(&self, command: &str, args: &[String]) -> FshResult<()> {
+        self.check_command_allowed(command, args)?;
+        check_command_policies(&self.command_policies, command, args)
+    }
+
+    fn check_command_allowed(&self, command: &str, args: &[String]) -> FshResult<()> {
+        if let Some(policy) = &self.policy {
+            let command_line = std::iter::once(command)
+                .chain(args.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" ");
+            match policy.decide(PolicyAction::Command, &command_line) {
+                PolicyDecision::Allow => return Ok(()),
+                PolicyDecision::Deny(rule) => {
+                    return Err(FshError::PermissionDenied(
+                        format!("Command '{}' denied by policy ({})", command_line, rule)
+                    ));
+                }
+                PolicyDecision::NoMatch => {}
+            }
+        }
+
+        if !self.is_command_allowed(command) {
+            return Err(FshError::PermissionDenied(
+                format!("Command '{}' is not allowed", command)
+            ));
+        }
+        Ok(())
+    }
+
     pub fn is_system_aware_command(&self, command: &str) -> bool {
         // Commands that need access to system environment and paths
         let system_aware_commands = [
@@ -97,4 +282,89 @@ impl SandboxConfig {
 
         system_aware_commands.iter().any(|&cmd| command == cmd || command.starts_with(&format!("{} ", cmd)))
     }
-}
\ No newline at end of file
+}
+/// Prepend `dir` to the `PATH` entry of `env` using the platform's separator.
+pub fn prepend_to_path(env: &mut HashMap<String, String>, dir: &Path) {
+    // Windows spells it `Path`; match whatever casing is present
+    let key = env.keys()
+        .find(|key| key.eq_ignore_ascii_case("PATH"))
+        .cloned()
+        .unwrap_or_else(|| "PATH".to_string());
+
+    let mut paths = vec![dir.to_path_buf()];
+    if let Some(existing) = env.get(&key) {
+        paths.extend(std::env::split_paths(existing));
+    }
+
+    if let Ok(joined) = std::env::join_paths(paths) {
+        env.insert(key, joined.to_string_lossy().to_string());
+    }
+}
+
+/// Whether `name` matches one of `patterns`, where a trailing `*` matches
+/// any suffix. Names are case-insensitive on Windows.
+fn env_matches<P: AsRef<str>>(patterns: &[P], name: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.as_ref();
+        let matches = |a: &str, b: &str| if cfg!(windows) { a.eq_ignore_ascii_case(b) } else { a == b };
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.len() >= prefix.len()
+                && name.is_char_boundary(prefix.len())
+                && matches(&name[..prefix.len()], prefix),
+            None => matches(name, pattern),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent_env() -> Vec<(String, String)> {
+        vec![
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("LC_CTYPE".to_string(), "C.UTF-8".to_string()),
+            ("https_proxy".to_string(), "http://proxy:3128".to_string()),
+            ("AWS_SECRET_ACCESS_KEY".to_string(), "secret".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_child_environment_allowlist() {
+        let config = SandboxConfig::new(PathBuf::from("/work"), ShellType::Bash);
+        let env = config.child_environment(parent_env());
+
+        assert_eq!(env.get("PATH").map(String::as_str), Some("/usr/bin"));
+        assert!(env.contains_key("LC_CTYPE"));
+        assert!(env.contains_key("https_proxy"));
+        assert!(!env.contains_key("AWS_SECRET_ACCESS_KEY"));
+        assert_eq!(env.get("FSH_MODE").map(String::as_str), Some("restricted"));
+
+        let inherited = config.with_inherit_environment(true).child_environment(parent_env());
+        assert!(inherited.contains_key("AWS_SECRET_ACCESS_KEY"));
+    }
+
+    #[test]
+    fn test_settable_env() {
+        let config = SandboxConfig::new(PathBuf::from("/work"), ShellType::Bash);
+        assert!(config.is_env_settable("LC_ALL"));
+        assert!(config.is_env_settable("EDITOR"));
+        assert!(!config.is_env_settable("AWS_PROFILE"));
+
+        // Protected names stay refused even when the folder allows everything
+        let config = config.with_settable_env(vec!["*".to_string()]);
+        assert!(config.is_env_settable("AWS_PROFILE"));
+        for name in ["PATH", "LD_PRELOAD", "FSH_ROOT", "BASH_ENV"] {
+            assert!(!config.is_env_settable(name), "{} is settable", name);
+        }
+    }
+
+    #[test]
+    fn test_prepend_to_path() {
+        let mut env = HashMap::from([("PATH".to_string(), "/usr/bin".to_string())]);
+        prepend_to_path(&mut env, Path::new("/work"));
+
+        let paths: Vec<PathBuf> = std::env::split_paths(&env["PATH"]).collect();
+        assert_eq!(paths, vec![PathBuf::from("/work"), PathBuf::from("/usr/bin")]);
+    }
+}
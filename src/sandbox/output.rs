@@ -0,0 +1,127 @@
+/// Longest line held back waiting for its end; longer ones are passed on in
+/// pieces.
+const MAX_PENDING_LINE: usize = 64 * 1024;
+
+/// A piece of a command's stdout or stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputChunk {
+    /// A line of text ending in `\n`, or part of an overlong one.
+    Text(String),
+    /// Raw bytes of output that isn't text.
+    Binary(Vec<u8>),
+}
+
+/// Splits a stream of command output into lines for as long as it looks like
+/// text. Once it holds a NUL byte or anything that isn't UTF-8, it is binary
+/// from then on, e.g. `tar -cO`, and passed through untouched.
+#[derive(Debug, Default)]
+pub struct OutputSplitter {
+    pending: Vec<u8>,
+    binary: bool,
+}
+
+impl OutputSplitter {
+    /// The chunks `data` completes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<OutputChunk> {
+        if self.binary {
+            return vec![OutputChunk::Binary(data.to_vec())];
+        }
+
+        self.pending.extend_from_slice(data);
+        if looks_binary(&self.pending, false) {
+            self.binary = true;
+            return vec![OutputChunk::Binary(std::mem::take(&mut self.pending))];
+        }
+
+        let mut chunks = Vec::new();
+        while let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let mut line: Vec<u8> = self.pending.drain(..=end).collect();
+            // Like a terminal, end lines with `\n` alone
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            line.push(b'\n');
+            chunks.push(OutputChunk::Text(String::from_utf8_lossy(&line).into_owned()));
+        }
+
+        if self.pending.len() >= MAX_PENDING_LINE {
+            // Keep an incomplete character for the next piece
+            let valid = match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            let piece: Vec<u8> = self.pending.drain(..valid).collect();
+            chunks.push(OutputChunk::Text(String::from_utf8_lossy(&piece).into_owned()));
+        }
+        chunks
+    }
+
+    /// What is left once the stream has ended. A last line without a line
+    /// break gets one.
+    pub fn finish(&mut self) -> Option<OutputChunk> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let mut rest = std::mem::take(&mut self.pending);
+        if self.binary || looks_binary(&rest, true) {
+            self.binary = true;
+            return Some(OutputChunk::Binary(rest));
+        }
+        rest.push(b'\n');
+        Some(OutputChunk::Text(String::from_utf8_lossy(&rest).into_owned()))
+    }
+}
+
+/// Whether all of a command's output `data` is binary rather than text.
+pub fn is_binary(data: &[u8]) -> bool {
+    looks_binary(data, true)
+}
+
+/// Whether `data` can't be text. A character cut off at the end only counts
+/// once the stream has `ended`.
+fn looks_binary(data: &[u8], ended: bool) -> bool {
+    if data.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => false,
+        Err(e) => ended || e.error_len().is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(line: &str) -> OutputChunk {
+        OutputChunk::Text(line.to_string())
+    }
+
+    #[test]
+    fn test_text_is_split_into_lines() {
+        let mut splitter = OutputSplitter::default();
+        assert_eq!(splitter.push(b"one\r\ntw"), vec![text("one\n")]);
+
+        // A character split between reads is still text
+        let snowman = "☃".as_bytes();
+        assert_eq!(splitter.push(&snowman[..1]), Vec::new());
+        assert_eq!(splitter.push(&[&snowman[1..], b"o\nthree"].concat()), vec![text("tw☃o\n")]);
+        assert_eq!(splitter.finish(), Some(text("three\n")));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn test_binary_is_passed_through() {
+        let mut splitter = OutputSplitter::default();
+        assert_eq!(splitter.push(b"header\n"), vec![text("header\n")]);
+        assert_eq!(splitter.push(b"ustar\0\xff\n"), vec![OutputChunk::Binary(b"ustar\0\xff\n".to_vec())]);
+
+        // Text after binary is still passed through as it is
+        assert_eq!(splitter.push(b"more\r\n"), vec![OutputChunk::Binary(b"more\r\n".to_vec())]);
+
+        let mut splitter = OutputSplitter::default();
+        splitter.push(b"cut off \xe2\x98");
+        assert_eq!(splitter.finish(), Some(OutputChunk::Binary(b"cut off \xe2\x98".to_vec())));
+    }
+}
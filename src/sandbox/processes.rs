@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Ids of the processes a shell has started and that are still running,
+/// shared with whatever samples their resource usage.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessIds(Arc<Mutex<HashSet<u32>>>);
+
+impl ProcessIds {
+    pub fn insert(&self, pid: u32) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).insert(pid);
+    }
+
+    pub fn remove(&self, pid: u32) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid);
+    }
+
+    pub fn snapshot(&self) -> Vec<u32> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).iter().copied().collect()
+    }
+}
+
+/// CPU time and memory of a set of processes and all their descendants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessTreeUsage {
+    /// User and system time the live processes have used so far.
+    pub cpu_time: Duration,
+    /// Resident memory.
+    pub memory_bytes: u64,
+    pub processes: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProcessEntry {
+    parent: u32,
+    cpu_ticks: u64,
+    resident_pages: u64,
+}
+
+/// Every process on the host at one moment, read from `/proc`. Empty on
+/// other platforms, where usage isn't sampled.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessTable {
+    processes: HashMap<u32, ProcessEntry>,
+    ticks_per_second: u64,
+    page_size: u64,
+}
+
+impl ProcessTable {
+    #[cfg(target_os = "linux")]
+    pub fn read() -> Self {
+        let processes = std::fs::read_dir("/proc").into_iter().flatten().flatten()
+            .filter_map(|entry| {
+                let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+                let stat = std::fs::read_to_string(entry.path().join("stat")).ok()?;
+                Some((pid, parse_stat(&stat)?))
+            })
+            .collect();
+        let (ticks_per_second, page_size) = unsafe {
+            (libc::sysconf(libc::_SC_CLK_TCK), libc::sysconf(libc::_SC_PAGESIZE))
+        };
+        Self {
+            processes,
+            ticks_per_second: ticks_per_second.max(1) as u64,
+            page_size: page_size.max(0) as u64,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read() -> Self {
+        Self::default()
+    }
+
+    /// Usage of the `roots` still running and everything they started.
+    pub fn tree_usage(&self, roots: &[u32]) -> ProcessTreeUsage {
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (pid, entry) in &self.processes {
+            children.entry(entry.parent).or_default().push(*pid);
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending: Vec<u32> = roots.iter().copied().filter(|pid| self.processes.contains_key(pid)).collect();
+        let (mut ticks, mut pages) = (0u64, 0u64);
+        while let Some(pid) = pending.pop() {
+            if !seen.insert(pid) {
+                continue;
+            }
+            let entry = &self.processes[&pid];
+            ticks += entry.cpu_ticks;
+            pages += entry.resident_pages;
+            pending.extend(children.get(&pid).into_iter().flatten());
+        }
+
+        ProcessTreeUsage {
+            cpu_time: Duration::from_millis(ticks * 1000 / self.ticks_per_second.max(1)),
+            memory_bytes: pages * self.page_size,
+            processes: seen.len() as u32,
+        }
+    }
+}
+
+/// Parent, CPU ticks and resident pages from a `/proc/<pid>/stat` line. The
+/// command name can hold spaces and parentheses, so fields are counted from
+/// its closing one.
+fn parse_stat(stat: &str) -> Option<ProcessEntry> {
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().collect();
+    let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    Some(ProcessEntry {
+        parent: field(1)? as u32,
+        cpu_ticks: field(11)? + field(12)?,
+        resident_pages: field(21)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (cargo (build)) S 4200 4242 4200 0 -1 4194304 900 0 0 0 150 25 0 0 20 0 3 0 1000 123456 2048 18446744073709551615";
+        let entry = parse_stat(stat).unwrap();
+        assert_eq!(entry.parent, 4200);
+        assert_eq!(entry.cpu_ticks, 175);
+        assert_eq!(entry.resident_pages, 2048);
+        assert!(parse_stat("4242 (truncated").is_none());
+    }
+
+    #[test]
+    fn test_tree_usage() {
+        let entry = |parent, cpu_ticks, resident_pages| ProcessEntry { parent, cpu_ticks, resident_pages };
+        let table = ProcessTable {
+            processes: HashMap::from([
+                (10, entry(1, 100, 10)),
+                (11, entry(10, 50, 5)),
+                (12, entry(11, 50, 5)),
+                (20, entry(1, 1000, 100)),
+            ]),
+            ticks_per_second: 100,
+            page_size: 4096,
+        };
+
+        let usage = table.tree_usage(&[10, 99]);
+        assert_eq!(usage.processes, 3);
+        assert_eq!(usage.cpu_time, Duration::from_secs(2));
+        assert_eq!(usage.memory_bytes, 20 * 4096);
+        assert_eq!(table.tree_usage(&[]), ProcessTreeUsage::default());
+    }
+}
@@ -1,12 +1,24 @@
-use std::path::PathBuf;
+use std::fs::Metadata;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::process::{Child, Command};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn, Instrument};
 use uuid::Uuid;
 
-use crate::protocol::{FshError, FshResult, ShellType};
-use super::{PathValidator, SandboxConfig};
+use crate::protocol::message::{FileEntry, FileStat, GitCommit, GitStatus};
+use crate::protocol::{
+    Checksum, ChecksumAlgorithm, FshError, FshResult, Permission, ShellType, MAX_FILE_CHUNK_SIZE,
+};
+use super::builtins::{self, FILE_BUILTINS};
+use super::validator::normalize_lexically;
+use crate::security::PolicyAction;
+use super::{
+    is_binary, metadata, prepend_to_path, FolderRepository, OutputChunk, OutputDecoder, OutputSplitter, PathValidator, ProcessIds, SandboxConfig,
+    Trash, TrashConfig, TrashEntry, MAX_GIT_LOG_COUNT,
+};
 
 #[derive(Debug)]
 pub struct SandboxedShell {
@@ -15,12 +27,20 @@ pub struct SandboxedShell {
     validator: PathValidator,
     current_process: Option<Child>,
     working_directory: PathBuf,
+    /// File the shell writes its final directory to after each external command.
+    cwd_file: PathBuf,
+    trash: Trash,
+    /// Trash of each bound folder root, and whether that folder uses it.
+    bound_trash: Vec<(Trash, bool)>,
+    /// Commands still running, for sampling their resource usage.
+    processes: ProcessIds,
 }
 
 #[derive(Debug, Clone)]
 pub struct CommandResult {
     pub exit_code: i32,
-    pub stdout: String,
+    /// Raw, as builtins such as `cat` may print a binary file.
+    pub stdout: Vec<u8>,
     pub stderr: String,
     pub execution_time_ms: u64,
 }
@@ -28,7 +48,9 @@ pub struct CommandResult {
 #[derive(Debug, Clone)]
 pub struct ShellOutput {
     pub output_type: OutputType,
-    pub data: String,
+    pub data: Vec<u8>,
+    /// Raw bytes of output that isn't text.
+    pub binary: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -37,10 +59,37 @@ pub enum OutputType {
     Stderr,
 }
 
+/// Stops a running command. Background jobs are killed together with any
+/// processes they started (Unix).
+#[derive(Debug)]
+pub struct CommandKiller(oneshot::Sender<()>);
+
+impl CommandKiller {
+    pub fn kill(self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// A command started with [`SandboxedShell::start_job`].
+#[derive(Debug)]
+pub struct RunningJob {
+    pub output: mpsc::Receiver<ShellOutput>,
+    pub result: mpsc::Receiver<CommandResult>,
+    /// `None` for builtins, which have already finished.
+    pub killer: Option<CommandKiller>,
+}
+
 impl SandboxedShell {
     pub fn new(config: SandboxConfig) -> FshResult<Self> {
-        let validator = PathValidator::new(config.root_path.clone())?;
+        let validator = PathValidator::new(config.root_path.clone())?
+            .with_symlink_policy(config.symlink_policy)
+            .with_permissions(config.permissions.clone());
         let session_id = Uuid::new_v4().to_string();
+        let cwd_file = match &config.container {
+            Some(container) => container.state_file(&format!("cwd-{}", session_id)).0,
+            None => std::env::temp_dir().join(format!("fsh-cwd-{}", session_id)),
+        };
+        let trash = Trash::new(validator.root_path(), &config.trash);
 
         Ok(Self {
             session_id,
@@ -48,9 +97,18 @@ impl SandboxedShell {
             config,
             validator,
             current_process: None,
+            cwd_file,
+            trash,
+            bound_trash: Vec::new(),
+            processes: ProcessIds::default(),
         })
     }
 
+    /// Record the commands this shell starts in `processes`.
+    pub fn track_processes(&mut self, processes: ProcessIds) {
+        self.processes = processes;
+    }
+
     pub fn session_id(&self) -> &str {
         &self.session_id
     }
@@ -59,15 +117,111 @@ impl SandboxedShell {
         &self.working_directory
     }
 
+    /// The working directory relative to the folder root, `/<name>/...` in a bound folder.
+    pub fn relative_working_directory(&self) -> PathBuf {
+        self.validator.get_relative_path(&self.working_directory).unwrap_or_default()
+    }
+
+    /// Move back to a directory saved by [`relative_working_directory`](Self::relative_working_directory).
+    pub fn restore_working_directory(&mut self, relative: &Path) -> FshResult<()> {
+        let target = self.resolve_path(&relative.to_string_lossy())?;
+        if !target.is_dir() {
+            return Err(FshError::InvalidPath(format!("Directory not found: {}", relative.display())));
+        }
+        self.working_directory = target;
+        Ok(())
+    }
+
+    /// Resolve `path` against the working directory, keeping it inside the sandbox.
+    pub fn resolve_path(&self, path: &str) -> FshResult<PathBuf> {
+        self.validator.validate_path(&self.working_directory.join(path).to_string_lossy())
+    }
+
+    /// Resolve `path` like [`resolve_path`](Self::resolve_path) and check
+    /// that the root it is in grants `permission`.
+    pub fn resolve_path_for(&self, path: &str, permission: Permission) -> FshResult<PathBuf> {
+        let target = self.resolve_path(path)?;
+        self.validator.check_access(&target, &permission)?;
+        Ok(target)
+    }
+
+    /// Bind another folder into the session as `/<name>`, with `permissions`
+    /// and `trash` in place of the session folder's under it.
+    pub fn bind_root(&mut self, name: &str, root_path: &Path, permissions: Vec<Permission>, trash: &TrashConfig) -> FshResult<()> {
+        if self.config.container.is_some() {
+            return Err(FshError::PermissionDenied("Folders can't be bound into a containerized session".to_string()));
+        }
+
+        self.validator.bind_root(name, root_path, permissions)?;
+        if let Some((_, root)) = self.validator.bound_roots().last() {
+            self.bound_trash.push((Trash::new(root, trash), trash.enabled));
+        }
+        Ok(())
+    }
+
+    /// The bound folder roots, as `/<name>` paths.
+    pub fn bound_roots(&self) -> Vec<String> {
+        self.validator.bound_roots().map(|(name, _)| format!("/{}", name)).collect()
+    }
+
+    /// The trash for `path`, and whether deleting there uses it.
+    fn trash_for(&self, path: &Path) -> (&Trash, bool) {
+        let bound = self.validator.bound_root(path)
+            .and_then(|(_, root)| self.bound_trash.iter().find(|(trash, _)| trash.root() == root));
+        match bound {
+            Some((trash, enabled)) => (trash, *enabled),
+            None => (&self.trash, self.config.trash.enabled),
+        }
+    }
+
+    /// Whether `path` is inside any root's trash.
+    fn in_trash(&self, path: &Path) -> bool {
+        self.trash.contains(path) || self.bound_trash.iter().any(|(trash, _)| trash.contains(path))
+    }
+
+    pub fn environment_vars(&self) -> &std::collections::HashMap<String, String> {
+        &self.config.environment_vars
+    }
+
+    /// Set `name` for the session's later commands, or unset it with `None`,
+    /// if the folder lets clients set it.
+    pub fn set_env(&mut self, name: &str, value: Option<String>) -> FshResult<()> {
+        let valid_name = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(FshError::ShellError(format!("Invalid variable name '{}'", name)));
+        }
+        if !self.config.is_env_settable(name) {
+            return Err(FshError::PermissionDenied(format!("Setting {} is not allowed in this folder", name)));
+        }
+
+        match value {
+            Some(value) if value.contains('\0') => {
+                Err(FshError::ShellError(format!("Value of {} contains a NUL byte", name)))
+            }
+            Some(value) => {
+                self.config.environment_vars.insert(name.to_string(), value);
+                Ok(())
+            }
+            None => {
+                self.config.environment_vars.remove(name);
+                Ok(())
+            }
+        }
+    }
+
     pub fn get_shell_prompt(&self) -> String {
         let relative_dir = self.validator
             .get_relative_path(&self.working_directory)
             .unwrap_or_else(|_| PathBuf::from("."));
 
         match self.config.shell_type {
-            ShellType::PowerShell => format!("PS {}> ", relative_dir.display()),
+            ShellType::PowerShell | ShellType::PowerShellCore => format!("PS {}> ", relative_dir.display()),
             ShellType::Cmd => format!("{}> ", relative_dir.display()),
-            ShellType::Bash | ShellType::GitBash => format!("{}$ ", relative_dir.display()),
+            ShellType::Bash | ShellType::GitBash | ShellType::Sh => format!("{}$ ", relative_dir.display()),
+            ShellType::Zsh => format!("{}% ", relative_dir.display()),
+            ShellType::Fish => format!("{}> ", relative_dir.display()),
+            ShellType::Nushell => format!("{}〉", relative_dir.display()),
         }
     }
 
@@ -76,15 +230,25 @@ impl SandboxedShell {
         command: &str,
         args: &[String],
     ) -> FshResult<(mpsc::Receiver<ShellOutput>, mpsc::Receiver<CommandResult>)> {
-        // Validate command
-        let validated_command = self.validator.validate_command_path(command)?;
+        let job = self.run_command(command, args, false).await?;
+        Ok((job.output, job.result))
+    }
 
-        if !self.config.is_command_allowed(&validated_command) {
-            return Err(FshError::PermissionDenied(
-                format!("Command '{}' is not allowed", command)
-            ));
+    /// Start `command` as a background job. Jobs run alongside other commands
+    /// and never move the session's working directory.
+    pub async fn start_job(&mut self, command: &str, args: &[String]) -> FshResult<RunningJob> {
+        if command.eq_ignore_ascii_case("cd") {
+            return Err(FshError::ShellError("cd cannot run as a background job".to_string()));
         }
 
+        self.run_command(command, args, true).await
+    }
+
+    async fn run_command(&mut self, command: &str, args: &[String], background: bool) -> FshResult<RunningJob> {
+        // Validate command
+        let validated_command = self.validator.validate_command_path(command)?;
+        self.config.check_command(&validated_command, args)?;
+
         // Handle special built-in commands
         if let Some(result) = self.handle_builtin_command(command, args).await? {
             let (output_tx, output_rx) = mpsc::channel(100);
@@ -94,23 +258,33 @@ impl SandboxedShell {
                 let _ = output_tx.send(ShellOutput {
                     output_type: OutputType::Stdout,
                     data: result.stdout.clone(),
+                    binary: is_binary(&result.stdout),
                 }).await;
 
                 if !result.stderr.is_empty() {
                     let _ = output_tx.send(ShellOutput {
                         output_type: OutputType::Stderr,
-                        data: result.stderr.clone(),
+                        data: result.stderr.clone().into_bytes(),
+                        binary: false,
                     }).await;
                 }
 
                 let _ = result_tx.send(result).await;
-            });
+            }.in_current_span());
+
+            return Ok(RunningJob { output: output_rx, result: result_rx, killer: None });
+        }
 
-            return Ok((output_rx, result_rx));
+        // A bound folder root may not allow running commands in it
+        self.validator.check_access(&self.working_directory, &Permission::Execute)?;
+
+        #[cfg(feature = "wasi")]
+        if let Some(module) = self.config.wasi.tool(command) {
+            return self.execute_wasi_command(module.to_path_buf(), command, args);
         }
 
         // Execute external command
-        self.execute_external_command(command, args).await
+        self.execute_external_command(command, args, background).await
     }
 
     async fn handle_builtin_command(
@@ -131,12 +305,12 @@ impl SandboxedShell {
                     if target == ".." {
                         let parent = self.working_directory.parent();
                         if let Some(parent) = parent {
-                            if parent.starts_with(&self.config.root_path) {
+                            if !self.validator.is_root(&self.working_directory) {
                                 parent.to_path_buf()
                             } else {
                                 return Ok(Some(CommandResult {
                                     exit_code: 1,
-                                    stdout: String::new(),
+                                    stdout: Vec::new(),
                                     stderr: "Access denied: Cannot navigate above project folder".to_string(),
                                     execution_time_ms: start_time.elapsed().as_millis() as u64,
                                 }));
@@ -159,14 +333,14 @@ impl SandboxedShell {
                     self.working_directory = target_dir;
                     Ok(Some(CommandResult {
                         exit_code: 0,
-                        stdout: String::new(),
+                        stdout: Vec::new(),
                         stderr: String::new(),
                         execution_time_ms: start_time.elapsed().as_millis() as u64,
                     }))
                 } else {
                     Ok(Some(CommandResult {
                         exit_code: 1,
-                        stdout: String::new(),
+                        stdout: Vec::new(),
                         stderr: format!("Directory not found: {}", args[0]),
                         execution_time_ms: start_time.elapsed().as_millis() as u64,
                     }))
@@ -179,11 +353,44 @@ impl SandboxedShell {
 
                 Ok(Some(CommandResult {
                     exit_code: 0,
-                    stdout: format!("{}\n", relative_path.display()),
+                    stdout: format!("{}\n", relative_path.display()).into_bytes(),
                     stderr: String::new(),
                     execution_time_ms: start_time.elapsed().as_millis() as u64,
                 }))
             }
+            "rm" if self.config.trash.enabled => {
+                let (exit_code, stdout, stderr) = match self.remove_to_trash(args) {
+                    Ok(stdout) => (0, stdout.into_bytes(), String::new()),
+                    Err((stdout, stderr)) => (1, stdout.into_bytes(), stderr),
+                };
+
+                Ok(Some(CommandResult {
+                    exit_code,
+                    stdout,
+                    stderr,
+                    execution_time_ms: start_time.elapsed().as_millis() as u64,
+                }))
+            }
+            name if FILE_BUILTINS.contains(&name) => {
+                let output = if self.config.has_permission(&Permission::Read) {
+                    builtins::run(name, args, &self.validator, &self.working_directory)
+                        .unwrap_or_else(|| Err(format!("{}: unknown builtin", name)))
+                } else {
+                    Err(format!("{}: Read permission denied", name))
+                };
+
+                let (exit_code, stdout, stderr) = match output {
+                    Ok(stdout) => (0, stdout, String::new()),
+                    Err(e) => (1, Vec::new(), format!("{}\n", e)),
+                };
+
+                Ok(Some(CommandResult {
+                    exit_code,
+                    stdout,
+                    stderr,
+                    execution_time_ms: start_time.elapsed().as_millis() as u64,
+                }))
+            }
             _ => Ok(None), // Not a built-in command
         }
     }
@@ -192,7 +399,8 @@ impl SandboxedShell {
         &mut self,
         command: &str,
         args: &[String],
-    ) -> FshResult<(mpsc::Receiver<ShellOutput>, mpsc::Receiver<CommandResult>)> {
+        background: bool,
+    ) -> FshResult<RunningJob> {
         let (output_tx, output_rx) = mpsc::channel(100);
         let (result_tx, result_rx) = mpsc::channel(1);
 
@@ -200,114 +408,169 @@ impl SandboxedShell {
         let is_system_aware = self.config.is_system_aware_command(command);
 
         // Prepare command based on shell type
-        let (shell_cmd, shell_args) = self.prepare_shell_command(command, args)?;
+        let track_cwd = !background && tracks_working_directory(&self.config.shell_type);
+        let (shell_cmd, shell_args) = self.prepare_shell_command(command, args, track_cwd)?;
 
-        let mut cmd = Command::new(&shell_cmd);
-        cmd.args(&shell_args)
-            .current_dir(&self.working_directory)
-            .stdout(Stdio::piped())
+        // The working directory may have been swapped for a symlink since `cd`
+        self.validator.validate_path(&self.working_directory.to_string_lossy())?;
+
+        let mut extra_env = Vec::new();
+        if track_cwd {
+            extra_env.push((CWD_FILE_VAR.to_string(), self.cwd_file_for_shell()));
+        }
+
+        let mut cmd = self.shell_process(&shell_cmd, &shell_args, extra_env, is_system_aware)?;
+        cmd.stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .stdin(Stdio::piped());
 
-        // For system-aware commands, inherit system environment
-        if is_system_aware {
-            // Use system environment variables
-            cmd.env_clear();
-            for (key, value) in std::env::vars() {
-                cmd.env(&key, &value);
-            }
-            // Override with custom environment vars if needed
-            for (key, value) in &self.config.environment_vars {
-                cmd.env(key, value);
-            }
-            // Ensure the working directory is in PATH for local executables
-            if let Ok(path) = std::env::var("PATH") {
-                let new_path = format!("{};{}", self.working_directory.display(), path);
-                cmd.env("PATH", new_path);
-            }
-        } else {
-            // Regular sandboxed mode: only use configured environment
-            for (key, value) in &self.config.environment_vars {
-                cmd.env(key, value);
-            }
+        // A job's own process group lets it be killed along with its children
+        #[cfg(unix)]
+        if background {
+            cmd.process_group(0);
         }
 
         let start_time = std::time::Instant::now();
         let mut child = cmd.spawn()
             .map_err(|e| FshError::ShellError(format!("Failed to spawn command: {}", e)))?;
 
+        let pid = child.id();
+        if let Some(pid) = pid {
+            self.processes.insert(pid);
+        }
+
         let stdout = child.stdout.take()
             .ok_or_else(|| FshError::ShellError("Failed to capture stdout".to_string()))?;
         let stderr = child.stderr.take()
             .ok_or_else(|| FshError::ShellError("Failed to capture stderr".to_string()))?;
 
-        let validator = self.validator.clone();
+        let decoder = || OutputDecoder::new(self.config.output_encoding, &self.config.shell_type);
+        tokio::spawn(
+            forward_output(stdout, OutputType::Stdout, decoder(), self.validator.clone(), output_tx.clone())
+                .in_current_span(),
+        );
+        tokio::spawn(
+            forward_output(stderr, OutputType::Stderr, decoder(), self.validator.clone(), output_tx.clone())
+                .in_current_span(),
+        );
 
-        // Handle stdout
-        let output_tx_stdout = output_tx.clone();
+        // Wait for process completion, or kill it when asked to
+        let (kill_tx, mut kill_rx) = oneshot::channel();
+        let processes = self.processes.clone();
         tokio::spawn(async move {
-            let reader = BufReader::new(stdout);
-            let mut lines = reader.lines();
-
-            while let Ok(Some(line)) = lines.next_line().await {
-                let sanitized_line = validator.sanitize_output_path(&line);
-                let _ = output_tx_stdout.send(ShellOutput {
-                    output_type: OutputType::Stdout,
-                    data: format!("{}\n", sanitized_line),
-                }).await;
-            }
-        });
-
-        // Handle stderr
-        let output_tx_stderr = output_tx.clone();
-        let validator_stderr = self.validator.clone();
-        tokio::spawn(async move {
-            let reader = BufReader::new(stderr);
-            let mut lines = reader.lines();
-
-            while let Ok(Some(line)) = lines.next_line().await {
-                let sanitized_line = validator_stderr.sanitize_output_path(&line);
-                let _ = output_tx_stderr.send(ShellOutput {
-                    output_type: OutputType::Stderr,
-                    data: format!("{}\n", sanitized_line),
-                }).await;
+            let status = tokio::select! {
+                status = child.wait() => status,
+                Ok(()) = &mut kill_rx => {
+                    kill_process(&mut child, background).await;
+                    child.wait().await
+                }
+            };
+            if let Some(pid) = pid {
+                processes.remove(pid);
             }
-        });
 
-        // Wait for process completion
-        tokio::spawn(async move {
-            let result = match child.wait().await {
+            let result = match status {
                 Ok(status) => CommandResult {
                     exit_code: status.code().unwrap_or(-1),
-                    stdout: String::new(),
+                    stdout: Vec::new(),
                     stderr: String::new(),
                     execution_time_ms: start_time.elapsed().as_millis() as u64,
                 },
                 Err(e) => CommandResult {
                     exit_code: -1,
-                    stdout: String::new(),
+                    stdout: Vec::new(),
                     stderr: format!("Process execution failed: {}", e),
                     execution_time_ms: start_time.elapsed().as_millis() as u64,
                 },
             };
 
             let _ = result_tx.send(result).await;
+        }.in_current_span());
+
+        Ok(RunningJob { output: output_rx, result: result_rx, killer: Some(CommandKiller(kill_tx)) })
+    }
+
+    /// Run a WASI tool in-process. It sees the folder as `/`, the working
+    /// directory as `.`, and only the folder's environment variables.
+    #[cfg(feature = "wasi")]
+    fn execute_wasi_command(&self, module: PathBuf, command: &str, args: &[String]) -> FshResult<RunningJob> {
+        use super::{WasiCommand, WasiKillSwitch};
+
+        self.validator.validate_path(&self.working_directory.to_string_lossy())?;
+        if self.validator.bound_root(&self.working_directory).is_some() {
+            return Err(FshError::ShellError(format!("{} only runs in the session's own folder", command)));
+        }
+        let mut env: Vec<_> = self.config.environment_vars.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        env.retain(|(key, _)| key != "FSH_ROOT");
+        env.push(("FSH_ROOT".to_string(), "/".to_string()));
+        let wasi_command = WasiCommand {
+            module,
+            args: std::iter::once(command.to_string()).chain(args.iter().cloned()).collect(),
+            env,
+            root: self.config.root_path.clone(),
+            working_dir: self.working_directory.clone(),
+            writable: self.config.has_permission(&Permission::Write),
+        };
+
+        let (output_tx, output_rx) = mpsc::channel(100);
+        let (result_tx, result_rx) = mpsc::channel(1);
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+        let kill_switch = WasiKillSwitch::default();
+        let config = self.config.wasi.clone();
+        let validator = self.validator.clone();
+
+        let killer = kill_switch.clone();
+        tokio::spawn(async move {
+            if kill_rx.await.is_ok() {
+                killer.kill();
+            }
         });
 
-        Ok((output_rx, result_rx))
+        tokio::spawn(async move {
+            let start_time = std::time::Instant::now();
+            let run = tokio::task::spawn_blocking(move || wasi_command.run(&config, &kill_switch)).await;
+            let (exit_code, stderr) = match run {
+                Ok(Ok(output)) => {
+                    for (output_type, data) in [(OutputType::Stdout, output.stdout), (OutputType::Stderr, output.stderr)] {
+                        let mut splitter = OutputSplitter::default();
+                        for chunk in splitter.push(&data).into_iter().chain(splitter.finish()) {
+                            let _ = output_tx.send(shell_output(output_type.clone(), chunk, &validator)).await;
+                        }
+                    }
+                    (output.exit_code, String::new())
+                }
+                Ok(Err(e)) => (-1, format!("Process execution failed: {}", e)),
+                Err(e) => (-1, format!("Process execution failed: {}", e)),
+            };
+
+            let _ = result_tx.send(CommandResult {
+                exit_code,
+                stdout: Vec::new(),
+                stderr,
+                execution_time_ms: start_time.elapsed().as_millis() as u64,
+            }).await;
+        }.in_current_span());
+
+        Ok(RunningJob { output: output_rx, result: result_rx, killer: Some(CommandKiller(kill_tx)) })
     }
 
-    fn prepare_shell_command(&self, command: &str, args: &[String]) -> FshResult<(String, Vec<String>)> {
+    fn prepare_shell_command(&self, command: &str, args: &[String], track_cwd: bool) -> FshResult<(String, Vec<String>)> {
         let full_command = if args.is_empty() {
             command.to_string()
         } else {
             format!("{} {}", command, args.join(" "))
         };
 
-        match self.config.shell_type {
-            ShellType::PowerShell => {
-                Ok(("powershell".to_string(), vec![
-                    "-NoExit".to_string(),
+        match &self.config.shell_type {
+            shell_type @ (ShellType::PowerShell | ShellType::PowerShellCore) => {
+                // Each command runs in its own process, so it must exit on completion
+                // and never stop to prompt the (absent) user
+                Ok((powershell_program(shell_type).to_string(), vec![
+                    "-NoLogo".to_string(),
+                    "-NoProfile".to_string(),
+                    "-NonInteractive".to_string(),
                     "-Command".to_string(),
                     full_command,
                 ]))
@@ -318,21 +581,413 @@ impl SandboxedShell {
                     full_command,
                 ]))
             }
-            ShellType::Bash => {
-                Ok(("bash".to_string(), vec![
+            shell_type => {
+                let script = if track_cwd {
+                    // Report where the command left the shell so `cd` inside scripts sticks
+                    format!(
+                        "{}\n__fsh_status=$?; pwd > \"${}\" 2>/dev/null; exit $__fsh_status",
+                        full_command, CWD_FILE_VAR
+                    )
+                } else {
+                    full_command
+                };
+
+                Ok((shell_program(shell_type).to_string(), vec![
                     "-c".to_string(),
-                    full_command,
+                    script,
                 ]))
             }
-            ShellType::GitBash => {
-                Ok(("bash".to_string(), vec![
-                    "-c".to_string(),
-                    full_command,
-                ]))
+        }
+    }
+
+    /// Build the process for a folder hook. Hooks come from the server's
+    /// config, so they skip the command allowlist but otherwise run like
+    /// commands: through the folder's shell, in the working directory, with
+    /// the folder's environment plus `env`.
+    pub fn hook_command(&self, hook: &str, env: Vec<(String, String)>) -> FshResult<Command> {
+        self.validator.validate_path(&self.working_directory.to_string_lossy())?;
+        let (shell_cmd, shell_args) = self.prepare_shell_command(hook, &[], false)?;
+
+        let mut cmd = self.shell_process(&shell_cmd, &shell_args, env, false)?;
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        Ok(cmd)
+    }
+
+    /// The process that runs the shell in the working directory: directly,
+    /// or in the folder's container. It starts from a clean environment, the
+    /// sandbox's own variables plus `extra_env` and, on the server,
+    /// allowlisted server variables.
+    fn shell_process(
+        &self,
+        shell_cmd: &str,
+        shell_args: &[String],
+        extra_env: Vec<(String, String)>,
+        system_aware: bool,
+    ) -> FshResult<Command> {
+        let Some(container) = &self.config.container else {
+            let mut env = self.config.child_environment(std::env::vars());
+            if system_aware {
+                // Let system-aware tools find executables in the working directory
+                prepend_to_path(&mut env, &self.working_directory);
+            }
+            let mut cmd = Command::new(shell_cmd);
+            cmd.args(shell_args)
+                .current_dir(&self.working_directory)
+                .env_clear()
+                .envs(env)
+                .envs(extra_env);
+            return Ok(cmd);
+        };
+
+        let working_dir = container.container_path(&self.working_directory)
+            .ok_or_else(|| FshError::ShellError("Working directory is outside the container mount".to_string()))?;
+        let mut env = self.config.environment_vars.clone();
+        env.insert("FSH_ROOT".to_string(), container.mount_path().to_string());
+        env.extend(extra_env);
+        Ok(container.exec(shell_cmd, shell_args, &working_dir, &env))
+    }
+
+    /// The cwd report file as the shell sees it.
+    fn cwd_file_for_shell(&self) -> String {
+        match &self.config.container {
+            Some(container) => container.state_file(&format!("cwd-{}", self.session_id)).1,
+            None => self.cwd_file.to_string_lossy().to_string(),
+        }
+    }
+
+    /// Hide the server-side folder path in text shown to the client.
+    pub fn sanitize_output(&self, output: &str) -> String {
+        self.validator.sanitize_output_path(output)
+    }
+
+    /// Pick up the directory an external command finished in. Directories
+    /// outside the sandbox are ignored. Returns whether the working directory changed.
+    pub fn sync_working_directory(&mut self) -> bool {
+        let Ok(reported) = std::fs::read_to_string(&self.cwd_file) else {
+            return false;
+        };
+        let _ = std::fs::remove_file(&self.cwd_file);
+
+        let reported = reported.trim_end_matches(['\r', '\n']);
+        if reported.is_empty() {
+            return false;
+        }
+        let reported = match &self.config.container {
+            Some(container) => match container.host_path(reported) {
+                Some(path) => path.to_string_lossy().to_string(),
+                None => {
+                    warn!("Ignoring working directory {} outside the container mount", reported);
+                    return false;
+                }
+            },
+            None => reported.to_string(),
+        };
+
+        match self.validator.validate_path(&reported) {
+            Ok(dir) if dir.is_dir() && dir != self.working_directory => {
+                debug!("Working directory of shell {} is now {}", self.session_id, dir.display());
+                self.working_directory = dir;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Ignoring working directory reported by shell {}: {}", self.session_id, e);
+                false
             }
         }
     }
 
+    fn file_entry(&self, name: String, path: &Path, metadata: &Metadata) -> FileEntry {
+        let relative_path = self.validator.get_relative_path(path)
+            .unwrap_or_else(|_| path.strip_prefix(&self.config.root_path).unwrap_or(path).to_path_buf());
+        let (owner, group) = metadata::owner_and_group(metadata);
+
+        FileEntry {
+            name,
+            path: relative_path.to_string_lossy().to_string(),
+            is_directory: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified()
+                .map(chrono::DateTime::from)
+                .unwrap_or_else(|_| chrono::Utc::now()),
+            permissions: Some(metadata::permission_string(metadata)),
+            owner,
+            group,
+            attributes: metadata::file_attributes(metadata),
+        }
+    }
+
+    /// `path` itself when it names a symlink inside the sandbox. `resolve_path`
+    /// follows symlinks, which is wrong for operations on the link.
+    fn symlink_path(&self, path: &str) -> Option<PathBuf> {
+        let lexical = normalize_lexically(&self.working_directory.join(path));
+        let link = lexical.starts_with(self.validator.root_path())
+            && lexical.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        link.then_some(lexical)
+    }
+
+    /// Extended metadata for `path`, resolved against the working directory.
+    pub fn stat_file(&self, path: &str) -> FshResult<FileStat> {
+        let target = self.resolve_path(path)?;
+        let link_path = self.symlink_path(path);
+        let link = link_path.is_some();
+        let named_path = link_path.as_ref().unwrap_or(&target);
+
+        let metadata = std::fs::metadata(&target)
+            .map_err(|e| FshError::ShellError(format!("Cannot stat '{}': {}", path, e)))?;
+        let name = named_path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| ".".to_string());
+        let (file_id, device, hard_links) = metadata::file_identity(&metadata);
+
+        Ok(FileStat {
+            entry: self.file_entry(name, named_path, &metadata),
+            is_symlink: link,
+            symlink_target: link.then(|| {
+                self.validator.get_relative_path(&target)
+                    .map(|relative| relative.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            }),
+            created: metadata.created().ok().map(chrono::DateTime::from),
+            accessed: metadata.accessed().ok().map(chrono::DateTime::from),
+            file_id,
+            device,
+            hard_links,
+        })
+    }
+
+    /// Read up to `length` bytes (at most [`MAX_FILE_CHUNK_SIZE`]) starting at
+    /// `offset`. Returns the data and the file's total size.
+    pub fn read_file_range(&self, path: &str, offset: u64, length: Option<u64>) -> FshResult<(Vec<u8>, u64)> {
+        let target = self.resolve_path_for(path, Permission::Read)?;
+        self.check_file_policy(PolicyAction::Read, &target)?;
+        let mut file = std::fs::File::open(&target)
+            .map_err(|e| FshError::ShellError(format!("Cannot open '{}': {}", path, e)))?;
+        let total_size = file.metadata()?.len();
+
+        let length = length.unwrap_or(u64::MAX).min(MAX_FILE_CHUNK_SIZE as u64);
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(length).read_to_end(&mut data)?;
+
+        Ok((data, total_size))
+    }
+
+    /// Write `data` to `path`: appended, at `offset` (without truncating), or
+    /// replacing the file when neither is requested. Returns the bytes written.
+    pub fn write_file(&self, path: &str, data: &[u8], append: bool, offset: Option<u64>) -> FshResult<u64> {
+        let target = self.resolve_path_for(path, Permission::Write)?;
+        self.check_file_policy(PolicyAction::Write, &target)?;
+        if target.is_dir() {
+            return Err(FshError::InvalidPath(format!("'{}' is a directory", path)));
+        }
+        if !append && offset.is_none() {
+            return replace_file(&target, data)
+                .map(|()| data.len() as u64)
+                .map_err(|e| FshError::ShellError(format!("Cannot write '{}': {}", path, e)));
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true);
+        if append {
+            options.append(true);
+        } else {
+            options.write(true);
+        }
+
+        let mut file = options.open(&target)
+            .map_err(|e| FshError::ShellError(format!("Cannot open '{}' for writing: {}", path, e)))?;
+        if let (false, Some(offset)) = (append, offset) {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        file.write_all(data)?;
+
+        Ok(data.len() as u64)
+    }
+
+    /// Create the directory `path` and any missing parents.
+    pub fn create_dir(&self, path: &str) -> FshResult<()> {
+        let target = self.resolve_path_for(path, Permission::Write)?;
+        self.check_file_policy(PolicyAction::Write, &target)?;
+        if self.in_trash(&target) {
+            return Err(FshError::PermissionDenied(format!("Cannot create '{}'", path)));
+        }
+
+        std::fs::create_dir_all(&target)
+            .map_err(|e| FshError::ShellError(format!("Cannot create '{}': {}", path, e)))
+    }
+
+    /// Checksum of `path`, or of `length` bytes starting at `offset`.
+    pub fn checksum_file(
+        &self,
+        path: &str,
+        algorithm: ChecksumAlgorithm,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> FshResult<(Checksum, u64)> {
+        let target = self.resolve_path_for(path, Permission::Read)?;
+        self.check_file_policy(PolicyAction::Read, &target)?;
+        let mut file = std::fs::File::open(&target)
+            .map_err(|e| FshError::ShellError(format!("Cannot open '{}': {}", path, e)))?;
+
+        file.seek(SeekFrom::Start(offset.unwrap_or(0)))?;
+        Ok(algorithm.digest_reader(file.take(length.unwrap_or(u64::MAX)))?)
+    }
+
+    /// Delete `path`, moving it to the folder's trash unless the trash is
+    /// disabled. Returns the trash entry it can be restored from.
+    pub fn delete_path(&mut self, path: &str, recursive: bool) -> FshResult<Option<TrashEntry>> {
+        let target = match self.symlink_path(path) {
+            Some(link) => link,
+            None => self.resolve_path(path)?,
+        };
+        self.validator.check_access(&target, &Permission::Write)?;
+        if self.validator.is_root(&target) || self.in_trash(&target) {
+            return Err(FshError::PermissionDenied(format!("Cannot delete '{}'", path)));
+        }
+        self.check_file_policy(PolicyAction::Delete, &target)?;
+
+        let metadata = target.symlink_metadata()
+            .map_err(|e| FshError::ShellError(format!("Cannot delete '{}': {}", path, e)))?;
+        if metadata.is_dir() && !recursive {
+            return Err(FshError::InvalidPath(format!("'{}' is a directory", path)));
+        }
+
+        let (trash, trash_enabled) = self.trash_for(&target);
+        let entry = if trash_enabled {
+            if let Err(e) = trash.purge_expired() {
+                warn!("Failed to purge expired trash for shell {}: {}", self.session_id, e);
+            }
+            Some(trash.move_to_trash(&target)?)
+        } else {
+            if metadata.is_dir() {
+                std::fs::remove_dir_all(&target)?;
+            } else {
+                std::fs::remove_file(&target)?;
+            }
+            None
+        };
+
+        // Don't leave the shell in a directory that no longer exists
+        if !self.working_directory.is_dir() {
+            self.working_directory = self.config.root_path.clone();
+        }
+
+        Ok(entry)
+    }
+
+    /// Restore a trashed item by trash id or original path.
+    pub fn restore_path(&self, target: &str) -> FshResult<TrashEntry> {
+        if !self.config.has_permission(&Permission::Write) {
+            return Err(FshError::PermissionDenied("Write permission denied".to_string()));
+        }
+
+        self.trash.restore(target)
+    }
+
+    /// Check access to a resolved path against the folder's policy.
+    /// Bound folder roots aren't covered by it; folders with a policy can't be bound.
+    pub fn check_file_policy(&self, action: PolicyAction, target: &Path) -> FshResult<()> {
+        match &self.config.policy {
+            Some(_) if self.validator.bound_root(target).is_some() => Ok(()),
+            Some(policy) => policy.check_path(action, &self.validator.get_relative_path(target)?),
+            None => Ok(()),
+        }
+    }
+
+    /// The git repository containing `path`, or the working directory.
+    fn git_repository(&self, path: Option<&str>) -> FshResult<FolderRepository> {
+        let target = self.resolve_path(path.unwrap_or("."))?;
+        self.check_file_policy(PolicyAction::Read, &target)?;
+        FolderRepository::open(self.validator.root_path(), &target)
+    }
+
+    /// Whether the policy lets the client read `path`, relative to the root.
+    fn git_readable(&self, path: &Path) -> bool {
+        self.check_file_policy(PolicyAction::Read, &self.validator.root_path().join(path)).is_ok()
+    }
+
+    /// Changed files in the repository containing `path`, leaving out those
+    /// the folder's policy keeps the client from reading.
+    pub fn git_status(&self, path: Option<&str>) -> FshResult<GitStatus> {
+        let repository = self.git_repository(path)?;
+        let mut status = repository.status()?;
+        status.entries.retain(|entry| self.git_readable(&repository.folder_path(Path::new(&entry.path))));
+        Ok(status)
+    }
+
+    /// Unified diff in the repository containing `path`, and whether it was
+    /// cut off.
+    pub fn git_diff(&self, path: Option<&str>, staged: bool, context_lines: u32) -> FshResult<(String, bool)> {
+        self.git_repository(path)?.diff(staged, context_lines, |path| self.git_readable(path))
+    }
+
+    pub fn git_log(&self, path: Option<&str>, max_count: u32) -> FshResult<Vec<GitCommit>> {
+        self.git_repository(path)?.log((max_count as usize).min(MAX_GIT_LOG_COUNT))
+    }
+
+    /// Items in the folder's trash, newest first.
+    pub fn trash_entries(&self) -> FshResult<Vec<TrashEntry>> {
+        self.trash.entries()
+    }
+
+    /// `rm` that moves its operands to the trash. On failure returns the
+    /// output so far and the error lines.
+    fn remove_to_trash(&mut self, args: &[String]) -> Result<String, (String, String)> {
+        let mut recursive = false;
+        let mut force = false;
+        let mut verbose = false;
+        let mut paths = Vec::new();
+        let mut options_done = false;
+
+        for arg in args {
+            match arg.as_str() {
+                "--" if !options_done => options_done = true,
+                "--recursive" if !options_done => recursive = true,
+                "--force" if !options_done => force = true,
+                "--verbose" if !options_done => verbose = true,
+                flags if !options_done && flags.len() > 1 && flags.starts_with('-') => {
+                    for flag in flags[1..].chars() {
+                        match flag {
+                            'r' | 'R' => recursive = true,
+                            'f' => force = true,
+                            'v' => verbose = true,
+                            _ => return Err((String::new(), format!("rm: invalid option -- '{}'\n", flag))),
+                        }
+                    }
+                }
+                path => paths.push(path),
+            }
+        }
+
+        if paths.is_empty() && !force {
+            return Err((String::new(), "rm: missing operand\n".to_string()));
+        }
+
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        for path in paths {
+            match self.delete_path(path, recursive) {
+                Ok(Some(entry)) if verbose => {
+                    stdout.push_str(&format!("removed '{}' (trash id {})\n", path, entry.id));
+                }
+                Ok(_) => {}
+                Err(_) if force && self.symlink_path(path).is_none()
+                    && self.resolve_path(path).map(|p| !p.exists()).unwrap_or(false) => {}
+                Err(e) => stderr.push_str(&format!("rm: cannot remove '{}': {}\n", path, e)),
+            }
+        }
+
+        if stderr.is_empty() {
+            Ok(stdout)
+        } else {
+            Err((stdout, stderr))
+        }
+    }
+
     pub async fn kill_current_process(&mut self) -> FshResult<()> {
         if let Some(mut process) = self.current_process.take() {
             process.kill().await
@@ -341,21 +996,25 @@ impl SandboxedShell {
         Ok(())
     }
 
-    pub fn list_files(&self, path: Option<&str>, show_hidden: bool) -> FshResult<Vec<crate::protocol::message::FileEntry>> {
-        let target_path = if let Some(path) = path {
-            self.validator.validate_path(path)?
-        } else {
-            self.working_directory.clone()
-        };
+    pub fn list_files(&self, path: Option<&str>, show_hidden: bool) -> FshResult<Vec<FileEntry>> {
+        let listing = self.read_directory(path, show_hidden)?;
+        Ok(self.file_entries(&listing))
+    }
+
+    /// Names and paths of a directory's entries in listing order: directories
+    /// first, then files, alphabetically within each group. Metadata is looked
+    /// up separately with [`file_entries`](Self::file_entries) so huge
+    /// directories can be sent in batches.
+    pub fn read_directory(&self, path: Option<&str>, show_hidden: bool) -> FshResult<Vec<(String, PathBuf)>> {
+        let target_path = self.validator.validate_path(
+            &path.map(PathBuf::from).unwrap_or_else(|| self.working_directory.clone()).to_string_lossy()
+        )?;
 
         let mut entries = Vec::new();
 
         for entry in std::fs::read_dir(&target_path)
-            .map_err(|e| FshError::ShellError(format!("Failed to read directory: {}", e)))? {
-            let entry = entry.map_err(|e| FshError::ShellError(format!("Failed to read entry: {}", e)))?;
-            let metadata = entry.metadata()
-                .map_err(|e| FshError::ShellError(format!("Failed to read metadata: {}", e)))?;
-
+            .map_err(|e| FshError::io("Failed to read directory", e))? {
+            let entry = entry.map_err(|e| FshError::io("Failed to read entry", e))?;
             let file_name = entry.file_name().to_string_lossy().to_string();
 
             // Skip hidden files if not requested
@@ -363,31 +1022,192 @@ impl SandboxedShell {
                 continue;
             }
 
-            let relative_path = self.validator.get_relative_path(&entry.path())
-                .unwrap_or_else(|_| entry.path().strip_prefix(&self.config.root_path).unwrap_or(&entry.path()).to_path_buf());
-
-            entries.push(crate::protocol::message::FileEntry {
-                name: file_name,
-                path: relative_path.to_string_lossy().to_string(),
-                is_directory: metadata.is_dir(),
-                size: metadata.len(),
-                modified: metadata.modified()
-                    .map(|time| chrono::DateTime::from(time))
-                    .unwrap_or_else(|_| chrono::Utc::now()),
-                permissions: None, // TODO: Implement permission strings
-            });
-        }
-
-        // Sort entries: directories first, then files, alphabetically within each group
-        entries.sort_by(|a, b| {
-            match (a.is_directory, b.is_directory) {
-                (true, false) => std::cmp::Ordering::Less,
-                (false, true) => std::cmp::Ordering::Greater,
-                _ => a.name.cmp(&b.name),
+            if self.in_trash(&entry.path()) {
+                continue;
             }
-        });
 
-        Ok(entries)
+            let Some(metadata) = self.validator.entry_metadata(&entry.path()) else {
+                continue; // Symlink pointing outside the sandbox
+            };
+
+            entries.push((metadata.is_dir(), file_name, entry.path()));
+        }
+
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        Ok(entries.into_iter().map(|(_, name, path)| (name, path)).collect())
+    }
+
+    /// Full entries for part of a [`read_directory`](Self::read_directory) listing.
+    /// Entries removed in the meantime are skipped.
+    pub fn file_entries(&self, listing: &[(String, PathBuf)]) -> Vec<FileEntry> {
+        listing.iter()
+            .filter_map(|(name, path)| {
+                let metadata = self.validator.entry_metadata(path)?;
+                Some(self.file_entry(name.clone(), path, &metadata))
+            })
+            .collect()
+    }
+}
+
+/// Replace `target` with `data` in one step, so readers see either the old
+/// or the new contents. The file keeps its permissions.
+fn replace_file(target: &Path, data: &[u8]) -> std::io::Result<()> {
+    let name = target.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let temp_path = target.with_file_name(format!(".{}.fsh-{}", name, Uuid::new_v4()));
+
+    let result = (|| {
+        let mut temp = std::fs::File::create(&temp_path)?;
+        temp.write_all(data)?;
+        temp.sync_all()?;
+        if let Ok(metadata) = std::fs::metadata(target) {
+            temp.set_permissions(metadata.permissions())?;
+        }
+        std::fs::rename(&temp_path, target)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Send what a command writes to `reader` on as it arrives, as UTF-8.
+async fn forward_output<R: AsyncRead + Unpin>(
+    mut reader: R,
+    output_type: OutputType,
+    mut decoder: OutputDecoder,
+    validator: PathValidator,
+    output_tx: mpsc::Sender<ShellOutput>,
+) {
+    let mut splitter = OutputSplitter::default();
+    let mut buffer = vec![0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for chunk in splitter.push(&decoder.decode(&buffer[..read])) {
+            let _ = output_tx.send(shell_output(output_type.clone(), chunk, &validator)).await;
+        }
+    }
+    let rest = splitter.push(&decoder.finish());
+    for chunk in rest.into_iter().chain(splitter.finish()) {
+        let _ = output_tx.send(shell_output(output_type.clone(), chunk, &validator)).await;
+    }
+}
+
+/// A chunk of output ready to send, with the folder's absolute paths hidden
+/// in text.
+fn shell_output(output_type: OutputType, chunk: OutputChunk, validator: &PathValidator) -> ShellOutput {
+    match chunk {
+        OutputChunk::Text(text) => ShellOutput {
+            output_type,
+            data: validator.sanitize_output_path(&text).into_bytes(),
+            binary: false,
+        },
+        OutputChunk::Binary(data) => ShellOutput { output_type, data, binary: true },
+    }
+}
+
+/// Kill `child`; for a background job, its whole process group.
+async fn kill_process(child: &mut Child, background: bool) {
+    #[cfg(unix)]
+    if let (true, Some(pid)) = (background, child.id()) {
+        // SAFETY: kill has no memory-safety preconditions; the group id is the job's pid
+        if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = background;
+
+    if let Err(e) = child.kill().await {
+        warn!("Failed to kill process: {}", e);
+    }
+}
+
+/// Environment variable naming the file a shell reports its final directory to.
+const CWD_FILE_VAR: &str = "FSH_CWD_FILE";
+
+/// Whether commands for `shell_type` are wrapped to report their final directory.
+/// Only POSIX shells share a syntax for it.
+fn tracks_working_directory(shell_type: &ShellType) -> bool {
+    matches!(shell_type, ShellType::Bash | ShellType::GitBash | ShellType::Zsh | ShellType::Sh)
+}
+
+/// Executable that runs commands for `shell_type`.
+fn shell_program(shell_type: &ShellType) -> &'static str {
+    match shell_type {
+        ShellType::Cmd => "cmd",
+        ShellType::PowerShell | ShellType::PowerShellCore => powershell_program(shell_type),
+        ShellType::Bash | ShellType::GitBash => "bash",
+        ShellType::Zsh => "zsh",
+        ShellType::Fish => "fish",
+        ShellType::Nushell => "nu",
+        ShellType::Sh => "sh",
+    }
+}
+
+/// Whether the server can run commands with `shell_type`.
+pub fn is_shell_available(shell_type: &ShellType) -> bool {
+    find_executable(shell_program(shell_type)).is_some()
+}
+
+/// Pick the shell to use for a session: `requested` if it is installed, otherwise
+/// the first installed shell from the platform's fallback list. Returns
+/// `requested` unchanged when nothing suitable is found.
+pub fn detect_shell(requested: &ShellType) -> ShellType {
+    if is_shell_available(requested) {
+        return requested.clone();
+    }
+
+    let fallbacks: &[ShellType] = if cfg!(windows) {
+        &[ShellType::PowerShellCore, ShellType::PowerShell, ShellType::Cmd, ShellType::GitBash]
+    } else {
+        &[ShellType::Bash, ShellType::Zsh, ShellType::Sh, ShellType::Fish, ShellType::Nushell, ShellType::PowerShellCore]
+    };
+
+    fallbacks.iter()
+        .find(|shell_type| is_shell_available(shell_type))
+        .cloned()
+        .unwrap_or_else(|| requested.clone())
+}
+
+/// Executable used for a PowerShell shell type. Windows PowerShell is only
+/// shipped on Windows, so fall back to `pwsh` when `powershell` is not found.
+fn powershell_program(shell_type: &ShellType) -> &'static str {
+    match shell_type {
+        ShellType::PowerShell if find_executable("powershell").is_none()
+            && find_executable("pwsh").is_some() => "pwsh",
+        ShellType::PowerShell => "powershell",
+        _ => "pwsh",
+    }
+}
+
+/// Locate `program` on the server's `PATH`.
+pub fn find_executable(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let extensions: Vec<String> = if cfg!(windows) {
+        std::env::var("PATHEXT")
+            .unwrap_or_else(|_| ".EXE;.CMD;.BAT;.COM".to_string())
+            .split(';')
+            .map(|ext| ext.to_string())
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+
+    std::env::split_paths(&path).find_map(|dir| {
+        extensions.iter()
+            .map(|ext| dir.join(format!("{}{}", program, ext)))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+impl Drop for SandboxedShell {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cwd_file);
     }
 }
 
@@ -429,4 +1249,160 @@ mod tests {
         assert!(result.is_some());
         assert_eq!(result.unwrap().exit_code, 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_builtin_rm_moves_to_trash() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir(temp_dir.path().join("build")).unwrap();
+        std::fs::write(temp_dir.path().join("build/out.o"), "obj").unwrap();
+
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::Bash);
+        let mut shell = SandboxedShell::new(config).unwrap();
+
+        // Directories need -r
+        let result = shell.handle_builtin_command("rm", &["build".to_string()]).await.unwrap().unwrap();
+        assert_eq!(result.exit_code, 1);
+
+        let result = shell.handle_builtin_command("rm", &["-rf".to_string(), "build".to_string(), "missing".to_string()])
+            .await.unwrap().unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert!(!temp_dir.path().join("build").exists());
+
+        // The trash is never listed
+        let listing = shell.read_directory(None, true).unwrap();
+        assert!(listing.is_empty());
+
+        let entry = shell.restore_path("build").unwrap();
+        assert!(entry.is_directory);
+        assert!(temp_dir.path().join("build/out.o").exists());
+    }
+
+    #[test]
+    fn test_powershell_runs_non_interactively() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::PowerShellCore);
+        let shell = SandboxedShell::new(config).unwrap();
+
+        let (program, args) = shell.prepare_shell_command("Get-ChildItem", &[], false).unwrap();
+        assert_eq!(program, "pwsh");
+        assert!(args.contains(&"-NonInteractive".to_string()));
+        assert!(args.contains(&"-NoProfile".to_string()));
+        assert!(!args.contains(&"-NoExit".to_string()));
+        assert_eq!(args.last().map(String::as_str), Some("Get-ChildItem"));
+    }
+
+    #[test]
+    fn test_posix_shells_use_dash_c() {
+        let temp_dir = TempDir::new().unwrap();
+
+        for (shell_type, program) in [
+            (ShellType::Zsh, "zsh"),
+            (ShellType::Fish, "fish"),
+            (ShellType::Nushell, "nu"),
+            (ShellType::Sh, "sh"),
+        ] {
+            let config = SandboxConfig::new(temp_dir.path().to_path_buf(), shell_type);
+            let shell = SandboxedShell::new(config).unwrap();
+            let (cmd, args) = shell.prepare_shell_command("echo", &["hi".to_string()], true).unwrap();
+            assert_eq!(cmd, program);
+            assert_eq!(args[0], "-c");
+            assert!(args[1].starts_with("echo hi"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_background_job_can_be_killed() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::Sh)
+            .with_allowed_commands(Vec::new());
+        let mut shell = SandboxedShell::new(config).unwrap();
+
+        // The child of the shell must die too, or its open pipe keeps output from closing
+        let mut job = shell.start_job("sleep 30; echo done", &[]).await.unwrap();
+        job.killer.take().unwrap().kill();
+
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), job.result.recv())
+            .await.unwrap().unwrap();
+        assert_ne!(result.exit_code, 0);
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(5), job.output.recv())
+            .await.unwrap().is_none());
+
+        assert!(shell.start_job("cd", &["..".to_string()]).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detect_shell_falls_back() {
+        // `sh` is always present on Unix hosts
+        assert_eq!(detect_shell(&ShellType::Sh), ShellType::Sh);
+        assert!(is_shell_available(&detect_shell(&ShellType::Cmd)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_external_cd_updates_working_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir(temp_dir.path().join("subdir")).unwrap();
+
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::Sh);
+        let mut shell = SandboxedShell::new(config).unwrap();
+
+        let mut job = shell.execute_external_command("cd", &["subdir".to_string()], false).await.unwrap();
+        assert_eq!(job.result.recv().await.unwrap().exit_code, 0);
+        assert!(shell.sync_working_directory());
+        assert!(shell.working_directory().ends_with("subdir"));
+
+        // Leaving the sandbox is not tracked
+        let mut job = shell.execute_external_command("cd", &["/".to_string()], false).await.unwrap();
+        assert_eq!(job.result.recv().await.unwrap().exit_code, 0);
+        assert!(!shell.sync_working_directory());
+        assert!(shell.working_directory().ends_with("subdir"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stat_file_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("data.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink("data.txt", temp_dir.path().join("link")).unwrap();
+
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::Bash);
+        let shell = SandboxedShell::new(config).unwrap();
+
+        let files = shell.list_files(None, false).unwrap();
+        assert!(files.iter().all(|file| file.permissions.is_some() && file.owner.is_some()));
+
+        let stat = shell.stat_file("link").unwrap();
+        assert!(stat.is_symlink);
+        assert_eq!(stat.symlink_target.as_deref(), Some("data.txt"));
+        assert_eq!(stat.entry.name, "link");
+        assert_eq!(stat.entry.size, 5);
+        assert!(stat.file_id.is_some());
+
+        let stat = shell.stat_file("data.txt").unwrap();
+        assert!(!stat.is_symlink);
+        assert_eq!(stat.symlink_target, None);
+    }
+
+    #[test]
+    fn test_file_range_operations() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = SandboxConfig::new(temp_dir.path().to_path_buf(), ShellType::Bash);
+        let shell = SandboxedShell::new(config).unwrap();
+
+        shell.write_file("data.bin", b"hello world", false, None).unwrap();
+        shell.write_file("data.bin", b"W", false, Some(6)).unwrap();
+        shell.write_file("data.bin", b"!", true, None).unwrap();
+
+        let (data, total) = shell.read_file_range("data.bin", 6, Some(5)).unwrap();
+        assert_eq!(data, b"World");
+        assert_eq!(total, 12);
+
+        let (checksum, size) = shell.checksum_file("data.bin", ChecksumAlgorithm::Sha256, Some(6), Some(5)).unwrap();
+        assert_eq!(size, 5);
+        assert!(checksum.verify(b"World"));
+
+        assert!(shell.write_file("../escape.txt", b"x", false, None).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::{File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{FshError, FshResult};
+
+/// How far back from the end of the file to look for the initial lines.
+const BACKLOG_WINDOW: u64 = 256 * 1024;
+
+/// Largest amount of new data read per poll.
+const MAX_READ_CHUNK: usize = 64 * 1024;
+
+/// Data appended to a followed file since the last poll.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailChunk {
+    /// Complete lines, including their trailing newline.
+    pub data: Vec<u8>,
+    /// The file was replaced or truncated; `data` starts at its beginning.
+    pub rotated: bool,
+}
+
+/// Follows a file by polling, like `tail -F`: appended lines are returned as
+/// they are completed and log rotation (replacement or truncation) is detected.
+#[derive(Debug)]
+pub struct FileFollower {
+    path: PathBuf,
+    position: u64,
+    file_id: Option<u64>,
+    partial: Vec<u8>,
+}
+
+impl FileFollower {
+    /// Start following `path` from its current end, returning its last `lines` lines.
+    pub fn open(path: &Path, lines: usize) -> FshResult<(Self, Vec<u8>)> {
+        let mut file = File::open(path)
+            .map_err(|e| FshError::io(format!("Failed to open '{}'", path.display()), e))?;
+        let metadata = file.metadata()?;
+        if metadata.is_dir() {
+            return Err(FshError::InvalidPath(format!("'{}' is a directory", path.display())));
+        }
+
+        let len = metadata.len();
+        let start = len.saturating_sub(BACKLOG_WINDOW);
+        file.seek(SeekFrom::Start(start))?;
+        let mut window = Vec::new();
+        file.take(len - start).read_to_end(&mut window)?;
+
+        let backlog = last_lines(&window, lines).to_vec();
+        let follower = Self {
+            path: path.to_path_buf(),
+            position: len,
+            file_id: file_id(&metadata),
+            partial: Vec::new(),
+        };
+
+        Ok((follower, backlog))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read whatever was appended since the last call. Returns `None` when there
+    /// are no new complete lines, including while a rotated file is missing.
+    pub fn poll(&mut self) -> FshResult<Option<TailChunk>> {
+        let Ok(metadata) = std::fs::metadata(&self.path) else {
+            return Ok(None); // Rotated away and not yet recreated
+        };
+
+        let id = file_id(&metadata);
+        let rotated = id != self.file_id || metadata.len() < self.position;
+        if rotated {
+            self.file_id = id;
+            self.position = 0;
+            self.partial.clear();
+        }
+
+        if metadata.len() == self.position {
+            return Ok(rotated.then(|| TailChunk { data: Vec::new(), rotated }));
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        let mut data = Vec::new();
+        let read = file.take(MAX_READ_CHUNK as u64).read_to_end(&mut data)?;
+        self.position += read as u64;
+
+        // Hold back an unterminated last line until the rest of it arrives
+        self.partial.extend_from_slice(&data);
+        let data = match self.partial.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                let rest = self.partial.split_off(end + 1);
+                std::mem::replace(&mut self.partial, rest)
+            }
+            // Don't buffer forever if the writer never ends the line
+            None if self.partial.len() >= MAX_READ_CHUNK => std::mem::take(&mut self.partial),
+            None => Vec::new(),
+        };
+
+        if data.is_empty() && !rotated {
+            return Ok(None);
+        }
+        Ok(Some(TailChunk { data, rotated }))
+    }
+}
+
+/// The trailing `count` lines of `data`.
+fn last_lines(data: &[u8], count: usize) -> &[u8] {
+    if count == 0 {
+        return &[];
+    }
+
+    // A trailing newline terminates the last line rather than starting a new one
+    let body = data.strip_suffix(b"\n").unwrap_or(data);
+    let start = body.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &b)| b == b'\n')
+        .nth(count - 1)
+        .map(|(index, _)| index + 1)
+        .unwrap_or(0);
+
+    &data[start..]
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<u64> {
+    None // Only truncation is detected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn append(path: &Path, data: &str) {
+        let mut file = std::fs::OpenOptions::new().append(true).create(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_last_lines() {
+        assert_eq!(last_lines(b"a\nb\nc\n", 2), b"b\nc\n");
+        assert_eq!(last_lines(b"a\nb\nc", 2), b"b\nc");
+        assert_eq!(last_lines(b"a\nb\n", 5), b"a\nb\n");
+        assert_eq!(last_lines(b"a\nb\n", 0), b"");
+    }
+
+    #[test]
+    fn test_follow_appended_lines() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.log");
+        append(&path, "one\ntwo\nthree\n");
+
+        let (mut follower, backlog) = FileFollower::open(&path, 2).unwrap();
+        assert_eq!(backlog, b"two\nthree\n");
+        assert_eq!(follower.poll().unwrap(), None);
+
+        append(&path, "four\nfi");
+        let chunk = follower.poll().unwrap().unwrap();
+        assert_eq!(chunk, TailChunk { data: b"four\n".to_vec(), rotated: false });
+
+        append(&path, "ve\n");
+        assert_eq!(follower.poll().unwrap().unwrap().data, b"five\n");
+    }
+
+    #[test]
+    fn test_follow_detects_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.log");
+        append(&path, "old line\n");
+
+        let (mut follower, _) = FileFollower::open(&path, 10).unwrap();
+
+        std::fs::rename(&path, temp_dir.path().join("app.log.1")).unwrap();
+        assert_eq!(follower.poll().unwrap(), None);
+
+        append(&path, "new\n");
+        let chunk = follower.poll().unwrap().unwrap();
+        assert!(chunk.rotated);
+        assert_eq!(chunk.data, b"new\n");
+    }
+}
@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Name of the per-folder directory deleted files are moved to.
+pub const TRASH_DIR: &str = ".fsh-trash";
+
+/// Suffix of the file recording where a trashed item came from.
+const INFO_SUFFIX: &str = ".info.json";
+
+/// How deletes are handled for a folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrashConfig {
+    /// Move deleted files to the trash instead of unlinking them.
+    pub enabled: bool,
+    /// Trashed items older than this many days are removed for good (0 = keep forever).
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 7,
+        }
+    }
+}
+
+impl TrashConfig {
+    pub fn retention(&self) -> Option<Duration> {
+        (self.retention_days > 0).then(|| Duration::from_secs(self.retention_days * 24 * 60 * 60))
+    }
+}
+
+/// A deleted file or directory waiting in the trash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    /// Where the item lived, relative to the folder root.
+    pub original_path: String,
+    pub deleted_at: chrono::DateTime<chrono::Utc>,
+    pub is_directory: bool,
+}
+
+/// The trash area of one folder, kept in [`TRASH_DIR`] under its root so items
+/// can be moved there with a rename.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+    dir: PathBuf,
+    retention: Option<Duration>,
+}
+
+impl Trash {
+    pub fn new(root: &Path, config: &TrashConfig) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            dir: root.join(TRASH_DIR),
+            retention: config.retention(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether `path` is the trash directory or something inside it.
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.dir)
+    }
+
+    /// Move `path`, which must be inside the folder root, into the trash.
+    pub fn move_to_trash(&self, path: &Path) -> FshResult<TrashEntry> {
+        let relative = path.strip_prefix(&self.root)
+            .map_err(|_| FshError::InvalidPath(format!("'{}' is outside the folder", path.display())))?;
+        if relative.as_os_str().is_empty() || self.contains(path) {
+            return Err(FshError::PermissionDenied(format!("Cannot delete '{}'", relative.display())));
+        }
+
+        let metadata = path.symlink_metadata()
+            .map_err(|e| FshError::ShellError(format!("Cannot delete '{}': {}", relative.display(), e)))?;
+        std::fs::create_dir_all(&self.dir)?;
+
+        // Ids sort by deletion time so the newest match is easy to find
+        let deleted_at = chrono::Utc::now();
+        let entry = TrashEntry {
+            id: format!("{}-{}", deleted_at.timestamp_millis(), &Uuid::new_v4().simple().to_string()[..8]),
+            original_path: relative_string(relative),
+            deleted_at,
+            is_directory: metadata.is_dir(),
+        };
+
+        let info = serde_json::to_vec_pretty(&entry)
+            .map_err(|e| FshError::ShellError(format!("Failed to record trash entry: {}", e)))?;
+        std::fs::write(self.info_path(&entry.id), info)?;
+
+        if let Err(e) = std::fs::rename(path, self.dir.join(&entry.id)) {
+            let _ = std::fs::remove_file(self.info_path(&entry.id));
+            return Err(FshError::ShellError(format!("Cannot delete '{}': {}", entry.original_path, e)));
+        }
+
+        Ok(entry)
+    }
+
+    /// Everything in the trash, newest first.
+    pub fn entries(&self) -> FshResult<Vec<TrashEntry>> {
+        let Ok(read_dir) = std::fs::read_dir(&self.dir) else {
+            return Ok(Vec::new()); // Nothing has been deleted yet
+        };
+
+        let mut entries: Vec<TrashEntry> = read_dir
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                entry.file_name().to_str()?.strip_suffix(INFO_SUFFIX)?;
+                let info = std::fs::read(entry.path()).ok()?;
+                serde_json::from_slice(&info).ok()
+            })
+            .collect();
+
+        entries.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(entries)
+    }
+
+    /// Put an item back where it was deleted from. `target` is a trash id or an
+    /// original path; for a path the most recently deleted copy is restored.
+    pub fn restore(&self, target: &str) -> FshResult<TrashEntry> {
+        let wanted = relative_string(Path::new(target.trim_start_matches(['/', '\\'])));
+        let entry = self.entries()?
+            .into_iter()
+            .find(|entry| entry.id == target || entry.original_path == wanted)
+            .ok_or_else(|| FshError::InvalidPath(format!("'{}' is not in the trash", target)))?;
+
+        let destination = self.root.join(&entry.original_path);
+        if destination.symlink_metadata().is_ok() {
+            return Err(FshError::ShellError(format!(
+                "Cannot restore '{}': the path already exists", entry.original_path
+            )));
+        }
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::rename(self.dir.join(&entry.id), &destination)
+            .map_err(|e| FshError::ShellError(format!("Cannot restore '{}': {}", entry.original_path, e)))?;
+        let _ = std::fs::remove_file(self.info_path(&entry.id));
+
+        Ok(entry)
+    }
+
+    /// Permanently remove items older than the retention period. Returns how many were removed.
+    pub fn purge_expired(&self) -> FshResult<usize> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = chrono::Utc::now() - chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+
+        let mut purged = 0;
+        for entry in self.entries()?.into_iter().filter(|entry| entry.deleted_at < cutoff) {
+            let data = self.dir.join(&entry.id);
+            let removed = match data.symlink_metadata() {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&data),
+                Ok(_) => std::fs::remove_file(&data),
+                Err(_) => Ok(()), // Data already gone; drop the stale record
+            };
+
+            if removed.is_ok() {
+                let _ = std::fs::remove_file(self.info_path(&entry.id));
+                purged += 1;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, INFO_SUFFIX))
+    }
+}
+
+/// `/`-separated form of a relative path, so records are portable between platforms.
+fn relative_string(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn trash_in(temp_dir: &TempDir) -> (PathBuf, Trash) {
+        let root = temp_dir.path().canonicalize().unwrap();
+        let trash = Trash::new(&root, &TrashConfig::default());
+        (root, trash)
+    }
+
+    #[test]
+    fn test_delete_and_restore() {
+        let temp_dir = TempDir::new().unwrap();
+        let (root, trash) = trash_in(&temp_dir);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+
+        let entry = trash.move_to_trash(&root.join("src/main.rs")).unwrap();
+        assert_eq!(entry.original_path, "src/main.rs");
+        assert!(!root.join("src/main.rs").exists());
+        assert_eq!(trash.entries().unwrap(), vec![entry.clone()]);
+
+        let restored = trash.restore("src/main.rs").unwrap();
+        assert_eq!(restored.id, entry.id);
+        assert_eq!(std::fs::read_to_string(root.join("src/main.rs")).unwrap(), "fn main() {}");
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_refuses_to_overwrite() {
+        let temp_dir = TempDir::new().unwrap();
+        let (root, trash) = trash_in(&temp_dir);
+        std::fs::write(root.join("notes.txt"), "old").unwrap();
+
+        let entry = trash.move_to_trash(&root.join("notes.txt")).unwrap();
+        std::fs::write(root.join("notes.txt"), "new").unwrap();
+
+        assert!(trash.restore(&entry.id).is_err());
+        assert_eq!(std::fs::read_to_string(root.join("notes.txt")).unwrap(), "new");
+        assert_eq!(trash.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_trash_itself_cannot_be_deleted() {
+        let temp_dir = TempDir::new().unwrap();
+        let (root, trash) = trash_in(&temp_dir);
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        trash.move_to_trash(&root.join("a.txt")).unwrap();
+
+        assert!(trash.move_to_trash(trash.dir()).is_err());
+        assert!(trash.move_to_trash(&root).is_err());
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let temp_dir = TempDir::new().unwrap();
+        let (root, trash) = trash_in(&temp_dir);
+        std::fs::create_dir(root.join("build")).unwrap();
+        std::fs::write(root.join("build/out.o"), "obj").unwrap();
+
+        let mut entry = trash.move_to_trash(&root.join("build")).unwrap();
+        assert!(entry.is_directory);
+        assert_eq!(trash.purge_expired().unwrap(), 0);
+
+        // Backdate the record past the retention period
+        entry.deleted_at -= chrono::Duration::days(8);
+        std::fs::write(trash.info_path(&entry.id), serde_json::to_vec(&entry).unwrap()).unwrap();
+
+        assert_eq!(trash.purge_expired().unwrap(), 1);
+        assert!(trash.entries().unwrap().is_empty());
+        assert!(!trash.dir().join(&entry.id).exists());
+    }
+}
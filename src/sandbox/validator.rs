@@ -1,9 +1,35 @@
-use std::path::{Path, PathBuf};
-use crate::protocol::{FshError, FshResult};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use crate::protocol::{FshError, FshResult, Permission};
+
+/// How symbolic links inside the sandbox are treated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Follow symlinks, but reject any whose target lies outside the sandbox.
+    #[default]
+    DenyEscape,
+    /// Never follow symlinks: paths that pass through one are rejected and
+    /// listings show the link itself rather than its target.
+    NoFollow,
+}
 
 #[derive(Debug, Clone)]
 pub struct PathValidator {
     root_path: PathBuf,
+    symlink_policy: SymlinkPolicy,
+    /// What may be done under `root_path`.
+    permissions: Vec<Permission>,
+    /// Other folders bound into the session, reachable as `/<name>`.
+    bound_roots: Vec<BoundRoot>,
+}
+
+/// A folder bound into a session next to its own, with its own permissions.
+#[derive(Debug, Clone)]
+struct BoundRoot {
+    name: String,
+    root_path: PathBuf,
+    permissions: Vec<Permission>,
 }
 
 impl PathValidator {
@@ -13,9 +39,117 @@ impl PathValidator {
 
         Ok(Self {
             root_path: canonical_root,
+            symlink_policy: SymlinkPolicy::default(),
+            permissions: vec![Permission::Read, Permission::Write, Permission::Execute],
+            bound_roots: Vec::new(),
         })
     }
 
+    pub fn with_permissions(mut self, permissions: Vec<Permission>) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Make `root_path` reachable as `/<name>`, with `permissions` applying to
+    /// everything under it.
+    pub fn bind_root(&mut self, name: &str, root_path: &Path, permissions: Vec<Permission>) -> FshResult<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(FshError::InvalidPath(format!("'{}' can't name a folder root", name)));
+        }
+        if self.bound_roots.iter().any(|bound| bound.name == name) {
+            return Err(FshError::InvalidPath(format!("/{} is already bound", name)));
+        }
+
+        let root_path = root_path.canonicalize()
+            .map_err(|e| FshError::InvalidPath(format!("Cannot canonicalize root path: {}", e)))?;
+        self.bound_roots.push(BoundRoot { name: name.to_string(), root_path, permissions });
+        Ok(())
+    }
+
+    /// Names of the bound folder roots, in the order they were bound.
+    pub fn bound_roots(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.bound_roots.iter().map(|bound| (bound.name.as_str(), bound.root_path.as_path()))
+    }
+
+    /// Check that `permission` is granted for `path`, a path returned by
+    /// [`validate_path`](Self::validate_path). Each bound root has its own
+    /// permissions; everything else has the session folder's.
+    pub fn check_access(&self, path: &Path, permission: &Permission) -> FshResult<()> {
+        let (name, permissions) = match self.bound_root_of(path) {
+            Some(bound) => (Some(bound.name.as_str()), &bound.permissions),
+            None => (None, &self.permissions),
+        };
+        if permissions.contains(permission) {
+            return Ok(());
+        }
+
+        Err(FshError::PermissionDenied(match name {
+            Some(name) => format!("{:?} permission denied in /{}", permission, name),
+            None => format!("{:?} permission denied", permission),
+        }))
+    }
+
+    /// Name and root of the bound root `path` is in, if it is in one.
+    pub fn bound_root(&self, path: &Path) -> Option<(&str, &Path)> {
+        self.bound_root_of(path).map(|bound| (bound.name.as_str(), bound.root_path.as_path()))
+    }
+
+    /// Whether `path` is the session folder's root or a bound one.
+    pub fn is_root(&self, path: &Path) -> bool {
+        path == self.root_path || self.bound_roots.iter().any(|bound| path == bound.root_path)
+    }
+
+    /// The bound root `path` is in, unless the session folder's root is a
+    /// closer ancestor.
+    fn bound_root_of(&self, path: &Path) -> Option<&BoundRoot> {
+        let bound = self.bound_roots.iter()
+            .filter(|bound| path.starts_with(&bound.root_path))
+            .max_by_key(|bound| bound.root_path.components().count())?;
+        let closer_to_own_root = path.starts_with(&self.root_path)
+            && self.root_path.components().count() > bound.root_path.components().count();
+        (!closer_to_own_root).then_some(bound)
+    }
+
+    /// The root `path` lies in, if any.
+    fn containing_root(&self, path: &Path) -> Option<&Path> {
+        match self.bound_root_of(path) {
+            Some(bound) => Some(&bound.root_path),
+            None => path.starts_with(&self.root_path).then_some(self.root_path.as_path()),
+        }
+    }
+
+    /// Map `/<name>/...` onto the bound root called `name`. Paths under the
+    /// session folder's own root are left alone, even if they start the same way.
+    fn map_bound_root(&self, normalized_path: &Path) -> Option<PathBuf> {
+        if normalized_path.starts_with(&self.root_path) {
+            return None;
+        }
+
+        let mut components = normalized_path.components();
+        if components.next() != Some(Component::RootDir) {
+            return None;
+        }
+        let Some(Component::Normal(name)) = components.next() else {
+            return None;
+        };
+        let bound = self.bound_roots.iter().find(|bound| name == bound.name.as_str())?;
+        Some(bound.root_path.join(components.as_path()))
+    }
+
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// Resolve `path` against the sandbox root and check that it stays inside it.
+    ///
+    /// The path does not need to exist: `.` and `..` are resolved lexically, the
+    /// deepest existing ancestor is canonicalized, and the missing components
+    /// are appended to it. This lets callers create new files and directories.
     pub fn validate_path(&self, path: &str) -> FshResult<PathBuf> {
         let requested_path = Path::new(path);
 
@@ -26,12 +160,17 @@ impl PathValidator {
             self.root_path.join(requested_path)
         };
 
-        // Canonicalize to resolve .. and . components
-        let canonical_path = absolute_path.canonicalize()
+        let normalized_path = normalize_lexically(&absolute_path);
+        let normalized_path = self.map_bound_root(&normalized_path).unwrap_or(normalized_path);
+        if self.symlink_policy == SymlinkPolicy::NoFollow {
+            self.reject_symlinks(&normalized_path, path)?;
+        }
+
+        let canonical_path = canonicalize_existing_prefix(&normalized_path)
             .map_err(|e| FshError::InvalidPath(format!("Cannot resolve path '{}': {}", path, e)))?;
 
-        // Check if the canonical path is within the allowed root
-        if !canonical_path.starts_with(&self.root_path) {
+        // Check if the canonical path is within one of the allowed roots
+        if self.containing_root(&canonical_path).is_none() {
             return Err(FshError::PermissionDenied(
                 format!("Path '{}' is outside the allowed directory", path)
             ));
@@ -40,6 +179,42 @@ impl PathValidator {
         Ok(canonical_path)
     }
 
+    /// Metadata to report for a directory entry, or `None` if the entry must be
+    /// hidden because it is a symlink escaping the sandbox (or a dangling one).
+    pub fn entry_metadata(&self, path: &Path) -> Option<std::fs::Metadata> {
+        let metadata = path.symlink_metadata().ok()?;
+        if !metadata.file_type().is_symlink() || self.symlink_policy == SymlinkPolicy::NoFollow {
+            return Some(metadata);
+        }
+
+        let target = path.canonicalize().ok()?;
+        self.containing_root(&target)?;
+        target.metadata().ok()
+    }
+
+    fn reject_symlinks(&self, normalized_path: &Path, requested: &str) -> FshResult<()> {
+        let Some(root) = self.containing_root(normalized_path) else {
+            return Ok(()); // Outside the roots entirely; the containment check rejects it
+        };
+        let relative = normalized_path.strip_prefix(root).unwrap_or(normalized_path);
+
+        let mut current = root.to_path_buf();
+        for component in relative.components() {
+            current.push(component);
+            match current.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(FshError::PermissionDenied(
+                        format!("Path '{}' goes through a symbolic link", requested)
+                    ));
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn validate_command_path(&self, command: &str) -> FshResult<String> {
         // Check for dangerous path traversal patterns
         let dangerous_patterns = ["../", "..\\", "/../../", "\\..\\..\\"];
@@ -70,7 +245,14 @@ impl PathValidator {
         Ok(command.to_string())
     }
 
+    /// `absolute_path` relative to the session folder's root, or as
+    /// `/<name>/...` when it is in a bound root.
     pub fn get_relative_path(&self, absolute_path: &Path) -> FshResult<PathBuf> {
+        if let Some(bound) = self.bound_root_of(absolute_path) {
+            let relative = absolute_path.strip_prefix(&bound.root_path).unwrap_or(Path::new(""));
+            return Ok(Path::new("/").join(&bound.name).join(relative));
+        }
+
         absolute_path.strip_prefix(&self.root_path)
             .map(|p| p.to_path_buf())
             .map_err(|_| FshError::InvalidPath(
@@ -114,17 +296,64 @@ impl PathValidator {
     }
 
     pub fn sanitize_output_path(&self, output: &str) -> String {
-        let root_str = self.root_path.to_string_lossy();
+        // Replace absolute paths with relative or virtual ones in output,
+        // longest root first so one that extends another is named correctly
+        let mut roots: Vec<(&Path, String)> = self.bound_roots.iter()
+            .map(|bound| (bound.root_path.as_path(), format!("/{}", bound.name)))
+            .collect();
+        roots.push((&self.root_path, ".".to_string()));
+        roots.sort_by_key(|(root, _)| std::cmp::Reverse(root.as_os_str().len()));
+
+        roots.into_iter().fold(output.to_string(), |output, (root, replacement)| {
+            output.replace(&*root.to_string_lossy(), &replacement)
+        })
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem.
+/// `..` at the root stays at the root, matching how the OS treats it.
+pub(crate) fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::Normal(_) => normalized.push(component),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if normalized.file_name().is_some() {
+                    normalized.pop();
+                }
+            }
+        }
+    }
+
+    normalized
+}
 
-        // Replace absolute paths with relative ones in output
-        output.replace(&root_str.to_string(), ".")
+/// Canonicalize the deepest ancestor of `path` that exists and re-append the
+/// components below it. `path` must already be lexically normalized.
+fn canonicalize_existing_prefix(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    while existing.symlink_metadata().is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
     }
+
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
     use tempfile::TempDir;
 
     #[test]
@@ -141,6 +370,82 @@ mod tests {
         assert!(invalid_path.is_err());
     }
 
+    #[test]
+    fn test_validate_new_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let validator = PathValidator::new(temp_dir.path().to_path_buf()).unwrap();
+        std::fs::create_dir(temp_dir.path().join("src")).unwrap();
+
+        // New file in an existing directory, and in directories that don't exist yet
+        let new_file = validator.validate_path("src/new.rs").unwrap();
+        assert_eq!(new_file, validator.root_path().join("src").join("new.rs"));
+        let nested = validator.validate_path("a/b/./c/../d.txt").unwrap();
+        assert_eq!(nested, validator.root_path().join("a").join("b").join("d.txt"));
+
+        // Traversal through a missing directory still can't leave the root
+        assert!(validator.validate_path("missing/../../outside.txt").is_err());
+        assert_eq!(validator.validate_path("src/..").unwrap(), validator.root_path());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        std::fs::write(outside.path().join("secret"), "x").unwrap();
+        std::fs::create_dir(temp_dir.path().join("real")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), temp_dir.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("real"), temp_dir.path().join("inner")).unwrap();
+
+        let validator = PathValidator::new(temp_dir.path().to_path_buf()).unwrap();
+        assert!(validator.validate_path("escape/secret").is_err());
+        assert!(validator.validate_path("escape/new.txt").is_err());
+        assert!(validator.validate_path("inner/file.txt").is_ok());
+        assert!(validator.entry_metadata(&temp_dir.path().join("escape")).is_none());
+        assert!(validator.entry_metadata(&temp_dir.path().join("inner")).unwrap().is_dir());
+
+        let no_follow = validator.with_symlink_policy(SymlinkPolicy::NoFollow);
+        assert!(no_follow.validate_path("inner/file.txt").is_err());
+        assert!(no_follow.validate_path("real/file.txt").is_ok());
+        assert!(no_follow.entry_metadata(&temp_dir.path().join("escape")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn test_bound_roots() {
+        let temp_dir = TempDir::new().unwrap();
+        let docs = TempDir::new().unwrap();
+        std::fs::write(docs.path().join("guide.md"), "x").unwrap();
+        let mut validator = PathValidator::new(temp_dir.path().to_path_buf()).unwrap()
+            .with_permissions(vec![Permission::Read, Permission::Write]);
+        validator.bind_root("docs", docs.path(), vec![Permission::Read]).unwrap();
+        assert!(validator.bind_root("docs", docs.path(), Vec::new()).is_err());
+        assert!(validator.bind_root("../up", docs.path(), Vec::new()).is_err());
+
+        let guide = validator.validate_path("/docs/guide.md").unwrap();
+        assert_eq!(guide, docs.path().canonicalize().unwrap().join("guide.md"));
+        assert_eq!(validator.get_relative_path(&guide).unwrap(), Path::new("/docs/guide.md"));
+        assert!(validator.is_root(&validator.validate_path("/docs").unwrap()));
+        assert!(validator.validate_path("/docs/../../etc/passwd").is_err());
+        assert!(validator.validate_path("/other/file").is_err());
+
+        // Each root has its own permissions
+        assert!(validator.check_access(&guide, &Permission::Read).is_ok());
+        assert!(matches!(validator.check_access(&guide, &Permission::Write), Err(FshError::PermissionDenied(_))));
+        let own = validator.validate_path("notes.md").unwrap();
+        assert!(validator.check_access(&own, &Permission::Write).is_ok());
+        assert!(validator.check_access(&own, &Permission::Execute).is_err());
+
+        let output = format!("{}/a and {}/b", validator.root_path().display(), docs.path().canonicalize().unwrap().display());
+        assert_eq!(validator.sanitize_output_path(&output), "./a and /docs/b");
+    }
+
+    #[test]
+    fn test_normalize_lexically() {
+        assert_eq!(normalize_lexically(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+        assert_eq!(normalize_lexically(Path::new("/../a")), PathBuf::from("/a"));
+        assert_eq!(normalize_lexically(Path::new("a/../../b")), PathBuf::from("b"));
+    }
+
     #[test]
     fn test_command_validation() {
         let temp_dir = TempDir::new().unwrap();
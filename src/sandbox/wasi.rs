@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::{FshError, FshResult};
+
+#[cfg(feature = "wasi")]
+pub use runtime::*;
+
+/// Commands implemented by WebAssembly modules, run in-process under
+/// wasmtime with nothing but the folder to see. Needs the `wasi` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WasiConfig {
+    pub enabled: bool,
+    /// Command name to the WASI (preview 1) module that implements it.
+    pub tools: BTreeMap<String, PathBuf>,
+    /// Fuel (roughly, instructions) a run may use before it is stopped.
+    pub fuel: Option<u64>,
+    pub max_memory_mb: u64,
+}
+
+impl Default for WasiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tools: BTreeMap::new(),
+            fuel: None,
+            max_memory_mb: 256,
+        }
+    }
+}
+
+impl WasiConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !cfg!(feature = "wasi") {
+            return Err(FshError::ConfigError("wasi tools need fsh built with the 'wasi' feature".to_string()));
+        }
+        if self.max_memory_mb == 0 {
+            return Err(FshError::ConfigError("wasi.max_memory_mb must be greater than 0".to_string()));
+        }
+        for (name, module) in &self.tools {
+            if name.is_empty() || name.contains(['/', '\\']) || name.contains(char::is_whitespace) {
+                return Err(FshError::ConfigError(format!("Invalid wasi tool name '{}'", name)));
+            }
+            if !module.is_file() {
+                return Err(FshError::ConfigError(format!(
+                    "Module for wasi tool '{}' not found: {}", name, module.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The module that runs `command`, if it is a WASI tool.
+    pub fn tool(&self, command: &str) -> Option<&Path> {
+        if !self.enabled {
+            return None;
+        }
+        self.tools.get(command).map(PathBuf::as_path)
+    }
+}
+
+#[cfg(feature = "wasi")]
+mod runtime {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::SystemTime;
+    use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap, UpdateDeadline};
+    use wasmtime_wasi::pipe::MemoryOutputPipe;
+    use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+    use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+    use super::WasiConfig;
+    use crate::protocol::{FshError, FshResult};
+
+    /// Output kept from each of a tool's stdout and stderr.
+    const MAX_TOOL_OUTPUT: usize = 16 * 1024 * 1024;
+
+    /// Shared by every run, so one kill only interrupts stores that asked to
+    /// be.
+    fn engine() -> &'static Engine {
+        static ENGINE: OnceLock<Engine> = OnceLock::new();
+        ENGINE.get_or_init(|| {
+            let mut config = Config::new();
+            config.consume_fuel(true).epoch_interruption(true);
+            Engine::new(&config).expect("wasmtime configuration is valid")
+        })
+    }
+
+    /// Compiled modules by path, recompiled when the file changes.
+    fn load_module(path: &Path) -> FshResult<Module> {
+        static MODULES: OnceLock<Mutex<HashMap<PathBuf, (SystemTime, Module)>>> = OnceLock::new();
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| FshError::io(format!("Failed to read {}", path.display()), e))?;
+
+        let mut modules = MODULES.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((compiled_at, module)) = modules.get(path) {
+            if *compiled_at == modified {
+                return Ok(module.clone());
+            }
+        }
+        let module = Module::from_file(engine(), path)
+            .map_err(|e| FshError::ShellError(format!("Failed to load {}: {}", path.display(), e)))?;
+        modules.insert(path.to_path_buf(), (modified, module.clone()));
+        Ok(module)
+    }
+
+    /// Stops a running tool the next time it checks in.
+    #[derive(Debug, Clone, Default)]
+    pub struct WasiKillSwitch(Arc<AtomicBool>);
+
+    impl WasiKillSwitch {
+        pub fn kill(&self) {
+            self.0.store(true, Ordering::SeqCst);
+            engine().increment_epoch();
+        }
+    }
+
+    /// A finished tool run.
+    #[derive(Debug, Clone)]
+    pub struct WasiOutput {
+        pub exit_code: i32,
+        pub stdout: Vec<u8>,
+        pub stderr: Vec<u8>,
+    }
+
+    /// One tool run: the folder is `/` to the tool and the working
+    /// directory `.`.
+    #[derive(Debug, Clone)]
+    pub struct WasiCommand {
+        pub module: PathBuf,
+        /// Including the program name.
+        pub args: Vec<String>,
+        pub env: Vec<(String, String)>,
+        pub root: PathBuf,
+        pub working_dir: PathBuf,
+        pub writable: bool,
+    }
+
+    struct ToolState {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+    }
+
+    impl WasiCommand {
+        /// Run the tool to completion. Blocks, so call it off the async
+        /// runtime.
+        pub fn run(&self, config: &WasiConfig, kill: &WasiKillSwitch) -> FshResult<WasiOutput> {
+            let module = load_module(&self.module)?;
+            let (dir_perms, file_perms) = if self.writable {
+                (DirPerms::all(), FilePerms::all())
+            } else {
+                (DirPerms::READ, FilePerms::READ)
+            };
+
+            let stdout = MemoryOutputPipe::new(MAX_TOOL_OUTPUT);
+            let stderr = MemoryOutputPipe::new(MAX_TOOL_OUTPUT);
+            let mut wasi = WasiCtxBuilder::new();
+            wasi.args(&self.args)
+                .envs(&self.env)
+                .stdout(stdout.clone())
+                .stderr(stderr.clone())
+                .preopened_dir(&self.root, "/", dir_perms, file_perms)
+                .and_then(|wasi| wasi.preopened_dir(&self.working_dir, ".", dir_perms, file_perms))
+                .map_err(|e| FshError::ShellError(format!("Failed to open the folder for {}: {}", self.args[0], e)))?;
+
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(config.max_memory_mb as usize * 1024 * 1024)
+                .build();
+            let mut store = Store::new(engine(), ToolState { wasi: wasi.build_p1(), limits });
+            store.limiter(|state| &mut state.limits);
+            store.set_fuel(config.fuel.unwrap_or(u64::MAX)).map_err(wasm_error)?;
+            store.set_epoch_deadline(1);
+            let killed = kill.0.clone();
+            store.epoch_deadline_callback(move |_| {
+                if killed.load(Ordering::SeqCst) {
+                    Err(wasmtime::Error::msg("killed"))
+                } else {
+                    Ok(UpdateDeadline::Continue(1))
+                }
+            });
+
+            let mut linker = Linker::new(engine());
+            preview1::add_to_linker_sync(&mut linker, |state: &mut ToolState| &mut state.wasi).map_err(wasm_error)?;
+            let outcome = linker.instantiate(&mut store, &module)
+                .and_then(|instance| instance.get_typed_func::<(), ()>(&mut store, "_start"))
+                .and_then(|start| start.call(&mut store, ()));
+
+            let mut stderr_text = Vec::new();
+            let exit_code = match outcome {
+                Ok(()) => 0,
+                Err(e) => match e.downcast_ref::<I32Exit>() {
+                    Some(exit) => exit.0,
+                    None => {
+                        let reason = match e.downcast_ref::<Trap>() {
+                            Some(Trap::OutOfFuel) => "ran out of fuel".to_string(),
+                            _ if kill.0.load(Ordering::SeqCst) => "killed".to_string(),
+                            _ => format!("failed: {:#}", e),
+                        };
+                        stderr_text = format!("{}: {}\n", self.args[0], reason).into_bytes();
+                        -1
+                    }
+                },
+            };
+            drop(store);
+
+            let mut stderr = stderr.contents().to_vec();
+            stderr.extend(stderr_text);
+            Ok(WasiOutput { exit_code, stdout: stdout.contents().to_vec(), stderr })
+        }
+    }
+
+    fn wasm_error(e: wasmtime::Error) -> FshError {
+        FshError::ShellError(format!("WASI runtime error: {:#}", e))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tempfile::TempDir;
+
+        // Writes "hello\n" to stdout and exits with 3
+        const HELLO: &str = r#"(module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "hello\n")
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 6))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (call $proc_exit (i32.const 3))))"#;
+
+        const SPIN: &str = r#"(module (func (export "_start") (loop (br 0))))"#;
+
+        fn command(dir: &TempDir, name: &str, source: &str) -> WasiCommand {
+            let module = dir.path().join(format!("{}.wat", name));
+            std::fs::write(&module, source).unwrap();
+            WasiCommand {
+                module,
+                args: vec![name.to_string()],
+                env: Vec::new(),
+                root: dir.path().to_path_buf(),
+                working_dir: dir.path().to_path_buf(),
+                writable: false,
+            }
+        }
+
+        #[test]
+        fn test_tool_output_and_limits() {
+            let dir = TempDir::new().unwrap();
+            let config = WasiConfig { enabled: true, fuel: Some(1_000_000), ..WasiConfig::default() };
+
+            let output = command(&dir, "hello", HELLO).run(&config, &WasiKillSwitch::default()).unwrap();
+            assert_eq!(output.exit_code, 3);
+            assert_eq!(output.stdout, b"hello\n");
+
+            let output = command(&dir, "spin", SPIN).run(&config, &WasiKillSwitch::default()).unwrap();
+            assert_eq!(output.exit_code, -1);
+            assert_eq!(output.stderr, b"spin: ran out of fuel\n");
+        }
+    }
+}
@@ -1,11 +1,14 @@
 use crate::config::SecurityConfig;
 use crate::protocol::{FshError, FshResult};
+use crate::security::{DlpMatch, LockoutKey};
 use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
+use async_trait::async_trait;
 use tokio::sync::Mutex;
 use tracing::debug;
 
@@ -32,7 +35,38 @@ pub enum SecurityEventType {
     PermissionDenied,
     SuspiciousActivity,
     IpBlocked,
+    /// A user or token was locked after failed authentication attempts.
+    AccountLocked,
     RateLimitExceeded,
+    /// Anything a client does in a honeypot folder. Always logged.
+    HoneypotActivity,
+    /// A session closed because its token was revoked or expired.
+    SessionRevoked,
+    /// Recorded by a server plugin; `details` starts with the plugin's name.
+    PluginEvent,
+    /// A written file was infected, or could not be scanned, and was quarantined.
+    MalwareDetected,
+    /// A data loss prevention rule matched data on its way to the client.
+    DlpMatch,
+    /// A forwarded connection was opened or closed.
+    PortForward,
+    /// A folder's scheduled task ran; `resource` is `<folder>/<task>`.
+    ScheduledTask,
+}
+
+/// The server's audit logger together with the address of the client a
+/// session belongs to.
+#[derive(Debug, Clone)]
+pub struct ClientAudit {
+    pub logger: Arc<AuditLogger>,
+    pub source_ip: IpAddr,
+}
+
+/// Somewhere audit events are sent besides the log file, e.g. an embedding
+/// application's own audit trail.
+#[async_trait]
+pub trait AuditSink: Send + Sync + std::fmt::Debug {
+    async fn record(&self, event: &SecurityEvent) -> FshResult<()>;
 }
 
 #[derive(Debug)]
@@ -40,6 +74,7 @@ pub struct AuditLogger {
     log_file: Option<PathBuf>,
     enabled: bool,
     file_mutex: Mutex<()>,
+    sinks: Vec<Arc<dyn AuditSink>>,
 }
 
 impl AuditLogger {
@@ -48,11 +83,18 @@ impl AuditLogger {
             log_file: config.log_file.clone(),
             enabled: config.enable_logging,
             file_mutex: Mutex::new(()),
+            sinks: Vec::new(),
         })
     }
 
+    /// Also send every logged event to `sink`.
+    pub fn with_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
     pub async fn log_security_event(&self, event: SecurityEvent) -> FshResult<()> {
-        if !self.enabled {
+        if !self.enabled && !matches!(event.event_type, SecurityEventType::HoneypotActivity) {
             return Ok(());
         }
 
@@ -63,11 +105,28 @@ impl AuditLogger {
             self.log_to_file(log_file, &event).await?;
         }
 
+        for sink in &self.sinks {
+            sink.record(&event).await?;
+        }
+
         // Log to system logger based on severity
         match event.event_type {
+            SecurityEventType::HoneypotActivity => {
+                tracing::error!(
+                    source_ip = %event.source_ip,
+                    session_id = ?event.session_id,
+                    resource = ?event.resource,
+                    details = %event.details,
+                    "Honeypot activity"
+                );
+            }
             SecurityEventType::SuspiciousActivity |
             SecurityEventType::PermissionDenied |
-            SecurityEventType::IpBlocked => {
+            SecurityEventType::IpBlocked |
+            SecurityEventType::AccountLocked |
+            SecurityEventType::SessionRevoked |
+            SecurityEventType::MalwareDetected |
+            SecurityEventType::DlpMatch => {
                 tracing::warn!(
                     event_type = ?event.event_type,
                     source_ip = %event.source_ip,
@@ -146,6 +205,21 @@ impl AuditLogger {
         self.log_security_event(event).await
     }
 
+    /// A connection refused before its handshake, e.g. over a connection limit.
+    pub async fn log_connection_rejected(&self, source_ip: IpAddr, reason: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::ConnectionAttempt,
+            source_ip,
+            session_id: None,
+            user_id: None,
+            resource: None,
+            details: format!("Connection rejected: {}", reason),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
     pub async fn log_authentication_attempt(&self, source_ip: IpAddr, user_id: Option<String>, success: bool, details: String) -> FshResult<()> {
         let event = SecurityEvent {
             event_type: if success { SecurityEventType::AuthenticationSuccess } else { SecurityEventType::AuthenticationFailure },
@@ -188,6 +262,77 @@ impl AuditLogger {
         self.log_security_event(event).await
     }
 
+    pub async fn log_macro_step(&self, source_ip: IpAddr, session_id: String, macro_name: &str, command: String) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::CommandExecution,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(command.clone()),
+            details: format!("Executed command from macro '{}': {}", macro_name, command),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    /// `participant` joined another client's session with interactive input.
+    pub async fn log_participant_joined(&self, source_ip: IpAddr, session_id: String, participant: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::SessionEstablished,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: Some(participant.to_string()),
+            resource: None,
+            details: format!("Participant '{}' joined the session", participant),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    /// A command typed by `participant` into another client's session.
+    pub async fn log_participant_command(
+        &self,
+        source_ip: IpAddr,
+        session_id: String,
+        participant: &str,
+        command: String,
+    ) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::CommandExecution,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: Some(participant.to_string()),
+            resource: Some(command.clone()),
+            details: format!("Participant '{}' executed command: {}", participant, command),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_honeypot_activity(
+        &self,
+        source_ip: IpAddr,
+        session_id: String,
+        folder: &str,
+        action: &str,
+        detail: &str,
+    ) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::HoneypotActivity,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(detail.to_string()),
+            details: format!("Honeypot folder '{}': {} {}", folder, action, detail),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
     pub async fn log_file_access(&self, source_ip: IpAddr, session_id: String, file_path: String, operation: String) -> FshResult<()> {
         let event = SecurityEvent {
             event_type: SecurityEventType::FileAccess,
@@ -230,14 +375,139 @@ impl AuditLogger {
         self.log_security_event(event).await
     }
 
-    pub async fn log_rate_limit_exceeded(&self, source_ip: IpAddr) -> FshResult<()> {
+    /// Record that `key` was locked out by repeated failures from `source_ip`.
+    pub async fn log_lockout(&self, source_ip: IpAddr, key: &LockoutKey) -> FshResult<()> {
+        let event_type = match key {
+            LockoutKey::Ip(_) => SecurityEventType::IpBlocked,
+            LockoutKey::User(_) | LockoutKey::Token(_) => SecurityEventType::AccountLocked,
+        };
         let event = SecurityEvent {
-            event_type: SecurityEventType::RateLimitExceeded,
+            event_type,
             source_ip,
             session_id: None,
             user_id: None,
+            resource: Some(key.to_string()),
+            details: format!("Locked out {} after repeated failed authentication attempts", key),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_session_revoked(&self, source_ip: IpAddr, session_id: String, token_id: &str, reason: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::SessionRevoked,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(format!("token:{}", token_id)),
+            details: reason.to_string(),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_rate_limit_exceeded(&self, source_ip: IpAddr, session_id: Option<String>, details: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::RateLimitExceeded,
+            source_ip,
+            session_id,
+            user_id: None,
             resource: None,
-            details: "Rate limit exceeded".to_string(),
+            details: details.to_string(),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_malware_detected(
+        &self,
+        source_ip: IpAddr,
+        session_id: String,
+        file_path: String,
+        verdict: &str,
+        quarantined_as: &Path,
+    ) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::MalwareDetected,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(file_path),
+            details: format!("{}; quarantined as {}", verdict, quarantined_as.display()),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_dlp_match(
+        &self,
+        source_ip: IpAddr,
+        session_id: String,
+        resource: String,
+        found: &DlpMatch,
+    ) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::DlpMatch,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(resource),
+            details: format!("Rule '{}' matched {} time(s); action: {}", found.rule, found.count, found.action.name()),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_port_forward(&self, source_ip: IpAddr, session_id: String, target: &str, details: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::PortForward,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource: Some(target.to_string()),
+            details: format!("Port forward to {}: {}", target, details),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    /// Scheduled tasks run on the server itself, so they are logged as
+    /// coming from the loopback address.
+    pub async fn log_scheduled_task(&self, folder: &str, task: &str, details: &str) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::ScheduledTask,
+            source_ip: IpAddr::from([127, 0, 0, 1]),
+            session_id: None,
+            user_id: None,
+            resource: Some(format!("{}/{}", folder, task)),
+            details: details.to_string(),
+            timestamp: SystemTime::now(),
+        };
+
+        self.log_security_event(event).await
+    }
+
+    pub async fn log_plugin_event(
+        &self,
+        source_ip: IpAddr,
+        session_id: String,
+        plugin: &str,
+        resource: Option<String>,
+        details: &str,
+    ) -> FshResult<()> {
+        let event = SecurityEvent {
+            event_type: SecurityEventType::PluginEvent,
+            source_ip,
+            session_id: Some(session_id),
+            user_id: None,
+            resource,
+            details: format!("{}: {}", plugin, details),
             timestamp: SystemTime::now(),
         };
 
@@ -268,6 +538,11 @@ mod tests {
             max_failed_attempts: 3,
             enable_logging: true,
             log_file: Some(temp_file.path().to_path_buf()),
+            rate_limits: crate::security::RateLimitConfig::default(),
+            lockout: crate::security::LockoutConfig::default(),
+            tokens: vec![],
+            token_store: None,
+            development_mode: false,
         };
 
         let logger = AuditLogger::new(&config).unwrap();
@@ -317,6 +592,11 @@ mod tests {
             max_failed_attempts: 3,
             enable_logging: false,
             log_file: None,
+            rate_limits: crate::security::RateLimitConfig::default(),
+            lockout: crate::security::LockoutConfig::default(),
+            tokens: vec![],
+            token_store: None,
+            development_mode: false,
         };
 
         let logger = AuditLogger::new(&config).unwrap();
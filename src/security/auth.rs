@@ -1,32 +1,135 @@
 use crate::config::SecurityConfig;
 use crate::protocol::{FshError, FshResult};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
 use uuid::Uuid;
 
+use super::TokenStore;
+
 #[derive(Debug)]
 pub struct AuthManager {
     auth_methods: Vec<String>,
     tokens: HashMap<String, TokenInfo>,
     sessions: HashMap<String, SessionInfo>,
+    store: Option<Arc<dyn TokenStore>>,
+    /// Ids of tokens cached from the store.
+    store_tokens: HashSet<String>,
+    /// Hashes of store tokens revoked here, which the store may still have.
+    revoked_hashes: HashSet<String>,
+}
+
+/// A token provisioned in the config file. Only its SHA-256 is stored, and
+/// that may itself be encrypted with the config master key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenConfig {
+    pub description: String,
+    /// Hex SHA-256 of the token, as printed by `fsh-server secrets hash-token`.
+    pub hash: String,
+    #[serde(default = "TokenConfig::default_permissions")]
+    pub permissions: Vec<crate::protocol::Permission>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The user the token logs in as, limiting it to the folders of its
+    /// `[[users.accounts]]` entry.
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl TokenConfig {
+    pub(crate) fn default_permissions() -> Vec<crate::protocol::Permission> {
+        vec![
+            crate::protocol::Permission::Read,
+            crate::protocol::Permission::Write,
+            crate::protocol::Permission::Execute,
+        ]
+    }
+
+    /// A new random token with full permissions, returned alongside the
+    /// entry that stores its hash. The token itself can't be recovered later.
+    pub fn generate(description: impl Into<String>) -> (Self, String) {
+        let token = AuthManager::generate_secure_token();
+        let config = Self {
+            description: description.into(),
+            hash: AuthManager::hash_token(&token),
+            permissions: Self::default_permissions(),
+            expires_at: None,
+            user: None,
+        };
+        (config, token)
+    }
 }
 
 #[derive(Debug, Clone)]
-struct TokenInfo {
-    token_hash: String,
-    created_at: SystemTime,
-    expires_at: Option<SystemTime>,
-    permissions: Vec<crate::protocol::Permission>,
-    description: String,
+pub struct TokenInfo {
+    pub token_hash: String,
+    pub created_at: SystemTime,
+    pub expires_at: Option<SystemTime>,
+    pub permissions: Vec<crate::protocol::Permission>,
+    pub description: String,
+    /// The only folder the token may bind, for share links.
+    pub folder: Option<String>,
+    /// Removed once a session has been opened with it.
+    pub single_use: bool,
+    pub user: Option<String>,
+}
+
+/// A token as shown to operators, without its hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenSummary {
+    pub id: String,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub permissions: Vec<crate::protocol::Permission>,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub single_use: bool,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// What a session opened with a token may reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenScope {
+    pub permissions: Vec<crate::protocol::Permission>,
+    /// The only folder the token may bind, for share links.
+    pub folder: Option<String>,
+    /// The user the token logs in as.
+    pub user: Option<String>,
+}
+
+impl TokenScope {
+    pub fn allows_folder(&self, folder: &str) -> bool {
+        self.folder.as_deref().is_none_or(|allowed| allowed == folder)
+    }
+
+    /// Drop the permissions the token doesn't grant.
+    pub fn restrict(&self, permissions: &mut Vec<crate::protocol::Permission>) {
+        permissions.retain(|permission| self.permissions.contains(permission));
+    }
+
+    /// Limit a session's copy of `folder` to the token. Tokens that can't
+    /// run commands can't forward ports either.
+    pub fn restrict_folder(&self, folder: &mut crate::config::FolderConfig) {
+        self.restrict(&mut folder.permissions);
+        if !folder.can_execute() {
+            folder.forward_ports.clear();
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
-struct SessionInfo {
-    user_id: String,
-    created_at: SystemTime,
-    last_activity: SystemTime,
-    client_ip: std::net::IpAddr,
+pub struct SessionInfo {
+    pub user_id: String,
+    pub created_at: SystemTime,
+    pub last_activity: SystemTime,
+    pub client_ip: std::net::IpAddr,
 }
 
 impl AuthManager {
@@ -35,10 +138,22 @@ impl AuthManager {
             auth_methods: config.auth_methods.clone(),
             tokens: HashMap::new(),
             sessions: HashMap::new(),
+            store: None,
+            store_tokens: HashSet::new(),
+            revoked_hashes: HashSet::new(),
         };
 
-        // Create a default token for development/testing
-        if config.auth_methods.contains(&"token".to_string()) {
+        // Managed deployments keep their tokens in the store alone
+        if let Some(store_config) = &config.token_store {
+            return Ok(auth_manager.with_store(store_config.build()?));
+        }
+
+        let token_auth = config.auth_methods.contains(&"token".to_string());
+        if token_auth && config.development_mode {
+            tracing::warn!(
+                "DEVELOPMENT MODE: the token \"default\" grants full access to every folder; \
+                 never enable development_mode on a reachable server"
+            );
             auth_manager.create_token(
                 "default",
                 None,
@@ -51,13 +166,134 @@ impl AuthManager {
             )?;
         }
 
+        for token in &config.tokens {
+            auth_manager.add_token_hash(token)?;
+        }
+
         Ok(auth_manager)
     }
 
+    /// Validate tokens not known locally against `store`.
+    pub fn with_store(mut self, store: Arc<dyn TokenStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Validate a token and return its id, asking the token store about
+    /// tokens not seen before and caching the ones it knows.
+    pub async fn authenticate_token(auth_manager: &RwLock<Self>, token: &str) -> FshResult<String> {
+        let store = {
+            let auth_manager = auth_manager.read().await;
+            match (auth_manager.token_id(token), &auth_manager.store) {
+                (Ok(token_id), _) => return Ok(token_id),
+                (Err(_), Some(store)) => Arc::clone(store),
+                (Err(e), None) => return Err(e),
+            }
+        };
+
+        let token_hash = Self::hash_token(token);
+        if auth_manager.read().await.revoked_hashes.contains(&token_hash) {
+            return Err(FshError::AuthenticationFailed);
+        }
+        // Clients only learn that the token was refused, not why the store failed
+        let stored = store.lookup(&token_hash).await
+            .map_err(|e| {
+                tracing::warn!("Token store lookup failed: {}", e);
+                FshError::AuthenticationFailed
+            })?
+            .ok_or(FshError::AuthenticationFailed)?;
+        if stored.expires_at.is_some_and(|expires_at| expires_at < Utc::now()) {
+            return Err(FshError::AuthenticationFailed);
+        }
+
+        let mut auth_manager = auth_manager.write().await;
+        // Another connection may have cached it while the store was asked
+        if let Ok(token_id) = auth_manager.token_id(token) {
+            return Ok(token_id);
+        }
+        let token_id = auth_manager.add_token_hash(&stored)?;
+        auth_manager.store_tokens.insert(token_id.clone());
+        Ok(token_id)
+    }
+
+    /// Ask the store again about cached tokens, updating them and removing
+    /// the ones it no longer has. Returns the ids of the removed tokens.
+    /// Tokens are kept when the store can't be reached.
+    pub async fn refresh_store_tokens(auth_manager: &RwLock<Self>) -> Vec<String> {
+        let (store, cached) = {
+            let auth_manager = auth_manager.read().await;
+            let Some(store) = auth_manager.store.clone() else {
+                return Vec::new();
+            };
+            let cached: Vec<(String, String)> = auth_manager.store_tokens.iter()
+                .filter_map(|id| auth_manager.tokens.get(id).map(|info| (id.clone(), info.token_hash.clone())))
+                .collect();
+            (store, cached)
+        };
+
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for (token_id, token_hash) in cached {
+            match store.lookup(&token_hash).await {
+                Ok(Some(stored)) => updated.push((token_id, stored)),
+                Ok(None) => removed.push(token_id),
+                Err(e) => tracing::warn!("Could not refresh token {}: {}", token_id, e),
+            }
+        }
+
+        let mut auth_manager = auth_manager.write().await;
+        for (token_id, stored) in updated {
+            if let Some(info) = auth_manager.tokens.get_mut(&token_id) {
+                info.permissions = stored.permissions;
+                info.expires_at = stored.expires_at.map(Into::into);
+                info.description = stored.description;
+                info.user = stored.user;
+            }
+        }
+        for token_id in &removed {
+            auth_manager.tokens.remove(token_id);
+            auth_manager.store_tokens.remove(token_id);
+        }
+        removed
+    }
+
+    /// Accept a token provisioned by its hash.
+    pub fn add_token_hash(&mut self, token: &TokenConfig) -> FshResult<String> {
+        let token_hash = token.hash.trim().to_ascii_lowercase();
+        if token_hash.len() != 64 || !token_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(FshError::ConfigError(format!(
+                "Token '{}' has an invalid hash; expected 64 hex characters", token.description
+            )));
+        }
+
+        let token_id = Uuid::new_v4().to_string();
+        self.tokens.insert(token_id.clone(), TokenInfo {
+            token_hash,
+            created_at: SystemTime::now(),
+            expires_at: token.expires_at.map(Into::into),
+            permissions: token.permissions.clone(),
+            description: token.description.clone(),
+            folder: None,
+            single_use: false,
+            user: token.user.clone(),
+        });
+
+        Ok(token_id)
+    }
+
     pub fn validate_token(&self, token: &str) -> FshResult<&TokenInfo> {
+        self.find_token(token).map(|(_, token_info)| token_info)
+    }
+
+    /// The id of a valid, unexpired token, for tracking the sessions it opens.
+    pub fn token_id(&self, token: &str) -> FshResult<String> {
+        self.find_token(token).map(|(token_id, _)| token_id.to_string())
+    }
+
+    fn find_token(&self, token: &str) -> FshResult<(&str, &TokenInfo)> {
         let token_hash = Self::hash_token(token);
 
-        for token_info in self.tokens.values() {
+        for (token_id, token_info) in &self.tokens {
             if token_info.token_hash == token_hash {
                 // Check if token is expired
                 if let Some(expires_at) = token_info.expires_at {
@@ -66,13 +302,31 @@ impl AuthManager {
                     }
                 }
 
-                return Ok(token_info);
+                return Ok((token_id, token_info));
             }
         }
 
         Err(FshError::AuthenticationFailed)
     }
 
+    /// Every token by id, for operators.
+    pub fn list_tokens(&self) -> Vec<TokenSummary> {
+        let mut tokens: Vec<TokenSummary> = self.tokens.iter()
+            .map(|(id, info)| TokenSummary {
+                id: id.clone(),
+                description: info.description.clone(),
+                created_at: info.created_at.into(),
+                expires_at: info.expires_at.map(Into::into),
+                permissions: info.permissions.clone(),
+                folder: info.folder.clone(),
+                single_use: info.single_use,
+                user: info.user.clone(),
+            })
+            .collect();
+        tokens.sort_by_key(|token| token.created_at);
+        tokens
+    }
+
     pub fn create_token(
         &mut self,
         token: &str,
@@ -89,6 +343,9 @@ impl AuthManager {
             expires_at,
             permissions,
             description,
+            folder: None,
+            single_use: false,
+            user: None,
         };
 
         self.tokens.insert(token_id.clone(), token_info);
@@ -96,10 +353,58 @@ impl AuthManager {
         Ok(token_id)
     }
 
+    /// A new random token for a share link: it binds only `folder`, with
+    /// `permissions`, until `expires_at`, and once if `single_use`. Returns
+    /// its id and the token, which can't be recovered later.
+    pub fn create_share(
+        &mut self,
+        folder: &str,
+        expires_at: SystemTime,
+        permissions: Vec<crate::protocol::Permission>,
+        single_use: bool,
+    ) -> (String, String) {
+        let token = Self::generate_secure_token();
+        let token_id = Uuid::new_v4().to_string();
+        self.tokens.insert(token_id.clone(), TokenInfo {
+            token_hash: Self::hash_token(&token),
+            created_at: SystemTime::now(),
+            expires_at: Some(expires_at),
+            permissions,
+            description: format!("Share link for '{}'", folder),
+            folder: Some(folder.to_string()),
+            single_use,
+            user: None,
+        });
+        (token_id, token)
+    }
+
+    pub fn token_scope(&self, token_id: &str) -> Option<TokenScope> {
+        self.tokens.get(token_id).map(|info| TokenScope {
+            permissions: info.permissions.clone(),
+            folder: info.folder.clone(),
+            user: info.user.clone(),
+        })
+    }
+
+    /// The scope of a token a session was just opened with. A single-use
+    /// token is removed, leaving the session open.
+    pub fn use_token(&mut self, token_id: &str) -> Option<TokenScope> {
+        let scope = self.token_scope(token_id)?;
+        if self.tokens.get(token_id).is_some_and(|info| info.single_use) {
+            self.tokens.remove(token_id);
+        }
+        Some(scope)
+    }
+
     pub fn revoke_token(&mut self, token_id: &str) -> FshResult<()> {
-        self.tokens.remove(token_id)
+        let token_info = self.tokens.remove(token_id)
             .ok_or_else(|| FshError::ConfigError("Token not found".to_string()))?;
 
+        // Keep the store from handing the token back on the next login
+        if self.store_tokens.remove(token_id) {
+            self.revoked_hashes.insert(token_info.token_hash);
+        }
+
         Ok(())
     }
 
@@ -170,6 +475,12 @@ impl AuthManager {
     }
 
     pub fn cleanup_expired_tokens(&mut self) -> usize {
+        self.remove_expired_tokens().len()
+    }
+
+    /// Remove expired tokens and return their ids, so sessions they opened
+    /// can be closed.
+    pub fn remove_expired_tokens(&mut self) -> Vec<String> {
         let now = SystemTime::now();
 
         let expired_tokens: Vec<String> = self.tokens
@@ -184,12 +495,12 @@ impl AuthManager {
             })
             .collect();
 
-        let count = expired_tokens.len();
-        for token_id in expired_tokens {
-            self.tokens.remove(&token_id);
+        for token_id in &expired_tokens {
+            self.tokens.remove(token_id);
+            self.store_tokens.remove(token_id);
         }
 
-        count
+        expired_tokens
     }
 
     pub fn get_active_sessions(&self) -> Vec<&SessionInfo> {
@@ -208,7 +519,7 @@ impl AuthManager {
         self.auth_methods.contains(&method.to_string())
     }
 
-    fn hash_token(token: &str) -> String {
+    pub fn hash_token(token: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(token.as_bytes());
         format!("{:x}", hasher.finalize())
@@ -266,6 +577,17 @@ impl AuthManager {
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct MemoryStore(Mutex<HashMap<String, TokenConfig>>);
+
+    #[async_trait::async_trait]
+    impl TokenStore for MemoryStore {
+        async fn lookup(&self, token_hash: &str) -> FshResult<Option<TokenConfig>> {
+            Ok(self.0.lock().unwrap().get(token_hash).cloned())
+        }
+    }
 
     fn create_test_config() -> SecurityConfig {
         SecurityConfig {
@@ -274,6 +596,11 @@ mod tests {
             max_failed_attempts: 3,
             enable_logging: false,
             log_file: None,
+            rate_limits: crate::security::RateLimitConfig::default(),
+            lockout: crate::security::LockoutConfig::default(),
+            tokens: vec![],
+            token_store: None,
+            development_mode: true,
         }
     }
 
@@ -287,6 +614,15 @@ mod tests {
         assert_eq!(auth_manager.get_token_count(), 1); // Default token
     }
 
+    #[test]
+    fn test_default_token_needs_development_mode() {
+        let mut config = create_test_config();
+        config.development_mode = false;
+        let auth_manager = AuthManager::new(&config).unwrap();
+        assert_eq!(auth_manager.get_token_count(), 0);
+        assert!(auth_manager.validate_token("default").is_err());
+    }
+
     #[test]
     fn test_token_operations() {
         let config = create_test_config();
@@ -305,6 +641,9 @@ mod tests {
         let token_info = auth_manager.validate_token(token).unwrap();
         assert!(token_info.permissions.contains(&crate::protocol::Permission::Read));
 
+        assert_eq!(auth_manager.token_id(token).unwrap(), token_id);
+        assert!(auth_manager.list_tokens().iter().any(|summary| summary.id == token_id));
+
         // Revoke the token
         auth_manager.revoke_token(&token_id).unwrap();
 
@@ -312,6 +651,25 @@ mod tests {
         assert!(auth_manager.validate_token(token).is_err());
     }
 
+    #[test]
+    fn test_single_use_share() {
+        let mut auth_manager = AuthManager::new(&create_test_config()).unwrap();
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+        let (token_id, token) = auth_manager.create_share("docs", expires_at, vec![crate::protocol::Permission::Read], true);
+        assert_eq!(auth_manager.token_id(&token).unwrap(), token_id);
+
+        let scope = auth_manager.use_token(&token_id).unwrap();
+        assert!(scope.allows_folder("docs"));
+        assert!(!scope.allows_folder("src"));
+        let mut permissions = vec![crate::protocol::Permission::Read, crate::protocol::Permission::Write];
+        scope.restrict(&mut permissions);
+        assert_eq!(permissions, vec![crate::protocol::Permission::Read]);
+
+        // Used up by the first session
+        assert!(auth_manager.token_id(&token).is_err());
+        assert!(auth_manager.use_token(&token_id).is_none());
+    }
+
     #[test]
     fn test_session_operations() {
         let config = create_test_config();
@@ -323,7 +681,8 @@ mod tests {
         let session_id = auth_manager.create_session("test_user".to_string(), test_ip).unwrap();
 
         // Validate the session
-        let session = auth_manager.validate_session(&session_id).unwrap();
+        assert!(auth_manager.validate_session(&session_id).unwrap());
+        let session = auth_manager.get_active_sessions()[0];
         assert_eq!(session.user_id, "test_user");
         assert_eq!(session.client_ip, test_ip);
 
@@ -334,6 +693,63 @@ mod tests {
         assert!(auth_manager.validate_session(&session_id).is_err());
     }
 
+    #[test]
+    fn test_provisioned_tokens() {
+        let mut config = create_test_config();
+        config.tokens.push(TokenConfig {
+            description: "CI".to_string(),
+            hash: AuthManager::hash_token("ci-secret").to_uppercase(),
+            permissions: vec![crate::protocol::Permission::Read],
+            expires_at: None,
+            user: None,
+        });
+        let auth_manager = AuthManager::new(&config).unwrap();
+
+        let token_info = auth_manager.validate_token("ci-secret").unwrap();
+        assert_eq!(token_info.description, "CI");
+        assert_eq!(token_info.permissions, vec![crate::protocol::Permission::Read]);
+
+        config.tokens[0].hash = "not-a-hash".to_string();
+        assert!(AuthManager::new(&config).is_err());
+
+        let (generated, token) = TokenConfig::generate("First run");
+        config.tokens = vec![generated];
+        let auth_manager = AuthManager::new(&config).unwrap();
+        assert_eq!(auth_manager.validate_token(&token).unwrap().description, "First run");
+    }
+
+    #[tokio::test]
+    async fn test_token_store() {
+        let store = Arc::new(MemoryStore::default());
+        for token in ["vault-token", "other-token"] {
+            let hash = AuthManager::hash_token(token);
+            store.0.lock().unwrap().insert(hash.clone(), TokenConfig {
+                description: token.to_string(),
+                hash,
+                permissions: vec![crate::protocol::Permission::Read],
+                expires_at: None,
+                user: None,
+            });
+        }
+        let auth_manager = RwLock::new(AuthManager::new(&create_test_config()).unwrap().with_store(store.clone()));
+
+        // Store tokens are cached on first use; unknown tokens are refused
+        let token_id = AuthManager::authenticate_token(&auth_manager, "vault-token").await.unwrap();
+        assert_eq!(AuthManager::authenticate_token(&auth_manager, "vault-token").await.unwrap(), token_id);
+        assert!(AuthManager::authenticate_token(&auth_manager, "unknown").await.is_err());
+        assert!(AuthManager::authenticate_token(&auth_manager, "default").await.is_ok());
+
+        // Tokens removed from the store are dropped on refresh
+        store.0.lock().unwrap().remove(&AuthManager::hash_token("vault-token"));
+        assert_eq!(AuthManager::refresh_store_tokens(&auth_manager).await, vec![token_id]);
+        assert!(AuthManager::authenticate_token(&auth_manager, "vault-token").await.is_err());
+
+        // Revoking a store token keeps it out even though the store still has it
+        let token_id = AuthManager::authenticate_token(&auth_manager, "other-token").await.unwrap();
+        auth_manager.write().await.revoke_token(&token_id).unwrap();
+        assert!(AuthManager::authenticate_token(&auth_manager, "other-token").await.is_err());
+    }
+
     #[test]
     fn test_token_hashing() {
         let token1 = "test-token";
@@ -0,0 +1,26 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+use crate::protocol::FshResult;
+use crate::security::CertIdentity;
+
+/// A login attempt, as handed to an `Authenticator`.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthRequest<'a> {
+    /// The auth type the client asked for, e.g. "token".
+    pub method: &'a str,
+    pub credentials: &'a HashMap<String, String>,
+    pub client_addr: &'a str,
+    /// Names from the client's verified TLS certificate, if it presented one.
+    pub identity: Option<&'a CertIdentity>,
+}
+
+/// Decides who may log in, in place of the built-in token and certificate
+/// checks. Lockouts and rate limits still apply before it is asked.
+#[async_trait]
+pub trait Authenticator: Send + Sync + std::fmt::Debug {
+    /// Accept the client, returning an id for the credential it used if
+    /// there is one, or fail with `FshError::AuthenticationFailed`.
+    /// Revoking that id through the server closes the sessions it opened.
+    async fn authenticate(&self, request: &AuthRequest<'_>) -> FshResult<Option<String>>;
+}
@@ -0,0 +1,175 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ServerName};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::protocol::{FshError, FshResult};
+
+/// Fingerprint of the public key in a DER certificate, `SHA256:<hex>`. A
+/// certificate renewed with the same key keeps its fingerprint.
+pub fn key_fingerprint(cert_der: &[u8]) -> FshResult<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| FshError::ProtocolError(format!("Unreadable server certificate: {}", e)))?;
+    Ok(format!("SHA256:{}", hex::encode(Sha256::digest(cert.public_key().raw))))
+}
+
+/// Whether a server was already known when its key was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerTrust {
+    /// Its key matches the one recorded before.
+    Known,
+    /// First connection; its key has now been recorded.
+    New,
+}
+
+/// Servers the client has connected to, with the key each presented the
+/// first time, one `server fingerprint` line each as in SSH's known_hosts.
+#[derive(Debug, Clone)]
+pub struct KnownServers {
+    path: PathBuf,
+}
+
+impl KnownServers {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// `known_servers` next to the default config file, e.g.
+    /// `~/.config/fsh/known_servers`.
+    pub fn default_path() -> FshResult<PathBuf> {
+        Ok(Config::get_default_config_path()?.with_file_name("known_servers"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The fingerprint recorded for `server`, if any.
+    pub fn fingerprint(&self, server: &str) -> FshResult<Option<String>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(FshError::io(format!("Cannot read {}", self.path.display()), e)),
+        };
+
+        Ok(content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .find(|(name, _)| *name == server)
+            .map(|(_, fingerprint)| fingerprint.trim().to_string()))
+    }
+
+    /// Accept `fingerprint` for `server` if it is the one recorded, or record
+    /// it if the server is new. A different key is refused, as it may belong
+    /// to someone in the middle.
+    pub fn check(&self, server: &str, fingerprint: &str) -> FshResult<ServerTrust> {
+        match self.fingerprint(server)? {
+            Some(known) if known == fingerprint => Ok(ServerTrust::Known),
+            Some(known) => Err(FshError::PermissionDenied(format!(
+                "{} presented key {}, but {} is recorded for it in {}; if the server's key really changed, remove its line",
+                server, fingerprint, known, self.path.display()
+            ))),
+            None => {
+                self.record(server, fingerprint)?;
+                Ok(ServerTrust::New)
+            }
+        }
+    }
+
+    fn record(&self, server: &str, fingerprint: &str) -> FshResult<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| FshError::io(format!("Cannot create {}", parent.display()), e))?;
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| FshError::io(format!("Cannot open {}", self.path.display()), e))?;
+        writeln!(file, "{} {}", server, fingerprint)
+            .map_err(|e| FshError::io(format!("Cannot write {}", self.path.display()), e))
+    }
+}
+
+/// Checks a server's key against the known servers, after its certificate
+/// chain when a CA bundle is configured.
+pub(crate) struct KnownServerVerifier {
+    ca: Option<WebPkiVerifier>,
+    known_servers: KnownServers,
+    server: String,
+}
+
+impl KnownServerVerifier {
+    pub(crate) fn new(ca: Option<WebPkiVerifier>, known_servers: KnownServers, server: &str) -> Self {
+        Self { ca, known_servers, server: server.to_string() }
+    }
+}
+
+impl ServerCertVerifier for KnownServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(ca) = &self.ca {
+            ca.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+
+        // The handshake still proves the server holds the key's private half
+        let fingerprint = key_fingerprint(&end_entity.0).map_err(|e| rustls::Error::General(e.detail()))?;
+        match self.known_servers.check(&self.server, &fingerprint) {
+            Ok(ServerTrust::Known) => {}
+            Ok(ServerTrust::New) => warn!(
+                "Trusting {} on first use; recorded its key {} in {}",
+                self.server, fingerprint, self.known_servers.path().display()
+            ),
+            Err(e) => return Err(rustls::Error::General(e.detail())),
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_known_servers() {
+        let temp_dir = TempDir::new().unwrap();
+        let known = KnownServers::new(temp_dir.path().join("fsh/known_servers"));
+        assert_eq!(known.fingerprint("lab:2222").unwrap(), None);
+
+        assert_eq!(known.check("lab:2222", "SHA256:aa").unwrap(), ServerTrust::New);
+        assert_eq!(known.check("lab:2222", "SHA256:aa").unwrap(), ServerTrust::Known);
+        assert!(known.check("lab:2222", "SHA256:bb").is_err());
+        assert_eq!(known.check("other:2222", "SHA256:bb").unwrap(), ServerTrust::New);
+        assert_eq!(
+            std::fs::read_to_string(known.path()).unwrap(),
+            "lab:2222 SHA256:aa\nother:2222 SHA256:bb\n"
+        );
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        let key = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert_for = |name: &str| {
+            let mut params = rcgen::CertificateParams::new(vec![name.to_string()]);
+            params.key_pair = Some(rcgen::KeyPair::from_pem(&key.serialize_pem()).unwrap());
+            rcgen::Certificate::from_params(params).unwrap().serialize_der().unwrap()
+        };
+
+        // A renewed certificate for the same key keeps the fingerprint
+        let fingerprint = key_fingerprint(&cert_for("lab")).unwrap();
+        assert!(fingerprint.starts_with("SHA256:"));
+        assert_eq!(key_fingerprint(&cert_for("lab.example.com")).unwrap(), fingerprint);
+        let other = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["lab".to_string()])).unwrap();
+        assert_ne!(key_fingerprint(&other.serialize_der().unwrap()).unwrap(), fingerprint);
+    }
+}
@@ -0,0 +1,312 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Delays and temporary locks after failed authentication attempts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// Delay before answering a failed attempt; doubles with each further
+    /// failure from the same address, user or token.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Failures before an address is locked; 0 never locks.
+    pub ip_threshold: u32,
+    /// Failures before a user or token is locked; 0 never locks.
+    pub account_threshold: u32,
+    pub lock_seconds: u64,
+    /// Failures older than this are forgotten.
+    pub failure_window_seconds: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            base_delay_ms: 250,
+            max_delay_ms: 8000,
+            ip_threshold: 20,
+            account_threshold: 5,
+            lock_seconds: 900,
+            failure_window_seconds: 3600,
+        }
+    }
+}
+
+/// What a failed attempt is counted against. Written as `ip:<address>`,
+/// `user:<name>` or `token:<fingerprint>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    User(String),
+    /// The start of the token's SHA-256, never the token itself.
+    Token(String),
+}
+
+impl LockoutKey {
+    pub fn token(token: &str) -> Self {
+        LockoutKey::Token(hex::encode(&Sha256::digest(token.as_bytes())[..8]))
+    }
+
+    /// The keys for an authentication attempt: the client's address and the
+    /// user or token it claimed.
+    pub fn for_attempt(ip: Option<IpAddr>, credentials: &HashMap<String, String>) -> Vec<Self> {
+        let account = match credentials.get("username") {
+            Some(user) => Some(LockoutKey::User(user.clone())),
+            None => credentials.get("token").map(|token| LockoutKey::token(token)),
+        };
+        ip.map(LockoutKey::Ip).into_iter().chain(account).collect()
+    }
+
+    fn threshold(&self, config: &LockoutConfig) -> u32 {
+        match self {
+            LockoutKey::Ip(_) => config.ip_threshold,
+            LockoutKey::User(_) | LockoutKey::Token(_) => config.account_threshold,
+        }
+    }
+}
+
+impl std::fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockoutKey::Ip(ip) => write!(f, "ip:{}", ip),
+            LockoutKey::User(user) => write!(f, "user:{}", user),
+            LockoutKey::Token(fingerprint) => write!(f, "token:{}", fingerprint),
+        }
+    }
+}
+
+impl FromStr for LockoutKey {
+    type Err = FshError;
+
+    fn from_str(s: &str) -> FshResult<Self> {
+        match s.split_once(':') {
+            Some(("ip", ip)) => ip.parse().map(LockoutKey::Ip)
+                .map_err(|_| FshError::ConfigError(format!("Invalid address in '{}'", s))),
+            Some(("user", user)) if !user.is_empty() => Ok(LockoutKey::User(user.to_string())),
+            Some(("token", fingerprint)) if !fingerprint.is_empty() => Ok(LockoutKey::Token(fingerprint.to_string())),
+            _ => Err(FshError::ConfigError(format!(
+                "Invalid lockout key '{}'; expected ip:<address>, user:<name> or token:<fingerprint>", s
+            ))),
+        }
+    }
+}
+
+/// Failures recorded against one key, as reported by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockoutStatus {
+    pub key: String,
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+struct LockoutEntry {
+    failures: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Failed authentication attempts across all connections.
+#[derive(Debug)]
+pub struct AuthLockout {
+    config: LockoutConfig,
+    entries: Mutex<HashMap<LockoutKey, LockoutEntry>>,
+}
+
+/// The outcome of a failed attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedAttempt {
+    /// How long to wait before answering the client.
+    pub delay: Duration,
+    /// Keys that this failure locked.
+    pub locked: Vec<LockoutKey>,
+}
+
+impl AuthLockout {
+    pub fn new(config: &LockoutConfig) -> Self {
+        Self {
+            config: config.clone(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fail if any of `keys` is locked.
+    pub fn check(&self, keys: &[LockoutKey]) -> FshResult<()> {
+        let now = Utc::now();
+        let entries = self.lock();
+        for key in keys {
+            if let Some(until) = entries.get(key).and_then(|entry| entry.locked_until).filter(|until| *until > now) {
+                return Err(FshError::PermissionDenied(format!(
+                    "{} is locked until {}", key, until.format("%Y-%m-%d %H:%M:%S UTC")
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_failure(&self, keys: &[LockoutKey]) -> FailedAttempt {
+        let now = Utc::now();
+        let window = chrono::Duration::seconds(self.config.failure_window_seconds as i64);
+        let mut entries = self.lock();
+        let mut most_failures = 0;
+        let mut locked = Vec::new();
+
+        for key in keys {
+            let entry = entries.entry(key.clone()).or_insert(LockoutEntry {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if now - entry.last_failure > window {
+                entry.failures = 0;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+            most_failures = most_failures.max(entry.failures);
+
+            let threshold = key.threshold(&self.config);
+            if threshold > 0 && entry.failures >= threshold && entry.locked_until.is_none_or(|until| until <= now) {
+                entry.locked_until = Some(now + chrono::Duration::seconds(self.config.lock_seconds as i64));
+                locked.push(key.clone());
+            }
+        }
+
+        FailedAttempt { delay: self.delay_after(most_failures), locked }
+    }
+
+    /// Forget past failures for `keys` once an attempt succeeds.
+    pub fn record_success(&self, keys: &[LockoutKey]) {
+        let mut entries = self.lock();
+        for key in keys {
+            entries.remove(key);
+        }
+    }
+
+    /// Keys with recent failures or an active lock.
+    pub fn list(&self) -> Vec<LockoutStatus> {
+        self.clean_expired();
+        let mut statuses: Vec<LockoutStatus> = self.lock().iter()
+            .map(|(key, entry)| LockoutStatus {
+                key: key.to_string(),
+                failures: entry.failures,
+                last_failure: entry.last_failure,
+                locked_until: entry.locked_until,
+            })
+            .collect();
+        statuses.sort_by_key(|status| std::cmp::Reverse(status.last_failure));
+        statuses
+    }
+
+    /// Lift a lock and forget the key's failures.
+    pub fn unlock(&self, key: &LockoutKey) -> FshResult<LockoutStatus> {
+        let entry = self.lock().remove(key)
+            .ok_or_else(|| FshError::ConfigError(format!("No lockout for '{}'", key)))?;
+        Ok(LockoutStatus {
+            key: key.to_string(),
+            failures: entry.failures,
+            last_failure: entry.last_failure,
+            locked_until: entry.locked_until,
+        })
+    }
+
+    /// Drop entries whose lock has ended and whose failures are outside the window.
+    pub fn clean_expired(&self) {
+        let now = Utc::now();
+        let window = chrono::Duration::seconds(self.config.failure_window_seconds as i64);
+        self.lock().retain(|_, entry| {
+            entry.locked_until.is_some_and(|until| until > now) || now - entry.last_failure <= window
+        });
+    }
+
+    fn delay_after(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u64.checked_shl(failures - 1).unwrap_or(u64::MAX);
+        Duration::from_millis(self.config.base_delay_ms.saturating_mul(factor).min(self.config.max_delay_ms))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<LockoutKey, LockoutEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout() -> AuthLockout {
+        AuthLockout::new(&LockoutConfig {
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            ip_threshold: 4,
+            account_threshold: 2,
+            ..LockoutConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_progressive_delays_and_locks() {
+        let lockout = lockout();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let alice = [LockoutKey::Ip(ip), LockoutKey::User("alice".to_string())];
+
+        let first = lockout.record_failure(&alice);
+        assert_eq!(first.delay, Duration::from_millis(100));
+        assert!(first.locked.is_empty());
+
+        // The account locks before the address
+        let second = lockout.record_failure(&alice);
+        assert_eq!(second.delay, Duration::from_millis(200));
+        assert_eq!(second.locked, vec![LockoutKey::User("alice".to_string())]);
+        assert!(lockout.check(&alice).is_err());
+        assert!(lockout.check(&[LockoutKey::Ip(ip), LockoutKey::User("bob".to_string())]).is_ok());
+
+        let bob = [LockoutKey::Ip(ip), LockoutKey::User("bob".to_string())];
+        lockout.record_failure(&bob);
+        let fourth = lockout.record_failure(&bob);
+        assert_eq!(fourth.delay, Duration::from_millis(800));
+        assert!(fourth.locked.contains(&LockoutKey::Ip(ip)));
+        assert!(lockout.check(&[LockoutKey::Ip(ip)]).is_err());
+
+        // Delays are capped
+        assert_eq!(lockout.record_failure(&bob).delay, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_unlock_and_success() {
+        let lockout = lockout();
+        let token = LockoutKey::token("guess");
+        lockout.record_failure(std::slice::from_ref(&token));
+        lockout.record_failure(std::slice::from_ref(&token));
+        assert!(lockout.check(std::slice::from_ref(&token)).is_err());
+        assert_eq!(lockout.list().len(), 1);
+
+        let key: LockoutKey = lockout.list()[0].key.parse().unwrap();
+        assert_eq!(key, token);
+        assert!(lockout.unlock(&key).is_ok());
+        assert!(lockout.check(std::slice::from_ref(&token)).is_ok());
+        assert!(lockout.unlock(&key).is_err());
+
+        let user = [LockoutKey::User("alice".to_string())];
+        lockout.record_failure(&user);
+        lockout.record_success(&user);
+        assert_eq!(lockout.record_failure(&user).delay, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_key_syntax() {
+        assert_eq!("ip:127.0.0.1".parse::<LockoutKey>().unwrap(), LockoutKey::Ip("127.0.0.1".parse().unwrap()));
+        assert_eq!("user:alice".parse::<LockoutKey>().unwrap().to_string(), "user:alice");
+        assert!("alice".parse::<LockoutKey>().is_err());
+        assert!("ip:nonsense".parse::<LockoutKey>().is_err());
+    }
+}
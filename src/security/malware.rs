@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::protocol::{FshError, FshResult};
+
+const DEFAULT_ICAP_PORT: u16 = 1344;
+
+/// Scanning of uploaded files by an external virus scanner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MalwareScanConfig {
+    pub enabled: bool,
+    /// Shell command run with the written file's path in `FSH_SCAN_PATH`,
+    /// e.g. `clamdscan --no-summary "$FSH_SCAN_PATH"`. Exit code 0 means
+    /// clean, 1 infected (its output names the threat), anything else a
+    /// failed scan.
+    pub command: Option<String>,
+    /// ICAP service to send files to instead, e.g. `icap://127.0.0.1:1344/avscan`.
+    pub icap_url: Option<String>,
+    /// Where infected files are moved. Keep it outside every shared folder.
+    pub quarantine_dir: PathBuf,
+    pub timeout_seconds: u64,
+    /// Keep files the scanner could not check instead of quarantining them.
+    pub allow_on_error: bool,
+}
+
+impl Default for MalwareScanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            command: None,
+            icap_url: None,
+            quarantine_dir: PathBuf::from("quarantine"),
+            timeout_seconds: 60,
+            allow_on_error: false,
+        }
+    }
+}
+
+impl MalwareScanConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        match (&self.command, &self.icap_url) {
+            (Some(_), None) => {}
+            (None, Some(url)) => {
+                IcapService::parse(url)?;
+            }
+            _ => {
+                return Err(FshError::ConfigError(
+                    "malware_scan needs exactly one of command and icap_url".to_string()
+                ));
+            }
+        }
+        if self.timeout_seconds == 0 {
+            return Err(FshError::ConfigError("malware_scan.timeout_seconds must be greater than 0".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// What the scanner made of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Names the threat as the scanner reported it.
+    Infected(String),
+}
+
+/// The outcome of checking a written file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanOutcome {
+    Clean,
+    /// The file was moved to `quarantined_as`.
+    Quarantined { verdict: String, quarantined_as: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IcapService {
+    host: String,
+    port: u16,
+    url: String,
+}
+
+impl IcapService {
+    fn parse(url: &str) -> FshResult<Self> {
+        let invalid = || FshError::ConfigError(format!("Invalid ICAP URL '{}'", url));
+        let rest = url.strip_prefix("icap://").ok_or_else(invalid)?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, DEFAULT_ICAP_PORT),
+        };
+        Ok(Self { host: host.to_string(), port, url: url.to_string() })
+    }
+
+    /// Send `data` as the body of an HTTP response for RESPMOD. The service
+    /// answers 204 when it has nothing to change, i.e. the file is clean.
+    async fn scan(&self, name: &str, data: &[u8]) -> FshResult<ScanVerdict> {
+        let http_header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len());
+        let request = format!(
+            "RESPMOD {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nX-Filename: {}\r\n\
+             Encapsulated: res-hdr=0, res-body={}\r\n\r\n{}",
+            self.url, self.host, name, http_header.len(), http_header,
+        );
+
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await
+            .map_err(|e| FshError::io(format!("Failed to connect to ICAP service {}", self.url), e))?;
+        let io_error = |e| FshError::io(format!("ICAP request to {} failed", self.url), e);
+        stream.write_all(request.as_bytes()).await.map_err(io_error)?;
+        if !data.is_empty() {
+            stream.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await.map_err(io_error)?;
+            stream.write_all(data).await.map_err(io_error)?;
+            stream.write_all(b"\r\n").await.map_err(io_error)?;
+        }
+        stream.write_all(b"0\r\n\r\n").await.map_err(io_error)?;
+
+        // Only the ICAP status and headers matter; the body is the service's block page
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buf).await.map_err(io_error)?;
+            if read == 0 {
+                break;
+            }
+            response.extend_from_slice(&buf[..read]);
+        }
+        parse_icap_response(&String::from_utf8_lossy(&response))
+    }
+}
+
+fn parse_icap_response(response: &str) -> FshResult<ScanVerdict> {
+    let mut lines = response.lines();
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| FshError::ProtocolError("Malformed ICAP response".to_string()))?;
+
+    match status {
+        204 => Ok(ScanVerdict::Clean),
+        200 => {
+            let threat = lines
+                .take_while(|line| !line.is_empty())
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| {
+                    ["x-infection-found", "x-violations-found", "x-virus-id"].contains(&name.trim().to_ascii_lowercase().as_str())
+                })
+                .map(|(_, value)| value.trim().to_string())
+                .unwrap_or_else(|| "Blocked by ICAP service".to_string());
+            Ok(ScanVerdict::Infected(threat))
+        }
+        status => Err(FshError::ProtocolError(format!("ICAP service answered {}", status))),
+    }
+}
+
+/// Checks files clients write and quarantines the infected ones.
+#[derive(Debug)]
+pub struct MalwareScanner {
+    config: MalwareScanConfig,
+    icap: Option<IcapService>,
+}
+
+impl MalwareScanner {
+    pub fn new(config: &MalwareScanConfig) -> FshResult<Self> {
+        let icap = match &config.icap_url {
+            Some(url) if config.enabled => Some(IcapService::parse(url)?),
+            _ => None,
+        };
+        Ok(Self { config: config.clone(), icap })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Scan `path` and quarantine it if it is infected, or if the scan fails
+    /// and `allow_on_error` is off.
+    pub async fn check(&self, path: &Path) -> FshResult<ScanOutcome> {
+        let verdict = match self.scan(path).await {
+            Ok(ScanVerdict::Clean) => return Ok(ScanOutcome::Clean),
+            Ok(ScanVerdict::Infected(threat)) => threat,
+            Err(e) if self.config.allow_on_error => {
+                warn!("Malware scan of {} failed, keeping the file: {}", path.display(), e);
+                return Ok(ScanOutcome::Clean);
+            }
+            Err(e) => format!("Scan failed: {}", e.detail()),
+        };
+
+        let quarantined_as = self.quarantine(path)?;
+        Ok(ScanOutcome::Quarantined { verdict, quarantined_as })
+    }
+
+    async fn scan(&self, path: &Path) -> FshResult<ScanVerdict> {
+        debug!("Scanning {} for malware", path.display());
+        let timeout = Duration::from_secs(self.config.timeout_seconds);
+        let scan = async {
+            match (&self.icap, &self.config.command) {
+                (Some(icap), _) => {
+                    let data = tokio::fs::read(path).await
+                        .map_err(|e| FshError::io(format!("Failed to read {} for scanning", path.display()), e))?;
+                    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                    icap.scan(&name, &data).await
+                }
+                (None, Some(command)) => run_scan_command(command, path).await,
+                (None, None) => Ok(ScanVerdict::Clean),
+            }
+        };
+        tokio::time::timeout(timeout, scan).await
+            .map_err(|_| FshError::ShellError(format!("Malware scan timed out after {}s", timeout.as_secs())))?
+    }
+
+    fn quarantine(&self, path: &Path) -> FshResult<PathBuf> {
+        std::fs::create_dir_all(&self.config.quarantine_dir)
+            .map_err(|e| FshError::io("Failed to create quarantine directory", e))?;
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let target = self.config.quarantine_dir.join(format!("{}-{}", Uuid::new_v4(), name));
+
+        // The quarantine may be on another filesystem
+        if std::fs::rename(path, &target).is_err() {
+            std::fs::copy(path, &target)
+                .and_then(|_| std::fs::remove_file(path))
+                .map_err(|e| FshError::io(format!("Failed to quarantine {}", path.display()), e))?;
+        }
+        Ok(target)
+    }
+}
+
+async fn run_scan_command(command: &str, path: &Path) -> FshResult<ScanVerdict> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = tokio::process::Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    let output = cmd.env("FSH_SCAN_PATH", path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| FshError::ShellError(format!("Failed to run malware scanner: {}", e)))?;
+
+    let report = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match output.status.code() {
+        Some(0) => Ok(ScanVerdict::Clean),
+        Some(1) => Ok(ScanVerdict::Infected(if report.is_empty() { "Infected".to_string() } else { report })),
+        code => Err(FshError::ShellError(format!(
+            "Malware scanner exited with {}: {}",
+            code.map(|code| code.to_string()).unwrap_or_else(|| "a signal".to_string()),
+            String::from_utf8_lossy(&output.stderr).trim(),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_icap_response() {
+        assert_eq!(parse_icap_response("ICAP/1.0 204 No Content\r\n\r\n").unwrap(), ScanVerdict::Clean);
+        assert_eq!(
+            parse_icap_response("ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=Eicar-Test-Signature;\r\n\r\n").unwrap(),
+            ScanVerdict::Infected("Type=0; Resolution=2; Threat=Eicar-Test-Signature;".to_string())
+        );
+        assert!(parse_icap_response("ICAP/1.0 500 Server Error\r\n\r\n").is_err());
+
+        assert_eq!(IcapService::parse("icap://av.local/avscan").unwrap().port, DEFAULT_ICAP_PORT);
+        assert!(IcapService::parse("http://av.local/avscan").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_infected_file_quarantined() {
+        let temp_dir = TempDir::new().unwrap();
+        let scanner = MalwareScanner::new(&MalwareScanConfig {
+            enabled: true,
+            command: Some("grep -q EICAR \"$FSH_SCAN_PATH\" && { echo Eicar-Test-Signature; exit 1; } || exit 0".to_string()),
+            quarantine_dir: temp_dir.path().join("quarantine"),
+            ..MalwareScanConfig::default()
+        }).unwrap();
+
+        let clean = temp_dir.path().join("clean.txt");
+        std::fs::write(&clean, "hello").unwrap();
+        assert_eq!(scanner.check(&clean).await.unwrap(), ScanOutcome::Clean);
+        assert!(clean.exists());
+
+        let infected = temp_dir.path().join("infected.txt");
+        std::fs::write(&infected, "X5O EICAR test").unwrap();
+        let ScanOutcome::Quarantined { verdict, quarantined_as } = scanner.check(&infected).await.unwrap() else {
+            panic!("Expected the file to be quarantined");
+        };
+        assert_eq!(verdict, "Eicar-Test-Signature");
+        assert!(!infected.exists());
+        assert_eq!(std::fs::read_to_string(quarantined_as).unwrap(), "X5O EICAR test");
+    }
+}
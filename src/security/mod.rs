@@ -1,19 +1,68 @@
 pub mod audit;
 pub mod auth;
+pub mod authenticator;
+pub mod dlp;
+pub mod known_servers;
+pub mod lockout;
+pub mod noise;
+pub mod malware;
+pub mod patterns;
+pub mod policy;
 pub mod rate_limit;
+pub mod tls;
+pub mod token_store;
 
 pub use audit::*;
 pub use auth::*;
+pub use authenticator::*;
+pub use dlp::*;
+pub use known_servers::*;
+pub use lockout::*;
+pub use noise::*;
+pub use malware::*;
+pub use patterns::*;
+pub use policy::*;
 pub use rate_limit::*;
+pub use tls::*;
+pub use token_store::*;
 
 use crate::protocol::{FshError, FshResult};
 use std::net::IpAddr;
 use std::time::{Duration, SystemTime};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
 use tracing::{warn, error, info};
 
+/// Commands containing any of these are refused, whatever their case.
+pub const DANGEROUS_COMMAND_PATTERNS: &[&str] = &[
+    "rm -rf /",
+    "del /f /q",
+    "format",
+    "fdisk",
+    "dd if=",
+    "mkfs",
+    "shutdown",
+    "reboot",
+    "halt",
+    "poweroff",
+    "sudo su",
+    "sudo -i",
+    "passwd",
+    "chpasswd",
+    "../../../",
+    "..\\..\\..\\",
+];
+
+/// System files clients may not touch.
+pub const SUSPICIOUS_PATH_PATTERNS: &[&str] = &[
+    "/etc/passwd",
+    "/etc/shadow",
+    "/etc/sudoers",
+    "C:\\Windows\\System32\\config\\SAM",
+    "C:\\Windows\\System32\\config\\SYSTEM",
+    "/proc/",
+    "/sys/",
+    "/dev/",
+];
+
 #[derive(Debug, Clone)]
 pub struct SecurityContext {
     pub client_ip: IpAddr,
@@ -29,8 +78,9 @@ pub struct SecurityManager {
     audit_logger: AuditLogger,
     auth_manager: AuthManager,
     rate_limiter: RateLimiter,
-    blocked_ips: Arc<RwLock<HashMap<IpAddr, SystemTime>>>,
-    failed_attempts: Arc<RwLock<HashMap<IpAddr, Vec<SystemTime>>>>,
+    lockout: AuthLockout,
+    dangerous_commands: PatternSet,
+    suspicious_paths: PatternSet,
 }
 
 impl SecurityManager {
@@ -39,19 +89,17 @@ impl SecurityManager {
             audit_logger: AuditLogger::new(config)?,
             auth_manager: AuthManager::new(config)?,
             rate_limiter: RateLimiter::new(100, Duration::from_secs(60)), // 100 requests per minute
-            blocked_ips: Arc::new(RwLock::new(HashMap::new())),
-            failed_attempts: Arc::new(RwLock::new(HashMap::new())),
+            lockout: AuthLockout::new(&config.lockout),
+            dangerous_commands: PatternSet::new_ignore_case(DANGEROUS_COMMAND_PATTERNS.iter().copied())?,
+            suspicious_paths: PatternSet::new(SUSPICIOUS_PATH_PATTERNS.iter().copied())?,
         })
     }
 
     pub async fn check_ip_allowed(&self, ip: IpAddr) -> FshResult<()> {
-        // Check if IP is blocked
-        let blocked_ips = self.blocked_ips.read().await;
-        if let Some(blocked_until) = blocked_ips.get(&ip) {
-            if SystemTime::now() < *blocked_until {
-                warn!("Blocked IP {} attempted connection", ip);
-                return Err(FshError::PermissionDenied("IP blocked".to_string()));
-            }
+        // Check if IP is locked out
+        if let Err(e) = self.lockout.check(&[LockoutKey::Ip(ip)]) {
+            warn!("Locked out IP {} attempted connection", ip);
+            return Err(e);
         }
 
         // Check rate limiting
@@ -63,44 +111,21 @@ impl SecurityManager {
         Ok(())
     }
 
-    pub async fn record_auth_failure(&self, ip: IpAddr) -> FshResult<()> {
-        let mut failed_attempts = self.failed_attempts.write().await;
-        let attempts = failed_attempts.entry(ip).or_insert_with(Vec::new);
-
-        let now = SystemTime::now();
-        attempts.push(now);
-
-        // Keep only attempts from the last hour
-        attempts.retain(|&time| now.duration_since(time).unwrap_or(Duration::ZERO) < Duration::from_secs(3600));
-
-        // Block IP if too many failures
-        let max_attempts = 5;
-        if attempts.len() >= max_attempts {
-            let mut blocked_ips = self.blocked_ips.write().await;
-            let block_duration = Duration::from_secs(3600); // Block for 1 hour
-            blocked_ips.insert(ip, now + block_duration);
-
-            error!("IP {} blocked due to {} failed authentication attempts", ip, attempts.len());
-
-            // Log security event
-            self.audit_logger.log_security_event(SecurityEvent {
-                event_type: SecurityEventType::IpBlocked,
-                source_ip: ip,
-                session_id: None,
-                user_id: None,
-                resource: None,
-                details: format!("Blocked after {} failed attempts", attempts.len()),
-                timestamp: now,
-            }).await?;
+    /// Record a failed attempt from `ip`. The caller should wait for the
+    /// returned delay before answering the client.
+    pub async fn record_auth_failure(&self, ip: IpAddr) -> FshResult<Duration> {
+        let attempt = self.lockout.record_failure(&[LockoutKey::Ip(ip)]);
+        for key in &attempt.locked {
+            error!("{} locked out after repeated failed authentication attempts", key);
+            self.audit_logger.log_lockout(ip, key).await?;
         }
 
-        Ok(())
+        Ok(attempt.delay)
     }
 
     pub async fn record_successful_auth(&self, ip: IpAddr) -> FshResult<()> {
         // Clear failed attempts for this IP
-        let mut failed_attempts = self.failed_attempts.write().await;
-        failed_attempts.remove(&ip);
+        self.lockout.record_success(&[LockoutKey::Ip(ip)]);
 
         info!("Successful authentication from {}", ip);
         Ok(())
@@ -119,43 +144,22 @@ impl SecurityManager {
         }).await?;
 
         // Check for dangerous patterns
-        let dangerous_patterns = [
-            "rm -rf /",
-            "del /f /q",
-            "format",
-            "fdisk",
-            "dd if=",
-            "mkfs",
-            "shutdown",
-            "reboot",
-            "halt",
-            "poweroff",
-            "sudo su",
-            "sudo -i",
-            "passwd",
-            "chpasswd",
-            "../../../",
-            "..\\..\\..\\",
-        ];
-
-        for pattern in &dangerous_patterns {
-            if command.to_lowercase().contains(pattern) {
-                warn!("Dangerous command pattern detected: {} from {}", pattern, context.client_ip);
-
-                self.audit_logger.log_security_event(SecurityEvent {
-                    event_type: SecurityEventType::SuspiciousActivity,
-                    source_ip: context.client_ip,
-                    session_id: context.session_id.clone(),
-                    user_id: None,
-                    resource: Some(command.to_string()),
-                    details: format!("Dangerous pattern detected: {}", pattern),
-                    timestamp: SystemTime::now(),
-                }).await?;
-
-                return Err(FshError::PermissionDenied(
-                    format!("Command contains dangerous pattern: {}", pattern)
-                ));
-            }
+        if let Some(pattern) = self.dangerous_commands.find(command) {
+            warn!("Dangerous command pattern detected: {} from {}", pattern, context.client_ip);
+
+            self.audit_logger.log_security_event(SecurityEvent {
+                event_type: SecurityEventType::SuspiciousActivity,
+                source_ip: context.client_ip,
+                session_id: context.session_id.clone(),
+                user_id: None,
+                resource: Some(command.to_string()),
+                details: format!("Dangerous pattern detected: {}", pattern),
+                timestamp: SystemTime::now(),
+            }).await?;
+
+            return Err(FshError::PermissionDenied(
+                format!("Command contains dangerous pattern: {}", pattern)
+            ));
         }
 
         Ok(())
@@ -174,68 +178,75 @@ impl SecurityManager {
         }).await?;
 
         // Check if file path is suspicious
-        let suspicious_paths = [
-            "/etc/passwd",
-            "/etc/shadow",
-            "/etc/sudoers",
-            "C:\\Windows\\System32\\config\\SAM",
-            "C:\\Windows\\System32\\config\\SYSTEM",
-            "/proc/",
-            "/sys/",
-            "/dev/",
-        ];
-
-        for path in &suspicious_paths {
-            if file_path.contains(path) {
-                warn!("Suspicious file access attempt: {} from {}", file_path, context.client_ip);
-
-                self.audit_logger.log_security_event(SecurityEvent {
-                    event_type: SecurityEventType::SuspiciousActivity,
-                    source_ip: context.client_ip,
-                    session_id: context.session_id.clone(),
-                    user_id: None,
-                    resource: Some(file_path.to_string()),
-                    details: format!("Suspicious file access: {}", path),
-                    timestamp: SystemTime::now(),
-                }).await?;
-
-                return Err(FshError::PermissionDenied(
-                    format!("Access denied to system file: {}", path)
-                ));
-            }
+        if let Some(path) = self.suspicious_paths.find(file_path) {
+            warn!("Suspicious file access attempt: {} from {}", file_path, context.client_ip);
+
+            self.audit_logger.log_security_event(SecurityEvent {
+                event_type: SecurityEventType::SuspiciousActivity,
+                source_ip: context.client_ip,
+                session_id: context.session_id.clone(),
+                user_id: None,
+                resource: Some(file_path.to_string()),
+                details: format!("Suspicious file access: {}", path),
+                timestamp: SystemTime::now(),
+            }).await?;
+
+            return Err(FshError::PermissionDenied(
+                format!("Access denied to system file: {}", path)
+            ));
         }
 
         Ok(())
     }
 
-    pub async fn clean_expired_entries(&self) -> FshResult<()> {
-        let now = SystemTime::now();
+    /// Check a request against a folder's policy, logging denials.
+    pub async fn validate_policy(
+        &self,
+        context: &SecurityContext,
+        policy: &Policy,
+        action: PolicyAction,
+        subject: &str,
+    ) -> FshResult<()> {
+        let PolicyDecision::Deny(rule) = policy.decide(action, subject) else {
+            return Ok(());
+        };
 
-        // Clean expired IP blocks
-        {
-            let mut blocked_ips = self.blocked_ips.write().await;
-            blocked_ips.retain(|_, &mut blocked_until| now < blocked_until);
-        }
+        warn!("Policy denied {} '{}' from {}: {}", action, subject, context.client_ip, rule);
+        self.audit_logger.log_security_event(SecurityEvent {
+            event_type: SecurityEventType::PermissionDenied,
+            source_ip: context.client_ip,
+            session_id: context.session_id.clone(),
+            user_id: None,
+            resource: Some(subject.to_string()),
+            details: format!("Denied {} by policy rule: {}", action, rule),
+            timestamp: SystemTime::now(),
+        }).await?;
 
-        // Clean old failed attempts
-        {
-            let mut failed_attempts = self.failed_attempts.write().await;
-            for attempts in failed_attempts.values_mut() {
-                attempts.retain(|&time| now.duration_since(time).unwrap_or(Duration::ZERO) < Duration::from_secs(3600));
-            }
-            failed_attempts.retain(|_, attempts| !attempts.is_empty());
-        }
+        Err(FshError::PermissionDenied(format!("{} '{}' denied by policy ({})", action, subject, rule)))
+    }
 
+    pub async fn clean_expired_entries(&self) -> FshResult<()> {
+        self.lockout.clean_expired();
         Ok(())
     }
 
+    pub fn auth_manager(&self) -> &AuthManager {
+        &self.auth_manager
+    }
+
+    pub fn audit_logger(&self) -> &AuditLogger {
+        &self.audit_logger
+    }
+
     pub async fn get_security_stats(&self) -> SecurityStats {
-        let blocked_ips = self.blocked_ips.read().await;
-        let failed_attempts = self.failed_attempts.read().await;
+        let now = chrono::Utc::now();
+        let lockouts = self.lockout.list();
 
         SecurityStats {
-            blocked_ips_count: blocked_ips.len(),
-            failed_attempts_count: failed_attempts.values().map(|v| v.len()).sum(),
+            blocked_ips_count: lockouts.iter()
+                .filter(|status| status.key.starts_with("ip:") && status.locked_until.is_some_and(|until| until > now))
+                .count(),
+            failed_attempts_count: lockouts.iter().map(|status| status.failures as usize).sum(),
             active_sessions_count: 0, // TODO: Track from session manager
         }
     }
@@ -271,6 +282,11 @@ mod tests {
             max_failed_attempts: 3,
             enable_logging: true,
             log_file: None,
+            rate_limits: RateLimitConfig::default(),
+            lockout: LockoutConfig { ip_threshold: 3, ..LockoutConfig::default() },
+            tokens: vec![],
+            token_store: None,
+            development_mode: false,
         };
 
         let security_manager = SecurityManager::new(&config).unwrap();
@@ -296,6 +312,11 @@ mod tests {
             max_failed_attempts: 3,
             enable_logging: false, // Disable logging for test
             log_file: None,
+            rate_limits: RateLimitConfig::default(),
+            lockout: LockoutConfig::default(),
+            tokens: vec![],
+            token_store: None,
+            development_mode: false,
         };
 
         let security_manager = SecurityManager::new(&config).unwrap();
@@ -313,5 +334,12 @@ mod tests {
 
         // Dangerous command should be blocked
         assert!(security_manager.validate_command(&context, "rm -rf /").await.is_err());
+
+        let policy = Policy::new(&PolicyConfig {
+            rules: vec![r#"deny command "git push *""#.to_string()],
+            ..PolicyConfig::default()
+        }).unwrap();
+        assert!(security_manager.validate_policy(&context, &policy, PolicyAction::Command, "git pull").await.is_ok());
+        assert!(security_manager.validate_policy(&context, &policy, PolicyAction::Command, "git push origin").await.is_err());
     }
 }
\ No newline at end of file
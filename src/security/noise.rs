@@ -0,0 +1,440 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use rand::RngCore;
+use snow::{Builder, HandshakeState, TransportState};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::codec::{Framed, FramedParts};
+use tracing::{info, warn};
+
+use crate::config::{decrypt_if_encrypted, Config, NoiseConfig};
+use crate::protocol::{FshError, FshFramed, FshMessage, FshResult, FshStream};
+
+/// Handshake pattern and algorithms used for Noise connections.
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, ciphertext and tag included.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Authentication tag added to each transport message.
+const NOISE_TAG_LEN: usize = 16;
+
+/// Plaintext carried by one transport message.
+const MAX_NOISE_PAYLOAD: usize = MAX_NOISE_MESSAGE - NOISE_TAG_LEN;
+
+/// A static X25519 key pair identifying a Noise peer.
+#[derive(Clone)]
+pub struct NoiseKey {
+    private: [u8; 32],
+    public: [u8; 32],
+}
+
+impl std::fmt::Debug for NoiseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKey").field("public", &self.public_key()).finish_non_exhaustive()
+    }
+}
+
+impl NoiseKey {
+    pub fn generate() -> Self {
+        let mut private = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut private);
+        Self::from_private(private)
+    }
+
+    pub fn from_private(private: [u8; 32]) -> Self {
+        let public = MontgomeryPoint::mul_base_clamped(private).to_bytes();
+        Self { private, public }
+    }
+
+    /// Read a key saved with `save`; the file may be encrypted with the master key.
+    pub fn load(path: &Path) -> FshResult<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| FshError::io(format!("Cannot read Noise key {}", path.display()), e))?;
+        let private = decode_key(&decrypt_if_encrypted(content)?)
+            .map_err(|e| FshError::ConfigError(format!("Invalid Noise key in {}: {}", path.display(), e.detail())))?;
+        Ok(Self::from_private(private))
+    }
+
+    /// Read the key at `path`, generating and saving one if there is none yet.
+    pub fn load_or_generate(path: &Path) -> FshResult<Self> {
+        if path.exists() {
+            return Self::load(path);
+        }
+        let key = Self::generate();
+        key.save(path)?;
+        info!("Generated Noise key {} with public key {}", path.display(), key.public_key());
+        Ok(key)
+    }
+
+    /// Write the private key as base64, readable only by its owner.
+    pub fn save(&self, path: &Path) -> FshResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| FshError::io(format!("Cannot create {}", parent.display()), e))?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)
+            .map_err(|e| FshError::io(format!("Cannot create Noise key {}", path.display()), e))?;
+        writeln!(file, "{}", BASE64.encode(self.private))
+            .map_err(|e| FshError::io(format!("Cannot write Noise key {}", path.display()), e))
+    }
+
+    /// The base64 public key, as listed in `authorized_keys` and given to clients.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public)
+    }
+}
+
+/// Decode a base64 X25519 key.
+pub fn decode_key(key: &str) -> FshResult<[u8; 32]> {
+    let bytes = BASE64.decode(key.trim())
+        .map_err(|e| FshError::ConfigError(format!("Noise key is not base64: {}", e)))?;
+    bytes.try_into()
+        .map_err(|_| FshError::ConfigError("Noise key must be 32 bytes".to_string()))
+}
+
+/// The server's side of Noise: its key and the client keys it accepts.
+#[derive(Debug)]
+pub struct NoiseServer {
+    key: NoiseKey,
+    authorized_keys: Vec<[u8; 32]>,
+    required: bool,
+}
+
+impl NoiseServer {
+    /// Load the server's key for a `[server.noise]` section, generating it on first start.
+    pub fn from_config(config: &NoiseConfig) -> FshResult<Self> {
+        let key_file = config.key_file.as_deref()
+            .ok_or_else(|| FshError::ConfigError("noise key_file is not set".to_string()))?;
+        let key = NoiseKey::load_or_generate(key_file)?;
+        let authorized_keys = config.authorized_keys.iter()
+            .map(|key| decode_key(key))
+            .collect::<FshResult<Vec<_>>>()?;
+        if authorized_keys.is_empty() {
+            warn!("Noise is enabled but no authorized_keys are listed, so every Noise client will be refused");
+        }
+        Ok(Self { key, authorized_keys, required: config.required })
+    }
+
+    pub fn public_key(&self) -> String {
+        self.key.public_key()
+    }
+
+    /// Whether clients that don't ask for Noise are refused.
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    /// Answer the client's handshake on the stream under `framed`, returning
+    /// it framed again over the encrypted channel, with the client's public
+    /// key. The key is returned even if it isn't authorized, so the caller
+    /// can tell the client over the channel before closing it.
+    pub async fn accept(&self, framed: FshFramed<FshStream>) -> FshResult<(FshFramed<FshStream>, String, bool)> {
+        let handshake = Builder::new(noise_params())
+            .local_private_key(&self.key.private)
+            .build_responder()
+            .map_err(noise_error)?;
+        let (mut io, codec) = unframe(framed)?;
+
+        let mut handshake = handshake;
+        read_handshake(&mut io, &mut handshake).await?;
+        write_handshake(&mut io, &mut handshake).await?;
+        read_handshake(&mut io, &mut handshake).await?;
+
+        let client_key: [u8; 32] = handshake.get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| FshError::ProtocolError("Noise client sent no static key".to_string()))?;
+        let authorized = self.authorized_keys.contains(&client_key);
+        let transport = handshake.into_transport_mode().map_err(noise_error)?;
+        Ok((reframe(io, transport, codec), BASE64.encode(client_key), authorized))
+    }
+}
+
+/// How a client identifies itself and the server over Noise.
+#[derive(Debug, Clone)]
+pub struct NoiseClientConfig {
+    /// The client's private key, generated on first use if missing.
+    pub key_file: PathBuf,
+    /// The server's base64 public key; any other server is refused.
+    pub server_key: String,
+}
+
+impl NoiseClientConfig {
+    pub fn new<P: AsRef<Path>>(key_file: P, server_key: impl Into<String>) -> Self {
+        Self { key_file: key_file.as_ref().to_path_buf(), server_key: server_key.into() }
+    }
+
+    /// `noise_key` next to the default config file, e.g. `~/.config/fsh/noise_key`.
+    pub fn default_key_path() -> FshResult<PathBuf> {
+        Ok(Config::get_default_config_path()?.with_file_name("noise_key"))
+    }
+
+    /// Run the handshake as initiator on the stream under `framed`,
+    /// returning it framed again over the encrypted channel.
+    pub async fn connect(&self, framed: FshFramed<FshStream>) -> FshResult<FshFramed<FshStream>> {
+        let key = NoiseKey::load_or_generate(&self.key_file)?;
+        let server_key = decode_key(&self.server_key)?;
+        let mut handshake = Builder::new(noise_params())
+            .local_private_key(&key.private)
+            .build_initiator()
+            .map_err(noise_error)?;
+        let (mut io, codec) = unframe(framed)?;
+
+        write_handshake(&mut io, &mut handshake).await?;
+        read_handshake(&mut io, &mut handshake).await?;
+        // Check the server before revealing who we are in the last message
+        if handshake.get_remote_static() != Some(&server_key[..]) {
+            let presented = handshake.get_remote_static().map(|key| BASE64.encode(key)).unwrap_or_default();
+            return Err(FshError::PermissionDenied(format!(
+                "Server presented Noise key {}, expected {}", presented, self.server_key.trim()
+            )));
+        }
+        write_handshake(&mut io, &mut handshake).await?;
+
+        let transport = handshake.into_transport_mode().map_err(noise_error)?;
+        Ok(reframe(io, transport, codec))
+    }
+}
+
+fn noise_params() -> snow::params::NoiseParams {
+    NOISE_PARAMS.parse().expect("valid Noise parameters")
+}
+
+fn noise_error(e: snow::Error) -> FshError {
+    FshError::ProtocolError(format!("Noise handshake failed: {}", e))
+}
+
+/// Take the stream out of `framed`. Nothing may be buffered: each side waits
+/// for the other's Connect message before starting the handshake.
+fn unframe(framed: FshFramed<FshStream>) -> FshResult<(FshStream, crate::protocol::FshCodec)> {
+    let parts = framed.into_parts();
+    if !parts.read_buf.is_empty() || !parts.write_buf.is_empty() {
+        return Err(FshError::ProtocolError("Unexpected data before the Noise handshake".to_string()));
+    }
+    Ok((parts.io, parts.codec))
+}
+
+fn reframe(io: FshStream, transport: TransportState, codec: crate::protocol::FshCodec) -> FshFramed<FshStream> {
+    let stream = FshStream::custom(NoiseStream::new(io, transport));
+    Framed::from_parts(FramedParts::new::<FshMessage>(stream, codec))
+}
+
+async fn write_handshake(io: &mut FshStream, handshake: &mut HandshakeState) -> FshResult<()> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = handshake.write_message(&[], &mut message).map_err(noise_error)?;
+    io.write_all(&(len as u16).to_be_bytes()).await
+        .and(io.write_all(&message[..len]).await)
+        .and(io.flush().await)
+        .map_err(|e| FshError::io("Noise handshake failed", e))
+}
+
+async fn read_handshake(io: &mut FshStream, handshake: &mut HandshakeState) -> FshResult<()> {
+    let mut len = [0u8; 2];
+    io.read_exact(&mut len).await.map_err(|e| FshError::io("Noise handshake failed", e))?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    io.read_exact(&mut message).await.map_err(|e| FshError::io("Noise handshake failed", e))?;
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    handshake.read_message(&message, &mut payload).map_err(noise_error)?;
+    Ok(())
+}
+
+/// A stream encrypted with an established Noise session. Bytes are sent as
+/// Noise transport messages, each after its two-byte big-endian length.
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: Box<TransportState>,
+    /// A transport message being read, length prefix included.
+    incoming: Vec<u8>,
+    /// Decrypted bytes not yet read, from `read_pos`.
+    plaintext: Vec<u8>,
+    read_pos: usize,
+    /// Encrypted bytes not yet written, from `write_pos`.
+    outgoing: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> NoiseStream<S> {
+    pub fn new(inner: S, transport: TransportState) -> Self {
+        Self {
+            inner,
+            transport: Box::new(transport),
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            read_pos: 0,
+            outgoing: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Length of the transport message in `incoming`, once its prefix is in.
+    fn incoming_len(&self) -> Option<usize> {
+        (self.incoming.len() >= 2).then(|| 2 + u16::from_be_bytes([self.incoming[0], self.incoming[1]]) as usize)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    fn poll_write_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.outgoing.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.write_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.outgoing.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.plaintext.len() {
+            let wanted = this.incoming_len().unwrap_or(2);
+            if this.incoming.len() < wanted {
+                let mut chunk = [0u8; 8192];
+                let limit = chunk.len().min(wanted - this.incoming.len());
+                let mut chunk_buf = ReadBuf::new(&mut chunk[..limit]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+                if chunk_buf.filled().is_empty() {
+                    if this.incoming.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Noise message cut short")));
+                }
+                this.incoming.extend_from_slice(chunk_buf.filled());
+                continue;
+            }
+
+            this.plaintext.resize(MAX_NOISE_MESSAGE, 0);
+            let len = this.transport.read_message(&this.incoming[2..], &mut this.plaintext)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Noise decryption failed: {}", e)))?;
+            this.plaintext.truncate(len);
+            this.read_pos = 0;
+            this.incoming.clear();
+        }
+
+        let available = &this.plaintext[this.read_pos..];
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+
+        let n = buf.len().min(MAX_NOISE_PAYLOAD);
+        let mut message = vec![0u8; n + NOISE_TAG_LEN];
+        let len = this.transport.write_message(&buf[..n], &mut message)
+            .map_err(|e| io::Error::other(format!("Noise encryption failed: {}", e)))?;
+        this.outgoing.extend_from_slice(&(len as u16).to_be_bytes());
+        this.outgoing.extend_from_slice(&message[..len]);
+
+        // The message is queued either way; flushing finishes sending it
+        if let Poll::Ready(Err(e)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ErrorMessage, FshCodec, FshErrorCode};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_noise_key_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("fsh/noise_key");
+        let key = NoiseKey::load_or_generate(&path).unwrap();
+        assert_eq!(NoiseKey::load_or_generate(&path).unwrap().public_key(), key.public_key());
+        assert_eq!(decode_key(&key.public_key()).unwrap(), key.public);
+
+        // The public key is the one snow derives for the private key
+        let mut handshake = Builder::new(noise_params()).local_private_key(&key.private).build_initiator().unwrap();
+        let server_key = NoiseKey::generate();
+        let mut responder = Builder::new(noise_params()).local_private_key(&server_key.private).build_responder().unwrap();
+        let mut message = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+        let len = handshake.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut message).unwrap();
+        handshake.read_message(&message[..len], &mut payload).unwrap();
+        let len = handshake.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(responder.get_remote_static(), Some(&key.public[..]));
+    }
+
+    #[tokio::test]
+    async fn test_noise_handshake_and_stream() {
+        let temp_dir = TempDir::new().unwrap();
+        let client_key = NoiseKey::generate();
+        client_key.save(&temp_dir.path().join("client_key")).unwrap();
+        let server = NoiseServer::from_config(&NoiseConfig {
+            enabled: true,
+            key_file: Some(temp_dir.path().join("server_key")),
+            authorized_keys: vec![client_key.public_key()],
+            required: false,
+        }).unwrap();
+        let client = NoiseClientConfig::new(temp_dir.path().join("client_key"), server.public_key());
+
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let (client_framed, server_accepted) = tokio::join!(
+            client.connect(FshCodec::framed(FshStream::from(client_io))),
+            server.accept(FshCodec::framed(FshStream::from(server_io))),
+        );
+        let mut client_framed = client_framed.unwrap();
+        let (mut server_framed, client_public, authorized) = server_accepted.unwrap();
+        assert_eq!(client_public, client_key.public_key());
+        assert!(authorized);
+
+        // Messages larger than one Noise message are split and reassembled
+        let data = "x".repeat(200_000);
+        let message = FshMessage::Error(ErrorMessage::new(FshErrorCode::ProtocolError, data.clone()));
+        let (sent, received) = tokio::join!(
+            FshCodec::write_message(&mut client_framed, message),
+            FshCodec::read_message(&mut server_framed),
+        );
+        sent.unwrap();
+        match received.unwrap() {
+            FshMessage::Error(error) => assert_eq!(error.message, data),
+            other => panic!("unexpected {:?}", other.message_type()),
+        }
+
+        // A client expecting another server key stops before identifying itself
+        let wrong = NoiseClientConfig::new(temp_dir.path().join("client_key"), NoiseKey::generate().public_key());
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let (connected, _) = tokio::join!(
+            wrong.connect(FshCodec::framed(FshStream::from(client_io))),
+            server.accept(FshCodec::framed(FshStream::from(server_io))),
+        );
+        assert!(matches!(connected, Err(FshError::PermissionDenied(_))));
+    }
+}
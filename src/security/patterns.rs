@@ -0,0 +1,86 @@
+use aho_corasick::AhoCorasick;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Substrings to screen text for, compiled once so a check is a single
+/// pass over the text however many patterns there are.
+#[derive(Debug, Clone)]
+pub struct PatternSet {
+    patterns: Vec<String>,
+    automaton: AhoCorasick,
+}
+
+impl PatternSet {
+    pub fn new<I, P>(patterns: I) -> FshResult<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        Self::build(patterns.into_iter().map(Into::into).collect(), false)
+    }
+
+    /// Like [`PatternSet::new`], matching ASCII letters regardless of case.
+    pub fn new_ignore_case<I, P>(patterns: I) -> FshResult<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        Self::build(patterns.into_iter().map(Into::into).collect(), true)
+    }
+
+    fn build(patterns: Vec<String>, ignore_case: bool) -> FshResult<Self> {
+        let automaton = AhoCorasick::builder()
+            .ascii_case_insensitive(ignore_case)
+            .build(&patterns)
+            .map_err(|e| FshError::ConfigError(format!("Cannot compile {} patterns: {}", patterns.len(), e)))?;
+        Ok(Self { patterns, automaton })
+    }
+
+    /// The pattern found earliest in `text`, if any.
+    pub fn find(&self, text: &str) -> Option<&str> {
+        self.automaton.find(text).map(|found| self.patterns[found.pattern().as_usize()].as_str())
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.automaton.is_match(text)
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+}
+
+impl Default for PatternSet {
+    fn default() -> Self {
+        Self::new(Vec::<String>::new()).expect("an empty pattern set always compiles")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_matching() {
+        let patterns = PatternSet::new(["rm -rf /", "mkfs", "dd if="]).unwrap();
+        assert_eq!(patterns.find("sudo mkfs.ext4 /dev/sda"), Some("mkfs"));
+        assert_eq!(patterns.find("ls; dd if=/dev/zero; rm -rf /"), Some("dd if="));
+        assert!(!patterns.is_match("MKFS"));
+        assert!(PatternSet::new_ignore_case(["mkfs"]).unwrap().is_match("MKFS"));
+        assert!(!PatternSet::default().is_match("anything"));
+
+        // Thousands of entries are one automaton
+        let many = PatternSet::new((0..5000).map(|n| format!("blocked-{}-", n))).unwrap();
+        assert_eq!(many.len(), 5000);
+        assert_eq!(many.find("run blocked-4321-now"), Some("blocked-4321-"));
+        assert!(!many.is_match("run blocked-now"));
+    }
+}
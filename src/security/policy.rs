@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Characters a `*` in an allow rule's command pattern never matches, so an
+/// allowed prefix can't be extended with a second command. Deny rules match
+/// across them.
+const SHELL_OPERATORS: &[char] = &[';', '&', '|', '<', '>', '`', '$', '(', ')', '\n', '\r'];
+
+/// Rule-based authorization for a folder, configured as a list of rules:
+///
+/// ```text
+/// allow command "git push origin *"
+/// deny command "git push *"
+/// deny write, delete "*.lock"
+/// ```
+///
+/// The first rule that matches decides; `default` applies when none does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    pub rules: Vec<String>,
+    pub default: PolicyDefault,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: PolicyDefault::Lists,
+        }
+    }
+}
+
+/// What happens when no rule matches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyDefault {
+    /// Commands fall back to the allowed/blocked lists; file access is allowed.
+    #[default]
+    Lists,
+    Allow,
+    Deny,
+}
+
+/// Something a client asks to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolicyAction {
+    /// Run a command; the subject is the command line.
+    Command,
+    /// Read a file; the subject is its path relative to the folder root.
+    Read,
+    Write,
+    Delete,
+}
+
+impl PolicyAction {
+    fn parse(word: &str) -> Option<Self> {
+        match word {
+            "command" => Some(PolicyAction::Command),
+            "read" => Some(PolicyAction::Read),
+            "write" => Some(PolicyAction::Write),
+            "delete" => Some(PolicyAction::Delete),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PolicyAction::Command => "command",
+            PolicyAction::Read => "read",
+            PolicyAction::Write => "write",
+            PolicyAction::Delete => "delete",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PolicyRule {
+    effect: Effect,
+    actions: Vec<PolicyAction>,
+    pattern: String,
+    source: String,
+}
+
+impl PolicyRule {
+    fn parse(source: &str) -> FshResult<Self> {
+        let invalid = |reason: &str| FshError::ConfigError(format!("Invalid policy rule '{}': {}", source, reason));
+
+        let line = source.trim();
+        let (effect, rest) = line.split_once(char::is_whitespace).ok_or_else(|| invalid("missing action"))?;
+        let effect = match effect {
+            "allow" => Effect::Allow,
+            "deny" => Effect::Deny,
+            _ => return Err(invalid("rules start with 'allow' or 'deny'")),
+        };
+
+        // Actions are a comma-separated list that ends where the pattern begins
+        let rest = rest.trim_start();
+        let mut actions = Vec::new();
+        let mut remainder = rest;
+        loop {
+            let end = remainder.find(|c: char| c == ',' || c.is_whitespace()).unwrap_or(remainder.len());
+            let action = PolicyAction::parse(&remainder[..end])
+                .ok_or_else(|| invalid(&format!("unknown action '{}'", &remainder[..end])))?;
+            actions.push(action);
+
+            remainder = remainder[end..].trim_start();
+            match remainder.strip_prefix(',') {
+                Some(next) => remainder = next.trim_start(),
+                None => break,
+            }
+        }
+
+        let pattern = match remainder.strip_prefix('"') {
+            Some(quoted) => quoted.strip_suffix('"').ok_or_else(|| invalid("unterminated quote"))?,
+            None => remainder,
+        };
+        if pattern.is_empty() {
+            return Err(invalid("missing pattern"));
+        }
+
+        Ok(Self {
+            effect,
+            actions,
+            pattern: pattern.to_string(),
+            source: line.to_string(),
+        })
+    }
+
+    fn matches(&self, action: PolicyAction, subject: &str) -> bool {
+        if !self.actions.contains(&action) {
+            return false;
+        }
+
+        match action {
+            PolicyAction::Command => {
+                let stops = if self.effect == Effect::Allow { SHELL_OPERATORS } else { &[] };
+                glob_match(self.pattern.as_bytes(), subject.as_bytes(), stops)
+            }
+            _ => {
+                // Patterns without a slash match the file name anywhere, like .gitignore
+                let pattern = self.pattern.trim_start_matches('/');
+                let subject = if pattern.contains('/') {
+                    subject
+                } else {
+                    subject.rsplit('/').next().unwrap_or(subject)
+                };
+                glob_match(pattern.as_bytes(), subject.as_bytes(), &['/'])
+            }
+        }
+    }
+}
+
+/// The outcome of checking a request against a policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    /// Denied by the given rule, or by the default.
+    Deny(String),
+    /// No rule matched and the policy defers to the allowed/blocked lists.
+    NoMatch,
+}
+
+/// A parsed [`PolicyConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<PolicyRule>,
+    default: PolicyDefault,
+}
+
+impl Policy {
+    pub fn new(config: &PolicyConfig) -> FshResult<Self> {
+        Ok(Self {
+            rules: config.rules.iter().map(|rule| PolicyRule::parse(rule)).collect::<FshResult<_>>()?,
+            default: config.default,
+        })
+    }
+
+    pub fn decide(&self, action: PolicyAction, subject: &str) -> PolicyDecision {
+        match self.rules.iter().find(|rule| rule.matches(action, subject)) {
+            Some(rule) if rule.effect == Effect::Allow => PolicyDecision::Allow,
+            Some(rule) => PolicyDecision::Deny(rule.source.clone()),
+            None => match self.default {
+                PolicyDefault::Allow => PolicyDecision::Allow,
+                PolicyDefault::Deny => PolicyDecision::Deny("default deny".to_string()),
+                PolicyDefault::Lists if action == PolicyAction::Command => PolicyDecision::NoMatch,
+                PolicyDefault::Lists => PolicyDecision::Allow,
+            },
+        }
+    }
+
+    /// Check access to `path`, given relative to the folder root.
+    pub fn check_path(&self, action: PolicyAction, path: &Path) -> FshResult<()> {
+        let subject = path.to_string_lossy().replace('\\', "/");
+        match self.decide(action, &subject) {
+            PolicyDecision::Deny(rule) => Err(FshError::PermissionDenied(format!(
+                "{} access to '{}' denied by policy ({})", action, subject, rule
+            ))),
+            PolicyDecision::Allow | PolicyDecision::NoMatch => Ok(()),
+        }
+    }
+}
+
+/// Whether a command line matches `pattern`, where `*` matches anything.
+pub fn command_matches(pattern: &str, command_line: &str) -> bool {
+    glob_match(pattern.as_bytes(), command_line.as_bytes(), &[])
+}
+
+/// Whether a file name matches `pattern`, where `*` matches anything but `/`.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    glob_match(pattern.as_bytes(), name.as_bytes(), &['/'])
+}
+
+/// Match `subject` against a glob where `?` is one character, `*` is any run
+/// of characters not in `stops`, and `**` is any run of characters at all.
+fn glob_match(pattern: &[u8], subject: &[u8], stops: &[char]) -> bool {
+    match pattern.split_first() {
+        None => subject.is_empty(),
+        Some((b'*', rest)) => {
+            let (rest, unbounded) = match rest.strip_prefix(b"*") {
+                Some(rest) => (rest, true),
+                None => (rest, false),
+            };
+            let mut i = 0;
+            loop {
+                if glob_match(rest, &subject[i..], stops) {
+                    return true;
+                }
+                match subject.get(i) {
+                    Some(&c) if unbounded || !stops.contains(&(c as char)) => i += 1,
+                    _ => return false,
+                }
+            }
+        }
+        Some((b'?', rest)) => !subject.is_empty() && glob_match(rest, &subject[1..], stops),
+        Some((&c, rest)) => subject.first() == Some(&c) && glob_match(rest, &subject[1..], stops),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[&str], default: PolicyDefault) -> Policy {
+        Policy::new(&PolicyConfig {
+            rules: rules.iter().map(|rule| rule.to_string()).collect(),
+            default,
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = policy(&[
+            r#"allow command "git push origin *""#,
+            r#"deny command "git push *""#,
+            r#"deny write, delete "*.lock""#,
+        ], PolicyDefault::Lists);
+
+        assert_eq!(policy.decide(PolicyAction::Command, "git push origin main"), PolicyDecision::Allow);
+        assert_eq!(
+            policy.decide(PolicyAction::Command, "git push upstream main"),
+            PolicyDecision::Deny(r#"deny command "git push *""#.to_string())
+        );
+        assert_eq!(policy.decide(PolicyAction::Command, "git status"), PolicyDecision::NoMatch);
+
+        // `*` doesn't reach past a shell operator
+        assert!(matches!(policy.decide(PolicyAction::Command, "git push origin x; git push evil"), PolicyDecision::Deny(_)));
+
+        assert!(policy.check_path(PolicyAction::Write, Path::new("sub/Cargo.lock")).is_err());
+        assert!(policy.check_path(PolicyAction::Read, Path::new("Cargo.lock")).is_ok());
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        let policy = policy(&["allow read docs/**", "deny read, write secrets/*"], PolicyDefault::Deny);
+
+        assert!(policy.check_path(PolicyAction::Read, Path::new("docs/a/b.md")).is_ok());
+        assert!(policy.check_path(PolicyAction::Read, Path::new("secrets/key")).is_err());
+        // Unmatched requests fall to the default
+        assert!(policy.check_path(PolicyAction::Read, Path::new("src/main.rs")).is_err());
+        assert_eq!(policy.decide(PolicyAction::Command, "ls"), PolicyDecision::Deny("default deny".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in ["permit command ls", "allow run ls", "allow command", r#"deny read "*.key"#] {
+            let config = PolicyConfig { rules: vec![rule.to_string()], ..PolicyConfig::default() };
+            assert!(Policy::new(&config).is_err(), "{} should be rejected", rule);
+        }
+    }
+}
@@ -1,7 +1,198 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
+use crate::protocol::{FshError, FshResult};
+
+/// Requests allowed per window for one kind of request, counted separately
+/// for each client IP, auth token and session. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitBudget {
+    pub per_ip: Option<usize>,
+    pub per_token: Option<usize>,
+    pub per_session: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub window_seconds: u64,
+    pub auth: RateLimitBudget,
+    pub commands: RateLimitBudget,
+    pub file_ops: RateLimitBudget,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            window_seconds: 60,
+            auth: RateLimitBudget { per_ip: Some(30), per_token: Some(10), per_session: None },
+            commands: RateLimitBudget::default(),
+            file_ops: RateLimitBudget::default(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    fn budget(&self, kind: RateLimitKind) -> &RateLimitBudget {
+        match kind {
+            RateLimitKind::Auth => &self.auth,
+            RateLimitKind::Command => &self.commands,
+            RateLimitKind::FileOp => &self.file_ops,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitKind {
+    Auth,
+    Command,
+    FileOp,
+}
+
+impl RateLimitKind {
+    const ALL: [RateLimitKind; 3] = [RateLimitKind::Auth, RateLimitKind::Command, RateLimitKind::FileOp];
+}
+
+impl std::fmt::Display for RateLimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RateLimitKind::Auth => "authentication attempts",
+            RateLimitKind::Command => "commands",
+            RateLimitKind::FileOp => "file operations",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitScope {
+    Ip,
+    Token,
+    Session,
+}
+
+impl RateLimitScope {
+    const ALL: [RateLimitScope; 3] = [RateLimitScope::Ip, RateLimitScope::Token, RateLimitScope::Session];
+
+    fn limit(&self, budget: &RateLimitBudget) -> Option<usize> {
+        match self {
+            RateLimitScope::Ip => budget.per_ip,
+            RateLimitScope::Token => budget.per_token,
+            RateLimitScope::Session => budget.per_session,
+        }
+    }
+}
+
+/// An identity a request is counted against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    /// Holds a hash of the token, never the token itself.
+    Token(String),
+    Session(String),
+}
+
+impl RateLimitKey {
+    pub fn token(token: &str) -> Self {
+        RateLimitKey::Token(hex::encode(Sha256::digest(token.as_bytes())))
+    }
+
+    fn scope(&self) -> RateLimitScope {
+        match self {
+            RateLimitKey::Ip(_) => RateLimitScope::Ip,
+            RateLimitKey::Token(_) => RateLimitScope::Token,
+            RateLimitKey::Session(_) => RateLimitScope::Session,
+        }
+    }
+
+    fn identifier(&self) -> String {
+        match self {
+            RateLimitKey::Ip(ip) => ip.to_string(),
+            RateLimitKey::Token(hash) | RateLimitKey::Session(hash) => hash.clone(),
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            RateLimitKey::Ip(_) => "address",
+            RateLimitKey::Token(_) => "token",
+            RateLimitKey::Session(_) => "session",
+        }
+    }
+}
+
+/// The server's rate limits: one limiter per kind of request and identity
+/// that has a budget.
+#[derive(Debug)]
+pub struct RateLimits {
+    limiters: HashMap<(RateLimitKind, RateLimitScope), RateLimiter>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let window = Duration::from_secs(config.window_seconds);
+        let mut limiters = HashMap::new();
+        for kind in RateLimitKind::ALL {
+            for scope in RateLimitScope::ALL {
+                if let Some(limit) = scope.limit(config.budget(kind)) {
+                    limiters.insert((kind, scope), RateLimiter::new(limit, window));
+                }
+            }
+        }
+        Self { limiters }
+    }
+
+    /// Count one request of `kind` against each of `keys`. A request over any
+    /// budget is refused and counted against none of them.
+    pub async fn check(&self, kind: RateLimitKind, keys: &[RateLimitKey]) -> FshResult<()> {
+        let limited: Vec<(&RateLimitKey, &RateLimiter)> = keys.iter()
+            .filter_map(|key| Some((key, self.limiters.get(&(kind, key.scope()))?)))
+            .collect();
+
+        for (key, limiter) in &limited {
+            if limiter.get_remaining(&key.identifier()).await == 0 {
+                return Err(FshError::RateLimited(format!("Too many {} from this {}", kind, key.describe())));
+            }
+        }
+        for (key, limiter) in limited {
+            limiter.allow(key.identifier()).await;
+        }
+        Ok(())
+    }
+
+    pub async fn cleanup_expired(&self) {
+        for limiter in self.limiters.values() {
+            limiter.cleanup_expired().await;
+        }
+    }
+}
+
+/// The server's rate limits bound to one session's IP, token and id.
+#[derive(Debug, Clone)]
+pub struct ClientRateLimits {
+    limits: Arc<RateLimits>,
+    keys: Vec<RateLimitKey>,
+}
+
+impl ClientRateLimits {
+    pub fn new(limits: Arc<RateLimits>, ip: Option<IpAddr>, token: Option<&str>, session_id: &str) -> Self {
+        let keys = ip.map(RateLimitKey::Ip).into_iter()
+            .chain(token.map(RateLimitKey::token))
+            .chain(std::iter::once(RateLimitKey::Session(session_id.to_string())))
+            .collect();
+        Self { limits, keys }
+    }
+
+    pub async fn check(&self, kind: RateLimitKind) -> FshResult<()> {
+        self.limits.check(kind, &self.keys).await
+    }
+}
+
 #[derive(Debug)]
 pub struct RateLimiter {
     requests: RwLock<HashMap<String, Vec<Instant>>>,
@@ -253,6 +444,40 @@ mod tests {
         assert!(limiter.get_remaining("client2").await <= 1);
     }
 
+    #[tokio::test]
+    async fn test_budgets_per_identity() {
+        let limits = Arc::new(RateLimits::new(&RateLimitConfig {
+            window_seconds: 60,
+            auth: RateLimitBudget { per_ip: None, per_token: Some(2), per_session: None },
+            commands: RateLimitBudget { per_ip: Some(3), per_token: None, per_session: Some(2) },
+            file_ops: RateLimitBudget::default(),
+        }));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // Clients behind one address have separate session budgets
+        let first = ClientRateLimits::new(Arc::clone(&limits), Some(ip), Some("token-a"), "s1");
+        let second = ClientRateLimits::new(Arc::clone(&limits), Some(ip), Some("token-b"), "s2");
+        assert!(first.check(RateLimitKind::Command).await.is_ok());
+        assert!(first.check(RateLimitKind::Command).await.is_ok());
+        assert!(matches!(first.check(RateLimitKind::Command).await, Err(FshError::RateLimited(_))));
+        assert!(second.check(RateLimitKind::Command).await.is_ok());
+        // ...but still share the address budget
+        assert!(second.check(RateLimitKind::Command).await.is_err());
+
+        // Token budgets follow the token across addresses
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let token = [RateLimitKey::Ip(ip), RateLimitKey::token("token-a")];
+        assert!(limits.check(RateLimitKind::Auth, &token).await.is_ok());
+        assert!(limits.check(RateLimitKind::Auth, &[RateLimitKey::Ip(other_ip), RateLimitKey::token("token-a")]).await.is_ok());
+        assert!(limits.check(RateLimitKind::Auth, &token).await.is_err());
+        assert!(limits.check(RateLimitKind::Auth, &[RateLimitKey::token("token-b")]).await.is_ok());
+
+        // Kinds without a budget are unlimited
+        for _ in 0..10 {
+            assert!(first.check(RateLimitKind::FileOp).await.is_ok());
+        }
+    }
+
     #[tokio::test]
     async fn test_cleanup() {
         let limiter = RateLimiter::new(2, Duration::from_millis(50));
@@ -0,0 +1,256 @@
+use rustls::client::{ServerCertVerifier, WebPkiVerifier};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::TlsConnector;
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{decrypt_if_encrypted, TlsConfig};
+use crate::protocol::{FshError, FshResult};
+use super::{KnownServerVerifier, KnownServers};
+
+/// Build the rustls configuration for a server's `[server.tls]` section.
+pub fn server_tls_config(config: &TlsConfig) -> FshResult<Arc<ServerConfig>> {
+    let cert_file = config.cert_file.as_deref()
+        .ok_or_else(|| FshError::ConfigError("tls cert_file is not set".to_string()))?;
+    let key_file = config.key_file.as_deref()
+        .ok_or_else(|| FshError::ConfigError("tls key_file is not set".to_string()))?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca_file {
+        Some(ca_file) => {
+            let roots = load_roots(ca_file)?;
+            let verifier = if config.require_client_cert {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(load_certs(cert_file)?, load_key(key_file)?)
+        .map_err(|e| FshError::ConfigError(format!("Invalid TLS certificate or key: {}", e)))?;
+    Ok(Arc::new(server_config))
+}
+
+/// How a client verifies the server and, optionally, identifies itself.
+#[derive(Debug, Clone)]
+pub struct TlsClientConfig {
+    /// PEM bundle of CAs trusted to sign the server's certificate.
+    pub ca_file: Option<PathBuf>,
+    /// File of keys servers presented on first connect. A server whose key
+    /// differs from the recorded one is refused.
+    pub known_servers: Option<PathBuf>,
+    /// PEM client certificate chain and key, for certificate authentication.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Name expected in the server's certificate; defaults to the host the
+    /// client connects to.
+    pub server_name: Option<String>,
+}
+
+impl TlsClientConfig {
+    pub fn new<P: AsRef<Path>>(ca_file: P) -> Self {
+        Self {
+            ca_file: Some(ca_file.as_ref().to_path_buf()),
+            known_servers: None,
+            cert_file: None,
+            key_file: None,
+            server_name: None,
+        }
+    }
+
+    /// Trust a server's key the first time it is seen, recording it in
+    /// `known_servers`, and refuse the server if it changes later.
+    pub fn trust_on_first_use<P: AsRef<Path>>(known_servers: P) -> Self {
+        Self {
+            ca_file: None,
+            known_servers: Some(known_servers.as_ref().to_path_buf()),
+            cert_file: None,
+            key_file: None,
+            server_name: None,
+        }
+    }
+
+    /// Also pin the key of a server whose certificate the CA signed.
+    pub fn with_known_servers<P: AsRef<Path>>(mut self, known_servers: P) -> Self {
+        self.known_servers = Some(known_servers.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_client_cert<P: AsRef<Path>>(mut self, cert_file: P, key_file: P) -> Self {
+        self.cert_file = Some(cert_file.as_ref().to_path_buf());
+        self.key_file = Some(key_file.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_server_name(mut self, server_name: String) -> Self {
+        self.server_name = Some(server_name);
+        self
+    }
+
+    pub fn has_client_cert(&self) -> bool {
+        self.cert_file.is_some()
+    }
+
+    /// Build a connector and the name to verify for a server at `server_addr`.
+    pub fn connector(&self, server_addr: &str) -> FshResult<(TlsConnector, ServerName)> {
+        let ca = self.ca_file.as_deref().map(load_roots).transpose()?
+            .map(|roots| WebPkiVerifier::new(roots, None));
+        let verifier: Arc<dyn ServerCertVerifier> = match (ca, &self.known_servers) {
+            (ca, Some(known_servers)) => Arc::new(KnownServerVerifier::new(ca, KnownServers::new(known_servers), server_addr)),
+            (Some(ca), None) => Arc::new(ca),
+            (None, None) => return Err(FshError::ConfigError(
+                "TLS needs a CA bundle or a known servers file to check the server with".to_string()
+            )),
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+
+        let client_config = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => builder
+                .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+                .map_err(|e| FshError::ConfigError(format!("Invalid client certificate or key: {}", e)))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(FshError::ConfigError(
+                "A client certificate needs both a certificate and a key file".to_string()
+            )),
+        };
+
+        let host = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => server_host(server_addr),
+        };
+        let server_name = ServerName::try_from(host)
+            .map_err(|_| FshError::ConfigError(format!("Invalid TLS server name '{}'", host)))?;
+
+        Ok((TlsConnector::from(Arc::new(client_config)), server_name))
+    }
+}
+
+/// The names in a verified client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertIdentity {
+    pub common_name: Option<String>,
+    /// DNS, email and URI subject alternative names.
+    pub alt_names: Vec<String>,
+}
+
+impl CertIdentity {
+    pub fn from_der(der: &[u8]) -> FshResult<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| FshError::ProtocolError(format!("Unreadable client certificate: {}", e)))?;
+
+        let common_name = cert.subject().iter_common_name()
+            .find_map(|attr| attr.as_str().ok())
+            .map(str::to_string);
+
+        let alt_names = cert.subject_alternative_name()
+            .map_err(|e| FshError::ProtocolError(format!("Unreadable client certificate: {}", e)))?
+            .map(|san| san.value.general_names.iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                        Some(name.to_string())
+                    }
+                    _ => None,
+                })
+                .collect())
+            .unwrap_or_default();
+
+        Ok(Self { common_name, alt_names })
+    }
+
+    /// The user the certificate authenticates: its CN, or its first SAN.
+    pub fn user(&self) -> Option<&str> {
+        self.common_name.as_deref().or_else(|| self.alt_names.first().map(String::as_str))
+    }
+
+    /// Every name folder ACLs may match against.
+    pub fn names(&self) -> Vec<String> {
+        self.common_name.iter().chain(&self.alt_names).cloned().collect()
+    }
+}
+
+fn server_host(server_addr: &str) -> &str {
+    let host = server_addr.rsplit_once(':').map_or(server_addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Read a PEM file, which may be encrypted with the config master key.
+fn read_pem(path: &Path) -> FshResult<Vec<rustls_pemfile::Item>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| FshError::ConfigError(format!("Failed to open {}: {}", path.display(), e)))?;
+    let content = decrypt_if_encrypted(content)?;
+    rustls_pemfile::read_all(&mut BufReader::new(content.as_bytes()))
+        .map_err(|e| FshError::ConfigError(format!("Failed to parse {}: {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> FshResult<Vec<Certificate>> {
+    let certs: Vec<Certificate> = read_pem(path)?.into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(FshError::ConfigError(format!("No certificates found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> FshResult<PrivateKey> {
+    read_pem(path)?.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| FshError::ConfigError(format!("No private key found in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> FshResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)
+            .map_err(|e| FshError::ConfigError(format!("Invalid CA certificate in {}: {}", path.display(), e)))?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate as GeneratedCert, CertificateParams, DnType, SanType};
+
+    #[test]
+    fn test_cert_identity() {
+        let mut params = CertificateParams::new(vec!["build-agent.internal".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.subject_alt_names.push(SanType::Rfc822Name("alice@example.com".to_string()));
+        let cert = GeneratedCert::from_params(params).unwrap();
+
+        let identity = CertIdentity::from_der(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(identity.user(), Some("alice"));
+        assert_eq!(identity.names(), vec!["alice", "build-agent.internal", "alice@example.com"]);
+
+        // Without a CN the first SAN names the user
+        let mut params = CertificateParams::new(vec!["build-agent.internal".to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let cert = GeneratedCert::from_params(params).unwrap();
+        let identity = CertIdentity::from_der(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(identity.user(), Some("build-agent.internal"));
+    }
+
+    #[test]
+    fn test_server_host() {
+        assert_eq!(server_host("127.0.0.1:2222"), "127.0.0.1");
+        assert_eq!(server_host("fsh.example.com:2222"), "fsh.example.com");
+        assert_eq!(server_host("[::1]:2222"), "::1");
+    }
+}
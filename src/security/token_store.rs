@@ -0,0 +1,230 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::config::keychain_lookup;
+use crate::protocol::{FshError, FshResult, Permission};
+use crate::security::TokenConfig;
+
+/// Environment variable the Vault token is read from when the config has none.
+pub const VAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+
+/// An external store of client tokens, keyed by the token's SHA-256.
+#[async_trait]
+pub trait TokenStore: Send + Sync + std::fmt::Debug {
+    /// The token with this hash, or `None` if the store doesn't know it.
+    async fn lookup(&self, token_hash: &str) -> FshResult<Option<TokenConfig>>;
+}
+
+/// `[security.token_store]`: where tokens are looked up instead of the
+/// config file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum TokenStoreConfig {
+    /// A HashiCorp Vault KV v2 engine, one secret per token at
+    /// `<mount>/data/<path>/<hash>`.
+    Vault {
+        address: String,
+        #[serde(default = "TokenStoreConfig::default_vault_mount")]
+        mount: String,
+        #[serde(default = "TokenStoreConfig::default_vault_path")]
+        path: String,
+        /// Vault token; `VAULT_TOKEN` is used when unset.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// The OS keychain, one item per token with the hash as its account.
+    Keychain {
+        #[serde(default = "TokenStoreConfig::default_keychain_service")]
+        service: String,
+    },
+}
+
+impl TokenStoreConfig {
+    fn default_vault_mount() -> String {
+        "secret".to_string()
+    }
+
+    fn default_vault_path() -> String {
+        "fsh/tokens".to_string()
+    }
+
+    fn default_keychain_service() -> String {
+        "fsh-tokens".to_string()
+    }
+
+    pub fn build(&self) -> FshResult<Arc<dyn TokenStore>> {
+        match self {
+            TokenStoreConfig::Vault { address, mount, path, token } => {
+                let token = match token {
+                    Some(token) => token.clone(),
+                    None => std::env::var(VAULT_TOKEN_ENV).map_err(|_| FshError::ConfigError(format!(
+                        "The Vault token store needs a token; set token or {}", VAULT_TOKEN_ENV
+                    )))?,
+                };
+                Ok(Arc::new(VaultTokenStore::new(address, mount, path, token)))
+            }
+            TokenStoreConfig::Keychain { service } => Ok(Arc::new(KeychainTokenStore::new(service))),
+        }
+    }
+}
+
+/// What a store keeps about a token.
+#[derive(Debug, Deserialize)]
+struct StoredToken {
+    #[serde(default)]
+    description: String,
+    #[serde(default = "TokenConfig::default_permissions")]
+    permissions: Vec<Permission>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    user: Option<String>,
+}
+
+impl StoredToken {
+    fn into_config(self, token_hash: &str) -> TokenConfig {
+        TokenConfig {
+            description: self.description,
+            hash: token_hash.to_string(),
+            permissions: self.permissions,
+            expires_at: self.expires_at,
+            user: self.user,
+        }
+    }
+}
+
+pub struct VaultTokenStore {
+    url: String,
+    token: String,
+    http: reqwest::Client,
+}
+
+impl VaultTokenStore {
+    pub fn new(address: &str, mount: &str, path: &str, token: String) -> Self {
+        Self {
+            url: format!(
+                "{}/v1/{}/data/{}",
+                address.trim_end_matches('/'),
+                mount.trim_matches('/'),
+                path.trim_matches('/')
+            ),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for VaultTokenStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultTokenStore").field("url", &self.url).finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+    data: VaultData,
+}
+
+#[derive(Deserialize)]
+struct VaultData {
+    data: StoredToken,
+}
+
+#[async_trait]
+impl TokenStore for VaultTokenStore {
+    async fn lookup(&self, token_hash: &str) -> FshResult<Option<TokenConfig>> {
+        let vault_error = |e: reqwest::Error| FshError::NetworkError(format!("Vault token lookup failed: {}", e));
+
+        let response = self.http.get(format!("{}/{}", self.url, token_hash))
+            .header("X-Vault-Token", &self.token)
+            .send().await
+            .map_err(vault_error)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let secret: VaultResponse = response.error_for_status().map_err(vault_error)?
+            .json().await.map_err(vault_error)?;
+        Ok(Some(secret.data.data.into_config(token_hash)))
+    }
+}
+
+#[derive(Debug)]
+pub struct KeychainTokenStore {
+    service: String,
+}
+
+impl KeychainTokenStore {
+    pub fn new(service: &str) -> Self {
+        Self { service: service.to_string() }
+    }
+}
+
+#[async_trait]
+impl TokenStore for KeychainTokenStore {
+    async fn lookup(&self, token_hash: &str) -> FshResult<Option<TokenConfig>> {
+        let service = self.service.clone();
+        let account = token_hash.to_string();
+        let secret = tokio::task::spawn_blocking(move || keychain_lookup(&service, &account))
+            .await
+            .map_err(|e| FshError::ProtocolError(format!("Keychain lookup failed: {}", e)))??;
+
+        // The item holds the token's details as JSON, or just a description
+        Ok(secret.map(|secret| {
+            serde_json::from_str::<StoredToken>(&secret)
+                .unwrap_or_else(|_| StoredToken {
+                    description: secret,
+                    permissions: TokenConfig::default_permissions(),
+                    expires_at: None,
+                    user: None,
+                })
+                .into_config(token_hash)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, http::{HeaderMap, StatusCode}, routing::get, Json, Router};
+
+    #[tokio::test]
+    async fn test_vault_lookup() {
+        let app = Router::new().route("/v1/secret/data/fsh/tokens/:hash", get(
+            |Path(hash): Path<String>, headers: HeaderMap| async move {
+                if headers.get("X-Vault-Token").and_then(|v| v.to_str().ok()) != Some("vault-token") {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                if hash != "abc123" {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                Ok(Json(serde_json::json!({
+                    "data": { "data": { "description": "CI", "permissions": ["Read"] } }
+                })))
+            }
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = TokenStoreConfig::Vault {
+            address: address.clone(),
+            mount: TokenStoreConfig::default_vault_mount(),
+            path: TokenStoreConfig::default_vault_path(),
+            token: Some("vault-token".to_string()),
+        };
+        let store = config.build().unwrap();
+
+        let token = store.lookup("abc123").await.unwrap().unwrap();
+        assert_eq!(token.description, "CI");
+        assert_eq!(token.hash, "abc123");
+        assert_eq!(token.permissions, vec![Permission::Read]);
+        assert!(store.lookup("missing").await.unwrap().is_none());
+
+        // A bad Vault token is an error, not a missing client token
+        let store = VaultTokenStore::new(&address, "secret", "fsh/tokens", "wrong".to_string());
+        assert!(store.lookup("abc123").await.is_err());
+    }
+}
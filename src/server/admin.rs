@@ -0,0 +1,355 @@
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::config::{AdminConfig, Config};
+use crate::protocol::{FshError, FshResult, Permission};
+use crate::security::{AuthLockout, AuthManager, LockoutKey, LockoutStatus, TokenSummary};
+use crate::server::{
+    ApprovalDecision, ApprovalQueue, ApprovalRequest, HealthChecker, HealthReport, ServerStats,
+    Scheduler, SessionManager, SessionStats, StatsCollector, TaskStatus,
+};
+
+#[derive(Debug, Clone)]
+struct AdminState {
+    token: Option<String>,
+    config: Arc<Config>,
+    approvals: Arc<ApprovalQueue>,
+    lockout: Arc<AuthLockout>,
+    auth_manager: Arc<RwLock<AuthManager>>,
+    sessions: SessionManager,
+    stats: StatsCollector,
+    health: HealthChecker,
+    scheduler: Scheduler,
+}
+
+/// Answer to `POST /tokens/{id}/revoke`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub token: TokenSummary,
+    /// Live sessions the token had opened, now closed.
+    pub closed_sessions: usize,
+}
+
+/// Body of `POST /shares`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareRequest {
+    pub folder: String,
+    pub ttl_seconds: u64,
+    /// Only list and read files; otherwise the folder's own permissions apply.
+    pub read_only: bool,
+    /// The link opens a single session.
+    pub single_use: bool,
+}
+
+/// Answer to `POST /shares`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareCreated {
+    /// The token to hand out; it is not stored and can't be shown again.
+    pub token: String,
+    pub share: TokenSummary,
+}
+
+/// Body of `POST /notices`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoticeRequest {
+    pub message: String,
+}
+
+/// Answer to `POST /notices`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoticeSent {
+    /// Sessions whose client received the notice.
+    pub delivered: usize,
+    /// Sessions whose client can't display notices.
+    pub skipped: usize,
+}
+
+impl AdminState {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let presented = headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Routes of the admin API:
+///
+/// - `GET /approvals` lists pending approval requests
+/// - `POST /approvals/{id}/approve` and `POST /approvals/{id}/deny` decide one
+/// - `GET /lockouts` lists addresses, users and tokens with failed logins
+/// - `POST /lockouts/{key}/unlock` lifts a lock, e.g. `ip:10.0.0.5`
+/// - `GET /tokens` lists the tokens clients can authenticate with
+/// - `POST /tokens/{id}/revoke` revokes one and closes its sessions
+/// - `POST /shares` creates a share link token for one folder
+/// - `POST /notices` sends `{"message": ...}` to every connected client
+/// - `GET /stats` reports connections and per-folder and per-session usage
+/// - `GET /sessions` lists live sessions with the CPU and memory they use
+/// - `GET /schedule` lists folders' scheduled tasks and how each last ran
+/// - `GET /healthz` and `GET /readyz` are liveness and readiness probes;
+///   they need no token and answer 503 when the server isn't accepting
+///   connections, or for `/readyz`, when a folder or the config is unusable
+fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/approvals", get(list_approvals))
+        .route("/approvals/:id/approve", post(approve))
+        .route("/approvals/:id/deny", post(deny))
+        .route("/lockouts", get(list_lockouts))
+        .route("/lockouts/:key/unlock", post(unlock))
+        .route("/tokens", get(list_tokens))
+        .route("/tokens/:id/revoke", post(revoke_token))
+        .route("/shares", post(create_share))
+        .route("/notices", post(send_notice))
+        .route("/stats", get(stats))
+        .route("/sessions", get(list_sessions))
+        .route("/schedule", get(schedule))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(state)
+}
+
+async fn list_approvals(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ApprovalRequest>>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.approvals.list()))
+}
+
+async fn approve(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ApprovalRequest>, StatusCode> {
+    decide(&state, &headers, &id, ApprovalDecision::Approved)
+}
+
+async fn deny(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ApprovalRequest>, StatusCode> {
+    decide(&state, &headers, &id, ApprovalDecision::Denied)
+}
+
+fn decide(
+    state: &AdminState,
+    headers: &HeaderMap,
+    id: &str,
+    decision: ApprovalDecision,
+) -> Result<Json<ApprovalRequest>, StatusCode> {
+    state.authorize(headers)?;
+    state.approvals.decide(id, decision)
+        .map(Json)
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+async fn list_lockouts(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<LockoutStatus>>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.lockout.list()))
+}
+
+async fn unlock(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<Json<LockoutStatus>, StatusCode> {
+    state.authorize(&headers)?;
+    let key: LockoutKey = key.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let status = state.lockout.unlock(&key).map_err(|_| StatusCode::NOT_FOUND)?;
+    info!("Admin API unlocked {}", key);
+    Ok(Json(status))
+}
+
+async fn list_tokens(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TokenSummary>>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.auth_manager.read().await.list_tokens()))
+}
+
+async fn revoke_token(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RevokedToken>, StatusCode> {
+    state.authorize(&headers)?;
+    let token = {
+        let mut auth_manager = state.auth_manager.write().await;
+        let token = auth_manager.list_tokens().into_iter()
+            .find(|token| token.id == id)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        auth_manager.revoke_token(&id).map_err(|_| StatusCode::NOT_FOUND)?;
+        token
+    };
+
+    let closed_sessions = state.sessions.revoke_tokens(std::slice::from_ref(&id), "Token revoked").await;
+    info!("Admin API revoked token {} and closed {} session(s)", id, closed_sessions);
+    Ok(Json(RevokedToken { token, closed_sessions }))
+}
+
+async fn create_share(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Json(request): Json<ShareRequest>,
+) -> Result<Json<ShareCreated>, StatusCode> {
+    state.authorize(&headers)?;
+    let folder = state.config.find_folder_by_name(&request.folder)
+        .filter(|folder| !folder.honeypot.enabled)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if request.ttl_seconds == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let permissions = if request.read_only { vec![Permission::Read] } else { folder.permissions.clone() };
+    let expires_at = std::time::SystemTime::now() + std::time::Duration::from_secs(request.ttl_seconds);
+
+    let mut auth_manager = state.auth_manager.write().await;
+    let (id, token) = auth_manager.create_share(&folder.name, expires_at, permissions, request.single_use);
+    let share = auth_manager.list_tokens().into_iter()
+        .find(|token| token.id == id)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    info!("Admin API created share link {} for folder '{}'", id, folder.name);
+    Ok(Json(ShareCreated { token, share }))
+}
+
+async fn send_notice(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Json(request): Json<NoticeRequest>,
+) -> Result<Json<NoticeSent>, StatusCode> {
+    state.authorize(&headers)?;
+    if request.message.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let sessions = state.sessions.len().await;
+    let delivered = state.sessions.broadcast(&request.message).await;
+    Ok(Json(NoticeSent { delivered, skipped: sessions.saturating_sub(delivered) }))
+}
+
+async fn stats(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<ServerStats>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.stats.collect().await))
+}
+
+async fn list_sessions(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SessionStats>>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.sessions.stats().await))
+}
+
+async fn schedule(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TaskStatus>>, StatusCode> {
+    state.authorize(&headers)?;
+    Ok(Json(state.scheduler.status()))
+}
+
+async fn healthz(State(state): State<AdminState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.check().await;
+    (probe_status(report.listening), Json(report))
+}
+
+async fn readyz(State(state): State<AdminState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health.check().await;
+    (probe_status(report.ready), Json(report))
+}
+
+fn probe_status(ok: bool) -> StatusCode {
+    if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }
+}
+
+/// Bind the admin API's listener.
+pub async fn bind_admin_api(config: &AdminConfig) -> FshResult<TcpListener> {
+    let listener = TcpListener::bind(&config.listen).await
+        .map_err(|e| FshError::io(format!("Failed to bind admin API to {}", config.listen), e))?;
+    if config.token.is_none() {
+        warn!("Admin API on {} has no token; anyone who can connect may use it", config.listen);
+    }
+    info!("Admin API listening on {}", config.listen);
+    Ok(listener)
+}
+
+/// Serve the admin API until the listener fails.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_admin_api(
+    listener: TcpListener,
+    config: Arc<Config>,
+    approvals: Arc<ApprovalQueue>,
+    lockout: Arc<AuthLockout>,
+    auth_manager: Arc<RwLock<AuthManager>>,
+    sessions: SessionManager,
+    stats: StatsCollector,
+    health: HealthChecker,
+    scheduler: Scheduler,
+) -> FshResult<()> {
+    let state = AdminState { token: config.admin.token.clone(), config, approvals, lockout, auth_manager, sessions, stats, health, scheduler };
+    axum::serve(listener, router(state)).await
+        .map_err(|e| FshError::io("Admin API failed", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ApprovalConfig;
+    use crate::security::LockoutConfig;
+
+    #[test]
+    fn test_bearer_token_required() {
+        let state = AdminState {
+            token: Some("secret".to_string()),
+            config: Arc::default(),
+            approvals: Arc::new(ApprovalQueue::new(&ApprovalConfig::default())),
+            lockout: Arc::new(AuthLockout::new(&LockoutConfig::default())),
+            auth_manager: Arc::new(RwLock::new(AuthManager::new(&crate::config::Config::default().security).unwrap())),
+            sessions: SessionManager::default(),
+            stats: StatsCollector {
+                sessions: SessionManager::default(),
+                connection_permits: Arc::new(tokio::sync::Semaphore::new(1)),
+                counters: Arc::default(),
+                handshakes: Arc::default(),
+                usage: crate::server::UsageTracker::default(),
+                max_connections: 1,
+                listen_addresses: Vec::new(),
+            },
+            health: HealthChecker { config: Arc::default(), counters: Arc::default() },
+            scheduler: Scheduler::new(
+                Arc::default(),
+                Arc::new(crate::security::AuditLogger::new(&crate::config::Config::default().security).unwrap()),
+            ),
+        };
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(state.authorize(&headers), Err(StatusCode::UNAUTHORIZED));
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert_eq!(state.authorize(&headers), Err(StatusCode::UNAUTHORIZED));
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert_eq!(state.authorize(&headers), Ok(()));
+    }
+}
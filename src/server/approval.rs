@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::ApprovalConfig;
+use crate::protocol::{FshError, FshResult};
+use crate::security::command_matches;
+
+/// A command waiting for an operator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovalRequest {
+    pub id: String,
+    pub session_id: String,
+    pub folder: String,
+    pub client_addr: String,
+    pub command: String,
+    pub requested_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalDecision {
+    Approved,
+    Denied,
+}
+
+/// A submitted request; pass it to [`ApprovalQueue::wait`].
+#[derive(Debug)]
+pub struct PendingApproval {
+    pub request: ApprovalRequest,
+    decision: oneshot::Receiver<ApprovalDecision>,
+}
+
+/// Approval requests from all sessions, decided through the admin API.
+#[derive(Debug)]
+pub struct ApprovalQueue {
+    pending: Mutex<HashMap<String, (ApprovalRequest, oneshot::Sender<ApprovalDecision>)>>,
+    timeout: Duration,
+    webhook_url: Option<String>,
+    http: reqwest::Client,
+}
+
+impl ApprovalQueue {
+    pub fn new(config: &ApprovalConfig) -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            timeout: Duration::from_secs(config.timeout_seconds),
+            webhook_url: config.webhook_url.clone(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Queue `command` for approval and notify the webhook, if any.
+    pub fn submit(&self, session_id: &str, folder: &str, client_addr: &str, command: &str) -> PendingApproval {
+        let request = ApprovalRequest {
+            id: Uuid::new_v4().to_string(),
+            session_id: session_id.to_string(),
+            folder: folder.to_string(),
+            client_addr: client_addr.to_string(),
+            command: command.to_string(),
+            requested_at: chrono::Utc::now(),
+        };
+        let (decision_tx, decision) = oneshot::channel();
+        self.lock().insert(request.id.clone(), (request.clone(), decision_tx));
+        info!("Approval {} requested for '{}' in session {}", request.id, command, session_id);
+
+        if let Some(url) = &self.webhook_url {
+            let body = serde_json::json!({ "event": "approval_requested", "approval": request });
+            let post = self.http.post(url).json(&body).send();
+            let id = request.id.clone();
+            tokio::spawn(async move {
+                match post.await.and_then(|response| response.error_for_status()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Approval webhook failed for {}: {}", id, e),
+                }
+            });
+        }
+
+        PendingApproval { request, decision }
+    }
+
+    /// Wait for the operator's decision. Requests that time out are denied.
+    pub async fn wait(&self, pending: PendingApproval) -> ApprovalDecision {
+        let decision = match tokio::time::timeout(self.timeout, pending.decision).await {
+            Ok(Ok(decision)) => decision,
+            _ => {
+                info!("Approval {} timed out", pending.request.id);
+                ApprovalDecision::Denied
+            }
+        };
+        self.lock().remove(&pending.request.id);
+        decision
+    }
+
+    /// Pending requests, oldest first.
+    pub fn list(&self) -> Vec<ApprovalRequest> {
+        let mut requests: Vec<ApprovalRequest> = self.lock().values()
+            .map(|(request, _)| request.clone())
+            .collect();
+        requests.sort_by_key(|request| request.requested_at);
+        requests
+    }
+
+    pub fn decide(&self, id: &str, decision: ApprovalDecision) -> FshResult<ApprovalRequest> {
+        let (request, decision_tx) = self.lock().remove(id)
+            .ok_or_else(|| FshError::ProtocolError(format!("No pending approval '{}'", id)))?;
+        info!("Approval {} for '{}': {:?}", id, request.command, decision);
+        let _ = decision_tx.send(decision);
+        Ok(request)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (ApprovalRequest, oneshot::Sender<ApprovalDecision>)>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A folder's approval rules, bound to one session.
+#[derive(Debug, Clone)]
+pub struct ApprovalGate {
+    patterns: Vec<String>,
+    queue: Option<Arc<ApprovalQueue>>,
+    session_id: String,
+    folder: String,
+    client_addr: String,
+}
+
+impl ApprovalGate {
+    pub fn new(
+        patterns: Vec<String>,
+        queue: Option<Arc<ApprovalQueue>>,
+        session_id: &str,
+        folder: &str,
+        client_addr: &str,
+    ) -> Self {
+        Self {
+            patterns,
+            queue,
+            session_id: session_id.to_string(),
+            folder: folder.to_string(),
+            client_addr: client_addr.to_string(),
+        }
+    }
+
+    /// The same session's gate after it moves to `folder`.
+    pub fn for_folder(&self, patterns: Vec<String>, folder: &str) -> Self {
+        Self { patterns, folder: folder.to_string(), ..self.clone() }
+    }
+
+    /// Whether any of the command lines that are about to run needs approval.
+    pub fn is_required<'a>(&self, mut command_lines: impl Iterator<Item = &'a str>) -> bool {
+        command_lines.any(|line| self.patterns.iter().any(|pattern| command_matches(pattern, line)))
+    }
+
+    /// Queue `command` for approval. Fails if the server has no approval queue.
+    pub fn submit(&self, command: &str) -> FshResult<PendingApproval> {
+        let queue = self.queue.as_ref().ok_or_else(|| FshError::PermissionDenied(format!(
+            "'{}' requires approval, but approvals are not available", command
+        )))?;
+        Ok(queue.submit(&self.session_id, &self.folder, &self.client_addr, command))
+    }
+
+    /// Wait for the decision on a request from [`ApprovalGate::submit`].
+    pub async fn wait(&self, pending: PendingApproval) -> FshResult<()> {
+        let command = pending.request.command.clone();
+        let Some(queue) = &self.queue else {
+            return Err(FshError::PermissionDenied(format!("'{}' was not approved", command)));
+        };
+        match queue.wait(pending).await {
+            ApprovalDecision::Approved => Ok(()),
+            ApprovalDecision::Denied => Err(FshError::PermissionDenied(format!("'{}' was not approved", command))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(timeout_seconds: u64) -> Arc<ApprovalQueue> {
+        Arc::new(ApprovalQueue::new(&ApprovalConfig { timeout_seconds, webhook_url: None }))
+    }
+
+    #[tokio::test]
+    async fn test_approve_and_deny() {
+        let queue = queue(60);
+        let gate = ApprovalGate::new(
+            vec!["terraform apply*".to_string()], Some(Arc::clone(&queue)), "s1", "infra", "10.0.0.5:4000",
+        );
+        assert!(gate.is_required(["terraform apply -auto-approve"].into_iter()));
+        assert!(!gate.is_required(["terraform plan"].into_iter()));
+
+        let pending = gate.submit("terraform apply").unwrap();
+        assert_eq!(queue.list(), vec![pending.request.clone()]);
+        let id = pending.request.id.clone();
+        let waiter = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.wait(pending).await }
+        });
+        queue.decide(&id, ApprovalDecision::Approved).unwrap();
+        assert!(waiter.await.unwrap().is_ok());
+        assert!(queue.list().is_empty());
+        assert!(queue.decide(&id, ApprovalDecision::Denied).is_err());
+
+        let pending = gate.submit("terraform apply").unwrap();
+        queue.decide(&pending.request.id.clone(), ApprovalDecision::Denied).unwrap();
+        assert!(gate.wait(pending).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_undecided_requests_are_denied() {
+        let queue = queue(0);
+        let gate = ApprovalGate::new(vec!["*".to_string()], Some(Arc::clone(&queue)), "s1", "infra", "");
+        let pending = gate.submit("make deploy").unwrap();
+        assert!(gate.wait(pending).await.is_err());
+        assert!(queue.list().is_empty());
+
+        let without_queue = ApprovalGate::new(vec!["*".to_string()], None, "s1", "infra", "");
+        assert!(without_queue.submit("make deploy").is_err());
+    }
+}
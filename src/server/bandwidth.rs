@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// Token bucket limiting throughput to a fixed number of bytes per second.
+///
+/// Up to one second's worth of bytes may be sent in a burst. Larger requests
+/// are let through but put the bucket into debt, which later callers wait out.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+        Self {
+            bytes_per_second,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_second as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limiter for a rate given in KB/s, as used in the configuration.
+    pub fn from_kbps(kbps: u64) -> Self {
+        Self::new(kbps.saturating_mul(1024))
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Take `bytes` from the bucket and return how long the caller must wait
+    /// before sending them.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let rate = self.bytes_per_second as f64;
+
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.available = (bucket.available + refill).min(rate);
+        bucket.updated = now;
+        bucket.available -= bytes as f64;
+
+        if bucket.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / rate)
+        }
+    }
+}
+
+/// The limiters that apply to one session's file transfers.
+#[derive(Debug, Clone, Default)]
+pub struct TransferLimits {
+    limiters: Vec<Arc<BandwidthLimiter>>,
+}
+
+impl TransferLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limiter(mut self, limiter: Arc<BandwidthLimiter>) -> Self {
+        self.limiters.push(limiter);
+        self
+    }
+
+    pub fn is_limited(&self) -> bool {
+        !self.limiters.is_empty()
+    }
+
+    /// Wait until `bytes` may be transferred under every limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.limiters.iter()
+            .map(|limiter| limiter.reserve(bytes))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Per-folder limiters shared by every session bound to the same folder.
+#[derive(Debug, Default)]
+pub struct FolderBandwidth {
+    limiters: Mutex<HashMap<String, Arc<BandwidthLimiter>>>,
+}
+
+impl FolderBandwidth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The shared limiter for `folder`, created on first use. A changed rate
+    /// replaces the limiter for sessions created from then on.
+    pub fn limiter(&self, folder: &str, kbps: u64) -> Arc<BandwidthLimiter> {
+        let mut limiters = self.limiters.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes_per_second = kbps.saturating_mul(1024).max(1);
+
+        match limiters.get(folder) {
+            Some(limiter) if limiter.bytes_per_second() == bytes_per_second => Arc::clone(limiter),
+            _ => {
+                let limiter = Arc::new(BandwidthLimiter::from_kbps(kbps));
+                limiters.insert(folder.to_string(), Arc::clone(&limiter));
+                limiter
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_within_burst() {
+        let limiter = BandwidthLimiter::new(1000);
+        assert_eq!(limiter.reserve(600), Duration::ZERO);
+        assert_eq!(limiter.reserve(400), Duration::ZERO);
+
+        // The bucket is empty now, so the next bytes have to wait
+        let wait = limiter.reserve(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_folder_limiter_is_shared() {
+        let folders = FolderBandwidth::new();
+        let a = folders.limiter("project", 64);
+        let b = folders.limiter("project", 64);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(a.bytes_per_second(), 64 * 1024);
+
+        let other = folders.limiter("other", 64);
+        assert!(!Arc::ptr_eq(&a, &other));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transfer_limits_wait_for_slowest() {
+        let limits = TransferLimits::new()
+            .with_limiter(Arc::new(BandwidthLimiter::new(1000)))
+            .with_limiter(Arc::new(BandwidthLimiter::new(100)));
+
+        let start = Instant::now();
+        limits.acquire(100).await;
+        assert!(start.elapsed() < Duration::from_millis(10));
+
+        limits.acquire(100).await;
+        assert!(start.elapsed() >= Duration::from_millis(990));
+    }
+}
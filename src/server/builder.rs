@@ -0,0 +1,182 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{FshError, FshListener, FshResult};
+use crate::security::{AuditSink, Authenticator};
+use crate::server::{EventBus, FshServer, Plugin, Plugins, ServerEvent, ServerStats, StatsCollector};
+
+/// Builds an `FshServer` in code, for applications that embed it instead of
+/// running `fsh-server` with a config file.
+#[derive(Debug)]
+pub struct FshServerBuilder {
+    config: Config,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    audit_sinks: Vec<Arc<dyn AuditSink>>,
+    listener: Option<Box<dyn FshListener>>,
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl Default for FshServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FshServerBuilder {
+    /// Start from `Config::default()`, which shares no folders.
+    pub fn new() -> Self {
+        Self::from_config(Config::default())
+    }
+
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            authenticator: None,
+            audit_sinks: Vec::new(),
+            listener: None,
+            plugins: Vec::new(),
+        }
+    }
+
+    /// Listen on `host:port`; port 0 picks a free port, which
+    /// `ServerHandle::local_addr` reports.
+    pub fn with_listen_address(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.config.server.host = host.into();
+        self.config.server.port = port;
+        self
+    }
+
+    /// Listen on each of `addresses` (a host on the configured port, or
+    /// `host:port`) instead of a single host.
+    pub fn with_listen_addresses(mut self, addresses: Vec<String>) -> Self {
+        self.config.server.listen = addresses;
+        self
+    }
+
+    /// Accept connections from `listener` instead of listening on TCP, e.g.
+    /// the `MemoryListener` of an in-process `memory_transport`.
+    pub fn with_listener(mut self, listener: impl FshListener + 'static) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn with_folder(mut self, folder: FolderConfig) -> Self {
+        self.config.folders.push(folder);
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.config.server.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: usize) -> Self {
+        self.config.server.max_connections_per_ip = Some(max_connections_per_ip);
+        self
+    }
+
+    /// Limit external commands running at once across all sessions.
+    pub fn with_max_running_commands(mut self, max_running_commands: usize) -> Self {
+        self.config.server.max_running_commands = Some(max_running_commands);
+        self
+    }
+
+    /// Let clients in without logging in.
+    pub fn without_authentication(mut self) -> Self {
+        self.config.security.require_authentication = false;
+        self
+    }
+
+    /// Let `authenticator` decide logins instead of the configured tokens and
+    /// certificates. Turns authentication on.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config.security.require_authentication = true;
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Also send audit events to `sink`.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sinks.push(sink);
+        self
+    }
+
+    /// Run `plugin` on every session's commands and file operations, after
+    /// the plugins registered before it.
+    pub fn with_plugin(mut self, plugin: Arc<dyn Plugin>) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    /// Everything the other methods don't cover.
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn build(self) -> FshResult<FshServer> {
+        let mut server = FshServer::from_parts(self.config, self.authenticator, self.audit_sinks)?;
+        server.listener = self.listener;
+        server.plugins = Plugins::new(self.plugins);
+        Ok(server)
+    }
+}
+
+/// A server running in the background, from `FshServer::spawn`.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    stats: StatsCollector,
+    events: EventBus,
+    shutdown: CancellationToken,
+    task: JoinHandle<FshResult<()>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addrs: Vec<SocketAddr>,
+        stats: StatsCollector,
+        events: EventBus,
+        shutdown: CancellationToken,
+        task: JoinHandle<FshResult<()>>,
+    ) -> Self {
+        Self { local_addrs, stats, events, shutdown, task }
+    }
+
+    /// The address clients connect to, unless the server was given a
+    /// listener that isn't a socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    /// Every address the server listens on, when `listen` lists several.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub async fn stats(&self) -> ServerStats {
+        self.stats.collect().await
+    }
+
+    /// Send an operator's notice to every connected client, as
+    /// `FshServer::broadcast`.
+    pub async fn broadcast(&self, message: &str) -> usize {
+        self.stats.sessions.broadcast(message).await
+    }
+
+    /// Receive the server's events, as `FshServer::subscribe`.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Stop accepting connections, close every session and wait for the
+    /// server to finish.
+    pub async fn stop(self) -> FshResult<()> {
+        self.shutdown.cancel();
+        self.task.await
+            .map_err(|e| FshError::ProtocolError(format!("Server task failed: {}", e)))?
+    }
+}
@@ -0,0 +1,272 @@
+use futures::StreamExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::protocol::{
+    message::*, FshCodec, FshError, FshErrorCode, FshFramed, FshMessage, FshResult, FshStream,
+};
+use crate::security::ClientAudit;
+use crate::server::{FrameSink, FrameSource, MirroredEvent, MirroredSink, Session};
+
+/// Commands from participants that may wait for the shell before more are
+/// refused as busy.
+const PARTICIPANT_QUEUE_DEPTH: usize = 4;
+
+/// A client the owner let type into their session.
+#[derive(Debug)]
+pub struct Participant {
+    /// Who the participant is in the session's audit trail and to the other
+    /// clients: their certificate's user, or else their address.
+    pub name: String,
+    pub client_addr: String,
+    /// Where the output of the participant's commands goes.
+    pub writer: Arc<Mutex<FrameSink>>,
+    pub audit: Option<ClientAudit>,
+}
+
+/// A command a participant typed, waiting its turn at the session's shell.
+#[derive(Debug)]
+pub struct ParticipantInput {
+    pub participant: Arc<Participant>,
+    pub command: CommandMessage,
+}
+
+/// Lets clients other than a session's owner run commands in it, once the
+/// owner has consented. Their commands are queued for the session's message
+/// loop, which runs them one at a time between the owner's own requests.
+#[derive(Debug, Clone)]
+pub struct SessionCollaboration {
+    consent: Arc<watch::Sender<bool>>,
+    input: mpsc::Sender<ParticipantInput>,
+    participants: Arc<AtomicUsize>,
+}
+
+impl SessionCollaboration {
+    /// The collaboration and the queue the session's message loop reads
+    /// participants' commands from.
+    pub fn new() -> (Self, mpsc::Receiver<ParticipantInput>) {
+        let (consent, _) = watch::channel(false);
+        let (input, input_rx) = mpsc::channel(PARTICIPANT_QUEUE_DEPTH);
+        let collaboration = Self {
+            consent: Arc::new(consent),
+            input,
+            participants: Arc::new(AtomicUsize::new(0)),
+        };
+        (collaboration, input_rx)
+    }
+
+    /// Allow participants to join, or detach every participant.
+    pub fn set_consent(&self, allow: bool) {
+        self.consent.send_replace(allow);
+    }
+
+    pub fn consented(&self) -> bool {
+        *self.consent.borrow()
+    }
+
+    /// Participants attached to the session.
+    pub fn participants(&self) -> usize {
+        self.participants.load(Ordering::Relaxed)
+    }
+
+    /// Queue `input` for the shell, refusing it if the session already has
+    /// as many participants' commands waiting as it takes.
+    pub fn submit(&self, input: ParticipantInput) -> FshResult<()> {
+        self.input.try_send(input).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                FshError::RateLimited("The session is busy, try again when its commands finish".to_string())
+            }
+            mpsc::error::TrySendError::Closed(_) => FshError::SessionNotFound("The session has ended".to_string()),
+        })
+    }
+}
+
+/// A connection typing into another client's session until the session ends,
+/// its owner withdraws consent, or the participant leaves. It sees what the
+/// owner and the other participants do, as an observer would.
+pub struct SessionParticipant {
+    reader: FrameSource,
+    participant: Arc<Participant>,
+    session: Arc<Session>,
+    events: broadcast::Receiver<MirroredEvent>,
+    consent: watch::Receiver<bool>,
+}
+
+impl SessionParticipant {
+    pub fn new(
+        stream: FshFramed<FshStream>,
+        session: Arc<Session>,
+        name: String,
+        client_addr: String,
+        audit: Option<ClientAudit>,
+    ) -> Self {
+        let collaboration = session.collaboration();
+        collaboration.participants.fetch_add(1, Ordering::Relaxed);
+        let consent = collaboration.consent.subscribe();
+        let events = session.mirror().subscribe();
+
+        let (writer, reader) = stream.split();
+        let writer = MirroredSink::for_participant(writer, session.mirror().clone(), name.clone());
+        let participant = Arc::new(Participant {
+            name,
+            client_addr,
+            writer: Arc::new(Mutex::new(writer)),
+            audit,
+        });
+        Self { reader, participant, session, events, consent }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.participant.name
+    }
+
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    /// Send `message` to the participant.
+    pub async fn send(&self, message: FshMessage) -> FshResult<()> {
+        let mut writer = self.participant.writer.lock().await;
+        FshCodec::write_message(&mut *writer, message).await
+    }
+
+    pub async fn run(mut self) -> FshResult<()> {
+        let session_id = self.session.id().to_string();
+        let name = self.participant.name.clone();
+        info!("{} ({}) joined session {}", name, self.participant.client_addr, session_id);
+        self.session.mirror().publish(Some(&name), ObservedEvent::ParticipantJoined { participant: name.clone() });
+
+        let reason = self.serve(&session_id).await;
+        self.session.mirror().publish(Some(&name), ObservedEvent::ParticipantLeft { participant: name.clone() });
+        let reason = match reason? {
+            Some(reason) => reason,
+            None => {
+                info!("{} left session {}", name, session_id);
+                return Ok(());
+            }
+        };
+
+        info!("{} was detached from session {}: {}", name, session_id, reason);
+        self.send(FshMessage::Disconnect(DisconnectMessage { reason: reason.to_string() })).await
+    }
+
+    /// Pass messages both ways until the participant leaves (`None`) or is
+    /// detached for the returned reason.
+    async fn serve(&mut self, session_id: &str) -> FshResult<Option<&'static str>> {
+        let session = Arc::clone(&self.session);
+        let closed = session.wait_closed();
+        tokio::pin!(closed);
+
+        loop {
+            tokio::select! {
+                // Pass on what the session sent before noticing it ended
+                biased;
+                event = self.events.recv() => match event {
+                    // The participant already saw what it caused itself
+                    Ok(MirroredEvent { source: Some(source), .. }) if source == self.participant.name => {}
+                    Ok(MirroredEvent { event, .. }) => {
+                        let observed = FshMessage::Observed(ObservedMessage { session_id: session_id.to_string(), event });
+                        self.send(observed).await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Participant {} in session {} missed {} events", self.participant.name, session_id, missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(Some("The shared session ended")),
+                },
+                changed = self.consent.changed() => {
+                    if changed.is_err() || !*self.consent.borrow() {
+                        return Ok(Some("The session's owner stopped sharing it"));
+                    }
+                }
+                _ = &mut closed => return Ok(Some("The shared session ended")),
+                message = FshCodec::read_message(&mut self.reader) => match message {
+                    Ok(FshMessage::Ping) => self.send(FshMessage::Pong).await?,
+                    Ok(FshMessage::Pong) => {}
+                    Ok(FshMessage::Disconnect(_)) | Err(_) => return Ok(None),
+                    Ok(FshMessage::Command(command)) if command.job_id.is_none() => {
+                        let input = ParticipantInput { participant: Arc::clone(&self.participant), command };
+                        if let Err(e) = self.session.collaboration().submit(input) {
+                            debug!("Refused a command from {} in session {}: {}", self.participant.name, session_id, e);
+                            self.send(FshMessage::Error(ErrorMessage::from(&e))).await?;
+                        }
+                    }
+                    Ok(other) => {
+                        debug!("Participant {} sent {:?}", self.participant.name, other.message_type());
+                        let error = FshMessage::Error(ErrorMessage::new(
+                            FshErrorCode::PermissionDenied,
+                            "Participants can only run foreground commands in the session",
+                        ));
+                        self.send(error).await?;
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl Drop for SessionParticipant {
+    fn drop(&mut self) {
+        self.session.collaboration().participants.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn participant(name: &str) -> (Arc<Participant>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (writer, _) = FshCodec::framed(server.into()).split();
+        let writer = MirroredSink::for_participant(writer, Default::default(), name.to_string());
+        let participant = Participant {
+            name: name.to_string(),
+            client_addr: "127.0.0.1:4000".to_string(),
+            writer: Arc::new(Mutex::new(writer)),
+            audit: None,
+        };
+        (Arc::new(participant), client)
+    }
+
+    fn command(command: &str) -> CommandMessage {
+        CommandMessage {
+            session_id: "owner".to_string(),
+            command: command.to_string(),
+            args: Vec::new(),
+            environment: None,
+            job_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_participant_input_is_queued_in_order() {
+        let (collaboration, mut input_rx) = SessionCollaboration::new();
+        assert!(!collaboration.consented());
+        collaboration.set_consent(true);
+        assert!(collaboration.consented());
+
+        let (alice, _alice_client) = participant("alice").await;
+        let (bob, _bob_client) = participant("bob").await;
+        for (participant, line) in [(&alice, "ls"), (&bob, "pwd"), (&alice, "make"), (&bob, "date")] {
+            collaboration.submit(ParticipantInput { participant: Arc::clone(participant), command: command(line) }).unwrap();
+        }
+
+        // Input beyond what the shell can keep up with is refused, not buffered
+        let busy = collaboration.submit(ParticipantInput { participant: Arc::clone(&alice), command: command("top") });
+        assert!(matches!(busy, Err(FshError::RateLimited(_))));
+
+        let mut received = Vec::new();
+        while let Ok(input) = input_rx.try_recv() {
+            received.push(format!("{}: {}", input.participant.name, input.command.command));
+        }
+        assert_eq!(received, vec!["alice: ls", "bob: pwd", "alice: make", "bob: date"]);
+
+        drop(input_rx);
+        let ended = collaboration.submit(ParticipantInput { participant: bob, command: command("ls") });
+        assert!(matches!(ended, Err(FshError::SessionNotFound(_))));
+    }
+}
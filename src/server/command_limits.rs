@@ -0,0 +1,328 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+
+use crate::config::FolderConfig;
+use crate::protocol::{FshError, FshResult};
+
+/// Limits on external commands running at once, server-wide and per folder,
+/// shared by every session. Commands over either limit queue in order of
+/// arrival; a queued command starts as soon as both limits allow it, even
+/// ahead of earlier ones still held back by their own folder's limit.
+#[derive(Debug, Default)]
+pub struct CommandLimiter {
+    max_running: Option<usize>,
+    max_per_session: Option<usize>,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    running: usize,
+    folders: HashMap<String, usize>,
+    sessions: HashMap<String, usize>,
+    /// Commands waiting to start; none of them fits under the limits.
+    queue: VecDeque<Waiter>,
+    next_waiter: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    slot: SlotKey,
+    position: watch::Sender<usize>,
+    start: oneshot::Sender<()>,
+}
+
+/// What a running command counts towards.
+#[derive(Debug, Clone)]
+struct SlotKey {
+    session_id: String,
+    folder: String,
+    folder_limit: Option<usize>,
+}
+
+impl LimiterState {
+    fn fits(&self, max_running: Option<usize>, slot: &SlotKey) -> bool {
+        max_running.is_none_or(|max| self.running < max)
+            && slot.folder_limit.is_none_or(|max| self.folders.get(&slot.folder).copied().unwrap_or(0) < max)
+    }
+
+    fn take(&mut self, slot: &SlotKey) {
+        self.running += 1;
+        *self.folders.entry(slot.folder.clone()).or_default() += 1;
+        *self.sessions.entry(slot.session_id.clone()).or_default() += 1;
+    }
+
+    fn release(&mut self, slot: &SlotKey) {
+        self.running = self.running.saturating_sub(1);
+        for (counts, key) in [(&mut self.folders, &slot.folder), (&mut self.sessions, &slot.session_id)] {
+            if let Some(count) = counts.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Start every queued command that fits now, then tell the rest where
+    /// they stand.
+    fn start_waiting(&mut self, max_running: Option<usize>) {
+        let mut index = 0;
+        while index < self.queue.len() {
+            if !self.fits(max_running, &self.queue[index].slot) {
+                index += 1;
+                continue;
+            }
+            let Some(waiter) = self.queue.remove(index) else {
+                break;
+            };
+            self.take(&waiter.slot);
+            if waiter.start.send(()).is_err() {
+                self.release(&waiter.slot);
+            }
+        }
+        for (index, waiter) in self.queue.iter().enumerate() {
+            waiter.position.send_if_modified(|position| std::mem::replace(position, index + 1) != index + 1);
+        }
+    }
+}
+
+impl CommandLimiter {
+    pub fn new(max_running: Option<usize>, max_per_session: Option<usize>) -> Self {
+        Self { max_running, max_per_session, ..Default::default() }
+    }
+
+    /// The limits of a session running commands in `folder`.
+    pub fn for_session(self: &Arc<Self>, session_id: &str, folder: &FolderConfig) -> CommandLimits {
+        CommandLimits {
+            limiter: Arc::clone(self),
+            slot: SlotKey {
+                session_id: session_id.to_string(),
+                folder: folder.name.clone(),
+                folder_limit: folder.max_running_commands,
+            },
+        }
+    }
+
+    /// Commands running across the server.
+    pub fn running(&self) -> usize {
+        self.lock().running
+    }
+
+    /// Commands waiting for a slot across the server.
+    pub fn queued(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn release(&self, slot: &SlotKey) {
+        let mut state = self.lock();
+        state.release(slot);
+        state.start_waiting(self.max_running);
+    }
+}
+
+/// The command limits that apply to one session.
+#[derive(Debug, Clone)]
+pub struct CommandLimits {
+    limiter: Arc<CommandLimiter>,
+    slot: SlotKey,
+}
+
+/// Whether a command may start right away.
+#[derive(Debug)]
+pub enum Admission {
+    Running(CommandSlot),
+    Queued(QueuedCommand),
+}
+
+impl CommandLimits {
+    /// No limits, for sessions created outside a server.
+    pub fn unlimited() -> Self {
+        Self {
+            limiter: Arc::default(),
+            slot: SlotKey { session_id: String::new(), folder: String::new(), folder_limit: None },
+        }
+    }
+
+    /// The same session's limits once it has moved to `folder`.
+    pub fn for_folder(&self, folder: &FolderConfig) -> Self {
+        self.limiter.for_session(&self.slot.session_id, folder)
+    }
+
+    /// Claim a slot for a command, or a place in the queue. A session
+    /// already running its own limit of commands is refused: only its own
+    /// commands finishing could make room, and it would be stuck waiting.
+    pub fn admit(&self) -> FshResult<Admission> {
+        let mut state = self.limiter.lock();
+        if let Some(max) = self.limiter.max_per_session {
+            if state.sessions.get(&self.slot.session_id).copied().unwrap_or(0) >= max {
+                return Err(FshError::ShellError(format!(
+                    "This session already runs {} command(s) at once, its limit", max
+                )));
+            }
+        }
+
+        // Nothing in the queue fits, so a command that does goes first
+        if state.fits(self.limiter.max_running, &self.slot) {
+            state.take(&self.slot);
+            return Ok(Admission::Running(CommandSlot { limiter: Arc::clone(&self.limiter), slot: self.slot.clone() }));
+        }
+
+        let id = state.next_waiter;
+        state.next_waiter += 1;
+        let (position_tx, position) = watch::channel(state.queue.len() + 1);
+        let (start_tx, start) = oneshot::channel();
+        state.queue.push_back(Waiter { id, slot: self.slot.clone(), position: position_tx, start: start_tx });
+        Ok(Admission::Queued(QueuedCommand {
+            limiter: Arc::clone(&self.limiter),
+            slot: self.slot.clone(),
+            id,
+            position,
+            start,
+            started: false,
+        }))
+    }
+}
+
+/// A running command's place under the limits, given back when dropped.
+#[derive(Debug)]
+pub struct CommandSlot {
+    limiter: Arc<CommandLimiter>,
+    slot: SlotKey,
+}
+
+impl Drop for CommandSlot {
+    fn drop(&mut self) {
+        self.limiter.release(&self.slot);
+    }
+}
+
+/// What happened to a queued command.
+#[derive(Debug)]
+pub enum QueueEvent {
+    /// It moved up to this position.
+    Moved(usize),
+    Started(CommandSlot),
+}
+
+/// A command waiting for a slot; dropping it leaves the queue.
+#[derive(Debug)]
+pub struct QueuedCommand {
+    limiter: Arc<CommandLimiter>,
+    slot: SlotKey,
+    id: u64,
+    position: watch::Receiver<usize>,
+    start: oneshot::Receiver<()>,
+    started: bool,
+}
+
+impl QueuedCommand {
+    /// 1 for the next command to start.
+    pub fn position(&self) -> usize {
+        *self.position.borrow()
+    }
+
+    /// Wait until the command moves up or may start. Not to be called again
+    /// once it has started.
+    pub async fn advance(&mut self) -> QueueEvent {
+        tokio::select! {
+            biased;
+            Ok(()) = &mut self.start => {
+                self.started = true;
+                QueueEvent::Started(CommandSlot { limiter: Arc::clone(&self.limiter), slot: self.slot.clone() })
+            }
+            Ok(()) = self.position.changed() => QueueEvent::Moved(*self.position.borrow_and_update()),
+            else => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for QueuedCommand {
+    fn drop(&mut self) {
+        if self.started {
+            return;
+        }
+        let mut state = self.limiter.lock();
+        match state.queue.iter().position(|waiter| waiter.id == self.id) {
+            Some(index) => {
+                state.queue.remove(index);
+            }
+            // Started, but nobody took the slot
+            None => state.release(&self.slot),
+        }
+        state.start_waiting(self.limiter.max_running);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder(name: &str, max_running_commands: Option<usize>) -> FolderConfig {
+        let mut folder = FolderConfig::new(name.to_string(), std::env::temp_dir());
+        folder.max_running_commands = max_running_commands;
+        folder
+    }
+
+    fn running(admission: FshResult<Admission>) -> CommandSlot {
+        match admission.unwrap() {
+            Admission::Running(slot) => slot,
+            Admission::Queued(_) => panic!("command was queued"),
+        }
+    }
+
+    fn queued(admission: FshResult<Admission>) -> QueuedCommand {
+        match admission.unwrap() {
+            Admission::Queued(queued) => queued,
+            Admission::Running(_) => panic!("command started"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_in_order() {
+        let limiter = Arc::new(CommandLimiter::new(Some(1), None));
+        let first = running(limiter.for_session("a", &folder("docs", None)).admit());
+        let mut second = queued(limiter.for_session("b", &folder("docs", None)).admit());
+        let mut third = queued(limiter.for_session("c", &folder("docs", None)).admit());
+        assert_eq!((second.position(), third.position()), (1, 2));
+
+        drop(first);
+        let QueueEvent::Started(second_slot) = second.advance().await else {
+            panic!("second command did not start");
+        };
+        assert!(matches!(third.advance().await, QueueEvent::Moved(1)));
+        assert_eq!(limiter.running(), 1);
+
+        // Leaving the queue makes room behind
+        drop(third);
+        assert_eq!(limiter.queued(), 0);
+        drop(second_slot);
+        assert_eq!(limiter.running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_folder_and_session_limits() {
+        let limiter = Arc::new(CommandLimiter::new(Some(3), Some(1)));
+        let build = folder("build", Some(1));
+        let building = running(limiter.for_session("a", &build).admit());
+        let mut waiting = queued(limiter.for_session("b", &build).admit());
+
+        // Another folder isn't held back by the busy one
+        let docs = limiter.for_session("c", &folder("docs", None));
+        let docs_slot = running(docs.admit());
+
+        // A session at its own limit is refused rather than queued
+        assert!(docs.admit().is_err());
+        drop(docs_slot);
+        assert!(docs.admit().is_ok());
+
+        drop(building);
+        assert!(matches!(waiting.advance().await, QueueEvent::Started(_)));
+    }
+}
@@ -1,36 +1,328 @@
-use crate::config::Config;
+use crate::config::{Config, UsersConfig};
 use crate::protocol::{
-    FshMessage, FshCodec, FshError, FshResult, FSH_VERSION, ClientInfo,
-    message::*,
+    FshMessage, FshCodec, FshFramed, FshError, FshResult, FSH_VERSION, ClientInfo,
+    FshErrorCode, Capabilities, ProtocolVersion, CORE_FEATURES, FEATURE_FRAGMENTATION, FEATURE_GIT, FEATURE_PORT_FORWARD,
+    FEATURE_COLLABORATE, FEATURE_ENV, FEATURE_FOLDER_ROOTS, FEATURE_FOLDER_SWITCH, FEATURE_HISTORY, FEATURE_IDLE_LOCK, FEATURE_MKDIR, FEATURE_OBSERVE, FEATURE_NOISE, FEATURE_OPERATION_IDS, FEATURE_SERVER_NOTICE, FEATURE_SESSION_INFO, FEATURE_SESSION_RESUME, FEATURE_USER_FOLDERS, FEATURE_WATCH_RUNS, FshStream, message::*, wire_revision_features,
 };
-use crate::server::Session;
+use crate::security::{
+    AuditLogger, AuthLockout, AuthManager, AuthRequest, Authenticator, CertIdentity, ClientAudit, ClientRateLimits, LockoutKey,
+    DlpScanner, MalwareScanner, NoiseServer, RateLimitKey, RateLimitKind, RateLimits, TokenScope,
+};
+use crate::server::{
+    ApprovalQueue, CommandLimiter, ConnectionCounters, EventBus, FolderAccess, FolderBandwidth, FolderHistory, IdleLock, OperationLog, Plugins, SavedSession, ServerEvent, Session,
+    SessionManager, SessionObserver, SessionParticipant, SessionPersistence, SessionStore, SessionUsage, UsageTracker,
+};
+use std::future::Future;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio_util::codec::Framed;
 use tokio::time::{timeout, Duration};
 use tracing::{info, warn, error, debug};
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct Connection {
-    stream: Option<TcpStream>,
+    stream: Option<FshFramed<FshStream>>,
     client_addr: String,
     config: Arc<Config>,
+    sessions: SessionManager,
     authenticated: bool,
     client_info: Option<ClientInfo>,
+    capabilities: Capabilities,
+    folder_bandwidth: Arc<FolderBandwidth>,
+    folder_history: Arc<FolderHistory>,
+    command_limiter: Arc<CommandLimiter>,
+    operations: Arc<OperationLog>,
+    audit_logger: Option<Arc<AuditLogger>>,
+    approvals: Option<Arc<ApprovalQueue>>,
+    rate_limits: Option<Arc<RateLimits>>,
+    lockout: Option<Arc<AuthLockout>>,
+    auth_manager: Option<Arc<RwLock<AuthManager>>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    usage: Option<UsageTracker>,
+    counters: Option<Arc<ConnectionCounters>>,
+    events: EventBus,
+    plugins: Plugins,
+    malware_scanner: Option<Arc<MalwareScanner>>,
+    dlp: Option<Arc<DlpScanner>>,
+    /// The token the client authenticated with, for per-token rate limits.
+    auth_token: Option<String>,
+    /// The id of that token, so revoking it closes the session.
+    token_id: Option<String>,
+    /// Limits of that token, e.g. a share link's folder.
+    token_scope: Option<TokenScope>,
+    /// Names from the client's verified TLS certificate.
+    client_identity: Option<CertIdentity>,
+    noise: Option<Arc<NoiseServer>>,
+    session_store: Option<Arc<SessionStore>>,
+    /// The saved session the client asked to resume, and its old id.
+    resumed: Option<(String, SavedSession)>,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, client_addr: String, config: Arc<Config>) -> Self {
+    pub fn new(stream: impl Into<FshStream>, client_addr: String, config: Arc<Config>, sessions: SessionManager) -> Self {
         Self {
-            stream: Some(stream),
+            stream: Some(Framed::new(
+                stream.into(),
+                FshCodec::new()
+                    .with_max_frame_length(config.server.frames.pre_auth_max_bytes)
+                    .with_framing_detection(),
+            )),
             client_addr,
             config,
+            sessions,
             authenticated: false,
             client_info: None,
+            capabilities: Capabilities::default(),
+            folder_bandwidth: Arc::new(FolderBandwidth::new()),
+            folder_history: Arc::new(FolderHistory::new()),
+            command_limiter: Arc::new(CommandLimiter::default()),
+            operations: Arc::new(OperationLog::new()),
+            audit_logger: None,
+            approvals: None,
+            rate_limits: None,
+            lockout: None,
+            auth_manager: None,
+            authenticator: None,
+            usage: None,
+            counters: None,
+            events: EventBus::default(),
+            plugins: Plugins::default(),
+            malware_scanner: None,
+            dlp: None,
+            auth_token: None,
+            token_id: None,
+            token_scope: None,
+            client_identity: None,
+            noise: None,
+            session_store: None,
+            resumed: None,
+        }
+    }
+
+    /// Share per-folder transfer limits with the server's other connections.
+    pub fn with_folder_bandwidth(mut self, folder_bandwidth: Arc<FolderBandwidth>) -> Self {
+        self.folder_bandwidth = folder_bandwidth;
+        self
+    }
+
+    /// Share per-folder command history with the server's other connections.
+    pub fn with_folder_history(mut self, folder_history: Arc<FolderHistory>) -> Self {
+        self.folder_history = folder_history;
+        self
+    }
+
+    /// Share replies to operations with the server's other connections, so
+    /// retries after a reconnect aren't done twice.
+    /// Share the server's limits on running commands.
+    pub fn with_command_limiter(mut self, command_limiter: Arc<CommandLimiter>) -> Self {
+        self.command_limiter = command_limiter;
+        self
+    }
+
+    pub fn with_operation_log(mut self, operations: Arc<OperationLog>) -> Self {
+        self.operations = operations;
+        self
+    }
+
+    /// Record this connection's sessions in the server's audit log.
+    pub fn with_audit_logger(mut self, audit_logger: Arc<AuditLogger>) -> Self {
+        self.audit_logger = Some(audit_logger);
+        self
+    }
+
+    /// Send commands that need approval to the server's approval queue.
+    pub fn with_approvals(mut self, approvals: Arc<ApprovalQueue>) -> Self {
+        self.approvals = Some(approvals);
+        self
+    }
+
+    /// Count this connection's requests against the server's rate limits.
+    pub fn with_rate_limits(mut self, rate_limits: Arc<RateLimits>) -> Self {
+        self.rate_limits = Some(rate_limits);
+        self
+    }
+
+    /// Share failed authentication attempts with the server's other connections.
+    pub fn with_lockout(mut self, lockout: Arc<AuthLockout>) -> Self {
+        self.lockout = Some(lockout);
+        self
+    }
+
+    /// Validate tokens against the server's token store.
+    pub fn with_auth_manager(mut self, auth_manager: Arc<RwLock<AuthManager>>) -> Self {
+        self.auth_manager = Some(auth_manager);
+        self
+    }
+
+    /// Let `authenticator` decide logins instead of the built-in checks.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Add this connection's session to the server's per-folder usage totals.
+    pub fn with_usage(mut self, usage: UsageTracker) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Count a failed authentication in the server's connection totals.
+    pub fn with_counters(mut self, counters: Arc<ConnectionCounters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Publish the connection's events on the server's bus.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Run the server's plugins on the session's requests.
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    /// Scan files the session writes, quarantining infected ones.
+    pub fn with_malware_scanner(mut self, malware_scanner: Arc<MalwareScanner>) -> Self {
+        self.malware_scanner = Some(malware_scanner);
+        self
+    }
+
+    /// Apply data loss prevention rules to what the session sends.
+    pub fn with_dlp(mut self, dlp: Arc<DlpScanner>) -> Self {
+        self.dlp = Some(dlp);
+        self
+    }
+
+    /// The identity from the client's TLS certificate, checked against folder
+    /// ACLs and accepted by the `certificate` auth method.
+    pub fn with_client_identity(mut self, identity: CertIdentity) -> Self {
+        self.client_identity = Some(identity);
+        self
+    }
+
+    /// Offer clients a Noise channel, authenticated by their static keys.
+    pub fn with_noise(mut self, noise: Arc<NoiseServer>) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// Save sessions in `session_store` so clients can resume them after a restart.
+    pub fn with_session_store(mut self, session_store: Arc<SessionStore>) -> Self {
+        self.session_store = Some(session_store);
+        self
+    }
+
+    /// Who may resume the client's sessions: whoever has its token, or
+    /// else its certificate's user.
+    fn session_owner(&self) -> Option<String> {
+        match (&self.auth_token, &self.client_identity) {
+            (Some(token), _) => Some(AuthManager::hash_token(token)),
+            (None, Some(identity)) => identity.user().map(|user| format!("cert:{}", user)),
+            (None, None) => None,
         }
     }
 
-    pub async fn handle(mut self) -> FshResult<Session> {
+    /// The user the client logged in as: the one its token names, or else
+    /// its certificate's user if that is a user here.
+    fn user(&self) -> Option<String> {
+        self.token_scope.as_ref().and_then(|scope| scope.user.clone()).or_else(|| {
+            self.client_identity.as_ref()
+                .and_then(CertIdentity::user)
+                .filter(|user| self.config.users.is_user(user))
+                .map(str::to_string)
+        })
+    }
+
+    /// Whether the client may see `folder` at all, given its user and token.
+    fn can_see_folder(&self, folder: &str) -> bool {
+        self.token_scope.as_ref().is_none_or(|scope| scope.allows_folder(folder))
+            && self.config.users.allows(self.user().as_deref(), folder)
+    }
+
+    /// Give a user without a configured home one on its first login, and add
+    /// it to this connection's folders.
+    async fn provision_home(&mut self, user: &str) -> FshResult<()> {
+        let users = &self.config.users;
+        if !users.provision_on_login || self.config.find_folder_by_name(&UsersConfig::home_folder_name(user)).is_some() {
+            return Ok(());
+        }
+
+        let (users, user_name) = (users.clone(), user.to_string());
+        let home = tokio::task::spawn_blocking(move || users.home_folder(&user_name)).await
+            .map_err(|e| FshError::ConfigError(format!("Failed to provision home folder: {}", e)))??;
+        if let Some(home) = home {
+            info!("Home folder '{}' at {} for user '{}'", home.name, home.path, user);
+            let mut config = (*self.config).clone();
+            config.folders.push(home);
+            self.config = Arc::new(config);
+        }
+        Ok(())
+    }
+
+    /// The folders to name to the client. Until a client has logged in, a
+    /// server with users only names those of its certificate's user.
+    fn visible_folders(&self) -> Vec<String> {
+        let anonymous = self.config.users.is_enabled() && !self.authenticated && self.user().is_none();
+        if anonymous {
+            return Vec::new();
+        }
+        self.config.folders.iter()
+            .filter(|folder| self.can_see_folder(&folder.name))
+            .map(|folder| folder.name.clone())
+            .collect()
+    }
+
+    /// The lock for a new session, when idle sessions lock and the client
+    /// authenticated with a token it can enter again.
+    fn idle_lock(&self) -> Option<IdleLock> {
+        if !self.capabilities.supports(FEATURE_IDLE_LOCK) {
+            return None;
+        }
+        let after = self.config.session_idle_lock()?;
+        self.auth_token.as_deref().map(|token| IdleLock::new(after, token))
+    }
+
+    /// Features the server is able to offer on this connection.
+    fn server_features(&self) -> Vec<String> {
+        let mut features: Vec<String> = CORE_FEATURES.iter().map(|f| f.to_string()).collect();
+        features.push(FEATURE_GIT.to_string());
+        features.push(FEATURE_PORT_FORWARD.to_string());
+        features.push(FEATURE_FRAGMENTATION.to_string());
+        features.push(FEATURE_SERVER_NOTICE.to_string());
+        features.push(FEATURE_ENV.to_string());
+        features.push(FEATURE_HISTORY.to_string());
+        features.push(FEATURE_OBSERVE.to_string());
+        features.push(FEATURE_COLLABORATE.to_string());
+        features.push(FEATURE_FOLDER_SWITCH.to_string());
+        features.push(FEATURE_FOLDER_ROOTS.to_string());
+        features.push(FEATURE_MKDIR.to_string());
+        features.push(FEATURE_OPERATION_IDS.to_string());
+        features.push(FEATURE_SESSION_INFO.to_string());
+        if self.config.session_idle_lock().is_some() {
+            features.push(FEATURE_IDLE_LOCK.to_string());
+        }
+        if self.session_store.is_some() {
+            features.push(FEATURE_SESSION_RESUME.to_string());
+        }
+        if self.config.folders.iter().any(|folder| !folder.watch.is_empty()) {
+            features.push(FEATURE_WATCH_RUNS.to_string());
+        }
+        if self.config.users.is_enabled() {
+            features.push(FEATURE_USER_FOLDERS.to_string());
+        }
+        if self.noise.is_some() {
+            features.push(FEATURE_NOISE.to_string());
+        }
+        features.extend(wire_revision_features());
+        features.extend(self.config.server.compression.allowed_algorithms().iter().map(|a| a.feature()));
+        features
+    }
+
+    pub async fn handle(mut self) -> FshResult<Established> {
         // Set connection timeout
         let timeout_duration = Duration::from_secs(self.config.server.connection_timeout_seconds);
 
@@ -39,25 +331,103 @@ impl Connection {
             .map_err(|_| FshError::NetworkError("Connection timeout".to_string()))?
     }
 
-    async fn handle_connection(&mut self) -> FshResult<Session> {
+    async fn handle_connection(&mut self) -> FshResult<Established> {
+        let limits = self.config.server.handshake.clone();
+
         // Step 1: Handle connection handshake
-        self.handle_connect().await?;
+        within(limits.connect_timeout_seconds, "Connect", self.handle_connect()).await?;
 
         // Step 2: Handle authentication (if required)
         if self.config.security.require_authentication {
-            self.handle_authentication().await?;
+            if let Err(e) = within(limits.auth_timeout_seconds, "authentication", self.handle_authentication()).await {
+                if let Some(counters) = &self.counters {
+                    counters.record_auth_failed();
+                }
+                return Err(e);
+            }
         } else {
             self.authenticated = true;
             info!("Authentication skipped for {}", self.client_addr);
         }
 
-        // Step 3: Handle folder binding
-        let folder_info = self.handle_folder_binding().await?;
+        // Authenticated clients may send full-size messages
+        if let Some(stream) = self.stream.as_mut() {
+            let frames = &self.config.server.frames;
+            let codec = stream.codec_mut();
+            codec.set_max_frame_length(frames.max_frame_bytes);
+            codec.set_max_message_length(frames.max_message_bytes);
+            codec.set_fragmentation(self.capabilities.supports(FEATURE_FRAGMENTATION));
+        }
+
+        // Step 3: Handle folder binding, or attach to another session as an observer or participant
+        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+        let message = within(limits.bind_timeout_seconds, "FolderBind", FshCodec::read_message(stream)).await?;
+        let message = match message {
+            FshMessage::ObserveSession(observe_msg) => {
+                return self.attach_observer(observe_msg).await.map(Established::Observer);
+            }
+            FshMessage::JoinSession(join_msg) => {
+                return self.attach_participant(join_msg).await.map(Established::Participant);
+            }
+            FshMessage::SessionResume(resume_msg) => self.resume_binding(resume_msg, limits.bind_timeout_seconds).await?,
+            message => message,
+        };
+        let folder_info = self.handle_folder_binding(message).await?;
 
         // Step 4: Create session
         let session = self.create_session(folder_info).await?;
 
-        Ok(session)
+        Ok(Established::Session(session))
+    }
+
+    /// The FolderBind for the folder of the session saved as
+    /// `resume_msg.session_id`, remembering the session to restore once it is
+    /// bound. A session that can't be resumed is refused, and the client may
+    /// bind a folder instead.
+    async fn resume_binding(&mut self, resume_msg: SessionResumeMessage, bind_timeout_seconds: u64) -> FshResult<FshMessage> {
+        let owner = self.session_owner();
+        let saved = self.session_store.as_ref()
+            .and_then(|store| store.load(&resume_msg.session_id))
+            .filter(|saved| saved.owner == owner);
+        if let Some(saved) = saved {
+            info!("Resuming session {} in folder '{}' for {}", resume_msg.session_id, saved.folder, self.client_addr);
+            let bind_msg = FolderBindMessage {
+                target_folder: saved.folder.clone(),
+                preferred_shell: resume_msg.preferred_shell,
+            };
+            self.resumed = Some((resume_msg.session_id, saved));
+            return Ok(FshMessage::FolderBind(bind_msg));
+        }
+
+        warn!("No saved session {} for {} to resume", resume_msg.session_id, self.client_addr);
+        let response = FshMessage::FolderBound(FolderBoundMessage {
+            success: false,
+            folder_info: None,
+            error_message: Some(format!("No saved session {} to resume", resume_msg.session_id)),
+        });
+        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+        FshCodec::write_message(stream, response).await?;
+        within(bind_timeout_seconds, "FolderBind", FshCodec::read_message(stream)).await
+    }
+
+    /// Switch the connection to a Noise channel, refusing clients whose key
+    /// isn't authorized.
+    async fn start_noise(&mut self, noise: &NoiseServer) -> FshResult<()> {
+        let stream = self.stream.take().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+        let (mut stream, client_key, authorized) = noise.accept(stream).await?;
+        if !authorized {
+            warn!("Refusing unauthorized Noise key {} from {}", client_key, self.client_addr);
+            let error_msg = FshMessage::Error(ErrorMessage::new(
+                FshErrorCode::PermissionDenied,
+                "Noise key not authorized",
+            ));
+            FshCodec::write_message(&mut stream, error_msg).await?;
+            return Err(FshError::PermissionDenied(format!("Noise key {} is not authorized", client_key)));
+        }
+
+        info!("Client {} connected with Noise key {}", self.client_addr, client_key);
+        self.stream = Some(stream);
+        Ok(())
     }
 
     async fn handle_connect(&mut self) -> FshResult<()> {
@@ -72,57 +442,91 @@ impl Connection {
                 info!("Connect request from {} ({})",
                       self.client_addr, connect_msg.client_info.platform);
 
-                // Validate protocol version
-                if connect_msg.version != FSH_VERSION {
+                // Peers sharing a major version interoperate; minor versions only add features
+                let server_version = ProtocolVersion::current();
+                let client_version = ProtocolVersion::parse(&connect_msg.version)
+                    .ok()
+                    .filter(|version| version.is_compatible_with(&server_version));
+
+                let Some(client_version) = client_version else {
                     let response = FshMessage::ConnectResponse(ConnectResponseMessage {
                         success: false,
                         server_version: FSH_VERSION.to_string(),
-                        supported_features: vec!["folder_binding".to_string(), "file_operations".to_string()],
+                        supported_features: self.server_features(),
                         available_folders: vec![],
-                        message: Some(format!("Unsupported protocol version: {}. Expected: {}",
-                                            connect_msg.version, FSH_VERSION)),
+                        message: Some(format!("Unsupported protocol version: {}. Expected: {}.x",
+                                            connect_msg.version, server_version.major)),
                     });
 
                     let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                    FshCodec::write_message(stream, response).await?;
                     return Err(FshError::ProtocolError("Version mismatch".to_string()));
+                };
+
+                if client_version != server_version {
+                    info!("Client {} speaks protocol {}, server speaks {}; using common features only",
+                          self.client_addr, client_version, server_version);
                 }
 
                 // Store client info
                 self.client_info = Some(connect_msg.client_info);
 
+                self.capabilities = Capabilities::negotiate(&connect_msg.supported_features, &self.server_features());
+                debug!("Negotiated features for {}: {:?}", self.client_addr, self.capabilities.features());
+
+                let noise = self.noise.clone().filter(|_| self.capabilities.supports(FEATURE_NOISE));
+                if noise.is_none() && self.noise.as_ref().is_some_and(|noise| noise.is_required()) {
+                    let response = FshMessage::ConnectResponse(ConnectResponseMessage {
+                        success: false,
+                        server_version: FSH_VERSION.to_string(),
+                        supported_features: self.server_features(),
+                        available_folders: vec![],
+                        message: Some("This server only accepts Noise-encrypted connections".to_string()),
+                    });
+                    let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                    FshCodec::write_message(stream, response).await?;
+                    return Err(FshError::PermissionDenied("Client did not ask for Noise".to_string()));
+                }
+
                 // Send successful response
-                let available_folders = self.config.folders.iter()
-                    .map(|f| f.name.clone())
-                    .collect();
+                let available_folders = self.visible_folders();
 
                 let response = FshMessage::ConnectResponse(ConnectResponseMessage {
                     success: true,
                     server_version: FSH_VERSION.to_string(),
-                    supported_features: vec![
-                        "folder_binding".to_string(),
-                        "file_operations".to_string(),
-                        "command_execution".to_string(),
-                        "shell_session".to_string(),
-                    ],
+                    supported_features: self.capabilities.features().to_vec(),
                     available_folders,
                     message: Some("Connection accepted".to_string()),
                 });
 
                 let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                FshCodec::write_message(stream, response).await?;
+
+                // The response itself goes out uncompressed and in the first wire
+                // revision; everything after it may not
+                let revision = self.capabilities.wire_revision();
+                stream.codec_mut().set_revision(revision);
+                debug!("Using wire revision {} for {}", revision, self.client_addr);
+                if let Some(algorithm) = self.capabilities.compression() {
+                    let codec = stream.codec_mut();
+                    codec.set_compression(Some(algorithm));
+                    codec.set_compression_threshold(self.config.server.compression.threshold_bytes);
+                    debug!("Using {} compression for {}", algorithm.name(), self.client_addr);
+                }
+                if let Some(noise) = noise {
+                    self.start_noise(&noise).await?;
+                }
                 info!("Connect handshake completed for {}", self.client_addr);
                 Ok(())
             }
             _ => {
                 error!("Expected Connect message, got {:?}", message.message_type());
-                let error_msg = FshMessage::Error(ErrorMessage {
-                    error_type: "protocol_error".to_string(),
-                    message: "Expected Connect message".to_string(),
-                    details: None,
-                });
+                let error_msg = FshMessage::Error(ErrorMessage::new(
+                    FshErrorCode::ProtocolError,
+                    "Expected Connect message",
+                ));
                 let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &error_msg).await?;
+                FshCodec::write_message(stream, error_msg).await?;
                 Err(FshError::ProtocolError("Expected Connect message".to_string()))
             }
         }
@@ -137,32 +541,87 @@ impl Connection {
         while attempts < max_attempts {
             // Wait for authentication message
             let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-        let message = FshCodec::read_message(stream).await?;
+            let message = FshCodec::read_message(stream).await?;
 
             match message {
                 FshMessage::Authenticate(auth_msg) => {
                     debug!("Authentication attempt {} from {}", attempts + 1, self.client_addr);
 
+                    if let Err(e) = self.check_auth_rate_limit(&auth_msg).await {
+                        warn!("Authentication from {} rate limited: {}", self.client_addr, e);
+                        let response = FshMessage::AuthResponse(AuthResponseMessage {
+                            success: false,
+                            message: Some(e.to_string()),
+                        });
+
+                        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                        FshCodec::write_message(stream, response).await?;
+                        return Err(e);
+                    }
+
+                    let lockout_keys = LockoutKey::for_attempt(self.client_ip(), &auth_msg.credentials);
+                    if let Some(Err(e)) = self.lockout.as_ref().map(|lockout| lockout.check(&lockout_keys)) {
+                        warn!("Authentication from {} refused: {}", self.client_addr, e);
+                        let response = FshMessage::AuthResponse(AuthResponseMessage {
+                            success: false,
+                            message: Some(e.to_string()),
+                        });
+
+                        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                        FshCodec::write_message(stream, response).await?;
+                        return Err(e);
+                    }
+
                     // Validate authentication
                     let auth_result = self.validate_authentication(&auth_msg).await;
 
                     match auth_result {
-                        Ok(()) => {
+                        Ok(token_id) => {
                             let response = FshMessage::AuthResponse(AuthResponseMessage {
                                 success: true,
                                 message: Some("Authentication successful".to_string()),
                             });
 
                             let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                            FshCodec::write_message(stream, response).await?;
                             self.authenticated = true;
+                            self.auth_token = auth_msg.credentials.get("token").cloned();
+                            if let (Some(token_id), Some(auth_manager)) = (&token_id, &self.auth_manager) {
+                                self.token_scope = auth_manager.write().await.use_token(token_id);
+                            }
+                            self.token_id = token_id;
+                            if let Some(lockout) = &self.lockout {
+                                lockout.record_success(&lockout_keys);
+                            }
+                            self.audit_authentication(&auth_msg, true, "Authentication successful".to_string()).await;
                             info!("Authentication successful for {}", self.client_addr);
+
+                            // Now that the server knows who the client is
+                            if let Some(user) = self.user() {
+                                info!("{} logged in as user '{}'", self.client_addr, user);
+                                if let Err(e) = self.provision_home(&user).await {
+                                    warn!("Failed to provision a home folder for user '{}': {}", user, e);
+                                }
+                            }
+                            if self.config.users.is_enabled() && self.capabilities.supports(FEATURE_USER_FOLDERS) {
+                                let folders = FshMessage::FolderList(FolderListMessage { folders: self.visible_folders() });
+                                let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                                FshCodec::write_message(stream, folders).await?;
+                            }
                             return Ok(());
                         }
                         Err(e) => {
                             attempts += 1;
                             warn!("Authentication failed for {} (attempt {}): {}",
                                   self.client_addr, attempts, e);
+                            self.audit_authentication(&auth_msg, false, e.detail()).await;
+                            self.events.emit(ServerEvent::AuthFailed {
+                                client_addr: self.client_addr.clone(),
+                                method: auth_msg.auth_type.clone(),
+                            });
+
+                            // Each failure makes the client wait longer for an answer
+                            let locked = self.record_auth_failure(&lockout_keys).await?;
 
                             let response = FshMessage::AuthResponse(AuthResponseMessage {
                                 success: false,
@@ -171,9 +630,9 @@ impl Connection {
                             });
 
                             let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                            FshCodec::write_message(stream, response).await?;
 
-                            if attempts >= max_attempts {
+                            if locked || attempts >= max_attempts {
                                 error!("Maximum authentication attempts exceeded for {}", self.client_addr);
                                 return Err(FshError::AuthenticationFailed);
                             }
@@ -183,13 +642,12 @@ impl Connection {
                 _ => {
                     error!("Expected Authenticate message from {}, got {:?}",
                            self.client_addr, message.message_type());
-                    let error_msg = FshMessage::Error(ErrorMessage {
-                        error_type: "protocol_error".to_string(),
-                        message: "Expected Authenticate message".to_string(),
-                        details: None,
-                    });
+                    let error_msg = FshMessage::Error(ErrorMessage::new(
+                        FshErrorCode::ProtocolError,
+                        "Expected Authenticate message",
+                    ));
                     let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &error_msg).await?;
+                    FshCodec::write_message(stream, error_msg).await?;
                     return Err(FshError::ProtocolError("Expected Authenticate message".to_string()));
                 }
             }
@@ -198,20 +656,33 @@ impl Connection {
         Err(FshError::AuthenticationFailed)
     }
 
-    async fn validate_authentication(&self, auth_msg: &AuthenticateMessage) -> FshResult<()> {
+    /// Check the client's credentials, returning the id of the token it
+    /// authenticated with.
+    async fn validate_authentication(&self, auth_msg: &AuthenticateMessage) -> FshResult<Option<String>> {
+        if let Some(authenticator) = &self.authenticator {
+            return authenticator.authenticate(&AuthRequest {
+                method: &auth_msg.auth_type,
+                credentials: &auth_msg.credentials,
+                client_addr: &self.client_addr,
+                identity: self.client_identity.as_ref(),
+            }).await;
+        }
+
         match auth_msg.auth_type.as_str() {
             "token" => {
-                if let Some(token) = auth_msg.credentials.get("token") {
-                    // TODO: Implement actual token validation
-                    // For now, accept any non-empty token
-                    if !token.is_empty() {
-                        Ok(())
-                    } else {
-                        Err(FshError::AuthenticationFailed)
-                    }
-                } else {
-                    Err(FshError::AuthenticationFailed)
+                let token = auth_msg.credentials.get("token").ok_or(FshError::AuthenticationFailed)?;
+                let auth_manager = self.auth_manager.as_ref().ok_or(FshError::AuthenticationFailed)?;
+                AuthManager::authenticate_token(auth_manager, token).await.map(Some)
+            }
+            "certificate" => {
+                // The TLS handshake already verified the certificate against the client CA
+                if !self.config.security.auth_methods.iter().any(|method| method == "certificate") {
+                    return Err(FshError::ProtocolError("Certificate authentication is not enabled".to_string()));
                 }
+                let user = self.client_identity.as_ref().and_then(|identity| identity.user())
+                    .ok_or(FshError::AuthenticationFailed)?;
+                info!("{} authenticated by client certificate as '{}'", self.client_addr, user);
+                Ok(None)
             }
             "password" => {
                 // TODO: Implement password authentication
@@ -223,13 +694,9 @@ impl Connection {
         }
     }
 
-    async fn handle_folder_binding(&mut self) -> FshResult<crate::protocol::FolderInfo> {
+    async fn handle_folder_binding(&mut self, message: FshMessage) -> FshResult<crate::protocol::FolderInfo> {
         debug!("Handling folder binding for {}", self.client_addr);
 
-        // Wait for folder bind message
-        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-        let message = FshCodec::read_message(stream).await?;
-
         match message {
             FshMessage::FolderBind(bind_msg) => {
                 info!("Folder bind request for '{}' from {}",
@@ -241,8 +708,43 @@ impl Connection {
 
                 match folder_config {
                     Some(folder) => {
+                        // Honeypots accept every bind
+                        let honeypot = folder.honeypot.enabled;
+
+                        // Folders may be limited to certain client certificates
+                        let identity_names = self.client_identity.as_ref().map(CertIdentity::names).unwrap_or_default();
+                        if !honeypot && !folder.allows_identity(&identity_names) {
+                            warn!("Client {} ({:?}) is not allowed to bind folder '{}'",
+                                  self.client_addr, identity_names, folder.name);
+                            let response = FshMessage::FolderBound(FolderBoundMessage {
+                                success: false,
+                                folder_info: None,
+                                error_message: Some(format!(
+                                    "Folder '{}' requires a client certificate it allows", folder.name
+                                )),
+                            });
+                            let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                            FshCodec::write_message(stream, response).await?;
+                            return Err(FshError::PermissionDenied(
+                                format!("Client identity not allowed for folder '{}'", folder.name)
+                            ));
+                        }
+
+                        // Share links only open their own folder, and users their own folders
+                        if !self.can_see_folder(&folder.name) {
+                            warn!("Client {} ({:?}) may not see folder '{}'", self.client_addr, self.user(), folder.name);
+                            let response = FshMessage::FolderBound(FolderBoundMessage {
+                                success: false,
+                                folder_info: None,
+                                error_message: Some(format!("Folder '{}' not found or not accessible", bind_msg.target_folder)),
+                            });
+                            let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                            FshCodec::write_message(stream, response).await?;
+                            return Err(FshError::FolderNotFound(bind_msg.target_folder));
+                        }
+
                         // Validate folder access
-                        if let Err(e) = folder.validate() {
+                        if let Err(e) = folder.validate().or_else(|e| if honeypot { Ok(()) } else { Err(e) }) {
                             warn!("Folder validation failed for '{}': {}", bind_msg.target_folder, e);
                             let response = FshMessage::FolderBound(FolderBoundMessage {
                                 success: false,
@@ -250,18 +752,51 @@ impl Connection {
                                 error_message: Some(format!("Folder access error: {}", e)),
                             });
                             let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                            FshCodec::write_message(stream, response).await?;
                             return Err(e);
                         }
 
+                        // Enforce the per-folder session limit
+                        if let Some(max_sessions) = folder.max_sessions.filter(|_| !honeypot) {
+                            let active_sessions = self.sessions.folder_count(&folder.name).await;
+                            if active_sessions >= max_sessions {
+                                warn!("Folder '{}' session limit reached ({}/{}), rejecting {}",
+                                      folder.name, active_sessions, max_sessions, self.client_addr);
+                                let response = FshMessage::FolderBound(FolderBoundMessage {
+                                    success: false,
+                                    folder_info: None,
+                                    error_message: Some(format!(
+                                        "Folder '{}' has reached its session limit ({} of {} sessions in use)",
+                                        folder.name, active_sessions, max_sessions
+                                    )),
+                                });
+                                let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+                                FshCodec::write_message(stream, response).await?;
+                                return Err(FshError::PermissionDenied(
+                                    format!("Session limit reached for folder '{}'", folder.name)
+                                ));
+                            }
+                        }
+
                         // Create folder info
                         let mut folder_info = folder.to_folder_info();
+                        if let Some(scope) = &self.token_scope {
+                            scope.restrict(&mut folder_info.permissions);
+                        }
 
                         // Override shell type if requested
                         if let Some(preferred_shell) = bind_msg.preferred_shell {
                             folder_info.shell_type = preferred_shell;
                         }
 
+                        // Fall back to an installed shell if the requested one is missing
+                        let shell_type = crate::sandbox::detect_shell(&folder_info.shell_type);
+                        if shell_type != folder_info.shell_type {
+                            warn!("Shell {:?} is not available for folder '{}', using {:?}",
+                                  folder_info.shell_type, folder.name, shell_type);
+                            folder_info.shell_type = shell_type;
+                        }
+
                         // Send successful response
                         let response = FshMessage::FolderBound(FolderBoundMessage {
                             success: true,
@@ -270,8 +805,12 @@ impl Connection {
                         });
 
                         let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                        FshCodec::write_message(stream, response).await?;
                         info!("Folder '{}' bound successfully for {}", bind_msg.target_folder, self.client_addr);
+                        self.events.emit(ServerEvent::FolderBound {
+                            folder: folder_info.name.clone(),
+                            client_addr: self.client_addr.clone(),
+                        });
                         Ok(folder_info)
                     }
                     None => {
@@ -282,7 +821,7 @@ impl Connection {
                             error_message: Some(format!("Folder '{}' not found or not accessible", bind_msg.target_folder)),
                         });
                         let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &response).await?;
+                        FshCodec::write_message(stream, response).await?;
                         Err(FshError::FolderNotFound(bind_msg.target_folder))
                     }
                 }
@@ -290,29 +829,238 @@ impl Connection {
             _ => {
                 error!("Expected FolderBind message from {}, got {:?}",
                        self.client_addr, message.message_type());
-                let error_msg = FshMessage::Error(ErrorMessage {
-                    error_type: "protocol_error".to_string(),
-                    message: "Expected FolderBind message".to_string(),
-                    details: None,
-                });
+                let error_msg = FshMessage::Error(ErrorMessage::new(
+                    FshErrorCode::ProtocolError,
+                    "Expected FolderBind message",
+                ));
                 let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
-                FshCodec::write_message(stream, &error_msg).await?;
+                FshCodec::write_message(stream, error_msg).await?;
                 Err(FshError::ProtocolError("Expected FolderBind message".to_string()))
             }
         }
     }
 
+    /// Attach to the session named in `observe_msg` if its owner allows
+    /// observers and this client could bind its folder.
+    async fn attach_observer(&mut self, observe_msg: ObserveSessionMessage) -> FshResult<SessionObserver> {
+        info!("Observe request for session {} from {}", observe_msg.session_id, self.client_addr);
+
+        let shared = self.shared_session(&observe_msg.session_id, |session| {
+            session.mirror().consented().then_some(()).ok_or("The session's owner has not allowed observers")
+        }).await;
+        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+        let session = match shared {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Refused to let {} observe session {}: {}", self.client_addr, observe_msg.session_id, e);
+                let response = FshMessage::ObserveStarted(ObserveStartedMessage {
+                    success: false,
+                    folder_info: None,
+                    working_directory: None,
+                    error_message: Some(e.to_string()),
+                });
+                FshCodec::write_message(stream, response).await?;
+                return Err(e);
+            }
+        };
+
+        // Subscribe before confirming so nothing the session does is missed
+        let stream = self.stream.take().ok_or_else(|| FshError::NetworkError("Stream already taken".to_string()))?;
+        let mut observer = SessionObserver::new(stream, Arc::clone(&session), self.client_addr.clone());
+        let response = FshMessage::ObserveStarted(ObserveStartedMessage {
+            success: true,
+            folder_info: Some(session.folder_info()),
+            working_directory: Some(session.working_directory().await),
+            error_message: None,
+        });
+        observer.send(response).await?;
+
+        // The owner always learns who is watching
+        let notice = format!("{} is now observing this session", self.client_addr);
+        if let Err(e) = session.notify(&notice).await {
+            warn!("Failed to tell session {} about its observer: {}", session.id(), e);
+        }
+        Ok(observer)
+    }
+
+    /// Join the session named in `join_msg` with interactive input if its
+    /// owner allows collaborators and this client could bind its folder.
+    async fn attach_participant(&mut self, join_msg: JoinSessionMessage) -> FshResult<SessionParticipant> {
+        info!("Join request for session {} from {}", join_msg.session_id, self.client_addr);
+
+        let may_execute = self.token_scope.as_ref()
+            .is_none_or(|scope| scope.permissions.contains(&crate::protocol::Permission::Execute));
+        let shared = self.shared_session(&join_msg.session_id, |session| {
+            if !may_execute {
+                return Err("Your token can't run commands");
+            }
+            session.collaboration().consented().then_some(()).ok_or("The session's owner has not allowed collaborators")
+        }).await;
+        let stream = self.stream.as_mut().ok_or_else(|| FshError::NetworkError("Stream not available".to_string()))?;
+        let session = match shared {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Refused to let {} join session {}: {}", self.client_addr, join_msg.session_id, e);
+                let response = FshMessage::SessionJoined(SessionJoinedMessage {
+                    success: false,
+                    folder_info: None,
+                    working_directory: None,
+                    participant: None,
+                    error_message: Some(e.to_string()),
+                });
+                FshCodec::write_message(stream, response).await?;
+                return Err(e);
+            }
+        };
+
+        // Participants are known by their certificate's user, or else their address
+        let name = self.client_identity.as_ref().and_then(|identity| identity.user())
+            .map(str::to_string)
+            .unwrap_or_else(|| self.client_addr.clone());
+        let audit = self.client_audit();
+        if let Some(audit) = &audit {
+            if let Err(e) = audit.logger.log_participant_joined(audit.source_ip, session.id().to_string(), &name).await {
+                warn!("Failed to audit {} joining session {}: {}", name, session.id(), e);
+            }
+        }
+
+        // Subscribe before confirming so nothing the session does is missed
+        let stream = self.stream.take().ok_or_else(|| FshError::NetworkError("Stream already taken".to_string()))?;
+        let participant = SessionParticipant::new(stream, Arc::clone(&session), name.clone(), self.client_addr.clone(), audit);
+        let response = FshMessage::SessionJoined(SessionJoinedMessage {
+            success: true,
+            folder_info: Some(session.folder_info()),
+            working_directory: Some(session.working_directory().await),
+            participant: Some(name.clone()),
+            error_message: None,
+        });
+        participant.send(response).await?;
+
+        let notice = format!("{} ({}) joined this session and can run commands in it", name, self.client_addr);
+        if let Err(e) = session.notify(&notice).await {
+            warn!("Failed to tell session {} about its participant: {}", session.id(), e);
+        }
+        Ok(participant)
+    }
+
+    /// The live session `session_id`, if this client could bind its folder
+    /// and `consented` finds its owner allows sharing it this way.
+    async fn shared_session(
+        &self,
+        session_id: &str,
+        consented: impl FnOnce(&Session) -> Result<(), &'static str>,
+    ) -> FshResult<Arc<Session>> {
+        let session = self.sessions.get(session_id).await
+            .ok_or_else(|| FshError::SessionNotFound(session_id.to_string()))?;
+        let identity_names = self.client_identity.as_ref().map(CertIdentity::names).unwrap_or_default();
+        let folder = session.folder_info().name;
+        let allowed = self.config.find_folder_by_name(&folder)
+            .is_some_and(|folder| folder.allows_identity(&identity_names))
+            && self.can_see_folder(&folder);
+        if !allowed {
+            return Err(FshError::PermissionDenied(format!("Client identity not allowed for folder '{}'", folder)));
+        }
+        consented(&session).map_err(|refusal| FshError::PermissionDenied(refusal.to_string()))?;
+        Ok(session)
+    }
+
+    fn client_ip(&self) -> Option<std::net::IpAddr> {
+        self.client_addr.parse::<std::net::SocketAddr>().ok().map(|addr| addr.ip())
+    }
+
+    fn client_audit(&self) -> Option<ClientAudit> {
+        let logger = self.audit_logger.as_ref()?;
+        Some(ClientAudit { logger: Arc::clone(logger), source_ip: self.client_ip()? })
+    }
+
+    async fn audit_authentication(&self, auth_msg: &AuthenticateMessage, success: bool, details: String) {
+        let Some(audit) = self.client_audit() else {
+            return;
+        };
+        let user_id = auth_msg.credentials.get("username").cloned();
+        if let Err(e) = audit.logger.log_authentication_attempt(audit.source_ip, user_id, success, details).await {
+            warn!("Failed to audit authentication from {}: {}", self.client_addr, e);
+        }
+    }
+
+    /// Record a failed attempt, wait out its delay, and report whether it
+    /// locked the client's address, user or token.
+    async fn record_auth_failure(&self, keys: &[LockoutKey]) -> FshResult<bool> {
+        let Some(lockout) = &self.lockout else {
+            return Ok(false);
+        };
+
+        let attempt = lockout.record_failure(keys);
+        for key in &attempt.locked {
+            error!("{} locked out after repeated failed authentication attempts from {}", key, self.client_addr);
+            if let Some(audit) = self.client_audit() {
+                audit.logger.log_lockout(audit.source_ip, key).await?;
+            }
+        }
+        tokio::time::sleep(attempt.delay).await;
+
+        Ok(!attempt.locked.is_empty())
+    }
+
+    /// Count an authentication attempt against the client's address and the
+    /// token it presented.
+    async fn check_auth_rate_limit(&self, auth_msg: &AuthenticateMessage) -> FshResult<()> {
+        let Some(rate_limits) = &self.rate_limits else {
+            return Ok(());
+        };
+        let keys: Vec<RateLimitKey> = self.client_ip().map(RateLimitKey::Ip).into_iter()
+            .chain(auth_msg.credentials.get("token").map(|token| RateLimitKey::token(token)))
+            .collect();
+
+        let result = rate_limits.check(RateLimitKind::Auth, &keys).await;
+        if let (Err(e), Some(audit)) = (&result, self.client_audit()) {
+            audit.logger.log_rate_limit_exceeded(audit.source_ip, None, &e.detail()).await?;
+        }
+        result
+    }
+
+    /// The folders the client's session may move to, and the per-folder
+    /// state sessions on them share.
+    fn folder_access(&self) -> FolderAccess {
+        let identity_names = self.client_identity.as_ref().map(CertIdentity::names).unwrap_or_default();
+        let access = FolderAccess::new(Arc::clone(&self.config), identity_names, self.sessions.clone())
+            .with_folder_state(Arc::clone(&self.folder_bandwidth), Arc::clone(&self.folder_history))
+            .with_user(self.user());
+        match &self.token_scope {
+            Some(scope) => access.with_token_scope(scope.clone()),
+            None => access,
+        }
+    }
+
     async fn create_session(&mut self, folder_info: crate::protocol::FolderInfo) -> FshResult<Session> {
         let session_id = Uuid::new_v4().to_string();
 
         debug!("Creating session {} for {}", session_id, self.client_addr);
 
         // Find the folder config
-        let folder_config = self.config.find_folder_by_name(&folder_info.name)
-            .ok_or_else(|| FshError::ConfigError("Folder config not found".to_string()))?;
+        let mut folder_config = self.config.find_folder_by_name(&folder_info.name)
+            .ok_or_else(|| FshError::ConfigError("Folder config not found".to_string()))?
+            .clone();
+        if let Some(scope) = &self.token_scope {
+            scope.restrict_folder(&mut folder_config);
+        }
 
         // Take ownership of the stream for the session
         let stream = self.stream.take().ok_or_else(|| FshError::NetworkError("Stream already taken".to_string()))?;
+        let folder_access = self.folder_access();
+
+        // Honeypot sessions aren't worth resuming
+        let owner = self.session_owner();
+        let (resumed_from, saved) = self.resumed.take().unzip();
+        let persistence = self.session_store.clone()
+            .filter(|_| !folder_config.honeypot.enabled)
+            .map(|store| {
+                let persistence = SessionPersistence::new(store, owner);
+                match saved {
+                    Some(saved) => persistence.with_restored(saved),
+                    None => persistence,
+                }
+            });
 
         // Create session
         let session = Session::new(
@@ -325,42 +1073,264 @@ impl Connection {
                 app_version: "unknown".to_string(),
                 app_name: "unknown".to_string(),
             }),
+            self.client_addr.clone(),
+            self.config.session_idle_timeout(&folder_config),
+            self.config.server.keepalive.clone(),
+            folder_access.transfer_limits(&folder_config),
+            self.command_limiter.for_session(&session_id, &folder_config),
+            self.client_audit(),
+            self.approvals.clone(),
+            self.rate_limits.clone().map(|rate_limits| {
+                ClientRateLimits::new(rate_limits, self.client_ip(), self.auth_token.as_deref(), &session_id)
+            }),
+            match &self.usage {
+                Some(usage) => usage.start_session(&folder_config.name),
+                None => SessionUsage::untracked(&folder_config.name),
+            },
+            folder_access.history(&folder_config),
+            self.events.clone(),
+            self.plugins.clone(),
+            self.malware_scanner.clone(),
+            self.dlp.clone(),
+            Some(folder_access),
+            Arc::clone(&self.operations),
+            self.idle_lock(),
+            persistence,
         ).await?;
+        if let Some((previous_id, store)) = resumed_from.zip(self.session_store.as_ref()) {
+            store.remove(&previous_id);
+        }
+        let session = session.with_capabilities(self.capabilities.clone());
+        let session = match self.token_id.clone() {
+            Some(token_id) => session.with_token_id(token_id),
+            None => session,
+        };
 
         // Note: Session will handle sending session start message internally
 
         info!("Session {} created for {} on folder '{}'",
               session_id, self.client_addr, folder_config.name);
+        self.events.emit(ServerEvent::SessionStarted {
+            session_id,
+            folder: folder_config.name.clone(),
+            client_addr: self.client_addr.clone(),
+        });
 
         Ok(session)
     }
 }
 
+/// What a connection becomes once its handshake is done.
+#[allow(clippy::large_enum_variant)]
+pub enum Established {
+    /// A session of its own on the folder it bound.
+    Session(Session),
+    /// A read-only view of another client's session.
+    Observer(SessionObserver),
+    /// Interactive input to another client's session.
+    Participant(SessionParticipant),
+}
+
+/// Run one handshake phase, giving up after `seconds`.
+async fn within<T>(seconds: u64, phase: &str, future: impl Future<Output = FshResult<T>>) -> FshResult<T> {
+    timeout(Duration::from_secs(seconds), future).await
+        .map_err(|_| FshError::NetworkError(format!("Timed out waiting for {}", phase)))?
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::FolderConfig;
-    use crate::protocol::ShellType;
+    use crate::server::{CommandLimits, SessionHistory, TransferLimits};
     use tempfile::TempDir;
     use tokio::net::{TcpListener, TcpStream};
 
-    async fn create_test_connection() -> (Connection, TcpStream) {
+    async fn stream_pair() -> (TcpStream, TcpStream) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
         let client_stream = TcpStream::connect(addr).await.unwrap();
         let (server_stream, _) = listener.accept().await.unwrap();
 
+        (server_stream, client_stream)
+    }
+
+    async fn create_test_connection(folder: FolderConfig, sessions: SessionManager) -> (Connection, FshFramed<TcpStream>) {
+        let (server_stream, client_stream) = stream_pair().await;
+
         let mut config = Config::default();
         config.security.require_authentication = false;
+        config.folders.push(folder);
+
+        let connection = Connection::new(server_stream, "127.0.0.1:12345".to_string(), Arc::new(config), sessions);
+
+        (connection, FshCodec::framed(client_stream))
+    }
 
+    async fn send_connect_and_bind(client: &mut FshFramed<TcpStream>, folder: &str) -> FolderBoundMessage {
+        FshCodec::write_message(client, FshMessage::Connect(ConnectMessage {
+            version: FSH_VERSION.to_string(),
+            client_info: ClientInfo {
+                platform: "test".to_string(),
+                app_version: "1.0".to_string(),
+                app_name: "test".to_string(),
+            },
+            supported_features: vec![],
+        })).await.unwrap();
+        assert!(matches!(FshCodec::read_message(client).await.unwrap(), FshMessage::ConnectResponse(_)));
+
+        FshCodec::write_message(client, FshMessage::FolderBind(FolderBindMessage {
+            target_folder: folder.to_string(),
+            preferred_shell: None,
+        })).await.unwrap();
+        match FshCodec::read_message(client).await.unwrap() {
+            FshMessage::FolderBound(bound) => bound,
+            other => panic!("Expected FolderBound, got {:?}", other.message_type()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_folder_session_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = FolderConfig::new("test".to_string(), temp_dir.path())
+            .with_max_sessions(1);
+        let sessions = SessionManager::new();
+
+        // Occupy the only slot with an existing session
+        let (existing_stream, _existing_client) = stream_pair().await;
+        let existing = Session::new(
+            "existing".to_string(),
+            FshCodec::framed(existing_stream.into()),
+            folder.to_folder_info(),
+            folder.clone(),
+            ClientInfo {
+                platform: "test".to_string(),
+                app_version: "1.0".to_string(),
+                app_name: "test".to_string(),
+            },
+            "127.0.0.1:12346".to_string(),
+            None,
+            crate::config::KeepaliveConfig::default(),
+            TransferLimits::new(),
+            CommandLimits::unlimited(),
+            None,
+            None,
+            None,
+            SessionUsage::untracked("test"),
+            SessionHistory::new(&Default::default()),
+            EventBus::default(),
+            Plugins::default(),
+            None,
+            None,
+            None,
+            Arc::new(OperationLog::new()),
+            None,
+            None,
+        ).await.unwrap();
+        sessions.insert(Arc::new(existing)).await;
+
+        let (connection, mut client) = create_test_connection(folder, sessions.clone()).await;
+        let handle = tokio::spawn(connection.handle());
+
+        let bound = send_connect_and_bind(&mut client, "test").await;
+        assert!(!bound.success);
+        assert!(bound.error_message.unwrap().contains("session limit"));
+        assert!(matches!(handle.await.unwrap(), Err(FshError::PermissionDenied(_))));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limits() {
         let temp_dir = TempDir::new().unwrap();
         let folder = FolderConfig::new("test".to_string(), temp_dir.path());
-        config.folders.push(folder);
 
-        let connection = Connection::new(server_stream, "127.0.0.1:12345".to_string(), Arc::new(config));
+        // A client that never sends Connect is dropped after the connect timeout
+        let (mut connection, _client) = create_test_connection(folder.clone(), SessionManager::default()).await;
+        Arc::make_mut(&mut connection.config).server.handshake.connect_timeout_seconds = 1;
+        let started = std::time::Instant::now();
+        let result = connection.handle().await;
+        assert!(matches!(result, Err(FshError::NetworkError(message)) if message.contains("Connect")));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Oversized frames are refused before authentication
+        let (connection, mut client) = create_test_connection(folder, SessionManager::default()).await;
+        let handle = tokio::spawn(connection.handle());
+        let _ = FshCodec::write_message(&mut client, FshMessage::Connect(ConnectMessage {
+            version: FSH_VERSION.to_string(),
+            client_info: ClientInfo {
+                platform: "test".to_string(),
+                app_version: "1.0".to_string(),
+                app_name: "test".to_string(),
+            },
+            supported_features: vec!["x".repeat(crate::protocol::PRE_AUTH_MAX_FRAME_LENGTH)],
+        })).await;
+        assert!(matches!(handle.await.unwrap(), Err(FshError::ProtocolError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_compression_negotiated() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = FolderConfig::new("test".to_string(), temp_dir.path());
+        let sessions = SessionManager::new();
+
+        let (mut connection, mut client) = create_test_connection(folder, sessions).await;
+        let handle = tokio::spawn(async move {
+            connection.handle_connect().await.unwrap();
+            let codec = connection.stream.unwrap().codec().clone();
+            (codec.compression(), codec.revision())
+        });
+
+        FshCodec::write_message(&mut client, FshMessage::Connect(ConnectMessage {
+            version: FSH_VERSION.to_string(),
+            client_info: ClientInfo {
+                platform: "test".to_string(),
+                app_version: "1.0".to_string(),
+                app_name: "test".to_string(),
+            },
+            supported_features: vec!["compression:lz4".to_string(), "compression:deflate".to_string()],
+        })).await.unwrap();
+
+        match FshCodec::read_message(&mut client).await.unwrap() {
+            FshMessage::ConnectResponse(resp) => {
+                assert!(resp.supported_features.contains(&"compression:deflate".to_string()));
+                assert!(!resp.supported_features.contains(&"compression:zstd".to_string()));
+            }
+            other => panic!("Expected ConnectResponse, got {:?}", other.message_type()),
+        }
+        // The client didn't list wire revisions, so the server keeps to the first
+        assert_eq!(handle.await.unwrap(), (Some(crate::protocol::CompressionAlgorithm::Deflate), crate::protocol::MIN_WIRE_REVISION));
+    }
 
-        (connection, client_stream)
+    #[tokio::test]
+    async fn test_version_skew() {
+        for (version, accepted) in [("1.7", true), ("2.0", false), ("garbage", false)] {
+            let temp_dir = TempDir::new().unwrap();
+            let folder = FolderConfig::new("test".to_string(), temp_dir.path());
+            let sessions = SessionManager::new();
+
+            let (mut connection, mut client) = create_test_connection(folder, sessions).await;
+            let handle = tokio::spawn(async move { connection.handle_connect().await });
+
+            FshCodec::write_message(&mut client, FshMessage::Connect(ConnectMessage {
+                version: version.to_string(),
+                client_info: ClientInfo {
+                    platform: "test".to_string(),
+                    app_version: "1.0".to_string(),
+                    app_name: "test".to_string(),
+                },
+                supported_features: vec!["pty".to_string()],
+            })).await.unwrap();
+
+            match FshCodec::read_message(&mut client).await.unwrap() {
+                FshMessage::ConnectResponse(resp) => {
+                    assert_eq!(resp.success, accepted, "version {}", version);
+                    if accepted {
+                        assert!(!resp.supported_features.contains(&"pty".to_string()));
+                    }
+                }
+                other => panic!("Expected ConnectResponse, got {:?}", other.message_type()),
+            }
+            assert_eq!(handle.await.unwrap().is_ok(), accepted);
+        }
     }
 
     #[tokio::test]
@@ -372,10 +1342,11 @@ mod tests {
             // Create a dummy stream for testing
             let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
             let addr = listener.local_addr().unwrap();
-            let client = TcpStream::connect(addr).await.unwrap();
+            let _client = TcpStream::connect(addr).await.unwrap();
             let (server, _) = listener.accept().await.unwrap();
 
-            let connection = Connection::new(server, "127.0.0.1:12345".to_string(), Arc::new(config));
+            let sessions = SessionManager::new();
+            let connection = Connection::new(server, "127.0.0.1:12345".to_string(), Arc::new(config), sessions);
             assert_eq!(connection.client_addr, "127.0.0.1:12345");
             assert!(!connection.authenticated);
         }
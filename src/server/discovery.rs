@@ -0,0 +1,125 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::config::FolderConfig;
+use crate::protocol::{FshError, FshResult, FSH_VERSION, MDNS_SERVICE_TYPE};
+
+/// Longest value of the `folders` TXT property; a TXT string holds at most
+/// 255 bytes including the key.
+const MAX_FOLDER_LIST: usize = 240;
+
+/// Advertising the server on the local network over mDNS, for
+/// `fsh-client discover`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    /// Instance name shown to clients; defaults to the host name.
+    pub name: Option<String>,
+    /// Include the folder names in the advertisement.
+    pub advertise_folders: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: None,
+            advertise_folders: true,
+        }
+    }
+}
+
+/// The server's mDNS registration, withdrawn when dropped.
+pub struct MdnsAdvertisement {
+    daemon: ServiceDaemon,
+}
+
+impl MdnsAdvertisement {
+    /// Advertise the server listening on `port` on every interface.
+    pub fn start(config: &DiscoveryConfig, folders: &[FolderConfig], port: u16, tls: bool) -> FshResult<Self> {
+        let name = config.name.clone().unwrap_or_else(host_name);
+        let host: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+            .collect();
+
+        let mut properties = HashMap::from([
+            ("version".to_string(), FSH_VERSION.to_string()),
+            ("tls".to_string(), if tls { "1" } else { "0" }.to_string()),
+        ]);
+        if config.advertise_folders {
+            properties.insert("folders".to_string(), folder_list(folders));
+        }
+
+        let service = ServiceInfo::new(MDNS_SERVICE_TYPE, &name, &format!("{}.local.", host), "", port, properties)
+            .map_err(mdns_error)?
+            .enable_addr_auto();
+        let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+        daemon.register(service).map_err(mdns_error)?;
+        info!("Advertising '{}' on port {} over mDNS", name, port);
+        Ok(Self { daemon })
+    }
+}
+
+impl Drop for MdnsAdvertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Folder names separated by commas, as many as fit in a TXT string.
+fn folder_list(folders: &[FolderConfig]) -> String {
+    let mut list = String::new();
+    for folder in folders {
+        let name = folder.name.replace(',', " ");
+        if list.len() + name.len() + 1 > MAX_FOLDER_LIST {
+            break;
+        }
+        if !list.is_empty() {
+            list.push(',');
+        }
+        list.push_str(&name);
+    }
+    list
+}
+
+fn host_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0 as libc::c_char; 256];
+        // SAFETY: the buffer is live and its length is passed along
+        if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len()) } == 0 {
+            // SAFETY: gethostname NUL-terminates on success within the buffer
+            let name = unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy();
+            if !name.is_empty() {
+                return name.split('.').next().unwrap_or_default().to_string();
+            }
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "fsh-server".to_string())
+}
+
+pub(crate) fn mdns_error(e: mdns_sd::Error) -> FshError {
+    FshError::NetworkError(format!("mDNS: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folder_list_fits_txt_record() {
+        let folders = vec![
+            FolderConfig::new("docs".to_string(), "/srv/docs"),
+            FolderConfig::new("a,b".to_string(), "/srv/ab"),
+        ];
+        assert_eq!(folder_list(&folders), "docs,a b");
+
+        let many: Vec<_> = (0..100).map(|i| FolderConfig::new(format!("folder-{}", i), "/srv")).collect();
+        let list = folder_list(&many);
+        assert!(list.len() <= MAX_FOLDER_LIST);
+        assert!(list.starts_with("folder-0,folder-1,"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Events a subscriber can act on without being wired into the session code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client opened a session on a folder.
+    SessionStarted {
+        session_id: String,
+        folder: String,
+        client_addr: String,
+    },
+    /// A client bound a folder; its `SessionStarted` follows.
+    FolderBound {
+        folder: String,
+        client_addr: String,
+    },
+    /// A foreground command or background job finished; `exit_code` is -1
+    /// when it could not be started.
+    CommandExecuted {
+        session_id: String,
+        folder: String,
+        command: String,
+        exit_code: i32,
+        duration: Duration,
+    },
+    /// A login attempt was refused; `method` is the auth type the client
+    /// asked for.
+    AuthFailed {
+        client_addr: String,
+        method: String,
+    },
+    /// The server is stopping; no events follow.
+    Shutdown,
+}
+
+/// Events buffered per subscriber before the slowest one starts missing them.
+const EVENT_BUFFER: usize = 1024;
+
+/// Broadcasts `ServerEvent`s to every subscriber. Emitting with nobody
+/// subscribed is free, and a subscriber that falls behind misses the oldest
+/// events (`RecvError::Lagged`) rather than slowing the server down.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self { sender: broadcast::channel(EVENT_BUFFER).0 }
+    }
+}
+
+impl EventBus {
+    /// Receive every event emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, event: ServerEvent) {
+        // Nobody listening is not an error
+        let _ = self.sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_see_later_events() {
+        let events = EventBus::default();
+        events.emit(ServerEvent::Shutdown);
+
+        let mut first = events.subscribe();
+        let mut second = events.clone().subscribe();
+        events.emit(ServerEvent::AuthFailed { client_addr: "10.0.0.5:4000".to_string(), method: "token".to_string() });
+
+        for receiver in [&mut first, &mut second] {
+            assert!(matches!(receiver.recv().await.unwrap(), ServerEvent::AuthFailed { .. }));
+            assert!(receiver.try_recv().is_err());
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{FolderInfo, FshError, FshResult, ShellType};
+use crate::security::TokenScope;
+use crate::server::{BandwidthLimiter, FolderBandwidth, FolderHistory, SessionHistory, SessionManager, TransferLimits};
+
+/// Decides which folders a client may move its session to, with the same
+/// checks as binding one, and sets up the per-folder state a new session on
+/// the folder would get.
+#[derive(Debug, Clone)]
+pub struct FolderAccess {
+    config: Arc<Config>,
+    /// Names from the client's verified TLS certificate.
+    identity_names: Vec<String>,
+    sessions: SessionManager,
+    folder_bandwidth: Arc<FolderBandwidth>,
+    folder_history: Arc<FolderHistory>,
+    token_scope: Option<TokenScope>,
+    /// The user the client logged in as, who only sees its own folders.
+    user: Option<String>,
+}
+
+/// A folder a session was let into.
+#[derive(Debug, Clone)]
+pub struct FolderGrant {
+    pub folder_info: FolderInfo,
+    pub folder_config: FolderConfig,
+    pub transfer_limits: TransferLimits,
+    pub history: SessionHistory,
+}
+
+impl FolderAccess {
+    pub fn new(config: Arc<Config>, identity_names: Vec<String>, sessions: SessionManager) -> Self {
+        Self {
+            config,
+            identity_names,
+            sessions,
+            folder_bandwidth: Arc::new(FolderBandwidth::new()),
+            folder_history: Arc::new(FolderHistory::new()),
+            token_scope: None,
+            user: None,
+        }
+    }
+
+    /// Share per-folder bandwidth limits and history with the server's other
+    /// connections.
+    pub fn with_folder_state(mut self, folder_bandwidth: Arc<FolderBandwidth>, folder_history: Arc<FolderHistory>) -> Self {
+        self.folder_bandwidth = folder_bandwidth;
+        self.folder_history = folder_history;
+        self
+    }
+
+    /// Limit folders to what the client's token may reach.
+    pub fn with_token_scope(mut self, token_scope: TokenScope) -> Self {
+        self.token_scope = Some(token_scope);
+        self
+    }
+
+    /// Limit folders to those of the user the client logged in as.
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Let a session currently in `current_folder` into `target`, a folder
+    /// name or path, using `preferred_shell` if it is installed.
+    pub async fn grant(&self, target: &str, preferred_shell: Option<ShellType>, current_folder: &str) -> FshResult<FolderGrant> {
+        let folder = self.config.find_folder_by_name(target)
+            .or_else(|| self.config.find_folder_by_path(target))
+            .ok_or_else(|| FshError::FolderNotFound(target.to_string()))?;
+
+        // A honeypot only ever holds sessions that bound it directly
+        if folder.honeypot.enabled {
+            return Err(FshError::FolderNotFound(target.to_string()));
+        }
+        if !folder.allows_identity(&self.identity_names) {
+            return Err(FshError::PermissionDenied(format!("Client identity not allowed for folder '{}'", folder.name)));
+        }
+        if self.token_scope.as_ref().is_some_and(|scope| !scope.allows_folder(&folder.name))
+            || !self.config.users.allows(self.user.as_deref(), &folder.name) {
+            return Err(FshError::FolderNotFound(target.to_string()));
+        }
+        folder.validate()?;
+
+        // The session already counts towards its own folder's limit
+        if let Some(max_sessions) = folder.max_sessions.filter(|_| folder.name != current_folder) {
+            if self.sessions.folder_count(&folder.name).await >= max_sessions {
+                return Err(FshError::PermissionDenied(format!("Session limit reached for folder '{}'", folder.name)));
+            }
+        }
+
+        let mut folder = folder.clone();
+        if let Some(scope) = &self.token_scope {
+            scope.restrict_folder(&mut folder);
+        }
+        let mut folder_info = folder.to_folder_info();
+        if let Some(preferred_shell) = preferred_shell {
+            folder_info.shell_type = preferred_shell;
+        }
+        folder_info.shell_type = crate::sandbox::detect_shell(&folder_info.shell_type);
+
+        Ok(FolderGrant {
+            folder_info,
+            transfer_limits: self.transfer_limits(&folder),
+            history: self.history(&folder),
+            folder_config: folder,
+        })
+    }
+
+    /// Bandwidth limits for a session on `folder_config`.
+    pub fn transfer_limits(&self, folder_config: &FolderConfig) -> TransferLimits {
+        let mut limits = TransferLimits::new();
+        if let Some(kbps) = folder_config.transfer_rate_limit_kbps {
+            limits = limits.with_limiter(self.folder_bandwidth.limiter(&folder_config.name, kbps));
+        }
+        if let Some(kbps) = folder_config.session_transfer_rate_limit_kbps {
+            limits = limits.with_limiter(Arc::new(BandwidthLimiter::from_kbps(kbps)));
+        }
+        limits
+    }
+
+    /// Command history for a session on `folder_config`.
+    pub fn history(&self, folder_config: &FolderConfig) -> SessionHistory {
+        let history = SessionHistory::new(&folder_config.history);
+        if folder_config.history.shared {
+            history.with_folder(Arc::clone(&self.folder_history), &folder_config.name)
+        } else {
+            history
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_folder_grants() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::default();
+        config.folders.push(FolderConfig::new("docs".to_string(), temp_dir.path()));
+        config.folders.push(FolderConfig::new("secret".to_string(), temp_dir.path()).with_allowed_identity("ops".to_string()));
+        let mut honeypot = FolderConfig::new("admin".to_string(), temp_dir.path());
+        honeypot.honeypot.enabled = true;
+        config.folders.push(honeypot);
+        let access = FolderAccess::new(Arc::new(config), Vec::new(), SessionManager::new());
+
+        let grant = access.grant("docs", None, "code").await.unwrap();
+        assert_eq!(grant.folder_info.name, "docs");
+        assert_eq!(grant.folder_config.name, "docs");
+
+        assert!(matches!(access.grant("missing", None, "docs").await, Err(FshError::FolderNotFound(_))));
+        assert!(matches!(access.grant("admin", None, "docs").await, Err(FshError::FolderNotFound(_))));
+        assert!(matches!(access.grant("secret", None, "docs").await, Err(FshError::PermissionDenied(_))));
+    }
+}
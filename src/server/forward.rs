@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::protocol::{FshError, FshResult};
+
+/// Most forwarded connections a session may have open at once.
+pub const MAX_FORWARDS_PER_SESSION: usize = 32;
+
+/// Whether `host` names the loopback interface.
+pub fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Connect to `port` on a loopback `host`. Addresses `host` resolves to
+/// outside the loopback interface are never tried.
+pub async fn connect_loopback(host: &str, port: u16) -> FshResult<TcpStream> {
+    if !is_loopback_host(host) {
+        return Err(FshError::PermissionDenied(format!("Only loopback hosts can be forwarded to, not '{}'", host)));
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = tokio::net::lookup_host((host, port)).await
+        .map_err(|e| FshError::io(format!("Cannot resolve '{}'", host), e))?;
+
+    let mut last_error = None;
+    for addr in addrs.filter(|addr| addr.ip().is_loopback()) {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => FshError::io(format!("Failed to connect to {}:{}", host, port), e),
+        None => FshError::NetworkError(format!("'{}' does not resolve to a loopback address", host)),
+    })
+}
+
+#[derive(Debug)]
+struct ForwardChannel {
+    /// Data from the client; dropped once the client has sent EOF.
+    input: Option<mpsc::Sender<Vec<u8>>>,
+    task: AbortHandle,
+}
+
+/// Forwarded connections open in one session, keyed by the client's channel id.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardTable {
+    channels: Arc<Mutex<HashMap<u32, ForwardChannel>>>,
+}
+
+impl ForwardTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check that a channel with `channel_id` may be opened.
+    pub fn check_available(&self, channel_id: u32) -> FshResult<()> {
+        let channels = self.lock();
+        if channels.contains_key(&channel_id) {
+            return Err(FshError::ProtocolError(format!("Channel {} is already open", channel_id)));
+        }
+        if channels.len() >= MAX_FORWARDS_PER_SESSION {
+            return Err(FshError::ShellError(format!(
+                "Too many forwarded connections (limit {})", MAX_FORWARDS_PER_SESSION
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn insert(&self, channel_id: u32, input: mpsc::Sender<Vec<u8>>, task: AbortHandle) {
+        self.lock().insert(channel_id, ForwardChannel { input: Some(input), task });
+    }
+
+    pub fn remove(&self, channel_id: u32) {
+        self.lock().remove(&channel_id);
+    }
+
+    /// Where the client's data for `channel_id` goes, if the channel is open
+    /// and the client hasn't sent EOF.
+    pub fn input(&self, channel_id: u32) -> Option<mpsc::Sender<Vec<u8>>> {
+        self.lock().get(&channel_id).and_then(|channel| channel.input.clone())
+    }
+
+    /// The client sent EOF: the connection's write side is shut down once
+    /// queued data has been written.
+    pub fn close_input(&self, channel_id: u32) {
+        if let Some(channel) = self.lock().get_mut(&channel_id) {
+            channel.input = None;
+        }
+    }
+
+    pub fn close_all(&self) {
+        for (_, channel) in self.lock().drain() {
+            channel.task.abort();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, ForwardChannel>> {
+        self.channels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback_hosts() {
+        for host in ["localhost", "LOCALHOST", "127.0.0.1", "127.1.2.3", "::1", "[::1]"] {
+            assert!(is_loopback_host(host), "{}", host);
+        }
+        for host in ["example.com", "10.0.0.1", "0.0.0.0", "localhost.example.com", ""] {
+            assert!(!is_loopback_host(host), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_ids_are_unique() {
+        let forwards = ForwardTable::new();
+        let task = tokio::spawn(std::future::pending::<()>());
+        let (input, _receiver) = mpsc::channel(1);
+        forwards.insert(1, input, task.abort_handle());
+        assert!(forwards.check_available(1).is_err());
+        assert!(forwards.check_available(2).is_ok());
+
+        forwards.close_input(1);
+        assert!(forwards.input(1).is_none());
+        assert_eq!(forwards.len(), 1);
+
+        forwards.close_all();
+        assert!(forwards.is_empty());
+        assert!(task.await.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::server::ConnectionCounters;
+
+/// Answer to `GET /healthz` and `GET /readyz`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Listening, with a valid configuration and every folder available.
+    pub ready: bool,
+    /// Whether the server is accepting connections.
+    pub listening: bool,
+    /// Why the configuration is invalid, apart from its folders.
+    pub config_error: Option<String>,
+    pub folders: Vec<FolderHealth>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderHealth {
+    pub name: String,
+    pub available: bool,
+    /// Why the folder can't be served, e.g. its path has gone.
+    pub error: Option<String>,
+}
+
+/// Checks the server's health for the admin API's probes.
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    pub(crate) config: Arc<Config>,
+    pub(crate) counters: Arc<ConnectionCounters>,
+}
+
+impl HealthChecker {
+    pub async fn check(&self) -> HealthReport {
+        let listening = self.counters.is_accepting();
+        let config = Arc::clone(&self.config);
+        // Folder checks touch the file system, which may hang on a dead mount
+        let (config_error, folders) = tokio::task::spawn_blocking(move || check_config(&config))
+            .await
+            .unwrap_or_else(|e| (Some(format!("Health check failed: {}", e)), Vec::new()));
+        HealthReport {
+            ready: listening && config_error.is_none() && folders.iter().all(|folder| folder.available),
+            listening,
+            config_error,
+            folders,
+        }
+    }
+}
+
+fn check_config(config: &Config) -> (Option<String>, Vec<FolderHealth>) {
+    let mut without_folders = config.clone();
+    without_folders.folders.clear();
+    let config_error = without_folders.validate().err().map(|e| e.to_string());
+    let folders = config.folders.iter()
+        .map(|folder| {
+            let error = folder.validate().err().map(|e| e.to_string());
+            FolderHealth { name: folder.name.clone(), available: error.is_none(), error }
+        })
+        .collect();
+    (config_error, folders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FolderConfig;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_ready_needs_listener_and_folders() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = Config::default();
+        config.folders = vec![FolderConfig::new("test".to_string(), temp_dir.path())];
+        let checker = HealthChecker { config: Arc::new(config), counters: Arc::default() };
+
+        let report = checker.check().await;
+        assert!(!report.listening);
+        assert!(!report.ready);
+        assert!(report.config_error.is_none());
+        assert!(report.folders[0].available);
+
+        checker.counters.set_accepting(true);
+        assert!(checker.check().await.ready);
+
+        drop(temp_dir);
+        let report = checker.check().await;
+        assert!(!report.ready);
+        assert!(!report.folders[0].available);
+        assert!(report.folders[0].error.is_some());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::KeepaliveConfig;
+use tokio::time::{Duration, Instant};
+
+/// What the session loop should do when a heartbeat deadline passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    /// Send a ping to the client.
+    SendPing,
+    /// Too many pings went unanswered; the peer is considered dead.
+    Close,
+    /// Nothing is due yet.
+    Wait,
+}
+
+/// Application-level dead-peer detection for a session.
+///
+/// A ping is sent only after `interval` without receiving any frame, so
+/// clients that are idle but still answering never get disconnected by this.
+/// Each ping must be answered (by a pong or any other frame) within `timeout`;
+/// after `max_missed` consecutive unanswered pings the session is closed.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    max_missed: u32,
+    last_received: Instant,
+    ping_sent_at: Option<Instant>,
+    missed: u32,
+}
+
+impl Heartbeat {
+    pub fn new(config: &KeepaliveConfig) -> Self {
+        Self::with_durations(
+            Duration::from_secs(config.interval_seconds),
+            Duration::from_secs(config.timeout_seconds),
+            config.max_missed,
+        )
+    }
+
+    pub fn with_durations(interval: Duration, timeout: Duration, max_missed: u32) -> Self {
+        Self {
+            interval,
+            timeout,
+            max_missed: max_missed.max(1),
+            last_received: Instant::now(),
+            ping_sent_at: None,
+            missed: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// When the next ping or pong deadline falls, if keepalives are enabled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        Some(match self.ping_sent_at {
+            Some(sent_at) => sent_at + self.timeout,
+            None => self.last_received + self.interval,
+        })
+    }
+
+    /// Any frame from the client proves it is alive.
+    pub fn on_received(&mut self) {
+        self.last_received = Instant::now();
+        self.ping_sent_at = None;
+        self.missed = 0;
+    }
+
+    /// Advance the state machine; callers must send a ping on `SendPing`.
+    pub fn poll(&mut self, now: Instant) -> HeartbeatAction {
+        match self.next_deadline() {
+            Some(deadline) if now >= deadline => {}
+            _ => return HeartbeatAction::Wait,
+        }
+
+        if self.ping_sent_at.is_some() {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return HeartbeatAction::Close;
+            }
+        }
+
+        self.ping_sent_at = Some(now);
+        HeartbeatAction::SendPing
+    }
+
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_closes_after_missed_pings() {
+        let mut heartbeat = Heartbeat::with_durations(Duration::from_secs(30), Duration::from_secs(10), 2);
+        let start = Instant::now();
+
+        assert_eq!(heartbeat.poll(start + Duration::from_secs(29)), HeartbeatAction::Wait);
+        assert_eq!(heartbeat.poll(start + Duration::from_secs(30)), HeartbeatAction::SendPing);
+        assert_eq!(heartbeat.poll(start + Duration::from_secs(35)), HeartbeatAction::Wait);
+        assert_eq!(heartbeat.poll(start + Duration::from_secs(41)), HeartbeatAction::SendPing);
+        assert_eq!(heartbeat.missed(), 1);
+        assert_eq!(heartbeat.poll(start + Duration::from_secs(52)), HeartbeatAction::Close);
+    }
+
+    #[test]
+    fn test_heartbeat_reset_by_traffic() {
+        let mut heartbeat = Heartbeat::with_durations(Duration::from_millis(50), Duration::from_millis(20), 1);
+        let start = Instant::now();
+
+        assert_eq!(heartbeat.poll(start + Duration::from_millis(60)), HeartbeatAction::SendPing);
+        heartbeat.on_received();
+        assert_eq!(heartbeat.missed(), 0);
+        assert!(heartbeat.next_deadline().unwrap() >= start + Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_heartbeat_disabled() {
+        let mut heartbeat = Heartbeat::with_durations(Duration::ZERO, Duration::from_secs(10), 3);
+        assert!(heartbeat.next_deadline().is_none());
+        assert_eq!(heartbeat.poll(Instant::now() + Duration::from_secs(3600)), HeartbeatAction::Wait);
+    }
+}
@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::message::HistoryEntry;
+
+/// How many commands a folder remembers for its clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Commands kept per session, and per folder when shared (0 = keep none).
+    pub max_entries: usize,
+    /// Keep one history for every session on the folder, so a client that
+    /// reconnects, from this machine or another, sees what was run before.
+    pub shared: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            shared: false,
+        }
+    }
+}
+
+/// Append `entry`, dropping the oldest entries beyond `max_entries`.
+fn push_bounded(entries: &mut VecDeque<HistoryEntry>, entry: HistoryEntry, max_entries: usize) {
+    entries.push_back(entry);
+    while entries.len() > max_entries {
+        entries.pop_front();
+    }
+}
+
+/// The newest `limit` entries, oldest first.
+fn newest(entries: &VecDeque<HistoryEntry>, limit: Option<usize>) -> Vec<HistoryEntry> {
+    let skip = limit.map_or(0, |limit| entries.len().saturating_sub(limit));
+    entries.iter().skip(skip).cloned().collect()
+}
+
+/// Histories shared by every session bound to the same folder. They live as
+/// long as the server.
+#[derive(Debug, Default)]
+pub struct FolderHistory {
+    folders: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+impl FolderHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, folder: &str, entry: HistoryEntry, max_entries: usize) {
+        let mut folders = self.folders.lock().unwrap_or_else(|e| e.into_inner());
+        push_bounded(folders.entry(folder.to_string()).or_default(), entry, max_entries);
+    }
+
+    pub fn entries(&self, folder: &str, limit: Option<usize>) -> Vec<HistoryEntry> {
+        let folders = self.folders.lock().unwrap_or_else(|e| e.into_inner());
+        folders.get(folder).map(|entries| newest(entries, limit)).unwrap_or_default()
+    }
+}
+
+/// The commands run in one session, also recorded in its folder's shared
+/// history when the folder has one.
+#[derive(Debug, Clone)]
+pub struct SessionHistory {
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    shared: Option<(Arc<FolderHistory>, String)>,
+    max_entries: usize,
+}
+
+impl SessionHistory {
+    pub fn new(config: &HistoryConfig) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            shared: None,
+            max_entries: config.max_entries,
+        }
+    }
+
+    /// Share history with other sessions on `folder` through `history`.
+    pub fn with_folder(mut self, history: Arc<FolderHistory>, folder: impl Into<String>) -> Self {
+        self.shared = Some((history, folder.into()));
+        self
+    }
+
+    /// Remember that `command` ran in `working_directory` and exited with
+    /// `exit_code`.
+    pub fn record(&self, session_id: &str, command: &str, working_directory: &str, exit_code: i32) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let entry = HistoryEntry {
+            command: command.to_string(),
+            working_directory: working_directory.to_string(),
+            exit_code,
+            session_id: session_id.to_string(),
+            ran_at: chrono::Utc::now(),
+        };
+        if let Some((history, folder)) = &self.shared {
+            history.record(folder, entry.clone(), self.max_entries);
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        push_bounded(&mut entries, entry, self.max_entries);
+    }
+
+    /// The commands run in this session alone, oldest first.
+    pub fn session_entries(&self) -> Vec<HistoryEntry> {
+        newest(&self.entries.lock().unwrap_or_else(|e| e.into_inner()), None)
+    }
+
+    /// Start from the commands a resumed session had run. The folder's
+    /// shared history is left alone.
+    pub fn restore(&self, entries: Vec<HistoryEntry>) {
+        let mut own = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        for entry in entries {
+            push_bounded(&mut own, entry, self.max_entries);
+        }
+    }
+
+    /// The newest `limit` commands, oldest first: the folder's when it is
+    /// shared, otherwise this session's.
+    pub fn entries(&self, limit: Option<usize>) -> Vec<HistoryEntry> {
+        match &self.shared {
+            Some((history, folder)) => history.entries(folder, limit),
+            None => newest(&self.entries.lock().unwrap_or_else(|e| e.into_inner()), limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(entries: Vec<HistoryEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.command).collect()
+    }
+
+    #[test]
+    fn test_session_history() {
+        let history = SessionHistory::new(&HistoryConfig { max_entries: 2, shared: false });
+        for command in ["ls", "pwd", "git status"] {
+            history.record("one", command, "/", 0);
+        }
+        assert_eq!(commands(history.entries(None)), vec!["pwd", "git status"]);
+        assert_eq!(commands(history.entries(Some(1))), vec!["git status"]);
+
+        let disabled = SessionHistory::new(&HistoryConfig { max_entries: 0, shared: false });
+        disabled.record("one", "ls", "/", 0);
+        assert!(disabled.entries(None).is_empty());
+    }
+
+    #[test]
+    fn test_shared_history() {
+        let config = HistoryConfig { max_entries: 10, shared: true };
+        let folders = Arc::new(FolderHistory::new());
+        let first = SessionHistory::new(&config).with_folder(Arc::clone(&folders), "docs");
+        let second = SessionHistory::new(&config).with_folder(Arc::clone(&folders), "docs");
+        let other = SessionHistory::new(&config).with_folder(Arc::clone(&folders), "code");
+
+        first.record("first", "ls", "/", 0);
+        second.record("second", "make", "/", 2);
+        other.record("other", "cargo test", "/", 0);
+
+        assert_eq!(commands(first.entries(None)), vec!["ls", "make"]);
+        assert_eq!(commands(other.entries(None)), vec!["cargo test"]);
+
+        // A later session on the folder starts with what was run before
+        drop((first, second));
+        let reconnected = SessionHistory::new(&config).with_folder(folders, "docs");
+        assert_eq!(commands(reconnected.entries(Some(1))), vec!["make"]);
+    }
+}
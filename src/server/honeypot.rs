@@ -0,0 +1,87 @@
+use tracing::{error, warn, Instrument};
+
+use crate::protocol::FshMessage;
+use crate::security::ClientAudit;
+
+/// Reports everything a client does in a honeypot folder.
+#[derive(Debug, Clone)]
+pub struct HoneypotMonitor {
+    folder: String,
+    session_id: String,
+    client_addr: String,
+    audit: Option<ClientAudit>,
+    webhook_url: Option<String>,
+    http: reqwest::Client,
+}
+
+impl HoneypotMonitor {
+    pub fn new(
+        folder: &str,
+        session_id: &str,
+        client_addr: &str,
+        audit: Option<ClientAudit>,
+        webhook_url: Option<String>,
+    ) -> Self {
+        Self {
+            folder: folder.to_string(),
+            session_id: session_id.to_string(),
+            client_addr: client_addr.to_string(),
+            audit,
+            webhook_url,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Record a message received from the client. Keepalives are ignored.
+    pub async fn record_message(&self, message: &FshMessage) {
+        let detail = match message {
+            FshMessage::Ping | FshMessage::Pong => return,
+            FshMessage::Command(cmd) => std::iter::once(cmd.command.as_str())
+                .chain(cmd.args.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" "),
+            FshMessage::FileRead(read) => read.file_path.clone(),
+            FshMessage::FileWrite(write) => write.file_path.clone(),
+            FshMessage::FileDelete(delete) => delete.file_path.clone(),
+            FshMessage::FileMkdir(mkdir) => mkdir.path.clone(),
+            FshMessage::FileStat(stat) => stat.path.clone(),
+            FshMessage::FileList(list) => list.path.clone(),
+            FshMessage::FileTail(tail) => tail.file_path.clone(),
+            FshMessage::PortForwardOpen(open) => format!("{}:{}", open.host, open.port),
+            _ => String::new(),
+        };
+        self.record(message.message_type(), &detail).await;
+    }
+
+    pub async fn record(&self, action: &str, detail: &str) {
+        error!("Honeypot '{}' {} from {} (session {}): {}",
+               self.folder, action, self.client_addr, self.session_id, detail);
+
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.logger.log_honeypot_activity(
+                audit.source_ip, self.session_id.clone(), &self.folder, action, detail,
+            ).await {
+                warn!("Failed to audit honeypot activity in session {}: {}", self.session_id, e);
+            }
+        }
+
+        if let Some(url) = &self.webhook_url {
+            let body = serde_json::json!({
+                "event": "honeypot_activity",
+                "folder": self.folder,
+                "session_id": self.session_id,
+                "client_addr": self.client_addr,
+                "action": action,
+                "detail": detail,
+                "timestamp": chrono::Utc::now(),
+            });
+            let post = self.http.post(url).json(&body).send();
+            let session_id = self.session_id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = post.await.and_then(|response| response.error_for_status()) {
+                    warn!("Honeypot alert webhook failed for session {}: {}", session_id, e);
+                }
+            }.in_current_span());
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::security::AuthManager;
+use crate::server::constant_time_eq;
+use tokio::time::Duration;
+
+/// Wrong tokens a locked session accepts before it is closed.
+pub const MAX_UNLOCK_ATTEMPTS: u32 = 3;
+
+/// What became of an attempt to unlock a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnlockOutcome {
+    Unlocked,
+    /// The token was wrong; the session stays locked.
+    Refused { attempts_left: u32 },
+    /// The token was wrong once too often; the session should be closed.
+    TooManyAttempts,
+}
+
+/// Locks a session that sat idle for `after`, so that someone else at a
+/// shared workstation can't use it until the token it authenticated with
+/// is entered again. Only the token's hash is kept.
+#[derive(Debug, Clone)]
+pub struct IdleLock {
+    after: Duration,
+    token_hash: String,
+    locked: bool,
+    failed_attempts: u32,
+}
+
+impl IdleLock {
+    pub fn new(after: Duration, token: &str) -> Self {
+        Self {
+            after,
+            token_hash: AuthManager::hash_token(token),
+            locked: false,
+            failed_attempts: 0,
+        }
+    }
+
+    /// Idle time after which the session locks.
+    pub fn after(&self) -> Duration {
+        self.after
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Whether a session idle for `idle` should lock now.
+    pub fn is_due(&self, idle: Duration) -> bool {
+        !self.locked && idle >= self.after
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+        self.failed_attempts = 0;
+    }
+
+    pub fn unlock(&mut self, token: &str) -> UnlockOutcome {
+        let hash = AuthManager::hash_token(token);
+        if constant_time_eq(hash.as_bytes(), self.token_hash.as_bytes()) {
+            self.locked = false;
+            self.failed_attempts = 0;
+            return UnlockOutcome::Unlocked;
+        }
+
+        self.failed_attempts += 1;
+        if self.failed_attempts >= MAX_UNLOCK_ATTEMPTS {
+            UnlockOutcome::TooManyAttempts
+        } else {
+            UnlockOutcome::Refused { attempts_left: MAX_UNLOCK_ATTEMPTS - self.failed_attempts }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_lock_unlock() {
+        let mut lock = IdleLock::new(Duration::from_secs(60), "secret");
+        assert!(!lock.is_due(Duration::from_secs(59)));
+        assert!(lock.is_due(Duration::from_secs(60)));
+
+        lock.lock();
+        assert!(lock.is_locked());
+        assert!(!lock.is_due(Duration::from_secs(120)));
+        assert_eq!(lock.unlock("wrong"), UnlockOutcome::Refused { attempts_left: 2 });
+        assert_eq!(lock.unlock("secret"), UnlockOutcome::Unlocked);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_idle_lock_too_many_attempts() {
+        let mut lock = IdleLock::new(Duration::from_secs(60), "secret");
+        lock.lock();
+        assert_eq!(lock.unlock("a"), UnlockOutcome::Refused { attempts_left: 2 });
+        assert_eq!(lock.unlock("b"), UnlockOutcome::Refused { attempts_left: 1 });
+        assert_eq!(lock.unlock("c"), UnlockOutcome::TooManyAttempts);
+        assert!(lock.is_locked());
+    }
+}
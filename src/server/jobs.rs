@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::message::JobInfo;
+use crate::protocol::{FshError, FshResult};
+use crate::sandbox::CommandKiller;
+
+/// Most background jobs a session may run at once.
+pub const MAX_JOBS_PER_SESSION: usize = 16;
+
+#[derive(Debug)]
+struct Job {
+    command: String,
+    started_at: chrono::DateTime<chrono::Utc>,
+    killer: Option<CommandKiller>,
+}
+
+/// Background jobs running in one session, keyed by the client's job id.
+#[derive(Debug, Clone, Default)]
+pub struct JobTable {
+    jobs: Arc<Mutex<HashMap<u32, Job>>>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check that a job with `job_id` may be started.
+    pub fn check_available(&self, job_id: u32) -> FshResult<()> {
+        let jobs = self.lock();
+        if jobs.contains_key(&job_id) {
+            return Err(FshError::ShellError(format!("Job %{} is already running", job_id)));
+        }
+        if jobs.len() >= MAX_JOBS_PER_SESSION {
+            return Err(FshError::ShellError(format!(
+                "Too many background jobs (limit {})", MAX_JOBS_PER_SESSION
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn insert(&self, job_id: u32, command: String, killer: Option<CommandKiller>) {
+        self.lock().insert(job_id, Job {
+            command,
+            started_at: chrono::Utc::now(),
+            killer,
+        });
+    }
+
+    pub fn remove(&self, job_id: u32) {
+        self.lock().remove(&job_id);
+    }
+
+    /// Running jobs ordered by id.
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<JobInfo> = self.lock().iter()
+            .map(|(&job_id, job)| JobInfo {
+                job_id,
+                command: job.command.clone(),
+                started_at: job.started_at,
+            })
+            .collect();
+        jobs.sort_by_key(|job| job.job_id);
+        jobs
+    }
+
+    /// Ask a job to stop. It stays listed until it has exited.
+    pub fn kill(&self, job_id: u32) -> FshResult<()> {
+        let mut jobs = self.lock();
+        let job = jobs.get_mut(&job_id)
+            .ok_or_else(|| FshError::ShellError(format!("No such job: %{}", job_id)))?;
+        if let Some(killer) = job.killer.take() {
+            killer.kill();
+        }
+        Ok(())
+    }
+
+    pub fn kill_all(&self) {
+        for job in self.lock().values_mut() {
+            if let Some(killer) = job.killer.take() {
+                killer.kill();
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_ids_are_unique_and_limited() {
+        let jobs = JobTable::new();
+        jobs.insert(1, "make".to_string(), None);
+        assert!(jobs.check_available(1).is_err());
+        assert!(jobs.check_available(2).is_ok());
+
+        for job_id in 2..=MAX_JOBS_PER_SESSION as u32 {
+            jobs.insert(job_id, "sleep 1".to_string(), None);
+        }
+        assert!(jobs.check_available(100).is_err());
+
+        jobs.remove(1);
+        assert!(jobs.check_available(1).is_ok());
+        assert_eq!(jobs.list().first().map(|job| job.job_id), Some(2));
+    }
+
+    #[test]
+    fn test_kill_unknown_job() {
+        let jobs = JobTable::new();
+        assert!(jobs.kill(3).is_err());
+
+        jobs.insert(3, "tail -f log".to_string(), None);
+        assert!(jobs.kill(3).is_ok());
+        assert_eq!(jobs.list().len(), 1);
+    }
+}
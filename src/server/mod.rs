@@ -1,116 +1,685 @@
+pub mod admin;
+pub mod approval;
+pub mod bandwidth;
+pub mod builder;
+pub mod collaboration;
+pub mod command_limits;
 pub mod connection;
+pub mod discovery;
+pub mod events;
+pub mod folder_switch;
+pub mod forward;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
+pub mod honeypot;
+pub mod idle_lock;
+pub mod jobs;
+pub mod notifications;
+pub mod observer;
+pub mod operations;
+pub mod output_cap;
+pub mod plugin;
+pub mod relay;
+pub mod schedule;
 pub mod session;
-
+pub mod session_manager;
+pub mod session_state;
+pub mod stats;
+pub mod systemd;
+pub mod watch;
+pub mod webdav;
+
+pub use admin::*;
+pub use approval::*;
+pub use bandwidth::*;
+pub use builder::*;
+pub use collaboration::*;
+pub use command_limits::*;
 pub use connection::*;
+pub use discovery::*;
+pub use events::*;
+pub use folder_switch::*;
+pub use forward::*;
+pub use health::*;
+pub use heartbeat::*;
+pub use history::*;
+pub use honeypot::*;
+pub use idle_lock::*;
+pub use jobs::*;
+pub use notifications::*;
+pub use observer::*;
+pub use operations::*;
+pub use output_cap::*;
+pub use plugin::*;
+pub use relay::*;
+pub use schedule::*;
 pub use session::*;
-
-use crate::config::Config;
-use crate::protocol::{FshError, FshResult};
+pub use session_manager::*;
+pub use session_state::*;
+pub use stats::*;
+pub use systemd::*;
+pub use watch::*;
+pub use webdav::*;
+
+use crate::config::{Config, KeepaliveConfig};
+use crate::protocol::{FshError, FshListener, FshResult, FshStream, TcpListeners};
+use crate::security::{
+    server_tls_config, AuditLogger, AuditSink, AuthLockout, AuthManager, Authenticator, CertIdentity, DlpScanner,
+    MalwareScanner, NoiseServer, RateLimits,
+};
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
-use tracing::{info, error, warn};
-use std::collections::HashMap;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, error, warn, info_span, Instrument};
+
+/// Turn on OS-level keepalive probes so half-open sockets are eventually reset
+/// even if the application-level heartbeat is disabled.
+fn configure_tcp_keepalive(stream: &tokio::net::TcpStream, keepalive: &KeepaliveConfig) -> std::io::Result<()> {
+    if !keepalive.tcp_keepalive {
+        return Ok(());
+    }
+
+    let params = socket2::TcpKeepalive::new()
+        .with_time(std::time::Duration::from_secs(keepalive.tcp_keepalive_idle_seconds));
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let params = params.with_interval(std::time::Duration::from_secs(keepalive.tcp_keepalive_interval_seconds));
+
+    socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+}
 
 #[derive(Debug)]
 pub struct FshServer {
     config: Arc<Config>,
-    sessions: Arc<RwLock<HashMap<String, Arc<Session>>>>,
-    listener: Option<TcpListener>,
+    sessions: SessionManager,
+    listener: Option<Box<dyn FshListener>>,
+    connection_permits: Arc<Semaphore>,
+    ip_connections: Arc<IpConnections>,
+    /// Connections per client address that haven't finished the handshake.
+    handshakes: Arc<IpConnections>,
+    counters: Arc<ConnectionCounters>,
+    folder_bandwidth: Arc<FolderBandwidth>,
+    folder_history: Arc<FolderHistory>,
+    command_limiter: Arc<CommandLimiter>,
+    operations: Arc<OperationLog>,
+    audit_logger: Arc<AuditLogger>,
+    approvals: Arc<ApprovalQueue>,
+    rate_limits: Arc<RateLimits>,
+    lockout: Arc<AuthLockout>,
+    auth_manager: Arc<RwLock<AuthManager>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    usage: UsageTracker,
+    events: EventBus,
+    plugins: Plugins,
+    malware_scanner: Option<Arc<MalwareScanner>>,
+    dlp: Option<Arc<DlpScanner>>,
+    notifier: Option<Arc<Notifier>>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    noise: Option<Arc<NoiseServer>>,
+    session_store: Option<Arc<SessionStore>>,
+    shutdown: CancellationToken,
 }
 
 impl FshServer {
     pub fn new(config: Config) -> FshResult<Self> {
+        Self::from_parts(config, None, Vec::new())
+    }
+
+    pub(crate) fn from_parts(
+        config: Config,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        audit_sinks: Vec<Arc<dyn AuditSink>>,
+    ) -> FshResult<Self> {
+        let mut config = config;
+        config.users.provision_homes(&mut config.folders)?;
         config.validate()?;
+        let notifier = Notifier::new(&config.notifications);
+        let notifier = notifier.is_enabled().then(|| Arc::new(notifier));
+        let audit_logger = audit_sinks.into_iter()
+            .chain(notifier.clone().map(|notifier| notifier as Arc<dyn AuditSink>))
+            .fold(AuditLogger::new(&config.security)?, AuditLogger::with_sink);
+        let audit_logger = Arc::new(audit_logger);
+        let approvals = Arc::new(ApprovalQueue::new(&config.approval));
+        let rate_limits = Arc::new(RateLimits::new(&config.security.rate_limits));
+        let lockout = Arc::new(AuthLockout::new(&config.security.lockout));
+        let auth_manager = Arc::new(RwLock::new(AuthManager::new(&config.security)?));
+        let security = &config.security;
+        let no_logins = security.require_authentication
+            && authenticator.is_none()
+            && security.token_store.is_none()
+            && security.tokens.is_empty()
+            && !security.development_mode
+            && config.server.tls.client_ca_file.is_none();
+        if no_logins {
+            warn!("No client tokens are provisioned, so every login will be refused; run `fsh-server init` or add [[security.tokens]]");
+        }
+        let tls = if config.server.tls.enabled {
+            Some(server_tls_config(&config.server.tls)?)
+        } else {
+            None
+        };
+        let noise = if config.server.noise.enabled {
+            let noise = NoiseServer::from_config(&config.server.noise)?;
+            info!("Noise public key: {}", noise.public_key());
+            Some(Arc::new(noise))
+        } else {
+            None
+        };
+
+        let usage = UsageTracker::new(&config.stats);
+        let command_limiter = Arc::new(CommandLimiter::new(
+            config.server.max_running_commands, config.server.max_running_commands_per_session,
+        ));
+        let session_store = config.session_state.directory.as_ref()
+            .map(|directory| SessionStore::open(directory, config.session_state.retention_hours).map(Arc::new))
+            .transpose()?;
+        let malware_scanner = MalwareScanner::new(&config.malware_scan)?;
+        let malware_scanner = malware_scanner.is_enabled().then(|| Arc::new(malware_scanner));
+        let dlp = DlpScanner::new(&config.dlp)?;
+        let dlp = dlp.is_enabled().then(|| Arc::new(dlp));
 
         Ok(Self {
+            connection_permits: Arc::new(Semaphore::new(config.server.max_connections)),
+            ip_connections: Arc::new(IpConnections::default()),
+            handshakes: Arc::new(IpConnections::default()),
             config: Arc::new(config),
-            sessions: Arc::new(RwLock::new(HashMap::new())),
+            sessions: SessionManager::new(),
             listener: None,
+            counters: Arc::new(ConnectionCounters::default()),
+            folder_bandwidth: Arc::new(FolderBandwidth::new()),
+            folder_history: Arc::new(FolderHistory::new()),
+            command_limiter,
+            operations: Arc::new(OperationLog::new()),
+            audit_logger,
+            approvals,
+            rate_limits,
+            lockout,
+            auth_manager,
+            authenticator,
+            usage,
+            events: EventBus::default(),
+            plugins: Plugins::default(),
+            malware_scanner,
+            dlp,
+            notifier,
+            tls,
+            noise,
+            session_store,
+            shutdown: CancellationToken::new(),
         })
     }
 
     pub async fn start(&mut self) -> FshResult<()> {
-        let bind_addr = format!("{}:{}", self.config.server.host, self.config.server.port);
+        self.bind().await?;
+        self.serve().await
+    }
+
+    /// Start serving in the background, returning a handle that stops the
+    /// server when asked rather than on a signal.
+    pub async fn spawn(mut self) -> FshResult<ServerHandle> {
+        self.bind().await?;
+        let local_addrs = self.local_addrs();
+        let stats = self.stats_collector();
+        let events = self.events.clone();
+        let shutdown = self.shutdown_token();
+
+        let task = tokio::spawn(async move {
+            self.serve().await?;
+            self.stop().await
+        }.in_current_span());
+
+        Ok(ServerHandle::new(local_addrs, stats, events, shutdown, task))
+    }
+
+    /// A token that, once cancelled from any task, makes `start` stop
+    /// accepting connections and return. `stop` cancels it too.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Run `task` in the background until it finishes or the server stops.
+    fn spawn_until_shutdown(&self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            shutdown.run_until_cancelled(task).await;
+        }.in_current_span());
+    }
+
+    /// Receive the server's events, from sessions starting to shutdown.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// The address the server is listening on, once it has started on a
+    /// socket.
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.listener.as_ref().and_then(|listener| listener.local_addr())
+    }
+
+    /// Every address the server is listening on.
+    pub fn local_addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.listener.as_ref().map(|listener| listener.local_addrs()).unwrap_or_default()
+    }
+
+    /// Listen on the configured addresses, unless a listener was supplied.
+    async fn bind(&mut self) -> FshResult<Option<std::net::SocketAddr>> {
+        let tls = if self.tls.is_some() { " (TLS)" } else { "" };
+        match &self.listener {
+            Some(listener) => info!("FSH server accepting connections from {:?}{}", listener, tls),
+            None if self.config.agent.enabled => {
+                info!("FSH server accepting connections through relay {}{}", self.config.agent.relay, tls);
+                let folders = self.config.folders.iter().map(|folder| folder.name.clone()).collect();
+                self.listener = Some(Box::new(RelayListener::start(&self.config.agent, folders)));
+            }
+            None => {
+                let listener = match activated_listeners()? {
+                    Some(listener) => {
+                        info!("Using {} socket(s) passed by systemd", listener.local_addrs().len());
+                        listener
+                    }
+                    None => {
+                        let bind_addrs = self.config.server.bind_addresses();
+                        info!("Starting FSH server on {}", bind_addrs.join(", "));
+                        TcpListeners::bind(&bind_addrs).await?
+                    }
+                };
+                for local_addr in listener.local_addrs() {
+                    info!("FSH server listening on {}{}", local_addr, tls);
+                }
+                self.listener = Some(Box::new(listener));
+            }
+        }
+        self.counters.mark_started();
+        let listening = self.local_addrs().iter().map(|addr| addr.to_string()).collect::<Vec<_>>();
+        notify_ready(&format!("Listening on {}", listening.join(", ")));
+        Ok(self.local_addr())
+    }
+
+    /// Accept connections on the bound listener until it closes or the server
+    /// is shut down.
+    async fn serve(&mut self) -> FshResult<()> {
+        let scheduler = Scheduler::new(Arc::clone(&self.config), Arc::clone(&self.audit_logger));
+        if scheduler.has_tasks() {
+            self.spawn_until_shutdown(scheduler.clone().run());
+        }
+
+        let watcher = FolderWatcher::new(Arc::clone(&self.config), self.sessions.clone());
+        if watcher.has_rules() {
+            self.spawn_until_shutdown(watcher.run());
+        }
+
+        if self.config.admin.enabled {
+            let admin_listener = bind_admin_api(&self.config.admin).await?;
+            let config = Arc::clone(&self.config);
+            let approvals = Arc::clone(&self.approvals);
+            let lockout = Arc::clone(&self.lockout);
+            let auth_manager = Arc::clone(&self.auth_manager);
+            let sessions = self.sessions.clone();
+            let stats = self.stats_collector();
+            let health = HealthChecker { config: Arc::clone(&self.config), counters: Arc::clone(&self.counters) };
+            self.spawn_until_shutdown(async move {
+                if let Err(e) = serve_admin_api(
+                    admin_listener, config, approvals, lockout, auth_manager, sessions, stats, health, scheduler,
+                ).await {
+                    error!("{}", e);
+                }
+            });
+        }
+
+        if self.config.webdav.enabled {
+            let webdav_listener = bind_webdav(&self.config.webdav).await?;
+            let serving = serve_webdav(
+                webdav_listener,
+                Arc::clone(&self.config),
+                Arc::clone(&self.auth_manager),
+                Arc::clone(&self.lockout),
+                self.malware_scanner.clone(),
+                self.dlp.clone(),
+            );
+            self.spawn_until_shutdown(async move {
+                if let Err(e) = serving.await {
+                    error!("{}", e);
+                }
+            });
+        }
+
+        if self.config.relay.enabled {
+            let relay_listener = bind_relay(&self.config.relay).await?;
+            let config = Arc::clone(&self.config);
+            self.spawn_until_shutdown(async move {
+                if let Err(e) = serve_relay(relay_listener, &config.relay).await {
+                    error!("{}", e);
+                }
+            });
+        }
+
+        let _watchdog = Watchdog::start();
+
+        // Withdrawn when serving stops
+        let _advertisement = match (self.config.discovery.enabled, self.local_addr()) {
+            (true, Some(local_addr)) => MdnsAdvertisement::start(
+                &self.config.discovery, &self.config.folders, local_addr.port(), self.tls.is_some(),
+            ).map_err(|e| warn!("Not advertising over mDNS: {}", e)).ok(),
+            _ => None,
+        };
+
+        // Forget rate limit history once it falls out of the window, and end
+        // sessions whose token has expired or that have sat idle too long
+        let rate_limits = Arc::clone(&self.rate_limits);
+        let lockout = Arc::clone(&self.lockout);
+        let auth_manager = Arc::clone(&self.auth_manager);
+        let sessions = self.sessions.clone();
+        let window = std::time::Duration::from_secs(self.config.security.rate_limits.window_seconds);
+        self.spawn_until_shutdown(async move {
+            let mut interval = tokio::time::interval(window);
+            loop {
+                interval.tick().await;
+                rate_limits.cleanup_expired().await;
+                lockout.clean_expired();
+                let removed = AuthManager::refresh_store_tokens(&auth_manager).await;
+                if !removed.is_empty() {
+                    sessions.revoke_tokens(&removed, "Token removed from store").await;
+                }
+                let expired = auth_manager.write().await.remove_expired_tokens();
+                if !expired.is_empty() {
+                    sessions.revoke_tokens(&expired, "Token expired").await;
+                }
+                sessions.remove_expired().await;
+            }
+        });
 
-        info!("Starting FSH server on {}", bind_addr);
+        if let Some(notifier) = &self.notifier {
+            let notifier = Arc::clone(notifier);
+            let events = self.events.subscribe();
+            self.spawn_until_shutdown(async move { notifier.forward(events).await });
+        }
 
-        let listener = TcpListener::bind(&bind_addr).await
-            .map_err(|e| FshError::NetworkError(format!("Failed to bind to {}: {}", bind_addr, e)))?;
+        if self.config.stats.file.is_some() {
+            let usage = self.usage.clone();
+            let period = std::time::Duration::from_secs(self.config.stats.persist_interval_seconds);
+            self.spawn_until_shutdown(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = usage.persist() {
+                        warn!("Failed to save usage statistics: {}", e);
+                    }
+                }
+            });
+        }
 
-        info!("FSH server listening on {}", bind_addr);
-        self.listener = Some(listener);
+        if self.config.stats.resource_sample_seconds > 0 {
+            let sessions = self.sessions.clone();
+            let period = std::time::Duration::from_secs(self.config.stats.resource_sample_seconds);
+            self.spawn_until_shutdown(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    sessions.sample_resources().await;
+                }
+            });
+        }
 
         // Main server loop
+        self.counters.set_accepting(true);
         while let Some(ref listener) = self.listener {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
+            let accepted = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => {
+                    info!("Shutting down, no longer accepting connections");
+                    break;
+                }
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok(None) => {
+                    info!("Listener closed, no longer accepting connections");
+                    break;
+                }
+                Ok(Some((stream, addr))) => {
                     info!("New connection from {}", addr);
 
-                    // Check connection limit
-                    let current_connections = self.sessions.read().await.len();
-                    if current_connections >= self.config.server.max_connections {
-                        warn!("Connection limit reached, rejecting connection from {}", addr);
-                        drop(stream);
-                        continue;
-                    }
+                    let client_ip = addr.parse::<std::net::SocketAddr>().ok().map(|addr| addr.ip());
+
+                    // One client address can't take up the whole connection pool
+                    let ip_connection = match (client_ip, self.config.server.max_connections_per_ip) {
+                        (Some(ip), Some(limit)) => match self.ip_connections.try_open(ip, limit) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                self.counters.record_rejected();
+                                warn!("Per-address connection limit reached, rejecting connection from {}", addr);
+                                self.audit_rejected_connection(ip, &format!("{} connections already open from this address", limit));
+                                drop(stream);
+                                continue;
+                            }
+                        },
+                        _ => None,
+                    };
+
+                    // Clients that connect and go quiet can't tie up the server from one address
+                    let max_pending = self.config.server.handshake.max_pending_per_ip;
+                    let handshake = match client_ip {
+                        Some(ip) => match self.handshakes.try_open(ip, max_pending) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                self.counters.record_rejected();
+                                warn!("Too many handshakes in progress, rejecting connection from {}", addr);
+                                self.audit_rejected_connection(ip, &format!("{} handshakes already in progress from this address", max_pending));
+                                drop(stream);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    // Each connection holds a permit from accept until its session ends
+                    let permit = match Arc::clone(&self.connection_permits).try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            self.counters.record_rejected();
+                            warn!("Connection limit reached, rejecting connection from {}", addr);
+                            if let Some(ip) = client_ip {
+                                self.audit_rejected_connection(ip, "server connection limit reached");
+                            }
+                            drop(stream);
+                            continue;
+                        }
+                    };
+                    let max_connections = self.config.server.max_connections;
+                    self.counters.record_accepted(max_connections - self.connection_permits.available_permits());
 
                     // Handle connection
                     let config = Arc::clone(&self.config);
-                    let sessions = Arc::clone(&self.sessions);
-
+                    let sessions = self.sessions.clone();
+                    let folder_bandwidth = Arc::clone(&self.folder_bandwidth);
+                    let folder_history = Arc::clone(&self.folder_history);
+                    let command_limiter = Arc::clone(&self.command_limiter);
+                    let operations = Arc::clone(&self.operations);
+                    let audit_logger = Arc::clone(&self.audit_logger);
+                    let approvals = Arc::clone(&self.approvals);
+                    let rate_limits = Arc::clone(&self.rate_limits);
+                    let lockout = Arc::clone(&self.lockout);
+                    let auth_manager = Arc::clone(&self.auth_manager);
+                    let authenticator = self.authenticator.clone();
+                    let usage = self.usage.clone();
+                    let counters = Arc::clone(&self.counters);
+                    let events = self.events.clone();
+                    let plugins = self.plugins.clone();
+                    let malware_scanner = self.malware_scanner.clone();
+                    let dlp = self.dlp.clone();
+                    let tls = self.tls.clone();
+                    let noise = self.noise.clone();
+                    let session_store = self.session_store.clone();
+
+                    let client_ip = client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| addr.clone());
+                    let span = info_span!("connection", client_ip = %client_ip);
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(stream, addr.to_string(), config, sessions).await {
+                        if let Err(e) = Self::handle_connection(
+                            stream, addr.clone(), config, sessions, folder_bandwidth, folder_history, command_limiter, operations, audit_logger,
+                            approvals, rate_limits, lockout, auth_manager, authenticator, usage, counters, events, plugins,
+                            malware_scanner, dlp, tls, noise, session_store, permit, ip_connection, handshake,
+                        ).await {
                             error!("Connection error from {}: {}", addr, e);
                         }
-                    });
+                    }.instrument(span));
                 }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
                 }
             }
         }
+        self.counters.set_accepting(false);
 
         Ok(())
     }
 
+    /// Record a connection refused at accept time without holding up the accept loop.
+    fn audit_rejected_connection(&self, ip: std::net::IpAddr, reason: &str) {
+        let audit_logger = Arc::clone(&self.audit_logger);
+        let reason = reason.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = audit_logger.log_connection_rejected(ip, &reason).await {
+                warn!("Failed to audit rejected connection from {}: {}", ip, e);
+            }
+        });
+    }
+
     pub async fn stop(&mut self) -> FshResult<()> {
         info!("Stopping FSH server");
+        notify_stopping();
+        self.counters.set_accepting(false);
+        self.shutdown.cancel();
+        self.events.emit(ServerEvent::Shutdown);
 
         // Drop the listener to stop accepting new connections
         self.listener = None;
 
         // Close all active sessions
-        let mut sessions = self.sessions.write().await;
-        for (session_id, session) in sessions.drain() {
-            info!("Closing session {}", session_id);
-            if let Err(e) = session.close().await {
-                error!("Error closing session {}: {}", session_id, e);
-            }
+        self.sessions.close_all().await;
+
+        if let Err(e) = self.usage.persist() {
+            error!("Failed to save usage statistics: {}", e);
         }
 
         info!("FSH server stopped");
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_connection(
-        stream: tokio::net::TcpStream,
+        stream: FshStream,
         client_addr: String,
         config: Arc<Config>,
-        sessions: Arc<RwLock<HashMap<String, Arc<Session>>>>,
+        sessions: SessionManager,
+        folder_bandwidth: Arc<FolderBandwidth>,
+        folder_history: Arc<FolderHistory>,
+        command_limiter: Arc<CommandLimiter>,
+        operations: Arc<OperationLog>,
+        audit_logger: Arc<AuditLogger>,
+        approvals: Arc<ApprovalQueue>,
+        rate_limits: Arc<RateLimits>,
+        lockout: Arc<AuthLockout>,
+        auth_manager: Arc<RwLock<AuthManager>>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        usage: UsageTracker,
+        counters: Arc<ConnectionCounters>,
+        events: EventBus,
+        plugins: Plugins,
+        malware_scanner: Option<Arc<MalwareScanner>>,
+        dlp: Option<Arc<DlpScanner>>,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        noise: Option<Arc<NoiseServer>>,
+        session_store: Option<Arc<SessionStore>>,
+        _permit: OwnedSemaphorePermit,
+        _ip_connection: Option<IpConnectionGuard>,
+        handshake: Option<IpConnectionGuard>,
     ) -> FshResult<()> {
-        let connection = Connection::new(stream, client_addr, config);
+        if let FshStream::Tcp(tcp) = &stream {
+            if let Err(e) = configure_tcp_keepalive(tcp, &config.server.keepalive) {
+                warn!("Failed to enable TCP keepalive for {}: {}", client_addr, e);
+            }
+        }
+
+        let mut client_identity = None;
+        let stream: FshStream = match tls {
+            Some(tls) => {
+                let acceptor = tokio_rustls::TlsAcceptor::from(tls);
+                let handshake_timeout = std::time::Duration::from_secs(config.server.handshake.connect_timeout_seconds);
+                let stream = tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await
+                    .map_err(|e| FshError::io(format!("TLS handshake with {} timed out", client_addr), e.into()))?
+                    .map_err(|e| FshError::io(format!("TLS handshake with {} failed", client_addr), e))?;
+
+                // Only certificates signed by the configured client CA get this far
+                if let Some(cert) = stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+                    let identity = CertIdentity::from_der(&cert.0)?;
+                    info!("Client {} presented certificate for {:?}", client_addr, identity.user());
+                    client_identity = Some(identity);
+                }
+                tokio_rustls::TlsStream::from(stream).into()
+            }
+            None => stream,
+        };
+
+        let mut connection = Connection::new(stream, client_addr, config, sessions.clone())
+            .with_folder_bandwidth(folder_bandwidth)
+            .with_folder_history(folder_history)
+            .with_command_limiter(command_limiter)
+            .with_operation_log(operations)
+            .with_audit_logger(audit_logger)
+            .with_approvals(approvals)
+            .with_rate_limits(rate_limits)
+            .with_lockout(lockout)
+            .with_auth_manager(auth_manager)
+            .with_usage(usage)
+            .with_counters(counters)
+            .with_events(events)
+            .with_plugins(plugins);
+        if let Some(identity) = client_identity {
+            connection = connection.with_client_identity(identity);
+        }
+        if let Some(authenticator) = authenticator {
+            connection = connection.with_authenticator(authenticator);
+        }
+        if let Some(malware_scanner) = malware_scanner {
+            connection = connection.with_malware_scanner(malware_scanner);
+        }
+        if let Some(dlp) = dlp {
+            connection = connection.with_dlp(dlp);
+        }
+        if let Some(noise) = noise {
+            connection = connection.with_noise(noise);
+        }
+        if let Some(session_store) = session_store {
+            connection = connection.with_session_store(session_store);
+        }
 
         // Handle the connection lifecycle
-        match connection.handle().await {
-            Ok(session) => {
+        let handled = connection.handle().await;
+        drop(handshake);
+        match handled {
+            Ok(Established::Observer(observer)) => {
+                if let Err(e) = observer.run().await {
+                    warn!("Observer connection failed: {}", e);
+                }
+            }
+            Ok(Established::Participant(participant)) => {
+                if let Err(e) = participant.run().await {
+                    warn!("Participant connection failed: {}", e);
+                }
+            }
+            Ok(Established::Session(session)) => {
                 let session_id = session.id().to_string();
                 info!("Session {} established", session_id);
 
-                // Store the session
-                sessions.write().await.insert(session_id.clone(), Arc::new(session));
+                // Store the session until its message loop ends
+                let session = Arc::new(session);
+                sessions.insert(Arc::clone(&session)).await;
+                session.wait_closed().await;
 
-                // Session will be removed when it's dropped or explicitly closed
+                sessions.remove(&session_id).await;
+                let duration = (chrono::Utc::now() - session.created_at()).to_std().unwrap_or_default();
+                crate::telemetry::metrics().record_session(&session.folder_info().name, duration);
+                info!("Session {} ended", session_id);
             }
             Err(e) => {
                 error!("Connection handling failed: {}", e);
@@ -121,53 +690,73 @@ impl FshServer {
     }
 
     pub async fn list_sessions(&self) -> Vec<String> {
-        self.sessions.read().await.keys().cloned().collect()
+        self.sessions.ids().await
     }
 
     pub async fn get_session(&self, session_id: &str) -> Option<Arc<Session>> {
-        self.sessions.read().await.get(session_id).cloned()
+        self.sessions.get(session_id).await
     }
 
     pub async fn close_session(&self, session_id: &str) -> FshResult<()> {
-        let session = {
-            let mut sessions = self.sessions.write().await;
-            sessions.remove(session_id)
-        };
+        self.sessions.close(session_id).await
+    }
 
-        if let Some(session) = session {
-            session.close().await?;
-            info!("Session {} closed", session_id);
-            Ok(())
-        } else {
-            Err(FshError::SessionNotFound(session_id.to_string()))
-        }
+    /// Send an operator's notice to every connected client that can display
+    /// it, returning how many received it.
+    pub async fn broadcast(&self, message: &str) -> usize {
+        self.sessions.broadcast(message).await
+    }
+
+    /// The server's live sessions.
+    pub fn sessions(&self) -> &SessionManager {
+        &self.sessions
     }
 
     pub fn config(&self) -> &Config {
         &self.config
     }
 
+    /// Commands waiting for an operator's decision.
+    pub fn approvals(&self) -> &Arc<ApprovalQueue> {
+        &self.approvals
+    }
+
+    pub fn lockout(&self) -> &Arc<AuthLockout> {
+        &self.lockout
+    }
+
+    /// Tokens clients authenticate with.
+    pub fn auth_manager(&self) -> &Arc<RwLock<AuthManager>> {
+        &self.auth_manager
+    }
+
+    /// Revoke a token and close every session it opened, returning how many
+    /// were closed.
+    pub async fn revoke_token(&self, token_id: &str) -> FshResult<usize> {
+        self.auth_manager.write().await.revoke_token(token_id)?;
+        Ok(self.sessions.revoke_tokens(&[token_id.to_string()], "Token revoked").await)
+    }
+
     pub async fn stats(&self) -> ServerStats {
-        let sessions = self.sessions.read().await;
-        ServerStats {
-            active_sessions: sessions.len(),
+        self.stats_collector().collect().await
+    }
+
+    fn stats_collector(&self) -> StatsCollector {
+        StatsCollector {
+            sessions: self.sessions.clone(),
+            connection_permits: Arc::clone(&self.connection_permits),
+            counters: Arc::clone(&self.counters),
+            handshakes: Arc::clone(&self.handshakes),
+            usage: self.usage.clone(),
             max_connections: self.config.server.max_connections,
-            uptime_seconds: 0, // TODO: Track uptime
+            listen_addresses: self.local_addrs(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ServerStats {
-    pub active_sessions: usize,
-    pub max_connections: usize,
-    pub uptime_seconds: u64,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::TempDir;
 
     #[tokio::test]
     async fn test_server_creation() {
@@ -183,5 +772,43 @@ mod tests {
         let stats = server.stats().await;
         assert_eq!(stats.active_sessions, 0);
         assert_eq!(stats.max_connections, 10); // Default value
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(stats.rejected_connections, 0);
+        assert!(stats.started_at.is_none());
+        assert_eq!(stats.uptime_seconds, 0);
+
+        server.counters.mark_started();
+        server.counters.record_accepted(2);
+        server.counters.record_accepted(1);
+        server.counters.record_rejected();
+        server.counters.record_auth_failed();
+        let stats = server.stats().await;
+        assert!(stats.started_at.is_some());
+        assert_eq!(stats.accepted_connections, 2);
+        assert_eq!(stats.rejected_connections, 1);
+        assert_eq!(stats.auth_failed_connections, 1);
+        assert_eq!(stats.peak_connections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_token_interrupts_accept() {
+        let mut config = Config::default();
+        config.server.host = "127.0.0.1".to_string();
+        config.server.port = 0;
+        let mut server = FshServer::new(config).unwrap();
+        let shutdown = server.shutdown_token();
+
+        let serving = tokio::spawn(async move {
+            server.start().await.unwrap();
+            server
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!serving.is_finished());
+
+        // Nothing connects, yet the accept loop still returns
+        shutdown.cancel();
+        let mut server = tokio::time::timeout(std::time::Duration::from_secs(5), serving).await.unwrap().unwrap();
+        assert!(!server.counters.is_accepting());
+        server.stop().await.unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,266 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, warn, Instrument};
+
+use crate::protocol::{FshError, FshResult};
+use crate::security::{AuditSink, SecurityEvent, SecurityEventType};
+use crate::server::ServerEvent;
+
+/// How long a webhook may take to answer before the notification is dropped.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Webhooks told about security and session events as they happen.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationsConfig {
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Events to send: audit event types such as `ip_blocked` or
+    /// `suspicious_activity`, plus `session_started`, `command_executed` and
+    /// `shutdown`.
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<String>,
+}
+
+/// The body POSTed to a webhook.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// `{"event": ..., "timestamp": ..., ...}` with the event's fields.
+    #[default]
+    Json,
+    /// A Slack incoming webhook message.
+    Slack,
+    /// A Discord webhook message.
+    Discord,
+}
+
+fn default_webhook_events() -> Vec<String> {
+    [
+        "ip_blocked", "account_locked", "suspicious_activity", "honeypot_activity", "malware_detected",
+        "session_started",
+    ].iter().map(|event| event.to_string()).collect()
+}
+
+/// Session events a webhook can subscribe to.
+const SESSION_EVENTS: &[&str] = &["session_started", "command_executed", "shutdown"];
+
+impl NotificationsConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        for webhook in &self.webhooks {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                return Err(FshError::ConfigError(format!("Invalid webhook URL '{}'", webhook.url)));
+            }
+            if let Some(event) = webhook.events.iter().find(|event| !is_known_event(event)) {
+                return Err(FshError::ConfigError(format!("Unknown notification event '{}'", event)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_known_event(name: &str) -> bool {
+    SESSION_EVENTS.contains(&name)
+        || serde_json::from_value::<SecurityEventType>(Value::String(camel_case(name))).is_ok()
+}
+
+/// `ip_blocked` for `IpBlocked`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// `IpBlocked` for `ip_blocked`.
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+        })
+        .collect()
+}
+
+/// One event, ready to be sent in any format.
+#[derive(Debug, Clone)]
+struct Notification {
+    event: String,
+    summary: String,
+    fields: Map<String, Value>,
+}
+
+impl Notification {
+    fn from_security_event(event: &SecurityEvent) -> Self {
+        let name = snake_case(&format!("{:?}", event.event_type));
+        let mut fields = Map::new();
+        fields.insert("source_ip".to_string(), json!(event.source_ip));
+        fields.insert("session_id".to_string(), json!(event.session_id));
+        fields.insert("user_id".to_string(), json!(event.user_id));
+        fields.insert("resource".to_string(), json!(event.resource));
+        fields.insert("details".to_string(), json!(event.details));
+        let summary = match &event.resource {
+            Some(resource) => format!("{} from {} ({}): {}", name, event.source_ip, resource, event.details),
+            None => format!("{} from {}: {}", name, event.source_ip, event.details),
+        };
+        Self { event: name, summary, fields }
+    }
+
+    fn from_server_event(event: &ServerEvent) -> Option<Self> {
+        let (name, summary, fields) = match event {
+            ServerEvent::SessionStarted { session_id, folder, client_addr } => (
+                "session_started",
+                format!("session {} started on '{}' from {}", session_id, folder, client_addr),
+                json!({ "session_id": session_id, "folder": folder, "client_addr": client_addr }),
+            ),
+            ServerEvent::CommandExecuted { session_id, folder, command, exit_code, duration } => (
+                "command_executed",
+                format!("'{}' exited with {} in session {} on '{}'", command, exit_code, session_id, folder),
+                json!({
+                    "session_id": session_id,
+                    "folder": folder,
+                    "command": command,
+                    "exit_code": exit_code,
+                    "duration_ms": duration.as_millis() as u64,
+                }),
+            ),
+            ServerEvent::Shutdown => ("shutdown", "server is shutting down".to_string(), json!({})),
+            // Failed logins arrive through the audit log as authentication_failure
+            ServerEvent::FolderBound { .. } | ServerEvent::AuthFailed { .. } => return None,
+        };
+        let Value::Object(fields) = fields else { unreachable!() };
+        Some(Self { event: name.to_string(), summary, fields })
+    }
+
+    fn body(&self, format: WebhookFormat) -> Value {
+        match format {
+            WebhookFormat::Json => {
+                let mut body = self.fields.clone();
+                body.insert("event".to_string(), json!(self.event));
+                body.insert("timestamp".to_string(), json!(chrono::Utc::now()));
+                Value::Object(body)
+            }
+            WebhookFormat::Slack => json!({ "text": format!("*FSH* {}", self.summary) }),
+            WebhookFormat::Discord => json!({ "content": format!("**FSH** {}", self.summary) }),
+        }
+    }
+}
+
+/// Sends events to the configured webhooks. Security events arrive as an
+/// `AuditSink`, so they are only sent while audit logging is enabled; session
+/// events come from the server's `EventBus`.
+#[derive(Debug)]
+pub struct Notifier {
+    webhooks: Vec<WebhookConfig>,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationsConfig) -> Self {
+        Self {
+            webhooks: config.webhooks.clone(),
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.webhooks.is_empty()
+    }
+
+    /// Send session events from `events` until the server shuts down.
+    pub async fn forward(&self, mut events: broadcast::Receiver<ServerEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Some(notification) = Notification::from_server_event(&event) {
+                        self.send(&notification);
+                    }
+                    if event == ServerEvent::Shutdown {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Dropped {} notifications while webhooks were busy", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// POST `notification` to every webhook subscribed to it, without waiting
+    /// for the answers.
+    fn send(&self, notification: &Notification) {
+        for webhook in self.webhooks.iter().filter(|webhook| webhook.events.contains(&notification.event)) {
+            debug!("Sending {} notification to {}", notification.event, webhook.url);
+            let post = self.http.post(&webhook.url).json(&notification.body(webhook.format)).send();
+            let event = notification.event.clone();
+            tokio::spawn(async move {
+                if let Err(e) = post.await.and_then(|response| response.error_for_status()) {
+                    warn!("Webhook notification of {} failed: {}", event, e);
+                }
+            }.in_current_span());
+        }
+    }
+}
+
+#[async_trait]
+impl AuditSink for Notifier {
+    async fn record(&self, event: &SecurityEvent) -> FshResult<()> {
+        self.send(&Notification::from_security_event(event));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_names_and_formats() {
+        let config = NotificationsConfig {
+            webhooks: vec![WebhookConfig {
+                url: "https://hooks.slack.com/services/T0/B0/x".to_string(),
+                format: WebhookFormat::Slack,
+                events: vec!["ip_blocked".to_string(), "session_started".to_string()],
+            }],
+        };
+        assert!(config.validate().is_ok());
+
+        let mut unknown = config.clone();
+        unknown.webhooks[0].events.push("ip_unblocked".to_string());
+        assert!(matches!(unknown.validate(), Err(FshError::ConfigError(_))));
+
+        let event = SecurityEvent {
+            event_type: SecurityEventType::IpBlocked,
+            source_ip: "10.0.0.5".parse().unwrap(),
+            session_id: None,
+            user_id: None,
+            resource: None,
+            details: "Too many failed logins".to_string(),
+            timestamp: std::time::SystemTime::now(),
+        };
+        let notification = Notification::from_security_event(&event);
+        assert_eq!(notification.event, "ip_blocked");
+        assert_eq!(
+            notification.body(WebhookFormat::Slack),
+            json!({ "text": "*FSH* ip_blocked from 10.0.0.5: Too many failed logins" }),
+        );
+        assert_eq!(notification.body(WebhookFormat::Json)["source_ip"], "10.0.0.5");
+    }
+}
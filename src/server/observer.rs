@@ -0,0 +1,299 @@
+use futures::stream::SplitSink;
+use futures::Sink;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, info, warn};
+
+use crate::protocol::{
+    message::*, FshCodec, FshError, FshErrorCode, FshFramed, FshMessage, FshResult, FshStream,
+};
+use crate::server::{PendingOperation, Session};
+
+/// Mirrored events an observer may fall behind by before it misses some.
+const MIRROR_CAPACITY: usize = 1024;
+
+/// Something that happened in a session, and the participant who caused it
+/// (`None` for the session's owner).
+#[derive(Debug, Clone)]
+pub struct MirroredEvent {
+    pub source: Option<String>,
+    pub event: ObservedEvent,
+}
+
+/// Copies a session's terminal activity to its observers and participants.
+/// Observers may only attach once the owner has consented.
+#[derive(Debug, Clone)]
+pub struct SessionMirror {
+    events: broadcast::Sender<MirroredEvent>,
+    consent: Arc<watch::Sender<bool>>,
+    observers: Arc<AtomicUsize>,
+}
+
+impl Default for SessionMirror {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionMirror {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(MIRROR_CAPACITY);
+        let (consent, _) = watch::channel(false);
+        Self { events, consent: Arc::new(consent), observers: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Allow observers to attach, or detach every observer.
+    pub fn set_consent(&self, allow: bool) {
+        self.consent.send_replace(allow);
+    }
+
+    pub fn consented(&self) -> bool {
+        *self.consent.borrow()
+    }
+
+    /// Observers attached to the session.
+    pub fn observers(&self) -> usize {
+        self.observers.load(Ordering::Relaxed)
+    }
+
+    /// Events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<MirroredEvent> {
+        self.events.subscribe()
+    }
+
+    /// The owner's consent to observers, which ends every observation when
+    /// withdrawn.
+    pub fn consent(&self) -> watch::Receiver<bool> {
+        self.consent.subscribe()
+    }
+
+    /// Pass `event`, caused by `source`, to whoever is listening.
+    pub fn publish(&self, source: Option<&str>, event: ObservedEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(MirroredEvent { source: source.map(str::to_string), event });
+        }
+    }
+
+    /// Mirror what a terminal shows of a message sent to it by `source`'s
+    /// connection.
+    fn publish_sent(&self, source: Option<&str>, message: &FshMessage) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let event = match message {
+            FshMessage::CommandOutput(output) => ObservedEvent::Output(output.clone()),
+            FshMessage::CommandComplete(complete) => ObservedEvent::Complete(complete.clone()),
+            FshMessage::WorkingDirChanged(changed) => ObservedEvent::WorkingDirChanged(changed.clone()),
+            FshMessage::Error(error) if error.code != FshErrorCode::IdleWarning => ObservedEvent::Error(error.clone()),
+            _ => return,
+        };
+        self.publish(source, event);
+    }
+}
+
+/// Write half of a connection to a session's owner or one of its
+/// participants, copying what it sends to the session's mirror.
+#[derive(Debug)]
+pub struct MirroredSink {
+    inner: SplitSink<FshFramed<FshStream>, FshMessage>,
+    mirror: SessionMirror,
+    participant: Option<String>,
+    /// The operation whose reply is next to be sent.
+    operation: Option<PendingOperation>,
+}
+
+impl MirroredSink {
+    pub fn new(inner: SplitSink<FshFramed<FshStream>, FshMessage>, mirror: SessionMirror) -> Self {
+        Self { inner, mirror, participant: None, operation: None }
+    }
+
+    /// The connection of `participant` rather than the session's owner.
+    pub fn for_participant(
+        inner: SplitSink<FshFramed<FshStream>, FshMessage>,
+        mirror: SessionMirror,
+        participant: String,
+    ) -> Self {
+        Self { inner, mirror, participant: Some(participant), operation: None }
+    }
+
+    /// Send the next reply to `operation`'s request as the operation's reply.
+    pub fn begin_operation(&mut self, operation: PendingOperation) {
+        if let Some(previous) = self.operation.replace(operation) {
+            previous.abandon();
+        }
+    }
+
+    /// Stop waiting for the current operation's reply. An operation that got
+    /// none may be retried.
+    pub fn end_operation(&mut self) {
+        if let Some(operation) = self.operation.take() {
+            operation.abandon();
+        }
+    }
+}
+
+impl Sink<FshMessage> for MirroredSink {
+    type Error = FshError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: FshMessage) -> Result<(), Self::Error> {
+        self.mirror.publish_sent(self.participant.as_deref(), &message);
+        let reply = self.operation.as_ref()
+            .and_then(|operation| OperationReply::answering(operation.request_type, &message));
+        let message = match (reply, self.operation.take()) {
+            (Some(reply), Some(operation)) => operation.finish(reply),
+            (_, operation) => {
+                self.operation = operation;
+                message
+            }
+        };
+        Pin::new(&mut self.inner).start_send(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// A read-only connection watching another client's session until the
+/// session ends, its owner withdraws consent, or the observer leaves.
+pub struct SessionObserver {
+    stream: FshFramed<FshStream>,
+    session: Arc<Session>,
+    client_addr: String,
+    events: broadcast::Receiver<MirroredEvent>,
+    consent: watch::Receiver<bool>,
+}
+
+impl SessionObserver {
+    pub fn new(stream: FshFramed<FshStream>, session: Arc<Session>, client_addr: String) -> Self {
+        let mirror = session.mirror();
+        mirror.observers.fetch_add(1, Ordering::Relaxed);
+        let (events, consent) = (mirror.subscribe(), mirror.consent());
+        Self { stream, session, client_addr, events, consent }
+    }
+
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    /// Send `message` to the observer.
+    pub async fn send(&mut self, message: FshMessage) -> FshResult<()> {
+        FshCodec::write_message(&mut self.stream, message).await
+    }
+
+    pub async fn run(mut self) -> FshResult<()> {
+        let session_id = self.session.id().to_string();
+        info!("{} is observing session {}", self.client_addr, session_id);
+
+        let session = Arc::clone(&self.session);
+        let closed = session.wait_closed();
+        tokio::pin!(closed);
+
+        let reason = loop {
+            tokio::select! {
+                // Pass on what the session sent before noticing it ended
+                biased;
+                event = self.events.recv() => match event {
+                    Ok(MirroredEvent { event, .. }) => {
+                        let observed = FshMessage::Observed(ObservedMessage { session_id: session_id.clone(), event });
+                        FshCodec::write_message(&mut self.stream, observed).await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Observer {} of session {} missed {} events", self.client_addr, session_id, missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break "The observed session ended",
+                },
+                changed = self.consent.changed() => {
+                    if changed.is_err() || !*self.consent.borrow() {
+                        break "The session's owner stopped sharing it";
+                    }
+                }
+                _ = &mut closed => break "The observed session ended",
+                message = FshCodec::read_message(&mut self.stream) => match message {
+                    Ok(FshMessage::Ping) => FshCodec::write_message(&mut self.stream, FshMessage::Pong).await?,
+                    Ok(FshMessage::Pong) => {}
+                    Ok(FshMessage::Disconnect(_)) | Err(_) => {
+                        info!("{} stopped observing session {}", self.client_addr, session_id);
+                        return Ok(());
+                    }
+                    Ok(other) => {
+                        debug!("Observer {} sent {:?}", self.client_addr, other.message_type());
+                        let error = FshMessage::Error(ErrorMessage::new(
+                            FshErrorCode::PermissionDenied,
+                            "Observers can't send requests to the session",
+                        ));
+                        FshCodec::write_message(&mut self.stream, error).await?;
+                    }
+                },
+            }
+        };
+
+        info!("Observation of session {} by {} ended: {}", session_id, self.client_addr, reason);
+        let disconnect = FshMessage::Disconnect(DisconnectMessage { reason: reason.to_string() });
+        FshCodec::write_message(&mut self.stream, disconnect).await
+    }
+}
+
+impl Drop for SessionObserver {
+    fn drop(&mut self) {
+        self.session.mirror().observers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(data: &str) -> FshMessage {
+        FshMessage::CommandOutput(CommandOutputMessage {
+            session_id: "owner".to_string(),
+            output_type: OutputType::Stdout,
+            data: data.as_bytes().to_vec().into(),
+            job_id: None,
+            binary: false,
+        })
+    }
+
+    #[test]
+    fn test_mirror_events() {
+        let mirror = SessionMirror::new();
+        assert!(!mirror.consented());
+
+        // Nothing is kept while nobody is watching
+        mirror.publish_sent(None, &output("before"));
+        mirror.set_consent(true);
+        let mut events = mirror.subscribe();
+        let consent = mirror.consent();
+        assert!(*consent.borrow());
+
+        mirror.publish_sent(None, &output("after"));
+        mirror.publish_sent(None, &FshMessage::Ping);
+        mirror.publish_sent(None, &FshMessage::Error(ErrorMessage::new(FshErrorCode::IdleWarning, "idle")));
+        mirror.publish_sent(Some("alice"), &FshMessage::Error(ErrorMessage::new(FshErrorCode::PermissionDenied, "denied")));
+
+        let mirrored = events.try_recv().unwrap();
+        assert_eq!(mirrored.source, None);
+        match mirrored.event {
+            ObservedEvent::Output(output) => assert_eq!(&output.data[..], b"after"),
+            other => panic!("unexpected event {:?}", other),
+        }
+        let mirrored = events.try_recv().unwrap();
+        assert_eq!(mirrored.source.as_deref(), Some("alice"));
+        assert!(matches!(mirrored.event, ObservedEvent::Error(error) if error.message == "denied"));
+        assert!(events.try_recv().is_err());
+
+        mirror.set_consent(false);
+        assert!(!*consent.borrow());
+    }
+}
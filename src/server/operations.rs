@@ -0,0 +1,194 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::message::{OperationReply, OperationResponseMessage};
+use crate::protocol::FshMessage;
+
+/// How long an operation's reply is kept for retries.
+pub const OPERATION_TTL: Duration = Duration::from_secs(600);
+
+/// Replies kept across all folders before the oldest are dropped.
+pub const MAX_OPERATIONS: usize = 10_000;
+
+/// What became of an operation the log has seen before.
+#[derive(Debug, Clone)]
+pub enum OperationState {
+    /// Not seen; the caller does it and reports with `finish` or `abandon`.
+    New,
+    /// Another connection is still doing it, e.g. the one that dropped.
+    InProgress,
+    /// Already done; this is the reply it got.
+    Done(OperationReply),
+}
+
+/// An operation's folder and id.
+type OperationKey = (String, String);
+
+#[derive(Debug)]
+struct Entry {
+    started: Instant,
+    reply: Option<OperationReply>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<OperationKey, Entry>,
+    /// Keys in the order their operations started.
+    order: VecDeque<OperationKey>,
+}
+
+/// Replies to recent operations by folder and operation id, shared by every
+/// connection so a client that reconnects and resends a change gets the
+/// first reply again instead of making the change twice.
+#[derive(Debug)]
+pub struct OperationLog {
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl Default for OperationLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OperationLog {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            ttl: OPERATION_TTL,
+            capacity: MAX_OPERATIONS,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Look up `operation_id` on `folder`, claiming it if it is new.
+    pub fn begin(&self, folder: &str, operation_id: &str) -> OperationState {
+        let mut guard = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Entries { by_key: entries, order } = &mut *guard;
+
+        // Entries are ordered by when they started, so expired ones are at the front
+        while let Some(key) = order.front() {
+            let expired = entries.get(key).is_none_or(|entry| entry.started.elapsed() >= self.ttl);
+            if !expired && order.len() < self.capacity {
+                break;
+            }
+            if let Some(key) = order.pop_front() {
+                entries.remove(&key);
+            }
+        }
+
+        let key = (folder.to_string(), operation_id.to_string());
+        match entries.get(&key) {
+            Some(Entry { reply: Some(reply), .. }) => OperationState::Done(reply.clone()),
+            Some(Entry { reply: None, .. }) => OperationState::InProgress,
+            None => {
+                entries.insert(key.clone(), Entry { started: Instant::now(), reply: None });
+                order.push_back(key);
+                OperationState::New
+            }
+        }
+    }
+
+    /// Keep `reply` for retries of an operation claimed with `begin`.
+    pub fn finish(&self, folder: &str, operation_id: &str, reply: OperationReply) {
+        let mut guard = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = guard.by_key.get_mut(&(folder.to_string(), operation_id.to_string())) {
+            entry.reply = Some(reply);
+        }
+    }
+
+    /// Forget an operation that wasn't done, so a retry does it.
+    pub fn abandon(&self, folder: &str, operation_id: &str) {
+        let mut guard = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Entries { by_key: entries, order } = &mut *guard;
+        let key = (folder.to_string(), operation_id.to_string());
+        if entries.remove(&key).is_some() {
+            order.retain(|queued| *queued != key);
+        }
+    }
+}
+
+/// An operation a session is doing. Its reply is sent with the operation's
+/// id and kept in the log for retries.
+#[derive(Debug)]
+pub struct PendingOperation {
+    pub operation_id: String,
+    /// `message_type` of the request, to recognise its reply.
+    pub request_type: &'static str,
+    pub folder: String,
+    pub log: Arc<OperationLog>,
+}
+
+impl PendingOperation {
+    /// Record `reply` and wrap it for the client. Errors worth retrying
+    /// aren't kept, so a retry does the operation.
+    pub fn finish(self, reply: OperationReply) -> FshMessage {
+        match &reply {
+            OperationReply::Error(error) if error.code.is_retryable() => self.abandon(),
+            reply => self.log.finish(&self.folder, &self.operation_id, reply.clone()),
+        }
+        FshMessage::OperationResponse(OperationResponseMessage {
+            operation_id: self.operation_id,
+            replayed: false,
+            reply,
+        })
+    }
+
+    /// Give up on the operation without a reply.
+    pub fn abandon(&self) {
+        self.log.abandon(&self.folder, &self.operation_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::FileMkdirResponseMessage;
+
+    fn created() -> OperationReply {
+        OperationReply::FileMkdir(FileMkdirResponseMessage { success: true, error_message: None })
+    }
+
+    #[test]
+    fn test_operation_log() {
+        let log = OperationLog::new();
+        assert!(matches!(log.begin("docs", "a"), OperationState::New));
+        assert!(matches!(log.begin("docs", "a"), OperationState::InProgress));
+        // Ids are per folder
+        assert!(matches!(log.begin("src", "a"), OperationState::New));
+
+        log.finish("docs", "a", created());
+        assert!(matches!(log.begin("docs", "a"), OperationState::Done(OperationReply::FileMkdir(_))));
+
+        log.abandon("src", "a");
+        assert!(matches!(log.begin("src", "a"), OperationState::New));
+    }
+
+    #[test]
+    fn test_operation_log_expiry() {
+        let log = OperationLog::new().with_capacity(2);
+        for id in ["a", "b", "c"] {
+            log.begin("docs", id);
+            log.finish("docs", id, created());
+        }
+        // The oldest reply made room for the newest
+        assert!(matches!(log.begin("docs", "a"), OperationState::New));
+
+        let log = OperationLog::new().with_ttl(Duration::ZERO);
+        log.begin("docs", "a");
+        log.finish("docs", "a", created());
+        assert!(matches!(log.begin("docs", "a"), OperationState::New));
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+use crate::config::FolderConfig;
+use crate::protocol::OutputType;
+
+/// Name of the per-folder directory output over the cap is written to.
+pub const OUTPUT_SPILL_DIR: &str = ".fsh-output";
+
+/// Caps how much of one command's output is streamed to the client. Past the
+/// cap the stream ends with a marker and the rest goes to a file in
+/// [`OUTPUT_SPILL_DIR`] under the folder, where the client can read it.
+#[derive(Debug)]
+pub struct OutputCap {
+    limit: usize,
+    sent: usize,
+    root: PathBuf,
+    spill: Spill,
+}
+
+#[derive(Debug)]
+enum Spill {
+    NotStarted,
+    File { relative: String, file: tokio::fs::File },
+    /// The file couldn't be written; the rest of the output is dropped.
+    Failed,
+}
+
+impl OutputCap {
+    pub fn new(root: &Path, limit: usize) -> Self {
+        Self { limit, sent: 0, root: root.to_path_buf(), spill: Spill::NotStarted }
+    }
+
+    /// The cap of a command run in `folder`, if it has one.
+    pub fn for_folder(folder: &FolderConfig) -> Option<Self> {
+        folder.max_command_output_kb.map(|kb| Self::new(Path::new(&folder.path), kb as usize * 1024))
+    }
+
+    /// Where the output over the cap went, relative to the folder root.
+    pub fn spilled(&self) -> Option<&str> {
+        match &self.spill {
+            Spill::File { relative, .. } => Some(relative),
+            _ => None,
+        }
+    }
+
+    /// What of a chunk of output to stream, and whether each piece is binary:
+    /// all of it under the cap, the part that fits and a marker when it
+    /// crosses the cap, nothing after that.
+    pub async fn pass(
+        &mut self,
+        output_type: OutputType,
+        binary: bool,
+        mut data: Vec<u8>,
+    ) -> Vec<(OutputType, bool, Vec<u8>)> {
+        if !matches!(self.spill, Spill::NotStarted) {
+            self.write_spill(&data).await;
+            return Vec::new();
+        }
+
+        let room = self.limit - self.sent;
+        if data.len() <= room {
+            self.sent += data.len();
+            return vec![(output_type, binary, data)];
+        }
+
+        // Stop the stream at the end of a line: the last one in the part
+        // that fits, or the previous chunk's if none does
+        let whole_lines = data[..room].iter().rposition(|byte| *byte == b'\n').map(|index| index + 1);
+        let cut = whole_lines.unwrap_or(if self.sent == 0 { room } else { 0 });
+        let rest = data.split_off(cut);
+        self.sent += cut;
+
+        let marker = match self.start_spill(&rest).await {
+            Ok(relative) => format!(
+                "[output truncated after {} KB; the rest is in {}]\n", self.limit / 1024, relative
+            ),
+            Err(e) => {
+                warn!("Failed to spill command output under {}: {}", self.root.display(), e);
+                format!("[output truncated after {} KB; the rest was discarded]\n", self.limit / 1024)
+            }
+        };
+
+        let mut passed = Vec::new();
+        if !data.is_empty() {
+            passed.push((output_type, binary, data));
+        }
+        passed.push((OutputType::Stderr, false, marker.into_bytes()));
+        passed
+    }
+
+    /// Make sure everything spilled has reached the file.
+    pub async fn finish(&mut self) {
+        if let Spill::File { file, relative } = &mut self.spill {
+            if let Err(e) = file.flush().await {
+                warn!("Failed to flush spilled output {}: {}", relative, e);
+            }
+        }
+    }
+
+    async fn start_spill(&mut self, data: &[u8]) -> std::io::Result<String> {
+        let dir = self.root.join(OUTPUT_SPILL_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let name = format!(
+            "{}-{}.log",
+            chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+            &uuid::Uuid::new_v4().simple().to_string()[..8],
+        );
+
+        let started = async {
+            let mut file = tokio::fs::File::create(dir.join(&name)).await?;
+            file.write_all(data).await?;
+            Ok::<_, std::io::Error>(file)
+        }.await;
+        match started {
+            Ok(file) => {
+                let relative = format!("{}/{}", OUTPUT_SPILL_DIR, name);
+                self.spill = Spill::File { relative: relative.clone(), file };
+                Ok(relative)
+            }
+            Err(e) => {
+                self.spill = Spill::Failed;
+                Err(e)
+            }
+        }
+    }
+
+    async fn write_spill(&mut self, data: &[u8]) {
+        if let Spill::File { relative, file } = &mut self.spill {
+            if let Err(e) = file.write_all(data).await {
+                warn!("Failed to spill command output to {}: {}", relative, e);
+                self.spill = Spill::Failed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_output_over_cap_is_spilled() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cap = OutputCap::new(temp_dir.path(), 10);
+
+        assert_eq!(cap.pass(OutputType::Stdout, false, b"one\n".to_vec()).await.len(), 1);
+        let passed = cap.pass(OutputType::Stdout, false, b"two\nthree\n".to_vec()).await;
+        assert_eq!(passed[0], (OutputType::Stdout, false, b"two\n".to_vec()));
+        assert_eq!(passed[1].0, OutputType::Stderr);
+        assert!(String::from_utf8_lossy(&passed[1].2).contains(".fsh-output/"));
+        assert!(cap.pass(OutputType::Stderr, false, b"four\n".to_vec()).await.is_empty());
+        cap.finish().await;
+
+        let spilled = std::fs::read_to_string(temp_dir.path().join(cap.spilled().unwrap())).unwrap();
+        assert_eq!(spilled, "three\nfour\n");
+
+        // A line crossing the cap isn't split
+        let mut cap = OutputCap::new(temp_dir.path(), 10);
+        cap.pass(OutputType::Stdout, false, b"12345678\n".to_vec()).await;
+        let passed = cap.pass(OutputType::Stdout, false, b"crossing\n".to_vec()).await;
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].0, OutputType::Stderr);
+    }
+}
@@ -0,0 +1,277 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::protocol::{FshMessage, FshResult};
+use crate::security::ClientAudit;
+
+/// A command about to run, as the client asked for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandInvocation {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOpKind {
+    List,
+    Stat,
+    Checksum,
+    Read,
+    Write,
+    Delete,
+    /// Creating a directory and its missing parents.
+    Mkdir,
+    Restore,
+    Tail,
+    /// Git status, diff or log of the repository containing the path.
+    Git,
+}
+
+/// A file operation about to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOperation {
+    pub kind: FileOpKind,
+    /// Relative to the folder root, as the client sent it.
+    pub path: String,
+    /// The bytes about to be written, for `FileOpKind::Write`.
+    pub data: Option<Vec<u8>>,
+}
+
+/// The session a plugin is called for.
+#[derive(Debug, Clone)]
+pub struct PluginContext {
+    pub session_id: String,
+    pub folder: String,
+    pub client_addr: String,
+    audit: Option<ClientAudit>,
+}
+
+impl PluginContext {
+    /// Record an event in the audit log on behalf of `plugin`. Does nothing
+    /// when auditing is off.
+    pub async fn audit(&self, plugin: &str, resource: Option<String>, details: &str) -> FshResult<()> {
+        match &self.audit {
+            Some(audit) => audit.logger.log_plugin_event(
+                audit.source_ip, self.session_id.clone(), plugin, resource, details,
+            ).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Extends what sessions do without changing the sandbox: refuse or rewrite
+/// commands and file operations, and add audit events. Every hook defaults
+/// to letting the request through unchanged.
+///
+/// A hook refuses a request by returning an error, usually
+/// `FshError::PermissionDenied`, which the client receives as is.
+#[async_trait]
+pub trait Plugin: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &str;
+
+    /// Called before a command or background job starts.
+    async fn filter_command(&self, _context: &PluginContext, _command: &mut CommandInvocation) -> FshResult<()> {
+        Ok(())
+    }
+
+    /// Called before a file operation; changing `path` or `data` changes
+    /// what the operation does. Clients verify uploads and downloads by
+    /// checksum, so a path should be rewritten the same way for every kind
+    /// of operation.
+    async fn intercept_file(&self, _context: &PluginContext, _operation: &mut FileOperation) -> FshResult<()> {
+        Ok(())
+    }
+}
+
+/// The plugins registered with a server, called in registration order.
+#[derive(Debug, Clone, Default)]
+pub struct Plugins(Arc<Vec<Arc<dyn Plugin>>>);
+
+impl Plugins {
+    pub fn new(plugins: Vec<Arc<dyn Plugin>>) -> Self {
+        Self(Arc::new(plugins))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn for_session(&self, session_id: &str, folder: &str, client_addr: &str, audit: Option<ClientAudit>) -> SessionPlugins {
+        SessionPlugins {
+            plugins: self.clone(),
+            context: PluginContext {
+                session_id: session_id.to_string(),
+                folder: folder.to_string(),
+                client_addr: client_addr.to_string(),
+                audit,
+            },
+        }
+    }
+}
+
+/// The server's plugins bound to one session.
+#[derive(Debug, Clone)]
+pub struct SessionPlugins {
+    plugins: Plugins,
+    context: PluginContext,
+}
+
+impl SessionPlugins {
+    /// Run a client request past every plugin, rewriting it in place. Fails
+    /// with the first plugin's refusal.
+    pub async fn filter_message(&self, message: &mut FshMessage) -> FshResult<()> {
+        if self.plugins.is_empty() {
+            return Ok(());
+        }
+
+        if let FshMessage::Command(cmd_msg) = message {
+            let mut command = CommandInvocation {
+                command: std::mem::take(&mut cmd_msg.command),
+                args: std::mem::take(&mut cmd_msg.args),
+            };
+            let result = self.filter_command(&mut command).await;
+            cmd_msg.command = command.command;
+            cmd_msg.args = command.args;
+            return result;
+        }
+
+        // A write that was corrupted in transit is refused before any plugin sees it
+        if let FshMessage::FileWrite(write_msg) = message {
+            if write_msg.checksum.as_ref().is_some_and(|checksum| !checksum.verify(&write_msg.data)) {
+                return Ok(());
+            }
+        }
+
+        let (kind, path) = match message {
+            FshMessage::FileList(msg) => (FileOpKind::List, &mut msg.path),
+            FshMessage::FileStat(msg) => (FileOpKind::Stat, &mut msg.path),
+            FshMessage::FileChecksum(msg) => (FileOpKind::Checksum, &mut msg.file_path),
+            FshMessage::FileRead(msg) => (FileOpKind::Read, &mut msg.file_path),
+            FshMessage::FileWrite(msg) => (FileOpKind::Write, &mut msg.file_path),
+            FshMessage::FileDelete(msg) => (FileOpKind::Delete, &mut msg.file_path),
+            FshMessage::FileMkdir(msg) => (FileOpKind::Mkdir, &mut msg.path),
+            FshMessage::FileRestore(msg) => (FileOpKind::Restore, &mut msg.target),
+            FshMessage::FileTail(msg) => (FileOpKind::Tail, &mut msg.file_path),
+            FshMessage::GitStatus(msg) => (FileOpKind::Git, msg.path.get_or_insert_with(|| ".".to_string())),
+            FshMessage::GitDiff(msg) => (FileOpKind::Git, msg.path.get_or_insert_with(|| ".".to_string())),
+            FshMessage::GitLog(msg) => (FileOpKind::Git, msg.path.get_or_insert_with(|| ".".to_string())),
+            _ => return Ok(()),
+        };
+        let mut operation = FileOperation { kind, path: std::mem::take(path), data: None };
+        if let FshMessage::FileWrite(write_msg) = message {
+            operation.data = Some(std::mem::take(&mut write_msg.data));
+        }
+
+        let result = self.intercept_file(&mut operation).await;
+
+        match message {
+            FshMessage::FileList(msg) => msg.path = operation.path,
+            FshMessage::FileStat(msg) => msg.path = operation.path,
+            FshMessage::FileChecksum(msg) => msg.file_path = operation.path,
+            FshMessage::FileRead(msg) => msg.file_path = operation.path,
+            FshMessage::FileDelete(msg) => msg.file_path = operation.path,
+            FshMessage::FileMkdir(msg) => msg.path = operation.path,
+            FshMessage::FileRestore(msg) => msg.target = operation.path,
+            FshMessage::FileTail(msg) => msg.file_path = operation.path,
+            FshMessage::GitStatus(msg) => msg.path = Some(operation.path),
+            FshMessage::GitDiff(msg) => msg.path = Some(operation.path),
+            FshMessage::GitLog(msg) => msg.path = Some(operation.path),
+            FshMessage::FileWrite(msg) => {
+                msg.file_path = operation.path;
+                msg.data = operation.data.unwrap_or_default();
+                // The client's checksum covered the original data
+                if let Some(checksum) = &mut msg.checksum {
+                    *checksum = checksum.algorithm.digest(&msg.data);
+                }
+            }
+            _ => {}
+        }
+        result
+    }
+
+    pub async fn filter_command(&self, command: &mut CommandInvocation) -> FshResult<()> {
+        for plugin in self.plugins.0.iter() {
+            plugin.filter_command(&self.context, command).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn intercept_file(&self, operation: &mut FileOperation) -> FshResult<()> {
+        for plugin in self.plugins.0.iter() {
+            plugin.intercept_file(&self.context, operation).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ChecksumAlgorithm, CommandMessage, FileWriteMessage, FshError};
+
+    #[derive(Debug)]
+    struct NoSecrets;
+
+    #[async_trait]
+    impl Plugin for NoSecrets {
+        fn name(&self) -> &str {
+            "no-secrets"
+        }
+
+        async fn filter_command(&self, _context: &PluginContext, command: &mut CommandInvocation) -> FshResult<()> {
+            if command.args.iter().any(|arg| arg.contains("secret")) {
+                return Err(FshError::PermissionDenied("Secrets stay put".to_string()));
+            }
+            command.args.retain(|arg| arg != "-v");
+            Ok(())
+        }
+
+        async fn intercept_file(&self, _context: &PluginContext, operation: &mut FileOperation) -> FshResult<()> {
+            if let Some(data) = &mut operation.data {
+                data.make_ascii_uppercase();
+            }
+            Ok(())
+        }
+    }
+
+    fn session_plugins() -> SessionPlugins {
+        Plugins::new(vec![Arc::new(NoSecrets)]).for_session("s1", "test", "127.0.0.1:1234", None)
+    }
+
+    #[tokio::test]
+    async fn test_commands_filtered() {
+        let plugins = session_plugins();
+        let command = |args: &[&str]| FshMessage::Command(CommandMessage {
+            session_id: "s1".to_string(),
+            command: "cat".to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            environment: None,
+            job_id: None,
+        });
+
+        let mut allowed = command(&["-v", "notes.txt"]);
+        plugins.filter_message(&mut allowed).await.unwrap();
+        assert!(matches!(&allowed, FshMessage::Command(msg) if msg.command == "cat" && msg.args == ["notes.txt"]));
+
+        let mut refused = command(&["secret.txt"]);
+        assert!(matches!(plugins.filter_message(&mut refused).await, Err(FshError::PermissionDenied(_))));
+    }
+
+    #[tokio::test]
+    async fn test_written_data_rewritten() {
+        let mut message = FshMessage::FileWrite(FileWriteMessage {
+            session_id: "s1".to_string(),
+            file_path: "notes.txt".to_string(),
+            data: b"hello".to_vec(),
+            append: false,
+            offset: None,
+            checksum: Some(ChecksumAlgorithm::default().digest(b"hello")),
+        });
+        session_plugins().filter_message(&mut message).await.unwrap();
+
+        let FshMessage::FileWrite(write_msg) = message else { unreachable!() };
+        assert_eq!(write_msg.file_path, "notes.txt");
+        assert_eq!(write_msg.data, b"HELLO");
+        assert!(write_msg.checksum.unwrap().verify(b"HELLO"));
+    }
+}
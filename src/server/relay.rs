@@ -0,0 +1,370 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::protocol::{
+    expect_relay_ok, is_relay_word, read_relay_line, write_relay_line, FshError, FshListener, FshResult, FshStream,
+    RelayHello,
+};
+use crate::server::constant_time_eq;
+
+/// How long a new connection has to send its handshake, and a client
+/// waits for its agent to open the tunnel.
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The relay pings each agent this often so idle NAT mappings stay open.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An agent reconnects when the relay has been silent this long.
+const CONTROL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Longest wait between an agent's attempts to reach the relay.
+const MAX_AGENT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Relay mode: servers behind NAT dial in as agents and register their
+/// folders, and clients are tunneled through to them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub enabled: bool,
+    /// Where agents and clients connect.
+    pub listen: String,
+    /// Agent name to the secret it registers with.
+    pub agents: BTreeMap<String, String>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "0.0.0.0:2223".to_string(),
+            agents: BTreeMap::new(),
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.listen.parse::<SocketAddr>().is_err() {
+            return Err(FshError::ConfigError(format!("Invalid relay listen address '{}'", self.listen)));
+        }
+        for (name, secret) in &self.agents {
+            if !is_relay_word(name) || !is_relay_word(secret) {
+                return Err(FshError::ConfigError(format!(
+                    "Relay agent '{}' needs a name and secret without spaces", name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Agent mode: take client connections through a relay instead of
+/// listening on `server.host` and `server.port`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub enabled: bool,
+    /// The relay's `host:port`.
+    pub relay: String,
+    pub name: String,
+    pub secret: String,
+}
+
+impl AgentConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.relay.is_empty() {
+            return Err(FshError::ConfigError("agent.relay is required".to_string()));
+        }
+        if !is_relay_word(&self.name) || !is_relay_word(&self.secret) {
+            return Err(FshError::ConfigError("agent.name and agent.secret are required, without spaces".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A connected agent.
+#[derive(Debug)]
+struct RegisteredAgent {
+    registration: u64,
+    folders: Vec<String>,
+    /// Tunnels to ask the agent for: id and client address.
+    control: mpsc::Sender<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+struct RelayState {
+    agents: HashMap<String, RegisteredAgent>,
+    /// Clients waiting for a data connection, by tunnel id: the agent
+    /// expected to open it, and where to hand it over.
+    pending: HashMap<String, (String, oneshot::Sender<TcpStream>)>,
+}
+
+#[derive(Debug)]
+struct Relay {
+    secrets: BTreeMap<String, String>,
+    state: std::sync::Mutex<RelayState>,
+    next_registration: AtomicU64,
+}
+
+impl Relay {
+    fn state(&self) -> std::sync::MutexGuard<'_, RelayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_secret(&self, name: &str, secret: &str) -> FshResult<()> {
+        match self.secrets.get(name) {
+            Some(expected) if constant_time_eq(expected.as_bytes(), secret.as_bytes()) => Ok(()),
+            _ => Err(FshError::AuthenticationFailed),
+        }
+    }
+
+    async fn handle(self: Arc<Self>, mut stream: TcpStream, peer: SocketAddr) -> FshResult<()> {
+        let hello = match timeout(TUNNEL_TIMEOUT, read_relay_line(&mut stream)).await {
+            Ok(line) => line.and_then(|line| RelayHello::parse(&line)),
+            Err(_) => Err(FshError::NetworkError("No relay handshake".to_string())),
+        };
+        let hello = match hello {
+            Ok(hello) => hello,
+            Err(e) => {
+                let _ = write_relay_line(&mut stream, "ERR invalid handshake").await;
+                return Err(e);
+            }
+        };
+
+        match hello {
+            RelayHello::Client { target } => self.tunnel(stream, peer, &target).await,
+            RelayHello::Agent { name, secret, folders } => {
+                if let Err(e) = self.check_secret(&name, &secret) {
+                    warn!("Relay refused agent '{}' from {}: bad name or secret", name, peer);
+                    let _ = write_relay_line(&mut stream, "ERR authentication failed").await;
+                    return Err(e);
+                }
+                self.serve_agent(stream, peer, name, folders).await
+            }
+            RelayHello::Data { name, secret, id } => {
+                self.check_secret(&name, &secret)?;
+                let waiting = {
+                    let mut state = self.state();
+                    match state.pending.get(&id) {
+                        Some((agent, _)) if *agent == name => state.pending.remove(&id),
+                        _ => None,
+                    }
+                };
+                let Some((_, client)) = waiting else {
+                    let _ = write_relay_line(&mut stream, "ERR unknown tunnel").await;
+                    return Err(FshError::NetworkError(format!("Unknown tunnel {} from agent '{}'", id, name)));
+                };
+                write_relay_line(&mut stream, "OK").await?;
+                let _ = client.send(stream);
+                Ok(())
+            }
+        }
+    }
+
+    /// Ask the agent for a data connection and join the client to it.
+    async fn tunnel(&self, mut client: TcpStream, peer: SocketAddr, target: &str) -> FshResult<()> {
+        let id = Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        let agent = {
+            let mut state = self.state();
+            let found = state.agents.get_key_value(target)
+                .or_else(|| state.agents.iter().find(|(_, agent)| agent.folders.iter().any(|folder| folder == target)))
+                .map(|(name, agent)| (name.clone(), agent.control.clone()));
+            if let Some((name, _)) = &found {
+                state.pending.insert(id.clone(), (name.clone(), sender));
+            }
+            found
+        };
+        let Some((agent, control)) = agent else {
+            write_relay_line(&mut client, &format!("ERR no agent serves '{}'", target)).await?;
+            return Err(FshError::FolderNotFound(target.to_string()));
+        };
+
+        let opened = match control.send((id.clone(), peer.to_string())).await {
+            Ok(()) => timeout(TUNNEL_TIMEOUT, receiver).await.ok().and_then(Result::ok),
+            Err(_) => None,
+        };
+        let Some(mut agent_stream) = opened else {
+            self.state().pending.remove(&id);
+            write_relay_line(&mut client, &format!("ERR agent '{}' did not answer", agent)).await?;
+            return Err(FshError::NetworkError(format!("Agent '{}' did not open tunnel {}", agent, id)));
+        };
+
+        write_relay_line(&mut client, "OK").await?;
+        info!("Relaying {} to agent '{}'", peer, agent);
+        let (to_agent, to_client) = tokio::io::copy_bidirectional(&mut client, &mut agent_stream).await
+            .map_err(|e| FshError::io("Relayed connection failed", e))?;
+        debug!("Relayed connection from {} closed after {} bytes up, {} down", peer, to_agent, to_client);
+        Ok(())
+    }
+
+    /// Register an agent and pass it tunnel requests until it disconnects
+    /// or registers again.
+    async fn serve_agent(&self, stream: TcpStream, peer: SocketAddr, name: String, folders: Vec<String>) -> FshResult<()> {
+        let registration = self.next_registration.fetch_add(1, Ordering::Relaxed);
+        let (control, mut requests) = mpsc::channel(16);
+        info!("Agent '{}' registered from {} serving {:?}", name, peer, folders);
+        self.state().agents.insert(name.clone(), RegisteredAgent { registration, folders, control });
+
+        let (mut reader, mut writer) = stream.into_split();
+        let result = async {
+            write_relay_line(&mut writer, "OK").await?;
+            let mut ping = tokio::time::interval(PING_INTERVAL);
+            loop {
+                tokio::select! {
+                    request = requests.recv() => match request {
+                        Some((id, client)) => write_relay_line(&mut writer, &format!("CONNECT {} {}", id, client)).await?,
+                        // Replaced by a newer registration
+                        None => return Ok(()),
+                    },
+                    _ = ping.tick() => write_relay_line(&mut writer, "PING").await?,
+                    read = reader.read_u8() => {
+                        if read.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }.await;
+
+        let mut state = self.state();
+        if state.agents.get(&name).is_some_and(|agent| agent.registration == registration) {
+            state.agents.remove(&name);
+            info!("Agent '{}' disconnected", name);
+        }
+        result
+    }
+}
+
+/// Bind the relay's listener.
+pub async fn bind_relay(config: &RelayConfig) -> FshResult<TcpListener> {
+    let listener = TcpListener::bind(&config.listen).await
+        .map_err(|e| FshError::io(format!("Failed to bind relay to {}", config.listen), e))?;
+    info!("Relay listening on {}", config.listen);
+    Ok(listener)
+}
+
+/// Accept agents and clients until the listener fails.
+pub async fn serve_relay(listener: TcpListener, config: &RelayConfig) -> FshResult<()> {
+    let relay = Arc::new(Relay {
+        secrets: config.agents.clone(),
+        state: Default::default(),
+        next_registration: AtomicU64::new(1),
+    });
+    loop {
+        let (stream, peer) = listener.accept().await
+            .map_err(|e| FshError::io("Failed to accept relay connection", e))?;
+        let relay = Arc::clone(&relay);
+        tokio::spawn(async move {
+            if let Err(e) = relay.handle(stream, peer).await {
+                debug!("Relay connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// The agent side: connections clients opened through the relay, as if
+/// they had been accepted locally. The peer address is the client's as the
+/// relay saw it.
+#[derive(Debug)]
+pub struct RelayListener {
+    incoming: Mutex<mpsc::Receiver<(FshStream, String)>>,
+    task: JoinHandle<()>,
+}
+
+impl RelayListener {
+    /// Keep a registration with the relay, reconnecting when it drops.
+    pub fn start(config: &AgentConfig, folders: Vec<String>) -> Self {
+        let (sender, incoming) = mpsc::channel(16);
+        let task = tokio::spawn(run_agent(config.clone(), folders, sender));
+        Self { incoming: Mutex::new(incoming), task }
+    }
+}
+
+impl Drop for RelayListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl FshListener for RelayListener {
+    async fn accept(&self) -> FshResult<Option<(FshStream, String)>> {
+        Ok(self.incoming.lock().await.recv().await)
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+async fn run_agent(config: AgentConfig, folders: Vec<String>, incoming: mpsc::Sender<(FshStream, String)>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        if let Err(e) = agent_session(&config, &folders, &incoming, &mut backoff).await {
+            warn!("Lost relay {}: {}; reconnecting in {}s", config.relay, e, backoff.as_secs());
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_AGENT_BACKOFF);
+    }
+}
+
+/// One registration with the relay, until the connection fails.
+async fn agent_session(
+    config: &AgentConfig,
+    folders: &[String],
+    incoming: &mpsc::Sender<(FshStream, String)>,
+    backoff: &mut Duration,
+) -> FshResult<()> {
+    let mut control = TcpStream::connect(&config.relay).await
+        .map_err(|e| FshError::io(format!("Failed to connect to relay {}", config.relay), e))?;
+    let hello = RelayHello::Agent { name: config.name.clone(), secret: config.secret.clone(), folders: folders.to_vec() };
+    write_relay_line(&mut control, &hello.to_line()).await?;
+    expect_relay_ok(&mut control).await?;
+    info!("Registered with relay {} as '{}'", config.relay, config.name);
+    *backoff = Duration::from_secs(1);
+
+    loop {
+        let line = timeout(CONTROL_IDLE_TIMEOUT, read_relay_line(&mut control)).await
+            .map_err(|_| FshError::NetworkError("Relay stopped answering".to_string()))??;
+        let Some((id, client)) = line.strip_prefix("CONNECT ").and_then(|rest| rest.split_once(' ')) else {
+            continue; // PING
+        };
+        let (config, id, client, incoming) = (config.clone(), id.to_string(), client.to_string(), incoming.clone());
+        tokio::spawn(async move {
+            match open_tunnel(&config, &id).await {
+                Ok(stream) => {
+                    let _ = incoming.send((stream, client)).await;
+                }
+                Err(e) => warn!("Failed to open relay tunnel for {}: {}", client, e),
+            }
+        });
+    }
+}
+
+async fn open_tunnel(config: &AgentConfig, id: &str) -> FshResult<FshStream> {
+    let mut stream = TcpStream::connect(&config.relay).await
+        .map_err(|e| FshError::io(format!("Failed to connect to relay {}", config.relay), e))?;
+    let hello = RelayHello::Data { name: config.name.clone(), secret: config.secret.clone(), id: id.to_string() };
+    write_relay_line(&mut stream, &hello.to_line()).await?;
+    expect_relay_ok(&mut stream).await?;
+    Ok(stream.into())
+}
@@ -0,0 +1,287 @@
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{FshError, FshResult};
+use crate::sandbox::expand_macro;
+use crate::security::AuditLogger;
+use crate::server::Session;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// Output kept from each run, from its end.
+const OUTPUT_LIMIT: usize = 4096;
+
+fn default_timeout_seconds() -> u64 {
+    600
+}
+
+/// A command a folder runs on its own, e.g. a nightly `git fetch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledTask {
+    pub name: String,
+    /// `minute hour day month weekday`, optionally with seconds first, or
+    /// a shorthand such as `@daily`. Times are UTC.
+    pub cron: String,
+    /// Command line run from the folder root under the folder's sandbox,
+    /// with steps separated by `&&` as in macros.
+    pub command: String,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl ScheduledTask {
+    pub fn new(name: &str, cron: &str, command: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            cron: cron.to_string(),
+            command: command.to_string(),
+            timeout_seconds: default_timeout_seconds(),
+        }
+    }
+
+    pub fn schedule(&self) -> FshResult<cron::Schedule> {
+        // The cron crate wants a seconds field
+        let expression = if self.cron.split_whitespace().count() == 5 {
+            format!("0 {}", self.cron)
+        } else {
+            self.cron.clone()
+        };
+        cron::Schedule::from_str(&expression)
+            .map_err(|e| FshError::ConfigError(format!("Task '{}' has an invalid cron expression '{}': {}", self.name, self.cron, e)))
+    }
+
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.schedule().ok()?.upcoming(Utc).next()
+    }
+
+    pub fn validate(&self) -> FshResult<()> {
+        if self.name.trim().is_empty() {
+            return Err(FshError::ConfigError("Scheduled task name cannot be empty".to_string()));
+        }
+        if self.command.split("&&").any(|step| step.trim().is_empty()) {
+            return Err(FshError::ConfigError(format!("Task '{}' contains an empty step", self.name)));
+        }
+        if self.timeout_seconds == 0 {
+            return Err(FshError::ConfigError(format!("Task '{}' timeout_seconds must be greater than 0", self.name)));
+        }
+        self.schedule().map(|_| ())
+    }
+}
+
+/// The outcome of one run of a scheduled task.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRun {
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// `None` if the command couldn't be started or timed out.
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    /// The end of its stdout and stderr.
+    pub output: String,
+}
+
+impl TaskRun {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// A task as listed by `GET /schedule`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub folder: String,
+    pub name: String,
+    pub cron: String,
+    pub command: String,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<TaskRun>,
+}
+
+/// Runs the folders' scheduled tasks and remembers how each last went.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    config: Arc<Config>,
+    audit_logger: Arc<AuditLogger>,
+    last_runs: Arc<Mutex<HashMap<(String, String), TaskRun>>>,
+}
+
+impl Scheduler {
+    pub fn new(config: Arc<Config>, audit_logger: Arc<AuditLogger>) -> Self {
+        Self { config, audit_logger, last_runs: Arc::default() }
+    }
+
+    pub fn has_tasks(&self) -> bool {
+        self.config.folders.iter().any(|folder| !folder.schedule.is_empty())
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        let last_runs = self.last_runs.lock().unwrap();
+        self.config.folders.iter()
+            .flat_map(|folder| folder.schedule.iter().map(move |task| (folder, task)))
+            .map(|(folder, task)| TaskStatus {
+                folder: folder.name.clone(),
+                name: task.name.clone(),
+                cron: task.cron.clone(),
+                command: task.command.clone(),
+                next_run: task.next_run(),
+                last_run: last_runs.get(&(folder.name.clone(), task.name.clone())).cloned(),
+            })
+            .collect()
+    }
+
+    /// Run every task whenever it is due, until dropped. A task still
+    /// running when it is due again skips that run.
+    pub async fn run(self) {
+        let tasks = self.config.folders.iter()
+            .flat_map(|folder| folder.schedule.iter().map(move |task| (folder, task)))
+            .map(|(folder, task)| self.run_on_schedule(folder, task));
+        futures::future::join_all(tasks).await;
+    }
+
+    async fn run_on_schedule(&self, folder: &FolderConfig, task: &ScheduledTask) {
+        let Ok(schedule) = task.schedule() else {
+            return;
+        };
+        while let Some(next) = schedule.upcoming(Utc).next() {
+            let wait = (next - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            self.run_task(folder, task).await;
+        }
+    }
+
+    /// Run `task` now, audit and remember the outcome.
+    pub async fn run_task(&self, folder: &FolderConfig, task: &ScheduledTask) -> TaskRun {
+        let started_at = Utc::now();
+        let start = Instant::now();
+        let mut output = String::new();
+        let result = run_in_folder(folder, &task.command, task.timeout_seconds, &mut output).await;
+        let run = TaskRun {
+            started_at,
+            duration_ms: start.elapsed().as_millis() as u64,
+            exit_code: result.as_ref().ok().copied(),
+            error: result.err().map(|e| e.to_string()),
+            output,
+        };
+
+        let details = match (&run.exit_code, &run.error) {
+            (Some(code), _) => format!("'{}' exited with {} after {}ms", task.command, code, run.duration_ms),
+            (None, Some(error)) => format!("'{}' failed: {}", task.command, error),
+            (None, None) => format!("'{}' failed", task.command),
+        };
+        if run.succeeded() {
+            info!("Scheduled task {}/{}: {}", folder.name, task.name, details);
+        } else {
+            warn!("Scheduled task {}/{}: {}", folder.name, task.name, details);
+        }
+        if let Err(e) = self.audit_logger.log_scheduled_task(&folder.name, &task.name, &details).await {
+            warn!("Failed to audit scheduled task {}/{}: {}", folder.name, task.name, e);
+        }
+
+        self.last_runs.lock().unwrap().insert((folder.name.clone(), task.name.clone()), run.clone());
+        run
+    }
+}
+
+/// Run the steps of `command` in a fresh shell for the folder, stopping at
+/// the first failure like `&&`, and collect the end of their output. Returns
+/// the last step's exit code.
+pub(crate) async fn run_in_folder(folder: &FolderConfig, command: &str, timeout_seconds: u64, output: &mut String) -> FshResult<i32> {
+    let steps = expand_macro(command, &[])?;
+    let mut shell = Session::build_shell(folder.get_path(), &folder.to_folder_info(), folder).await?;
+    let deadline = Instant::now() + Duration::from_secs(timeout_seconds);
+
+    let mut exit_code = 0;
+    for step in steps {
+        let mut job = shell.start_job(&step.command, &step.args).await?;
+        let collect = async {
+            while let Some(chunk) = job.output.recv().await {
+                if chunk.binary {
+                    append_output(output, &format!("[{} bytes of binary output]\n", chunk.data.len()));
+                } else {
+                    append_output(output, &String::from_utf8_lossy(&chunk.data));
+                }
+            }
+        };
+        let finished = tokio::time::timeout_at(deadline, async {
+            tokio::join!(collect, job.result.recv()).1
+        }).await;
+
+        exit_code = match finished {
+            Ok(Some(result)) => result.exit_code,
+            Ok(None) => return Err(FshError::ShellError(format!("'{}' ended without an exit status", step.command_line()))),
+            Err(_) => {
+                if let Some(killer) = job.killer {
+                    killer.kill();
+                }
+                return Err(FshError::ShellError(format!("Timed out after {}s", timeout_seconds)));
+            }
+        };
+        if exit_code != 0 {
+            break;
+        }
+    }
+    Ok(exit_code)
+}
+
+fn append_output(output: &mut String, data: &str) {
+    output.push_str(data);
+    if output.len() > OUTPUT_LIMIT {
+        let mut cut = output.len() - OUTPUT_LIMIT;
+        while !output.is_char_boundary(cut) {
+            cut += 1;
+        }
+        output.drain(..cut);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_expressions() {
+        assert!(ScheduledTask::new("fetch", "0 3 * * *", "git fetch").validate().is_ok());
+        assert!(ScheduledTask::new("fetch", "30 0 3 * * Mon-Fri", "git fetch").validate().is_ok());
+        assert!(ScheduledTask::new("fetch", "@daily", "git fetch").validate().is_ok());
+        assert!(ScheduledTask::new("fetch", "every night", "git fetch").validate().is_err());
+        assert!(ScheduledTask::new("fetch", "0 3 * * *", "git fetch &&").validate().is_err());
+
+        let next = ScheduledTask::new("fetch", "0 3 * * *", "git fetch").next_run().unwrap();
+        assert_eq!(next.format("%H:%M:%S").to_string(), "03:00:00");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_task_audited_and_remembered() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("notes.txt"), "hello").unwrap();
+        let task = ScheduledTask::new("list", "@daily", "ls && false");
+        let mut folder = FolderConfig::new("project".to_string(), temp_dir.path())
+            .with_shell_type(crate::protocol::ShellType::Sh);
+        folder.allowed_commands = vec!["ls".to_string(), "false".to_string()];
+        folder.schedule = vec![task.clone()];
+
+        let audit_dir = tempfile::TempDir::new().unwrap();
+        let audit_file = audit_dir.path().join("audit.log");
+        let mut config = Config::default();
+        config.security.enable_logging = true;
+        config.security.log_file = Some(audit_file.clone());
+        config.folders.push(folder.clone());
+        let audit_logger = Arc::new(AuditLogger::new(&config.security).unwrap());
+        let scheduler = Scheduler::new(Arc::new(config), audit_logger);
+
+        let run = scheduler.run_task(&folder, &task).await;
+        assert!(run.output.contains("notes.txt"));
+        assert_eq!(run.exit_code, Some(1));
+        assert!(!run.succeeded());
+
+        let status = scheduler.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].last_run.as_ref().unwrap().exit_code, Some(1));
+        assert!(std::fs::read_to_string(&audit_file).unwrap().contains("ScheduledTask"));
+    }
+}
@@ -1,93 +1,502 @@
-use crate::config::FolderConfig;
+use crate::config::{FolderConfig, KeepaliveConfig};
 use crate::protocol::{
-    FshMessage, FshCodec, FshResult, ClientInfo, FolderInfo,
-    message::*,
+    FshMessage, FshCodec, FshFramed, FshError, FshResult, FshErrorCode, FshStream, ClientInfo, FolderInfo,
+    Capabilities, Checksum, ChecksumAlgorithm, CHECKSUM_MISMATCH, FEATURE_SERVER_NOTICE, FEATURE_WATCH_RUNS, FORWARD_CHUNK_SIZE,
+    FORWARD_QUEUE_DEPTH, MAX_FILE_CHUNK_SIZE, Permission, message::*,
 };
-use crate::sandbox::{SandboxedShell, SandboxConfig};
+use crate::sandbox::{expand_macro, CommandHooks, FileFollower, FolderContainer, HoneypotSnapshot, MacroStep, OutputEncoding, ProcessTable, SandboxedShell, SandboxConfig, ShellOutput, RUN_MACRO_COMMAND};
+use crate::security::{
+    ClientAudit, ClientRateLimits, DlpAction, DlpMatch, DlpScanner, MalwareScanner, PatternSet, Policy, PolicyAction,
+    RateLimitKind, ScanOutcome,
+};
+use crate::server::{
+    Admission, ApprovalGate, ApprovalQueue, CommandLimits, CommandSlot, EventBus, FolderAccess, FolderGrant, ForwardTable, Heartbeat, HeartbeatAction, HoneypotMonitor, IdleLock, JobTable, OperationLog, OperationState, OutputCap, PendingOperation, Plugins, SavedSession,
+    MirroredEvent, MirroredSink, ParticipantInput, QueueEvent, ServerEvent, SessionCollaboration, SessionHistory, SessionMirror, SessionPersistence, SessionPlugins, SessionStats, SessionUsage, TransferLimits, UnlockOutcome, connect_loopback,
+};
+use bytes::Bytes;
+use futures::stream::SplitStream;
+use futures::StreamExt;
+use std::io::Read;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::{RwLock, Mutex};
-use tokio::time::{timeout, Duration};
-use tracing::{info, warn, error, debug};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch, RwLock, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, Instant};
+use tracing::{info, warn, error, debug, info_span, Instrument};
+
+/// How long before an idle session expires the client is warned.
+const IDLE_WARNING_LEAD: Duration = Duration::from_secs(60);
+
+/// Directory entries per FileListResponse batch.
+const LIST_BATCH_SIZE: usize = 256;
+
+/// How long command output is held back to be sent along with what follows it.
+const OUTPUT_BATCH_WINDOW: Duration = Duration::from_millis(10);
+
+/// Batched command output is sent once it reaches this size.
+const OUTPUT_BATCH_SIZE: usize = 16 * 1024;
+
+/// How often a followed file is checked for new lines.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A command as the client typed it.
+fn command_line(cmd_msg: &CommandMessage) -> String {
+    std::iter::once(cmd_msg.command.as_str())
+        .chain(cmd_msg.args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The rate limit budget a client request counts against, if any.
+fn rate_limit_kind(message: &FshMessage) -> Option<RateLimitKind> {
+    match message {
+        FshMessage::Command(_) => Some(RateLimitKind::Command),
+        FshMessage::FileList(_)
+        | FshMessage::FileChecksum(_)
+        | FshMessage::FileStat(_)
+        | FshMessage::FileDelete(_)
+        | FshMessage::FileMkdir(_)
+        | FshMessage::FileRestore(_)
+        | FshMessage::GitStatus(_)
+        | FshMessage::GitDiff(_)
+        | FshMessage::GitLog(_)
+        | FshMessage::FileRead(_)
+        | FshMessage::FileWrite(_)
+        | FshMessage::FileTail(_) => Some(RateLimitKind::FileOp),
+        _ => None,
+    }
+}
+
+/// The rule that keeps data from being sent, if any.
+fn dlp_blocked(matches: &[DlpMatch]) -> Option<&DlpMatch> {
+    matches.iter().find(|found| found.action == DlpAction::Block)
+}
+
+/// Read part of a file as the data loss prevention rules let the client see it.
+fn read_inspected(
+    shell: &SandboxedShell,
+    dlp: &DlpScanner,
+    path: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> FshResult<(Vec<u8>, u64, Vec<DlpMatch>)> {
+    let target = shell.resolve_path_for(path, Permission::Read)?;
+    shell.check_file_policy(PolicyAction::Read, &target)?;
+    let file = std::fs::File::open(&target)
+        .map_err(|e| FshError::ShellError(format!("Cannot open '{}': {}", path, e)))?;
+    let total_size = file.metadata()?.len();
+
+    let length = length.unwrap_or(u64::MAX).min(MAX_FILE_CHUNK_SIZE as u64);
+    let mut reader = dlp.reader(file, offset)?;
+    let mut data = Vec::new();
+    (&mut reader).take(length).read_to_end(&mut data)?;
+    Ok((data, total_size, reader.matches().to_vec()))
+}
+
+/// Checksum of what the client reads from a file, so a redacted download
+/// still verifies.
+fn checksum_inspected(
+    shell: &SandboxedShell,
+    dlp: &DlpScanner,
+    path: &str,
+    algorithm: ChecksumAlgorithm,
+    offset: Option<u64>,
+    length: Option<u64>,
+) -> FshResult<(Checksum, u64)> {
+    let target = shell.resolve_path_for(path, Permission::Read)?;
+    shell.check_file_policy(PolicyAction::Read, &target)?;
+    let file = std::fs::File::open(&target)
+        .map_err(|e| FshError::ShellError(format!("Cannot open '{}': {}", path, e)))?;
+
+    let mut reader = dlp.reader(file, offset.unwrap_or(0))?;
+    let checksum = algorithm.digest_reader((&mut reader).take(length.unwrap_or(u64::MAX)))?;
+    match dlp_blocked(reader.matches()) {
+        Some(blocked) => Err(blocked.blocked_error()),
+        None => Ok(checksum),
+    }
+}
+
+/// Write half of a session's framed connection, mirrored to its observers and
+/// participants.
+pub type FrameSink = MirroredSink;
+
+/// Read half of a session's framed connection.
+pub type FrameSource = SplitStream<FshFramed<FshStream>>;
+
+/// What the message loop woke up for.
+enum Incoming {
+    Owner(FshResult<FshMessage>),
+    Participant(ParticipantInput),
+}
 
 #[derive(Debug)]
 pub struct Session {
     id: String,
-    stream: Arc<Mutex<TcpStream>>,
-    folder_info: FolderInfo,
+    writer: Arc<Mutex<FrameSink>>,
+    /// The folder the session is in; it changes when the client switches folders.
+    folder_info: Arc<std::sync::RwLock<FolderInfo>>,
     folder_config: FolderConfig,
     client_info: ClientInfo,
+    client_addr: String,
     shell: Arc<Mutex<SandboxedShell>>,
     active: Arc<RwLock<bool>>,
+    closed: watch::Receiver<bool>,
     created_at: chrono::DateTime<chrono::Utc>,
+    last_activity: Arc<RwLock<Instant>>,
+    idle_timeout: Option<Duration>,
+    keepalive: KeepaliveConfig,
+    transfer_limits: TransferLimits,
+    commands: CommandLimits,
+    audit: Option<ClientAudit>,
+    hooks: CommandHooks,
+    approval: ApprovalGate,
+    honeypot: Option<HoneypotMonitor>,
+    rate_limits: Option<ClientRateLimits>,
+    usage: SessionUsage,
+    history: SessionHistory,
+    events: EventBus,
+    plugins: SessionPlugins,
+    malware_scanner: Option<Arc<MalwareScanner>>,
+    dlp: Option<Arc<DlpScanner>>,
+    /// The token that authenticated the session; revoking it closes the session.
+    token_id: Option<String>,
+    /// Features negotiated with the client.
+    capabilities: Capabilities,
+    /// Copies the session's terminal activity to observers the owner allows.
+    mirror: SessionMirror,
+    /// Other clients the owner lets run commands in the session.
+    collaboration: SessionCollaboration,
+    /// The honeypot copy the shell runs in; removed with the session.
+    _snapshot: Option<HoneypotSnapshot>,
 }
 
 impl Session {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         id: String,
-        stream: TcpStream,
+        framed: FshFramed<FshStream>,
         folder_info: FolderInfo,
         folder_config: FolderConfig,
         client_info: ClientInfo,
+        client_addr: String,
+        idle_timeout: Option<Duration>,
+        keepalive: KeepaliveConfig,
+        transfer_limits: TransferLimits,
+        commands: CommandLimits,
+        audit: Option<ClientAudit>,
+        approvals: Option<Arc<ApprovalQueue>>,
+        rate_limits: Option<ClientRateLimits>,
+        usage: SessionUsage,
+        history: SessionHistory,
+        events: EventBus,
+        plugins: Plugins,
+        malware_scanner: Option<Arc<MalwareScanner>>,
+        dlp: Option<Arc<DlpScanner>>,
+        folder_access: Option<FolderAccess>,
+        operations: Arc<OperationLog>,
+        idle_lock: Option<IdleLock>,
+        mut persistence: Option<SessionPersistence>,
     ) -> FshResult<Self> {
-        // Create sandboxed shell
-        let sandbox_config = SandboxConfig::new(
-            folder_config.get_path(),
-            folder_info.shell_type.clone(),
-        )
-        .with_permissions(folder_info.permissions.clone())
-        .with_allowed_commands(folder_config.allowed_commands.clone())
-        .with_blocked_commands(folder_config.blocked_commands.clone());
+        // A honeypot session works on its own copy of the folder
+        let snapshot = if folder_config.honeypot.enabled {
+            Some(HoneypotSnapshot::create(&folder_config.get_path(), &id)?)
+        } else {
+            None
+        };
+        let root_path = match &snapshot {
+            Some(snapshot) => snapshot.path().to_path_buf(),
+            None => folder_config.get_path(),
+        };
 
-        // Add environment variables
-        let sandbox_config = folder_config.environment_vars.iter()
-            .fold(sandbox_config, |config, (key, value)| {
-                config.add_environment_var(key.clone(), value.clone())
-            });
+        let mut shell = Self::build_shell(root_path, &folder_info, &folder_config).await?;
+        shell.track_processes(usage.processes());
+        if let Some((persistence, saved)) = persistence.as_mut().and_then(|p| p.take_restored().map(|saved| (p, saved))) {
+            Self::restore_saved(&id, &mut shell, &history, persistence, saved);
+        }
 
-        let shell = SandboxedShell::new(sandbox_config)?;
+        // Hooks identify the client by its address
+        let user = client_addr.parse::<std::net::SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| client_addr.clone());
+        let hooks = CommandHooks::new(folder_config.hooks.clone(), &id, &folder_config.name, &user);
+        let approval = ApprovalGate::new(
+            folder_config.requires_approval.clone(), approvals, &id, &folder_config.name, &client_addr,
+        );
+        let honeypot = folder_config.honeypot.enabled.then(|| HoneypotMonitor::new(
+            &folder_config.name, &id, &client_addr, audit.clone(), folder_config.honeypot.alert_webhook_url.clone(),
+        ));
+        let plugins = plugins.for_session(&id, &folder_config.name, &client_addr, audit.clone());
+        let (writer, reader) = framed.split();
+        let mirror = SessionMirror::new();
+        let writer = MirroredSink::new(writer, mirror.clone());
+        let (collaboration, participant_input) = SessionCollaboration::new();
+        let (closed_tx, closed) = watch::channel(false);
 
         let session = Self {
             id: id.clone(),
-            stream: Arc::new(Mutex::new(stream)),
-            folder_info,
+            writer: Arc::new(Mutex::new(writer)),
+            folder_info: Arc::new(std::sync::RwLock::new(folder_info)),
             folder_config,
             client_info,
+            client_addr,
             shell: Arc::new(Mutex::new(shell)),
             active: Arc::new(RwLock::new(true)),
+            closed,
             created_at: chrono::Utc::now(),
+            last_activity: Arc::new(RwLock::new(Instant::now())),
+            idle_timeout,
+            keepalive,
+            transfer_limits,
+            commands,
+            audit,
+            hooks,
+            approval,
+            honeypot,
+            rate_limits,
+            usage,
+            history,
+            events,
+            plugins,
+            malware_scanner,
+            dlp,
+            token_id: None,
+            capabilities: Capabilities::default(),
+            mirror,
+            collaboration,
+            _snapshot: snapshot,
         };
 
-        // Send session ready message
+        if let Some(honeypot) = &session.honeypot {
+            honeypot.record("folder_bind", &session.client_info.app_name).await;
+        }
+
+        // Announce the session, then report it ready
+        session.send_session_start().await?;
         session.send_session_ready().await?;
 
         // Start message handling loop
-        session.start_message_loop().await?;
+        session.start_message_loop(reader, participant_input, folder_access, operations, idle_lock, persistence, closed_tx).await?;
 
         info!("Session {} initialized successfully", id);
         Ok(session)
     }
 
+    /// Pick up where a session saved before a restart left off, skipping
+    /// whatever no longer applies.
+    fn restore_saved(
+        session_id: &str,
+        shell: &mut SandboxedShell,
+        history: &SessionHistory,
+        persistence: &mut SessionPersistence,
+        saved: SavedSession,
+    ) {
+        for (name, value) in saved.environment {
+            match shell.set_env(&name, value.clone()) {
+                Ok(()) => persistence.set_env(&name, value),
+                Err(e) => warn!("Not restoring {} in session {}: {}", name, session_id, e),
+            }
+        }
+        if let Err(e) = shell.restore_working_directory(&saved.working_directory) {
+            warn!("Session {} starts in the folder root: {}", session_id, e);
+        }
+        history.restore(saved.history);
+        info!("Session {} resumed in {}", session_id, saved.working_directory.display());
+    }
+
+    /// A sandboxed shell for `folder_config`, working in `root_path`.
+    pub(crate) async fn build_shell(root_path: std::path::PathBuf, folder_info: &FolderInfo, folder_config: &FolderConfig) -> FshResult<SandboxedShell> {
+        let blocked_commands = PatternSet::new(&folder_config.blocked_commands)?;
+        let sandbox_config = SandboxConfig::new(
+            root_path,
+            folder_info.shell_type.clone(),
+        )
+        .with_permissions(folder_info.permissions.clone())
+        .with_allowed_commands(folder_config.allowed_commands.clone())
+        .with_blocked_commands(blocked_commands)
+        .with_symlink_policy(folder_config.symlink_policy)
+        .with_inherit_environment(folder_config.inherit_environment)
+        .with_trash(folder_config.trash.clone())
+        .with_command_policies(folder_config.command_policies.clone())
+        .with_wasi(folder_config.wasi.clone());
+        let sandbox_config = match &folder_config.env_allowlist {
+            Some(allowlist) => sandbox_config.with_env_allowlist(allowlist.clone()),
+            None => sandbox_config,
+        };
+        let sandbox_config = match &folder_config.settable_env {
+            Some(settable) => sandbox_config.with_settable_env(settable.clone()),
+            None => sandbox_config,
+        };
+        let sandbox_config = match &folder_config.policy {
+            Some(policy) => sandbox_config.with_policy(Policy::new(policy)?),
+            None => sandbox_config,
+        };
+        let sandbox_config = match &folder_config.output_encoding {
+            Some(encoding) => sandbox_config.with_output_encoding(OutputEncoding::parse(encoding)?),
+            None => sandbox_config,
+        };
+        let sandbox_config = if folder_config.container.enabled {
+            let container = FolderContainer::new(
+                &folder_config.container, &folder_config.name, &sandbox_config.root_path, !folder_config.can_write(),
+            );
+            container.ensure_running().await?;
+            sandbox_config.with_container(container)
+        } else {
+            sandbox_config
+        };
+
+        // Add environment variables
+        let sandbox_config = folder_config.environment_vars.iter()
+            .fold(sandbox_config, |config, (key, value)| {
+                config.add_environment_var(key.clone(), value.clone())
+            });
+
+        SandboxedShell::new(sandbox_config)
+    }
+
+    /// Bind the folder in `grant` into `shell` as `/<name>`, next to the
+    /// session's own folder `current`.
+    async fn bind_root(shell: &Mutex<SandboxedShell>, grant: FolderGrant, current: &str) -> FshResult<RootBoundMessage> {
+        let folder = &grant.folder_config;
+        if folder.name == current {
+            return Err(FshError::InvalidPath(format!("'{}' is already the session's folder", folder.name)));
+        }
+        // Neither would apply to the folder's files through another session's shell
+        if folder.policy.is_some() {
+            return Err(FshError::PermissionDenied(format!("Folder '{}' has a file policy and can't be bound", folder.name)));
+        }
+        if folder.container.enabled {
+            return Err(FshError::PermissionDenied(format!("Folder '{}' runs in a container and can't be bound", folder.name)));
+        }
+
+        shell.lock().await.bind_root(&folder.name, &folder.get_path(), grant.folder_info.permissions.clone(), &folder.trash)?;
+        Ok(RootBoundMessage { root: format!("/{}", folder.name), folder_info: grant.folder_info })
+    }
+
+    /// Record the token that authenticated the session.
+    pub fn with_token_id(mut self, token_id: String) -> Self {
+        self.token_id = Some(token_id);
+        self
+    }
+
+    /// Record the features negotiated with the client.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
 
-    pub fn folder_info(&self) -> &FolderInfo {
-        &self.folder_info
+    pub fn token_id(&self) -> Option<&str> {
+        self.token_id.as_deref()
+    }
+
+    pub fn folder_info(&self) -> FolderInfo {
+        self.folder_info.read().unwrap_or_else(|e| e.into_inner()).clone()
     }
 
     pub fn client_info(&self) -> &ClientInfo {
         &self.client_info
     }
 
+    pub fn mirror(&self) -> &SessionMirror {
+        &self.mirror
+    }
+
+    pub fn collaboration(&self) -> &SessionCollaboration {
+        &self.collaboration
+    }
+
+    pub async fn working_directory(&self) -> String {
+        self.shell.lock().await.working_directory().to_string_lossy().to_string()
+    }
+
+    pub fn client_addr(&self) -> &str {
+        &self.client_addr
+    }
+
     pub fn created_at(&self) -> chrono::DateTime<chrono::Utc> {
         self.created_at
     }
 
+    /// What the session has done so far.
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            session_id: self.id.clone(),
+            folder: self.folder_info().name,
+            client_addr: self.client_addr.clone(),
+            started_at: self.created_at,
+            usage: self.usage.snapshot(),
+            resources: self.usage.resources(),
+        }
+    }
+
+    pub fn sample_resources(&self, table: &ProcessTable) {
+        self.usage.sample_resources(table);
+    }
+
     pub async fn is_active(&self) -> bool {
         *self.active.read().await
     }
 
+    /// Send an operator's notice to the client, returning false without
+    /// sending anything if the client can't display notices.
+    pub async fn notify(&self, message: &str) -> FshResult<bool> {
+        if !self.capabilities.supports(FEATURE_SERVER_NOTICE) {
+            return Ok(false);
+        }
+        let notice = FshMessage::ServerNotice(ServerNoticeMessage {
+            message: message.to_string(),
+            sent_at: chrono::Utc::now(),
+        });
+        let mut writer = self.writer.lock().await;
+        FshCodec::write_message(&mut *writer, notice).await?;
+        Ok(true)
+    }
+
+    /// Send the outcome of a watch rule's run to the client, returning false
+    /// without sending anything if the client can't display it.
+    pub async fn send_watch_run(&self, run: &WatchRunMessage) -> FshResult<bool> {
+        if !self.capabilities.supports(FEATURE_WATCH_RUNS) {
+            return Ok(false);
+        }
+        let mut writer = self.writer.lock().await;
+        FshCodec::write_message(&mut *writer, FshMessage::WatchRun(run.clone())).await?;
+        Ok(true)
+    }
+
+    /// Wait until the session's message loop has finished.
+    pub async fn wait_closed(&self) {
+        let mut closed = self.closed.clone();
+        // An error means the loop dropped its sender, which also means it is done
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Time since the client last sent anything other than a keepalive.
+    pub async fn idle_duration(&self) -> Duration {
+        self.last_activity.read().await.elapsed()
+    }
+
+    async fn send_session_start(&self) -> FshResult<()> {
+        let environment_vars = self.shell.lock().await.environment_vars().clone();
+
+        let message = FshMessage::SessionStart(SessionStartMessage {
+            session_id: self.id.clone(),
+            environment_vars,
+        });
+
+        let mut writer = self.writer.lock().await;
+        FshCodec::write_message(&mut *writer, message).await?;
+
+        debug!("Session start message sent for session {}", self.id);
+        Ok(())
+    }
+
     async fn send_session_ready(&self) -> FshResult<()> {
         let shell = self.shell.lock().await;
         let prompt = shell.get_shell_prompt();
@@ -99,335 +508,2254 @@ impl Session {
             working_directory: working_dir,
         });
 
-        let mut stream = self.stream.lock().await;
-        FshCodec::write_message(&mut *stream, &message).await?;
+        let mut writer = self.writer.lock().await;
+        FshCodec::write_message(&mut *writer, message).await?;
 
         debug!("Session ready message sent for session {}", self.id);
         Ok(())
     }
 
-    async fn start_message_loop(&self) -> FshResult<()> {
+    #[allow(clippy::too_many_arguments)]
+    async fn start_message_loop(
+        &self,
+        reader: FrameSource,
+        participant_input: mpsc::Receiver<ParticipantInput>,
+        folder_access: Option<FolderAccess>,
+        operations: Arc<OperationLog>,
+        idle_lock: Option<IdleLock>,
+        persistence: Option<SessionPersistence>,
+        closed_tx: watch::Sender<bool>,
+    ) -> FshResult<()> {
         let session_id = self.id.clone();
-        let stream = Arc::clone(&self.stream);
+        let folder_info = Arc::clone(&self.folder_info);
+        let writer = Arc::clone(&self.writer);
         let shell = Arc::clone(&self.shell);
         let active = Arc::clone(&self.active);
         let folder_config = self.folder_config.clone();
-
+        let last_activity = Arc::clone(&self.last_activity);
+        let idle_timeout = self.idle_timeout;
+        let heartbeat = Heartbeat::new(&self.keepalive);
+        let transfer_limits = self.transfer_limits.clone();
+        let commands = self.commands.clone();
+        let audit = self.audit.clone();
+        let hooks = self.hooks.clone();
+        let approval = self.approval.clone();
+        let honeypot = self.honeypot.clone();
+        let rate_limits = self.rate_limits.clone();
+        let usage = self.usage.clone();
+        let history = self.history.clone();
+        let mirror = self.mirror.clone();
+        let collaboration = self.collaboration.clone();
+        let events = self.events.clone();
+        let plugins = self.plugins.clone();
+        let malware_scanner = self.malware_scanner.clone();
+        let dlp = self.dlp.clone();
+
+        // Everything logged for the session carries its id and folder, inside
+        // the connection's span with the client's address
+        let span = info_span!("session", session_id = %session_id, folder = %folder_config.name);
         tokio::spawn(async move {
-            if let Err(e) = Self::message_loop(session_id, stream, shell, active, folder_config).await {
+            if let Err(e) = Self::message_loop(
+                session_id, reader, participant_input, writer, shell, active, folder_info, folder_config, folder_access, operations, last_activity,
+                idle_timeout, idle_lock, persistence, heartbeat,
+                transfer_limits, commands, audit, hooks, approval, honeypot, rate_limits, usage, history, mirror,
+                collaboration, events, plugins, malware_scanner, dlp,
+            ).await {
                 error!("Session message loop error: {}", e);
             }
-        });
+            let _ = closed_tx.send(true);
+        }.instrument(span));
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn message_loop(
         session_id: String,
-        stream: Arc<Mutex<TcpStream>>,
+        mut reader: FrameSource,
+        mut participant_input: mpsc::Receiver<ParticipantInput>,
+        writer: Arc<Mutex<FrameSink>>,
         shell: Arc<Mutex<SandboxedShell>>,
         active: Arc<RwLock<bool>>,
-        folder_config: FolderConfig,
+        folder_info: Arc<std::sync::RwLock<FolderInfo>>,
+        mut folder_config: FolderConfig,
+        folder_access: Option<FolderAccess>,
+        operations: Arc<OperationLog>,
+        last_activity: Arc<RwLock<Instant>>,
+        idle_timeout: Option<Duration>,
+        mut idle_lock: Option<IdleLock>,
+        mut persistence: Option<SessionPersistence>,
+        mut heartbeat: Heartbeat,
+        mut transfer_limits: TransferLimits,
+        mut commands: CommandLimits,
+        audit: Option<ClientAudit>,
+        mut hooks: CommandHooks,
+        mut approval: ApprovalGate,
+        honeypot: Option<HoneypotMonitor>,
+        rate_limits: Option<ClientRateLimits>,
+        usage: SessionUsage,
+        mut history: SessionHistory,
+        mirror: SessionMirror,
+        collaboration: SessionCollaboration,
+        events: EventBus,
+        plugins: SessionPlugins,
+        malware_scanner: Option<Arc<MalwareScanner>>,
+        dlp: Option<Arc<DlpScanner>>,
     ) -> FshResult<()> {
         debug!("Starting message loop for session {}", session_id);
 
+        let mut idle_warning_sent = false;
+        let mut command_id: u64 = 0;
+        let mut tail_task: Option<JoinHandle<()>> = None;
+        let jobs = JobTable::new();
+        let forwards = ForwardTable::new();
+        let mut participant_forwarder: Option<JoinHandle<()>> = None;
+
         while *active.read().await {
-            // Read message with timeout
-            let message = {
-                let mut stream = stream.lock().await;
-                match timeout(Duration::from_secs(30), FshCodec::read_message(&mut *stream)).await {
-                    Ok(Ok(msg)) => msg,
-                    Ok(Err(e)) => {
-                        error!("Message read error in session {}: {}", session_id, e);
-                        break;
+            // Save what resuming the session after a restart needs, whenever it changes
+            if let Some(persistence) = persistence.as_mut() {
+                let working_directory = shell.lock().await.relative_working_directory();
+                persistence.save(&session_id, &folder_config.name, working_directory, history.session_entries());
+            }
+
+            // Ping the client or give up on it once its keepalive deadline passes
+            match heartbeat.poll(Instant::now()) {
+                HeartbeatAction::SendPing => {
+                    if heartbeat.missed() > 0 {
+                        debug!("Session {} missed {} keepalive(s)", session_id, heartbeat.missed());
                     }
-                    Err(_) => {
-                        // Timeout - send ping to check if client is still alive
-                        if let Err(e) = FshCodec::write_message(&mut *stream, &FshMessage::Ping).await {
-                            error!("Failed to send ping in session {}: {}", session_id, e);
-                            break;
-                        }
-                        continue;
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, FshMessage::Ping).await {
+                        error!("Failed to send ping in session {}: {}", session_id, e);
+                        break;
                     }
                 }
-            };
-
-            debug!("Received message in session {}: {:?}", session_id, message.message_type());
+                HeartbeatAction::Close => {
+                    warn!("Session {} missed {} consecutive keepalives, closing", session_id, heartbeat.missed());
+                    break;
+                }
+                HeartbeatAction::Wait => {}
+            }
 
-            match message {
-                FshMessage::Command(cmd_msg) => {
-                    if let Err(e) = Self::handle_command(
-                        &session_id,
-                        cmd_msg,
-                        Arc::clone(&shell),
-                        Arc::clone(&stream),
-                        &folder_config,
-                    ).await {
-                        error!("Command handling error in session {}: {}", session_id, e);
+            // Wake up for whichever comes first: a keepalive deadline or an idle deadline
+            let mut wait = heartbeat.next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::MAX)
+                .max(Duration::from_millis(10));
+
+            // Enforce the idle timeout before waiting for the next message
+            if let Some(idle_timeout) = idle_timeout {
+                let idle = last_activity.read().await.elapsed();
+                if idle >= idle_timeout {
+                    info!("Session {} idle for {}s, closing", session_id, idle.as_secs());
+                    let disconnect_msg = FshMessage::Disconnect(DisconnectMessage {
+                        reason: "Session closed due to inactivity".to_string(),
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, disconnect_msg).await {
+                        warn!("Failed to send idle disconnect in session {}: {}", session_id, e);
                     }
+                    break;
                 }
 
-                FshMessage::FileList(list_msg) => {
-                    if let Err(e) = Self::handle_file_list(
-                        &session_id,
-                        list_msg,
-                        Arc::clone(&shell),
-                        Arc::clone(&stream),
-                    ).await {
-                        error!("File list error in session {}: {}", session_id, e);
+                let warning_at = idle_timeout.saturating_sub(idle_warning_lead(idle_timeout));
+                if idle >= warning_at && !idle_warning_sent {
+                    let remaining = idle_timeout - idle;
+                    let warning_msg = FshMessage::Error(ErrorMessage {
+                        code: FshErrorCode::IdleWarning,
+                        message: format!(
+                            "Session will be closed in {} seconds due to inactivity",
+                            remaining.as_secs()
+                        ),
+                        details: Some(std::collections::HashMap::from([(
+                            "seconds_remaining".to_string(),
+                            remaining.as_secs().to_string(),
+                        )])),
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, warning_msg).await {
+                        error!("Failed to send idle warning in session {}: {}", session_id, e);
+                        break;
                     }
+                    idle_warning_sent = true;
                 }
 
-                FshMessage::FileRead(read_msg) => {
-                    if let Err(e) = Self::handle_file_read(
-                        &session_id,
-                        read_msg,
-                        Arc::clone(&shell),
-                        Arc::clone(&stream),
-                        &folder_config,
-                    ).await {
-                        error!("File read error in session {}: {}", session_id, e);
+                let next_deadline = if idle_warning_sent { idle_timeout } else { warning_at };
+                wait = wait.min(next_deadline.saturating_sub(idle).max(Duration::from_millis(10)));
+            }
+
+            // Lock the session once it has sat idle for the lock period
+            if let Some(lock) = idle_lock.as_mut() {
+                let idle = last_activity.read().await.elapsed();
+                if lock.is_due(idle) {
+                    info!("Session {} idle for {}s, locking", session_id, idle.as_secs());
+                    lock.lock();
+                    let locked_msg = FshMessage::SessionLocked(SessionLockedMessage {
+                        session_id: session_id.clone(),
+                        reason: "Session locked due to inactivity".to_string(),
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, locked_msg).await {
+                        error!("Failed to send lock notice in session {}: {}", session_id, e);
+                        break;
                     }
                 }
+                if !lock.is_locked() {
+                    wait = wait.min(lock.after().saturating_sub(idle).max(Duration::from_millis(10)));
+                }
+            }
+
+            // Read the owner's next message, or a participant's command, with timeout
+            let incoming = timeout(wait, async {
+                tokio::select! {
+                    message = FshCodec::read_message(&mut reader) => Incoming::Owner(message),
+                    Some(input) = participant_input.recv() => Incoming::Participant(input),
+                }
+            }).await;
+            let mut message = match incoming {
+                Ok(Incoming::Owner(Ok(msg))) => msg,
+                Ok(Incoming::Owner(Err(e))) => {
+                    error!("Message read error in session {}: {}", session_id, e);
+                    break;
+                }
+                Ok(Incoming::Participant(input)) => {
+                    *last_activity.write().await = Instant::now();
+                    idle_warning_sent = false;
+                    command_id += 1;
+
+                    let ParticipantInput { participant, command } = input;
+                    let span = info_span!("command", command_id, participant = %participant.name, command = %command.command);
+                    mirror.publish(Some(&participant.name), ObservedEvent::ParticipantCommand {
+                        participant: participant.name.clone(),
+                        command: command.clone(),
+                    });
+                    if let Some(audit) = &participant.audit {
+                        if let Err(e) = audit.logger.log_participant_command(
+                            audit.source_ip, session_id.clone(), &participant.name, command_line(&command),
+                        ).await {
+                            warn!("Failed to audit {}'s command in session {}: {}", participant.name, session_id, e);
+                        }
+                    }
 
-                FshMessage::FileWrite(write_msg) => {
-                    if let Err(e) = Self::handle_file_write(
+                    // Participants' commands pass the same checks as the owner's
+                    let mut message = FshMessage::Command(command);
+                    if let Err(e) = plugins.filter_message(&mut message).await {
+                        warn!("Command from {} in session {} refused by a plugin: {}", participant.name, session_id, e);
+                        let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+                        let mut writer = participant.writer.lock().await;
+                        if let Err(e) = FshCodec::write_message(&mut *writer, error_msg).await {
+                            warn!("Failed to send plugin error to {} in session {}: {}", participant.name, session_id, e);
+                        }
+                        continue;
+                    }
+                    let FshMessage::Command(cmd_msg) = message else {
+                        continue;
+                    };
+                    if let Err(e) = Self::handle_command(
                         &session_id,
-                        write_msg,
+                        cmd_msg,
                         Arc::clone(&shell),
-                        Arc::clone(&stream),
+                        Arc::clone(&participant.writer),
                         &folder_config,
-                    ).await {
-                        error!("File write error in session {}: {}", session_id, e);
+                        participant.audit.as_ref(),
+                        &hooks,
+                        &approval,
+                        &commands,
+                        &usage,
+                        &history,
+                        &events,
+                        dlp.as_ref(),
+                    ).instrument(span).await {
+                        // The participant may have left; the session carries on
+                        warn!("Command from {} in session {} failed: {}", participant.name, session_id, e);
                     }
+                    continue;
                 }
+                Err(_) => continue,
+            };
 
-                FshMessage::Ping => {
-                    let mut stream = stream.lock().await;
-                    if let Err(e) = FshCodec::write_message(&mut *stream, &FshMessage::Pong).await {
-                        error!("Failed to send pong in session {}: {}", session_id, e);
+            debug!("Received message in session {}: {:?}", session_id, message.message_type());
+            heartbeat.on_received();
+
+            // A locked session only lets the client unlock it or leave
+            if let Some(lock) = idle_lock.as_mut() {
+                if let FshMessage::SessionUnlock(unlock) = &message {
+                    let (response, close) = match lock.unlock(&unlock.token) {
+                        UnlockOutcome::Unlocked => {
+                            info!("Session {} unlocked", session_id);
+                            *last_activity.write().await = Instant::now();
+                            idle_warning_sent = false;
+                            (SessionUnlockResponseMessage { success: true, error_message: None }, false)
+                        }
+                        UnlockOutcome::Refused { attempts_left } => {
+                            warn!("Wrong token to unlock session {}", session_id);
+                            (SessionUnlockResponseMessage {
+                                success: false,
+                                error_message: Some(format!("Wrong token; {} attempt(s) left", attempts_left)),
+                            }, false)
+                        }
+                        UnlockOutcome::TooManyAttempts => {
+                            warn!("Too many wrong tokens to unlock session {}, closing", session_id);
+                            (SessionUnlockResponseMessage {
+                                success: false,
+                                error_message: Some("Too many wrong tokens; session closed".to_string()),
+                            }, true)
+                        }
+                    };
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, FshMessage::SessionUnlockResponse(response)).await {
+                        error!("Failed to answer unlock in session {}: {}", session_id, e);
                         break;
                     }
+                    if close {
+                        break;
+                    }
+                    continue;
                 }
 
-                FshMessage::Pong => {
-                    debug!("Received pong from session {}", session_id);
-                }
-
-                FshMessage::Disconnect(disconnect_msg) => {
-                    info!("Client requested disconnect for session {}: {}", session_id, disconnect_msg.reason);
-                    break;
+                if lock.is_locked() && !matches!(message, FshMessage::Ping | FshMessage::Pong | FshMessage::Disconnect(_)) {
+                    let error_msg = FshMessage::Error(ErrorMessage::from(&FshError::PermissionDenied(
+                        "Session is locked; re-enter your token to continue".to_string(),
+                    )));
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, error_msg).await {
+                        error!("Failed to refuse request in locked session {}: {}", session_id, e);
+                        break;
+                    }
+                    continue;
                 }
+            }
 
-                _ => {
-                    warn!("Unexpected message type in session {}: {:?}", session_id, message.message_type());
+            // A change the client may resend after reconnecting. One already
+            // made gets its first reply again rather than being made twice.
+            if let FshMessage::Operation(OperationMessage { operation_id, request }) = message {
+                let request = FshMessage::from(request);
+                let reply = match operations.begin(&folder_config.name, &operation_id) {
+                    OperationState::New => None,
+                    OperationState::InProgress => Some((false, OperationReply::Error(ErrorMessage::from(&FshError::NetworkError(
+                        format!("Operation {} is still in progress", operation_id),
+                    ))))),
+                    OperationState::Done(reply) => Some((true, reply)),
+                };
+                match reply {
+                    None => {
+                        writer.lock().await.begin_operation(PendingOperation {
+                            operation_id,
+                            request_type: request.message_type(),
+                            folder: folder_config.name.clone(),
+                            log: Arc::clone(&operations),
+                        });
+                        message = request;
+                    }
+                    Some((replayed, reply)) => {
+                        info!("Session {} answered a retry of operation {}", session_id, operation_id);
+                        let response = FshMessage::OperationResponse(OperationResponseMessage { operation_id, replayed, reply });
+                        let mut writer = writer.lock().await;
+                        if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                            error!("Failed to answer operation in session {}: {}", session_id, e);
+                            break;
+                        }
+                        continue;
+                    }
                 }
             }
-        }
-
-        // Mark session as inactive
-        *active.write().await = false;
-        info!("Session {} message loop ended", session_id);
-        Ok(())
-    }
-
-    async fn handle_command(
-        session_id: &str,
-        cmd_msg: CommandMessage,
-        shell: Arc<Mutex<SandboxedShell>>,
-        stream: Arc<Mutex<TcpStream>>,
-        folder_config: &FolderConfig,
-    ) -> FshResult<()> {
-        debug!("Executing command in session {}: {}", session_id, cmd_msg.command);
-
-        // Check permissions
-        if !folder_config.can_execute() {
-            let error_msg = FshMessage::Error(ErrorMessage {
-                error_type: "permission_denied".to_string(),
-                message: "Execute permission denied".to_string(),
-                details: None,
-            });
-
-            let mut stream = stream.lock().await;
-            FshCodec::write_message(&mut *stream, &error_msg).await?;
-            return Ok(());
-        }
-
-        let mut shell = shell.lock().await;
-
-        // Execute command
-        match shell.execute_command(&cmd_msg.command, &cmd_msg.args).await {
-            Ok((mut output_rx, mut result_rx)) => {
-                drop(shell); // Release the shell lock
 
-                // Handle output streaming
-                let stream_clone = Arc::clone(&stream);
-                let session_id_clone = session_id.to_string();
+            if let Some(honeypot) = &honeypot {
+                honeypot.record_message(&message).await;
+            }
 
-                tokio::spawn(async move {
-                    while let Some(output) = output_rx.recv().await {
-                        let output_msg = FshMessage::CommandOutput(CommandOutputMessage {
-                            session_id: session_id_clone.clone(),
-                            output_type: match output.output_type {
-                                crate::sandbox::OutputType::Stdout => OutputType::Stdout,
-                                crate::sandbox::OutputType::Stderr => OutputType::Stderr,
-                            },
-                            data: output.data.into_bytes(),
-                        });
+            // Keepalives don't count as user activity
+            if !matches!(message, FshMessage::Ping | FshMessage::Pong) {
+                *last_activity.write().await = Instant::now();
+                idle_warning_sent = false;
+            }
 
-                        let mut stream = stream_clone.lock().await;
-                        if let Err(e) = FshCodec::write_message(&mut *stream, &output_msg).await {
-                            error!("Failed to send command output: {}", e);
-                            break;
+            if let (Some(rate_limits), Some(kind)) = (&rate_limits, rate_limit_kind(&message)) {
+                if let Err(e) = rate_limits.check(kind).await {
+                    warn!("Session {} rate limited: {}", session_id, e);
+                    if let Some(audit) = &audit {
+                        if let Err(log_error) = audit.logger.log_rate_limit_exceeded(
+                            audit.source_ip, Some(session_id.clone()), &e.detail(),
+                        ).await {
+                            warn!("Failed to audit rate limit in session {}: {}", session_id, log_error);
                         }
                     }
-                });
-
-                // Wait for command completion
-                if let Some(result) = result_rx.recv().await {
-                    let complete_msg = FshMessage::CommandComplete(CommandCompleteMessage {
-                        session_id: session_id.to_string(),
-                        exit_code: result.exit_code,
-                        execution_time_ms: result.execution_time_ms,
-                    });
 
-                    let mut stream = stream.lock().await;
-                    FshCodec::write_message(&mut *stream, &complete_msg).await?;
+                    let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, error_msg).await {
+                        error!("Failed to send rate limit error in session {}: {}", session_id, e);
+                        break;
+                    }
+                    continue;
                 }
             }
-            Err(e) => {
-                error!("Command execution failed in session {}: {}", session_id, e);
 
-                let error_msg = FshMessage::Error(ErrorMessage {
-                    error_type: "command_error".to_string(),
-                    message: format!("Command execution failed: {}", e),
-                    details: None,
-                });
+            if let Err(e) = plugins.filter_message(&mut message).await {
+                warn!("Session {} request refused by a plugin: {}", session_id, e);
+                let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+                let mut writer = writer.lock().await;
+                if let Err(e) = FshCodec::write_message(&mut *writer, error_msg).await {
+                    error!("Failed to send plugin error in session {}: {}", session_id, e);
+                    break;
+                }
+                continue;
+            }
 
-                let mut stream = stream.lock().await;
-                FshCodec::write_message(&mut *stream, &error_msg).await?;
+            if let FshMessage::Command(cmd_msg) = &message {
+                mirror.publish(None, ObservedEvent::Command(cmd_msg.clone()));
             }
-        }
 
-        Ok(())
-    }
+            match message {
+                FshMessage::Command(cmd_msg) if cmd_msg.job_id.is_some() => {
+                    command_id += 1;
+                    let span = info_span!("command", command_id, job_id = cmd_msg.job_id, command = %cmd_msg.command);
+                    if let Err(e) = Self::handle_background_command(
+                        &session_id,
+                        cmd_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                        &jobs,
+                        &hooks,
+                        audit.as_ref(),
+                        &approval,
+                        &commands,
+                        &usage,
+                        &history,
+                        &events,
+                        dlp.as_ref(),
+                    ).instrument(span).await {
+                        error!("Background job error in session {}: {}", session_id, e);
+                    }
+                }
 
-    async fn handle_file_list(
-        session_id: &str,
-        list_msg: FileListMessage,
+                FshMessage::Command(cmd_msg) => {
+                    command_id += 1;
+                    let span = info_span!("command", command_id, command = %cmd_msg.command);
+                    if let Err(e) = Self::handle_command(
+                        &session_id,
+                        cmd_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                        audit.as_ref(),
+                        &hooks,
+                        &approval,
+                        &commands,
+                        &usage,
+                        &history,
+                        &events,
+                        dlp.as_ref(),
+                    ).instrument(span).await {
+                        error!("Command handling error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::JobList(_) => {
+                    let response = FshMessage::JobListResponse(JobListResponseMessage {
+                        jobs: jobs.list(),
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send job list in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::JobKill(kill_msg) => {
+                    info!("Killing job %{} in session {}", kill_msg.job_id, session_id);
+                    let response = FshMessage::JobKillResponse(match jobs.kill(kill_msg.job_id) {
+                        Ok(()) => JobKillResponseMessage { success: true, error_message: None },
+                        Err(e) => JobKillResponseMessage { success: false, error_message: Some(e.to_string()) },
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send job kill response in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::EnvSet(set_msg) => {
+                    let result = shell.lock().await.set_env(&set_msg.name, set_msg.value.clone());
+                    let response = match result {
+                        Ok(()) => {
+                            info!("Session {} set {}", session_id, set_msg.name);
+                            if let Some(persistence) = persistence.as_mut() {
+                                persistence.set_env(&set_msg.name, set_msg.value.clone());
+                            }
+                            FshMessage::EnvSetResponse(EnvSetResponseMessage { name: set_msg.name, value: set_msg.value })
+                        }
+                        Err(e) => {
+                            warn!("Refused to set {} in session {}: {}", set_msg.name, session_id, e);
+                            FshMessage::Error(ErrorMessage::from(&e))
+                        }
+                    };
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send env set response in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::EnvGet(get_msg) => {
+                    let variables = {
+                        let shell = shell.lock().await;
+                        let environment = shell.environment_vars();
+                        match &get_msg.name {
+                            Some(name) => environment.get_key_value(name)
+                                .map(|(name, value)| (name.clone(), value.clone()))
+                                .into_iter()
+                                .collect(),
+                            None => environment.clone(),
+                        }
+                    };
+                    let response = FshMessage::EnvGetResponse(EnvGetResponseMessage { variables });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send env get response in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::ObserverConsent(consent_msg) => {
+                    mirror.set_consent(consent_msg.allow);
+                    info!("Session {} {} observers", session_id, if consent_msg.allow { "allows" } else { "no longer allows" });
+                    let response = FshMessage::ObserverConsentResponse(ObserverConsentResponseMessage {
+                        allow: consent_msg.allow,
+                        observers: mirror.observers() as u32,
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to confirm observer consent in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::CollaboratorConsent(consent_msg) => {
+                    collaboration.set_consent(consent_msg.allow);
+                    info!("Session {} {} collaborators", session_id, if consent_msg.allow { "allows" } else { "no longer allows" });
+
+                    // Show the owner what participants do while any may join
+                    if let Some(forwarder) = participant_forwarder.take() {
+                        forwarder.abort();
+                    }
+                    if consent_msg.allow {
+                        participant_forwarder = Some(Self::spawn_participant_forwarder(
+                            session_id.clone(), mirror.subscribe(), Arc::clone(&writer),
+                        ));
+                    }
+
+                    let response = FshMessage::CollaboratorConsentResponse(CollaboratorConsentResponseMessage {
+                        allow: consent_msg.allow,
+                        participants: collaboration.participants() as u32,
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to confirm collaborator consent in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FolderSwitch(switch_msg) => {
+                    let target = switch_msg.target_folder.clone();
+                    let current = folder_info.read().unwrap_or_else(|e| e.into_inner()).name.clone();
+                    let switched = match &folder_access {
+                        // A honeypot keeps its intruder where it is
+                        Some(_) if honeypot.is_some() => Err(FshError::FolderNotFound(target.clone())),
+                        Some(access) => match access.grant(&target, switch_msg.preferred_shell, &current).await {
+                            Ok(grant) => Self::build_shell(grant.folder_config.get_path(), &grant.folder_info, &grant.folder_config).await
+                                .map(|new_shell| (grant, new_shell)),
+                            Err(e) => Err(e),
+                        },
+                        None => Err(FshError::ProtocolError("This session can't switch folders".to_string())),
+                    };
+
+                    let response = match switched {
+                        Ok((grant, new_shell)) => {
+                            // Nothing started in the old folder outlives the switch
+                            if let Some(task) = tail_task.take() {
+                                task.abort();
+                            }
+                            jobs.kill_all();
+                            forwards.close_all();
+
+                            let (shell_prompt, working_directory, environment_vars) = {
+                                let mut shell = shell.lock().await;
+                                *shell = new_shell;
+                                shell.track_processes(usage.processes());
+                                (
+                                    shell.get_shell_prompt(),
+                                    shell.working_directory().to_string_lossy().to_string(),
+                                    shell.environment_vars().clone(),
+                                )
+                            };
+                            hooks = hooks.for_folder(grant.folder_config.hooks.clone(), &grant.folder_config.name);
+                            approval = approval.for_folder(grant.folder_config.requires_approval.clone(), &grant.folder_config.name);
+                            transfer_limits = grant.transfer_limits;
+                            commands = commands.for_folder(&grant.folder_config);
+                            history = grant.history;
+                            if let Some(persistence) = persistence.as_mut() {
+                                persistence.clear_env();
+                            }
+                            folder_config = grant.folder_config;
+                            *folder_info.write().unwrap_or_else(|e| e.into_inner()) = grant.folder_info.clone();
+
+                            info!("Session {} switched from folder '{}' to '{}'", session_id, current, folder_config.name);
+                            FshMessage::FolderSwitched(FolderSwitchedMessage {
+                                folder_info: grant.folder_info,
+                                shell_prompt,
+                                working_directory,
+                                environment_vars,
+                            })
+                        }
+                        Err(e) => {
+                            warn!("Session {} can't switch to folder '{}': {}", session_id, target, e);
+                            FshMessage::Error(ErrorMessage::from(&e))
+                        }
+                    };
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send folder switch response in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::RootBind(bind_msg) => {
+                    let current = folder_info.read().unwrap_or_else(|e| e.into_inner()).name.clone();
+                    let bound = match &folder_access {
+                        Some(_) if honeypot.is_some() => Err(FshError::FolderNotFound(bind_msg.folder.clone())),
+                        Some(access) => match access.grant(&bind_msg.folder, None, &current).await {
+                            Ok(grant) => Self::bind_root(&shell, grant, &current).await,
+                            Err(e) => Err(e),
+                        },
+                        None => Err(FshError::ProtocolError("This session can't bind folders".to_string())),
+                    };
+
+                    let response = match bound {
+                        Ok(bound) => {
+                            info!("Session {} bound folder '{}' as {}", session_id, bound.folder_info.name, bound.root);
+                            FshMessage::RootBound(bound)
+                        }
+                        Err(e) => {
+                            warn!("Session {} can't bind folder '{}': {}", session_id, bind_msg.folder, e);
+                            FshMessage::Error(ErrorMessage::from(&e))
+                        }
+                    };
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send root bind response in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::HistoryRequest(history_msg) => {
+                    let entries = history.entries(history_msg.limit.map(|limit| limit as usize));
+                    let response = FshMessage::HistoryResponse(HistoryResponseMessage { entries });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send history in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::SessionInfoRequest(_) => {
+                    let (folder, working_directory) = {
+                        let shell = shell.lock().await;
+                        (
+                            folder_info.read().unwrap_or_else(|e| e.into_inner()).name.clone(),
+                            shell.relative_working_directory().to_string_lossy().to_string(),
+                        )
+                    };
+                    let response = FshMessage::SessionInfo(SessionInfoMessage {
+                        session_id: session_id.clone(),
+                        folder,
+                        working_directory,
+                        commands_run: usage.snapshot().commands_run,
+                        resources: usage.resources(),
+                    });
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, response).await {
+                        error!("Failed to send session info in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileList(list_msg) => {
+                    let span = info_span!("file_op", op = "list", path = %list_msg.path);
+                    if let Err(e) = Self::handle_file_list(
+                        &session_id,
+                        list_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                    ).instrument(span).await {
+                        error!("File list error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileChecksum(checksum_msg) => {
+                    let span = info_span!("file_op", op = "checksum", path = %checksum_msg.file_path);
+                    if let Err(e) = Self::handle_file_checksum(
+                        &session_id,
+                        checksum_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        dlp.as_deref(),
+                    ).instrument(span).await {
+                        error!("File checksum error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileStat(stat_msg) => {
+                    let span = info_span!("file_op", op = "stat", path = %stat_msg.path);
+                    if let Err(e) = Self::handle_file_stat(
+                        &session_id,
+                        stat_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                    ).instrument(span).await {
+                        error!("File stat error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileDelete(delete_msg) => {
+                    let span = info_span!("file_op", op = "delete", path = %delete_msg.file_path);
+                    if let Err(e) = Self::handle_file_delete(
+                        &session_id,
+                        delete_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                    ).instrument(span).await {
+                        error!("File delete error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileMkdir(mkdir_msg) => {
+                    let span = info_span!("file_op", op = "mkdir", path = %mkdir_msg.path);
+                    if let Err(e) = Self::handle_file_mkdir(
+                        &session_id,
+                        mkdir_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                    ).instrument(span).await {
+                        error!("Directory creation error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileRestore(restore_msg) => {
+                    let span = info_span!("file_op", op = "restore", path = %restore_msg.target);
+                    if let Err(e) = Self::handle_file_restore(
+                        &session_id,
+                        restore_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                    ).instrument(span).await {
+                        error!("File restore error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::GitStatus(status_msg) => {
+                    let span = info_span!("file_op", op = "git_status", path = ?status_msg.path);
+                    if let Err(e) = Self::handle_git_status(
+                        &session_id,
+                        status_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                    ).instrument(span).await {
+                        error!("Git status error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::GitDiff(diff_msg) => {
+                    let span = info_span!("file_op", op = "git_diff", path = ?diff_msg.path);
+                    if let Err(e) = Self::handle_git_diff(
+                        &session_id,
+                        diff_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                        dlp.as_deref(),
+                        audit.as_ref(),
+                    ).instrument(span).await {
+                        error!("Git diff error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::GitLog(log_msg) => {
+                    let span = info_span!("file_op", op = "git_log", path = ?log_msg.path);
+                    if let Err(e) = Self::handle_git_log(
+                        &session_id,
+                        log_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                    ).instrument(span).await {
+                        error!("Git log error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileRead(read_msg) => {
+                    let span = info_span!("file_op", op = "read", path = %read_msg.file_path);
+                    if let Err(e) = Self::handle_file_read(
+                        &session_id,
+                        read_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                        &transfer_limits,
+                        &usage,
+                        dlp.as_deref(),
+                        audit.as_ref(),
+                    ).instrument(span).await {
+                        error!("File read error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileWrite(write_msg) => {
+                    let span = info_span!("file_op", op = "write", path = %write_msg.file_path);
+                    if let Err(e) = Self::handle_file_write(
+                        &session_id,
+                        write_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        &folder_config,
+                        &transfer_limits,
+                        &usage,
+                        malware_scanner.as_deref(),
+                        audit.as_ref(),
+                    ).instrument(span).await {
+                        error!("File write error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::FileTail(tail_msg) => {
+                    // Only one file is followed at a time; a new request replaces the old one
+                    if let Some(task) = tail_task.take() {
+                        task.abort();
+                    }
+
+                    let span = info_span!("file_op", op = "tail", path = %tail_msg.file_path);
+                    match Self::handle_file_tail(
+                        &session_id,
+                        tail_msg,
+                        Arc::clone(&shell),
+                        Arc::clone(&writer),
+                        dlp.as_ref(),
+                        audit.as_ref(),
+                    ).instrument(span).await {
+                        Ok(task) => tail_task = task,
+                        Err(e) => error!("File tail error in session {}: {}", session_id, e),
+                    }
+                }
+
+                FshMessage::FileTailStop(_) => {
+                    if let Some(task) = tail_task.take() {
+                        task.abort();
+                        let end_msg = FshMessage::FileTailEnd(FileTailEndMessage {
+                            session_id: session_id.clone(),
+                            error_message: None,
+                        });
+                        let mut writer = writer.lock().await;
+                        if let Err(e) = FshCodec::write_message(&mut *writer, end_msg).await {
+                            error!("Failed to end file tail in session {}: {}", session_id, e);
+                            break;
+                        }
+                    }
+                }
+
+                FshMessage::PortForwardOpen(open_msg) => {
+                    let span = info_span!("port_forward", channel_id = open_msg.channel_id, port = open_msg.port);
+                    if let Err(e) = Self::handle_port_forward_open(
+                        &session_id,
+                        open_msg,
+                        Arc::clone(&writer),
+                        &folder_config,
+                        &forwards,
+                        audit.as_ref(),
+                    ).instrument(span).await {
+                        error!("Port forward error in session {}: {}", session_id, e);
+                    }
+                }
+
+                FshMessage::PortForwardData(data_msg) => {
+                    // Waits while the connection's queue is full
+                    if let Some(input) = forwards.input(data_msg.channel_id) {
+                        let _ = input.send(data_msg.data).await;
+                    }
+                }
+
+                FshMessage::PortForwardEof(eof_msg) => {
+                    forwards.close_input(eof_msg.channel_id);
+                }
+
+                FshMessage::Ping => {
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = FshCodec::write_message(&mut *writer, FshMessage::Pong).await {
+                        error!("Failed to send pong in session {}: {}", session_id, e);
+                        break;
+                    }
+                }
+
+                FshMessage::Pong => {
+                    debug!("Received pong from session {}", session_id);
+                }
+
+                FshMessage::Disconnect(disconnect_msg) => {
+                    info!("Client requested disconnect for session {}: {}", session_id, disconnect_msg.reason);
+                    // Unless the server closed the session first, e.g. to restart, it needn't be resumed
+                    if let (Some(persistence), true) = (&persistence, *active.read().await) {
+                        persistence.remove(&session_id);
+                    }
+                    break;
+                }
+
+                _ => {
+                    warn!("Unexpected message type in session {}: {:?}", session_id, message.message_type());
+                }
+            }
+
+            // An operation that failed before answering may be retried
+            writer.lock().await.end_operation();
+        }
+
+        if let Some(task) = tail_task {
+            task.abort();
+        }
+        if let Some(forwarder) = participant_forwarder {
+            forwarder.abort();
+        }
+
+        // Background jobs and forwarded connections don't outlive their session
+        jobs.kill_all();
+        forwards.close_all();
+
+        // Mark session as inactive
+        *active.write().await = false;
+        info!("Session {} message loop ended", session_id);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_command(
+        session_id: &str,
+        cmd_msg: CommandMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+        audit: Option<&ClientAudit>,
+        hooks: &CommandHooks,
+        approval: &ApprovalGate,
+        commands: &CommandLimits,
+        usage: &SessionUsage,
+        history: &SessionHistory,
+        events: &EventBus,
+        dlp: Option<&Arc<DlpScanner>>,
+    ) -> FshResult<()> {
+        debug!("Executing command in session {}: {}", session_id, cmd_msg.command);
+        let command_line = command_line(&cmd_msg);
+
+        // Check permissions
+        if !folder_config.can_execute() {
+            let error_msg = FshMessage::Error(ErrorMessage::new(
+                FshErrorCode::PermissionDenied,
+                "Execute permission denied",
+            ));
+
+            let mut writer = writer.lock().await;
+            FshCodec::write_message(&mut *writer, error_msg).await?;
+            return Ok(());
+        }
+
+        let (steps, is_macro) = match Self::expand_macro(session_id, &cmd_msg, folder_config, audit).await {
+            Some(Ok(steps)) => (steps, true),
+            Some(Err(e)) => {
+                let error_msg = FshMessage::Error(ErrorMessage::new(
+                    e.code(),
+                    format!("Macro expansion failed: {}", e.detail()),
+                ));
+
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, error_msg).await?;
+                return Ok(());
+            }
+            None => (vec![MacroStep { command: cmd_msg.command, args: cmd_msg.args }], false),
+        };
+
+        if let Err(e) = hooks.pre_command(&shell, &command_line).await {
+            info!("Command '{}' refused in session {}: {}", command_line, session_id, e);
+            let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+
+            let mut writer = writer.lock().await;
+            FshCodec::write_message(&mut *writer, error_msg).await?;
+            return Ok(());
+        }
+
+        // A macro needs approval if any of its steps does
+        let step_lines: Vec<String> = steps.iter().map(MacroStep::command_line).collect();
+        let lines = std::iter::once(command_line.as_str()).chain(step_lines.iter().map(String::as_str));
+        if approval.is_required(lines) {
+            if let Err(e) = Self::await_approval(session_id, &command_line, approval, &writer, None).await {
+                info!("Command '{}' refused in session {}: {}", command_line, session_id, e);
+                let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, error_msg).await?;
+                return Ok(());
+            }
+        }
+
+        // Held until every step has finished
+        let _slot = match Self::await_command_slot(session_id, commands, &writer, None).await {
+            Ok(slot) => slot,
+            Err(e) => {
+                info!("Command '{}' refused in session {}: {}", command_line, session_id, e);
+                let error_msg = FshMessage::Error(ErrorMessage::from(&e));
+
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, error_msg).await?;
+                return Ok(());
+            }
+        };
+
+        let previous_directory = shell.lock().await.working_directory().clone();
+        let start_time = Instant::now();
+        let mut exit_code = 0;
+
+        // Run the steps in order, stopping at the first failure like `&&`
+        for step in steps {
+            if is_macro {
+                // Echo each expanded step, like `set -x`
+                let echo_msg = FshMessage::CommandOutput(CommandOutputMessage {
+                    session_id: session_id.to_string(),
+                    output_type: OutputType::Stderr,
+                    data: format!("+ {}\n", step.command_line()).into_bytes().into(),
+                    job_id: None,
+                    binary: false,
+                });
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, echo_msg).await?;
+            }
+
+            let started = shell.lock().await.execute_command(&step.command, &step.args).await;
+            let (output_rx, mut result_rx) = match started {
+                Ok(receivers) => receivers,
+                Err(e) => {
+                    error!("Command execution failed in session {}: {}", session_id, e);
+                    usage.record_command(start_time.elapsed(), -1);
+                    history.record(session_id, &command_line, &previous_directory.to_string_lossy(), -1);
+                    events.emit(ServerEvent::CommandExecuted {
+                        session_id: session_id.to_string(),
+                        folder: folder_config.name.clone(),
+                        command: command_line.clone(),
+                        exit_code: -1,
+                        duration: start_time.elapsed(),
+                    });
+
+                    let error_msg = FshMessage::Error(ErrorMessage::new(
+                        e.code(),
+                        format!("Command execution failed: {}", e.detail()),
+                    ));
+
+                    let mut writer = writer.lock().await;
+                    FshCodec::write_message(&mut *writer, error_msg).await?;
+                    return Ok(());
+                }
+            };
+
+            // Handle output streaming
+            let forwarder = Self::spawn_output_forwarder(
+                session_id, None, output_rx, Arc::clone(&writer), dlp.cloned(), audit.cloned(),
+                OutputCap::for_folder(folder_config),
+            );
+
+            // Wait for command completion, then for its output to be flushed
+            // so CommandComplete is always the last message for the command
+            let result = result_rx.recv().await;
+            if let Err(e) = forwarder.await {
+                error!("Output forwarding task failed in session {}: {}", session_id, e);
+            }
+
+            // The next step starts wherever this one left the shell
+            shell.lock().await.sync_working_directory();
+
+            match result {
+                Some(result) => exit_code = result.exit_code,
+                None => return Ok(()),
+            }
+            if exit_code != 0 {
+                break;
+            }
+        }
+
+        hooks.post_command(&shell, &command_line, exit_code).await;
+        crate::telemetry::metrics().record_command(&folder_config.name, start_time.elapsed(), exit_code);
+        usage.record_command(start_time.elapsed(), exit_code);
+        history.record(session_id, &command_line, &previous_directory.to_string_lossy(), exit_code);
+        events.emit(ServerEvent::CommandExecuted {
+            session_id: session_id.to_string(),
+            folder: folder_config.name.clone(),
+            command: command_line.clone(),
+            exit_code,
+            duration: start_time.elapsed(),
+        });
+
+        let (working_directory, changed_msg) = {
+            let shell = shell.lock().await;
+            let working_directory = shell.working_directory().to_string_lossy().to_string();
+            let changed_msg = (*shell.working_directory() != previous_directory).then(|| {
+                FshMessage::WorkingDirChanged(WorkingDirChangedMessage {
+                    session_id: session_id.to_string(),
+                    working_directory: working_directory.clone(),
+                    shell_prompt: shell.get_shell_prompt(),
+                })
+            });
+            (working_directory, changed_msg)
+        };
+
+        let complete_msg = FshMessage::CommandComplete(CommandCompleteMessage {
+            session_id: session_id.to_string(),
+            exit_code,
+            execution_time_ms: start_time.elapsed().as_millis() as u64,
+            working_directory,
+            job_id: None,
+        });
+
+        let mut writer = writer.lock().await;
+        if let Some(changed_msg) = changed_msg {
+            FshCodec::write_message(&mut *writer, changed_msg).await?;
+        }
+        FshCodec::write_message(&mut *writer, complete_msg).await?;
+
+        Ok(())
+    }
+
+    /// Hold a flagged command until an operator decides on it, telling the
+    /// client what it is waiting for.
+    async fn await_approval(
+        session_id: &str,
+        command_line: &str,
+        approval: &ApprovalGate,
+        writer: &Arc<Mutex<FrameSink>>,
+        job_id: Option<u32>,
+    ) -> FshResult<()> {
+        let pending = approval.submit(command_line)?;
+
+        let notice = FshMessage::CommandOutput(CommandOutputMessage {
+            session_id: session_id.to_string(),
+            output_type: OutputType::Stderr,
+            data: format!(
+                "Waiting for operator approval of '{}' (request {})\n", command_line, pending.request.id
+            ).into_bytes().into(),
+            job_id,
+            binary: false,
+        });
+        {
+            let mut writer = writer.lock().await;
+            FshCodec::write_message(&mut *writer, notice).await?;
+        }
+
+        approval.wait(pending).await
+    }
+
+    /// Wait for the command limits to let a command start, telling the
+    /// client where it stands in the queue meanwhile.
+    async fn await_command_slot(
+        session_id: &str,
+        commands: &CommandLimits,
+        writer: &Arc<Mutex<FrameSink>>,
+        job_id: Option<u32>,
+    ) -> FshResult<CommandSlot> {
+        let mut queued = match commands.admit()? {
+            Admission::Running(slot) => return Ok(slot),
+            Admission::Queued(queued) => queued,
+        };
+
+        let mut position = queued.position();
+        loop {
+            let notice = FshMessage::CommandOutput(CommandOutputMessage {
+                session_id: session_id.to_string(),
+                output_type: OutputType::Stderr,
+                data: format!("Queued, position {}: waiting for other commands to finish\n", position).into_bytes().into(),
+                job_id,
+                binary: false,
+            });
+            {
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, notice).await?;
+            }
+
+            match queued.advance().await {
+                QueueEvent::Started(slot) => return Ok(slot),
+                QueueEvent::Moved(moved_to) => position = moved_to,
+            }
+        }
+    }
+
+    /// Pass what participants do in the session on to its owner until
+    /// aborted or the owner's connection fails.
+    fn spawn_participant_forwarder(
+        session_id: String,
+        mut events: tokio::sync::broadcast::Receiver<MirroredEvent>,
+        writer: Arc<Mutex<FrameSink>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(MirroredEvent { source: Some(_), event }) => event,
+                    Ok(_) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Owner of session {} missed {} participant events", session_id, missed);
+                        continue;
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let observed = FshMessage::Observed(ObservedMessage { session_id: session_id.clone(), event });
+                let mut writer = writer.lock().await;
+                if let Err(e) = FshCodec::write_message(&mut *writer, observed).await {
+                    debug!("Stopped forwarding participant events in session {}: {}", session_id, e);
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Expand `run <macro> [args...]` into its steps, recording them in the
+    /// audit log. `None` if the command is not a macro invocation.
+    async fn expand_macro(
+        session_id: &str,
+        cmd_msg: &CommandMessage,
+        folder_config: &FolderConfig,
+        audit: Option<&ClientAudit>,
+    ) -> Option<FshResult<Vec<MacroStep>>> {
+        if cmd_msg.command != RUN_MACRO_COMMAND {
+            return None;
+        }
+        // `run` that doesn't name a macro is an ordinary command
+        let (name, args) = cmd_msg.args.split_first()?;
+        let body = folder_config.macros.get(name)?;
+
+        let steps = match expand_macro(body, args) {
+            Ok(steps) => steps,
+            Err(e) => return Some(Err(e)),
+        };
+        info!("Session {} running macro '{}' ({} steps)", session_id, name, steps.len());
+
+        if let Some(audit) = audit {
+            for step in &steps {
+                if let Err(e) = audit.logger.log_macro_step(
+                    audit.source_ip, session_id.to_string(), name, step.command_line(),
+                ).await {
+                    warn!("Failed to audit macro step in session {}: {}", session_id, e);
+                }
+            }
+        }
+
+        Some(Ok(steps))
+    }
+
+    /// Stream a command's output to the client, tagged with its job id.
+    /// Output a data loss prevention rule blocks is replaced with a notice,
+    /// and output past the folder's cap is spilled to a file.
+    ///
+    /// Chunks of the same kind arriving within [`OUTPUT_BATCH_WINDOW`] of the
+    /// first are sent as one message, up to [`OUTPUT_BATCH_SIZE`] bytes, so
+    /// chatty commands don't cost a frame per line. Order is preserved.
+    fn spawn_output_forwarder(
+        session_id: &str,
+        job_id: Option<u32>,
+        mut output_rx: mpsc::Receiver<ShellOutput>,
+        writer: Arc<Mutex<FrameSink>>,
+        dlp: Option<Arc<DlpScanner>>,
+        audit: Option<ClientAudit>,
+        mut cap: Option<OutputCap>,
+    ) -> JoinHandle<()> {
+        let session_id = session_id.to_string();
+
+        tokio::spawn(async move {
+            let mut batch: Option<(OutputType, bool, Vec<u8>)> = None;
+            let mut deadline = Instant::now();
+            'forward: loop {
+                let received = if batch.is_some() {
+                    match tokio::time::timeout_at(deadline, output_rx.recv()).await {
+                        Ok(received) => received,
+                        Err(_) => {
+                            // The window has closed
+                            if !Self::flush_output(&writer, &session_id, job_id, batch.take()).await {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                } else {
+                    output_rx.recv().await
+                };
+                let Some(output) = received else {
+                    Self::flush_output(&writer, &session_id, job_id, batch.take()).await;
+                    break;
+                };
+
+                let mut output_type = match output.output_type {
+                    crate::sandbox::OutputType::Stdout => OutputType::Stdout,
+                    crate::sandbox::OutputType::Stderr => OutputType::Stderr,
+                };
+                let (mut data, mut binary) = (output.data, output.binary);
+                if let Some(dlp) = &dlp {
+                    let matches = dlp.inspect(&mut data);
+                    Self::audit_dlp_matches(&session_id, audit.as_ref(), "command output", &matches).await;
+                    if let Some(blocked) = dlp_blocked(&matches) {
+                        (output_type, binary) = (OutputType::Stderr, false);
+                        data = format!("[output withheld: {}]\n", blocked.blocked_error().detail()).into_bytes();
+                    }
+                }
+
+                let passed = match cap.as_mut() {
+                    Some(cap) => cap.pass(output_type, binary, data).await,
+                    None => vec![(output_type, binary, data)],
+                };
+                for (output_type, binary, data) in passed {
+                    match &mut batch {
+                        Some((batch_type, batch_binary, batch_data)) if *batch_type == output_type && *batch_binary == binary => {
+                            batch_data.extend_from_slice(&data)
+                        }
+                        _ => {
+                            if !Self::flush_output(&writer, &session_id, job_id, batch.take()).await {
+                                break 'forward;
+                            }
+                            batch = Some((output_type, binary, data));
+                            deadline = Instant::now() + OUTPUT_BATCH_WINDOW;
+                        }
+                    }
+                    if batch.as_ref().is_some_and(|(_, _, data)| data.len() >= OUTPUT_BATCH_SIZE)
+                        && !Self::flush_output(&writer, &session_id, job_id, batch.take()).await
+                    {
+                        break 'forward;
+                    }
+                }
+            }
+            if let Some(cap) = cap.as_mut() {
+                cap.finish().await;
+            }
+        }.in_current_span())
+    }
+
+    /// Send batched output, if any. Returns whether the client can still be written to.
+    async fn flush_output(
+        writer: &Mutex<FrameSink>,
+        session_id: &str,
+        job_id: Option<u32>,
+        batch: Option<(OutputType, bool, Vec<u8>)>,
+    ) -> bool {
+        let Some((output_type, binary, data)) = batch else {
+            return true;
+        };
+        let output_msg = FshMessage::CommandOutput(CommandOutputMessage {
+            session_id: session_id.to_string(),
+            output_type,
+            data: data.into(),
+            job_id,
+            binary,
+        });
+        let mut writer = writer.lock().await;
+        if let Err(e) = FshCodec::write_message(&mut *writer, output_msg).await {
+            error!("Failed to send command output: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Record what the data loss prevention rules found in data on its way
+    /// to the client.
+    async fn audit_dlp_matches(session_id: &str, audit: Option<&ClientAudit>, resource: &str, matches: &[DlpMatch]) {
+        for found in matches {
+            info!("DLP rule '{}' matched {} in session {} ({})", found.rule, resource, session_id, found.action.name());
+            if let Some(audit) = audit {
+                if let Err(e) = audit.logger.log_dlp_match(
+                    audit.source_ip, session_id.to_string(), resource.to_string(), found,
+                ).await {
+                    warn!("Failed to audit DLP match in session {}: {}", session_id, e);
+                }
+            }
+        }
+    }
+
+    /// Start a background job and return right away; its output and
+    /// completion are sent as they happen, tagged with the job id.
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_background_command(
+        session_id: &str,
+        cmd_msg: CommandMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+        jobs: &JobTable,
+        hooks: &CommandHooks,
+        audit: Option<&ClientAudit>,
+        approval: &ApprovalGate,
+        commands: &CommandLimits,
+        usage: &SessionUsage,
+        history: &SessionHistory,
+        events: &EventBus,
+        dlp: Option<&Arc<DlpScanner>>,
+    ) -> FshResult<()> {
+        let Some(job_id) = cmd_msg.job_id else {
+            return Err(FshError::ProtocolError("Background command without a job id".to_string()));
+        };
+        debug!("Starting job %{} in session {}: {}", job_id, session_id, cmd_msg.command);
+
+        let command_line = command_line(&cmd_msg);
+
+        // Everything that must pass before the job may start, in order
+        let checked = async {
+            if !folder_config.can_execute() {
+                return Err(FshError::PermissionDenied("Execute permission denied".to_string()));
+            }
+            jobs.check_available(job_id)?;
+            hooks.pre_command(&shell, &command_line).await?;
+            if approval.is_required(std::iter::once(command_line.as_str())) {
+                Self::await_approval(session_id, &command_line, approval, &writer, Some(job_id)).await?;
+            }
+            Self::await_command_slot(session_id, commands, &writer, Some(job_id)).await
+        };
+        let started = match checked.await {
+            Ok(slot) => shell.lock().await.start_job(&cmd_msg.command, &cmd_msg.args).await.map(|job| (job, slot)),
+            Err(e) => Err(e),
+        };
+        let started_in = shell.lock().await.working_directory().to_string_lossy().to_string();
+
+        let (mut job, slot) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                usage.record_command(Duration::ZERO, -1);
+                history.record(session_id, &command_line, &started_in, -1);
+                events.emit(ServerEvent::CommandExecuted {
+                    session_id: session_id.to_string(),
+                    folder: folder_config.name.clone(),
+                    command: command_line.clone(),
+                    exit_code: -1,
+                    duration: Duration::ZERO,
+                });
+
+                // Report the failure as the job's output so the client can match it up
+                let output_msg = FshMessage::CommandOutput(CommandOutputMessage {
+                    session_id: session_id.to_string(),
+                    output_type: OutputType::Stderr,
+                    data: format!("Command execution failed: {}\n", e).into_bytes().into(),
+                    job_id: Some(job_id),
+                    binary: false,
+                });
+                let complete_msg = FshMessage::CommandComplete(CommandCompleteMessage {
+                    session_id: session_id.to_string(),
+                    exit_code: -1,
+                    execution_time_ms: 0,
+                    working_directory: started_in,
+                    job_id: Some(job_id),
+                });
+
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, output_msg).await?;
+                FshCodec::write_message(&mut *writer, complete_msg).await?;
+                return Ok(());
+            }
+        };
+
+        jobs.insert(job_id, command_line.clone(), job.killer.take());
+
+        let forwarder = Self::spawn_output_forwarder(
+            session_id, Some(job_id), job.output, Arc::clone(&writer), dlp.cloned(), audit.cloned(),
+            OutputCap::for_folder(folder_config),
+        );
+        let session_id = session_id.to_string();
+        let jobs = jobs.clone();
+        let hooks = hooks.clone();
+        let folder = folder_config.name.clone();
+        let usage = usage.clone();
+        let history = history.clone();
+        let events = events.clone();
+
+        tokio::spawn(async move {
+            let result = job.result.recv().await;
+            drop(slot);
+            if let Err(e) = forwarder.await {
+                error!("Output forwarding task failed for job %{} in session {}: {}", job_id, session_id, e);
+            }
+            jobs.remove(job_id);
+
+            let (exit_code, execution_time_ms) = result
+                .map(|result| (result.exit_code, result.execution_time_ms))
+                .unwrap_or((-1, 0));
+            debug!("Job %{} in session {} exited with {}", job_id, session_id, exit_code);
+            hooks.post_command(&shell, &command_line, exit_code).await;
+            crate::telemetry::metrics().record_command(&folder, Duration::from_millis(execution_time_ms), exit_code);
+            usage.record_command(Duration::from_millis(execution_time_ms), exit_code);
+            history.record(&session_id, &command_line, &started_in, exit_code);
+            events.emit(ServerEvent::CommandExecuted {
+                session_id: session_id.clone(),
+                folder: folder.clone(),
+                command: command_line.clone(),
+                exit_code,
+                duration: Duration::from_millis(execution_time_ms),
+            });
+
+            let working_directory = shell.lock().await.working_directory().to_string_lossy().to_string();
+            let complete_msg = FshMessage::CommandComplete(CommandCompleteMessage {
+                session_id: session_id.clone(),
+                exit_code,
+                execution_time_ms,
+                working_directory,
+                job_id: Some(job_id),
+            });
+
+            let mut writer = writer.lock().await;
+            if let Err(e) = FshCodec::write_message(&mut *writer, complete_msg).await {
+                warn!("Failed to report completion of job %{} in session {}: {}", job_id, session_id, e);
+            }
+        }.in_current_span());
+
+        Ok(())
+    }
+
+    async fn handle_file_list(
+        session_id: &str,
+        list_msg: FileListMessage,
         shell: Arc<Mutex<SandboxedShell>>,
-        stream: Arc<Mutex<TcpStream>>,
+        writer: Arc<Mutex<FrameSink>>,
     ) -> FshResult<()> {
         debug!("Listing files in session {}: {}", session_id, list_msg.path);
 
         let shell = shell.lock().await;
         let path = if list_msg.path.is_empty() { None } else { Some(list_msg.path.as_str()) };
 
-        match shell.list_files(path, list_msg.show_hidden) {
-            Ok(files) => {
-                let response = FshMessage::FileListResponse(FileListResponseMessage {
-                    success: true,
-                    files,
-                    error_message: None,
-                });
-
-                let mut stream = stream.lock().await;
-                FshCodec::write_message(&mut *stream, &response).await?;
-            }
+        let listing = match shell.read_directory(path, list_msg.show_hidden) {
+            Ok(listing) => listing,
             Err(e) => {
                 let response = FshMessage::FileListResponse(FileListResponseMessage {
                     success: false,
                     files: vec![],
                     error_message: Some(format!("Failed to list files: {}", e)),
+                    offset: list_msg.offset,
+                    total: 0,
+                    complete: true,
+                    next_offset: None,
                 });
 
-                let mut stream = stream.lock().await;
-                FshCodec::write_message(&mut *stream, &response).await?;
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, response).await?;
+                return Ok(());
             }
+        };
+
+        let total = listing.len();
+        let start = usize::try_from(list_msg.offset).unwrap_or(usize::MAX).min(total);
+        let end = match list_msg.limit {
+            Some(limit) => start.saturating_add(limit as usize).min(total),
+            None => total,
+        };
+        let next_offset = (end < total).then_some(end as u64);
+
+        // Send the window in batches so neither side holds one huge message
+        let window = &listing[start..end];
+        let batch_count = window.len().div_ceil(LIST_BATCH_SIZE).max(1);
+        for batch in 0..batch_count {
+            let batch_start = batch * LIST_BATCH_SIZE;
+            let batch_end = (batch_start + LIST_BATCH_SIZE).min(window.len());
+
+            let response = FshMessage::FileListResponse(FileListResponseMessage {
+                success: true,
+                files: shell.file_entries(&window[batch_start..batch_end]),
+                error_message: None,
+                offset: (start + batch_start) as u64,
+                total: total as u64,
+                complete: batch + 1 == batch_count,
+                next_offset,
+            });
+
+            let mut writer = writer.lock().await;
+            FshCodec::write_message(&mut *writer, response).await?;
         }
 
         Ok(())
     }
 
+    async fn handle_file_stat(
+        session_id: &str,
+        stat_msg: FileStatMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+    ) -> FshResult<()> {
+        debug!("Stat of file in session {}: {}", session_id, stat_msg.path);
+
+        let result = shell.lock().await.stat_file(&stat_msg.path);
+        let response = match result {
+            Ok(stat) => FshMessage::FileStatResponse(FileStatResponseMessage {
+                success: true,
+                stat: Some(stat),
+                error_message: None,
+            }),
+            Err(e) => FshMessage::FileStatResponse(FileStatResponseMessage {
+                success: false,
+                stat: None,
+                error_message: Some(format!("Failed to stat file: {}", e)),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_file_delete(
+        session_id: &str,
+        delete_msg: FileDeleteMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+    ) -> FshResult<()> {
+        debug!("Delete of file in session {}: {}", session_id, delete_msg.file_path);
+
+        let result = shell.lock().await.delete_path(&delete_msg.file_path, delete_msg.recursive);
+        let response = match result {
+            Ok(entry) => {
+                info!("Deleted {} in session {} (trash id {:?})",
+                      delete_msg.file_path, session_id, entry.as_ref().map(|entry| &entry.id));
+                FshMessage::FileDeleteResponse(FileDeleteResponseMessage {
+                    success: true,
+                    trash_id: entry.map(|entry| entry.id),
+                    error_message: None,
+                })
+            }
+            Err(e) => FshMessage::FileDeleteResponse(FileDeleteResponseMessage {
+                success: false,
+                trash_id: None,
+                error_message: Some(format!("Failed to delete file: {}", e)),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_file_mkdir(
+        session_id: &str,
+        mkdir_msg: FileMkdirMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+    ) -> FshResult<()> {
+        debug!("Creating directory in session {}: {}", session_id, mkdir_msg.path);
+
+        let result = shell.lock().await.create_dir(&mkdir_msg.path);
+        let response = FshMessage::FileMkdirResponse(FileMkdirResponseMessage {
+            success: result.is_ok(),
+            error_message: result.err().map(|e| format!("Failed to create directory: {}", e)),
+        });
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_file_restore(
+        session_id: &str,
+        restore_msg: FileRestoreMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+    ) -> FshResult<()> {
+        debug!("Restore from trash in session {}: {}", session_id, restore_msg.target);
+
+        let result = shell.lock().await.restore_path(&restore_msg.target);
+        let response = match result {
+            Ok(entry) => {
+                info!("Restored {} in session {}", entry.original_path, session_id);
+                FshMessage::FileRestoreResponse(FileRestoreResponseMessage {
+                    success: true,
+                    restored_path: Some(entry.original_path),
+                    error_message: None,
+                })
+            }
+            Err(e) => FshMessage::FileRestoreResponse(FileRestoreResponseMessage {
+                success: false,
+                restored_path: None,
+                error_message: Some(format!("Failed to restore file: {}", e)),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_git_status(
+        session_id: &str,
+        status_msg: GitStatusMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+    ) -> FshResult<()> {
+        debug!("Git status in session {}: {:?}", session_id, status_msg.path);
+
+        let result = if folder_config.can_read() {
+            shell.lock().await.git_status(status_msg.path.as_deref())
+        } else {
+            Err(FshError::PermissionDenied("Read permission denied".to_string()))
+        };
+        let response = match result {
+            Ok(status) => FshMessage::GitStatusResponse(GitStatusResponseMessage {
+                success: true,
+                status: Some(status),
+                error_message: None,
+            }),
+            Err(e) => FshMessage::GitStatusResponse(GitStatusResponseMessage {
+                success: false,
+                status: None,
+                error_message: Some(e.detail()),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    /// Diffs show file contents, so they go through the data loss prevention
+    /// rules like file reads.
+    async fn handle_git_diff(
+        session_id: &str,
+        diff_msg: GitDiffMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+        dlp: Option<&DlpScanner>,
+        audit: Option<&ClientAudit>,
+    ) -> FshResult<()> {
+        debug!("Git diff in session {}: {:?}", session_id, diff_msg.path);
+
+        let result = if folder_config.can_read() {
+            shell.lock().await.git_diff(diff_msg.path.as_deref(), diff_msg.staged, diff_msg.context_lines)
+        } else {
+            Err(FshError::PermissionDenied("Read permission denied".to_string()))
+        };
+        let result = match (result, dlp) {
+            (Ok((diff, truncated)), Some(dlp)) => {
+                let mut data = diff.into_bytes();
+                let matches = dlp.inspect(&mut data);
+                let resource = format!("git diff {}", diff_msg.path.as_deref().unwrap_or("."));
+                Self::audit_dlp_matches(session_id, audit, &resource, &matches).await;
+                match dlp_blocked(&matches) {
+                    Some(blocked) => Err(blocked.blocked_error()),
+                    None => Ok((String::from_utf8_lossy(&data).into_owned(), truncated)),
+                }
+            }
+            (result, _) => result,
+        };
+
+        let response = match result {
+            Ok((diff, truncated)) => FshMessage::GitDiffResponse(GitDiffResponseMessage {
+                success: true,
+                diff: Some(diff),
+                truncated,
+                error_message: None,
+            }),
+            Err(e) => FshMessage::GitDiffResponse(GitDiffResponseMessage {
+                success: false,
+                diff: None,
+                truncated: false,
+                error_message: Some(e.detail()),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_git_log(
+        session_id: &str,
+        log_msg: GitLogMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+    ) -> FshResult<()> {
+        debug!("Git log in session {}: {:?}", session_id, log_msg.path);
+
+        let result = if folder_config.can_read() {
+            shell.lock().await.git_log(log_msg.path.as_deref(), log_msg.max_count)
+        } else {
+            Err(FshError::PermissionDenied("Read permission denied".to_string()))
+        };
+        let response = match result {
+            Ok(commits) => FshMessage::GitLogResponse(GitLogResponseMessage {
+                success: true,
+                commits,
+                error_message: None,
+            }),
+            Err(e) => FshMessage::GitLogResponse(GitLogResponseMessage {
+                success: false,
+                commits: Vec::new(),
+                error_message: Some(e.detail()),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_file_read(
         session_id: &str,
         read_msg: FileReadMessage,
         shell: Arc<Mutex<SandboxedShell>>,
-        stream: Arc<Mutex<TcpStream>>,
+        writer: Arc<Mutex<FrameSink>>,
         folder_config: &FolderConfig,
+        transfer_limits: &TransferLimits,
+        usage: &SessionUsage,
+        dlp: Option<&DlpScanner>,
+        audit: Option<&ClientAudit>,
     ) -> FshResult<()> {
         debug!("Reading file in session {}: {}", session_id, read_msg.file_path);
 
-        // Check read permission
-        if !folder_config.can_read() {
-            let response = FshMessage::FileReadResponse(FileReadResponseMessage {
-                success: false,
-                data: vec![],
-                total_size: 0,
-                error_message: Some("Read permission denied".to_string()),
-            });
+        let offset = read_msg.offset.unwrap_or(0);
+        let result = if let Some(dlp) = dlp {
+            let inspected = read_inspected(&*shell.lock().await, dlp, &read_msg.file_path, offset, read_msg.length);
+            match inspected {
+                Ok((data, total_size, matches)) => {
+                    Self::audit_dlp_matches(session_id, audit, &read_msg.file_path, &matches).await;
+                    match dlp_blocked(&matches) {
+                        Some(blocked) => Err(blocked.blocked_error()),
+                        None => Ok((data, total_size)),
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            shell.lock().await.read_file_range(&read_msg.file_path, offset, read_msg.length)
+        };
 
-            let mut stream = stream.lock().await;
-            FshCodec::write_message(&mut *stream, &response).await?;
-            return Ok(());
+        // Throttle before sending so other sessions keep their share of the link
+        if let Ok((data, _)) = &result {
+            transfer_limits.acquire(data.len()).await;
+            crate::telemetry::metrics().record_transfer(&folder_config.name, "read", data.len());
+            usage.record_read(data.len() as u64);
         }
 
-        // TODO: Implement file reading with offset and length support
-        // For now, just read the entire file
-        let _shell = shell.lock().await;
-
-        // Use the path validator to get the safe absolute path
-        // This is a simplified implementation
-        let response = FshMessage::FileReadResponse(FileReadResponseMessage {
-            success: false,
-            data: vec![],
-            total_size: 0,
-            error_message: Some("File reading not yet implemented".to_string()),
-        });
+        let response = match result {
+            Ok((data, total_size)) => FshMessage::FileReadResponse(FileReadResponseMessage {
+                success: true,
+                checksum: Some(ChecksumAlgorithm::default().digest(&data)),
+                data: data.into(),
+                total_size,
+                error_message: None,
+                offset,
+            }),
+            Err(e) => FshMessage::FileReadResponse(FileReadResponseMessage {
+                success: false,
+                data: Bytes::new(),
+                total_size: 0,
+                error_message: Some(e.detail()),
+                offset,
+                checksum: None,
+            }),
+        };
 
-        let mut stream = stream.lock().await;
-        FshCodec::write_message(&mut *stream, &response).await?;
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_file_write(
         session_id: &str,
         write_msg: FileWriteMessage,
-        _shell: Arc<Mutex<SandboxedShell>>,
-        stream: Arc<Mutex<TcpStream>>,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
         folder_config: &FolderConfig,
+        transfer_limits: &TransferLimits,
+        usage: &SessionUsage,
+        malware_scanner: Option<&MalwareScanner>,
+        audit: Option<&ClientAudit>,
     ) -> FshResult<()> {
         debug!("Writing file in session {}: {}", session_id, write_msg.file_path);
 
-        // Check write permission
-        if !folder_config.can_write() {
-            let response = FshMessage::FileWriteResponse(FileWriteResponseMessage {
+        // The data has already arrived; waiting here holds back the client's next chunk
+        transfer_limits.acquire(write_msg.data.len()).await;
+
+        let result = if write_msg.checksum.as_ref().is_some_and(|checksum| !checksum.verify(&write_msg.data)) {
+            // Corrupted in transit; the client resends the chunk
+            Err(FshError::ProtocolError(CHECKSUM_MISMATCH.to_string()))
+        } else {
+            shell.lock().await.write_file(
+                &write_msg.file_path,
+                &write_msg.data,
+                write_msg.append,
+                write_msg.offset,
+            )
+        };
+
+        if let Ok(bytes_written) = &result {
+            crate::telemetry::metrics().record_transfer(&folder_config.name, "write", *bytes_written as usize);
+            usage.record_write(*bytes_written);
+        }
+
+        // The whole file is scanned after every write, so an upload is checked
+        // again as each chunk lands
+        let result = match (result, malware_scanner) {
+            (Ok(bytes_written), Some(scanner)) => {
+                let scanned = match shell.lock().await.resolve_path(&write_msg.file_path) {
+                    Ok(path) => scanner.check(&path).await,
+                    Err(e) => Err(e),
+                };
+                match scanned {
+                    Ok(ScanOutcome::Clean) => Ok(bytes_written),
+                    Ok(ScanOutcome::Quarantined { verdict, quarantined_as }) => {
+                        warn!("Quarantined '{}' written in session {}: {}", write_msg.file_path, session_id, verdict);
+                        if let Some(audit) = audit {
+                            if let Err(e) = audit.logger.log_malware_detected(
+                                audit.source_ip, session_id.to_string(), write_msg.file_path.clone(), &verdict, &quarantined_as,
+                            ).await {
+                                warn!("Failed to audit malware in session {}: {}", session_id, e);
+                            }
+                        }
+
+                        let error = FshError::MalwareDetected(format!(
+                            "'{}' was quarantined: {}", write_msg.file_path, verdict
+                        ));
+                        let mut writer = writer.lock().await;
+                        FshCodec::write_message(&mut *writer, FshMessage::Error(ErrorMessage::from(&error))).await?;
+                        return Ok(());
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (result, _) => result,
+        };
+
+        let response = match result {
+            Ok(bytes_written) => FshMessage::FileWriteResponse(FileWriteResponseMessage {
+                success: true,
+                bytes_written,
+                error_message: None,
+            }),
+            Err(e) => FshMessage::FileWriteResponse(FileWriteResponseMessage {
                 success: false,
                 bytes_written: 0,
-                error_message: Some("Write permission denied".to_string()),
-            });
+                error_message: Some(e.detail()),
+            }),
+        };
 
-            let mut stream = stream.lock().await;
-            FshCodec::write_message(&mut *stream, &response).await?;
-            return Ok(());
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    async fn handle_file_checksum(
+        session_id: &str,
+        checksum_msg: FileChecksumMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        dlp: Option<&DlpScanner>,
+    ) -> FshResult<()> {
+        debug!("Checksumming file in session {}: {}", session_id, checksum_msg.file_path);
+
+        let result = if let Some(dlp) = dlp {
+            checksum_inspected(
+                &*shell.lock().await,
+                dlp,
+                &checksum_msg.file_path,
+                checksum_msg.algorithm,
+                checksum_msg.offset,
+                checksum_msg.length,
+            )
+        } else {
+            shell.lock().await.checksum_file(
+                &checksum_msg.file_path,
+                checksum_msg.algorithm,
+                checksum_msg.offset,
+                checksum_msg.length,
+            )
+        };
+
+        let response = match result {
+            Ok((checksum, size)) => FshMessage::FileChecksumResponse(FileChecksumResponseMessage {
+                success: true,
+                checksum: Some(checksum),
+                size,
+                error_message: None,
+            }),
+            Err(e) => FshMessage::FileChecksumResponse(FileChecksumResponseMessage {
+                success: false,
+                checksum: None,
+                size: 0,
+                error_message: Some(e.detail()),
+            }),
+        };
+
+        let mut writer = writer.lock().await;
+        FshCodec::write_message(&mut *writer, response).await?;
+
+        Ok(())
+    }
+
+    /// Send the requested backlog of a file and, when following, spawn the task
+    /// that streams appended lines until it is aborted. Data a data loss
+    /// prevention rule blocks ends the tail.
+    async fn handle_file_tail(
+        session_id: &str,
+        tail_msg: FileTailMessage,
+        shell: Arc<Mutex<SandboxedShell>>,
+        writer: Arc<Mutex<FrameSink>>,
+        dlp: Option<&Arc<DlpScanner>>,
+        audit: Option<&ClientAudit>,
+    ) -> FshResult<Option<JoinHandle<()>>> {
+        debug!("Tailing file in session {}: {}", session_id, tail_msg.file_path);
+
+        let path = {
+            let shell = shell.lock().await;
+            shell.resolve_path_for(&tail_msg.file_path, Permission::Read)
+                .and_then(|path| shell.check_file_policy(PolicyAction::Read, &path).map(|()| path))
+        };
+        let opened = path.and_then(|path| FileFollower::open(&path, tail_msg.lines as usize));
+
+        let opened = match (opened, dlp) {
+            (Ok((follower, mut backlog)), Some(dlp)) => {
+                let matches = dlp.inspect(&mut backlog);
+                Self::audit_dlp_matches(session_id, audit, &tail_msg.file_path, &matches).await;
+                match dlp_blocked(&matches) {
+                    Some(blocked) => Err(blocked.blocked_error()),
+                    None => Ok((follower, backlog)),
+                }
+            }
+            (opened, _) => opened,
+        };
+
+        let (mut follower, backlog) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                let end_msg = FshMessage::FileTailEnd(FileTailEndMessage {
+                    session_id: session_id.to_string(),
+                    error_message: Some(e.to_string()),
+                });
+                let mut writer = writer.lock().await;
+                FshCodec::write_message(&mut *writer, end_msg).await?;
+                return Ok(None);
+            }
+        };
+
+        {
+            let mut writer = writer.lock().await;
+            if !backlog.is_empty() {
+                let data_msg = FshMessage::FileTailData(FileTailDataMessage {
+                    session_id: session_id.to_string(),
+                    data: backlog,
+                    rotated: false,
+                });
+                FshCodec::write_message(&mut *writer, data_msg).await?;
+            }
+
+            if !tail_msg.follow {
+                let end_msg = FshMessage::FileTailEnd(FileTailEndMessage {
+                    session_id: session_id.to_string(),
+                    error_message: None,
+                });
+                FshCodec::write_message(&mut *writer, end_msg).await?;
+                return Ok(None);
+            }
+        }
+
+        let session_id = session_id.to_string();
+        let file_path = tail_msg.file_path;
+        let dlp = dlp.cloned();
+        let audit = audit.cloned();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+
+                let mut polled = follower.poll();
+                if let (Ok(Some(chunk)), Some(dlp)) = (&mut polled, &dlp) {
+                    let matches = dlp.inspect(&mut chunk.data);
+                    Self::audit_dlp_matches(&session_id, audit.as_ref(), &file_path, &matches).await;
+                    if let Some(blocked) = dlp_blocked(&matches) {
+                        polled = Err(blocked.blocked_error());
+                    }
+                }
+
+                let message = match polled {
+                    Ok(Some(chunk)) => FshMessage::FileTailData(FileTailDataMessage {
+                        session_id: session_id.clone(),
+                        data: chunk.data,
+                        rotated: chunk.rotated,
+                    }),
+                    Ok(None) => continue,
+                    Err(e) => FshMessage::FileTailEnd(FileTailEndMessage {
+                        session_id: session_id.clone(),
+                        error_message: Some(format!("Failed to follow '{}': {}", follower.path().display(), e)),
+                    }),
+                };
+
+                let ended = matches!(message, FshMessage::FileTailEnd(_));
+                let mut writer = writer.lock().await;
+                if let Err(e) = FshCodec::write_message(&mut *writer, message).await {
+                    error!("Failed to send file tail data in session {}: {}", session_id, e);
+                    break;
+                }
+                if ended {
+                    break;
+                }
+            }
+        }.in_current_span());
+
+        Ok(Some(task))
+    }
+
+    /// Connect to a forwardable port and carry the connection as the
+    /// client's channel until both sides have sent EOF.
+    async fn handle_port_forward_open(
+        session_id: &str,
+        open_msg: PortForwardOpenMessage,
+        writer: Arc<Mutex<FrameSink>>,
+        folder_config: &FolderConfig,
+        forwards: &ForwardTable,
+        audit: Option<&ClientAudit>,
+    ) -> FshResult<()> {
+        let channel_id = open_msg.channel_id;
+        let target = format!("{}:{}", open_msg.host, open_msg.port);
+        info!("Forwarding channel {} to {} in session {}", channel_id, target, session_id);
+
+        let opened = async {
+            if !folder_config.can_forward_port(open_msg.port) {
+                return Err(FshError::PermissionDenied(format!(
+                    "Port {} is not forwardable in folder '{}'", open_msg.port, folder_config.name
+                )));
+            }
+            forwards.check_available(channel_id)?;
+            connect_loopback(&open_msg.host, open_msg.port).await
+        }.await;
+
+        let stream = match opened {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Refused forward to {} in session {}: {}", target, session_id, e);
+                if let Some(audit) = audit {
+                    let logged = match &e {
+                        FshError::PermissionDenied(reason) => audit.logger.log_permission_denied(
+                            audit.source_ip, Some(session_id.to_string()), target.clone(), reason.clone(),
+                        ).await,
+                        _ => audit.logger.log_port_forward(
+                            audit.source_ip, session_id.to_string(), &target, &format!("failed: {}", e.detail()),
+                        ).await,
+                    };
+                    if let Err(log_error) = logged {
+                        warn!("Failed to audit port forward in session {}: {}", session_id, log_error);
+                    }
+                }
+                let response = FshMessage::PortForwardOpenResponse(PortForwardOpenResponseMessage {
+                    channel_id,
+                    success: false,
+                    error_message: Some(e.detail()),
+                });
+                let mut writer = writer.lock().await;
+                return FshCodec::write_message(&mut *writer, response).await;
+            }
+        };
+
+        if let Some(audit) = audit {
+            if let Err(e) = audit.logger.log_port_forward(audit.source_ip, session_id.to_string(), &target, "opened").await {
+                warn!("Failed to audit port forward in session {}: {}", session_id, e);
+            }
         }
 
-        // TODO: Implement file writing
-        // For now, just return not implemented
-        let response = FshMessage::FileWriteResponse(FileWriteResponseMessage {
-            success: false,
-            bytes_written: 0,
-            error_message: Some("File writing not yet implemented".to_string()),
+        // Reply before the task can send data on the channel
+        let response = FshMessage::PortForwardOpenResponse(PortForwardOpenResponseMessage {
+            channel_id,
+            success: true,
+            error_message: None,
         });
+        {
+            let mut writer = writer.lock().await;
+            FshCodec::write_message(&mut *writer, response).await?;
+        }
 
-        let mut stream = stream.lock().await;
-        FshCodec::write_message(&mut *stream, &response).await?;
+        let (input_tx, mut input) = mpsc::channel::<Vec<u8>>(FORWARD_QUEUE_DEPTH);
+        let (mut read_half, mut write_half) = stream.into_split();
+        let session_id = session_id.to_string();
+        let table = forwards.clone();
+        let audit = audit.cloned();
+        let (registered_tx, registered) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            // Not removed from the table before it has been added
+            let _ = registered.await;
+
+            // Each direction runs on its own so a peer that isn't reading
+            // can't stall the other
+            let upstream = async {
+                let mut bytes = 0u64;
+                while let Some(data) = input.recv().await {
+                    if write_half.write_all(&data).await.is_err() {
+                        break;
+                    }
+                    bytes += data.len() as u64;
+                }
+                input.close();
+                let _ = write_half.shutdown().await;
+                bytes
+            };
+            let downstream = async {
+                let mut bytes = 0u64;
+                let mut buffer = vec![0u8; FORWARD_CHUNK_SIZE];
+                loop {
+                    let read = match read_half.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => read,
+                    };
+                    bytes += read as u64;
+                    let data_msg = FshMessage::PortForwardData(PortForwardDataMessage {
+                        session_id: session_id.clone(),
+                        channel_id,
+                        data: buffer[..read].to_vec(),
+                    });
+                    let mut writer = writer.lock().await;
+                    if FshCodec::write_message(&mut *writer, data_msg).await.is_err() {
+                        return bytes;
+                    }
+                }
+                let eof_msg = FshMessage::PortForwardEof(PortForwardEofMessage {
+                    session_id: session_id.clone(),
+                    channel_id,
+                });
+                let mut writer = writer.lock().await;
+                let _ = FshCodec::write_message(&mut *writer, eof_msg).await;
+                bytes
+            };
+            let (sent, received) = tokio::join!(upstream, downstream);
+
+            table.remove(channel_id);
+            debug!("Channel {} to {} closed in session {}", channel_id, target, session_id);
+            if let Some(audit) = &audit {
+                let details = format!("closed after {} bytes out, {} bytes back", sent, received);
+                if let Err(e) = audit.logger.log_port_forward(audit.source_ip, session_id.clone(), &target, &details).await {
+                    warn!("Failed to audit port forward in session {}: {}", session_id, e);
+                }
+            }
+        }.in_current_span());
+        forwards.insert(channel_id, input_tx, task.abort_handle());
+        let _ = registered_tx.send(());
 
         Ok(())
     }
 
     pub async fn close(&self) -> FshResult<()> {
+        self.close_with_reason("Session closed by server").await
+    }
+
+    /// Close the session because its token is no longer valid, recording why
+    /// in the audit log.
+    pub async fn revoke(&self, reason: &str) -> FshResult<()> {
+        warn!("Revoking session {}: {}", self.id, reason);
+        if let (Some(audit), Some(token_id)) = (&self.audit, &self.token_id) {
+            audit.logger.log_session_revoked(audit.source_ip, self.id.clone(), token_id, reason).await?;
+        }
+        self.close_with_reason(reason).await
+    }
+
+    pub(crate) async fn close_with_reason(&self, reason: &str) -> FshResult<()> {
         info!("Closing session {}", self.id);
 
         // Mark session as inactive
@@ -439,11 +2767,11 @@ impl Session {
 
         // Send disconnect message to client
         let disconnect_msg = FshMessage::Disconnect(DisconnectMessage {
-            reason: "Session closed by server".to_string(),
+            reason: reason.to_string(),
         });
 
-        let mut stream = self.stream.lock().await;
-        if let Err(e) = FshCodec::write_message(&mut *stream, &disconnect_msg).await {
+        let mut writer = self.writer.lock().await;
+        if let Err(e) = FshCodec::write_message(&mut *writer, disconnect_msg).await {
             warn!("Failed to send disconnect message: {}", e);
         }
 
@@ -452,18 +2780,27 @@ impl Session {
     }
 }
 
+/// Warning lead for an idle timeout: one minute, or half the timeout for short ones.
+fn idle_warning_lead(idle_timeout: Duration) -> Duration {
+    IDLE_WARNING_LEAD.min(idle_timeout / 2)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::FolderConfig;
-    use crate::protocol::ShellType;
     use tempfile::TempDir;
     use tokio::net::{TcpListener, TcpStream};
 
-    #[tokio::test]
-    async fn test_session_creation() {
-        let temp_dir = TempDir::new().unwrap();
+    async fn create_test_session(temp_dir: &TempDir, idle_timeout: Option<Duration>) -> (Session, FshFramed<TcpStream>) {
+        create_locking_test_session(temp_dir, idle_timeout, None).await
+    }
 
+    async fn create_locking_test_session(
+        temp_dir: &TempDir,
+        idle_timeout: Option<Duration>,
+        idle_lock: Option<IdleLock>,
+    ) -> (Session, FshFramed<TcpStream>) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
@@ -481,15 +2818,140 @@ mod tests {
 
         let session = Session::new(
             "test-session".to_string(),
-            server_stream,
+            FshCodec::framed(server_stream.into()),
             folder_info,
             folder_config,
             client_info,
-        ).await;
+            "127.0.0.1:12345".to_string(),
+            idle_timeout,
+            KeepaliveConfig::default(),
+            TransferLimits::new(),
+            CommandLimits::unlimited(),
+            None,
+            None,
+            None,
+            SessionUsage::untracked("test"),
+            SessionHistory::new(&Default::default()),
+            EventBus::default(),
+            Plugins::default(),
+            None,
+            None,
+            None,
+            Arc::new(OperationLog::new()),
+            idle_lock,
+            None,
+        ).await.unwrap();
+
+        (session, FshCodec::framed(client_stream))
+    }
+
+    #[tokio::test]
+    async fn test_session_creation() {
+        let temp_dir = TempDir::new().unwrap();
+        let (session, _client_stream) = create_test_session(&temp_dir, None).await;
 
-        assert!(session.is_ok());
-        let session = session.unwrap();
         assert_eq!(session.id(), "test-session");
         assert!(session.is_active().await);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_idle_session_warned_then_closed() {
+        let temp_dir = TempDir::new().unwrap();
+        let (session, mut client) = create_test_session(&temp_dir, Some(Duration::from_millis(400))).await;
+
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionStart(_)));
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionReady(_)));
+
+        match FshCodec::read_message(&mut client).await.unwrap() {
+            FshMessage::Error(warning) => assert_eq!(warning.code, FshErrorCode::IdleWarning),
+            other => panic!("Expected idle warning, got {:?}", other.message_type()),
+        }
+
+        match FshCodec::read_message(&mut client).await.unwrap() {
+            FshMessage::Disconnect(disconnect) => assert!(disconnect.reason.contains("inactivity")),
+            other => panic!("Expected disconnect, got {:?}", other.message_type()),
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!session.is_active().await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_locked_until_token_entered() {
+        let temp_dir = TempDir::new().unwrap();
+        let lock = IdleLock::new(Duration::from_millis(100), "secret");
+        let (session, mut client) = create_locking_test_session(&temp_dir, None, Some(lock)).await;
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionStart(_)));
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionReady(_)));
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionLocked(_)));
+
+        // Requests are refused until the right token is entered
+        let list = FshMessage::FileList(FileListMessage {
+            session_id: "test-session".to_string(),
+            path: ".".to_string(),
+            show_hidden: false,
+            offset: 0,
+            limit: None,
+        });
+        FshCodec::write_message(&mut client, list.clone()).await.unwrap();
+        match FshCodec::read_message(&mut client).await.unwrap() {
+            FshMessage::Error(error) => assert_eq!(error.code, FshErrorCode::PermissionDenied),
+            other => panic!("Expected a refusal, got {:?}", other.message_type()),
+        }
+
+        for (token, success) in [("wrong", false), ("secret", true)] {
+            let unlock = FshMessage::SessionUnlock(SessionUnlockMessage {
+                session_id: "test-session".to_string(),
+                token: token.to_string(),
+            });
+            FshCodec::write_message(&mut client, unlock).await.unwrap();
+            match FshCodec::read_message(&mut client).await.unwrap() {
+                FshMessage::SessionUnlockResponse(resp) => assert_eq!(resp.success, success),
+                other => panic!("Expected an unlock response, got {:?}", other.message_type()),
+            }
+        }
+
+        FshCodec::write_message(&mut client, list).await.unwrap();
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::FileListResponse(_)));
+        assert!(session.is_active().await);
+    }
+
+    #[tokio::test]
+    async fn test_operation_retries_answered_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_session, mut client) = create_test_session(&temp_dir, None).await;
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionStart(_)));
+        assert!(matches!(FshCodec::read_message(&mut client).await.unwrap(), FshMessage::SessionReady(_)));
+
+        let append = OperationMessage {
+            operation_id: "op-1".to_string(),
+            request: OperationRequest::FileWrite(FileWriteMessage {
+                session_id: "test-session".to_string(),
+                file_path: "log.txt".to_string(),
+                data: b"line\n".to_vec(),
+                append: true,
+                offset: None,
+                checksum: None,
+            }),
+        };
+
+        // The retry is answered like the first attempt without appending again
+        for replayed in [false, true] {
+            FshCodec::write_message(&mut client, FshMessage::Operation(append.clone())).await.unwrap();
+            match FshCodec::read_message(&mut client).await.unwrap() {
+                FshMessage::OperationResponse(resp) => {
+                    assert_eq!((resp.operation_id.as_str(), resp.replayed), ("op-1", replayed));
+                    assert!(matches!(resp.reply, OperationReply::FileWrite(write) if write.success && write.bytes_written == 5));
+                }
+                other => panic!("Expected an operation response, got {:?}", other.message_type()),
+            }
+        }
+        assert_eq!(std::fs::read_to_string(temp_dir.path().join("log.txt")).unwrap(), "line\n");
+    }
+
+    #[test]
+    fn test_idle_warning_lead() {
+        assert_eq!(idle_warning_lead(Duration::from_secs(3600)), IDLE_WARNING_LEAD);
+        assert_eq!(idle_warning_lead(Duration::from_secs(60)), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::protocol::{message::WatchRunMessage, FshError, FshResult};
+use crate::sandbox::ProcessTable;
+use crate::server::{Session, SessionStats};
+
+/// The server's live sessions, shared by connections, the admin API and
+/// statistics.
+#[derive(Debug, Clone, Default)]
+pub struct SessionManager {
+    sessions: Arc<RwLock<HashMap<String, Arc<Session>>>>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track `session` until it is removed or closed.
+    pub async fn insert(&self, session: Arc<Session>) {
+        self.sessions.write().await.insert(session.id().to_string(), session);
+    }
+
+    /// Stop tracking a session without closing it.
+    pub async fn remove(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.write().await.remove(session_id)
+    }
+
+    pub async fn get(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.read().await.get(session_id).cloned()
+    }
+
+    pub async fn ids(&self) -> Vec<String> {
+        self.sessions.read().await.keys().cloned().collect()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.sessions.read().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.sessions.read().await.is_empty()
+    }
+
+    /// Every tracked session, in no particular order.
+    pub async fn sessions(&self) -> Vec<Arc<Session>> {
+        self.sessions.read().await.values().cloned().collect()
+    }
+
+    /// Sessions in `folder` that are still active.
+    pub async fn folder_count(&self, folder: &str) -> usize {
+        let mut count = 0;
+        for session in self.sessions().await {
+            if session.folder_info().name == folder && session.is_active().await {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Statistics for every session, oldest first.
+    pub async fn stats(&self) -> Vec<SessionStats> {
+        let mut stats: Vec<SessionStats> = self.sessions.read().await.values()
+            .map(|session| session.stats())
+            .collect();
+        stats.sort_by_key(|session| session.started_at);
+        stats
+    }
+
+    /// Sample what every session's commands use, reading the process table
+    /// once for all of them.
+    pub async fn sample_resources(&self) {
+        let Ok(table) = tokio::task::spawn_blocking(ProcessTable::read).await else {
+            return;
+        };
+        for session in self.sessions.read().await.values() {
+            session.sample_resources(&table);
+        }
+    }
+
+    /// Stop tracking a session and close it.
+    pub async fn close(&self, session_id: &str) -> FshResult<()> {
+        let session = self.remove(session_id).await
+            .ok_or_else(|| FshError::SessionNotFound(session_id.to_string()))?;
+        session.close().await?;
+        info!("Session {} closed", session_id);
+        Ok(())
+    }
+
+    /// Close every session, returning how many there were.
+    pub async fn close_all(&self) -> usize {
+        let sessions: Vec<Arc<Session>> = self.sessions.write().await.drain().map(|(_, session)| session).collect();
+        for session in &sessions {
+            info!("Closing session {}", session.id());
+            if let Err(e) = session.close().await {
+                error!("Error closing session {}: {}", session.id(), e);
+            }
+        }
+        sessions.len()
+    }
+
+    /// Close every session opened with one of `token_ids`, returning how many
+    /// were closed.
+    pub async fn revoke_tokens(&self, token_ids: &[String], reason: &str) -> usize {
+        let revoked: Vec<Arc<Session>> = self.sessions().await.into_iter()
+            .filter(|session| session.token_id().is_some_and(|id| token_ids.iter().any(|token_id| token_id == id)))
+            .collect();
+
+        for session in &revoked {
+            if let Err(e) = session.revoke(reason).await {
+                error!("Failed to revoke session {}: {}", session.id(), e);
+            }
+        }
+        revoked.len()
+    }
+
+    /// Send an operator's notice to every session that can display it,
+    /// returning how many received it.
+    pub async fn broadcast(&self, message: &str) -> usize {
+        let mut delivered = 0;
+        for session in self.sessions().await {
+            match session.notify(message).await {
+                Ok(true) => delivered += 1,
+                Ok(false) => debug!("Session {} can't display notices", session.id()),
+                Err(e) => warn!("Failed to send notice to session {}: {}", session.id(), e),
+            }
+        }
+        info!("Sent notice to {} session(s): {}", delivered, message);
+        delivered
+    }
+
+    /// Send a watch rule's run to the sessions on its folder that can
+    /// display it, returning how many received it.
+    pub async fn send_watch_run(&self, run: &WatchRunMessage) -> usize {
+        let mut delivered = 0;
+        for session in self.sessions().await {
+            if session.folder_info().name != run.folder {
+                continue;
+            }
+            match session.send_watch_run(run).await {
+                Ok(true) => delivered += 1,
+                Ok(false) => debug!("Session {} can't display watch runs", session.id()),
+                Err(e) => warn!("Failed to send watch run to session {}: {}", session.id(), e),
+            }
+        }
+        delivered
+    }
+
+    /// Close sessions idle past their timeout and forget ones that have
+    /// already ended, returning how many were dropped. Sessions time
+    /// themselves out; this catches any whose message loop is stuck.
+    pub async fn remove_expired(&self) -> usize {
+        let mut expired = Vec::new();
+        for session in self.sessions().await {
+            let timed_out = match session.idle_timeout() {
+                Some(idle_timeout) => session.idle_duration().await >= idle_timeout,
+                None => false,
+            };
+            if timed_out || !session.is_active().await {
+                expired.push(session);
+            }
+        }
+
+        for session in &expired {
+            self.remove(session.id()).await;
+            if session.is_active().await {
+                info!("Session {} expired", session.id());
+                if let Err(e) = session.close_with_reason("Session expired").await {
+                    error!("Error closing expired session {}: {}", session.id(), e);
+                }
+            }
+        }
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FolderConfig, KeepaliveConfig};
+    use crate::protocol::{ClientInfo, FshCodec};
+    use crate::server::{CommandLimits, EventBus, OperationLog, Plugins, SessionHistory, SessionUsage, TransferLimits};
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn test_session(id: &str, folder: &FolderConfig, idle_timeout: Option<Duration>) -> (Arc<Session>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let session = Session::new(
+            id.to_string(),
+            FshCodec::framed(server.into()),
+            folder.to_folder_info(),
+            folder.clone(),
+            ClientInfo { platform: "test".to_string(), app_version: "1.0".to_string(), app_name: "test".to_string() },
+            "127.0.0.1:12346".to_string(),
+            idle_timeout,
+            KeepaliveConfig::default(),
+            TransferLimits::new(),
+            CommandLimits::unlimited(),
+            None,
+            None,
+            None,
+            SessionUsage::untracked(&folder.name),
+            SessionHistory::new(&Default::default()),
+            EventBus::default(),
+            Plugins::default(),
+            None,
+            None,
+            None,
+            Arc::new(OperationLog::new()),
+            None,
+            None,
+        ).await.unwrap();
+        (Arc::new(session), client)
+    }
+
+    #[tokio::test]
+    async fn test_session_bookkeeping() {
+        let temp_dir = TempDir::new().unwrap();
+        let docs = FolderConfig::new("docs".to_string(), temp_dir.path());
+        let code = FolderConfig::new("code".to_string(), temp_dir.path());
+        let manager = SessionManager::new();
+        assert!(manager.is_empty().await);
+
+        let (first, _first_client) = test_session("first", &docs, None).await;
+        let (second, _second_client) = test_session("second", &docs, None).await;
+        let (third, _third_client) = test_session("third", &code, None).await;
+        for session in [&first, &second, &third] {
+            manager.insert(Arc::clone(session)).await;
+        }
+        assert_eq!(manager.len().await, 3);
+        assert_eq!(manager.folder_count("docs").await, 2);
+        assert_eq!(manager.folder_count("code").await, 1);
+        assert_eq!(manager.get("second").await.unwrap().id(), "second");
+        let stats = manager.stats().await;
+        assert_eq!(stats.len(), 3);
+        assert!(stats.windows(2).all(|pair| pair[0].started_at <= pair[1].started_at));
+
+        manager.close("first").await.unwrap();
+        assert!(!first.is_active().await);
+        assert!(manager.get("first").await.is_none());
+        assert!(matches!(manager.close("first").await, Err(FshError::SessionNotFound(_))));
+        assert_eq!(manager.folder_count("docs").await, 1);
+
+        // Ended sessions that were never removed are swept up
+        second.close().await.unwrap();
+        assert_eq!(manager.remove_expired().await, 1);
+        assert_eq!(manager.ids().await, vec!["third".to_string()]);
+
+        assert_eq!(manager.close_all().await, 1);
+        assert!(!third.is_active().await);
+        assert!(manager.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_expire() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = FolderConfig::new("docs".to_string(), temp_dir.path());
+        let manager = SessionManager::new();
+
+        let (session, _client) = test_session("idle", &folder, Some(Duration::from_millis(50))).await;
+        manager.insert(Arc::clone(&session)).await;
+        assert_eq!(manager.remove_expired().await, 0);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(manager.remove_expired().await, 1);
+        assert!(!session.is_active().await);
+        assert!(manager.is_empty().await);
+    }
+}
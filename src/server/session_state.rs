@@ -0,0 +1,259 @@
+use crate::protocol::{message::HistoryEntry, FshError, FshResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// What a session needs to be resumed after the server restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedSession {
+    pub folder: String,
+    /// Relative to the folder root.
+    pub working_directory: PathBuf,
+    /// Variables the client set, or unset with `None`, over the folder's own.
+    pub environment: HashMap<String, Option<String>>,
+    /// The session's own commands, oldest first.
+    pub history: Vec<HistoryEntry>,
+    /// Whoever may resume it: the hash of the token the session logged in
+    /// with, or its client certificate's user.
+    pub owner: Option<String>,
+    pub saved_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl SavedSession {
+    /// Whether the two hold the same state, whenever they were saved.
+    fn same_state(&self, other: &SavedSession) -> bool {
+        self.folder == other.folder
+            && self.working_directory == other.working_directory
+            && self.environment == other.environment
+            && self.history == other.history
+            && self.owner == other.owner
+    }
+}
+
+/// Saved sessions, one JSON file per session id in a directory only the
+/// server can read, since variables may hold secrets.
+#[derive(Debug)]
+pub struct SessionStore {
+    directory: PathBuf,
+    retention: chrono::Duration,
+}
+
+impl SessionStore {
+    /// Open the store in `directory`, dropping sessions saved more than
+    /// `retention_hours` ago.
+    pub fn open(directory: &Path, retention_hours: u64) -> FshResult<Self> {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| FshError::io(format!("Failed to create {}", directory.display()), e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(directory, std::fs::Permissions::from_mode(0o700))
+                .map_err(|e| FshError::io(format!("Failed to restrict {}", directory.display()), e))?;
+        }
+
+        let store = Self {
+            directory: directory.to_path_buf(),
+            retention: chrono::Duration::hours(retention_hours as i64),
+        };
+        store.prune();
+        Ok(store)
+    }
+
+    pub fn save(&self, session_id: &str, session: &SavedSession) -> FshResult<()> {
+        let file = self.file(session_id)
+            .ok_or_else(|| FshError::SessionNotFound(format!("Invalid session id '{}'", session_id)))?;
+        let content = serde_json::to_string(session)
+            .map_err(|e| FshError::ConfigError(format!("Failed to serialize session {}: {}", session_id, e)))?;
+
+        // Write a temporary file first so a crash never leaves a truncated one
+        let temp_file = file.with_extension("tmp");
+        write_private(&temp_file, content.as_bytes())
+            .and_then(|()| std::fs::rename(&temp_file, &file))
+            .map_err(|e| FshError::io(format!("Failed to write {}", file.display()), e))
+    }
+
+    /// The session saved as `session_id`, unless there is none or it expired.
+    pub fn load(&self, session_id: &str) -> Option<SavedSession> {
+        let file = self.file(session_id)?;
+        let content = std::fs::read_to_string(&file).ok()?;
+        match serde_json::from_str::<SavedSession>(&content) {
+            Ok(session) if !self.expired(&session) => Some(session),
+            Ok(_) => {
+                self.remove(session_id);
+                None
+            }
+            Err(e) => {
+                warn!("Ignoring saved session in {}: {}", file.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        if let Some(file) = self.file(session_id) {
+            if let Err(e) = std::fs::remove_file(&file) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to remove saved session {}: {}", file.display(), e);
+                }
+            }
+        }
+    }
+
+    fn expired(&self, session: &SavedSession) -> bool {
+        chrono::Utc::now() - session.saved_at > self.retention
+    }
+
+    /// Remove expired sessions and leftovers from interrupted saves.
+    fn prune(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.directory) else {
+            return;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let stale = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("tmp") => true,
+                Some("json") => std::fs::read_to_string(&path).ok()
+                    .and_then(|content| serde_json::from_str::<SavedSession>(&content).ok())
+                    .is_none_or(|session| self.expired(&session)),
+                _ => false,
+            };
+            if stale {
+                debug!("Removing stale saved session {}", path.display());
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+
+    /// The file for `session_id`, if it is a plausible session id.
+    fn file(&self, session_id: &str) -> Option<PathBuf> {
+        let valid = !session_id.is_empty() && session_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        valid.then(|| self.directory.join(format!("{}.json", session_id)))
+    }
+}
+
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, content)
+}
+
+/// Keeps one session's saved state up to date as the client changes it.
+#[derive(Debug)]
+pub struct SessionPersistence {
+    store: Arc<SessionStore>,
+    owner: Option<String>,
+    restored: Option<SavedSession>,
+    environment: HashMap<String, Option<String>>,
+    last_saved: Option<SavedSession>,
+}
+
+impl SessionPersistence {
+    pub fn new(store: Arc<SessionStore>, owner: Option<String>) -> Self {
+        Self {
+            store,
+            owner,
+            restored: None,
+            environment: HashMap::new(),
+            last_saved: None,
+        }
+    }
+
+    /// Start the session from `saved` instead of the folder root.
+    pub fn with_restored(mut self, saved: SavedSession) -> Self {
+        self.restored = Some(saved);
+        self
+    }
+
+    pub fn take_restored(&mut self) -> Option<SavedSession> {
+        self.restored.take()
+    }
+
+    /// Remember that the client set `name`, or unset it with `None`.
+    pub fn set_env(&mut self, name: &str, value: Option<String>) {
+        self.environment.insert(name.to_string(), value);
+    }
+
+    /// Forget the variables set so far, e.g. once the session moves to
+    /// another folder with its own.
+    pub fn clear_env(&mut self) {
+        self.environment.clear();
+    }
+
+    /// Save the session's state if it changed since it was last saved.
+    pub fn save(&mut self, session_id: &str, folder: &str, working_directory: PathBuf, history: Vec<HistoryEntry>) {
+        let session = SavedSession {
+            folder: folder.to_string(),
+            working_directory,
+            environment: self.environment.clone(),
+            history,
+            owner: self.owner.clone(),
+            saved_at: chrono::Utc::now(),
+        };
+        if self.last_saved.as_ref().is_some_and(|saved| saved.same_state(&session)) {
+            return;
+        }
+        match self.store.save(session_id, &session) {
+            Ok(()) => self.last_saved = Some(session),
+            Err(e) => warn!("Failed to save session {}: {}", session_id, e),
+        }
+    }
+
+    /// Forget the session once the client has left it for good.
+    pub fn remove(&self, session_id: &str) {
+        self.store.remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SessionStateConfig;
+    use tempfile::TempDir;
+
+    fn saved(folder: &str) -> SavedSession {
+        SavedSession {
+            folder: folder.to_string(),
+            working_directory: PathBuf::from("src"),
+            environment: HashMap::from([("EDITOR".to_string(), Some("vim".to_string()))]),
+            history: Vec::new(),
+            owner: Some("owner".to_string()),
+            saved_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_session_store_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = SessionStore::open(temp_dir.path(), SessionStateConfig::default().retention_hours).unwrap();
+
+        store.save("abc-123", &saved("docs")).unwrap();
+        assert_eq!(store.load("abc-123").unwrap().folder, "docs");
+        assert!(store.load("missing").is_none());
+        assert!(store.save("../escape", &saved("docs")).is_err());
+
+        // A restarted server finds it again
+        let reopened = SessionStore::open(temp_dir.path(), SessionStateConfig::default().retention_hours).unwrap();
+        assert_eq!(reopened.load("abc-123").unwrap().working_directory, PathBuf::from("src"));
+        reopened.remove("abc-123");
+        assert!(reopened.load("abc-123").is_none());
+    }
+
+    #[test]
+    fn test_expired_sessions_dropped() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = SessionStateConfig::default();
+        let store = SessionStore::open(temp_dir.path(), config.retention_hours).unwrap();
+        let mut old = saved("docs");
+        old.saved_at = chrono::Utc::now() - chrono::Duration::hours(config.retention_hours as i64 + 1);
+        store.save("old", &old).unwrap();
+
+        SessionStore::open(temp_dir.path(), config.retention_hours).unwrap();
+        assert!(!temp_dir.path().join("old.json").exists());
+    }
+}
@@ -0,0 +1,462 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::config::StatsConfig;
+use crate::protocol::{message::ResourceUsage, FshError, FshResult};
+use crate::sandbox::{ProcessIds, ProcessTable};
+use crate::server::SessionManager;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// The sockets the server accepts connections on.
+    #[serde(default)]
+    pub listen_addresses: Vec<String>,
+    pub active_sessions: usize,
+    /// Connections holding a permit, including those still handshaking.
+    pub active_connections: usize,
+    /// Connections that haven't finished the handshake yet.
+    #[serde(default)]
+    pub pending_handshakes: usize,
+    pub max_connections: usize,
+    pub accepted_connections: u64,
+    /// Connections dropped at accept time because `max_connections` was reached.
+    pub rejected_connections: u64,
+    /// Connections that never got past authentication.
+    pub auth_failed_connections: u64,
+    /// Most connections open at once since the server started.
+    pub peak_connections: usize,
+    /// When the server started listening; `None` until then.
+    pub started_at: Option<DateTime<Utc>>,
+    pub uptime_seconds: u64,
+    /// Totals for every folder that has had a session, including earlier runs
+    /// when `[stats] file` is set.
+    pub folders: Vec<FolderStats>,
+    pub sessions: Vec<SessionStats>,
+}
+
+/// Running connection totals since the server started.
+#[derive(Debug, Default)]
+pub struct ConnectionCounters {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    auth_failed: AtomicU64,
+    peak_concurrent: AtomicUsize,
+    /// Unix time in milliseconds, 0 before the server starts.
+    started_at_ms: AtomicI64,
+    accepting: AtomicBool,
+}
+
+impl ConnectionCounters {
+    pub(crate) fn mark_started(&self) {
+        self.started_at_ms.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    /// Whether the accept loop is running.
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    /// Count an accepted connection, `active` being how many are open including it.
+    pub(crate) fn record_accepted(&self, active: usize) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.peak_concurrent.fetch_max(active, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_auth_failed(&self) {
+        self.auth_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn started_at(&self) -> Option<DateTime<Utc>> {
+        match self.started_at_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => DateTime::from_timestamp_millis(ms),
+        }
+    }
+}
+
+/// Connections open per client address, for `max_connections_per_ip`.
+#[derive(Debug, Default)]
+pub struct IpConnections {
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpConnections {
+    /// Count a connection from `ip` unless `limit` are already open. The
+    /// connection is counted until the returned guard is dropped.
+    pub(crate) fn try_open(self: &Arc<Self>, ip: IpAddr, limit: usize) -> Option<IpConnectionGuard> {
+        let mut open = self.lock();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(IpConnectionGuard { connections: Arc::clone(self), ip })
+    }
+
+    pub fn open_from(&self, ip: IpAddr) -> usize {
+        self.lock().get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> usize {
+        self.lock().values().sum()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One connection counted in `IpConnections`.
+#[derive(Debug)]
+pub struct IpConnectionGuard {
+    connections: Arc<IpConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.connections.lock();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Everything `ServerStats` is built from, shared with the admin API.
+#[derive(Debug, Clone)]
+pub struct StatsCollector {
+    pub(crate) sessions: SessionManager,
+    pub(crate) connection_permits: Arc<Semaphore>,
+    pub(crate) counters: Arc<ConnectionCounters>,
+    pub(crate) handshakes: Arc<IpConnections>,
+    pub(crate) usage: UsageTracker,
+    pub(crate) max_connections: usize,
+    pub(crate) listen_addresses: Vec<std::net::SocketAddr>,
+}
+
+impl StatsCollector {
+    pub async fn collect(&self) -> ServerStats {
+        let sessions = self.sessions.stats().await;
+        let started_at = self.counters.started_at();
+
+        ServerStats {
+            listen_addresses: self.listen_addresses.iter().map(|addr| addr.to_string()).collect(),
+            active_sessions: sessions.len(),
+            active_connections: self.max_connections - self.connection_permits.available_permits(),
+            pending_handshakes: self.handshakes.total(),
+            max_connections: self.max_connections,
+            accepted_connections: self.counters.accepted.load(Ordering::Relaxed),
+            rejected_connections: self.counters.rejected.load(Ordering::Relaxed),
+            auth_failed_connections: self.counters.auth_failed.load(Ordering::Relaxed),
+            peak_connections: self.counters.peak_concurrent.load(Ordering::Relaxed),
+            started_at,
+            uptime_seconds: started_at
+                .map(|started_at| (Utc::now() - started_at).num_seconds().max(0) as u64)
+                .unwrap_or(0),
+            folders: self.usage.folders(),
+            sessions,
+        }
+    }
+}
+
+/// Usage counters, kept per session and summed per folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStats {
+    pub commands_run: u64,
+    /// Commands that exited with a non-zero code or could not be started.
+    pub command_failures: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Wall time spent running commands.
+    pub command_time_ms: u64,
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+impl UsageStats {
+    fn record_command(&mut self, duration: Duration, exit_code: i32) {
+        self.commands_run += 1;
+        if exit_code != 0 {
+            self.command_failures += 1;
+        }
+        self.command_time_ms += duration.as_millis() as u64;
+        self.last_activity = Some(Utc::now());
+    }
+
+    fn record_read(&mut self, bytes: u64) {
+        self.bytes_read += bytes;
+        self.last_activity = Some(Utc::now());
+    }
+
+    fn record_write(&mut self, bytes: u64) {
+        self.bytes_written += bytes;
+        self.last_activity = Some(Utc::now());
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderStats {
+    pub folder: String,
+    /// Sessions opened on the folder.
+    pub sessions: u64,
+    #[serde(flatten)]
+    pub usage: UsageStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub session_id: String,
+    pub folder: String,
+    pub client_addr: String,
+    pub started_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub usage: UsageStats,
+    /// What its running commands use, as last sampled.
+    #[serde(default)]
+    pub resources: ResourceUsage,
+}
+
+/// Per-folder usage totals, optionally kept across restarts in a JSON file.
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    folders: Arc<Mutex<BTreeMap<String, FolderStats>>>,
+    file: Option<PathBuf>,
+}
+
+impl UsageTracker {
+    /// Start from the totals saved in `config.file`, if there are any.
+    pub fn new(config: &StatsConfig) -> Self {
+        let folders = config.file.as_ref()
+            .filter(|file| file.exists())
+            .and_then(|file| {
+                let loaded = std::fs::read_to_string(file).map_err(|e| e.to_string())
+                    .and_then(|content| serde_json::from_str::<Vec<FolderStats>>(&content).map_err(|e| e.to_string()));
+                match loaded {
+                    Ok(folders) => Some(folders),
+                    Err(e) => {
+                        warn!("Ignoring usage statistics in {}: {}", file.display(), e);
+                        None
+                    }
+                }
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|stats| (stats.folder.clone(), stats))
+            .collect();
+
+        Self {
+            folders: Arc::new(Mutex::new(folders)),
+            file: config.file.clone(),
+        }
+    }
+
+    /// Count a new session on `folder` and return its recorder.
+    pub fn start_session(&self, folder: &str) -> SessionUsage {
+        self.update(folder, |stats| stats.sessions += 1);
+        SessionUsage {
+            folder: folder.to_string(),
+            tracker: Some(self.clone()),
+            ..Default::default()
+        }
+    }
+
+    pub fn folders(&self) -> Vec<FolderStats> {
+        self.folders.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Write the totals to the stats file, if one is configured.
+    pub fn persist(&self) -> FshResult<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let content = serde_json::to_string_pretty(&self.folders())
+            .map_err(|e| FshError::ConfigError(format!("Failed to serialize usage statistics: {}", e)))?;
+
+        // Write a temporary file first so a crash never leaves a truncated one
+        let temp_file = file.with_extension("tmp");
+        std::fs::write(&temp_file, content)
+            .and_then(|()| std::fs::rename(&temp_file, file))
+            .map_err(|e| FshError::io(format!("Failed to write {}", file.display()), e))
+    }
+
+    fn update(&self, folder: &str, update: impl FnOnce(&mut FolderStats)) {
+        let mut folders = self.folders.lock().unwrap();
+        let stats = folders.entry(folder.to_string()).or_insert_with(|| FolderStats {
+            folder: folder.to_string(),
+            ..Default::default()
+        });
+        update(stats);
+    }
+}
+
+/// Records one session's usage, adding it to its folder's totals.
+#[derive(Debug, Clone, Default)]
+pub struct SessionUsage {
+    folder: String,
+    stats: Arc<Mutex<UsageStats>>,
+    tracker: Option<UsageTracker>,
+    processes: ProcessIds,
+    resources: Arc<Mutex<ResourceSample>>,
+}
+
+/// The latest resource sample of a session, and the CPU time it saw, to
+/// tell how busy the session was until the next one.
+#[derive(Debug, Default)]
+struct ResourceSample {
+    usage: ResourceUsage,
+    cpu_time: Duration,
+    taken_at: Option<std::time::Instant>,
+}
+
+impl SessionUsage {
+    /// A recorder for a session whose usage isn't added to any totals.
+    pub fn untracked(folder: &str) -> Self {
+        Self { folder: folder.to_string(), ..Default::default() }
+    }
+
+    pub fn record_command(&self, duration: Duration, exit_code: i32) {
+        self.record(|usage| usage.record_command(duration, exit_code));
+    }
+
+    pub fn record_read(&self, bytes: u64) {
+        self.record(|usage| usage.record_read(bytes));
+    }
+
+    pub fn record_write(&self, bytes: u64) {
+        self.record(|usage| usage.record_write(bytes));
+    }
+
+    pub fn snapshot(&self) -> UsageStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// The processes the session's shell runs, to be sampled.
+    pub fn processes(&self) -> ProcessIds {
+        self.processes.clone()
+    }
+
+    pub fn resources(&self) -> ResourceUsage {
+        self.resources.lock().unwrap().usage
+    }
+
+    /// Sample what the session's commands and their children use now.
+    pub fn sample_resources(&self, table: &ProcessTable) {
+        let tree = table.tree_usage(&self.processes.snapshot());
+        let now = std::time::Instant::now();
+        let mut sample = self.resources.lock().unwrap();
+        let cpu_percent = match sample.taken_at {
+            Some(taken_at) if now > taken_at => {
+                let busy = tree.cpu_time.saturating_sub(sample.cpu_time);
+                (busy.as_secs_f64() * 100.0 / (now - taken_at).as_secs_f64()).round() as u32
+            }
+            _ => 0,
+        };
+        *sample = ResourceSample {
+            usage: ResourceUsage { cpu_percent, memory_bytes: tree.memory_bytes, processes: tree.processes },
+            cpu_time: tree.cpu_time,
+            taken_at: Some(now),
+        };
+    }
+
+    fn record(&self, record: impl Fn(&mut UsageStats)) {
+        record(&mut self.stats.lock().unwrap());
+        if let Some(tracker) = &self.tracker {
+            tracker.update(&self.folder, |stats| record(&mut stats.usage));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_connection_limit() {
+        let connections = Arc::new(IpConnections::default());
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+
+        let first = connections.try_open(ip, 2).unwrap();
+        let _second = connections.try_open(ip, 2).unwrap();
+        assert!(connections.try_open(ip, 2).is_none());
+        assert!(connections.try_open(other, 2).is_some());
+
+        drop(first);
+        assert_eq!(connections.open_from(ip), 1);
+        assert!(connections.try_open(ip, 2).is_some());
+    }
+
+    #[test]
+    fn test_usage_totals_persist() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = StatsConfig {
+            file: Some(temp_dir.path().join("stats.json")),
+            ..Default::default()
+        };
+
+        let tracker = UsageTracker::new(&config);
+        let first = tracker.start_session("project");
+        let second = tracker.start_session("project");
+        first.record_command(Duration::from_millis(40), 0);
+        second.record_command(Duration::from_millis(60), 1);
+        second.record_read(100);
+        second.record_write(25);
+
+        assert_eq!(first.snapshot().commands_run, 1);
+        assert_eq!(second.snapshot().bytes_read, 100);
+
+        let folders = tracker.folders();
+        assert_eq!(folders.len(), 1);
+        assert_eq!(folders[0].sessions, 2);
+        assert_eq!(folders[0].usage.commands_run, 2);
+        assert_eq!(folders[0].usage.command_failures, 1);
+        assert_eq!(folders[0].usage.command_time_ms, 100);
+        assert_eq!(folders[0].usage.bytes_written, 25);
+
+        // Totals survive a restart
+        tracker.persist().unwrap();
+        let restarted = UsageTracker::new(&config);
+        assert_eq!(restarted.folders(), folders);
+
+        // Untracked sessions only count for themselves
+        let untracked = SessionUsage::untracked("project");
+        untracked.record_read(5);
+        assert_eq!(untracked.snapshot().bytes_read, 5);
+        assert_eq!(restarted.folders()[0].usage.bytes_read, 100);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sample_resources() {
+        let usage = SessionUsage::untracked("project");
+        assert_eq!(usage.resources(), ResourceUsage::default());
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        usage.processes().insert(child.id());
+        usage.sample_resources(&ProcessTable::read());
+        let resources = usage.resources();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert_eq!(resources.processes, 1);
+        assert!(resources.memory_bytes > 0);
+    }
+}
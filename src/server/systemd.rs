@@ -0,0 +1,131 @@
+use tokio::task::JoinHandle;
+#[cfg(unix)]
+use tracing::{debug, warn};
+
+use crate::protocol::{FshResult, TcpListeners};
+
+/// The TCP sockets systemd passed in for socket activation, if the server
+/// was started by a `.socket` unit. Other kinds of sockets are refused.
+#[cfg(unix)]
+pub fn activated_listeners() -> FshResult<Option<TcpListeners>> {
+    use std::os::fd::FromRawFd;
+    use crate::protocol::FshError;
+
+    let fds = sd_notify::listen_fds()
+        .map_err(|e| FshError::io("Invalid socket activation environment", e))?;
+    let mut listeners = Vec::new();
+    for fd in fds {
+        // systemd hands each descriptor to this process alone
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        let is_tcp = socket.r#type().is_ok_and(|kind| kind == socket2::Type::STREAM)
+            && socket.local_addr().is_ok_and(|addr| addr.as_socket().is_some());
+        if !is_tcp {
+            return Err(FshError::ConfigError(format!("Socket {} passed by systemd is not a TCP socket", fd)));
+        }
+        socket.set_nonblocking(true)
+            .map_err(|e| FshError::io("Failed to use socket passed by systemd", e))?;
+        let listener = tokio::net::TcpListener::from_std(socket.into())
+            .map_err(|e| FshError::io("Failed to use socket passed by systemd", e))?;
+        listeners.push(listener);
+    }
+    Ok((!listeners.is_empty()).then(|| TcpListeners::from_listeners(listeners)))
+}
+
+#[cfg(not(unix))]
+pub fn activated_listeners() -> FshResult<Option<TcpListeners>> {
+    Ok(None)
+}
+
+/// Tell systemd the server is accepting connections. Does nothing unless
+/// started by a `Type=notify` service.
+pub fn notify_ready(status: &str) {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Ready, sd_notify::NotifyState::Status(status)]);
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Tell systemd the server is shutting down.
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+#[cfg(unix)]
+fn notify(states: &[sd_notify::NotifyState]) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Resolves on Ctrl+C or, on unix, SIGTERM — how systemd asks a service
+/// to stop.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Pings systemd's watchdog while held.
+#[derive(Debug)]
+pub struct Watchdog(JoinHandle<()>);
+
+impl Watchdog {
+    /// Start pinging at half the interval systemd expects, if the service
+    /// has `WatchdogSec=` set.
+    pub fn start() -> Option<Self> {
+        #[cfg(unix)]
+        {
+            let mut usec = 0;
+            if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+                return None;
+            }
+            let period = std::time::Duration::from_micros(usec / 2);
+            debug!("Pinging the systemd watchdog every {:?}", period);
+            Some(Self(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    notify(&[sd_notify::NotifyState::Watchdog]);
+                }
+            })))
+        }
+        #[cfg(not(unix))]
+        None
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_not_socket_activated() {
+        // Without LISTEN_PID naming this process nothing is taken over
+        assert!(activated_listeners().unwrap().is_none());
+        assert!(Watchdog::start().is_none());
+        notify_ready("Listening");
+        notify_stopping();
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{message::WatchRunMessage, FshError, FshResult};
+use crate::sandbox::TRASH_DIR;
+use crate::security::name_matches;
+use crate::server::{run_in_folder, SessionManager, OUTPUT_SPILL_DIR};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// How often watched folders are scanned for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Changed files named in a `WatchRun`, at most.
+const MAX_CHANGED_FILES: usize = 10;
+
+fn default_ignore() -> Vec<String> {
+    vec!["target".to_string(), "node_modules".to_string()]
+}
+
+fn default_debounce_ms() -> u64 {
+    500
+}
+
+fn default_timeout_seconds() -> u64 {
+    600
+}
+
+/// Runs a command in a folder whenever files matching its patterns change,
+/// e.g. `cargo check` on `*.rs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchRule {
+    pub name: String,
+    /// File patterns; ones without a `/` match the file name anywhere, like
+    /// `.gitignore`, and `**` spans directories.
+    pub patterns: Vec<String>,
+    /// Directories never scanned, by the same rules. `.git` and the trash
+    /// never are.
+    #[serde(default = "default_ignore")]
+    pub ignore: Vec<String>,
+    /// Command line run from the folder root under the folder's sandbox,
+    /// with steps separated by `&&` as in macros.
+    pub command: String,
+    /// How long files must stay unchanged before the command runs.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl WatchRule {
+    pub fn new(name: &str, patterns: &[&str], command: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            ignore: default_ignore(),
+            command: command.to_string(),
+            debounce_ms: default_debounce_ms(),
+            timeout_seconds: default_timeout_seconds(),
+        }
+    }
+
+    pub fn validate(&self) -> FshResult<()> {
+        if self.name.trim().is_empty() {
+            return Err(FshError::ConfigError("Watch rule name cannot be empty".to_string()));
+        }
+        if self.patterns.is_empty() {
+            return Err(FshError::ConfigError(format!("Watch rule '{}' has no patterns", self.name)));
+        }
+        if self.command.split("&&").any(|step| step.trim().is_empty()) {
+            return Err(FshError::ConfigError(format!("Watch rule '{}' contains an empty step", self.name)));
+        }
+        if self.timeout_seconds == 0 {
+            return Err(FshError::ConfigError(format!("Watch rule '{}' timeout_seconds must be greater than 0", self.name)));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, relative: &str) -> bool {
+        self.patterns.iter().any(|pattern| path_matches(pattern, relative))
+    }
+
+    fn ignores(&self, relative: &str) -> bool {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        name == ".git" || name == TRASH_DIR || name == OUTPUT_SPILL_DIR || self.ignore.iter().any(|pattern| path_matches(pattern, relative))
+    }
+}
+
+fn path_matches(pattern: &str, relative: &str) -> bool {
+    if pattern.contains('/') {
+        name_matches(pattern.trim_start_matches('/'), relative)
+    } else {
+        name_matches(pattern, relative.rsplit('/').next().unwrap_or(relative))
+    }
+}
+
+/// Modification time and size of each watched file, by path relative to
+/// the folder root.
+type Snapshot = HashMap<String, (Option<SystemTime>, u64)>;
+
+fn snapshot(root: &Path, rule: &WatchRule) -> Snapshot {
+    let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_string_lossy().replace('\\', "/");
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !entry.file_type().is_dir() || !rule.ignores(&relative(entry.path())))
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let path = relative(entry.path());
+            let metadata = entry.metadata().ok()?;
+            rule.matches(&path).then(|| (path, (metadata.modified().ok(), metadata.len())))
+        })
+        .collect()
+}
+
+async fn scan(root: &Path, rule: &WatchRule) -> Snapshot {
+    let (root, rule) = (root.to_path_buf(), rule.clone());
+    tokio::task::spawn_blocking(move || snapshot(&root, &rule)).await.unwrap_or_default()
+}
+
+/// Files added, changed or removed between two snapshots.
+fn changed_files(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    let mut changed: Vec<String> = after.iter()
+        .filter(|(path, state)| before.get(*path) != Some(state))
+        .map(|(path, _)| path.clone())
+        .chain(before.keys().filter(|path| !after.contains_key(*path)).cloned())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Scans folders with watch rules by polling and sends the output of each
+/// run to the sessions on the folder.
+#[derive(Debug, Clone)]
+pub struct FolderWatcher {
+    config: Arc<Config>,
+    sessions: SessionManager,
+}
+
+impl FolderWatcher {
+    pub fn new(config: Arc<Config>, sessions: SessionManager) -> Self {
+        Self { config, sessions }
+    }
+
+    pub fn has_rules(&self) -> bool {
+        self.config.folders.iter().any(|folder| !folder.watch.is_empty())
+    }
+
+    /// Watch every rule's folder until dropped.
+    pub async fn run(self) {
+        let rules = self.config.folders.iter()
+            .flat_map(|folder| folder.watch.iter().map(move |rule| (folder, rule)))
+            .map(|(folder, rule)| self.watch(folder, rule));
+        futures::future::join_all(rules).await;
+    }
+
+    async fn watch(&self, folder: &FolderConfig, rule: &WatchRule) {
+        let root = folder.get_path();
+        let debounce = Duration::from_millis(rule.debounce_ms);
+        let mut last = scan(&root, rule).await;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let mut current = scan(&root, rule).await;
+            if current == last {
+                continue;
+            }
+
+            // Wait for a burst of saves to finish
+            loop {
+                tokio::time::sleep(debounce).await;
+                let settled = scan(&root, rule).await;
+                if settled == current {
+                    break;
+                }
+                current = settled;
+            }
+
+            let changed = changed_files(&last, &current);
+            let run = self.run_rule(folder, rule, changed).await;
+            let delivered = self.sessions.send_watch_run(&run).await;
+            info!("Watch rule {}/{} ran '{}' ({:?}), sent to {} session(s)",
+                  folder.name, rule.name, rule.command, run.exit_code, delivered);
+
+            // What the command itself changed doesn't trigger it again
+            last = scan(&root, rule).await;
+        }
+    }
+
+    pub async fn run_rule(&self, folder: &FolderConfig, rule: &WatchRule, mut changed: Vec<String>) -> WatchRunMessage {
+        changed.truncate(MAX_CHANGED_FILES);
+        let mut output = String::new();
+        let result = run_in_folder(folder, &rule.command, rule.timeout_seconds, &mut output).await;
+        if let Err(e) = &result {
+            warn!("Watch rule {}/{} failed: {}", folder.name, rule.name, e);
+        }
+        WatchRunMessage {
+            folder: folder.name.clone(),
+            rule: rule.name.clone(),
+            command: rule.command.clone(),
+            changed,
+            exit_code: result.as_ref().ok().copied(),
+            error: result.err().map(|e| e.to_string()),
+            output,
+            finished_at: chrono::Utc::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_watched_files() {
+        let temp_dir = TempDir::new().unwrap();
+        for file in ["src/main.rs", "src/notes.txt", "target/debug/build.rs", ".git/hooks/x.rs", "README.md"] {
+            let path = temp_dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "x").unwrap();
+        }
+
+        let rule = WatchRule::new("check", &["*.rs", "/README.md"], "cargo check");
+        let mut watched: Vec<String> = snapshot(temp_dir.path(), &rule).into_keys().collect();
+        watched.sort();
+        assert_eq!(watched, vec!["README.md", "src/main.rs"]);
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let before = Snapshot::from([("a.rs".to_string(), (None, 1)), ("b.rs".to_string(), (None, 1))]);
+        let after = Snapshot::from([("a.rs".to_string(), (None, 2)), ("c.rs".to_string(), (None, 1))]);
+        assert_eq!(changed_files(&before, &after), vec!["a.rs", "b.rs", "c.rs"]);
+    }
+}
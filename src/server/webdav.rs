@@ -0,0 +1,737 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use base64::Engine as _;
+use futures::StreamExt;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{FshError, FshResult};
+use crate::sandbox::{SandboxConfig, SandboxedShell};
+use crate::security::{
+    AuthLockout, AuthManager, DlpAction, DlpScanner, LockoutKey, MalwareScanner, Policy, PolicyAction, ScanOutcome,
+};
+
+/// Bytes read from disk per chunk of a download.
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Characters escaped in the hrefs of a PROPFIND answer.
+const HREF_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'&').add(b'<').add(b'>').add(b'?')
+    .add(b'[').add(b']').add(b'\\').add(b'^').add(b'`').add(b'{').add(b'|').add(b'}');
+
+/// WebDAV access to folders, so they can be mounted as network drives where
+/// the fsh client can't be installed. Clients log in with HTTP Basic auth,
+/// any user name and a token as the password.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebDavConfig {
+    pub enabled: bool,
+    pub listen: String,
+}
+
+impl Default for WebDavConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+impl WebDavConfig {
+    pub fn validate(&self) -> FshResult<()> {
+        if self.enabled && self.listen.parse::<SocketAddr>().is_err() {
+            return Err(FshError::ConfigError(format!("Invalid webdav listen address '{}'", self.listen)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WebDavState {
+    config: Arc<Config>,
+    auth_manager: Arc<RwLock<AuthManager>>,
+    lockout: Arc<AuthLockout>,
+    malware_scanner: Option<Arc<MalwareScanner>>,
+    dlp: Option<Arc<DlpScanner>>,
+}
+
+/// A request path: `/` lists the folders, `/<folder>/<path>` is inside one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DavPath {
+    folder: Option<String>,
+    /// Relative to the folder root, `.` for the root itself.
+    path: String,
+}
+
+impl DavPath {
+    /// Decode a request path or `Destination` header. Paths with `.` or
+    /// `..` segments are refused.
+    fn parse(raw: &str) -> Option<Self> {
+        let raw = match raw.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => raw,
+        };
+        let decoded = percent_decode_str(raw).decode_utf8().ok()?;
+        let mut segments = decoded.split('/').filter(|segment| !segment.is_empty());
+        if decoded.split('/').any(|segment| segment == "." || segment == "..") {
+            return None;
+        }
+        let folder = segments.next().map(str::to_string);
+        let path = segments.collect::<Vec<_>>().join("/");
+        Some(Self { folder, path: if path.is_empty() { ".".to_string() } else { path } })
+    }
+
+    fn is_root(&self) -> bool {
+        self.path == "."
+    }
+}
+
+/// The URL of `path` in `folder`; collections end in `/`.
+fn href(folder: &str, path: &str, collection: bool) -> String {
+    let mut href = format!("/{}", utf8_percent_encode(folder, HREF_ESCAPE));
+    for segment in path.split(['/', '\\']).filter(|segment| !segment.is_empty() && *segment != ".") {
+        href.push('/');
+        href.push_str(&utf8_percent_encode(segment, HREF_ESCAPE).to_string());
+    }
+    if collection {
+        href.push('/');
+    }
+    href
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn http_date(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// One `<D:response>` of a multistatus answer.
+struct DavResource {
+    href: String,
+    name: String,
+    collection: bool,
+    size: u64,
+    modified: chrono::DateTime<chrono::Utc>,
+}
+
+impl DavResource {
+    fn etag(&self) -> String {
+        format!("\"{:x}-{:x}\"", self.size, self.modified.timestamp_micros())
+    }
+
+    fn write_xml(&self, xml: &mut String) {
+        xml.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>",
+            xml_escape(&self.href), xml_escape(&self.name),
+        ));
+        if self.collection {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            xml.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>application/octet-stream</D:getcontenttype><D:getetag>{}</D:getetag>",
+                self.size, xml_escape(&self.etag()),
+            ));
+        }
+        xml.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+            http_date(self.modified),
+        ));
+    }
+}
+
+fn multistatus(resources: &[DavResource]) -> Response {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">");
+    for resource in resources {
+        resource.write_xml(&mut xml);
+    }
+    xml.push_str("</D:multistatus>");
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        xml,
+    ).into_response()
+}
+
+fn error_response(e: &FshError) -> Response {
+    let status = match e {
+        FshError::PermissionDenied(_) | FshError::MalwareDetected(_) => StatusCode::FORBIDDEN,
+        FshError::InvalidPath(_) => StatusCode::CONFLICT,
+        FshError::FolderNotFound(_) => StatusCode::NOT_FOUND,
+        _ => match e.io_kind() {
+            Some(std::io::ErrorKind::NotFound) => StatusCode::NOT_FOUND,
+            Some(std::io::ErrorKind::PermissionDenied) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    };
+    debug!("WebDAV request failed with {}: {}", status, e);
+    (status, e.detail()).into_response()
+}
+
+/// A shell for file operations on `folder`, with its permissions, symlink
+/// policy, trash and policy.
+fn folder_shell(folder: &FolderConfig) -> FshResult<SandboxedShell> {
+    let config = SandboxConfig::new(folder.get_path(), folder.shell_type.clone())
+        .with_permissions(folder.permissions.clone())
+        .with_symlink_policy(folder.symlink_policy)
+        .with_trash(folder.trash.clone());
+    let config = match &folder.policy {
+        Some(policy) => config.with_policy(Policy::new(policy)?),
+        None => config,
+    };
+    SandboxedShell::new(config)
+}
+
+/// The password of a Basic `Authorization` header.
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"fsh\", charset=\"UTF-8\"")]).into_response()
+}
+
+impl WebDavState {
+    /// Check the request's token, returning the user it logs in as.
+    async fn authenticate(&self, headers: &HeaderMap, client_addr: SocketAddr) -> Result<Option<String>, Response> {
+        if !self.config.security.require_authentication {
+            return Ok(None);
+        }
+        let Some(token) = basic_password(headers) else {
+            return Err(unauthorized());
+        };
+
+        let credentials = HashMap::from([("token".to_string(), token.clone())]);
+        let keys = LockoutKey::for_attempt(Some(client_addr.ip()), &credentials);
+        if let Err(e) = self.lockout.check(&keys) {
+            return Err(error_response(&e));
+        }
+        match AuthManager::authenticate_token(&self.auth_manager, &token).await {
+            Ok(token_id) => {
+                self.lockout.record_success(&keys);
+                // Share links are limited to sessions, where their scope is enforced
+                let scope = self.auth_manager.read().await.token_scope(&token_id);
+                if scope.as_ref().is_some_and(|scope| scope.folder.is_some()) {
+                    warn!("WebDAV request from {} used a share link", client_addr);
+                    return Err(StatusCode::FORBIDDEN.into_response());
+                }
+                Ok(scope.and_then(|scope| scope.user))
+            }
+            Err(e) => {
+                warn!("WebDAV authentication from {} failed: {}", client_addr, e);
+                self.lockout.record_failure(&keys);
+                Err(unauthorized())
+            }
+        }
+    }
+
+    /// Folders served over WebDAV to `user`. Honeypots and folders limited
+    /// to client certificate identities are left out.
+    fn folders<'a>(&'a self, user: Option<&'a str>) -> impl Iterator<Item = &'a FolderConfig> {
+        self.config.folders.iter()
+            .filter(|folder| !folder.honeypot.enabled && folder.allowed_identities.is_empty())
+            .filter(move |folder| self.config.users.allows(user, &folder.name))
+    }
+
+    fn folder<'a>(&'a self, name: &str, user: Option<&'a str>) -> FshResult<&'a FolderConfig> {
+        let folder = self.folders(user).find(|folder| folder.name == name)
+            .ok_or_else(|| FshError::FolderNotFound(name.to_string()))?;
+        folder.validate()?;
+        Ok(folder)
+    }
+}
+
+/// Routes every path and method to [`handle`].
+fn router(state: WebDavState) -> Router {
+    Router::new().fallback(handle).with_state(state)
+}
+
+async fn handle(
+    State(state): State<WebDavState>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    // Clients probe with OPTIONS before they send credentials
+    if method == Method::OPTIONS {
+        return options();
+    }
+    let user = match state.authenticate(&headers, client_addr).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let Some(path) = DavPath::parse(uri.path()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    debug!("WebDAV {} {:?} from {}", method, path, client_addr);
+
+    let Some(folder_name) = path.folder.clone() else {
+        return match method.as_str() {
+            "PROPFIND" => list_folders(&state, &headers, user.as_deref()),
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        };
+    };
+    let result = match state.folder(&folder_name, user.as_deref()) {
+        Ok(folder) => dispatch(&state, folder, &method, &path, &headers, body).await,
+        Err(e) => Err(e),
+    };
+    result.unwrap_or_else(|e| error_response(&e))
+}
+
+async fn dispatch(
+    state: &WebDavState,
+    folder: &FolderConfig,
+    method: &Method,
+    path: &DavPath,
+    headers: &HeaderMap,
+    body: Body,
+) -> FshResult<Response> {
+    let write = !matches!(method.as_str(), "GET" | "HEAD" | "PROPFIND" | "LOCK" | "UNLOCK");
+    if !folder.can_read() || (write && !folder.can_write()) {
+        let kind = if write { "Write" } else { "Read" };
+        return Err(FshError::PermissionDenied(format!("{} permission denied", kind)));
+    }
+
+    let mut shell = folder_shell(folder)?;
+    match method.as_str() {
+        "PROPFIND" => propfind(&shell, folder, path, headers),
+        "GET" | "HEAD" => get(state, &shell, path, *method == Method::HEAD).await,
+        "PUT" => put(state, &shell, path, body).await,
+        "DELETE" => {
+            existing(&shell, &path.path)?;
+            shell.delete_path(&path.path, true)?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        "MKCOL" => mkcol(&shell, path),
+        "MOVE" | "COPY" => transfer(&mut shell, path, headers, method.as_str() == "MOVE"),
+        "PROPPATCH" => {
+            // Properties can't be stored; accept them so clients don't fail
+            existing(&shell, &path.path)?;
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">");
+            xml.push_str(&format!(
+                "<D:response><D:href>{}</D:href><D:propstat><D:prop/><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response></D:multistatus>",
+                xml_escape(&href(&folder.name, &path.path, false)),
+            ));
+            Ok((StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], xml).into_response())
+        }
+        "LOCK" => Ok(lock()),
+        "UNLOCK" => Ok(StatusCode::NO_CONTENT.into_response()),
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    }
+}
+
+fn options() -> Response {
+    (
+        StatusCode::OK,
+        [
+            ("dav", "1, 2"),
+            ("ms-author-via", "DAV"),
+            ("allow", "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, COPY, PROPFIND, PROPPATCH, LOCK, UNLOCK"),
+        ],
+    ).into_response()
+}
+
+/// Locks aren't enforced; a token is handed out so clients that insist on
+/// locking before they write can go ahead.
+fn lock() -> Response {
+    let token = format!("opaquelocktoken:{}", Uuid::new_v4());
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+         <D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope><D:depth>0</D:depth>\
+         <D:timeout>Second-3600</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+         </D:activelock></D:lockdiscovery></D:prop>",
+        token,
+    );
+    let lock_token = HeaderValue::from_str(&format!("<{}>", token)).expect("lock token is ASCII");
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, HeaderValue::from_static("application/xml; charset=utf-8")), (header::HeaderName::from_static("lock-token"), lock_token)],
+        xml,
+    ).into_response()
+}
+
+/// The resolved path, or a not-found error if nothing is there.
+fn existing(shell: &SandboxedShell, path: &str) -> FshResult<std::path::PathBuf> {
+    let target = shell.resolve_path(path)?;
+    if !target.exists() {
+        return Err(FshError::io(
+            format!("'{}' does not exist", path),
+            std::io::Error::from(std::io::ErrorKind::NotFound),
+        ));
+    }
+    Ok(target)
+}
+
+/// `Depth: 0` describes only the resource; anything else, including
+/// `infinity`, also lists a collection's children.
+fn lists_children(headers: &HeaderMap) -> bool {
+    headers.get("depth").and_then(|depth| depth.to_str().ok()) != Some("0")
+}
+
+fn list_folders(state: &WebDavState, headers: &HeaderMap, user: Option<&str>) -> Response {
+    let mut resources = vec![DavResource {
+        href: "/".to_string(),
+        name: String::new(),
+        collection: true,
+        size: 0,
+        modified: chrono::Utc::now(),
+    }];
+    if lists_children(headers) {
+        resources.extend(state.folders(user).map(|folder| DavResource {
+            href: href(&folder.name, ".", true),
+            name: folder.name.clone(),
+            collection: true,
+            size: 0,
+            modified: std::fs::metadata(folder.get_path())
+                .and_then(|metadata| metadata.modified())
+                .map(chrono::DateTime::from)
+                .unwrap_or_else(|_| chrono::Utc::now()),
+        }));
+    }
+    multistatus(&resources)
+}
+
+fn propfind(shell: &SandboxedShell, folder: &FolderConfig, path: &DavPath, headers: &HeaderMap) -> FshResult<Response> {
+    existing(shell, &path.path)?;
+    let stat = shell.stat_file(&path.path)?;
+    let mut resources = vec![DavResource {
+        href: href(&folder.name, &path.path, stat.entry.is_directory),
+        name: if path.is_root() { folder.name.clone() } else { stat.entry.name.clone() },
+        collection: stat.entry.is_directory,
+        size: stat.entry.size,
+        modified: stat.entry.modified,
+    }];
+    if stat.entry.is_directory && lists_children(headers) {
+        resources.extend(shell.list_files(Some(&path.path), true)?.into_iter().map(|entry| DavResource {
+            href: href(&folder.name, &entry.path, entry.is_directory),
+            name: entry.name,
+            collection: entry.is_directory,
+            size: entry.size,
+            modified: entry.modified,
+        }));
+    }
+    Ok(multistatus(&resources))
+}
+
+async fn get(state: &WebDavState, shell: &SandboxedShell, path: &DavPath, head: bool) -> FshResult<Response> {
+    let target = existing(shell, &path.path)?;
+    shell.check_file_policy(PolicyAction::Read, &target)?;
+    let stat = shell.stat_file(&path.path)?;
+    if stat.entry.is_directory {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+    }
+
+    // Read the file once to find out whether a rule blocks it; what is sent
+    // is redacted on the way out
+    if let (Some(dlp), false) = (&state.dlp, head) {
+        let dlp = Arc::clone(dlp);
+        let scanned = target.clone();
+        let matches = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            let mut reader = dlp.reader(std::fs::File::open(&scanned)?, 0)?;
+            std::io::copy(&mut reader, &mut std::io::sink())?;
+            Ok(reader.matches().to_vec())
+        }).await.map_err(|e| FshError::ShellError(format!("DLP scan failed: {}", e)))??;
+        for found in &matches {
+            info!("DLP rule '{}' matched {} over WebDAV ({})", found.rule, path.path, found.action.name());
+        }
+        if let Some(blocked) = matches.iter().find(|found| found.action == DlpAction::Block) {
+            return Err(blocked.blocked_error());
+        }
+    }
+
+    let resource = DavResource {
+        href: String::new(),
+        name: stat.entry.name,
+        collection: false,
+        size: stat.entry.size,
+        modified: stat.entry.modified,
+    };
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (header::CONTENT_LENGTH, resource.size.to_string()),
+        (header::LAST_MODIFIED, http_date(resource.modified)),
+        (header::ETAG, resource.etag()),
+    ];
+    if head {
+        return Ok((StatusCode::OK, headers).into_response());
+    }
+    let file = std::fs::File::open(&target)?;
+    Ok((StatusCode::OK, headers, file_body(file, state.dlp.clone())).into_response())
+}
+
+/// Stream `file` from a blocking thread, through the DLP rules if there are
+/// any. Redaction keeps the length, so `Content-Length` still holds.
+fn file_body(file: std::fs::File, dlp: Option<Arc<DlpScanner>>) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+    tokio::task::spawn_blocking(move || {
+        let mut reader: Box<dyn Read> = match &dlp {
+            Some(dlp) => match dlp.reader(file, 0) {
+                Ok(reader) => Box::new(reader),
+                Err(e) => {
+                    let _ = sender.blocking_send(Err(e));
+                    return;
+                }
+            },
+            None => Box::new(file),
+        };
+        loop {
+            let mut chunk = vec![0; DOWNLOAD_CHUNK_SIZE];
+            let item = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Ok(Bytes::from(chunk))
+                }
+                Err(e) => Err(e),
+            };
+            let failed = item.is_err();
+            if sender.blocking_send(item).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Body::from_stream(futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    }))
+}
+
+async fn put(state: &WebDavState, shell: &SandboxedShell, path: &DavPath, body: Body) -> FshResult<Response> {
+    let target = shell.resolve_path(&path.path)?;
+    shell.check_file_policy(PolicyAction::Write, &target)?;
+    if target.is_dir() {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    let existed = target.exists();
+
+    replace_from_body(&target, body).await
+        .map_err(|e| FshError::io(format!("Cannot write '{}'", path.path), e))?;
+
+    if let Some(scanner) = &state.malware_scanner {
+        if let ScanOutcome::Quarantined { verdict, .. } = scanner.check(&target).await? {
+            warn!("Quarantined '{}' uploaded over WebDAV: {}", path.path, verdict);
+            return Err(FshError::MalwareDetected(format!("'{}' was quarantined: {}", path.path, verdict)));
+        }
+    }
+    Ok(if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED }.into_response())
+}
+
+/// Write the request body next to `target` and rename it into place, so
+/// readers never see a half-uploaded file.
+async fn replace_from_body(target: &Path, body: Body) -> std::io::Result<()> {
+    let name = target.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let temp_path = target.with_file_name(format!(".{}.fsh-{}", name, Uuid::new_v4()));
+
+    let result = async {
+        let mut temp = tokio::fs::File::create(&temp_path).await?;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            temp.write_all(&chunk.map_err(std::io::Error::other)?).await?;
+        }
+        temp.sync_all().await?;
+        if let Ok(metadata) = tokio::fs::metadata(target).await {
+            temp.set_permissions(metadata.permissions()).await?;
+        }
+        tokio::fs::rename(&temp_path, target).await
+    }.await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+fn mkcol(shell: &SandboxedShell, path: &DavPath) -> FshResult<Response> {
+    let target = shell.resolve_path(&path.path)?;
+    shell.check_file_policy(PolicyAction::Write, &target)?;
+    if target.exists() {
+        return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+    std::fs::create_dir(&target)?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+/// MOVE or COPY within a folder. Whatever is replaced at the destination
+/// goes to the trash.
+fn transfer(shell: &mut SandboxedShell, path: &DavPath, headers: &HeaderMap, is_move: bool) -> FshResult<Response> {
+    let Some(destination) = headers.get("destination")
+        .and_then(|destination| destination.to_str().ok())
+        .and_then(DavPath::parse)
+    else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    if destination.folder != path.folder {
+        return Ok(StatusCode::BAD_GATEWAY.into_response());
+    }
+    if path.is_root() || destination.is_root() {
+        return Err(FshError::PermissionDenied("Cannot move or copy the folder root".to_string()));
+    }
+
+    let source = existing(shell, &path.path)?;
+    let target = shell.resolve_path(&destination.path)?;
+    if target.starts_with(&source) {
+        return Err(FshError::PermissionDenied("Cannot move or copy a directory into itself".to_string()));
+    }
+    shell.check_file_policy(if is_move { PolicyAction::Delete } else { PolicyAction::Read }, &source)?;
+    shell.check_file_policy(PolicyAction::Write, &target)?;
+    if !target.parent().is_some_and(Path::is_dir) {
+        return Ok(StatusCode::CONFLICT.into_response());
+    }
+
+    let replaced = target.exists();
+    if replaced {
+        let overwrite = headers.get("overwrite").and_then(|overwrite| overwrite.to_str().ok()) != Some("F");
+        if !overwrite {
+            return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+        }
+        shell.delete_path(&destination.path, true)?;
+    }
+
+    if is_move {
+        std::fs::rename(&source, &target)?;
+    } else if source.is_dir() {
+        copy_dir(&source, &target)?;
+    } else {
+        std::fs::copy(&source, &target)?;
+    }
+    Ok(if replaced { StatusCode::NO_CONTENT } else { StatusCode::CREATED }.into_response())
+}
+
+/// Copy a directory tree. Symlinks are left out, so nothing outside the
+/// folder is copied into it.
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<()> {
+    for entry in walkdir::WalkDir::new(source) {
+        let entry = entry.map_err(std::io::Error::other)?;
+        let destination = target.join(entry.path().strip_prefix(source).map_err(std::io::Error::other)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&destination)?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &destination)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bind the WebDAV endpoint's listener.
+pub async fn bind_webdav(config: &WebDavConfig) -> FshResult<TcpListener> {
+    let listener = TcpListener::bind(&config.listen).await
+        .map_err(|e| FshError::io(format!("Failed to bind WebDAV to {}", config.listen), e))?;
+    info!("WebDAV listening on {}", config.listen);
+    Ok(listener)
+}
+
+/// Serve folders over WebDAV until the listener fails.
+pub async fn serve_webdav(
+    listener: TcpListener,
+    config: Arc<Config>,
+    auth_manager: Arc<RwLock<AuthManager>>,
+    lockout: Arc<AuthLockout>,
+    malware_scanner: Option<Arc<MalwareScanner>>,
+    dlp: Option<Arc<DlpScanner>>,
+) -> FshResult<()> {
+    let state = WebDavState { config, auth_manager, lockout, malware_scanner, dlp };
+    axum::serve(listener, router(state).into_make_service_with_connect_info::<SocketAddr>()).await
+        .map_err(|e| FshError::io("WebDAV failed", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Permission;
+    use crate::security::LockoutConfig;
+    use tempfile::TempDir;
+
+    fn method(name: &str) -> reqwest::Method {
+        reqwest::Method::from_bytes(name.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_paths_and_hrefs() {
+        let path = DavPath::parse("/my%20project/src/main.rs").unwrap();
+        assert_eq!(path.folder.as_deref(), Some("my project"));
+        assert_eq!(path.path, "src/main.rs");
+        assert_eq!(DavPath::parse("http://host:8080/docs/").unwrap(), DavPath { folder: Some("docs".to_string()), path: ".".to_string() });
+        assert_eq!(DavPath::parse("/").unwrap().folder, None);
+        assert!(DavPath::parse("/docs/../etc/passwd").is_none());
+        assert!(DavPath::parse("/docs/%2e%2e/x").is_none());
+
+        assert_eq!(href("my project", "a b/c#d.txt", false), "/my%20project/a%20b/c%23d.txt");
+        assert_eq!(href("docs", ".", true), "/docs/");
+    }
+
+    #[tokio::test]
+    async fn test_webdav_operations() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+
+        let mut config = Config::default();
+        config.security.require_authentication = false;
+        config.folders.push(FolderConfig::new("docs".to_string(), dir.path())
+            .with_permissions(vec![Permission::Read, Permission::Write]));
+        let auth_manager = Arc::new(RwLock::new(AuthManager::new(&config.security).unwrap()));
+        let lockout = Arc::new(AuthLockout::new(&LockoutConfig::default()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve_webdav(listener, Arc::new(config), auth_manager, lockout, None, None));
+        let http = reqwest::Client::new();
+
+        let listing = http.request(method("PROPFIND"), format!("{}/docs/", base)).header("Depth", "1")
+            .send().await.unwrap();
+        assert_eq!(listing.status(), StatusCode::MULTI_STATUS);
+        let listing = listing.text().await.unwrap();
+        assert!(listing.contains("<D:href>/docs/notes.txt</D:href>"));
+        assert!(listing.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        let read = http.get(format!("{}/docs/notes.txt", base)).send().await.unwrap();
+        assert_eq!(read.text().await.unwrap(), "hello");
+
+        let created = http.request(method("MKCOL"), format!("{}/docs/sub", base)).send().await.unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let put = http.put(format!("{}/docs/sub/new.txt", base)).body("uploaded").send().await.unwrap();
+        assert_eq!(put.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read_to_string(dir.path().join("sub/new.txt")).unwrap(), "uploaded");
+
+        let moved = http.request(method("MOVE"), format!("{}/docs/sub/new.txt", base))
+            .header("Destination", format!("{}/docs/moved.txt", base))
+            .send().await.unwrap();
+        assert_eq!(moved.status(), StatusCode::CREATED);
+        assert!(dir.path().join("moved.txt").exists());
+
+        let deleted = http.delete(format!("{}/docs/moved.txt", base)).send().await.unwrap();
+        assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+        assert!(!dir.path().join("moved.txt").exists());
+
+        let missing = http.get(format!("{}/docs/moved.txt", base)).send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let escape = http.get(format!("{}/docs/..%2f..%2fetc/passwd", base)).send().await.unwrap();
+        assert_eq!(escape.status(), StatusCode::BAD_REQUEST);
+    }
+}
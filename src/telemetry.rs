@@ -0,0 +1,195 @@
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::metrics::periodic_reader_with_async_runtime::PeriodicReader;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::ObservabilityConfig;
+use crate::protocol::{FshError, FshResult};
+
+/// OTLP exporters started from `[observability]`. Call `shutdown` before
+/// exiting so buffered spans and metrics are sent.
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Telemetry {
+    /// Start the exporters. Must be called inside the Tokio runtime, and
+    /// before the first call to `metrics()` so instruments reach the exporter.
+    pub fn init(config: &ObservabilityConfig) -> FshResult<Self> {
+        let endpoint = config.otlp_endpoint.trim_end_matches('/');
+        let resource = Resource::builder().with_service_name(config.service_name.clone()).build();
+        let export_error = |e: opentelemetry_otlp::ExporterBuildError| {
+            FshError::ConfigError(format!("Failed to set up OTLP export: {}", e))
+        };
+
+        let tracer_provider = if config.traces {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint))
+                .with_headers(config.headers.clone())
+                .build()
+                .map_err(export_error)?;
+            Some(SdkTracerProvider::builder()
+                .with_span_processor(BatchSpanProcessor::builder(exporter, Tokio).build())
+                .with_resource(resource.clone())
+                .build())
+        } else {
+            None
+        };
+
+        let meter_provider = if config.metrics {
+            let exporter = MetricExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/metrics", endpoint))
+                .with_headers(config.headers.clone())
+                .build()
+                .map_err(export_error)?;
+            let reader = PeriodicReader::builder(exporter, Tokio)
+                .with_interval(Duration::from_secs(config.metrics_interval_seconds))
+                .build();
+            let provider = SdkMeterProvider::builder()
+                .with_reader(reader)
+                .with_resource(resource)
+                .build();
+            global::set_meter_provider(provider.clone());
+            Some(provider)
+        } else {
+            None
+        };
+
+        Ok(Self { tracer_provider, meter_provider })
+    }
+
+    /// A layer that exports tracing spans, if trace export is enabled.
+    pub fn tracing_layer<S>(&self) -> Option<OpenTelemetryLayer<S, SdkTracer>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        self.tracer_provider.as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("fsh")))
+    }
+
+    /// Flush and stop the exporters.
+    pub fn shutdown(self) {
+        if let Some(provider) = self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+        if let Some(provider) = self.meter_provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush metrics: {}", e);
+            }
+        }
+    }
+}
+
+/// Server metrics, recorded whether or not they are exported.
+pub struct Metrics {
+    session_duration: Histogram<f64>,
+    command_duration: Histogram<f64>,
+    transfer_bytes: Counter<u64>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let meter = global::meter("fsh");
+        Self {
+            session_duration: meter.f64_histogram("fsh.session.duration")
+                .with_unit("s")
+                .with_description("How long sessions stayed open")
+                .build(),
+            command_duration: meter.f64_histogram("fsh.command.duration")
+                .with_unit("ms")
+                .with_description("Time from receiving a command to its completion")
+                .build(),
+            transfer_bytes: meter.u64_counter("fsh.transfer.bytes")
+                .with_unit("By")
+                .with_description("File data read and written by clients")
+                .build(),
+        }
+    }
+
+    pub fn record_session(&self, folder: &str, duration: Duration) {
+        self.session_duration.record(duration.as_secs_f64(), &[KeyValue::new("folder", folder.to_string())]);
+    }
+
+    pub fn record_command(&self, folder: &str, duration: Duration, exit_code: i32) {
+        self.command_duration.record(duration.as_secs_f64() * 1000.0, &[
+            KeyValue::new("folder", folder.to_string()),
+            KeyValue::new("success", exit_code == 0),
+        ]);
+    }
+
+    /// `direction` is "read" or "write", from the client's point of view.
+    pub fn record_transfer(&self, folder: &str, direction: &'static str, bytes: usize) {
+        self.transfer_bytes.add(bytes as u64, &[
+            KeyValue::new("folder", folder.to_string()),
+            KeyValue::new("direction", direction),
+        ]);
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Path, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_otlp_export() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new().route("/v1/:signal", post(move |Path(signal): Path<String>, headers: HeaderMap| {
+            let authorized = headers.get("authorization").is_some_and(|value| value == "Bearer collector");
+            let _ = tx.send((signal, authorized));
+            async { StatusCode::OK }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let otlp_endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = ObservabilityConfig {
+            enabled: true,
+            otlp_endpoint,
+            headers: HashMap::from([("authorization".to_string(), "Bearer collector".to_string())]),
+            ..Default::default()
+        };
+        let telemetry = Telemetry::init(&config).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(telemetry.tracing_layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("command", command_id = 1).entered();
+        });
+        global::meter("fsh-test").u64_counter("fsh.test").build().add(1, &[]);
+
+        // Shutting down flushes both signals
+        tokio::task::spawn_blocking(move || telemetry.shutdown()).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let request = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+            received.push(request);
+        }
+        received.sort();
+        assert_eq!(received, vec![("metrics".to_string(), true), ("traces".to_string(), true)]);
+    }
+}
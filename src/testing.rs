@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+use crate::client::{CommandOutputType, FshClient};
+use crate::config::{Config, FolderConfig};
+use crate::protocol::{memory_transport, FshError, FshResult, MemoryTransport};
+use crate::security::TokenConfig;
+use crate::server::{FshServerBuilder, ServerHandle};
+
+/// Name of the folder a `TestServer` shares when none are added.
+pub const DEFAULT_FOLDER: &str = "test";
+
+type ConfigureFolder = Box<dyn FnOnce(FolderConfig) -> FolderConfig + Send>;
+
+/// Sets up a `TestServer`: its folders, transport and login.
+pub struct TestServerBuilder {
+    server: FshServerBuilder,
+    folders: Vec<(String, ConfigureFolder)>,
+    in_memory: bool,
+    with_token: bool,
+}
+
+impl Default for TestServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestServerBuilder {
+    /// A server on an ephemeral loopback port that lets clients in without
+    /// logging in.
+    pub fn new() -> Self {
+        Self {
+            server: FshServerBuilder::new().with_listen_address("127.0.0.1", 0).without_authentication(),
+            folders: Vec::new(),
+            in_memory: false,
+            with_token: false,
+        }
+    }
+
+    /// Serve over in-process pipes instead of a TCP socket.
+    pub fn in_memory(mut self) -> Self {
+        self.in_memory = true;
+        self
+    }
+
+    /// Share an empty temporary directory as `name`.
+    pub fn with_folder(self, name: impl Into<String>) -> Self {
+        self.with_folder_config(name, |folder| folder)
+    }
+
+    /// Share an empty temporary directory as `name`, with `configure`
+    /// adjusting its folder config.
+    pub fn with_folder_config(
+        mut self,
+        name: impl Into<String>,
+        configure: impl FnOnce(FolderConfig) -> FolderConfig + Send + 'static,
+    ) -> Self {
+        self.folders.push((name.into(), Box::new(configure)));
+        self
+    }
+
+    /// Require a login with a freshly generated token, which
+    /// `TestServer::connect` uses.
+    pub fn with_token(mut self) -> Self {
+        self.with_token = true;
+        self
+    }
+
+    /// Adjust the underlying server builder, for plugins, authenticators and
+    /// audit sinks.
+    pub fn with_server(mut self, configure: impl FnOnce(FshServerBuilder) -> FshServerBuilder) -> Self {
+        self.server = configure(self.server);
+        self
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        self.server.config_mut()
+    }
+
+    pub async fn start(mut self) -> FshResult<TestServer> {
+        let root = TempDir::new()?;
+        if self.folders.is_empty() {
+            self = self.with_folder(DEFAULT_FOLDER);
+        }
+
+        let mut server = self.server;
+        for (name, configure) in self.folders {
+            let path = root.path().join(&name);
+            std::fs::create_dir_all(&path)?;
+            server = server.with_folder(configure(FolderConfig::new(name, path)));
+        }
+
+        let token = if self.with_token {
+            let (token_config, token) = TokenConfig::generate("test server");
+            let config = server.config_mut();
+            config.security.require_authentication = true;
+            config.security.tokens.push(token_config);
+            Some(token)
+        } else {
+            None
+        };
+
+        let transport = if self.in_memory {
+            let (transport, listener) = memory_transport();
+            server = server.with_listener(listener);
+            Some(transport)
+        } else {
+            None
+        };
+
+        let handle = server.build()?.spawn().await?;
+        Ok(TestServer { handle, transport, root, token })
+    }
+}
+
+/// An `FshServer` running in this process over temporary folders, for
+/// end-to-end tests of clients and embedders. The folders are removed when
+/// it's dropped.
+pub struct TestServer {
+    handle: ServerHandle,
+    transport: Option<MemoryTransport>,
+    root: TempDir,
+    token: Option<String>,
+}
+
+impl TestServer {
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::new()
+    }
+
+    /// Start a server sharing one folder, `DEFAULT_FOLDER`, over TCP.
+    pub async fn start() -> FshResult<Self> {
+        TestServerBuilder::new().start().await
+    }
+
+    /// The address clients connect to; in-process servers accept any.
+    pub fn address(&self) -> String {
+        match self.handle.local_addr() {
+            Some(addr) => addr.to_string(),
+            None => "in-process".to_string(),
+        }
+    }
+
+    /// Where the server keeps the folder shared as `name`.
+    pub fn folder_path(&self, name: &str) -> PathBuf {
+        self.root.path().join(name)
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// A client set up to reach this server, not yet connected.
+    pub fn client(&self) -> FshClient {
+        let client = FshClient::new(self.address());
+        match &self.transport {
+            Some(transport) => client.with_transport(Arc::new(transport.clone())),
+            None => client,
+        }
+    }
+
+    /// A client that has completed the handshake and logged in if the
+    /// server requires it.
+    pub async fn connect(&self) -> FshResult<FshClient> {
+        let mut client = self.client();
+        client.connect().await?;
+        if let Some(token) = &self.token {
+            let mut credentials = HashMap::new();
+            credentials.insert("token".to_string(), token.clone());
+            client.authenticate("token", credentials).await?;
+        }
+        Ok(client)
+    }
+
+    /// A connected client with a ready session in `folder`.
+    pub async fn bind(&self, folder: &str) -> FshResult<FshClient> {
+        let mut client = self.connect().await?;
+        client.bind_folder(folder, None).await?;
+        client.wait_for_session_ready().await?;
+        Ok(client)
+    }
+
+    pub async fn stop(self) -> FshResult<()> {
+        self.handle.stop().await
+    }
+}
+
+/// What a command printed, as collected by `run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandRun {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run `command` to completion in `client`'s session. A command the server
+/// refuses or fails to run is an error.
+pub async fn run(client: &mut FshClient, command: &str, args: &[&str]) -> FshResult<CommandRun> {
+    let args = args.iter().map(|arg| arg.to_string()).collect();
+    let mut output_rx = client.execute_command(command, args).await?;
+    let mut run = CommandRun::default();
+    while let Some(output) = output_rx.recv().await {
+        match output.output_type {
+            CommandOutputType::Stdout => run.stdout.push_str(&output.data),
+            CommandOutputType::Stderr => run.stderr.push_str(&output.data),
+            CommandOutputType::Complete => break,
+            CommandOutputType::Error => return Err(FshError::ShellError(output.data)),
+        }
+    }
+    Ok(run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(server: &TestServer) {
+        std::fs::write(server.folder_path(DEFAULT_FOLDER).join("hello.txt"), "hello").unwrap();
+        let mut client = server.bind(DEFAULT_FOLDER).await.unwrap();
+
+        let files = client.list_files(".", false).await.unwrap();
+        assert!(files.iter().any(|file| file.name == "hello.txt"));
+        client.write_file_range("copy.txt", b"written", None).await.unwrap();
+        assert_eq!(std::fs::read(server.folder_path(DEFAULT_FOLDER).join("copy.txt")).unwrap(), b"written");
+        let (data, _) = client.read_file_range("hello.txt", 0, 5).await.unwrap();
+        assert_eq!(data, b"hello");
+
+        #[cfg(unix)]
+        assert_eq!(run(&mut client, "echo", &["hi"]).await.unwrap().stdout.trim(), "hi");
+
+        assert_eq!(server.handle().stats().await.active_sessions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_server() {
+        let server = TestServer::start().await.unwrap();
+        assert!(server.handle().local_addr().is_some());
+        exercise(&server).await;
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_server_with_token() {
+        let server = TestServer::builder().in_memory().with_token().start().await.unwrap();
+        assert_eq!(server.address(), "in-process");
+
+        // Without the token the server refuses to bind
+        let mut anonymous = server.client();
+        anonymous.connect().await.unwrap();
+        assert!(anonymous.bind_folder(DEFAULT_FOLDER, None).await.is_err());
+
+        exercise(&server).await;
+        server.stop().await.unwrap();
+    }
+}